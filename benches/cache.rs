@@ -0,0 +1,72 @@
+// lookups per second on a shared cache with every core hammering it, once with a single shard
+// (one lock for everything) and once with the default sharding:
+// cargo bench --bench cache [threads]
+use dns_server::cache::{Cache, CacheKey};
+use dns_server::{DnsPacket, DnsRecord, QueryType};
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NAMES: usize = 10_000;
+const RUN_FOR: Duration = Duration::from_secs(2);
+
+fn filled(cache: Cache) -> (Cache, Vec<CacheKey>) {
+    let mut keys = Vec::with_capacity(NAMES);
+    for i in 0..NAMES {
+        let name = format!("host{}.example.com", i);
+        let query = DnsPacket::query(&name, QueryType::A).build();
+        let mut response = DnsPacket::response_to(&query);
+        response.answers.push(DnsRecord::A {
+            domain: name,
+            class: 1,
+            ip: Ipv4Addr::new(192, 0, 2, (i % 256) as u8).into(),
+            ttl: 3600,
+        });
+        let key = CacheKey::from(&query.questions[0]);
+        cache.insert(key.clone(), &response);
+        keys.push(key);
+    }
+    (cache, keys)
+}
+
+fn lookups_per_sec(cache: &Cache, keys: &[CacheKey], threads: usize) -> f64 {
+    let start = Instant::now();
+    let total: u64 = thread::scope(|s| {
+        let workers: Vec<_> = (0..threads)
+            .map(|t| {
+                s.spawn(move || {
+                    let mut done = 0u64;
+                    let mut i = t * 7919;
+                    while start.elapsed() < RUN_FOR {
+                        // check the clock every so often only, it isn't free either
+                        for _ in 0..1000 {
+                            i = (i + 104_729) % keys.len();
+                            assert!(cache.get(&keys[i]).is_some());
+                        }
+                        done += 1000;
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).sum()
+    });
+    total as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    // cargo bench passes --bench along, anything that isn't a number is skipped
+    let threads = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    println!("{} threads, {} names", threads, NAMES);
+    for (label, cache) in [
+        ("1 shard", Cache::new().shards(1)),
+        ("default shards", Cache::new()),
+    ] {
+        let (cache, keys) = filled(cache);
+        let rate = lookups_per_sec(&cache, &keys, threads);
+        println!("  {:<16} {:>12.0} lookups/s", label, rate);
+    }
+}
@@ -0,0 +1,136 @@
+// serves zone files authoritatively, and secondary copies of zones transferred from a primary:
+// cargo run --example authoritative -- <listen address> <origin>=<zone file>...
+//     <origin>@<primary address>[=<saved copy>]... [--key [algorithm:]name:secret]...
+//     [--dnssec-key <key file>]... [--nsec3]
+// with keys, transfers have to be signed with one of them and secondaries sign with the first.
+// zones that DNSSEC keys are given for are signed with them, with NSEC3 instead of NSEC if
+// asked for. the dnssec_keys example makes and rolls over keys.
+use dns_server::authority::Authority;
+use dns_server::client::Client;
+use dns_server::net::parse_socket_addr;
+use dns_server::secondary::Secondary;
+use dns_server::server::BlockingServer;
+use dns_server::signer::{Chain, Signer, ZoneKey};
+use dns_server::tsig::{Keyring, TsigKey};
+use dns_server::zone::Zone;
+use std::env;
+use std::sync::Arc;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = env::args().skip(1);
+    let Some(addr) = args.next() else {
+        anyhow::bail!(
+            "usage: authoritative <listen address> <origin>=<zone file>... \
+             <origin>@<primary address>[=<saved copy>]... [--key [algorithm:]name:secret]... \
+             [--dnssec-key <key file>]... [--nsec3]"
+        );
+    };
+    let addr = parse_socket_addr(&addr, 53)?;
+
+    let mut zones = Vec::new();
+    let mut secondaries = Vec::new();
+    let mut keys: Vec<TsigKey> = Vec::new();
+    let mut zone_keys: Vec<ZoneKey> = Vec::new();
+    let mut chain = Chain::Nsec;
+    while let Some(arg) = args.next() {
+        if arg == "--dnssec-key" {
+            let Some(path) = args.next() else {
+                anyhow::bail!("--dnssec-key needs a key file");
+            };
+            zone_keys.push(ZoneKey::load(path)?);
+            continue;
+        }
+        if arg == "--nsec3" {
+            // no extra iterations and no salt, rfc 9276 section 3.1
+            chain = Chain::Nsec3 {
+                iterations: 0,
+                salt: Vec::new(),
+                opt_out: false,
+            };
+            continue;
+        }
+        if arg == "--key" {
+            let Some(key) = args.next() else {
+                anyhow::bail!("--key needs a key");
+            };
+            keys.push(key.parse()?);
+            continue;
+        }
+        if let Some((origin, primary)) = arg.split_once('@') {
+            let (primary, path) = match primary.split_once('=') {
+                Some((primary, path)) => (primary, Some(path)),
+                None => (primary, None),
+            };
+            let mut secondary = Secondary::new(origin, vec![parse_socket_addr(primary, 53)?]);
+            if let Some(path) = path {
+                secondary = secondary.persist(path);
+            }
+            secondaries.push(secondary);
+            continue;
+        }
+        let Some((origin, path)) = arg.split_once('=') else {
+            anyhow::bail!(
+                "expected <origin>=<zone file> or <origin>@<primary>, got {:?}",
+                arg
+            );
+        };
+        let zone = Zone::load(path, origin)?;
+        println!("loaded {} records for {}", zone.len(), zone.origin());
+        zones.push(zone);
+    }
+
+    // zones with keys are signed before serving, and again before the signatures expire
+    let mut signers = Vec::new();
+    for zone in &mut zones {
+        let keys: Vec<ZoneKey> = zone_keys
+            .iter()
+            .filter(|key| key.zone() == zone.origin())
+            .cloned()
+            .collect();
+        if keys.is_empty() {
+            continue;
+        }
+        let mut signer = Signer::new(zone.clone(), keys).chain(chain.clone());
+        *zone = signer.sign()?;
+        println!("signed {} with {} records", zone.origin(), zone.len());
+        signers.push(signer);
+    }
+    if let Some(key) = zone_keys
+        .iter()
+        .find(|key| !zones.iter().any(|zone| zone.origin() == key.zone()))
+    {
+        anyhow::bail!(
+            "key {} is for {}, which isn't loaded",
+            key.key_tag(),
+            key.zone()
+        );
+    }
+
+    let mut authority = Authority::new(zones);
+    if !keys.is_empty() {
+        let names = keys.iter().map(|key| key.name().to_string()).collect();
+        authority = authority.require_transfer_key(names);
+    }
+    let authority = Arc::new(authority);
+    let server = BlockingServer::bind_shared(addr, authority.clone())?
+        .keys(keys.iter().cloned().collect::<Keyring>());
+    for mut secondary in secondaries {
+        println!("secondary for {}", secondary.origin());
+        if let Some(key) = keys.first() {
+            secondary = secondary.client(Client::new().tsig(key.clone()));
+        }
+        let authority = authority.clone();
+        server
+            .runtime()
+            .spawn(async move { secondary.run(&authority).await });
+    }
+    for signer in signers {
+        let authority = authority.clone();
+        server
+            .runtime()
+            .spawn(async move { signer.run(&authority).await });
+    }
+    println!("listening on {}", server.local_addr()?);
+    server.run()?;
+    Ok(())
+}
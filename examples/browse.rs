@@ -0,0 +1,43 @@
+// lists the instances of a service type on the link as they come and go, with where they are:
+// cargo run --example browse [<service type>], e.g. _ipp._tcp, default all service types
+use dns_server::dns_sd::{self, Browser, SERVICE_TYPES};
+use std::env;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let service_type = env::args().nth(1).unwrap_or(SERVICE_TYPES.to_string());
+    let listing_types = service_type.trim_end_matches('.') == SERVICE_TYPES;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let removed = tx.clone();
+    let mut browser = Browser::new(&service_type)
+        .on_added(move |name| {
+            let _ = tx.send((name.to_string(), true));
+        })
+        .on_removed(move |name| {
+            let _ = removed.send((name.to_string(), false));
+        });
+    tokio::spawn(async move {
+        if let Err(e) = browser.run().await {
+            eprintln!("browsing failed: {}", e);
+        }
+    });
+
+    while let Some((name, added)) = rx.recv().await {
+        if !added {
+            println!("- {}", name);
+        } else if listing_types {
+            println!("+ {}", name);
+        } else {
+            match dns_sd::resolve(&name, Duration::from_secs(1)).await? {
+                Some(service) => println!(
+                    "+ {} at {}:{} {:?} {:?}",
+                    name, service.host, service.port, service.addrs, service.txt
+                ),
+                None => println!("+ {}, not resolved", name),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+// manages the DNSSEC keys the authoritative example signs zones with, as BIND's key files:
+// cargo run --example dnssec_keys -- generate <zone> [--ksk] [--algorithm <name>]
+//     [--ttl <ttl>] [--dir <directory>]
+// cargo run --example dnssec_keys -- ds <key file>...
+// cargo run --example dnssec_keys -- rollover <key file> [--interval <duration>]
+// generate makes a key that's published and signs right away, a zone signing key unless
+// --ksk is given. ds prints the DS records to hand to the parent zone. rollover makes a
+// successor for a key and records when it takes over in both keys' files: zone signing keys
+// are pre-published, key signing keys double-sign until the old one goes. the server picks up
+// the new key when restarted with it, and goes through the steps on its own from there.
+use dns_server::dnssec::{self, SECURE_ENTRY_POINT, ZONE_KEY};
+use dns_server::presentation::parse_ttl;
+use dns_server::signature::{Algorithm, SigningKey};
+use dns_server::signer::{KeyTiming, Rollover, ZoneKey};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const USAGE: &str = "usage: dnssec_keys generate <zone> [--ksk] [--algorithm <name>] \
+                     [--ttl <ttl>] [--dir <directory>]\n\
+                     \x20      dnssec_keys ds <key file>...\n\
+                     \x20      dnssec_keys rollover <key file> [--interval <duration>]";
+// rfc 8624 section 3.1 recommends it for signing
+const DEFAULT_ALGORITHM: Algorithm = Algorithm::EcdsaP256Sha256;
+const DEFAULT_TTL: u32 = 3600;
+// long enough for the new key to reach caches and secondaries before it's used
+const ZSK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+// the new DS record has to be at the parent, and old ones out of caches, by the end of it
+const KSK_INTERVAL: Duration = Duration::from_secs(7 * 24 * 3600);
+// SHA-256, rfc 4509
+const DS_DIGEST: u8 = 2;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("generate") => generate(args),
+        Some("ds") => {
+            for path in args {
+                print_ds(&ZoneKey::load(path)?)?;
+            }
+            Ok(())
+        }
+        Some("rollover") => rollover(args),
+        _ => anyhow::bail!(USAGE),
+    }
+}
+
+fn generate(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let Some(zone) = args.next() else {
+        anyhow::bail!(USAGE);
+    };
+    let mut flags = ZONE_KEY;
+    let mut algorithm = DEFAULT_ALGORITHM;
+    let mut ttl = DEFAULT_TTL;
+    let mut dir = PathBuf::from(".");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ksk" => flags |= SECURE_ENTRY_POINT,
+            "--algorithm" => {
+                let name = args.next().unwrap_or_default();
+                algorithm = Algorithm::from_name(&name)
+                    .ok_or_else(|| anyhow::anyhow!("unsupported algorithm {:?}", name))?;
+            }
+            "--ttl" => ttl = parse_ttl(&args.next().unwrap_or_default())?,
+            "--dir" => dir = args.next().unwrap_or_default().into(),
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+
+    let mut key = ZoneKey::new(&zone, flags, ttl, SigningKey::generate(algorithm));
+    key.set_timing(KeyTiming::immediate(dnssec::now()));
+    println!("{}", key.save(&dir)?.display());
+    Ok(())
+}
+
+fn print_ds(key: &ZoneKey) -> anyhow::Result<()> {
+    if !key.is_key_signing() {
+        eprintln!(
+            "warning: key {} is a zone signing key, the parent should point at a key signing key",
+            key.key_tag()
+        );
+    }
+    let ds = key
+        .ds(DS_DIGEST)
+        .ok_or_else(|| anyhow::anyhow!("can't make a DS record for key {}", key.key_tag()))?;
+    println!("{}", ds);
+    Ok(())
+}
+
+fn rollover(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let Some(path) = args.next() else {
+        anyhow::bail!(USAGE);
+    };
+    let mut interval = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval" => {
+                let secs = parse_ttl(&args.next().unwrap_or_default())?;
+                interval = Some(Duration::from_secs(secs as u64));
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+    // the new key's files go next to the old one's
+    let dir = PathBuf::from(&path)
+        .parent()
+        .map(PathBuf::from)
+        .unwrap_or_default();
+
+    let mut old = ZoneKey::load(&path)?;
+    let key = SigningKey::generate(old.algorithm());
+    let mut new = ZoneKey::new(old.zone(), old.flags(), old.dnskey().ttl(), key);
+    let now = dnssec::now();
+    new.set_timing(KeyTiming {
+        created: Some(now),
+        ..KeyTiming::default()
+    });
+    let (rollover, interval) = if old.is_key_signing() {
+        (Rollover::DoubleSignature, interval.unwrap_or(KSK_INTERVAL))
+    } else {
+        (Rollover::PrePublish, interval.unwrap_or(ZSK_INTERVAL))
+    };
+    rollover.schedule(&mut old, &mut new, now, interval);
+
+    println!("{}", new.save(&dir)?.display());
+    old.save(&dir)?;
+    let interval = describe(interval);
+    match rollover {
+        Rollover::PrePublish => println!(
+            "key {} takes over from key {} in {}, which is removed {} after that",
+            new.key_tag(),
+            old.key_tag(),
+            interval,
+            interval
+        ),
+        Rollover::DoubleSignature => {
+            println!(
+                "key {} signs next to key {}, which is removed in {}. the parent needs \
+                 this DS record before then:",
+                new.key_tag(),
+                old.key_tag(),
+                interval
+            );
+            print_ds(&new)?;
+        }
+    }
+    Ok(())
+}
+
+// the interval in the largest unit it's a whole number of
+fn describe(interval: Duration) -> String {
+    let secs = interval.as_secs();
+    if secs.is_multiple_of(86400) {
+        format!("{} days", secs / 86400)
+    } else if secs.is_multiple_of(3600) {
+        format!("{} hours", secs / 3600)
+    } else {
+        format!("{} seconds", secs)
+    }
+}
@@ -0,0 +1,36 @@
+// prints a cache snapshot in zone file syntax: cargo run --example dump_cache <snapshot>
+use dns_server::snapshot::Snapshot;
+use std::env;
+use std::time::UNIX_EPOCH;
+
+fn main() -> anyhow::Result<()> {
+    let Some(path) = env::args().nth(1) else {
+        anyhow::bail!("usage: dump_cache <snapshot>");
+    };
+    let snapshot = Snapshot::read(&path)?;
+    let saved_at = snapshot.saved_at.duration_since(UNIX_EPOCH)?.as_secs();
+    println!(
+        "; {} entries, saved at {}",
+        snapshot.entries.len(),
+        saved_at
+    );
+
+    for entry in &snapshot.entries {
+        println!();
+        println!(
+            "; {} {} class {}: {:?}, {}s left",
+            entry.key.name,
+            entry.key.qtype,
+            entry.key.class,
+            entry.answer.rcode,
+            entry.ttl.as_secs()
+        );
+        for rec in &entry.answer.answers {
+            println!("{}", rec);
+        }
+        for rec in &entry.answer.authorities {
+            println!("{} ; authority", rec);
+        }
+    }
+    Ok(())
+}
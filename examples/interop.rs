@@ -0,0 +1,165 @@
+// checks the client against live public resolvers and prints what worked. it needs network
+// access, so it's an example rather than a test: cargo run --example interop [resolver...]
+use dns_server::client::Client;
+use dns_server::net::parse_socket_addr;
+use dns_server::structure::{DnsRecord, DEFAULT_EDNS_PAYLOAD};
+use dns_server::{DnsPacket, QueryType, Result, ResultCode};
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const DEFAULT_RESOLVERS: [&str; 3] = ["8.8.8.8", "1.1.1.1", "9.9.9.9"];
+
+struct Check {
+    name: &'static str,
+    query: fn() -> DnsPacket,
+    // the answer is fine when this returns None, otherwise it says what's wrong
+    run: fn(&DnsPacket) -> Option<String>,
+    tcp: bool,
+}
+
+fn has_answers(res: &DnsPacket) -> Option<String> {
+    if res.header.rcode != ResultCode::NOERROR {
+        return Some(format!("rcode {:?}", res.header.rcode));
+    }
+    if res.answers.is_empty() {
+        return Some("no answers".into());
+    }
+    None
+}
+
+fn checks() -> Vec<Check> {
+    vec![
+        Check {
+            name: "udp with edns",
+            query: || DnsPacket::query("example.com", QueryType::A).build(),
+            run: |res| {
+                has_answers(res).or_else(|| match res.edns() {
+                    Some(Ok(_)) => None,
+                    Some(Err(e)) => Some(format!("bad OPT record: {}", e)),
+                    None => Some("no OPT record in the response".into()),
+                })
+            },
+            tcp: false,
+        },
+        Check {
+            name: "udp without edns",
+            query: || {
+                DnsPacket::query("example.com", QueryType::A)
+                    .edns(None)
+                    .build()
+            },
+            run: has_answers,
+            tcp: false,
+        },
+        Check {
+            name: "mixed case name",
+            query: || DnsPacket::query("ExAmPlE.CoM", QueryType::A).build(),
+            run: has_answers,
+            tcp: false,
+        },
+        Check {
+            // MX answers compress the exchange names against the question
+            name: "compressed rdata",
+            query: || DnsPacket::query("gmail.com", QueryType::MX).build(),
+            run: |res| {
+                has_answers(res).or_else(|| {
+                    let mx = res
+                        .answers
+                        .iter()
+                        .any(|rec| matches!(rec, DnsRecord::MX { host, .. } if !host.is_empty()));
+                    (!mx).then(|| "no MX record with a host".into())
+                })
+            },
+            tcp: false,
+        },
+        Check {
+            name: "tcp",
+            query: || DnsPacket::query("example.com", QueryType::A).build(),
+            run: has_answers,
+            tcp: true,
+        },
+        Check {
+            // a small payload size makes the TXT answer truncate, the client has to retry
+            // over tcp to get all of it
+            name: "tc fallback",
+            query: || {
+                DnsPacket::query("google.com", QueryType::TXT)
+                    .edns(Some(512))
+                    .build()
+            },
+            run: has_answers,
+            tcp: false,
+        },
+        Check {
+            name: "dnssec ok",
+            query: || {
+                DnsPacket::query("example.com", QueryType::A)
+                    .edns(Some(DEFAULT_EDNS_PAYLOAD))
+                    .dnssec_ok(true)
+                    .build()
+            },
+            run: |res| {
+                has_answers(res).or_else(|| {
+                    // RRSIG
+                    let signed = res
+                        .answers
+                        .iter()
+                        .any(|rec| rec.qtype() == QueryType::UNKNOWN(46));
+                    (!signed).then(|| "no signatures in the answer".into())
+                })
+            },
+            tcp: false,
+        },
+    ]
+}
+
+async fn run(client: &Client, resolver: SocketAddr, check: &Check) -> Result<Option<String>> {
+    let mut query = (check.query)();
+    let res = if check.tcp {
+        client.query_tcp(resolver, &mut query).await?
+    } else {
+        client.query(resolver, &mut query).await?
+    };
+    Ok((check.run)(&res))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let resolvers = if args.is_empty() {
+        DEFAULT_RESOLVERS.iter().map(|s| s.to_string()).collect()
+    } else {
+        args
+    };
+    let resolvers = resolvers
+        .iter()
+        .map(|s| parse_socket_addr(s, 53))
+        .collect::<Result<Vec<_>>>()?;
+
+    let client = Client::new().timeout(Duration::from_secs(5));
+    let checks = checks();
+    let mut failures = 0;
+    for resolver in resolvers {
+        println!("{}", resolver);
+        for check in &checks {
+            let outcome = match run(&client, resolver, check).await {
+                Ok(None) => "ok".to_string(),
+                Ok(Some(problem)) => {
+                    failures += 1;
+                    format!("FAIL ({})", problem)
+                }
+                Err(e) => {
+                    failures += 1;
+                    format!("ERROR ({})", e)
+                }
+            };
+            println!("  {:<20} {}", check.name, outcome);
+        }
+    }
+
+    if failures > 0 {
+        anyhow::bail!("{} checks failed", failures);
+    }
+    Ok(())
+}
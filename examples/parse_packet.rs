@@ -0,0 +1,29 @@
+use anyhow::Result;
+use dns_server::{BytePacketBuffer, DnsPacket, MAX_MESSAGE_SIZE};
+use std::fs::File;
+use std::io::Read;
+
+// prints a packet captured to response_packet.txt, run from the repository root
+fn main() -> Result<()> {
+    let mut f = File::open("response_packet.txt")?;
+    let mut buffer = BytePacketBuffer::with_len(MAX_MESSAGE_SIZE);
+    let len = f.read(&mut buffer.buf[..])?;
+
+    let packet = DnsPacket::from_message(&mut buffer, len)?;
+    println!("{:#?}", packet.header);
+
+    for q in packet.questions {
+        println!("{}", q);
+    }
+    for rec in packet.answers {
+        println!("{}", rec);
+    }
+    for rec in packet.authorities {
+        println!("{}", rec);
+    }
+    for rec in packet.additional {
+        println!("{}", rec);
+    }
+
+    Ok(())
+}
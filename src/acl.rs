@@ -0,0 +1,240 @@
+// who may do what, by client address. each capability (querying, getting names looked up
+// beyond our own data, transferring zones, updating them) has a list of subnets allowed and
+// denied, and the most specific subnet a client is in decides, denial winning a tie. a client
+// in none of them is denied. requests are checked before anything else is done with them
+// and refused otherwise, with an extended error saying so. an open resolver gets used to
+// flood others with answers, so by default only loopback and private addresses get
+// recursion, and nobody gets transfers or updates.
+use crate::edns::EdeCode;
+use crate::net::Subnet;
+use crate::server::Handler;
+use crate::structure::{DnsPacket, Opcode, QueryType, ResultCode};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Something a client can be allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Asking anything at all.
+    Query,
+    /// Having names looked up that aren't in our own zones and local records.
+    Recursion,
+    /// AXFR and IXFR.
+    Transfer,
+    /// Dynamic updates (rfc 2136).
+    Update,
+}
+
+impl Capability {
+    /// What `request` needs, on top of being allowed to query.
+    pub fn of(request: &DnsPacket) -> Capability {
+        if request.header.flags.opcode == Opcode::UPDATE {
+            return Capability::Update;
+        }
+        match request.questions.first().map(|question| question.qtype) {
+            Some(QueryType::AXFR | QueryType::IXFR) => Capability::Transfer,
+            _ => Capability::Query,
+        }
+    }
+}
+
+/// The subnets allowed and denied something.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessList {
+    allow: Vec<Subnet>,
+    deny: Vec<Subnet>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Subnet>, deny: Vec<Subnet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Allows nobody.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Allows everybody.
+    pub fn any() -> Self {
+        Self::new(everywhere().to_vec(), Vec::new())
+    }
+
+    /// Allows loopback and private addresses, the clients on our own networks.
+    pub fn local() -> Self {
+        Self::new(local_nets(), Vec::new())
+    }
+
+    pub fn allow(mut self, net: Subnet) -> Self {
+        self.allow.push(net);
+        self
+    }
+
+    pub fn deny(mut self, net: Subnet) -> Self {
+        self.deny.push(net);
+        self
+    }
+
+    pub fn allowed(&self) -> &[Subnet] {
+        &self.allow
+    }
+
+    pub fn denied(&self) -> &[Subnet] {
+        &self.deny
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        let closest = |nets: &[Subnet]| {
+            nets.iter()
+                .filter(|net| net.contains(client))
+                .map(|net| net.prefix())
+                .max()
+        };
+        match (closest(&self.allow), closest(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (allow, _) => allow.is_some(),
+        }
+    }
+}
+
+// loopback, rfc 1918, shared address space (rfc 6598), link-local and unique local addresses
+const LOCAL_NETS: [&str; 9] = [
+    "127.0.0.0/8",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "100.64.0.0/10",
+    "169.254.0.0/16",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// Loopback and private addresses.
+pub fn local_nets() -> Vec<Subnet> {
+    LOCAL_NETS.iter().map(|net| net.parse().unwrap()).collect()
+}
+
+/// The whole of ipv4 and ipv6.
+pub fn everywhere() -> [Subnet; 2] {
+    [
+        Subnet::new(Ipv4Addr::UNSPECIFIED.into(), 0).unwrap(),
+        Subnet::new(Ipv6Addr::UNSPECIFIED.into(), 0).unwrap(),
+    ]
+}
+
+/// An access list for each capability.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Acl {
+    query: AccessList,
+    recursion: AccessList,
+    transfer: AccessList,
+    update: AccessList,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl::new()
+    }
+}
+
+impl Acl {
+    /// Queries from anybody, recursion for local clients, and no transfers or updates.
+    pub fn new() -> Self {
+        Self {
+            query: AccessList::any(),
+            recursion: AccessList::local(),
+            transfer: AccessList::none(),
+            update: AccessList::none(),
+        }
+    }
+
+    pub fn set(mut self, capability: Capability, list: AccessList) -> Self {
+        *self.list_mut(capability) = list;
+        self
+    }
+
+    pub fn list(&self, capability: Capability) -> &AccessList {
+        match capability {
+            Capability::Query => &self.query,
+            Capability::Recursion => &self.recursion,
+            Capability::Transfer => &self.transfer,
+            Capability::Update => &self.update,
+        }
+    }
+
+    pub fn list_mut(&mut self, capability: Capability) -> &mut AccessList {
+        match capability {
+            Capability::Query => &mut self.query,
+            Capability::Recursion => &mut self.recursion,
+            Capability::Transfer => &mut self.transfer,
+            Capability::Update => &mut self.update,
+        }
+    }
+
+    /// Whether `client` may do `capability`. Everything but querying needs the client to be
+    /// allowed to query too.
+    pub fn allows(&self, capability: Capability, client: IpAddr) -> bool {
+        self.query.allows(client) && self.list(capability).allows(client)
+    }
+
+    /// A REFUSED response to `request` if `client` isn't allowed to do what it asks.
+    pub fn check(&self, request: &DnsPacket, client: IpAddr) -> Option<DnsPacket> {
+        let capability = Capability::of(request);
+        if self.allows(capability, client) {
+            None
+        } else {
+            Some(refused(request, capability))
+        }
+    }
+}
+
+/// A REFUSED response to `request` for not being allowed `capability`.
+pub fn refused(request: &DnsPacket, capability: Capability) -> DnsPacket {
+    let mut res = DnsPacket::response_to(request);
+    res.set_rcode(ResultCode::REFUSED);
+    let edns = DnsPacket::response_edns(request);
+    res.set_edns(edns.as_ref());
+    if edns.is_some() {
+        let reason = match capability {
+            Capability::Query => "queries",
+            Capability::Recursion => "recursion",
+            Capability::Transfer => "zone transfers",
+            Capability::Update => "updates",
+        };
+        let _ = res.add_extended_error(EdeCode::Prohibited, &format!("{} not allowed", reason));
+    }
+    res
+}
+
+/// Refuses the requests [`Acl::check`] doesn't let through before `inner` sees them.
+/// Recursion is up to `inner`, which is the one that knows what it answers itself.
+pub struct Restricted<H> {
+    inner: H,
+    acl: Acl,
+}
+
+impl<H: Handler> Restricted<H> {
+    pub fn new(inner: H, acl: Acl) -> Self {
+        Self { inner, acl }
+    }
+
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+}
+
+impl<H: Handler> Handler for Restricted<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        if let Some(refused) = self.acl.check(&request, src.ip()) {
+            return Some(refused);
+        }
+        self.inner.handle(request, src).await
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        if !self.acl.allows(Capability::Transfer, src.ip()) {
+            return Some(vec![refused(request, Capability::Transfer)]);
+        }
+        self.inner.transfer(request, src).await
+    }
+}
@@ -0,0 +1,874 @@
+// answering from zones we're authoritative for. the question goes to the most specific zone
+// containing it. a name with records of the asked for type gets them, a name that exists
+// without them gets NODATA, and a name that doesn't exist at all gets NXDOMAIN, both negative
+// answers carrying the zone's SOA so resolvers know how long to cache them (rfc 2308). names
+// outside every zone are refused, we're not a resolver. names that don't exist can still be
+// answered from a wildcard (rfc 4592), and CNAMEs that stay inside the zone are followed, as
+// are the ones made up for names below a DNAME (rfc 6672), which go out along with the DNAME.
+// names at or below a delegation get a referral to the child zone's nameservers instead. whole
+// zones can be transferred over tcp (AXFR, rfc 5936) by secondaries the acl lets (see acl.rs),
+// optionally only with a TSIG key, and each zone keeps a journal of its changes so secondaries
+// that are only a few versions behind can get just those (IXFR, rfc 1995). queries with DO set
+// get the signatures of signed zones along with the records, and negative answers, wildcard
+// answers and referrals the NSEC or NSEC3 records that prove them (rfc 4035 section 3.1). the
+// records of each RRset in an answer can be reordered from one response to the next to spread
+// clients over the addresses, see rotation.rs, and addresses failing their health checks left
+// out, see health.rs. earlier versions of each zone are kept to roll back to, see versions.rs.
+use crate::dnssec;
+use crate::error::{DnsError, Result};
+use crate::health::HealthChecks;
+use crate::journal::{serial_newer, soa_serial, Journal, ZoneDiff, DEFAULT_JOURNAL_SIZE};
+use crate::logging;
+use crate::rotation::Rotator;
+use crate::server::Handler;
+use crate::structure::{
+    is_subdomain, BytePacketBuffer, DnsPacket, DnsRecord, Opcode, QueryType, ResultCode,
+};
+use crate::tsig;
+use crate::versions::ZoneVersions;
+use crate::zone::Zone;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+
+// longer chains are almost certainly a loop
+const MAX_CNAME_CHAIN: usize = 8;
+// how many bytes of records go into each message of a transfer, well below the 64k limit so
+// there's room for the header, the question and whatever gets appended to sign it
+const TRANSFER_MESSAGE_SIZE: usize = 16 * 1024;
+
+pub struct Authority {
+    zones: RwLock<Vec<Arc<Zone>>>,
+    // origins of zones we serve but have no copy of, see add_pending
+    pending: RwLock<Vec<String>>,
+    transfer_keys: Option<Vec<String>>,
+    journals: Mutex<HashMap<String, Journal>>,
+    journal_size: usize,
+    rotator: Rotator,
+    health: Option<Arc<HealthChecks>>,
+    versions: Arc<ZoneVersions>,
+}
+
+impl Authority {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        let authority = Self {
+            zones: RwLock::new(Vec::new()),
+            pending: RwLock::new(Vec::new()),
+            transfer_keys: None,
+            journals: Mutex::new(HashMap::new()),
+            journal_size: DEFAULT_JOURNAL_SIZE,
+            rotator: Rotator::default(),
+            health: None,
+            versions: Arc::default(),
+        };
+        for zone in zones {
+            authority.add_zone(zone);
+        }
+        authority
+    }
+
+    /// Only allows zone transfers signed with one of the TSIG keys named in `keys`, on top of
+    /// the clients the server's acl lets transfer zones (see acl.rs). The server checks the
+    /// signature, so it needs the keys too.
+    pub fn require_transfer_key(mut self, keys: Vec<String>) -> Self {
+        let keys = keys
+            .iter()
+            .map(|key| key.trim_end_matches('.').to_lowercase())
+            .collect();
+        self.transfer_keys = Some(keys);
+        self
+    }
+
+    /// How many changes to keep for each zone for incremental transfers.
+    pub fn journal_size(mut self, size: usize) -> Self {
+        self.journal_size = size;
+        // the zones given to new already have journals, without any history yet
+        for journal in self.journals.get_mut().unwrap().values_mut() {
+            *journal = Journal::new(size);
+        }
+        self
+    }
+
+    /// Orders the records of the RRsets in answers, see [`Rotator`]. Without this they go
+    /// out in the order of the zone.
+    pub fn rotation(mut self, rotator: Rotator) -> Self {
+        self.rotator = rotator;
+        self
+    }
+
+    /// Leaves addresses that fail their checks out of answers. The checks are run by
+    /// [`HealthChecks::run`].
+    pub fn health_checks(mut self, health: Arc<HealthChecks>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Keeps the versions of the zones in `versions`, which can be shared with the authority
+    /// this one replaces so a rollback holds and older versions are still there, see
+    /// [`ZoneVersions`]. Without this each authority keeps its own.
+    pub fn zone_versions(mut self, versions: Arc<ZoneVersions>) -> Self {
+        self.versions = versions;
+        // the zones given to new may be versions rolled back from
+        for zone in self.zones.get_mut().unwrap() {
+            *zone = self.versions.admit(zone.clone());
+        }
+        self
+    }
+
+    /// Adds a zone, replacing any zone with the same origin. Queries already being answered
+    /// finish with the zone they started with. Replacing a zone with a newer version keeps
+    /// what changed in its journal. A version rolled back from isn't added, see
+    /// [`ZoneVersions`].
+    pub fn add_zone(&self, zone: Zone) {
+        let occluded: Vec<&DnsRecord> = zone.occluded().collect();
+        if let [first, ..] = occluded[..] {
+            logging::warning(&format!(
+                "zone {} has {} records below a delegation or DNAME that are never served, e.g. {}",
+                zone.origin(),
+                occluded.len(),
+                first
+            ));
+        }
+        self.pending.write().unwrap().retain(|o| o != zone.origin());
+        let zone = self.versions.admit(Arc::new(zone));
+        self.serve(zone);
+    }
+
+    /// Serves an earlier version of the zone at `origin` and returns it: the one with
+    /// `serial`, or without one the version before the one being served. See
+    /// [`ZoneVersions`] for how long it's served.
+    pub fn rollback(&self, origin: &str, serial: Option<u32>) -> Result<Arc<Zone>> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        if !self.zones().iter().any(|zone| zone.origin() == origin) {
+            return Err(DnsError::Control(format!("no zone {}", origin)));
+        }
+        let zone = self.versions.rollback(&origin, serial)?;
+        logging::info(&format!(
+            "zone {} rolled back to serial {}",
+            origin,
+            zone.serial().unwrap_or_default()
+        ));
+        self.serve(zone.clone());
+        Ok(zone)
+    }
+
+    /// The serials of the versions of the zone at `origin` that are kept, the one being
+    /// served first.
+    pub fn versions(&self, origin: &str) -> Vec<Option<u32>> {
+        self.versions.serials(origin)
+    }
+
+    // serves `zone` in place of any zone with the same origin
+    fn serve(&self, zone: Arc<Zone>) {
+        let mut zones = self.zones.write().unwrap();
+        let old = zones
+            .iter()
+            .position(|z| z.origin() == zone.origin())
+            .map(|i| zones.remove(i));
+        self.record_change(old.as_deref(), &zone);
+        zones.push(zone);
+        // most specific first, so the first match is the closest zone
+        zones.sort_by_key(|z| std::cmp::Reverse(dnssec::label_count(z.origin())));
+    }
+
+    /// Stops serving the zone at `origin`, returning it if there was one.
+    pub fn remove_zone(&self, origin: &str) -> Option<Arc<Zone>> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let mut zones = self.zones.write().unwrap();
+        let i = zones.iter().position(|z| z.origin() == origin)?;
+        self.journals.lock().unwrap().remove(&origin);
+        Some(zones.remove(i))
+    }
+
+    /// Answers the names in the zone at `origin` with SERVFAIL until the zone is added, for a
+    /// zone that's ours but that we don't have a copy of, like a secondary zone before its
+    /// first transfer. A more specific zone that is there still answers for its names.
+    pub fn add_pending(&self, origin: &str) {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let mut pending = self.pending.write().unwrap();
+        if !pending.contains(&origin) {
+            pending.push(origin);
+        }
+    }
+
+    // whether `name` is in a pending zone more specific than any zone we have
+    fn is_pending(&self, name: &str) -> bool {
+        let served = self
+            .find(name)
+            .map(|zone| dnssec::label_count(zone.origin()));
+        let pending = self.pending.read().unwrap();
+        pending.iter().any(|origin| {
+            is_subdomain(name, origin)
+                && served.is_none_or(|served| dnssec::label_count(origin) > served)
+        })
+    }
+
+    // a newer version goes in the journal, anything else means the history no longer leads
+    // up to what's being served and starts over
+    fn record_change(&self, old: Option<&Zone>, new: &Zone) {
+        let newer = |old: &&Zone| match (new.serial(), old.serial()) {
+            (Some(new), Some(old)) => serial_newer(new, old),
+            _ => false,
+        };
+        let diff = old
+            .filter(newer)
+            .and_then(|old| ZoneDiff::between(old, new));
+
+        let mut journals = self.journals.lock().unwrap();
+        let journal = journals
+            .entry(new.origin().to_string())
+            .or_insert_with(|| Journal::new(self.journal_size));
+        match diff {
+            Some(diff) => journal.push(diff),
+            None => journal.clear(),
+        }
+    }
+
+    pub fn zones(&self) -> Vec<Arc<Zone>> {
+        self.zones.read().unwrap().clone()
+    }
+
+    /// The zone `name` falls into, if any.
+    pub fn find(&self, name: &str) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().unwrap();
+        zones
+            .iter()
+            .find(|z| is_subdomain(name, z.origin()))
+            .cloned()
+    }
+
+    /// The authoritative response for `request`, or None if its question isn't in any of our
+    /// zones. SERVFAIL for questions in zones that are pending, see [`Authority::add_pending`].
+    pub fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if self.is_pending(&question.name) {
+            let mut res = DnsPacket::response_to(request);
+            res.set_rcode(ResultCode::SERVFAIL);
+            res.set_edns(DnsPacket::response_edns(request).as_ref());
+            return Some(res);
+        }
+        let mut res = self.respond(request)?;
+        if let Some(health) = &self.health {
+            health.filter(&mut res);
+        }
+        self.rotator.apply(&mut res);
+        Some(res)
+    }
+
+    // the response for `request`, with the records in zone order
+    fn respond(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        let mut zone = self.find(&question.name)?;
+        // DS records are the parent's, so the zone above answers for them at our apexes
+        if question.qtype == QueryType::DS && zone.origin() == question.name.to_lowercase() {
+            let parent = question
+                .name
+                .split_once('.')
+                .map_or("", |(_, parent)| parent);
+            if !zone.origin().is_empty() {
+                if let Some(above) = self.find(parent) {
+                    zone = above;
+                }
+            }
+        }
+        let soa = zone.soa()?;
+        if question.class != soa.class() {
+            return None;
+        }
+
+        let mut res = DnsPacket::response_to(request);
+        res.set_authoritative(true);
+        let edns = DnsPacket::response_edns(request);
+        res.set_edns(edns.as_ref());
+        let dnssec_ok = edns.is_some_and(|edns| edns.dnssec_ok) && is_signed(&zone);
+
+        // aliases are followed as long as they stay in the zone, each hop going into the
+        // answer. the rcode is the one for the last name in the chain (rfc 6604).
+        let mut name = question.name.to_lowercase();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let cut = zone
+                .delegation(&name)
+                .filter(|cut| question.qtype != QueryType::DS || *cut != name);
+            // a DNAME redirects the names below it, unless it's in the child of a cut above it.
+            // the answer is the DNAME and the CNAME it makes for the name (rfc 6672 section 3.2)
+            let dname = zone
+                .dname(&name)
+                .filter(|dname| cut.is_none_or(|cut| !is_subdomain(dname.domain(), cut)))
+                .cloned();
+            if let Some(dname) = dname {
+                let owner = dname.domain().to_string();
+                let cname = dname.synthesize_cname(&name);
+                res.add_answer(dname);
+                if dnssec_ok {
+                    for rec in signatures(&zone, &owner, QueryType::DNAME) {
+                        res.add_answer(rec);
+                    }
+                }
+                let target = match cname {
+                    Ok(Some(DnsRecord::CNAME {
+                        domain,
+                        class,
+                        ttl,
+                        host,
+                    })) => {
+                        res.add_answer(DnsRecord::CNAME {
+                            domain,
+                            class,
+                            ttl,
+                            host: host.clone(),
+                        });
+                        host
+                    }
+                    // the new name is too long to exist (rfc 6672 section 2.2)
+                    Err(_) => {
+                        res.set_rcode(ResultCode::YXDOMAIN);
+                        return Some(res);
+                    }
+                    _ => return Some(res),
+                };
+                let seen = |target: &str| res.answers.iter().any(|rec| rec.domain() == target);
+                if !is_subdomain(&target, zone.origin()) || seen(&target) {
+                    return Some(res);
+                }
+                name = target;
+                continue;
+            }
+            if let Some(cut) = cut {
+                // the answer isn't ours to give. a referral to the question itself isn't
+                // authoritative, one at the end of a chain leaves the aliases before it as is.
+                if res.answers.is_empty() {
+                    res.set_authoritative(false);
+                }
+                referral(&zone, cut, &mut res);
+                if dnssec_ok {
+                    // the DS records, or the proof there are none and the child is unsigned
+                    match zone.rrset(cut, QueryType::DS).next() {
+                        Some(_) => {
+                            for rec in zone.rrset(cut, QueryType::DS).cloned() {
+                                res.add_authority(rec);
+                            }
+                            for rec in signatures(&zone, cut, QueryType::DS) {
+                                res.add_authority(rec);
+                            }
+                        }
+                        None => {
+                            for rec in denial(&zone, cut, Proof::NoData) {
+                                res.add_authority(rec);
+                            }
+                        }
+                    }
+                }
+                return Some(res);
+            }
+            match lookup(&zone, &name, question.qtype, dnssec_ok) {
+                Lookup::Answer { records, wildcard } => {
+                    let target = records.iter().find_map(|rec| match rec {
+                        DnsRecord::CNAME { host, .. } if question.qtype != QueryType::CNAME => {
+                            Some(host.clone())
+                        }
+                        _ => None,
+                    });
+                    for rec in records {
+                        res.add_answer(rec);
+                    }
+                    // an answer made up from a wildcard needs proof there was nothing closer
+                    if dnssec_ok && wildcard {
+                        for rec in denial(&zone, &name, Proof::Wildcard) {
+                            res.add_authority(rec);
+                        }
+                    }
+                    // a target already in the answer is a loop, stop there
+                    let seen = |target: &str| res.answers.iter().any(|rec| rec.domain() == target);
+                    match target {
+                        Some(target) if is_subdomain(&target, zone.origin()) && !seen(&target) => {
+                            name = target
+                        }
+                        _ => return Some(res),
+                    }
+                }
+                Lookup::NoData { wildcard } => {
+                    add_negative_soa(&zone, soa, dnssec_ok, &mut res);
+                    if dnssec_ok {
+                        let proof = if wildcard {
+                            Proof::NxDomain
+                        } else {
+                            Proof::NoData
+                        };
+                        for rec in denial(&zone, &name, proof) {
+                            res.add_authority(rec);
+                        }
+                    }
+                    return Some(res);
+                }
+                Lookup::NxDomain => {
+                    res.set_rcode(ResultCode::NXDOMAIN);
+                    add_negative_soa(&zone, soa, dnssec_ok, &mut res);
+                    if dnssec_ok {
+                        for rec in denial(&zone, &name, Proof::NxDomain) {
+                            res.add_authority(rec);
+                        }
+                    }
+                    return Some(res);
+                }
+            }
+        }
+        Some(res)
+    }
+
+    /// The messages of a zone transfer for `request`, split over as many messages as it
+    /// takes. For AXFR that's the zone's SOA, every other record and the SOA again. IXFR gets
+    /// the changes since the serial of the SOA in the request's authority section when the
+    /// journal has them, and the whole zone like AXFR when it doesn't. A single message with
+    /// an error rcode if the client isn't allowed to or the zone isn't ours.
+    pub fn transfer(&self, request: &DnsPacket) -> Vec<DnsPacket> {
+        let mut res = DnsPacket::response_to(request);
+        let Some(question) = request.questions.first() else {
+            res.set_rcode(ResultCode::FORMERR);
+            return vec![res];
+        };
+        let allowed = self.transfer_keys.as_ref().is_none_or(|keys| {
+            tsig::signer(request).is_some_and(|signer| keys.iter().any(|key| key == signer))
+        });
+        if !allowed {
+            res.set_rcode(ResultCode::REFUSED);
+            return vec![res];
+        }
+        // only whole zones can be transferred, the question has to be the apex
+        let name = question.name.trim_end_matches('.').to_lowercase();
+        let zone = self.find(&name).filter(|z| z.origin() == name);
+        let Some((zone, soa)) = zone.as_ref().and_then(|z| Some((z, z.soa()?))) else {
+            res.set_rcode(ResultCode::NOTAUTH);
+            return vec![res];
+        };
+
+        res.set_authoritative(true);
+        res.set_edns(DnsPacket::response_edns(request).as_ref());
+        let records = match question.qtype {
+            QueryType::IXFR => self.changes(zone, soa, request),
+            _ => None,
+        };
+        let records = records.unwrap_or_else(|| {
+            std::iter::once(soa)
+                .chain(zone.records().filter(|rec| rec.qtype() != QueryType::SOA))
+                .chain(std::iter::once(soa))
+                .cloned()
+                .collect()
+        });
+
+        // only the first message repeats the question (rfc 5936 section 2.2.1)
+        let mut next = res.clone();
+        next.questions.clear();
+        next.set_edns(None);
+
+        let mut messages = Vec::new();
+        let mut scratch = BytePacketBuffer::new();
+        let mut size = 0;
+        for rec in records {
+            // written on its own a record takes at least as much space as it will in the
+            // message, where its names may be compressed
+            scratch.seek(0).expect("0 is within the buffer");
+            let len = rec.write(&mut scratch).unwrap_or(0);
+            if size + len > TRANSFER_MESSAGE_SIZE && !res.answers.is_empty() {
+                messages.push(std::mem::replace(&mut res, next.clone()));
+                size = 0;
+            }
+            res.add_answer(rec);
+            size += len;
+        }
+        messages.push(res);
+        messages
+    }
+
+    // the answer records of an incremental transfer (rfc 1995 section 4): just the current
+    // SOA for a client that's up to date, otherwise the current SOA, each change as the SOA it
+    // starts from, the removed records, the SOA it leads to and the added records, and the
+    // current SOA again. None if the journal doesn't reach back to the client's serial.
+    fn changes(&self, zone: &Zone, soa: &DnsRecord, request: &DnsPacket) -> Option<Vec<DnsRecord>> {
+        let theirs = request.authorities.iter().find_map(soa_serial)?;
+        if !serial_newer(zone.serial()?, theirs) {
+            return Some(vec![soa.clone()]);
+        }
+
+        let journals = self.journals.lock().unwrap();
+        let mut records = vec![soa.clone()];
+        for diff in journals.get(zone.origin())?.since(theirs)? {
+            records.push(diff.from.clone());
+            records.extend(diff.removed.iter().cloned());
+            records.push(diff.to.clone());
+            records.extend(diff.added.iter().cloned());
+        }
+        records.push(soa.clone());
+        Some(records)
+    }
+}
+
+enum Lookup {
+    /// The records of the asked for type, or the CNAME if the name is an alias, with their
+    /// signatures if asked for. `wildcard` if they were made up from a wildcard.
+    Answer {
+        records: Vec<DnsRecord>,
+        wildcard: bool,
+    },
+    /// The name exists without records of the type, or, with `wildcard`, the name doesn't
+    /// exist but a wildcard without them covers it.
+    NoData {
+        wildcard: bool,
+    },
+    NxDomain,
+}
+
+// the records for a single name in the zone. a name that doesn't exist may still be covered
+// by a wildcard, in which case the answer is made up from the wildcard's records with the name
+// asked for as the owner.
+fn lookup(zone: &Zone, name: &str, qtype: QueryType, dnssec_ok: bool) -> Lookup {
+    // NSEC3 owner names are hashes, not names in the zone (rfc 5155 section 7.2.8)
+    let records = zone.get(name).filter(|records| {
+        !records.iter().all(|rec| {
+            matches!(rec, DnsRecord::NSEC3 { .. })
+                || matches!(rec, DnsRecord::RRSIG { type_covered, .. } if *type_covered == QueryType::NSEC3)
+        })
+    });
+    let (records, owner, synthesized) = match records {
+        Some(records) => (records, name.to_string(), false),
+        None if zone.has_descendants(name) => return Lookup::NoData { wildcard: false },
+        None => match zone
+            .closest_encloser(name)
+            .map(|encloser| dnssec::wildcard(&encloser))
+        {
+            Some(wildcard) => match zone.get(&wildcard) {
+                Some(records) => (records, wildcard, true),
+                None => return Lookup::NxDomain,
+            },
+            None => return Lookup::NxDomain,
+        },
+    };
+
+    let mut rrset: Vec<DnsRecord> = records
+        .iter()
+        .filter(|rec| rec.qtype() == qtype)
+        .cloned()
+        .collect();
+    // an alias owns no other data, so whatever the type it's the answer
+    if rrset.is_empty() && qtype != QueryType::CNAME {
+        rrset = records
+            .iter()
+            .filter(|rec| rec.qtype() == QueryType::CNAME)
+            .cloned()
+            .collect();
+    }
+    if rrset.is_empty() {
+        return Lookup::NoData {
+            wildcard: synthesized,
+        };
+    }
+    if dnssec_ok && qtype != QueryType::RRSIG {
+        let qtype = rrset[0].qtype();
+        rrset.extend(signatures(zone, &owner, qtype));
+    }
+    if synthesized {
+        for rec in &mut rrset {
+            rec.set_domain(name);
+        }
+    }
+    Lookup::Answer {
+        records: rrset,
+        wildcard: synthesized,
+    }
+}
+
+// whether the zone has been signed, going by the signatures over its SOA
+fn is_signed(zone: &Zone) -> bool {
+    signatures(zone, zone.origin(), QueryType::SOA)
+        .next()
+        .is_some()
+}
+
+// the RRSIGs over the `qtype` RRset at `name`
+fn signatures<'a>(
+    zone: &'a Zone,
+    name: &str,
+    qtype: QueryType,
+) -> impl Iterator<Item = DnsRecord> + 'a {
+    zone.rrset(name, QueryType::RRSIG)
+        .filter(move |rec| matches!(rec, DnsRecord::RRSIG { type_covered, .. } if *type_covered == qtype))
+        .cloned()
+}
+
+// what a negative answer or a wildcard answer has to prove
+#[derive(Clone, Copy)]
+enum Proof {
+    // the name exists, without the type
+    NoData,
+    // the name doesn't exist. it doesn't match a wildcard either, or the wildcard it matches
+    // doesn't have the type
+    NxDomain,
+    // the name doesn't exist, so the wildcard the answer came from applies
+    Wildcard,
+}
+
+// the NSEC or NSEC3 records, with their signatures, that prove `proof` for `name`. for NSEC
+// that's the records at or covering the name and the wildcard that would have matched it
+// (rfc 4035 section 3.1.3), for NSEC3 the closest encloser proof (rfc 5155 section 7.2)
+fn denial(zone: &Zone, name: &str, proof: Proof) -> Vec<DnsRecord> {
+    let encloser = zone
+        .closest_encloser(name)
+        .unwrap_or_else(|| zone.origin().to_string());
+    let next_closer = dnssec::suffix(name, dnssec::labels(&encloser).len() + 1);
+    let wildcard = dnssec::wildcard(&encloser);
+
+    let nsec3 = zone
+        .rrset(zone.origin(), QueryType::NSEC3PARAM)
+        .find_map(|rec| match rec {
+            DnsRecord::NSEC3PARAM {
+                iterations, salt, ..
+            } => Some((*iterations, salt)),
+            _ => None,
+        });
+    let (qtype, owners) = match nsec3 {
+        None => {
+            let names = match proof {
+                Proof::NoData | Proof::Wildcard => vec![name.to_string()],
+                Proof::NxDomain => vec![name.to_string(), wildcard],
+            };
+            (QueryType::NSEC, names)
+        }
+        Some((iterations, salt)) => {
+            let owner = |name: &str| dnssec::nsec3_owner(name, zone.origin(), salt, iterations);
+            let names = match proof {
+                // a name without an NSEC3 record of its own is an unsigned delegation in an
+                // opt-out span, proven by the closest encloser proof instead
+                Proof::NoData if zone.get(&owner(name)).is_some() => vec![name],
+                Proof::NoData => vec![encloser.as_str(), &next_closer],
+                Proof::NxDomain => vec![encloser.as_str(), &next_closer, &wildcard],
+                Proof::Wildcard => vec![next_closer.as_str()],
+            };
+            (QueryType::NSEC3, names.into_iter().map(owner).collect())
+        }
+    };
+
+    let mut records: Vec<DnsRecord> = Vec::new();
+    for owner in owners {
+        let Some(rec) = zone.chain_record(&owner, qtype) else {
+            continue;
+        };
+        if records.contains(rec) {
+            continue;
+        }
+        records.push(rec.clone());
+        records.extend(signatures(zone, rec.domain(), qtype));
+    }
+    records
+}
+
+// rfc 2308 section 3, the SOA in a negative answer has the smaller of its own ttl and its
+// minimum field as its ttl, which is what the answer is cached for. its signatures go with it
+// for clients that want them
+fn add_negative_soa(zone: &Zone, soa: &DnsRecord, dnssec_ok: bool, res: &mut DnsPacket) {
+    let soa = negative_soa(soa);
+    let ttl = soa.ttl();
+    res.add_authority(soa);
+    if dnssec_ok {
+        for mut rrsig in signatures(zone, zone.origin(), QueryType::SOA) {
+            rrsig.set_ttl(ttl);
+            res.add_authority(rrsig);
+        }
+    }
+}
+
+impl Handler for Authority {
+    /// Refuses anything that isn't a standard query for a name in one of the zones.
+    async fn handle(&self, request: DnsPacket, _src: SocketAddr) -> Option<DnsPacket> {
+        if request.header.flags.opcode != Opcode::QUERY {
+            let mut res = DnsPacket::response_to(&request);
+            res.set_rcode(ResultCode::NOTIMP);
+            return Some(res);
+        }
+        // transfers are for tcp, where they go to transfer instead
+        match request.questions.first().map(|q| q.qtype) {
+            Some(QueryType::AXFR) => {
+                let mut res = DnsPacket::response_to(&request);
+                res.set_rcode(ResultCode::NOTIMP);
+                return Some(res);
+            }
+            // over udp an incremental transfer only gets the current SOA, which tells the
+            // client to come back over tcp unless it's up to date (rfc 1995 section 2)
+            Some(QueryType::IXFR) => {
+                let mut res = Authority::transfer(self, &request).swap_remove(0);
+                res.answers.truncate(1);
+                return Some(res);
+            }
+            _ => {}
+        }
+        if let Some(res) = self.answer(&request) {
+            return Some(res);
+        }
+        let mut res = DnsPacket::response_to(&request);
+        res.set_rcode(if request.questions.is_empty() {
+            ResultCode::FORMERR
+        } else {
+            ResultCode::REFUSED
+        });
+        Some(res)
+    }
+
+    // which clients may transfer zones is up to the acl in front of this
+    async fn transfer(&self, request: &DnsPacket, _src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        Some(Authority::transfer(self, request))
+    }
+}
+
+// the child's NS set goes in the authority section, with the addresses we have for those of
+// its nameservers inside our zone as glue (rfc 1034 section 4.3.2). without glue a resolver
+// couldn't reach nameservers named below the cut.
+fn referral(zone: &Zone, cut: &str, res: &mut DnsPacket) {
+    let ns: Vec<DnsRecord> = zone.rrset(cut, QueryType::NS).cloned().collect();
+    for rec in &ns {
+        let DnsRecord::NS { host, .. } = rec else {
+            continue;
+        };
+        let glue = zone
+            .rrset(host, QueryType::A)
+            .chain(zone.rrset(host, QueryType::AAAA));
+        for rec in glue {
+            if !res.additional.contains(rec) {
+                res.add_additional(rec.clone());
+            }
+        }
+    }
+    for rec in ns {
+        res.add_authority(rec);
+    }
+}
+
+// rfc 2308 section 3, the SOA in a negative answer has the smaller of its own ttl and its
+// minimum field as its ttl, which is what the answer is cached for
+fn negative_soa(soa: &DnsRecord) -> DnsRecord {
+    let mut soa = soa.clone();
+    if let DnsRecord::SOA { ttl, minimum, .. } = &mut soa {
+        *ttl = (*ttl).min(*minimum);
+    }
+    soa
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+ns A 192.0.2.1
+old DNAME new
+x.old A 192.0.2.6
+www.new A 192.0.2.2
+long DNAME a123456789a123456789a123456789a123456789a123456789.b123456789b123456789b123456789b123456789b123456789.c123456789c123456789c123456789c123456789c123456789.d123456789d123456789d123456789d123456789d123456789.
+";
+
+    fn ask(authority: &Authority, name: &str) -> DnsPacket {
+        authority
+            .answer(&DnsPacket::query(name, QueryType::A).build())
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrites_names_below_a_dname() {
+        let authority = Authority::new(vec![Zone::parse(ZONE, "example.com").unwrap()]);
+
+        let res = ask(&authority, "www.old.example.com");
+        assert_eq!(res.header.rcode, ResultCode::NOERROR);
+        assert!(res.header.flags.authoritative);
+        let answers: Vec<_> = res
+            .answers
+            .iter()
+            .map(|rec| (rec.domain(), rec.qtype()))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                ("old.example.com", QueryType::DNAME),
+                ("www.old.example.com", QueryType::CNAME),
+                ("www.new.example.com", QueryType::A),
+            ]
+        );
+        let DnsRecord::CNAME { host, .. } = &res.answers[1] else {
+            panic!("{:?} isn't a CNAME", res.answers[1]);
+        };
+        assert_eq!(host, "www.new.example.com");
+
+        // neither is what the zone has below it served
+        let res = ask(&authority, "x.old.example.com");
+        assert_eq!(res.header.rcode, ResultCode::NXDOMAIN);
+        assert_eq!(res.answers.len(), 2);
+
+        // the DNAME's own name isn't redirected
+        let res = ask(&authority, "old.example.com");
+        assert!(res.answers.is_empty());
+
+        let label = "x".repeat(63);
+        let res = ask(&authority, &format!("{0}.{0}.long.example.com", label));
+        assert_eq!(res.header.rcode, ResultCode::YXDOMAIN);
+        assert_eq!(res.answers.len(), 1);
+    }
+
+    #[test]
+    fn fails_names_in_zones_it_has_no_copy_of() {
+        let authority = Authority::new(vec![Zone::parse(ZONE, "example.com").unwrap()]);
+        authority.add_pending("sub.example.com");
+        authority.add_pending("example.org");
+
+        let res = ask(&authority, "www.sub.example.com");
+        assert_eq!(res.header.rcode, ResultCode::SERVFAIL);
+        let res = ask(&authority, "example.org");
+        assert_eq!(res.header.rcode, ResultCode::SERVFAIL);
+        // the zone above still answers for the rest of its names
+        let res = ask(&authority, "ns.example.com");
+        assert_eq!(res.answers.len(), 1);
+
+        let zone = ZONE.replace("ns A", "ns.sub.example.com. A");
+        authority.add_zone(Zone::parse(&zone, "sub.example.com").unwrap());
+        let res = ask(&authority, "ns.sub.example.com");
+        assert_eq!(res.answers.len(), 1);
+    }
+
+    #[test]
+    fn rolls_back_to_earlier_versions() {
+        let version = |serial: u32, ns: &str| {
+            let zone = ZONE
+                .replace("hostmaster 1", &format!("hostmaster {}", serial))
+                .replace("192.0.2.1", ns);
+            Zone::parse(&zone, "example.com").unwrap()
+        };
+        let versions = Arc::new(ZoneVersions::new(3));
+        let authority =
+            Authority::new(vec![version(1, "192.0.2.1")]).zone_versions(versions.clone());
+        authority.add_zone(version(2, "192.0.2.2"));
+        // only the serial changed, which isn't a version of its own
+        authority.add_zone(version(3, "192.0.2.2"));
+        authority.add_zone(version(4, "192.0.2.4"));
+        assert_eq!(
+            authority.versions("example.com"),
+            [Some(4), Some(3), Some(1)]
+        );
+
+        assert_eq!(
+            authority.rollback("example.com", None).unwrap().serial(),
+            Some(3)
+        );
+        let res = ask(&authority, "ns.example.com");
+        assert!(res.answers[0].to_string().contains("192.0.2.2"));
+        assert!(authority.rollback("example.com", Some(2)).is_err());
+        assert!(authority.rollback("example.org", None).is_err());
+
+        // a reload reading the version rolled back from again keeps the one rolled back to
+        let reloaded = Authority::new(vec![version(4, "192.0.2.4")]).zone_versions(versions);
+        assert_eq!(reloaded.find("example.com").unwrap().serial(), Some(3));
+        reloaded.add_zone(version(5, "192.0.2.5"));
+        assert_eq!(
+            reloaded.versions("example.com"),
+            [Some(5), Some(3), Some(4)]
+        );
+        // and the oldest is gone
+        assert!(reloaded.rollback("example.com", Some(1)).is_err());
+    }
+}
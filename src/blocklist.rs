@@ -0,0 +1,690 @@
+// blocking names from Pi-hole and AdGuard style lists: hosts files ("0.0.0.0 ads.example"),
+// plain lists of names, "*.example" for the names below a domain and adblock's "||example^"
+// for a domain and everything below it. the lists are read from files or downloaded over
+// http, merged into a trie of labels from the root down so looking a name up takes as many
+// steps as it has labels however long the lists are, and fetched again every refresh
+// interval. a list that can't be fetched keeps what it had. there's no TLS here, lists only
+// served over https have to be fetched into a file by something else (curl from cron does),
+// files are read again on every refresh too. blocked names are answered with NXDOMAIN, the
+// unspecified address or a sinkhole address, with an extended error saying they're blocked.
+// exceptions ("@@||example^", or allowlists) win over blocks. clients are put into groups by
+// their address, each with its own blocklists and exceptions, so that e.g. the kids' devices
+// can be filtered more than the rest and one laptop not at all.
+use crate::edns::EdeCode;
+use crate::error::{DnsError, Result};
+use crate::logging;
+use crate::net::Subnet;
+use crate::structure::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// The TTL of the answers for blocked names if not told otherwise. Short, so a name taken off
+/// a list stops being blocked soon after. Pi-hole uses 2 seconds.
+pub const DEFAULT_TTL: u32 = 10;
+/// How often [`Blocklist::run`] fetches the lists if not told otherwise.
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(24 * 3600);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+// the biggest lists around have a few million names, a lot less than this
+const MAX_DOWNLOAD: u64 = 256 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+// names hosts files map to themselves, not to block
+const LOCAL_NAMES: [&str; 12] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+/// How blocked names are answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockAction {
+    /// As if they didn't exist.
+    NxDomain,
+    /// With 0.0.0.0 and ::, which go nowhere.
+    Null,
+    /// With an address of a server that shows a page saying why, queries for the other
+    /// address family get no records.
+    Sinkhole(IpAddr),
+}
+
+impl std::str::FromStr for BlockAction {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nxdomain" => Ok(BlockAction::NxDomain),
+            "null" => Ok(BlockAction::Null),
+            _ => Ok(BlockAction::Sinkhole(s.parse()?)),
+        }
+    }
+}
+
+/// What a line of a list blocks.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// Just the name, what hosts files and plain lists mean.
+    Exact(String),
+    /// The names below the domain, `*.example.com`.
+    Subdomains(String),
+    /// The domain and the names below it, `||example.com^`.
+    Domain(String),
+}
+
+impl Rule {
+    /// A rule written the way lists have them, `example.com`, `*.example.com` or
+    /// `||example.com^`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let rules = parse_list(s);
+        match (&rules.block[..], &rules.allow[..]) {
+            ([rule], []) => Ok(rule.clone()),
+            _ => Err(DnsError::Syntax(format!("not a blocklist rule: {:?}", s))),
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            Rule::Exact(domain) | Rule::Subdomains(domain) | Rule::Domain(domain) => domain,
+        }
+    }
+}
+
+/// What a list blocks, and the exceptions it makes for names it or other lists block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rules {
+    pub block: Vec<Rule>,
+    pub allow: Vec<Rule>,
+}
+
+/// The rules in a list in any of the formats, lines that aren't understood are skipped.
+/// Adblock rules with modifiers only apply in some cases, so those are skipped too.
+/// Exceptions, `@@||example.com^`, are allowed.
+pub fn parse_list(text: &str) -> Rules {
+    let mut rules = Rules::default();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('!') || line.starts_with('#') {
+            continue;
+        }
+        let (line, list) = match line.strip_prefix("@@") {
+            Some(line) => (line, &mut rules.allow),
+            None => (line, &mut rules.block),
+        };
+        if let Some(rule) = line.strip_prefix("||") {
+            if let Some(domain) = rule.strip_suffix('^').and_then(domain) {
+                list.push(Rule::Domain(domain));
+            }
+            continue;
+        }
+        // hosts files can have comments after the names
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(first) = fields.next() else {
+            continue;
+        };
+        if first.parse::<IpAddr>().is_ok() {
+            for name in fields {
+                if let Some(name) = domain(name).filter(|name| !LOCAL_NAMES.contains(&&**name)) {
+                    list.push(Rule::Exact(name));
+                }
+            }
+        } else if fields.next().is_none() {
+            match first.strip_prefix("*.") {
+                Some(name) => list.extend(domain(name).map(Rule::Subdomains)),
+                None => list.extend(domain(first).map(Rule::Exact)),
+            }
+        }
+    }
+    rules
+}
+
+// the name in lowercase without the final dot, if it's one
+fn domain(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    valid.then_some(name)
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    // the name this node is at is blocked
+    exact: bool,
+    // the names below it are
+    below: bool,
+}
+
+/// Blocked names, by label from the root down.
+#[derive(Default)]
+pub struct DomainTrie {
+    root: Node,
+    len: usize,
+}
+
+impl DomainTrie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, rule: &Rule) {
+        let mut node = &mut self.root;
+        for label in rule.domain().rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        match rule {
+            Rule::Exact(_) => node.exact = true,
+            Rule::Subdomains(_) => node.below = true,
+            Rule::Domain(_) => {
+                node.exact = true;
+                node.below = true;
+            }
+        }
+        self.len += 1;
+    }
+
+    /// Whether a rule blocks `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name.is_empty() {
+            return false;
+        }
+        let mut node = &self.root;
+        for label in name.rsplit('.') {
+            if node.below {
+                return true;
+            }
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.exact
+    }
+
+    /// How many rules were inserted, counting the same one twice if it was.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Where a list comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    File(PathBuf),
+    /// An `http://` url.
+    Url(String),
+}
+
+impl Source {
+    /// A url for anything starting with a scheme, a file otherwise.
+    pub fn parse(s: &str) -> Self {
+        if s.contains("://") {
+            Source::Url(s.to_string())
+        } else {
+            Source::File(s.into())
+        }
+    }
+
+    async fn fetch(&self) -> Result<String> {
+        match self {
+            Source::File(path) => Ok(std::fs::read_to_string(path)?),
+            Source::Url(url) => time::timeout(DOWNLOAD_TIMEOUT, download(url))
+                .await
+                .map_err(|_| DnsError::Download(url.clone(), "timed out".into()))?,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Url(url) => f.write_str(url),
+        }
+    }
+}
+
+/// Blocks the names on a set of lists, except for the ones on its allowlists, kept up to date
+/// with [`Blocklist::run`].
+pub struct Blocklist {
+    sources: Vec<Source>,
+    allowlists: Vec<Source>,
+    allowed: Vec<Rule>,
+    // the rules each source and then each allowlist had the last time it could be fetched
+    rules: Mutex<Vec<Rules>>,
+    tries: RwLock<Arc<Tries>>,
+    action: BlockAction,
+    ttl: u32,
+}
+
+#[derive(Default)]
+struct Tries {
+    blocked: DomainTrie,
+    allowed: DomainTrie,
+}
+
+impl Blocklist {
+    /// Blocks the names on the lists at `sources`, which are empty until
+    /// [`Blocklist::refresh`] first fetches them.
+    pub fn new(sources: Vec<Source>) -> Self {
+        Self {
+            sources,
+            allowlists: Vec::new(),
+            allowed: Vec::new(),
+            rules: Mutex::new(Vec::new()),
+            tries: RwLock::new(Arc::new(Tries::default())),
+            action: BlockAction::Null,
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// Lists of names not to block even if the blocklists have them, every rule on them is
+    /// taken as an exception.
+    pub fn allowlists(mut self, sources: Vec<Source>) -> Self {
+        self.allowlists = sources;
+        self
+    }
+
+    /// Doesn't block what `rule` matches, whatever the lists say.
+    pub fn allow(mut self, rule: Rule) -> Self {
+        self.allowed.push(rule);
+        self
+    }
+
+    /// How blocked names are answered, with the unspecified address by default.
+    pub fn action(mut self, action: BlockAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// The ttl of the answers for blocked names.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    /// How many rules the blocklists have together, not counting exceptions.
+    pub fn len(&self) -> usize {
+        self.tries.read().unwrap().blocked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fetches every list again and starts blocking what they have now. Lists that can't be
+    /// fetched are reported and keep the rules they had. Returns how many rules there are.
+    pub async fn refresh(&self) -> usize {
+        let sources: Vec<&Source> = self.sources.iter().chain(&self.allowlists).collect();
+        for (i, source) in sources.iter().enumerate() {
+            let rules = match source.fetch().await {
+                Ok(text) => parse_list(&text),
+                Err(e) => {
+                    logging::warning(&format!("fetching blocklist {} failed: {}", source, e));
+                    continue;
+                }
+            };
+            let mut all = self.rules.lock().unwrap();
+            all.resize_with(sources.len(), Rules::default);
+            all[i] = rules;
+        }
+
+        let mut tries = Tries::default();
+        for rule in &self.allowed {
+            tries.allowed.insert(rule);
+        }
+        let all = self.rules.lock().unwrap();
+        for (i, rules) in all.iter().enumerate() {
+            if i < self.sources.len() {
+                rules
+                    .block
+                    .iter()
+                    .for_each(|rule| tries.blocked.insert(rule));
+            } else {
+                rules
+                    .block
+                    .iter()
+                    .for_each(|rule| tries.allowed.insert(rule));
+            }
+            rules
+                .allow
+                .iter()
+                .for_each(|rule| tries.allowed.insert(rule));
+        }
+        drop(all);
+        let len = tries.blocked.len();
+        *self.tries.write().unwrap() = Arc::new(tries);
+        len
+    }
+
+    /// Refreshes the lists now and then every `interval`, a day if None. Runs until the
+    /// future is dropped.
+    pub async fn run(&self, interval: Option<Duration>) {
+        loop {
+            self.refresh().await;
+            time::sleep(interval.unwrap_or(DEFAULT_REFRESH)).await;
+        }
+    }
+
+    pub fn is_blocked(&self, name: &str) -> bool {
+        let tries = self.tries.read().unwrap();
+        tries.blocked.contains(name) && !tries.allowed.contains(name)
+    }
+
+    /// The response for `request` if the name it asks for is blocked.
+    pub fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if question.class != 1 || !self.is_blocked(&question.name) {
+            return None;
+        }
+        Some(self.blocked(request))
+    }
+
+    // the response for a request for a blocked name
+    fn blocked(&self, request: &DnsPacket) -> DnsPacket {
+        let question = &request.questions[0];
+        let mut res = DnsPacket::response_to(request);
+        res.set_recursion_available(true);
+        let addr = match (self.action, question.qtype) {
+            (BlockAction::NxDomain, _) => {
+                res.set_rcode(ResultCode::NXDOMAIN);
+                None
+            }
+            (BlockAction::Null, QueryType::A) => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            (BlockAction::Null, QueryType::AAAA) => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            (BlockAction::Sinkhole(addr @ IpAddr::V4(_)), QueryType::A)
+            | (BlockAction::Sinkhole(addr @ IpAddr::V6(_)), QueryType::AAAA) => Some(addr),
+            // no records of the type asked for
+            _ => None,
+        };
+        match addr {
+            Some(IpAddr::V4(addr)) => {
+                res.add_answer(DnsRecord::A {
+                    domain: question.name.clone(),
+                    class: 1,
+                    ttl: self.ttl,
+                    ip: addr.into(),
+                });
+            }
+            Some(IpAddr::V6(addr)) => {
+                res.add_answer(DnsRecord::AAAA {
+                    domain: question.name.clone(),
+                    class: 1,
+                    ttl: self.ttl,
+                    ip: addr.into(),
+                });
+            }
+            None => {}
+        }
+
+        let edns = DnsPacket::response_edns(request);
+        res.set_edns(edns.as_ref());
+        if edns.is_some() {
+            let _ = res.add_extended_error(EdeCode::Blocked, "on a blocklist");
+        }
+        res
+    }
+}
+
+/// Clients that get the same filtering, the blocklists that apply to them and exceptions of
+/// their own on top of the lists'.
+pub struct Group {
+    name: String,
+    clients: Vec<Subnet>,
+    blocklists: Vec<Arc<Blocklist>>,
+    allowed: DomainTrie,
+}
+
+impl Group {
+    /// A group for the clients in `clients`, which has nothing blocked until it's given
+    /// blocklists.
+    pub fn new(name: &str, clients: Vec<Subnet>) -> Self {
+        Self {
+            name: name.to_string(),
+            clients,
+            blocklists: Vec::new(),
+            allowed: DomainTrie::new(),
+        }
+    }
+
+    /// Blocks what `blocklist` does for the group. Blocklists can be shared between groups.
+    pub fn blocklist(mut self, blocklist: Arc<Blocklist>) -> Self {
+        self.blocklists.push(blocklist);
+        self
+    }
+
+    /// Doesn't block what `rule` matches for the group, whatever its blocklists say.
+    pub fn allow(mut self, rule: &Rule) -> Self {
+        self.allowed.insert(rule);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn clients(&self) -> &[Subnet] {
+        &self.clients
+    }
+
+    pub fn blocklists(&self) -> &[Arc<Blocklist>] {
+        &self.blocklists
+    }
+
+    // the longest prefix of the group's subnets `client` is in
+    fn matches(&self, client: IpAddr) -> Option<u8> {
+        self.clients
+            .iter()
+            .filter(|net| net.contains(client))
+            .map(Subnet::prefix)
+            .max()
+    }
+}
+
+/// Which clients get which filtering. Each client is in the group with the most specific
+/// subnet it's in, so a single address can be exempted from the filtering of its network,
+/// and clients not in any group are in the default one.
+pub struct Policy {
+    default: Group,
+    groups: Vec<Group>,
+}
+
+impl Policy {
+    pub fn new(default: Group) -> Self {
+        Self {
+            default,
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn group(mut self, group: Group) -> Self {
+        self.groups.push(group);
+        self
+    }
+
+    /// The group `client` is in.
+    pub fn group_for(&self, client: IpAddr) -> &Group {
+        self.groups
+            .iter()
+            .filter_map(|group| Some((group.matches(client)?, group)))
+            .max_by_key(|(prefix, _)| *prefix)
+            .map_or(&self.default, |(_, group)| group)
+    }
+
+    /// Every blocklist any group uses, each once, for keeping them up to date.
+    pub fn blocklists(&self) -> Vec<Arc<Blocklist>> {
+        let mut blocklists: Vec<Arc<Blocklist>> = Vec::new();
+        for blocklist in std::iter::once(&self.default)
+            .chain(&self.groups)
+            .flat_map(|group| &group.blocklists)
+        {
+            if !blocklists.iter().any(|b| Arc::ptr_eq(b, blocklist)) {
+                blocklists.push(blocklist.clone());
+            }
+        }
+        blocklists
+    }
+
+    /// The response for `request` from `client` if the name it asks for is blocked for the
+    /// client's group, answered the way the first blocklist blocking it says.
+    pub fn answer(&self, request: &DnsPacket, client: IpAddr) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if question.class != 1 {
+            return None;
+        }
+        let group = self.group_for(client);
+        if group.allowed.contains(&question.name) {
+            return None;
+        }
+        let blocklist = group
+            .blocklists
+            .iter()
+            .find(|blocklist| blocklist.is_blocked(&question.name))?;
+        Some(blocklist.blocked(request))
+    }
+}
+
+// GETs an http url, following redirects. HTTP/1.0 so the body comes in one piece, up to where
+// the server closes the connection
+async fn download(url: &str) -> Result<String> {
+    let failed = |reason: &str| DnsError::Download(url.to_string(), reason.to_string());
+    let mut location = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let Some(rest) = location.strip_prefix("http://") else {
+            return Err(failed("only http urls can be downloaded"));
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        let mut stream = TcpStream::connect(&addr).await?;
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: dns-server\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream
+            .take(MAX_DOWNLOAD + 1)
+            .read_to_end(&mut response)
+            .await?;
+        if response.len() as u64 > MAX_DOWNLOAD {
+            return Err(failed("too big"));
+        }
+
+        let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return Err(failed("no end of headers"));
+        };
+        let head = String::from_utf8_lossy(&response[..end]);
+        let mut lines = head.split("\r\n");
+        let status: u16 = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| failed("no status line"))?;
+        let header = |name: &str| {
+            head.split("\r\n").skip(1).find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.trim()
+                    .eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_string())
+            })
+        };
+        match status {
+            200 => {
+                let body = &response[end + 4..];
+                let chunked = header("transfer-encoding")
+                    .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+                let body = if chunked {
+                    dechunk(body).ok_or_else(|| failed("bad chunked body"))?
+                } else {
+                    body.to_vec()
+                };
+                return Ok(String::from_utf8_lossy(&body).into_owned());
+            }
+            301 | 302 | 303 | 307 | 308 => {
+                let next = header("location").ok_or_else(|| failed("redirect to nowhere"))?;
+                location = if next.starts_with('/') {
+                    format!("http://{}{}", host, next)
+                } else {
+                    next
+                };
+            }
+            _ => return Err(failed(&format!("status {}", status))),
+        }
+    }
+    Err(failed("too many redirects"))
+}
+
+// the body of a chunked response, which servers shouldn't send to HTTP/1.0 requests but some do
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_with_its_ttl() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.txt", std::process::id()));
+        std::fs::write(&path, "0.0.0.0 ads.example\n").unwrap();
+        let query = DnsPacket::query("ads.example", QueryType::A).build();
+
+        let blocklist = Blocklist::new(vec![Source::File(path.clone())]);
+        assert_eq!(blocklist.refresh().await, 1);
+        let res = blocklist.answer(&query).unwrap();
+        assert_eq!(res.answers[0].ttl(), DEFAULT_TTL);
+
+        let blocklist = Blocklist::new(vec![Source::File(path.clone())]).ttl(300);
+        blocklist.refresh().await;
+        let res = blocklist.answer(&query).unwrap();
+        assert_eq!(res.answers[0].ttl(), 300);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
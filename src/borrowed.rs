@@ -0,0 +1,450 @@
+// zero-copy view of a packet. names and rdata are slices into the original bytes, so
+// parsing doesn't allocate a String per label like the owned structs in structure.rs do.
+use crate::error::{DnsError, Result};
+use crate::structure::{BytePacketBuffer, DnsHeader, DnsQuestion, DnsRecord, QueryType};
+use std::fmt;
+
+const MAX_JUMPS: usize = 5;
+
+// a tiny read cursor over a borrowed slice, mirrors the reads on BytePacketBuffer
+#[derive(Clone)]
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn read(&mut self) -> Result<u8> {
+        let Some(&byte) = self.bytes.get(self.pos) else {
+            return Err(DnsError::BufferOverrun);
+        };
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        Ok(((self.read()? as u16) << 8) | (self.read()? as u16))
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(((self.read_u16()? as u32) << 16) | (self.read_u16()? as u32))
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        let Some(slice) = self.bytes.get(self.pos..self.pos + len) else {
+            return Err(DnsError::BufferOverrun);
+        };
+        self.pos += len;
+        Ok(slice)
+    }
+
+    // walks a (possibly compressed) name once to validate it and move past it. the labels
+    // themselves are decoded lazily through NameRef.
+    fn read_name(&mut self) -> Result<NameRef<'a>> {
+        let start = self.pos;
+        let mut pos = self.pos;
+        let mut jumped = false;
+        let mut jumps_performed = 0;
+
+        loop {
+            let Some(&len) = self.bytes.get(pos) else {
+                return Err(DnsError::BufferOverrun);
+            };
+
+            if (len & 0xC0) == 0xC0 {
+                if jumps_performed >= MAX_JUMPS {
+                    return Err(DnsError::CompressionLoop);
+                }
+                let Some(&b2) = self.bytes.get(pos + 1) else {
+                    return Err(DnsError::BufferOverrun);
+                };
+                if !jumped {
+                    self.pos = pos + 2;
+                }
+                pos = ((((len as u16) ^ 0xC0) << 8) | b2 as u16) as usize;
+                jumped = true;
+                jumps_performed += 1;
+            } else {
+                pos += 1;
+                if len == 0 {
+                    break;
+                }
+                if pos + len as usize > self.bytes.len() {
+                    return Err(DnsError::BufferOverrun);
+                }
+                pos += len as usize;
+            }
+        }
+
+        if !jumped {
+            self.pos = pos;
+        }
+
+        Ok(NameRef {
+            bytes: self.bytes,
+            start,
+        })
+    }
+}
+
+/// A domain name that still lives in the packet bytes. Labels are decoded (and compression
+/// pointers followed) on demand.
+#[derive(Clone, Copy)]
+pub struct NameRef<'a> {
+    bytes: &'a [u8],
+    start: usize,
+}
+
+impl<'a> NameRef<'a> {
+    /// Iterates over the raw labels, following compression pointers.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            bytes: self.bytes,
+            pos: self.start,
+            jumps: 0,
+        }
+    }
+
+    /// Case-insensitive comparison against a dotted name, without allocating.
+    pub fn eq_ignore_case(&self, name: &str) -> bool {
+        let mut wanted = name
+            .trim_end_matches('.')
+            .split('.')
+            .filter(|l| !l.is_empty());
+        for label in self.labels() {
+            match wanted.next() {
+                Some(w) if w.as_bytes().eq_ignore_ascii_case(label) => {}
+                _ => return false,
+            }
+        }
+        wanted.next().is_none()
+    }
+
+    /// Allocates the dotted, lowercased form that the owned parser produces.
+    pub fn to_lowercase_string(self) -> String {
+        self.to_string().to_lowercase()
+    }
+}
+
+impl fmt::Display for NameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut delim = "";
+        for label in self.labels() {
+            write!(f, "{}{}", delim, String::from_utf8_lossy(label))?;
+            delim = ".";
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl PartialEq for NameRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        let mut a = self.labels();
+        let mut b = other.labels();
+        loop {
+            match (a.next(), b.next()) {
+                (None, None) => return true,
+                (Some(x), Some(y)) if x.eq_ignore_ascii_case(y) => {}
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Eq for NameRef<'_> {}
+
+/// Iterator over the labels of a [`NameRef`].
+pub struct Labels<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    jumps: usize,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        // the name was validated when it was read, so running off the end here just ends
+        // the iteration
+        loop {
+            let len = *self.bytes.get(self.pos)?;
+            if (len & 0xC0) == 0xC0 {
+                if self.jumps >= MAX_JUMPS {
+                    return None;
+                }
+                let b2 = *self.bytes.get(self.pos + 1)?;
+                self.pos = ((((len as u16) ^ 0xC0) << 8) | b2 as u16) as usize;
+                self.jumps += 1;
+                continue;
+            }
+            if len == 0 {
+                return None;
+            }
+            let label = self.bytes.get(self.pos + 1..self.pos + 1 + len as usize)?;
+            self.pos += 1 + len as usize;
+            return Some(label);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DnsQuestionRef<'a> {
+    pub name: NameRef<'a>,
+    pub qtype: QueryType,
+    pub class: u16,
+}
+
+impl DnsQuestionRef<'_> {
+    pub fn to_owned(&self) -> DnsQuestion {
+        DnsQuestion {
+            name: self.name.to_lowercase_string(),
+            qtype: self.qtype,
+            class: self.class,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct DnsRecordRef<'a> {
+    pub domain: NameRef<'a>,
+    pub qtype: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: &'a [u8],
+    // where the record starts in the packet, so it can be decoded into the owned form later
+    offset: usize,
+    bytes: &'a [u8],
+}
+
+impl fmt::Debug for DnsRecordRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsRecordRef")
+            .field("domain", &self.domain)
+            .field("qtype", &self.qtype)
+            .field("class", &self.class)
+            .field("ttl", &self.ttl)
+            .field("rdata", &self.rdata)
+            .finish()
+    }
+}
+
+impl DnsRecordRef<'_> {
+    /// Decodes the record into the owned [`DnsRecord`]. Names inside the rdata may be
+    /// compressed against the rest of the packet, so this goes through the regular parser.
+    pub fn to_owned(&self) -> Result<DnsRecord> {
+        let mut buf = BytePacketBuffer::from_bytes(self.bytes)?;
+        buf.seek(self.offset)?;
+        DnsRecord::from(&mut buf)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DnsPacketRef<'a> {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestionRef<'a>>,
+    pub answers: Vec<DnsRecordRef<'a>>,
+    pub authorities: Vec<DnsRecordRef<'a>>,
+    pub additional: Vec<DnsRecordRef<'a>>,
+}
+
+impl<'a> DnsPacketRef<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        let mut cur = Cursor { bytes, pos: 0 };
+        let header = read_header(&mut cur)?;
+
+        let mut questions = Vec::with_capacity(header.qdcount as usize);
+        for _ in 0..header.qdcount {
+            questions.push(read_question(&mut cur)?);
+        }
+
+        let answers = read_records(&mut cur, header.anscount)?;
+        let authorities = read_records(&mut cur, header.nscount)?;
+        let additional = read_records(&mut cur, header.arcount)?;
+        if cur.pos < bytes.len() {
+            return Err(DnsError::TrailingBytes(bytes.len() - cur.pos));
+        }
+
+        Ok(Self {
+            header,
+            questions,
+            answers,
+            authorities,
+            additional,
+        })
+    }
+}
+
+/// A packet where only the header has been decoded. Each section is decoded on demand while
+/// iterating, so looking at the first answer of a large response doesn't parse the rest of it.
+#[derive(Clone, Debug)]
+pub struct LazyPacket<'a> {
+    pub header: DnsHeader,
+    bytes: &'a [u8],
+}
+
+impl<'a> LazyPacket<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self> {
+        let mut cur = Cursor { bytes, pos: 0 };
+        let header = read_header(&mut cur)?;
+        Ok(Self { header, bytes })
+    }
+
+    pub fn iter_questions(&self) -> QuestionIter<'a> {
+        QuestionIter {
+            cur: Cursor {
+                bytes: self.bytes,
+                pos: HEADER_LEN,
+            },
+            remaining: self.header.qdcount,
+            failed: false,
+        }
+    }
+
+    pub fn iter_answers(&self) -> RecordIter<'a> {
+        self.section(0, self.header.anscount)
+    }
+
+    pub fn iter_authorities(&self) -> RecordIter<'a> {
+        self.section(self.header.anscount as usize, self.header.nscount)
+    }
+
+    pub fn iter_additional(&self) -> RecordIter<'a> {
+        self.section(
+            self.header.anscount as usize + self.header.nscount as usize,
+            self.header.arcount,
+        )
+    }
+
+    fn section(&self, skip_records: usize, count: u16) -> RecordIter<'a> {
+        RecordIter {
+            cur: Cursor {
+                bytes: self.bytes,
+                pos: HEADER_LEN,
+            },
+            skip_questions: self.header.qdcount,
+            skip_records,
+            remaining: count,
+            failed: false,
+        }
+    }
+}
+
+/// Yields the questions of a [`LazyPacket`]. Stops after the first error.
+pub struct QuestionIter<'a> {
+    cur: Cursor<'a>,
+    remaining: u16,
+    failed: bool,
+}
+
+impl<'a> Iterator for QuestionIter<'a> {
+    type Item = Result<DnsQuestionRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let res = read_question(&mut self.cur);
+        self.failed = res.is_err();
+        Some(res)
+    }
+}
+
+/// Yields the records of one section of a [`LazyPacket`]. The sections before it are walked
+/// over (not decoded) on the first call to `next`. Stops after the first error.
+pub struct RecordIter<'a> {
+    cur: Cursor<'a>,
+    skip_questions: u16,
+    skip_records: usize,
+    remaining: u16,
+    failed: bool,
+}
+
+impl RecordIter<'_> {
+    fn skip_preceding(&mut self) -> Result<()> {
+        while self.skip_questions > 0 {
+            read_question(&mut self.cur)?;
+            self.skip_questions -= 1;
+        }
+        while self.skip_records > 0 {
+            read_record(&mut self.cur)?;
+            self.skip_records -= 1;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for RecordIter<'a> {
+    type Item = Result<DnsRecordRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let res = self
+            .skip_preceding()
+            .and_then(|_| read_record(&mut self.cur));
+        self.failed = res.is_err();
+        Some(res)
+    }
+}
+
+const HEADER_LEN: usize = 12;
+
+fn read_header(cur: &mut Cursor) -> Result<DnsHeader> {
+    let mut header = DnsHeader::new();
+    header.id = cur.read_u16()?;
+    let a = cur.read()?;
+    let b = cur.read()?;
+    header.unpack_flags(a, b);
+    header.qdcount = cur.read_u16()?;
+    header.anscount = cur.read_u16()?;
+    header.nscount = cur.read_u16()?;
+    header.arcount = cur.read_u16()?;
+
+    Ok(header)
+}
+
+fn read_question<'a>(cur: &mut Cursor<'a>) -> Result<DnsQuestionRef<'a>> {
+    Ok(DnsQuestionRef {
+        name: cur.read_name()?,
+        qtype: QueryType::from_num(cur.read_u16()?),
+        class: cur.read_u16()?,
+    })
+}
+fn read_record<'a>(cur: &mut Cursor<'a>) -> Result<DnsRecordRef<'a>> {
+    let offset = cur.pos;
+    let domain = cur.read_name()?;
+    let qtype = QueryType::from_num(cur.read_u16()?);
+    let class = cur.read_u16()?;
+    let ttl = cur.read_u32()?;
+    let len = cur.read_u16()?;
+    let rdata = cur.read_slice(len as usize)?;
+
+    Ok(DnsRecordRef {
+        domain,
+        qtype,
+        class,
+        ttl,
+        rdata,
+        offset,
+        bytes: cur.bytes,
+    })
+}
+
+fn read_records<'a>(cur: &mut Cursor<'a>, count: u16) -> Result<Vec<DnsRecordRef<'a>>> {
+    let mut out = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        out.push(read_record(cur)?);
+    }
+    Ok(out)
+}
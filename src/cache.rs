@@ -0,0 +1,921 @@
+// an in-memory cache of answers keyed by question. positive answers live as long as their
+// shortest TTL, negative ones (NXDOMAIN and NODATA) as long as the SOA in their authority
+// section says (rfc 2308 section 5). TTLs count down while an answer sits in the cache, so
+// clients see how much is left of it. the cache is bounded by an entry count and a rough byte
+// budget, when either is exceeded the least recently used answers make room. entries are spread
+// over shards by key, each with its own lock and its own share of the limits, so lookups on
+// different cores rarely wait for each other. recency is tracked per shard, which makes the
+// eviction order an approximation of global LRU. misses for a question that is already being
+// resolved wait for that resolution instead of starting their own. answers that keep
+// getting asked for are prefetched: a hit close to the end of the TTL refreshes the entry in the
+// background, so popular names don't miss every time they expire. names the stale policy says
+// may get one (see refresh.rs) are answered from an expired entry, with a short TTL, while it is
+// refreshed in the background, rfc 8767's serve-stale without waiting for the upstream to fail
+// first. expired entries of those names are kept for a day for that.
+use crate::metrics::CacheCounters;
+use crate::querylog::{answered_from, Origin};
+use crate::refresh::{self, RefreshLimiter, RefreshPolicy, StalePolicy, StaleStrategy};
+use crate::server::Handler;
+use crate::structure::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::{OnceCell, Semaphore};
+
+const DEFAULT_MAX_TTL: Duration = Duration::from_secs(86400);
+// rfc 2308 section 5 suggests one to three hours
+const DEFAULT_MAX_NEGATIVE_TTL: Duration = Duration::from_secs(3 * 3600);
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+// more shards than cores keeps the chance of two lookups colliding low
+const SHARDS_PER_CORE: usize = 4;
+// an answer asked for this often within one TTL is worth keeping warm
+const DEFAULT_PREFETCH_HITS: u64 = 3;
+const MAX_CONCURRENT_PREFETCHES: usize = 64;
+// longer chains are almost certainly a loop
+const MAX_CNAME_CHAIN: usize = 8;
+// how long past their TTL entries are kept to serve stale, and with what TTL they are, as rfc
+// 8767 suggests. a refresh that hasn't replaced the entry by the time a stale answer's TTL is
+// up is tried again
+const MAX_STALE: Duration = Duration::from_secs(86400);
+const STALE_TTL: u32 = 30;
+const RETRY_REFRESH: Duration = Duration::from_secs(STALE_TTL as u64);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: String,
+    pub qtype: QueryType,
+    pub class: u16,
+}
+
+impl CacheKey {
+    pub fn new(name: &str, qtype: QueryType, class: u16) -> Self {
+        Self {
+            name: name.trim_end_matches('.').to_lowercase(),
+            qtype,
+            class,
+        }
+    }
+}
+
+impl From<&DnsQuestion> for CacheKey {
+    fn from(question: &DnsQuestion) -> Self {
+        CacheKey::new(&question.name, question.qtype, question.class)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedAnswer {
+    pub rcode: ResultCode,
+    pub recursion_available: bool,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+}
+
+impl CachedAnswer {
+    pub fn from_response(response: &DnsPacket) -> Self {
+        Self {
+            rcode: response.header.rcode,
+            recursion_available: response.header.flags.recursion_available,
+            answers: response.answers.clone(),
+            authorities: response.authorities.clone(),
+        }
+    }
+
+    /// A response to `request` carrying this answer.
+    pub fn response_to(self, request: &DnsPacket) -> DnsPacket {
+        let mut res = DnsPacket::response_to(request);
+        res.set_rcode(self.rcode)
+            .set_recursion_available(self.recursion_available);
+        res.answers = self.answers;
+        res.authorities = self.authorities;
+        res.set_edns(DnsPacket::response_edns(request).as_ref());
+        res
+    }
+}
+
+struct Entry {
+    answer: CachedAnswer,
+    inserted: Instant,
+    ttl: Duration,
+    size: usize,
+    // position in the recency order, bumped on every hit
+    used: u64,
+    hits: u64,
+    // how old the entry has to be before a hit prefetches it
+    refresh_at: Duration,
+    // when the last prefetch started
+    prefetched: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<CacheKey, Entry>,
+    // least recently used first
+    lru: BTreeMap<u64, CacheKey>,
+    bytes: usize,
+    clock: u64,
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+        self.lru.remove(&entry.used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let Some((_, key)) = self.lru.pop_first() else {
+            return false;
+        };
+        if let Some(entry) = self.map.remove(&key) {
+            self.bytes -= entry.size;
+        }
+        true
+    }
+}
+
+pub struct Cache {
+    shards: Box<[Mutex<Entries>]>,
+    hasher: RandomState,
+    max_ttl: Duration,
+    max_negative_ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    refresh: RefreshPolicy,
+    stale: StalePolicy,
+    counters: Arc<CacheCounters>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new()
+    }
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Self {
+            shards: new_shards(cores * SHARDS_PER_CORE),
+            hasher: RandomState::new(),
+            max_ttl: DEFAULT_MAX_TTL,
+            max_negative_ttl: DEFAULT_MAX_NEGATIVE_TTL,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            refresh: RefreshPolicy::default(),
+            stale: StalePolicy::default(),
+            counters: Arc::default(),
+        }
+    }
+
+    /// How many independently locked parts the cache is split into, a few per core by
+    /// default. One shard makes it a single LRU under a single lock.
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.shards = new_shards(shards.max(1));
+        self
+    }
+
+    /// Caps the number of cached answers. Like the byte budget, the cap is split evenly
+    /// between the shards.
+    pub fn max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max;
+        self
+    }
+
+    /// Caps the memory the cached answers take. The size of an answer is an estimate from its
+    /// records and names, allocator overhead isn't accounted for.
+    pub fn max_bytes(mut self, max: usize) -> Self {
+        self.max_bytes = max;
+        self
+    }
+
+    /// When entries become due for a prefetch, see [`RefreshPolicy::refresh_after`], and how
+    /// many prefetches may be out to each upstream at once.
+    pub fn refresh_policy(mut self, policy: RefreshPolicy) -> Self {
+        self.refresh = policy;
+        self
+    }
+
+    /// Which names are answered from expired entries while they're refreshed, none by default.
+    pub fn serve_stale(mut self, policy: StalePolicy) -> Self {
+        self.stale = policy;
+        self
+    }
+
+    /// Counts into `counters`, which other caches can count into too, rather than counters
+    /// of its own.
+    pub fn shared_counters(mut self, counters: Arc<CacheCounters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    pub fn counters(&self) -> &CacheCounters {
+        &self.counters
+    }
+
+    /// Caps how long any answer is kept, whatever its TTL says.
+    pub fn max_ttl(mut self, ttl: Duration) -> Self {
+        self.max_ttl = ttl;
+        self
+    }
+
+    /// Caps how long NXDOMAIN and NODATA answers are kept.
+    pub fn max_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.max_negative_ttl = ttl;
+        self
+    }
+
+    /// The cached answer for `key` with its TTLs counted down, if there is one that hasn't
+    /// expired.
+    pub fn get(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        let answer = self.fetch(key);
+        let counter = match answer {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        answer
+    }
+
+    /// Puts together an answer for `key` from cached CNAMEs and whatever their chain ends in,
+    /// for when the answer to `key` itself isn't cached but the hops are. Chains longer than
+    /// a few hops are treated as missing.
+    pub fn chase(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        if key.qtype == QueryType::CNAME {
+            return None;
+        }
+        let mut answers = Vec::new();
+        let mut name = key.name.clone();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let hop = CacheKey::new(&name, key.qtype, key.class);
+            if let Some(mut end) = self.fetch(&hop) {
+                answers.append(&mut end.answers);
+                end.answers = answers;
+                return Some(end);
+            }
+
+            let alias = self.fetch(&CacheKey::new(&name, QueryType::CNAME, key.class))?;
+            let target = alias.answers.iter().find_map(|rec| match rec {
+                // owners keep the case and trailing dot they came with
+                DnsRecord::CNAME { domain, host, .. }
+                    if domain
+                        .trim_end_matches('.')
+                        .eq_ignore_ascii_case(name.trim_end_matches('.')) =>
+                {
+                    Some(host.clone())
+                }
+                _ => None,
+            })?;
+            answers.extend(alias.answers);
+            name = target;
+        }
+        None
+    }
+
+    /// The expired answer for `key` with its TTLs at 30 seconds, if the stale policy says
+    /// `key`'s name may be answered from one and there is one.
+    pub fn get_stale(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        if !self.serves_stale(key) {
+            return None;
+        }
+        let mut entries = self.shard(key);
+        let entry = entries.map.get(key)?;
+        let age = entry.inserted.elapsed();
+        if age < entry.ttl || age >= entry.ttl + MAX_STALE {
+            return None;
+        }
+        let mut answer = entry.answer.clone();
+        let used = entries.tick();
+        let previous = mem::replace(&mut entries.map.get_mut(key)?.used, used);
+        entries.lru.remove(&previous);
+        entries.lru.insert(used, key.clone());
+
+        drop(entries);
+        for rec in answer.answers.iter_mut().chain(&mut answer.authorities) {
+            rec.set_ttl(STALE_TTL);
+        }
+        Some(answer)
+    }
+
+    fn serves_stale(&self, key: &CacheKey) -> bool {
+        self.stale.strategy_for(&key.name) == StaleStrategy::StaleWhileRevalidate
+    }
+
+    // the lookup behind get and chase, without counting hits and misses
+    fn fetch(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        let mut entries = self.shard(key);
+        let entry = entries.map.get(key)?;
+        let age = entry.inserted.elapsed();
+        if age >= entry.ttl {
+            // kept to serve stale for a while
+            if age < entry.ttl + MAX_STALE && self.serves_stale(key) {
+                return None;
+            }
+            entries.remove(key);
+            self.counters.expired.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let used = entries.tick();
+        let entry = entries.map.get_mut(key)?;
+        let previous = mem::replace(&mut entry.used, used);
+        entry.hits += 1;
+        let mut answer = entry.answer.clone();
+        entries.lru.remove(&previous);
+        entries.lru.insert(used, key.clone());
+
+        drop(entries);
+        age_records(&mut answer, age);
+        Some(answer)
+    }
+
+    /// Stores `response` as the answer for `key`. Responses that shouldn't be cached, like
+    /// errors, truncated answers and negative answers without a SOA, are ignored.
+    pub fn insert(&self, key: CacheKey, response: &DnsPacket) {
+        if response.header.flags.truncated {
+            return;
+        }
+        let ttl = match response.header.rcode {
+            ResultCode::NOERROR if !response.answers.is_empty() => response
+                .answers
+                .iter()
+                .map(|rec| rec.ttl())
+                .min()
+                .map(|ttl| Duration::from_secs(ttl as u64).min(self.max_ttl)),
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => {
+                negative_ttl(response).map(|ttl| ttl.min(self.max_negative_ttl))
+            }
+            _ => None,
+        };
+        let Some(ttl) = ttl.filter(|ttl| !ttl.is_zero()) else {
+            return;
+        };
+
+        // each hop of a CNAME chain is cached on its own too, so other names that lead into
+        // the same chain can be answered by chasing it. only the hops that follow from the
+        // name asked for: a CNAME for any other name in the answer is one the upstream had no
+        // business vouching for, and caching it would let it answer queries for that name
+        if key.qtype != QueryType::CNAME {
+            let mut name = key.name.clone();
+            for _ in 0..=MAX_CNAME_CHAIN {
+                let next = response.answers.iter().find_map(|rec| match rec {
+                    DnsRecord::CNAME { domain, host, .. }
+                        if domain
+                            .trim_end_matches('.')
+                            .eq_ignore_ascii_case(name.trim_end_matches('.')) =>
+                    {
+                        Some((rec, domain, host))
+                    }
+                    _ => None,
+                });
+                let Some((rec, domain, host)) = next else {
+                    break;
+                };
+                name = host.clone();
+                let ttl = Duration::from_secs(rec.ttl() as u64).min(self.max_ttl);
+                if ttl.is_zero() {
+                    continue;
+                }
+                let hop = CachedAnswer {
+                    rcode: ResultCode::NOERROR,
+                    recursion_available: response.header.flags.recursion_available,
+                    answers: vec![rec.clone()],
+                    authorities: Vec::new(),
+                };
+                self.store(CacheKey::new(domain, QueryType::CNAME, key.class), hop, ttl);
+            }
+        }
+        self.store(key, CachedAnswer::from_response(response), ttl);
+    }
+
+    /// Every live entry with its TTLs counted down, and how long it has left.
+    pub fn entries(&self) -> Vec<(CacheKey, CachedAnswer, Duration)> {
+        let mut live = Vec::new();
+        for shard in self.shards.iter() {
+            let entries = shard.lock().unwrap();
+            for (key, entry) in &entries.map {
+                let age = entry.inserted.elapsed();
+                let Some(left) = entry.ttl.checked_sub(age).filter(|left| !left.is_zero()) else {
+                    continue;
+                };
+                let mut answer = entry.answer.clone();
+                age_records(&mut answer, age);
+                live.push((key.clone(), answer, left));
+            }
+        }
+        live
+    }
+
+    /// Stores an answer that is already known to be cacheable for `ttl`, e.g. one read back
+    /// from a snapshot. The limits still apply.
+    pub fn restore(&self, key: CacheKey, answer: CachedAnswer, ttl: Duration) {
+        if !ttl.is_zero() {
+            self.store(key, answer, ttl.min(self.max_ttl));
+        }
+    }
+
+    fn store(&self, key: CacheKey, answer: CachedAnswer, ttl: Duration) {
+        let size = answer_size(&key, &answer);
+        let max_entries = self.max_entries.div_ceil(self.shards.len());
+        let max_bytes = self.max_bytes / self.shards.len();
+        if size > max_bytes || max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.shard(&key);
+        entries.remove(&key);
+        while entries.map.len() >= max_entries || entries.bytes + size > max_bytes {
+            if !entries.evict_oldest() {
+                break;
+            }
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let used = entries.tick();
+        entries.lru.insert(used, key.clone());
+        entries.bytes += size;
+        let entry = Entry {
+            answer,
+            inserted: Instant::now(),
+            ttl,
+            size,
+            used,
+            hits: 0,
+            refresh_at: self.refresh.refresh_after(ttl),
+            prefetched: None,
+        };
+        entries.map.insert(key, entry);
+    }
+
+    /// Whether the entry for `key` should be refreshed ahead of its expiry, or since it has
+    /// expired: it has been hit at least `min_hits` times and is close to the end of its TTL
+    /// or past it. Only the first caller to ask gets true, so an entry is prefetched once no
+    /// matter how many hits race for it, unless that prefetch hasn't replaced it within 30
+    /// seconds.
+    pub fn claim_prefetch(&self, key: &CacheKey, min_hits: u64) -> bool {
+        let mut entries = self.shard(key);
+        let Some(entry) = entries.map.get_mut(key) else {
+            return false;
+        };
+        let prefetching = entry
+            .prefetched
+            .is_some_and(|started| started.elapsed() < RETRY_REFRESH);
+        if prefetching || entry.hits < min_hits || entry.inserted.elapsed() < entry.refresh_at {
+            return false;
+        }
+        entry.prefetched = Some(Instant::now());
+        true
+    }
+
+    pub fn contains(&self, key: &CacheKey) -> bool {
+        self.shard(key).map.contains_key(key)
+    }
+
+    pub fn remove(&self, key: &CacheKey) {
+        self.shard(key).remove(key);
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            *shard.lock().unwrap() = Entries::default();
+        }
+    }
+
+    /// The number of entries, including expired ones that haven't been looked up since.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().map.len())
+            .sum()
+    }
+
+    /// The estimated memory the entries take, see [`Cache::max_bytes`].
+    pub fn bytes(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().bytes)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &CacheKey) -> MutexGuard<'_, Entries> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        self.shards[index].lock().unwrap()
+    }
+}
+
+fn new_shards(count: usize) -> Box<[Mutex<Entries>]> {
+    (0..count).map(|_| Mutex::default()).collect()
+}
+
+fn age_records(answer: &mut CachedAnswer, age: Duration) {
+    let elapsed = age.as_secs() as u32;
+    for rec in answer.answers.iter_mut().chain(&mut answer.authorities) {
+        rec.set_ttl(rec.ttl().saturating_sub(elapsed));
+    }
+}
+
+// rfc 2308 section 5, the smaller of the SOA's own TTL and its minimum field
+fn negative_ttl(response: &DnsPacket) -> Option<Duration> {
+    response.authorities.iter().find_map(|rec| match *rec {
+        DnsRecord::SOA { ttl, minimum, .. } => Some(Duration::from_secs(ttl.min(minimum) as u64)),
+        _ => None,
+    })
+}
+
+// the entry itself plus whatever its names and records hold on the heap
+fn answer_size(key: &CacheKey, answer: &CachedAnswer) -> usize {
+    mem::size_of::<Entry>()
+        + mem::size_of::<CacheKey>()
+        + key.name.len()
+        + answer
+            .answers
+            .iter()
+            .chain(&answer.authorities)
+            .map(record_size)
+            .sum::<usize>()
+}
+
+fn record_size(rec: &DnsRecord) -> usize {
+    let rdata = match rec {
+        DnsRecord::UNKNOWN { data, .. }
+        | DnsRecord::NULL { data, .. }
+        | DnsRecord::OPT { data, .. } => data.len(),
+        DnsRecord::NS { host, .. }
+        | DnsRecord::CNAME { host, .. }
+        | DnsRecord::PTR { host, .. }
+        | DnsRecord::MX { host, .. }
+        | DnsRecord::SRV { host, .. }
+        | DnsRecord::DNAME { host, .. } => host.len(),
+        DnsRecord::SOA { mname, rname, .. } => mname.len() + rname.len(),
+        DnsRecord::TXT { data, .. } => data
+            .iter()
+            .map(|s| mem::size_of::<String>() + s.len())
+            .sum(),
+        DnsRecord::DS { digest: data, .. }
+        | DnsRecord::DNSKEY {
+            public_key: data, ..
+        }
+        | DnsRecord::NSEC3PARAM { salt: data, .. } => data.len(),
+        DnsRecord::RRSIG {
+            signer, signature, ..
+        } => signer.len() + signature.len(),
+        DnsRecord::NSEC { next, types, .. } => next.len() + mem::size_of_val(types.as_slice()),
+        DnsRecord::NSEC3 {
+            salt,
+            next_hashed,
+            types,
+            ..
+        } => salt.len() + next_hashed.len() + mem::size_of_val(types.as_slice()),
+        DnsRecord::SVCB { target, params, .. } => {
+            target.len()
+                + params
+                    .iter()
+                    .map(|(_, value)| mem::size_of::<(u16, Vec<u8>)>() + value.len())
+                    .sum::<usize>()
+        }
+        DnsRecord::A { .. } | DnsRecord::AAAA { .. } => 0,
+    };
+    mem::size_of::<DnsRecord>() + rec.domain().len() + rdata
+}
+
+/// Answers from a [`Cache`] where it can and asks `inner` otherwise, caching what it says.
+pub struct Cached<H> {
+    inner: Arc<H>,
+    cache: Arc<Cache>,
+    warm_up: Vec<(String, QueryType)>,
+    prefetch_hits: Option<u64>,
+    prefetches: Arc<Semaphore>,
+    // the prefetches out to each upstream
+    refreshes: Arc<RefreshLimiter>,
+    in_flight: Mutex<HashMap<CacheKey, Arc<OnceCell<Option<DnsPacket>>>>>,
+}
+
+impl<H: Handler> Cached<H> {
+    pub fn new(inner: H, cache: Cache) -> Self {
+        Self::shared(inner, Arc::new(cache))
+    }
+
+    /// Caches in a cache that may outlive it, e.g. one kept across reloads.
+    pub fn shared(inner: H, cache: Arc<Cache>) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            refreshes: Arc::new(RefreshLimiter::new(&cache.refresh)),
+            cache,
+            warm_up: Vec::new(),
+            prefetch_hits: Some(DEFAULT_PREFETCH_HITS),
+            prefetches: Arc::new(Semaphore::new(MAX_CONCURRENT_PREFETCHES)),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How many hits an entry needs before it's refreshed ahead of its expiry, None turns
+    /// prefetching off.
+    pub fn prefetch(mut self, min_hits: Option<u64>) -> Self {
+        self.prefetch_hits = min_hits;
+        self
+    }
+
+    /// Names to resolve ahead of clients asking for them, see [`Cached::warm`].
+    pub fn warm_up(mut self, names: Vec<(String, QueryType)>) -> Self {
+        self.warm_up = names;
+        self
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Resolves the warm-up names through the inner handler and caches the answers, replacing
+    /// whatever was cached for them. Meant to run at startup, before the listeners take
+    /// queries. Returns how many of the names got an answer worth caching.
+    pub async fn warm(&self) -> usize {
+        // the answers aren't for anyone in particular
+        let src = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let mut warmed = 0;
+        for (name, qtype) in &self.warm_up {
+            let query = DnsPacket::query(name, *qtype).build();
+            let key = CacheKey::from(&query.questions[0]);
+            let Some(response) = self.inner.handle(query, src).await else {
+                continue;
+            };
+            self.cache.insert(key.clone(), &response);
+            if self.cache.contains(&key) {
+                warmed += 1;
+            }
+        }
+        warmed
+    }
+
+    // refreshes the entry on its own task, the hit that triggered it is answered from the cache
+    // in the meantime. when too many prefetches are running already, in all or towards the
+    // upstreams it would go to, this one is skipped, the entry will simply expire and be
+    // resolved again on the next miss.
+    fn spawn_prefetch(&self, key: CacheKey, src: SocketAddr) {
+        let Ok(permit) = self.prefetches.clone().try_acquire_owned() else {
+            return;
+        };
+        self.cache
+            .counters
+            .prefetches
+            .fetch_add(1, Ordering::Relaxed);
+        let inner = self.inner.clone();
+        let cache = self.cache.clone();
+        let prefetch = async move {
+            let query = DnsPacket::query(&key.name, key.qtype)
+                .class(key.class)
+                .build();
+            if let Some(response) = inner.handle(query, src).await {
+                cache.insert(key, &response);
+            }
+            drop(permit);
+        };
+        tokio::spawn(refresh::limited(self.refreshes.clone(), prefetch));
+    }
+
+    // resolves a miss, or waits for the resolution another miss for the same key already
+    // started. whoever runs first asks the inner handler, the rest get a copy of its answer
+    // addressed to them. if the one resolving is cancelled, e.g. by the query timeout, one of
+    // the waiters takes over.
+    async fn resolve(
+        &self,
+        key: CacheKey,
+        request: DnsPacket,
+        src: SocketAddr,
+    ) -> Option<DnsPacket> {
+        let shared = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.entry(key.clone()).or_default().clone()
+        };
+
+        let mut led = false;
+        let response = shared
+            .get_or_init(|| {
+                led = true;
+                let request = request.clone();
+                async {
+                    let response = self.inner.handle(request, src).await;
+                    if let Some(response) = &response {
+                        self.cache.insert(key.clone(), response);
+                    }
+                    response
+                }
+            })
+            .await;
+        // waiting on someone else's miss is waiting on upstream all the same
+        answered_from(Origin::Upstream);
+
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight
+                .get(&key)
+                .is_some_and(|cell| Arc::ptr_eq(cell, &shared))
+            {
+                in_flight.remove(&key);
+            }
+        }
+
+        if led {
+            return response.clone();
+        }
+        self.cache
+            .counters
+            .coalesced
+            .fetch_add(1, Ordering::Relaxed);
+        let response = response.as_ref()?;
+        Some(CachedAnswer::from_response(response).response_to(&request))
+    }
+
+    /// Empties the cache and warms it up again.
+    pub async fn flush(&self) -> usize {
+        self.cache.clear();
+        self.warm().await
+    }
+}
+
+impl<H: Handler> Handler for Cached<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let Some(key) = request.questions.first().map(CacheKey::from) else {
+            return self.inner.handle(request, src).await;
+        };
+
+        if let Some(cached) = self.cache.get(&key) {
+            answered_from(Origin::Cache);
+            if let Some(min_hits) = self.prefetch_hits {
+                if self.cache.claim_prefetch(&key, min_hits) {
+                    self.spawn_prefetch(key, src);
+                }
+            }
+            return Some(cached.response_to(&request));
+        }
+        if let Some(chased) = self.cache.chase(&key) {
+            answered_from(Origin::Cache);
+            return Some(chased.response_to(&request));
+        }
+        if let Some(stale) = self.cache.get_stale(&key) {
+            answered_from(Origin::Cache);
+            if self.cache.claim_prefetch(&key, 0) {
+                self.spawn_prefetch(key, src);
+            }
+            return Some(stale.response_to(&request));
+        }
+
+        self.resolve(key, request, src).await
+    }
+
+    // transfers aren't cached, they're rare and their answers don't fit in an entry
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.inner.transfer(request, src).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+
+    fn answer(name: &str, qtype: QueryType, record: DnsRecord) -> DnsPacket {
+        let query = DnsPacket::query(name, qtype).build();
+        let mut res = DnsPacket::response_to(&query);
+        res.add_answer(record);
+        res
+    }
+
+    #[test]
+    fn chases_cnames_whatever_their_case() {
+        let cache = Cache::new();
+        let alias = DnsRecord::CNAME {
+            domain: "Alias.Example.com.".to_string(),
+            class: 1,
+            ttl: 300,
+            host: "target.example.com".to_string(),
+        };
+        let target = DnsRecord::A {
+            domain: "target.example.com".to_string(),
+            class: 1,
+            ttl: 300,
+            ip: 0xc000_020a,
+        };
+        let key = CacheKey::new("alias.example.com", QueryType::CNAME, 1);
+        cache.insert(key, &answer("alias.example.com", QueryType::CNAME, alias));
+        let key = CacheKey::new("target.example.com", QueryType::A, 1);
+        cache.insert(key, &answer("target.example.com", QueryType::A, target));
+
+        let chased = cache.chase(&CacheKey::new("ALIAS.example.com", QueryType::A, 1));
+        assert_eq!(chased.map(|answer| answer.answers.len()), Some(2));
+    }
+
+    #[tokio::test]
+    async fn serves_stale_answers_while_refreshing_them() {
+        let mut stale = StalePolicy::new(StaleStrategy::StaleWhileRevalidate);
+        stale.set("bank.example", StaleStrategy::Strict);
+        let cache = Arc::new(Cache::new().serve_stale(stale));
+        let a = |name: &str, ip: u32| DnsRecord::A {
+            domain: name.to_string(),
+            class: 1,
+            ttl: 300,
+            ip,
+        };
+        for name in ["www.example.com", "www.bank.example"] {
+            let answer = CachedAnswer::from_response(&answer(name, QueryType::A, a(name, 1)));
+            let key = CacheKey::new(name, QueryType::A, 1);
+            cache.restore(key, answer, Duration::from_millis(1));
+        }
+        thread::sleep(Duration::from_millis(10));
+
+        let key = CacheKey::new("www.example.com", QueryType::A, 1);
+        assert!(cache.get(&key).is_none());
+        let stale = cache.get_stale(&key).unwrap();
+        assert_eq!(stale.answers[0].ttl(), STALE_TTL);
+        let bank = CacheKey::new("www.bank.example", QueryType::A, 1);
+        assert!(cache.get(&bank).is_none());
+        assert!(cache.get_stale(&bank).is_none());
+
+        // the stale answer goes out right away, and the refresh replaces it
+        let refreshes = Arc::new(AtomicU64::new(0));
+        let counted = refreshes.clone();
+        let inner = move |request: DnsPacket, _| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            let mut res = DnsPacket::response_to(&request);
+            res.add_answer(a("www.example.com", 2));
+            async move { Some(res) }
+        };
+        let cached = Cached::shared(inner, cache.clone());
+        let query = DnsPacket::query("www.example.com", QueryType::A).build();
+        let src = "127.0.0.1:5300".parse().unwrap();
+        let res = cached.handle(query.clone(), src).await.unwrap();
+        assert!(matches!(
+            res.answers[..],
+            [DnsRecord::A {
+                ip: 1,
+                ttl: STALE_TTL,
+                ..
+            }]
+        ));
+        for _ in 0..100 {
+            if cache.get(&key).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(refreshes.load(Ordering::Relaxed), 1);
+        let res = cached.handle(query, src).await.unwrap();
+        assert!(matches!(res.answers[..], [DnsRecord::A { ip: 2, .. }]));
+    }
+
+    #[test]
+    fn keeps_only_the_cnames_that_follow_from_the_question() {
+        let cache = Cache::new();
+        let cname = |domain: &str, host: &str| DnsRecord::CNAME {
+            domain: domain.to_string(),
+            class: 1,
+            ttl: 300,
+            host: host.to_string(),
+        };
+        let mut response = answer(
+            "www.example.com",
+            QueryType::A,
+            cname("www.example.com", "a.example.net"),
+        );
+        response.add_answer(cname("bank.example", "evil.example"));
+        response.add_answer(cname("a.example.net", "b.example.net"));
+        response.add_answer(DnsRecord::A {
+            domain: "b.example.net".to_string(),
+            class: 1,
+            ttl: 300,
+            ip: 0xc000_020a,
+        });
+        cache.insert(CacheKey::new("www.example.com", QueryType::A, 1), &response);
+
+        for hop in ["www.example.com", "a.example.net"] {
+            assert!(
+                cache
+                    .get(&CacheKey::new(hop, QueryType::CNAME, 1))
+                    .is_some(),
+                "{}",
+                hop
+            );
+        }
+        assert!(cache
+            .get(&CacheKey::new("bank.example", QueryType::CNAME, 1))
+            .is_none());
+        assert!(cache
+            .chase(&CacheKey::new("bank.example", QueryType::A, 1))
+            .is_none());
+    }
+}
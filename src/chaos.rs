@@ -0,0 +1,167 @@
+// the names in class CH that tell who a server is (rfc 4892): version.bind and version.server
+// for the software, hostname.bind and id.server for the instance answering, asked for with
+// type TXT by monitoring and by whoever debugs which server of an anycast or load-balanced
+// set they reached. each answers with its configured text, the defaults being the server's
+// version and the host's name, or is refused for operators who'd rather not say. nothing in
+// class CH is ever forwarded: an upstream's answer would be about the upstream, not us, and any
+// other CH name is refused.
+use crate::error::{DnsError, Result};
+use crate::server::Handler;
+use crate::structure::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use std::net::SocketAddr;
+
+/// Class CH, the chaos class.
+pub const CLASS: u16 = 3;
+
+// the answers can change on a reload, nothing's gained caching them
+const TTL: u32 = 0;
+
+/// One of the things a server can be asked about itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Probe {
+    /// version.bind and version.server.
+    Version,
+    /// hostname.bind.
+    Hostname,
+    /// id.server.
+    Id,
+}
+
+impl Probe {
+    /// The names it's asked for under.
+    pub fn names(self) -> &'static [&'static str] {
+        match self {
+            Probe::Version => &["version.bind", "version.server"],
+            Probe::Hostname => &["hostname.bind"],
+            Probe::Id => &["id.server"],
+        }
+    }
+
+    fn find(name: &str) -> Option<Self> {
+        let name = name.trim_end_matches('.');
+        [Probe::Version, Probe::Hostname, Probe::Id]
+            .into_iter()
+            .find(|probe| probe.names().iter().any(|n| n.eq_ignore_ascii_case(name)))
+    }
+}
+
+impl std::str::FromStr for Probe {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "version" => Ok(Probe::Version),
+            "hostname" => Ok(Probe::Hostname),
+            "id" => Ok(Probe::Id),
+            _ => Err(DnsError::Syntax(format!(
+                "{:?} isn't version, hostname or id",
+                s
+            ))),
+        }
+    }
+}
+
+/// How a probe is answered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// A TXT record with the text.
+    Text(String),
+    /// REFUSED, as if the name were any other in class CH.
+    Refuse,
+}
+
+impl std::str::FromStr for Reply {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "" => Err(DnsError::Syntax("needs a text or refuse".into())),
+            "refuse" => Ok(Reply::Refuse),
+            // one character-string, which is all clients show
+            text if text.len() > 255 => Err(DnsError::Syntax(format!(
+                "{:?} is longer than the 255 bytes of a TXT string",
+                text
+            ))),
+            text => Ok(Reply::Text(text.to_string())),
+        }
+    }
+}
+
+/// Answers the CH class questions about this server before asking `inner` anything else.
+pub struct Chaos<H> {
+    inner: H,
+    version: Reply,
+    hostname: Reply,
+    id: Reply,
+}
+
+impl<H: Handler> Chaos<H> {
+    /// Answers with the server's version and the host's name, or refuses those if the host
+    /// has no name.
+    pub fn new(inner: H) -> Self {
+        let hostname = match crate::logging::hostname() {
+            Some(name) if !name.is_empty() => Reply::Text(name),
+            _ => Reply::Refuse,
+        };
+        Self {
+            inner,
+            version: Reply::Text(format!("dns-server {}", env!("CARGO_PKG_VERSION"))),
+            hostname: hostname.clone(),
+            id: hostname,
+        }
+    }
+
+    /// Answers `probe` with `reply` instead.
+    pub fn reply(mut self, probe: Probe, reply: Reply) -> Self {
+        match probe {
+            Probe::Version => self.version = reply,
+            Probe::Hostname => self.hostname = reply,
+            Probe::Id => self.id = reply,
+        }
+        self
+    }
+
+    /// The response for `request` if it asks anything in class CH.
+    pub fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if question.class != CLASS {
+            return None;
+        }
+        let reply = match Probe::find(&question.name) {
+            Some(Probe::Version) => &self.version,
+            Some(Probe::Hostname) => &self.hostname,
+            Some(Probe::Id) => &self.id,
+            None => &Reply::Refuse,
+        };
+
+        let mut res = DnsPacket::response_to(request);
+        match reply {
+            Reply::Refuse => {
+                res.set_rcode(ResultCode::REFUSED);
+            }
+            Reply::Text(text) => {
+                res.set_authoritative(true);
+                // ANY gets the TXT record too, other types have nothing
+                if matches!(question.qtype, QueryType::TXT | QueryType::UNKNOWN(255)) {
+                    res.add_answer(DnsRecord::TXT {
+                        domain: question.name.clone(),
+                        class: CLASS,
+                        ttl: TTL,
+                        data: vec![text.clone()],
+                    });
+                }
+            }
+        }
+        res.set_edns(DnsPacket::response_edns(request).as_ref());
+        Some(res)
+    }
+}
+
+impl<H: Handler> Handler for Chaos<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        match self.answer(&request) {
+            Some(res) => Some(res),
+            None => self.inner.handle(request, src).await,
+        }
+    }
+}
@@ -0,0 +1,582 @@
+// sending queries to other servers. every query goes out from a fresh socket on a random port,
+// connected to the server, so a response only counts if it comes from the server to that port
+// with the query's id and question. truncated answers are fetched again over tcp. zone
+// transfers are tcp only and come back as a series of messages. with a TSIG key everything sent
+// is signed and only responses signed with the same key count. with 0x20 the letters of the
+// name asked about are randomly upper or lower case, and servers echo the question as it was
+// sent, so a spoofed response has to guess the case on top of the id and port. a response that
+// gets it wrong, because it's spoofed or because the server doesn't keep the case, makes us ask
+// again over tcp, which can't be spoofed that way, and is counted against the server (see
+// upstreams.rs) so one that never keeps it gets names in lower case. lookups by name go through the nameservers
+// and search list of a resolv.conf, see resolv_conf.rs.
+use crate::error::{DnsError, Result};
+use crate::journal::{serial_newer, soa_serial, ZoneDiff};
+use crate::limits::UpstreamSockets;
+use crate::metrics::AnomalyCounters;
+use crate::resolv_conf::ResolvConf;
+use crate::structure::{
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, MIN_UDP_PAYLOAD,
+};
+use crate::tsig::{Session, TsigKey};
+use rand::Rng;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+// random ports tried for a query's socket before leaving the choice to the kernel
+const PORT_ATTEMPTS: usize = 8;
+// below this are the ports of well-known services
+const MIN_PORT: u16 = 1024;
+
+pub struct Client {
+    timeout: Duration,
+    sockets: UpstreamSockets,
+    counters: AnomalyCounters,
+    key: Option<TsigKey>,
+    randomize_case: bool,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            sockets: UpstreamSockets::default(),
+            counters: AnomalyCounters::default(),
+            key: None,
+            randomize_case: false,
+        }
+    }
+
+    /// How long to wait for a server, including a retry over TCP.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Caps the number of sockets open towards servers at once.
+    pub fn max_sockets(mut self, max: usize) -> Self {
+        self.sockets = UpstreamSockets::new(max);
+        self
+    }
+
+    /// Signs queries and transfer requests with `key`, and rejects responses that aren't
+    /// signed with it.
+    pub fn tsig(mut self, key: TsigKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Randomizes the case of the name in queries sent over UDP and checks that responses
+    /// have it the same (draft-vixie-dnsext-dns0x20).
+    pub fn randomize_case(mut self, on: bool) -> Self {
+        self.randomize_case = on;
+        self
+    }
+
+    pub fn counters(&self) -> &AnomalyCounters {
+        &self.counters
+    }
+
+    /// Sends `query` to `server` and waits for the matching response. The query needs exactly
+    /// one question.
+    pub async fn query(&self, server: SocketAddr, query: &mut DnsPacket) -> Result<DnsPacket> {
+        let (response, _) = self.query_cased(server, query, true).await?;
+        Ok(response)
+    }
+
+    /// Like [`Client::query`], randomizing the case of the name only if `randomize` does too,
+    /// for servers known not to keep it. Also says whether the response kept the case, None if
+    /// it wasn't randomized.
+    pub async fn query_cased(
+        &self,
+        server: SocketAddr,
+        query: &mut DnsPacket,
+        randomize: bool,
+    ) -> Result<(DnsPacket, Option<bool>)> {
+        let [question] = &query.questions[..] else {
+            return Err(DnsError::QuestionCount(query.questions.len()));
+        };
+        let question = question.clone();
+
+        let mut out = BytePacketBuffer::new();
+        query.write(&mut out)?;
+        let randomized = self.randomize_case && randomize;
+        if randomized {
+            randomize_case(&mut out.buf[..out.pos]);
+        }
+        let session = self.sign(&mut out)?;
+        // a response can't be larger than the query said it could take
+        let payload = match query.edns() {
+            Some(Ok(edns)) => edns.payload_size.max(MIN_UDP_PAYLOAD),
+            _ => MIN_UDP_PAYLOAD,
+        };
+        let exchange = self.exchange(
+            server,
+            out.as_slice(),
+            &question,
+            session,
+            randomized,
+            payload,
+        );
+        match time::timeout(self.timeout, exchange).await {
+            Ok(res) => res,
+            Err(_) => Err(DnsError::Timeout(server)),
+        }
+    }
+
+    /// Like [`Client::query`], but over TCP from the start.
+    pub async fn query_tcp(&self, server: SocketAddr, query: &mut DnsPacket) -> Result<DnsPacket> {
+        let [question] = &query.questions[..] else {
+            return Err(DnsError::QuestionCount(query.questions.len()));
+        };
+        let question = question.clone();
+
+        let mut out = BytePacketBuffer::new();
+        query.write(&mut out)?;
+        let session = self.sign(&mut out)?;
+        let exchange = async {
+            let _permit = self.sockets.acquire().await;
+            self.exchange_tcp(server, out.as_slice(), &question, session)
+                .await
+        };
+        match time::timeout(self.timeout, exchange).await {
+            Ok(res) => res,
+            Err(_) => Err(DnsError::Timeout(server)),
+        }
+    }
+
+    /// Looks `name` up like the system's stub resolver would: with the suffixes of the search
+    /// list in `conf`'s order, asking its nameservers in turn until one of them answers. A name
+    /// that doesn't exist, has no records of the type or that no nameserver could answer for
+    /// leads to the next suffix, and if no suffix does better the first empty answer is
+    /// returned, else the last NXDOMAIN, else the last failure.
+    pub async fn lookup(
+        &self,
+        conf: &ResolvConf,
+        name: &str,
+        qtype: QueryType,
+    ) -> Result<DnsPacket> {
+        let mut nodata = None;
+        let mut nxdomain = None;
+        let mut last_err = DnsError::NoNameservers(name.to_string());
+        for candidate in conf.candidates(name) {
+            let mut query = DnsPacket::query(&candidate, qtype).build();
+            let mut response = None;
+            for &server in &conf.nameservers {
+                match self.query(server, &mut query).await {
+                    Ok(res)
+                        if matches!(
+                            res.header.rcode,
+                            ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP
+                        ) =>
+                    {
+                        last_err = DnsError::ServerFailure(server, res.header.rcode);
+                    }
+                    Ok(res) => {
+                        response = Some(res);
+                        break;
+                    }
+                    Err(e) => last_err = e,
+                }
+            }
+            let Some(response) = response else {
+                continue;
+            };
+            match response.header.rcode {
+                ResultCode::NXDOMAIN => nxdomain = Some(response),
+                ResultCode::NOERROR if response.answers.is_empty() => {
+                    nodata.get_or_insert(response);
+                }
+                _ => return Ok(response),
+            }
+        }
+        nodata.or(nxdomain).ok_or(last_err)
+    }
+
+    /// Fetches all of `zone` from `server` with AXFR (rfc 5936). The records are in the order
+    /// the server sent them, starting with the zone's SOA, without the SOA that ends the
+    /// transfer. The timeout applies to each message rather than the whole transfer, which
+    /// can take a while for a large zone.
+    pub async fn transfer(&self, server: SocketAddr, zone: &str) -> Result<Vec<DnsRecord>> {
+        let mut query = DnsPacket::query(zone, QueryType::AXFR)
+            .recursion_desired(false)
+            .edns(None)
+            .build();
+        let incoming = Incoming::new(server, None);
+        match self.stream_transfer(server, &mut query, incoming).await? {
+            Transfer::Full(records) => Ok(records),
+            // without a serial of our own there's nothing to be up to date with or to apply
+            // changes to
+            _ => unreachable!("AXFR always transfers the whole zone"),
+        }
+    }
+
+    /// Asks `server` for the changes to `zone` since the version with the SOA `soa` (IXFR, rfc
+    /// 1995). Servers that don't have them send the whole zone instead.
+    pub async fn transfer_incremental(
+        &self,
+        server: SocketAddr,
+        zone: &str,
+        soa: &DnsRecord,
+    ) -> Result<Transfer> {
+        let Some(serial) = soa_serial(soa) else {
+            return Err(DnsError::InvalidRdata(format!(
+                "{} isn't a SOA record",
+                soa
+            )));
+        };
+        let mut query = DnsPacket::query(zone, QueryType::IXFR)
+            .recursion_desired(false)
+            .edns(None)
+            .build();
+        query.add_authority(soa.clone());
+        let incoming = Incoming::new(server, Some(serial));
+        self.stream_transfer(server, &mut query, incoming).await
+    }
+
+    async fn stream_transfer(
+        &self,
+        server: SocketAddr,
+        query: &mut DnsPacket,
+        mut incoming: Incoming,
+    ) -> Result<Transfer> {
+        let question = query.questions[0].clone();
+        let mut out = BytePacketBuffer::new();
+        query.write(&mut out)?;
+        let mut session = self.sign(&mut out)?;
+        let query = out.as_slice();
+
+        let _permit = self.sockets.acquire().await;
+        let mut stream = match time::timeout(self.timeout, TcpStream::connect(server)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(DnsError::Timeout(server)),
+        };
+        write_tcp_message(&mut stream, query).await?;
+
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        loop {
+            let (mut buf, len) =
+                match time::timeout(self.timeout, read_tcp_message(&mut stream)).await {
+                    Ok(msg) => msg?,
+                    Err(_) => return Err(DnsError::Timeout(server)),
+                };
+            let mut response = read_response(&mut buf, len)?;
+            let signed = match &mut session {
+                Some(session) => session.verify(&mut response, &buf.buf[..len])?,
+                None => false,
+            };
+            // messages after the first may leave the question out
+            let matches = match response.questions.is_empty() {
+                true => response.header.id == id,
+                false => matches_query(&response, id, &question),
+            };
+            if !matches {
+                return Err(DnsError::MismatchedResponse(server));
+            }
+            if response.header.rcode != ResultCode::NOERROR {
+                return Err(DnsError::ServerFailure(server, response.header.rcode));
+            }
+
+            for rec in response.answers {
+                if incoming.push(rec)? {
+                    // unsigned messages are only covered by a signed one after them
+                    if session.is_some() && !signed {
+                        return Err(DnsError::Tsig(
+                            "the last message of the transfer isn't signed".into(),
+                        ));
+                    }
+                    return Ok(incoming.finish());
+                }
+            }
+        }
+    }
+
+    // signs the query in `out` if there's a key, returning the session its response is
+    // checked with
+    fn sign(&self, out: &mut BytePacketBuffer) -> Result<Option<Session>> {
+        self.key
+            .as_ref()
+            .map(|key| Session::sign_request(key, out, 0))
+            .transpose()
+    }
+
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        query: &[u8],
+        question: &DnsQuestion,
+        mut session: Option<Session>,
+        randomized: bool,
+        payload: u16,
+    ) -> Result<(DnsPacket, Option<bool>)> {
+        let _permit = self.sockets.acquire().await;
+        let socket = bind_random_port(server).await?;
+        socket.connect(server).await?;
+        socket.send(query).await?;
+
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        loop {
+            let mut buf = BytePacketBuffer::with_len(payload as usize);
+            let len = socket.recv(&mut buf.buf[..]).await?;
+            if !self.counters.accept_response(&buf.buf[..len]) {
+                continue;
+            }
+            // anything that doesn't parse or doesn't match is ignored, it may be a spoofing
+            // attempt racing the real answer
+            let response = read_response(&mut buf, len)
+                .ok()
+                .filter(|response| matches_query(response, id, question));
+            let Some(mut response) = response else {
+                self.counters
+                    .unmatched_responses
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            let kept_case = randomized.then(|| wire_name(query) == wire_name(&buf.buf[..len]));
+            if kept_case == Some(false) {
+                self.counters
+                    .case_mismatches
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            if kept_case == Some(false) || response.header.flags.truncated {
+                drop(socket);
+                let response = self.exchange_tcp(server, query, question, session).await?;
+                return Ok((response, kept_case));
+            }
+            if let Some(session) = &mut session {
+                session.verify(&mut response, &buf.buf[..len])?;
+            }
+            return Ok((response, kept_case));
+        }
+    }
+
+    async fn exchange_tcp(
+        &self,
+        server: SocketAddr,
+        query: &[u8],
+        question: &DnsQuestion,
+        session: Option<Session>,
+    ) -> Result<DnsPacket> {
+        let mut stream = TcpStream::connect(server).await?;
+        write_tcp_message(&mut stream, query).await?;
+
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        let (mut buf, len) = read_tcp_message(&mut stream).await?;
+        let mut response = read_response(&mut buf, len)?;
+        if !matches_query(&response, id, question) {
+            return Err(DnsError::MismatchedResponse(server));
+        }
+        if let Some(mut session) = session {
+            session.verify(&mut response, &buf.buf[..len])?;
+        }
+        Ok(response)
+    }
+}
+
+/// What an incremental transfer came back with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    /// The zone hasn't changed since the version asked about.
+    UpToDate,
+    /// The whole zone, starting with its SOA, like AXFR would have sent it.
+    Full(Vec<DnsRecord>),
+    /// The changes since the version asked about, oldest first.
+    Incremental(Vec<ZoneDiff>),
+}
+
+enum Stage {
+    Start,
+    // the second record tells an incremental answer from a full one
+    Second,
+    Full,
+    Removing,
+    Adding,
+    UpToDate,
+}
+
+// puts the records of a transfer back together as they come in. both kinds start and end with
+// the zone's latest SOA, in between an incremental one has a SOA before each change's removed
+// and before its added records (rfc 1995 section 4).
+struct Incoming {
+    server: SocketAddr,
+    // the serial the client has, for IXFR
+    ours: Option<u32>,
+    latest: u32,
+    stage: Stage,
+    records: Vec<DnsRecord>,
+    diffs: Vec<ZoneDiff>,
+}
+
+impl Incoming {
+    fn new(server: SocketAddr, ours: Option<u32>) -> Self {
+        Self {
+            server,
+            ours,
+            latest: 0,
+            stage: Stage::Start,
+            records: Vec::new(),
+            diffs: Vec::new(),
+        }
+    }
+
+    // takes the next record, returning whether it was the last one
+    fn push(&mut self, rec: DnsRecord) -> Result<bool> {
+        let serial = soa_serial(&rec);
+        match self.stage {
+            Stage::Start => {
+                let Some(serial) = serial else {
+                    return Err(DnsError::TransferFailed(
+                        self.server,
+                        "the first record isn't the zone's SOA".into(),
+                    ));
+                };
+                self.latest = serial;
+                self.records.push(rec);
+                if self.ours.is_some_and(|ours| !serial_newer(serial, ours)) {
+                    self.stage = Stage::UpToDate;
+                    return Ok(true);
+                }
+                self.stage = match self.ours {
+                    Some(_) => Stage::Second,
+                    None => Stage::Full,
+                };
+            }
+            Stage::Second if serial.is_some() && serial == self.ours => self.start_diff(rec),
+            // the SOA again marks the end of the zone
+            Stage::Second | Stage::Full if serial.is_some() => return Ok(true),
+            Stage::Second | Stage::Full => {
+                self.records.push(rec);
+                self.stage = Stage::Full;
+            }
+            Stage::Removing => {
+                let diff = self.diffs.last_mut().expect("a change was started");
+                match serial {
+                    Some(_) => {
+                        diff.to = rec;
+                        self.stage = Stage::Adding;
+                    }
+                    None => diff.removed.push(rec),
+                }
+            }
+            Stage::Adding => match serial {
+                Some(serial) if serial == self.latest => return Ok(true),
+                Some(_) => self.start_diff(rec),
+                None => {
+                    let diff = self.diffs.last_mut().expect("a change was started");
+                    diff.added.push(rec);
+                }
+            },
+            Stage::UpToDate => return Ok(true),
+        }
+        Ok(false)
+    }
+
+    fn start_diff(&mut self, from: DnsRecord) {
+        self.diffs.push(ZoneDiff {
+            // replaced once the SOA it leads to comes in
+            to: from.clone(),
+            from,
+            removed: Vec::new(),
+            added: Vec::new(),
+        });
+        self.stage = Stage::Removing;
+    }
+
+    fn finish(self) -> Transfer {
+        match self.stage {
+            Stage::UpToDate => Transfer::UpToDate,
+            Stage::Removing | Stage::Adding => Transfer::Incremental(self.diffs),
+            _ => Transfer::Full(self.records),
+        }
+    }
+}
+
+// tcp messages are prefixed with their length, rfc 1035 section 4.2.2
+async fn write_tcp_message(stream: &mut TcpStream, msg: &[u8]) -> Result<()> {
+    let mut framed = Vec::with_capacity(msg.len() + 2);
+    framed.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed).await?;
+    Ok(())
+}
+
+// the message and its length, still to be parsed
+async fn read_tcp_message(stream: &mut TcpStream) -> Result<(BytePacketBuffer, usize)> {
+    let mut prefix = [0u8; 2];
+    stream.read_exact(&mut prefix).await?;
+    let len = u16::from_be_bytes(prefix) as usize;
+    let mut buf = BytePacketBuffer::with_len(len);
+    stream.read_exact(&mut buf.buf[..len]).await?;
+    Ok((buf, len))
+}
+
+pub(crate) fn read_response(buf: &mut BytePacketBuffer, len: usize) -> Result<DnsPacket> {
+    DnsPacket::from_message(buf, len)
+}
+
+// a udp socket for talking to `server` on a port picked at random. kernels don't all pick
+// ephemeral ports at random, and one that's easy to guess makes spoofing responses much easier
+async fn bind_random_port(server: SocketAddr) -> Result<UdpSocket> {
+    let ip = match server {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    for _ in 0..PORT_ATTEMPTS {
+        let port = rand::thread_rng().gen_range(MIN_PORT..=u16::MAX);
+        match UdpSocket::bind(SocketAddr::new(ip, port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(UdpSocket::bind(SocketAddr::new(ip, 0)).await?)
+}
+
+// flips the letters of the question's name in the message at random
+fn randomize_case(msg: &mut [u8]) {
+    let Some(end) = wire_name(msg).map(|name| 12 + name.len()) else {
+        return;
+    };
+    for byte in &mut msg[12..end] {
+        if byte.is_ascii_alphabetic() && rand::random() {
+            *byte ^= 0x20;
+        }
+    }
+}
+
+// the question's name in a message as it is on the wire, length bytes and all. questions
+// always come first, so there's nothing before it to point at
+fn wire_name(msg: &[u8]) -> Option<&[u8]> {
+    let mut pos = 12;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        pos += len + 1;
+        if len == 0 {
+            return msg.get(12..pos);
+        }
+    }
+}
+
+pub(crate) fn matches_query(response: &DnsPacket, id: u16, question: &DnsQuestion) -> bool {
+    response.header.id == id
+        && response.questions.len() == 1
+        && response.questions[0]
+            .name
+            .eq_ignore_ascii_case(&question.name)
+        && response.questions[0].qtype == question.qtype
+        && response.questions[0].class == question.class
+}
@@ -0,0 +1,1576 @@
+// the server's config file. one directive per line, a keyword and its arguments, with `#`
+// starting a comment line:
+//
+//     listen 0.0.0.0:53
+//     listen-https 0.0.0.0:443 server.crt server.key
+//     listen-quic 0.0.0.0:853
+//     designated-resolver dns.example.net
+//     upstream 1.1.1.1 9.9.9.9
+//     resolv-conf /etc/resolv.conf
+//     hosts-file lab.hosts
+//     mdns on
+//     mdns-proxy on
+//     upstream-tls 9.9.9.9 dns.quad9.net
+//     upstream-https https://cloudflare-dns.com/dns-query 1.1.1.1
+//     edns-payload 1232
+//     client-subnet forward 24 56
+//     qname-minimisation off
+//     randomize-case off
+//     randomize-case max-mismatches 0.2
+//     upstream-race 2 50
+//     record nas.home A 192.168.1.10
+//     record files.home CNAME nas.home
+//     record nas.home 60 TXT "backups at 3am"
+//     forward corp.example 10.0.0.53 10.0.0.54
+//     validate-except corp.example
+//     zone example.com example.com.zone
+//     zone-versions 5
+//     rotate www.example.com A weighted 192.0.2.1=3 192.0.2.2=1
+//     health-check www.example.com http 80 /healthz
+//     geoip GeoLite2-Country.mmdb
+//     geo continent:EU www.example.com A 198.51.100.10
+//     dns64 64:ff9b::/96 2001:db8:64::/48
+//     rewrite 203.0.113.5 192.168.1.5 192.168.0.0/16
+//     allow-recursion local 203.0.113.0/24
+//     deny-query 198.51.100.0/24
+//     rate-limit responses 5
+//     rate-limit exempt local
+//     query-limit rate 50
+//     query-limit action drop
+//     cache max-entries 200000
+//     cache serve-stale on
+//     cache serve-stale off bank.example
+//     cache snapshot cache.snap
+//     counters counters.txt every 5m
+//     blocklist https://example.net/ads.txt
+//     allow *.cdn.example.net
+//     block-with nxdomain
+//     block-ttl 60
+//     log queries file queries.log size 100000000 every 1d keep 7
+//     log sample 0.1 *.example.com
+//     log level warning
+//     chaos version refuse
+//     chaos id ns1.example.com
+//     user dns-server
+//     chroot /var/lib/dns-server
+//     control /run/dns-server.sock
+//
+//     view internal 192.168.0.0/16 10.0.0.0/8
+//     zone example.com internal/example.com.zone
+//     record printer.example.com A 192.168.1.20
+//
+// listen-https serves DNS over HTTPS too (port 443 if the address has none), with the
+// certificate chain and private key in the PEM files given and on the path given or /dns-query,
+// see doh_server.rs. listen-quic serves DNS over QUIC (udp port 853 if the address has none),
+// with a certificate chain and key of its own or else the HTTPS listener's, see doq_server.rs.
+// designated-resolver answers clients asking for _dns.resolver.arpa with where those two
+// listeners are, under the name given, which their certificate has to be for, see ddr.rs.
+// edns-payload is the largest udp message we send or ask upstreams for (DEFAULT_EDNS_PAYLOAD if
+// not given), bigger responses are truncated. client-subnet has upstreams sent no EDNS client
+// subnet (strip, the default), the one the client sent (forward) or else one made from its
+// address (add), cut down to the v4 and v6 prefix lengths given or /24 and /56, see edns.rs.
+// answers are still cached for every client alike, whatever subnet they were given for. without
+// any upstreams the nameservers in resolv-conf (/etc/resolv.conf if not given) other than
+// ourselves are forwarded to, and names are only resolved from the root if there are none or
+// it's off, see resolv_conf.rs. the names in /etc/hosts, unless etc-hosts is off, and in every
+// hosts-file are answered before anything is looked up, and read again when the files change,
+// see hosts.rs. mdns on answers multicast DNS questions about this host's name under .local,
+// and mdns-proxy on asks the link about the .local names clients ask for, see mdns.rs.
+// qname-minimisation off has names resolved from the root asked about in full at every level,
+// see recursive.rs. randomize-case off sends names upstream in lower case rather than with
+// random upper case letters the answers have to echo, see client.rs, and randomize-case
+// max-mismatches turns it off for each upstream that gets more than that share of them back in
+// the wrong case, see upstreams.rs. upstream-race sends queries to that many upstreams at once,
+// or that many milliseconds apart while none has answered if a delay follows, see forward.rs.
+// upstream-tls forwards to an upstream over TLS (port 853 if the address has none), checking
+// its certificate is for the name given or, without one, its address, and leads to one of the
+// CAs in the tls-ca file or the system's; pin-sha256=<base64> also has a key in its chain have
+// to have that hash, see dot.rs. upstream-quic does the same over QUIC (udp port 853), see
+// doq.rs. upstream-https forwards to a DNS over HTTPS url, at the addresses given or else the
+// ones its host has, looked up with the bootstrap resolvers (the upstreams if there are none),
+// and takes pin-sha256 too, see doh.rs. records are written as in master files, with names
+// always taken as fully qualified and DEFAULT_RECORD_TTL when they don't have a ttl of their
+// own. forward sends the names under a domain to upstreams of their own, and validate-except
+// leaves domains unvalidated, which internal domains below a signed public one have to be. zone
+// serves a master file, with a path relative to the config file's directory. zone-versions is
+// how many versions of each zone are kept, the one being served among them, for the control
+// socket to roll back to (DEFAULT_ZONE_VERSIONS if not given), see versions.rs. rotate orders
+// an RRset in the zones' answers (fixed, random, round-robin or weighted by address), see
+// rotation.rs. health-check checks the addresses of a name in the zones over tcp or http and
+// leaves the failing ones out of answers, see health.rs. geo answers with a record of its own
+// for clients in a country or continent, located with the MaxMind database geoip names, see
+// geoip.rs. dns64 makes AAAA records up from A records with a NAT64 prefix (the well-known one
+// if none is given) for the clients after it, or everyone, see dns64.rs. rewrite puts the
+// second address in place of the first in answers, for the clients after it or everyone, so
+// clients behind NAT asking for a name with the router's public address get the inside one, see
+// rewrite.rs. allow-query, allow-recursion, allow-transfer and allow-update replace who's
+// allowed to do what, deny-* who's denied it, with subnets, `any`, `none` or `local` for
+// loopback and private addresses, see acl.rs. rate-limit on turns on response rate limiting
+// with its defaults, and rate-limit with one of its settings (responses, nxdomains and errors
+// per second, slip, window, ipv4-prefix, ipv6-prefix, exempt) and a value sets that, see
+// rrl.rs. query-limit does the same for limiting each client's queries (rate, burst, action
+// refuse or drop, exempt), see ratelimit.rs. cache sets a limit of every view's cache
+// (max-entries, max-bytes, max-ttl, max-negative-ttl), has names answered from expired entries
+// while those are refreshed (serve-stale on, or off, for everything or the domains after it),
+// see cache.rs, or the file the caches are saved to when the server stops and loaded from when
+// it starts (snapshot), see snapshot.rs. counters saves the queries, blocked queries and cache
+// counters to a file every interval (5m if not given) and when the server stops, and adds them
+// back when it starts, see totals.rs. blocklist and allowlist add a list file or http url to
+// block or not block the names on, allow a single name not to block, and block-with how blocked
+// names are answered (nxdomain, null or an address) and block-ttl with what TTL (10 seconds if
+// not given), all for clients that aren't in a --group, see blocklist.rs. log sends a stream
+// (queries or server) to stderr, journald, syslog at a socket path or udp address, or a file
+// that is rotated when it grows past a size, gets older than an interval, or both, with the
+// number of old files to keep, see logging.rs. the queries stream is only written when it is
+// given a target, and log sample has it take that fraction of the queries, always including the
+// names after it, see querylog.rs. log level leaves out the server's messages less severe than
+// error, warning, info (the default) or debug, whichever is given. chaos answers the CH class
+// TXT question for version.bind and version.server (version), hostname.bind (hostname) or
+// id.server (id) with a text instead of the version or host name, or refuses it, see chaos.rs.
+// user has the server become that user once its sockets are bound, in the chroot directory if
+// there is one, see privileges.rs. files read after that, by reloads, the cache snapshot or
+// counters, have to be reachable and readable for the user there. control answers commands on a
+// unix socket at the path given, like the last reload's status and what it changed or what a
+// zone has, or to roll a zone back, see control.rs. everything but listen, listen-https,
+// listen-quic, designated-resolver, upstream, resolv-conf, etc-hosts, hosts-file, mdns,
+// mdns-proxy, cache, counters, blocklist, allowlist, allow, block-with, block-ttl, log, chaos,
+// user, chroot, control, zone-versions, upstream-tls, upstream-quic, upstream-https, bootstrap,
+// tls-ca, edns-payload, client-subnet, qname-minimisation, randomize-case, upstream-race,
+// geoip, access lists and limits after a view line belongs to that view, for the clients in its
+// subnets (or `any`), up to the next view. what comes before the first view is for clients none
+// of them match. views don't inherit anything from there, see views.rs.
+//
+// a file whose name ends in .toml says the same in TOML, with the directives as keys. the toml
+// crate reads it into TomlConfig, which has a field for each directive and only those, so a
+// misspelt key is an error. a string or number is a directive's arguments, a boolean on or off,
+// and an array its arguments one by one, except for record, zone, forward, rotate,
+// health-check, geo, dns64, rewrite, hosts-file, upstream-*, blocklist, allowlist and allow,
+// which take one directive per item. a table is settings, an empty one just turns them on.
+// views are [[view]] tables with a name and clients, and errors say which line of the file
+// they're on:
+//
+//     listen = "0.0.0.0:53"
+//     upstream = ["1.1.1.1", "9.9.9.9"]
+//     record = ["nas.home A 192.168.1.10", 'nas.home 60 TXT "backups at 3am"']
+//     zone = [["example.com", "example.com.zone"]]
+//     allow-recursion = ["local", "203.0.113.0/24"]
+//     blocklist = ["https://example.net/ads.txt"]
+//     mdns = true
+//
+//     [cache]
+//     max-entries = 200000
+//
+//     [rate-limit]
+//
+//     [log]
+//     queries = "syslog 192.0.2.1:514"
+//
+//     [[view]]
+//     name = "internal"
+//     clients = ["192.168.0.0/16", "10.0.0.0/8"]
+//     zone = ["example.com internal/example.com.zone"]
+use crate::acl::{everywhere, local_nets, AccessList, Acl, Capability};
+use crate::blocklist::{BlockAction, Rule, Source};
+use crate::cache::Cache;
+use crate::chaos::{Probe, Reply};
+use crate::dns64::{Dns64Prefix, WELL_KNOWN_PREFIX};
+use crate::doh::{DohUrl, DEFAULT_PATH, HTTPS_PORT};
+use crate::doq::DOQ_PORT;
+use crate::dot::DOT_PORT;
+use crate::edns::EcsPolicy;
+use crate::error::{DnsError, Result};
+use crate::geoip::Region;
+use crate::health::Check;
+use crate::json::Value as Json;
+use crate::logging::{LogTarget, Severity, Stream};
+use crate::net::{parse_socket_addr, Subnet};
+use crate::presentation::{parse_base64, parse_record, parse_ttl};
+use crate::ratelimit::QueryLimiter;
+use crate::refresh::{StalePolicy, StaleStrategy};
+use crate::rewrite::RewriteRule;
+use crate::rotation::Rotation;
+use crate::rrl::Rrl;
+use crate::structure::{DnsRecord, QueryType};
+use crate::tls::ServerIdentity;
+use serde::de::{Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml::Spanned;
+
+/// The ttl of records in the config file that don't have one of their own.
+pub const DEFAULT_RECORD_TTL: u32 = 300;
+
+/// What the config file says, the parts of it that were given.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub listen: Option<SocketAddr>,
+    /// Where to serve DNS over HTTPS, with the PEM files of the certificate chain and its
+    /// key, and the path queries are taken on.
+    pub listen_https: Option<(SocketAddr, PathBuf, PathBuf, String)>,
+    /// Where to serve DNS over QUIC, with the PEM files of the certificate chain and its key
+    /// if it has its own rather than the HTTPS listener's.
+    pub listen_quic: Option<(SocketAddr, Option<(PathBuf, PathBuf)>)>,
+    /// The name the HTTPS and QUIC listeners are advertised under to clients that discover
+    /// them, which their certificate has to be for.
+    pub designated_resolver: Option<String>,
+    pub upstreams: Vec<SocketAddr>,
+    /// Upstreams spoken to over TLS, and who they have to prove they are.
+    pub tls_upstreams: Vec<(SocketAddr, ServerIdentity)>,
+    /// Upstreams spoken to over QUIC, and who they have to prove they are.
+    pub quic_upstreams: Vec<(SocketAddr, ServerIdentity)>,
+    /// Upstreams spoken to over HTTPS, with their addresses if given and pinned keys.
+    pub https_upstreams: Vec<(DohUrl, Vec<IpAddr>, Vec<[u8; 32]>)>,
+    /// The resolv.conf whose nameservers are the upstreams if there are none, None inside if
+    /// it's not to be read.
+    pub resolv_conf: Option<Option<PathBuf>>,
+    /// Whether /etc/hosts is read, if the config file says.
+    pub etc_hosts: Option<bool>,
+    /// Hosts files read besides /etc/hosts.
+    pub hosts_files: Vec<PathBuf>,
+    /// Whether this host's name is answered for under .local, if the config file says.
+    pub mdns: Option<bool>,
+    /// Whether clients' questions about .local names are asked on the link, if the config
+    /// file says.
+    pub mdns_proxy: Option<bool>,
+    /// The resolvers HTTPS upstreams' names are looked up with.
+    pub bootstrap: Vec<SocketAddr>,
+    /// The CAs upstreams' certificates have to lead to, the system's if not given.
+    pub tls_ca: Option<PathBuf>,
+    /// The udp payload size we advertise and answer with at most.
+    pub edns_payload: Option<u16>,
+    /// What upstreams are told of clients' addresses, if the config file says.
+    pub client_subnet: Option<EcsPolicy>,
+    /// Whether names resolved from the root are minimised, if the config file says.
+    pub qname_minimisation: Option<bool>,
+    /// Whether the case of names sent upstream is randomized, if the config file says.
+    pub randomize_case: Option<bool>,
+    /// The share of names an upstream may not echo the case of before it gets them in lower
+    /// case.
+    pub max_case_mismatches: Option<f64>,
+    /// How many upstreams a query goes to at once, and how far apart.
+    pub race: Option<(usize, Duration)>,
+    /// Who may do what, for all views alike.
+    pub acl: Acl,
+    /// The MaxMind database clients are located with.
+    pub geoip: Option<PathBuf>,
+    /// Response rate limiting, if there's a rate-limit line.
+    pub rrl: Option<Rrl>,
+    /// Per-client query limits, if there's a query-limit line.
+    pub query_limit: Option<QueryLimiter>,
+    /// The limits of each view's cache.
+    pub cache: CacheConfig,
+    /// Where the default view's cache is saved to when the server stops and loaded from when
+    /// it starts, with the other views' next to it under the file name and `.` and their name.
+    pub cache_snapshot: Option<PathBuf>,
+    /// Where the counters kept across restarts are saved, and how often if the config file
+    /// says, see [`crate::totals`].
+    pub counters: Option<(PathBuf, Option<Duration>)>,
+    /// Lists of names blocked for clients that aren't in a group, see [`crate::blocklist`].
+    pub blocklists: Vec<Source>,
+    /// Lists of names that aren't blocked even if a blocklist has them.
+    pub allowlists: Vec<Source>,
+    /// Names that aren't blocked, one rule each.
+    pub allowed: Vec<Rule>,
+    /// How blocked names are answered, if the config file says.
+    pub block_with: Option<BlockAction>,
+    /// The TTL of the answers for blocked names, if the config file says.
+    pub block_ttl: Option<u32>,
+    /// Where the log streams go, the ones not given to stderr.
+    pub logs: Vec<(Stream, LogTarget)>,
+    /// The fraction of queries the query log has, and names it always has, if there's a log
+    /// sample line.
+    pub log_sample: Option<(f64, Vec<String>)>,
+    /// The least severe of the server's messages that are logged, if there's a log level line.
+    pub log_level: Option<Severity>,
+    /// How the CH class questions about the server are answered, those that aren't by
+    /// default, see [`crate::chaos`].
+    pub chaos: Vec<(Probe, Reply)>,
+    /// Who the server becomes once its sockets are bound, see [`crate::privileges`].
+    pub user: Option<String>,
+    /// The directory the server is locked into as it becomes the user.
+    pub chroot: Option<PathBuf>,
+    /// Where the control socket is, see [`crate::control`].
+    pub control: Option<PathBuf>,
+    /// How many versions of each zone are kept to roll back to, see [`crate::versions`].
+    pub zone_versions: Option<usize>,
+    /// What clients that aren't in any of the views get.
+    pub default_view: ViewConfig,
+    pub views: Vec<ViewConfig>,
+}
+
+/// The limits of a cache, its defaults for the ones not given.
+#[derive(Clone, Debug, Default)]
+pub struct CacheConfig {
+    pub max_entries: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub max_ttl: Option<Duration>,
+    pub max_negative_ttl: Option<Duration>,
+    /// The names answered from expired entries while they're refreshed.
+    pub serve_stale: StalePolicy,
+}
+
+impl CacheConfig {
+    /// An empty cache with these limits.
+    pub fn cache(&self) -> Cache {
+        let mut cache = Cache::new();
+        if let Some(max) = self.max_entries {
+            cache = cache.max_entries(max);
+        }
+        if let Some(max) = self.max_bytes {
+            cache = cache.max_bytes(max);
+        }
+        if let Some(ttl) = self.max_ttl {
+            cache = cache.max_ttl(ttl);
+        }
+        if let Some(ttl) = self.max_negative_ttl {
+            cache = cache.max_negative_ttl(ttl);
+        }
+        cache.serve_stale(self.serve_stale.clone())
+    }
+}
+
+/// The answers a set of clients gets.
+#[derive(Clone, Debug, Default)]
+pub struct ViewConfig {
+    pub name: String,
+    pub clients: Vec<Subnet>,
+    /// Answered from here rather than being looked up, see [`crate::overrides`].
+    pub records: Vec<DnsRecord>,
+    /// Domains to forward to their own upstreams, see [`crate::forward::ConditionalForwarder`].
+    pub forwards: Vec<(String, Vec<SocketAddr>)>,
+    pub validate_except: Vec<String>,
+    /// Origins and the master files of zones to serve authoritatively.
+    pub zones: Vec<(String, PathBuf)>,
+    /// How RRsets in the zones are ordered, by name and type.
+    pub rotations: Vec<(String, QueryType, Rotation)>,
+    /// Names in the zones whose addresses are checked, and how.
+    pub health_checks: Vec<(String, Check)>,
+    /// Records for the clients in a region only.
+    pub geo: Vec<(Region, DnsRecord)>,
+    pub dns64: Vec<Dns64Prefix>,
+    /// Addresses in answers swapped for others, see [`crate::rewrite`].
+    pub rewrites: Vec<RewriteRule>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::parse_file(text, "<config>", Path::new(""))
+    }
+
+    /// Parses a config file in TOML, see the top of this file.
+    pub fn parse_toml(text: &str) -> Result<Self> {
+        Self::parse_toml_file(text, "<config>", Path::new(""))
+    }
+
+    /// Loads a config file, in TOML if its name ends in `.toml`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let text = fs::read_to_string(path)?;
+        let file = path.display().to_string();
+        match path.extension().is_some_and(|ext| ext == "toml") {
+            true => Self::parse_toml_file(&text, &file, dir),
+            false => Self::parse_file(&text, &file, dir),
+        }
+    }
+
+    fn empty() -> Self {
+        Config {
+            default_view: ViewConfig {
+                name: "default".to_string(),
+                ..ViewConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    fn parse_toml_file(text: &str, file: &str, dir: &Path) -> Result<Self> {
+        let at = |span: Range<usize>, error: DnsError| DnsError::ZoneFile {
+            file: file.to_string(),
+            line: text[..span.start.min(text.len())].matches('\n').count() + 1,
+            error: Box::new(error),
+        };
+        let toml: TomlConfig = toml::from_str(text).map_err(|e| {
+            at(
+                e.span().unwrap_or_default(),
+                DnsError::Syntax(e.message().to_string()),
+            )
+        })?;
+
+        let mut config = Self::empty();
+        let mut views = toml
+            .view
+            .iter()
+            .map(|view| (view.span(), Some(view.get_ref())));
+        for (span, view) in std::iter::once((0..0, None)).chain(&mut views) {
+            let keys = match view {
+                Some(view) => {
+                    let directive = toml_args(&view.clients)
+                        .map(|clients| format!("view {} {}", view.name, clients))
+                        .and_then(|directive| config.directive(&directive, dir));
+                    directive.map_err(|e| at(span, e))?;
+                    view.keys()
+                }
+                None => toml.keys(),
+            };
+            for (key, value) in keys {
+                for (span, directive) in toml_directives(key, value) {
+                    directive
+                        .and_then(|directive| config.directive(&directive, dir))
+                        .map_err(|e| at(span, e))?;
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn parse_file(text: &str, file: &str, dir: &Path) -> Result<Self> {
+        let mut config = Self::empty();
+        for (i, line) in text.lines().enumerate() {
+            config
+                .directive(line, dir)
+                .map_err(|error| DnsError::ZoneFile {
+                    file: file.to_string(),
+                    line: i + 1,
+                    error: Box::new(error),
+                })?;
+        }
+        Ok(config)
+    }
+
+    fn directive(&mut self, line: &str, dir: &Path) -> Result<()> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match keyword {
+            "listen" => self.listen = Some(parse_socket_addr(rest, 53)?),
+            "listen-https" => {
+                let mut args = rest.split_whitespace();
+                let (Some(addr), Some(chain), Some(key), path, None) = (
+                    args.next(),
+                    args.next(),
+                    args.next(),
+                    args.next(),
+                    args.next(),
+                ) else {
+                    return Err(DnsError::Syntax(
+                        "listen-https needs an address, a certificate and a key".into(),
+                    ));
+                };
+                let path = path.unwrap_or(DEFAULT_PATH);
+                if !path.starts_with('/') {
+                    return Err(DnsError::Syntax(format!("bad path {}", path)));
+                }
+                self.listen_https = Some((
+                    parse_socket_addr(addr, HTTPS_PORT)?,
+                    dir.join(chain),
+                    dir.join(key),
+                    path.to_string(),
+                ));
+            }
+            "listen-quic" => {
+                let mut args = rest.split_whitespace();
+                let (Some(addr), chain, key, None) =
+                    (args.next(), args.next(), args.next(), args.next())
+                else {
+                    return Err(DnsError::Syntax(
+                        "listen-quic needs an address, and a certificate and key if any".into(),
+                    ));
+                };
+                let certificate = match (chain, key) {
+                    (Some(chain), Some(key)) => Some((dir.join(chain), dir.join(key))),
+                    (None, None) => None,
+                    _ => {
+                        return Err(DnsError::Syntax(
+                            "listen-quic needs a key with its certificate".into(),
+                        ))
+                    }
+                };
+                self.listen_quic = Some((parse_socket_addr(addr, DOQ_PORT)?, certificate));
+            }
+            "designated-resolver" => {
+                if rest.is_empty() || rest.contains(char::is_whitespace) {
+                    return Err(DnsError::Syntax(
+                        "designated-resolver needs a single name".into(),
+                    ));
+                }
+                self.designated_resolver = Some(rest.trim_end_matches('.').to_lowercase());
+            }
+            "upstream" => {
+                for upstream in rest.split_whitespace() {
+                    self.upstreams.push(parse_socket_addr(upstream, 53)?);
+                }
+            }
+            "resolv-conf" => {
+                self.resolv_conf = match rest {
+                    "" => return Err(DnsError::Syntax("resolv-conf needs a file or off".into())),
+                    "off" => Some(None),
+                    path => Some(Some(dir.join(path))),
+                }
+            }
+            "etc-hosts" => self.etc_hosts = Some(switch(keyword, rest)?),
+            "mdns" => self.mdns = Some(switch(keyword, rest)?),
+            "mdns-proxy" => self.mdns_proxy = Some(switch(keyword, rest)?),
+            "hosts-file" => {
+                if rest.is_empty() {
+                    return Err(DnsError::Syntax("hosts-file needs a file".into()));
+                }
+                self.hosts_files.push(dir.join(rest));
+            }
+            "upstream-tls" => {
+                let mut args = rest.split_whitespace();
+                let Some(addr) = args.next() else {
+                    return Err(DnsError::Syntax("upstream-tls needs an address".into()));
+                };
+                let addr = parse_socket_addr(addr, DOT_PORT)?;
+                self.tls_upstreams.push((addr, parse_identity(addr, args)?));
+            }
+            "upstream-quic" => {
+                let mut args = rest.split_whitespace();
+                let Some(addr) = args.next() else {
+                    return Err(DnsError::Syntax("upstream-quic needs an address".into()));
+                };
+                let addr = parse_socket_addr(addr, DOQ_PORT)?;
+                self.quic_upstreams
+                    .push((addr, parse_identity(addr, args)?));
+            }
+            "upstream-https" => {
+                let mut args = rest.split_whitespace();
+                let Some(url) = args.next() else {
+                    return Err(DnsError::Syntax("upstream-https needs a url".into()));
+                };
+                let url: DohUrl = url.parse()?;
+                let mut addrs = Vec::new();
+                let mut pins = Vec::new();
+                for arg in args {
+                    match arg.strip_prefix("pin-sha256=") {
+                        Some(pin) => pins.push(parse_pin(pin)?),
+                        None => addrs.push(arg.parse()?),
+                    }
+                }
+                self.https_upstreams.push((url, addrs, pins));
+            }
+            "bootstrap" => {
+                for resolver in rest.split_whitespace() {
+                    self.bootstrap.push(parse_socket_addr(resolver, 53)?);
+                }
+            }
+            "tls-ca" => self.tls_ca = Some(dir.join(rest)),
+            "edns-payload" => self.edns_payload = Some(number("edns-payload", rest)?),
+            "client-subnet" => self.client_subnet = Some(rest.parse()?),
+            "qname-minimisation" => self.qname_minimisation = Some(switch(keyword, rest)?),
+            "randomize-case" => match rest.split_once(char::is_whitespace) {
+                Some(("max-mismatches", rate)) => match rate.trim().parse::<f64>() {
+                    Ok(rate) if (0.0..=1.0).contains(&rate) => {
+                        self.max_case_mismatches = Some(rate)
+                    }
+                    _ => {
+                        return Err(DnsError::Syntax(format!(
+                            "invalid randomize-case max-mismatches {:?}, expected 0 to 1",
+                            rate.trim()
+                        )))
+                    }
+                },
+                _ => self.randomize_case = Some(switch(keyword, rest)?),
+            },
+            "upstream-race" => {
+                let mut args = rest.split_whitespace();
+                let (Some(upstreams), stagger, None) = (args.next(), args.next(), args.next())
+                else {
+                    return Err(DnsError::Syntax(
+                        "upstream-race needs a number of upstreams and optionally a delay".into(),
+                    ));
+                };
+                let stagger = match stagger {
+                    Some(ms) => Duration::from_millis(number(keyword, ms)?),
+                    None => Duration::ZERO,
+                };
+                self.race = Some((number(keyword, upstreams)?, stagger));
+            }
+            "view" => {
+                let mut args = rest.split_whitespace();
+                let Some(name) = args.next() else {
+                    return Err(DnsError::Syntax("view needs a name".into()));
+                };
+                let clients = subnets(args)?;
+                if clients.is_empty() {
+                    return Err(DnsError::Syntax(format!("no clients for view {}", name)));
+                }
+                self.views.push(ViewConfig {
+                    name: name.to_string(),
+                    clients,
+                    ..ViewConfig::default()
+                });
+            }
+            "record" => self
+                .view()
+                .records
+                .push(parse_record(rest, "", DEFAULT_RECORD_TTL)?),
+            "forward" => {
+                let mut args = rest.split_whitespace();
+                let Some(domain) = args.next() else {
+                    return Err(DnsError::Syntax("forward needs a domain".into()));
+                };
+                let upstreams = args
+                    .map(|upstream| parse_socket_addr(upstream, 53))
+                    .collect::<Result<Vec<_>>>()?;
+                if upstreams.is_empty() {
+                    return Err(DnsError::Syntax(format!("no upstreams for {}", domain)));
+                }
+                self.view().forwards.push((domain.to_string(), upstreams));
+            }
+            "validate-except" => self
+                .view()
+                .validate_except
+                .extend(rest.split_whitespace().map(str::to_string)),
+            "zone" => {
+                let mut args = rest.split_whitespace();
+                let (Some(origin), Some(path), None) = (args.next(), args.next(), args.next())
+                else {
+                    return Err(DnsError::Syntax("zone needs an origin and a file".into()));
+                };
+                self.view().zones.push((origin.to_string(), dir.join(path)));
+            }
+            "rotate" => {
+                let mut args = rest.splitn(3, char::is_whitespace);
+                let (Some(name), Some(qtype), Some(rotation)) =
+                    (args.next(), args.next(), args.next())
+                else {
+                    return Err(DnsError::Syntax(
+                        "rotate needs a name, a type and a rotation".into(),
+                    ));
+                };
+                let rotation = (name.to_string(), qtype.parse()?, rotation.parse()?);
+                self.view().rotations.push(rotation);
+            }
+            "health-check" => {
+                let Some((name, check)) = rest.split_once(char::is_whitespace) else {
+                    return Err(DnsError::Syntax(
+                        "health-check needs a name and a check".into(),
+                    ));
+                };
+                let check = (name.to_string(), check.parse()?);
+                self.view().health_checks.push(check);
+            }
+            "geoip" => self.geoip = Some(dir.join(rest)),
+            "geo" => {
+                let Some((region, record)) = rest.split_once(char::is_whitespace) else {
+                    return Err(DnsError::Syntax("geo needs a region and a record".into()));
+                };
+                let record = parse_record(record.trim(), "", DEFAULT_RECORD_TTL)?;
+                self.view().geo.push((region.parse()?, record));
+            }
+            "dns64" => {
+                let mut args = rest.split_whitespace();
+                let prefix = args.next().unwrap_or(WELL_KNOWN_PREFIX).parse()?;
+                let clients = subnets(args)?;
+                self.view().dns64.push(Dns64Prefix::new(prefix, clients)?);
+            }
+            "rewrite" => {
+                let mut args = rest.split_whitespace();
+                let (Some(from), Some(to)) = (args.next(), args.next()) else {
+                    return Err(DnsError::Syntax(
+                        "rewrite needs an address and the one to put in its place".into(),
+                    ));
+                };
+                let rule = RewriteRule::new(from.parse()?, to.parse()?, subnets(args)?)?;
+                self.view().rewrites.push(rule);
+            }
+            "rate-limit" => {
+                let (setting, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let value = value.trim();
+                let mut rrl = self.rrl.take().unwrap_or_default();
+                rrl = match setting {
+                    "responses" => rrl.responses_per_second(number(setting, value)?),
+                    "nxdomains" => rrl.nxdomains_per_second(number(setting, value)?),
+                    "errors" => rrl.errors_per_second(number(setting, value)?),
+                    "slip" => rrl.slip(number(setting, value)?),
+                    "window" => rrl.window(Duration::from_secs(parse_ttl(value)? as u64)),
+                    "ipv4-prefix" => {
+                        let (_, ipv6) = rrl.prefix_lengths();
+                        rrl.prefixes(number(setting, value)?, ipv6)
+                    }
+                    "ipv6-prefix" => {
+                        let (ipv4, _) = rrl.prefix_lengths();
+                        rrl.prefixes(ipv4, number(setting, value)?)
+                    }
+                    "exempt" => subnets(value.split_whitespace())?
+                        .into_iter()
+                        .fold(rrl, Rrl::exempt),
+                    "on" => rrl,
+                    _ => {
+                        return Err(DnsError::Syntax(format!(
+                            "unknown rate-limit setting {:?}",
+                            setting
+                        )))
+                    }
+                };
+                self.rrl = Some(rrl);
+            }
+            "query-limit" => {
+                let (setting, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let value = value.trim();
+                let mut limiter = self.query_limit.take().unwrap_or_default();
+                limiter = match setting {
+                    "rate" => limiter.rate(number(setting, value)?),
+                    "burst" => limiter.burst(number(setting, value)?),
+                    "action" => limiter.action(value.parse()?),
+                    "exempt" => subnets(value.split_whitespace())?
+                        .into_iter()
+                        .fold(limiter, QueryLimiter::exempt),
+                    "on" => limiter,
+                    _ => {
+                        return Err(DnsError::Syntax(format!(
+                            "unknown query-limit setting {:?}",
+                            setting
+                        )))
+                    }
+                };
+                self.query_limit = Some(limiter);
+            }
+            "cache" => {
+                let (setting, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let value = value.trim();
+                let cache = &mut self.cache;
+                match setting {
+                    "max-entries" => cache.max_entries = Some(number(setting, value)?),
+                    "max-bytes" => cache.max_bytes = Some(number(setting, value)?),
+                    "max-ttl" => {
+                        cache.max_ttl = Some(Duration::from_secs(parse_ttl(value)? as u64))
+                    }
+                    "max-negative-ttl" => {
+                        cache.max_negative_ttl = Some(Duration::from_secs(parse_ttl(value)? as u64))
+                    }
+                    "serve-stale" => {
+                        let mut args = value.split_whitespace();
+                        let strategy = match args.next() {
+                            Some("on") => StaleStrategy::StaleWhileRevalidate,
+                            Some("off") => StaleStrategy::Strict,
+                            _ => {
+                                return Err(DnsError::Syntax(
+                                    "cache serve-stale needs on or off".into(),
+                                ))
+                            }
+                        };
+                        let domains: Vec<&str> = args.collect();
+                        if domains.is_empty() {
+                            cache.serve_stale.set_default(strategy);
+                        }
+                        for domain in domains {
+                            cache.serve_stale.set(domain, strategy);
+                        }
+                    }
+                    "snapshot" if value.is_empty() => {
+                        return Err(DnsError::Syntax("cache snapshot needs a file".into()))
+                    }
+                    "snapshot" => self.cache_snapshot = Some(dir.join(value)),
+                    "on" => {}
+                    _ => {
+                        return Err(DnsError::Syntax(format!(
+                            "unknown cache setting {:?}",
+                            setting
+                        )))
+                    }
+                }
+            }
+            "counters" => {
+                let args: Vec<&str> = rest.split_whitespace().collect();
+                let every = match args[..] {
+                    [_] => None,
+                    [_, "every", interval] => {
+                        Some(Duration::from_secs(parse_ttl(interval)? as u64))
+                    }
+                    _ => {
+                        return Err(DnsError::Syntax(
+                            "counters needs a file and optionally every and an interval".into(),
+                        ))
+                    }
+                };
+                self.counters = Some((dir.join(args[0]), every));
+            }
+            "blocklist" | "allowlist" => {
+                if rest.is_empty() {
+                    return Err(DnsError::Syntax(format!("{} needs a file or url", keyword)));
+                }
+                // files are relative to the config file, urls are left as they are
+                let source = match Source::parse(rest) {
+                    Source::File(path) => Source::File(dir.join(path)),
+                    url => url,
+                };
+                match keyword {
+                    "blocklist" => self.blocklists.push(source),
+                    _ => self.allowlists.push(source),
+                }
+            }
+            "allow" => self.allowed.push(Rule::parse(rest)?),
+            "block-with" => self.block_with = Some(rest.parse()?),
+            "block-ttl" => self.block_ttl = Some(parse_ttl(rest)?),
+            "log" => match rest.split_once(char::is_whitespace) {
+                Some(("level", level)) => self.log_level = Some(level.trim().parse()?),
+                Some(("sample", sample)) => {
+                    let mut words = sample.split_whitespace();
+                    let rate = words.next().unwrap_or_default();
+                    match rate.parse::<f64>() {
+                        Ok(rate) if (0.0..=1.0).contains(&rate) => {
+                            self.log_sample = Some((rate, words.map(str::to_string).collect()))
+                        }
+                        _ => {
+                            return Err(DnsError::Syntax(format!(
+                                "invalid log sample rate {:?}, expected 0 to 1",
+                                rate
+                            )))
+                        }
+                    }
+                }
+                Some((stream, target)) => {
+                    // files are relative to the config file like everything else
+                    let target = match target.trim().parse()? {
+                        LogTarget::File(path, rollover) => {
+                            LogTarget::File(dir.join(path), rollover)
+                        }
+                        target => target,
+                    };
+                    self.logs.push((stream.parse()?, target));
+                }
+                None => return Err(DnsError::Syntax("log needs a stream and a target".into())),
+            },
+            "chaos" => {
+                let (probe, reply) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                if reply.trim().is_empty() {
+                    return Err(DnsError::Syntax(
+                        "chaos needs version, hostname or id and a text or refuse".into(),
+                    ));
+                }
+                self.chaos.push((probe.parse()?, reply.trim().parse()?));
+            }
+            "user" if rest.is_empty() => {
+                return Err(DnsError::Syntax("user needs a user name".into()))
+            }
+            "user" => self.user = Some(rest.to_string()),
+            "chroot" if rest.is_empty() => {
+                return Err(DnsError::Syntax("chroot needs a directory".into()))
+            }
+            "chroot" => self.chroot = Some(dir.join(rest)),
+            "control" if rest.is_empty() => {
+                return Err(DnsError::Syntax("control needs a socket path".into()))
+            }
+            "control" => self.control = Some(dir.join(rest)),
+            "zone-versions" => self.zone_versions = Some(number(keyword, rest)?),
+            _ if keyword.starts_with("allow-") || keyword.starts_with("deny-") => {
+                let (verb, capability) = keyword.split_once('-').unwrap();
+                let capability = match capability {
+                    "query" => Capability::Query,
+                    "recursion" => Capability::Recursion,
+                    "transfer" => Capability::Transfer,
+                    "update" => Capability::Update,
+                    _ => return Err(DnsError::Syntax(format!("unknown directive {:?}", keyword))),
+                };
+                let nets = subnets(rest.split_whitespace())?;
+                let list = self.acl.list_mut(capability);
+                *list = match verb {
+                    "allow" => AccessList::new(nets, list.denied().to_vec()),
+                    _ => AccessList::new(list.allowed().to_vec(), nets),
+                };
+            }
+            _ => return Err(DnsError::Syntax(format!("unknown directive {:?}", keyword))),
+        }
+        Ok(())
+    }
+
+    // the view the directives are for, the last one so far
+    fn view(&mut self) -> &mut ViewConfig {
+        self.views.last_mut().unwrap_or(&mut self.default_view)
+    }
+
+    /// What changed going from this config to `new`.
+    pub fn diff(&self, new: &Config) -> ConfigDiff {
+        let (zones_added, zones_removed) = added_and_removed(self.zones(), new.zones());
+        let (upstreams_added, upstreams_removed) =
+            added_and_removed(self.all_upstreams(), new.all_upstreams());
+        let (old, new) = (self.policies(), new.policies());
+        let policies_changed = old
+            .iter()
+            .zip(&new)
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((policy, _), _)| policy.to_string())
+            .collect();
+        ConfigDiff {
+            zones_added,
+            zones_removed,
+            upstreams_added,
+            upstreams_removed,
+            policies_changed,
+        }
+    }
+
+    fn all_views(&self) -> impl Iterator<Item = &ViewConfig> {
+        std::iter::once(&self.default_view).chain(&self.views)
+    }
+
+    // the zones of every view, with the view's name in front but for the default one
+    fn zones(&self) -> Vec<String> {
+        let mut zones = Vec::new();
+        for (i, view) in self.all_views().enumerate() {
+            for (origin, _) in &view.zones {
+                let origin = origin.trim_end_matches('.').to_lowercase();
+                zones.push(match i {
+                    0 => origin,
+                    _ => format!("{}/{}", view.name, origin),
+                });
+            }
+        }
+        zones
+    }
+
+    // the upstreams, with the transport in front of the encrypted ones, and each view's
+    // conditional forwarders with the domain in front
+    fn all_upstreams(&self) -> Vec<String> {
+        let mut upstreams: Vec<String> = self.upstreams.iter().map(|a| a.to_string()).collect();
+        for (transport, list) in [("tls", &self.tls_upstreams), ("quic", &self.quic_upstreams)] {
+            for (addr, identity) in list {
+                upstreams.push(format!("{} {} {}", transport, addr, identity.name()));
+            }
+        }
+        for (url, addrs, _) in &self.https_upstreams {
+            let addrs: Vec<String> = addrs.iter().map(|addr| format!(" {}", addr)).collect();
+            upstreams.push(format!("{}{}", url, addrs.concat()));
+        }
+        for (i, view) in self.all_views().enumerate() {
+            for (domain, addrs) in &view.forwards {
+                for addr in addrs {
+                    upstreams.push(match i {
+                        0 => format!("{} {}", domain, addr),
+                        _ => format!("{}/{} {}", view.name, domain, addr),
+                    });
+                }
+            }
+        }
+        upstreams
+    }
+
+    // each policy and what it is, written out to be compared
+    fn policies(&self) -> [(&'static str, String); 10] {
+        let views: Vec<_> = self.views.iter().map(|v| (&v.name, &v.clients)).collect();
+        let per_view = |f: fn(&ViewConfig) -> String| -> String {
+            self.all_views().map(f).collect::<Vec<_>>().join("|")
+        };
+        [
+            (
+                "blocking",
+                format!(
+                    "{:?}",
+                    (
+                        &self.blocklists,
+                        &self.allowlists,
+                        &self.allowed,
+                        self.block_with,
+                        self.block_ttl
+                    )
+                ),
+            ),
+            ("access", format!("{:?}", self.acl)),
+            ("rate-limit", format!("{:?}", self.rrl)),
+            ("query-limit", format!("{:?}", self.query_limit)),
+            ("views", format!("{:?}", views)),
+            (
+                "validation",
+                per_view(|view| format!("{:?}", view.validate_except)),
+            ),
+            ("dns64", per_view(|view| format!("{:?}", view.dns64))),
+            ("rewrites", per_view(|view| format!("{:?}", view.rewrites))),
+            ("records", per_view(|view| format!("{:?}", view.records))),
+            ("chaos", format!("{:?}", self.chaos)),
+        ]
+    }
+}
+
+/// What changed from one config to the next: the zones served, the upstreams forwarded to and
+/// the policies for clients.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    /// Zones served now that weren't, by origin, with the view's name and `/` in front for
+    /// views other than the default one.
+    pub zones_added: Vec<String>,
+    pub zones_removed: Vec<String>,
+    /// Upstreams forwarded to now that weren't, with `tls` or `quic` in front of those spoken
+    /// to that way and the domain in front of conditional forwarders'.
+    pub upstreams_added: Vec<String>,
+    pub upstreams_removed: Vec<String>,
+    /// The policies that are different now: blocking, access, rate-limit, query-limit, views,
+    /// validation, dns64, rewrites, records or chaos.
+    pub policies_changed: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == ConfigDiff::default()
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("zones_added", self.zones_added.clone().into()),
+            ("zones_removed", self.zones_removed.clone().into()),
+            ("upstreams_added", self.upstreams_added.clone().into()),
+            ("upstreams_removed", self.upstreams_removed.clone().into()),
+            ("policies_changed", self.policies_changed.clone().into()),
+        ])
+    }
+}
+
+// e.g. "zones +example.com -internal/old.example, policies changed: blocking"
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("nothing changed");
+        }
+        let mut parts = Vec::new();
+        for (what, added, removed) in [
+            ("zones", &self.zones_added, &self.zones_removed),
+            ("upstreams", &self.upstreams_added, &self.upstreams_removed),
+        ] {
+            let changes: Vec<String> = (added.iter().map(|item| format!("+{}", item)))
+                .chain(removed.iter().map(|item| format!("-{}", item)))
+                .collect();
+            if !changes.is_empty() {
+                parts.push(format!("{} {}", what, changes.join(" ")));
+            }
+        }
+        if !self.policies_changed.is_empty() {
+            parts.push(format!(
+                "policies changed: {}",
+                self.policies_changed.join(" ")
+            ));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+// what's in `new` but not `old`, and the other way around
+fn added_and_removed(old: Vec<String>, new: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let added = new.iter().filter(|item| !old.contains(item)).cloned();
+    let removed = old.iter().filter(|item| !new.contains(item)).cloned();
+    (added.collect(), removed.collect())
+}
+
+// what a TOML config file can have: a key for each directive, and [[view]] tables. how the
+// values are read as directives is up to toml_directives
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct TomlConfig {
+    listen: Option<Spanned<Args>>,
+    listen_https: Option<Spanned<Args>>,
+    listen_quic: Option<Spanned<Args>>,
+    designated_resolver: Option<Spanned<Args>>,
+    upstream: Option<Spanned<Args>>,
+    resolv_conf: Option<Spanned<Args>>,
+    etc_hosts: Option<Spanned<Args>>,
+    hosts_file: Option<Spanned<Args>>,
+    mdns: Option<Spanned<Args>>,
+    mdns_proxy: Option<Spanned<Args>>,
+    upstream_tls: Option<Spanned<Args>>,
+    upstream_quic: Option<Spanned<Args>>,
+    upstream_https: Option<Spanned<Args>>,
+    bootstrap: Option<Spanned<Args>>,
+    tls_ca: Option<Spanned<Args>>,
+    edns_payload: Option<Spanned<Args>>,
+    client_subnet: Option<Spanned<Args>>,
+    qname_minimisation: Option<Spanned<Args>>,
+    randomize_case: Option<Spanned<Args>>,
+    upstream_race: Option<Spanned<Args>>,
+    geoip: Option<Spanned<Args>>,
+    allow_query: Option<Spanned<Args>>,
+    allow_recursion: Option<Spanned<Args>>,
+    allow_transfer: Option<Spanned<Args>>,
+    allow_update: Option<Spanned<Args>>,
+    deny_query: Option<Spanned<Args>>,
+    deny_recursion: Option<Spanned<Args>>,
+    deny_transfer: Option<Spanned<Args>>,
+    deny_update: Option<Spanned<Args>>,
+    rate_limit: Option<Spanned<Args>>,
+    query_limit: Option<Spanned<Args>>,
+    cache: Option<Spanned<Args>>,
+    counters: Option<Spanned<Args>>,
+    blocklist: Option<Spanned<Args>>,
+    allowlist: Option<Spanned<Args>>,
+    allow: Option<Spanned<Args>>,
+    block_with: Option<Spanned<Args>>,
+    block_ttl: Option<Spanned<Args>>,
+    log: Option<Spanned<Args>>,
+    chaos: Option<Spanned<Args>>,
+    user: Option<Spanned<Args>>,
+    chroot: Option<Spanned<Args>>,
+    control: Option<Spanned<Args>>,
+    zone_versions: Option<Spanned<Args>>,
+    // what's for clients none of the views match
+    record: Option<Spanned<Args>>,
+    forward: Option<Spanned<Args>>,
+    validate_except: Option<Spanned<Args>>,
+    zone: Option<Spanned<Args>>,
+    rotate: Option<Spanned<Args>>,
+    health_check: Option<Spanned<Args>>,
+    geo: Option<Spanned<Args>>,
+    dns64: Option<Spanned<Args>>,
+    rewrite: Option<Spanned<Args>>,
+    #[serde(default)]
+    view: Vec<Spanned<TomlView>>,
+}
+
+impl TomlConfig {
+    // the keys given, as the directives they stand for
+    fn keys(&self) -> Vec<(&'static str, &Spanned<Args>)> {
+        let keys = [
+            ("listen", &self.listen),
+            ("listen-https", &self.listen_https),
+            ("listen-quic", &self.listen_quic),
+            ("designated-resolver", &self.designated_resolver),
+            ("upstream", &self.upstream),
+            ("resolv-conf", &self.resolv_conf),
+            ("etc-hosts", &self.etc_hosts),
+            ("hosts-file", &self.hosts_file),
+            ("mdns", &self.mdns),
+            ("mdns-proxy", &self.mdns_proxy),
+            ("upstream-tls", &self.upstream_tls),
+            ("upstream-quic", &self.upstream_quic),
+            ("upstream-https", &self.upstream_https),
+            ("bootstrap", &self.bootstrap),
+            ("tls-ca", &self.tls_ca),
+            ("edns-payload", &self.edns_payload),
+            ("client-subnet", &self.client_subnet),
+            ("qname-minimisation", &self.qname_minimisation),
+            ("randomize-case", &self.randomize_case),
+            ("upstream-race", &self.upstream_race),
+            ("geoip", &self.geoip),
+            ("allow-query", &self.allow_query),
+            ("allow-recursion", &self.allow_recursion),
+            ("allow-transfer", &self.allow_transfer),
+            ("allow-update", &self.allow_update),
+            ("deny-query", &self.deny_query),
+            ("deny-recursion", &self.deny_recursion),
+            ("deny-transfer", &self.deny_transfer),
+            ("deny-update", &self.deny_update),
+            ("rate-limit", &self.rate_limit),
+            ("query-limit", &self.query_limit),
+            ("cache", &self.cache),
+            ("counters", &self.counters),
+            ("blocklist", &self.blocklist),
+            ("allowlist", &self.allowlist),
+            ("allow", &self.allow),
+            ("block-with", &self.block_with),
+            ("block-ttl", &self.block_ttl),
+            ("log", &self.log),
+            ("chaos", &self.chaos),
+            ("user", &self.user),
+            ("chroot", &self.chroot),
+            ("control", &self.control),
+            ("zone-versions", &self.zone_versions),
+            ("record", &self.record),
+            ("forward", &self.forward),
+            ("validate-except", &self.validate_except),
+            ("zone", &self.zone),
+            ("rotate", &self.rotate),
+            ("health-check", &self.health_check),
+            ("geo", &self.geo),
+            ("dns64", &self.dns64),
+            ("rewrite", &self.rewrite),
+        ];
+        given(keys)
+    }
+}
+
+// a [[view]] table, with the directives that can be for a view
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct TomlView {
+    name: String,
+    clients: Args,
+    record: Option<Spanned<Args>>,
+    forward: Option<Spanned<Args>>,
+    validate_except: Option<Spanned<Args>>,
+    zone: Option<Spanned<Args>>,
+    rotate: Option<Spanned<Args>>,
+    health_check: Option<Spanned<Args>>,
+    geo: Option<Spanned<Args>>,
+    dns64: Option<Spanned<Args>>,
+    rewrite: Option<Spanned<Args>>,
+}
+
+impl TomlView {
+    fn keys(&self) -> Vec<(&'static str, &Spanned<Args>)> {
+        let keys = [
+            ("record", &self.record),
+            ("forward", &self.forward),
+            ("validate-except", &self.validate_except),
+            ("zone", &self.zone),
+            ("rotate", &self.rotate),
+            ("health-check", &self.health_check),
+            ("geo", &self.geo),
+            ("dns64", &self.dns64),
+            ("rewrite", &self.rewrite),
+        ];
+        given(keys)
+    }
+}
+
+// the keys that have a value
+fn given<'a, const N: usize>(
+    keys: [(&'static str, &'a Option<Spanned<Args>>); N],
+) -> Vec<(&'static str, &'a Spanned<Args>)> {
+    keys.into_iter()
+        .filter_map(|(key, value)| Some((key, value.as_ref()?)))
+        .collect()
+}
+
+// a directive's arguments as TOML has them. tables keep their settings in the order they were
+// written, and every value its place in the file for errors to point at
+enum Args {
+    Switch(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<Spanned<Args>>),
+    Settings(Vec<(String, Spanned<Args>)>),
+}
+
+impl<'de> Deserialize<'de> for Args {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(ArgsVisitor)
+    }
+}
+
+struct ArgsVisitor;
+
+impl<'de> Visitor<'de> for ArgsVisitor {
+    type Value = Args;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, a number, a boolean, an array or a table")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> std::result::Result<Args, E> {
+        Ok(Args::Switch(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> std::result::Result<Args, E> {
+        Ok(Args::Integer(value))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> std::result::Result<Args, E> {
+        Ok(Args::Float(value))
+    }
+
+    fn visit_str<E>(self, value: &str) -> std::result::Result<Args, E> {
+        Ok(Args::Text(value.to_string()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Args, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Args::List(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Args, A::Error> {
+        let mut settings = Vec::new();
+        while let Some(setting) = map.next_entry()? {
+            settings.push(setting);
+        }
+        Ok(Args::Settings(settings))
+    }
+}
+
+// directives written one per item of an array rather than with the items as their arguments
+const REPEATED: [&str; 15] = [
+    "record",
+    "zone",
+    "forward",
+    "rotate",
+    "health-check",
+    "geo",
+    "dns64",
+    "rewrite",
+    "hosts-file",
+    "upstream-tls",
+    "upstream-quic",
+    "upstream-https",
+    "blocklist",
+    "allowlist",
+    "allow",
+];
+
+// the directives, and where they are, that a key stands for: `key = value` is `key value`, a
+// table's keys are settings, so `[rate-limit]` with `responses = 5` is `rate-limit responses 5`
+// and an empty one `rate-limit on`, and an array is a directive's arguments, or one directive
+// per item for the REPEATED ones
+fn toml_directives(key: &str, value: &Spanned<Args>) -> Vec<(Range<usize>, Result<String>)> {
+    match value.get_ref() {
+        Args::Settings(settings) if settings.is_empty() => {
+            vec![(value.span(), Ok(format!("{} on", key)))]
+        }
+        Args::Settings(settings) => settings
+            .iter()
+            .map(|(setting, value)| {
+                let args = toml_args(value.get_ref());
+                (
+                    value.span(),
+                    args.map(|args| format!("{} {} {}", key, setting, args)),
+                )
+            })
+            .collect(),
+        Args::List(items) if REPEATED.contains(&key) => items
+            .iter()
+            .map(|item| {
+                let args = toml_args(item.get_ref());
+                (item.span(), args.map(|args| format!("{} {}", key, args)))
+            })
+            .collect(),
+        args => vec![(
+            value.span(),
+            toml_args(args).map(|args| format!("{} {}", key, args)),
+        )],
+    }
+}
+
+// a value as directive arguments, with booleans as on or off
+fn toml_args(args: &Args) -> Result<String> {
+    match args {
+        Args::Text(s) => Ok(s.clone()),
+        Args::Integer(n) => Ok(n.to_string()),
+        Args::Float(x) => Ok(x.to_string()),
+        Args::Switch(true) => Ok("on".to_string()),
+        Args::Switch(false) => Ok("off".to_string()),
+        Args::List(items) => Ok(items
+            .iter()
+            .map(|item| toml_args(item.get_ref()))
+            .collect::<Result<Vec<_>>>()?
+            .join(" ")),
+        Args::Settings(_) => Err(DnsError::Syntax(
+            "expected a string, a number, a boolean or an array, not a table".into(),
+        )),
+    }
+}
+
+// subnets as written in views and access lists
+fn subnets<'a>(args: impl Iterator<Item = &'a str>) -> Result<Vec<Subnet>> {
+    let mut nets = Vec::new();
+    for arg in args {
+        match arg {
+            "any" => nets.extend(everywhere()),
+            "local" => nets.extend(local_nets()),
+            "none" => {}
+            _ => nets.push(arg.parse()?),
+        }
+    }
+    Ok(nets)
+}
+
+// who a TLS or QUIC upstream has to be: the name given, or its address, unless its key is
+// pinned
+fn parse_identity<'a>(
+    addr: SocketAddr,
+    args: impl Iterator<Item = &'a str>,
+) -> Result<ServerIdentity> {
+    let mut name = addr.ip().to_string();
+    let mut pins = Vec::new();
+    for arg in args {
+        match arg.strip_prefix("pin-sha256=") {
+            Some(pin) => pins.push(parse_pin(pin)?),
+            None => name = arg.to_string(),
+        }
+    }
+    Ok(pins
+        .into_iter()
+        .fold(ServerIdentity::new(&name), ServerIdentity::pin))
+}
+
+// a key's SHA-256 hash in base64
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    parse_base64(pin)?
+        .try_into()
+        .map_err(|_| DnsError::Syntax(format!("{} isn't a SHA-256 hash", pin)))
+}
+
+// `on` or `off`
+fn switch(setting: &str, value: &str) -> Result<bool> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(DnsError::Syntax(format!(
+            "{} is on or off, not {:?}",
+            setting, value
+        ))),
+    }
+}
+
+// the value of a numeric setting
+fn number<T: FromStr>(setting: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| DnsError::Syntax(format!("invalid {} {:?}", setting, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn says_what_changed_between_configs() {
+        let old = Config::parse(
+            "upstream 1.1.1.1\n\
+             zone example.com example.com.zone\n\
+             blocklist ads.txt\n\
+             view internal 10.0.0.0/8\n\
+             zone corp.example corp.zone\n",
+        )
+        .unwrap();
+        let new = Config::parse(
+            "upstream 1.1.1.1 9.9.9.9\n\
+             zone example.net example.net.zone\n\
+             blocklist ads.txt\n\
+             allow cdn.example\n\
+             view internal 10.0.0.0/8\n\
+             zone corp.example corp.zone\n\
+             forward lab.example 10.0.0.53\n",
+        )
+        .unwrap();
+        assert!(old.diff(&old).is_empty());
+        assert_eq!(old.diff(&old).to_string(), "nothing changed");
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.zones_added, ["example.net"]);
+        assert_eq!(diff.zones_removed, ["example.com"]);
+        assert_eq!(
+            diff.upstreams_added,
+            ["9.9.9.9:53", "internal/lab.example 10.0.0.53:53"]
+        );
+        assert!(diff.upstreams_removed.is_empty());
+        assert_eq!(diff.policies_changed, ["blocking"]);
+        assert_eq!(
+            diff.to_string(),
+            "zones +example.net -example.com, upstreams +9.9.9.9:53 \
+             +internal/lab.example 10.0.0.53:53, policies changed: blocking"
+        );
+        assert_eq!(new.diff(&old).zones_added, ["example.com"]);
+    }
+
+    #[test]
+    fn reads_rewrites_for_each_view() {
+        let config = Config::parse(
+            "rewrite 203.0.113.5 192.168.1.5 192.168.0.0/16\n\
+             view lab 10.0.0.0/8\n\
+             rewrite 2001:db8::5 fd00::5\n",
+        )
+        .unwrap();
+        let rule = &config.default_view.rewrites[0];
+        assert_eq!(rule.from, "203.0.113.5".parse::<IpAddr>().unwrap());
+        assert_eq!(rule.to, "192.168.1.5".parse::<IpAddr>().unwrap());
+        assert_eq!(rule.clients, ["192.168.0.0/16".parse().unwrap()]);
+        assert!(config.views[0].rewrites[0].clients.is_empty());
+
+        assert!(Config::parse("rewrite 203.0.113.5\n").is_err());
+        assert!(Config::parse("rewrite 203.0.113.5 fd00::5\n").is_err());
+        let old = Config::parse("rewrite 203.0.113.5 192.168.1.5\n").unwrap();
+        let new = Config::parse("rewrite 203.0.113.5 192.168.1.6\n").unwrap();
+        assert_eq!(old.diff(&new).policies_changed, ["rewrites"]);
+    }
+
+    #[test]
+    fn reads_which_names_are_served_stale() {
+        let config = Config::parse(
+            "cache serve-stale on\n\
+             cache serve-stale off bank.example pay.example\n",
+        )
+        .unwrap();
+        let stale = &config.cache.serve_stale;
+        assert_eq!(
+            stale.strategy_for("www.example.com"),
+            StaleStrategy::StaleWhileRevalidate
+        );
+        assert_eq!(
+            stale.strategy_for("www.bank.example"),
+            StaleStrategy::Strict
+        );
+        assert_eq!(
+            Config::parse("")
+                .unwrap()
+                .cache
+                .serve_stale
+                .strategy_for("example.com"),
+            StaleStrategy::Strict
+        );
+        assert!(Config::parse("cache serve-stale example.com\n").is_err());
+    }
+
+    #[test]
+    fn reads_how_blocked_names_are_answered() {
+        let config = Config::parse("block-with nxdomain\nblock-ttl 1m\n").unwrap();
+        assert_eq!(config.block_with, Some(BlockAction::NxDomain));
+        assert_eq!(config.block_ttl, Some(60));
+        assert_eq!(Config::parse("").unwrap().block_ttl, None);
+        assert!(Config::parse("block-ttl soon\n").is_err());
+
+        let longer = Config::parse("block-with nxdomain\nblock-ttl 2m\n").unwrap();
+        assert_eq!(config.diff(&longer).policies_changed, ["blocking"]);
+    }
+
+    #[test]
+    fn reads_toml_as_the_directives_it_stands_for() {
+        let config = Config::parse_toml(
+            r#"
+listen = "127.0.0.1:5353"
+upstream = ["1.1.1.1", "9.9.9.9"]
+record = ["nas.home A 192.168.1.10", 'nas.home 60 TXT "backups at 3am"']
+zone = [["example.com", "example.com.zone"]]
+mdns = true
+randomize-case = { max-mismatches = 0.2 }
+
+[cache]
+max-entries = 200000
+
+[rate-limit]
+
+[[view]]
+name = "internal"
+clients = ["192.168.0.0/16", "10.0.0.0/8"]
+zone = ["example.com internal/example.com.zone"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, Some("127.0.0.1:5353".parse().unwrap()));
+        assert_eq!(config.upstreams.len(), 2);
+        assert_eq!(config.default_view.records.len(), 2);
+        assert_eq!(config.default_view.zones[0].0, "example.com");
+        assert_eq!(config.mdns, Some(true));
+        assert_eq!(config.max_case_mismatches, Some(0.2));
+        assert_eq!(config.cache.max_entries, Some(200000));
+        assert!(config.rrl.is_some());
+        assert_eq!(config.views[0].name, "internal");
+        assert_eq!(config.views[0].clients.len(), 2);
+        assert_eq!(config.views[0].zones.len(), 1);
+
+        let line = |text: &str| match Config::parse_toml(text) {
+            Err(DnsError::ZoneFile { line, .. }) => line,
+            res => panic!("{:?}", res.map(|_| ())),
+        };
+        assert_eq!(line("mdns = true\n\n[cache]\nmax-entries = \"lots\"\n"), 4);
+        assert_eq!(line("mdns = true\nmdns-proxy = \"maybe\"\n"), 2);
+        assert_eq!(
+            line("record = [\n  \"a.home A 10.0.0.1\",\n  \"b.home A\",\n]\n"),
+            3
+        );
+        assert_eq!(line("listen = \"127.0.0.1\"\nunknown = 1\n"), 2);
+        assert_eq!(
+            line("[[view]]\nname = \"lab\"\nclients = \"10.0.0.0/8\"\nlisten = 1\n"),
+            4
+        );
+    }
+}
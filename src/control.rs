@@ -0,0 +1,159 @@
+// the control socket, a unix stream socket operators and their scripts ask the running server
+// things over. a request is one line, a command and its arguments separated by spaces, and
+// the answer is one line of JSON (see json.rs), an object with an "error" if the command
+// failed or doesn't exist, after which the server closes the connection. the commands are
+// whatever the server gives the socket; `help` lists them. the socket is only for the user
+// the server was started as, and one left behind by a server that's gone is replaced.
+use crate::error::{DnsError, Result};
+use crate::json::Value;
+use crate::logging;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::UnixListener;
+use tokio::time;
+
+// longer requests than this are cut off, no command takes more than a few names
+const MAX_REQUEST: usize = 4096;
+// how long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Command = Box<dyn Fn(&[&str]) -> Result<Value> + Send + Sync>;
+
+/// Answers the commands it's given on a unix socket.
+pub struct Control {
+    listener: UnixListener,
+    commands: Arc<Commands>,
+}
+
+/// The commands a control socket answers, by name.
+#[derive(Default)]
+pub struct Commands(BTreeMap<String, Command>);
+
+impl Commands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers `name` with what `run` makes of the arguments after it.
+    pub fn command(
+        mut self,
+        name: &str,
+        run: impl Fn(&[&str]) -> Result<Value> + Send + Sync + 'static,
+    ) -> Self {
+        self.0.insert(name.to_string(), Box::new(run));
+        self
+    }
+
+    /// The answer to a request line.
+    pub fn answer(&self, line: &str) -> Value {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let result = match (name, self.0.get(name)) {
+            ("help", None) => Ok(self.0.keys().cloned().collect::<Vec<String>>().into()),
+            (_, Some(run)) => run(&args),
+            ("", None) => Err(DnsError::Control("no command, try help".into())),
+            (_, None) => Err(DnsError::Control(format!(
+                "unknown command {:?}, try help",
+                name
+            ))),
+        };
+        result.unwrap_or_else(|e| Value::object([("error", e.to_string().into())]))
+    }
+}
+
+impl Control {
+    /// Binds the socket at `path`, replacing one no server is listening on any more. Has to
+    /// be called from within a tokio runtime.
+    pub fn bind(path: &Path, commands: Commands) -> Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            if StdUnixStream::connect(path).is_ok() {
+                return Err(DnsError::Control(format!(
+                    "a server is listening on {} already",
+                    path.display()
+                )));
+            }
+            fs::remove_file(path)?;
+        }
+        // the socket is created with the umask's permissions, so they're 0600 from the start
+        // rather than changed after others could have connected. the umask is the process's,
+        // nothing else creates files while the server starts
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        Ok(Self {
+            listener,
+            commands: Arc::new(commands),
+        })
+    }
+
+    /// Answers requests until accepting a connection fails.
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let commands = self.commands.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut line = String::new();
+                let mut read = AsyncBufReader::new(read).take(MAX_REQUEST as u64);
+                match time::timeout(REQUEST_TIMEOUT, read.read_line(&mut line)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => return logging::warning(&format!("control socket: {}", e)),
+                    Err(_) => return,
+                }
+                let answer = format!("{}\n", commands.answer(line.trim()));
+                if let Err(e) = write.write_all(answer.as_bytes()).await {
+                    logging::warning(&format!("control socket: {}", e));
+                }
+            });
+        }
+    }
+}
+
+/// Sends `request` to the control socket at `path` and returns the answer, for the command
+/// line.
+pub fn request(path: &Path, request: &str) -> io::Result<String> {
+    let mut stream = StdUnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    Ok(answer.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_the_commands_it_has() {
+        let commands = Commands::new().command("echo", |args| Ok(args.join(" ").into()));
+        assert_eq!(commands.answer("echo a  b").to_string(), r#""a b""#);
+        assert_eq!(commands.answer("help").to_string(), r#"["echo"]"#);
+        assert_eq!(
+            commands.answer("rm -rf").to_string(),
+            r#"{"error":"control: unknown command \"rm\", try help"}"#
+        );
+        assert!(commands.answer("").get("error").is_some());
+    }
+
+    #[tokio::test]
+    async fn binds_a_socket_only_its_user_can_use() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("control-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let control = Control::bind(&path, Commands::new()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(control);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+// discovery of designated resolvers (rfc 9462). a client that only knows us by address, from
+// dhcp or its own config, asks for the SVCB records of _dns.resolver.arpa and learns from them
+// where the same resolver takes encrypted queries: one record per listener, with the name its
+// certificate is for as the target, and the alpn, port and addresses, plus the path template
+// for DNS over HTTPS (rfc 9461). the client then connects there and, to be sure it's still
+// talking to us, checks that the certificate covers the address it asked (section 4.2). the
+// rest of resolver.arpa is a zone nobody has, served empty rather than forwarded (section 6.4).
+use crate::server::Handler;
+use crate::structure::{is_subdomain, DnsPacket, DnsRecord, Opcode, QueryType, ResultCode};
+use crate::svcb;
+use std::net::{IpAddr, SocketAddr};
+
+/// The zone the special-use name is in.
+pub const ZONE: &str = "resolver.arpa";
+/// The name clients ask for, with type SVCB.
+pub const NAME: &str = "_dns.resolver.arpa";
+
+// short enough for a changed config to reach clients soon
+const TTL: u32 = 300;
+
+/// Answers for resolver.arpa, with the encrypted endpoints of this server for
+/// `_dns.resolver.arpa`, before asking `inner` anything else. Without endpoints the name has
+/// no records, like the rest of the zone.
+pub struct Designated<H> {
+    inner: H,
+    records: Vec<DnsRecord>,
+}
+
+impl<H: Handler> Designated<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            records: Vec::new(),
+        }
+    }
+
+    /// Advertises DNS over HTTPS at `addr`, taking queries on `path`, with a certificate for
+    /// `target`.
+    pub fn https(self, target: &str, addr: SocketAddr, path: &str) -> Self {
+        let template = format!("{}{{?dns}}", path);
+        self.endpoint(target, addr, &["h2", "http/1.1"], Some(&template))
+    }
+
+    /// Advertises DNS over QUIC at `addr`, with a certificate for `target`.
+    pub fn quic(self, target: &str, addr: SocketAddr) -> Self {
+        self.endpoint(target, addr, &["doq"], None)
+    }
+
+    // the records are preferred in the order they were added
+    fn endpoint(
+        mut self,
+        target: &str,
+        addr: SocketAddr,
+        alpn: &[&str],
+        dohpath: Option<&str>,
+    ) -> Self {
+        let mut params = vec![svcb::alpn(alpn), svcb::port(addr.port())];
+        // a wildcard address isn't one to connect to, clients look the target up instead
+        match addr.ip() {
+            ip if ip.is_unspecified() => {}
+            IpAddr::V4(ip) => params.push(svcb::ipv4hint(&[ip])),
+            IpAddr::V6(ip) => params.push(svcb::ipv6hint(&[ip])),
+        }
+        if let Some(template) = dohpath {
+            params.push(svcb::dohpath(template));
+        }
+        self.records.push(DnsRecord::SVCB {
+            domain: NAME.to_string(),
+            class: 1,
+            ttl: TTL,
+            priority: self.records.len() as u16 + 1,
+            target: target.trim_end_matches('.').to_lowercase(),
+            params,
+        });
+        self
+    }
+
+    pub fn records(&self) -> &[DnsRecord] {
+        &self.records
+    }
+
+    /// The response for `request` if it asks about a name in resolver.arpa.
+    pub fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if question.class != 1
+            || request.header.flags.opcode != Opcode::QUERY
+            || !is_subdomain(&question.name, ZONE)
+        {
+            return None;
+        }
+        let soa = DnsRecord::SOA {
+            domain: ZONE.to_string(),
+            class: 1,
+            ttl: TTL,
+            mname: ZONE.to_string(),
+            rname: "nobody.invalid".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 1200,
+            expire: 604800,
+            minimum: TTL,
+        };
+
+        let mut res = DnsPacket::response_to(request);
+        res.set_authoritative(true).set_recursion_available(true);
+        let name = question.name.trim_end_matches('.');
+        if name.eq_ignore_ascii_case(NAME) && question.qtype == QueryType::SVCB {
+            for record in &self.records {
+                res.add_answer(record.clone());
+            }
+        } else if name.eq_ignore_ascii_case(ZONE) && question.qtype == QueryType::SOA {
+            res.add_answer(soa);
+        } else if name.eq_ignore_ascii_case(NAME) || name.eq_ignore_ascii_case(ZONE) {
+            res.add_authority(soa);
+        } else {
+            res.set_rcode(ResultCode::NXDOMAIN).add_authority(soa);
+        }
+        res.set_edns(DnsPacket::response_edns(request).as_ref());
+        Some(res)
+    }
+}
+
+impl<H: Handler> Handler for Designated<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        match self.answer(&request) {
+            Some(res) => Some(res),
+            None => self.inner.handle(request, src).await,
+        }
+    }
+}
@@ -0,0 +1,211 @@
+// aggressive use of DNSSEC-validated cache (rfc 8198). the NSEC and NSEC3 records of validated
+// negative answers each prove a whole range of names doesn't exist, not just the one that was
+// asked for. they're kept by zone, in canonical order of their owner names, and a name that
+// falls into a range they cover is answered with NXDOMAIN from them without asking upstream.
+// queries for random names below a signed zone then stop reaching its servers after the first
+// few. which records prove what is worked out by the same code that checks the proofs in
+// answers, from the records closest to the name and its possible wildcards.
+use crate::dnssec::{self, CanonicalName, Denial, RRset};
+use crate::structure::{is_subdomain, DnsRecord, QueryType};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// more records than this and expired ones are dropped, or everything if none have
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+// an NSEC, NSEC3 or SOA RRset with the signatures over it
+struct Entry {
+    records: Vec<DnsRecord>,
+    expires: Instant,
+}
+
+impl Entry {
+    fn new(set: &RRset<'_>, ttl: Duration) -> Self {
+        Self {
+            records: set
+                .records
+                .iter()
+                .chain(&set.signatures)
+                .map(|&rec| rec.clone())
+                .collect(),
+            expires: Instant::now() + ttl,
+        }
+    }
+
+    // the records with what's left of their ttl, None once that's nothing
+    fn live(&self, now: Instant) -> Option<impl Iterator<Item = DnsRecord> + '_> {
+        let left = self.expires.checked_duration_since(now)?.as_secs() as u32;
+        if left == 0 {
+            return None;
+        }
+        Some(self.records.iter().map(move |rec| {
+            let mut rec = rec.clone();
+            rec.set_ttl(rec.ttl().min(left));
+            rec
+        }))
+    }
+}
+
+#[derive(Default)]
+struct ZoneDenials {
+    soa: Option<Entry>,
+    // by owner name, which for NSEC3 sorts the same as the hashes
+    chain: BTreeMap<CanonicalName, Entry>,
+}
+
+impl ZoneDenials {
+    // the record at `name` or the one before it, which is the one that would cover it. the
+    // first name wraps around to the last record
+    fn at_or_before(&self, name: &str) -> Option<&Entry> {
+        self.chain
+            .range(..=CanonicalName(name.to_string()))
+            .next_back()
+            .or_else(|| self.chain.iter().next_back())
+            .map(|(_, entry)| entry)
+    }
+
+    // the parameters of the zone's NSEC3 chain, None if it's NSEC
+    fn nsec3_params(&self) -> Option<(Vec<u8>, u16)> {
+        self.chain
+            .values()
+            .flat_map(|entry| &entry.records)
+            .find_map(|rec| match rec {
+                DnsRecord::NSEC3 {
+                    salt, iterations, ..
+                } => Some((salt.clone(), *iterations)),
+                _ => None,
+            })
+    }
+
+    fn len(&self) -> usize {
+        self.chain.len() + self.soa.is_some() as usize
+    }
+}
+
+/// Validated NSEC and NSEC3 records by zone, to answer for names they prove don't exist.
+pub struct DenialCache {
+    zones: Mutex<HashMap<String, ZoneDenials>>,
+    max_entries: usize,
+}
+
+impl Default for DenialCache {
+    fn default() -> Self {
+        DenialCache::new()
+    }
+}
+
+impl DenialCache {
+    pub fn new() -> Self {
+        Self {
+            zones: Mutex::new(HashMap::new()),
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    /// How many RRsets to keep at most.
+    pub fn max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max;
+        self
+    }
+
+    /// Keeps an NSEC, NSEC3 or SOA RRset from a negative answer that has been validated as
+    /// signed by `zone`, for as long as its records and signatures live. Other sets are
+    /// ignored.
+    pub fn insert(&self, zone: &str, set: &RRset<'_>) {
+        let soa = set.qtype == QueryType::SOA && set.name.eq_ignore_ascii_case(zone);
+        if !soa && !matches!(set.qtype, QueryType::NSEC | QueryType::NSEC3) {
+            return;
+        }
+        if !is_subdomain(set.name, zone) {
+            return;
+        }
+        let ttl = set.records.iter().map(|rec| rec.ttl()).min().unwrap_or(0);
+        // the signatures run out eventually too
+        let now = dnssec::now();
+        let valid = set
+            .signatures
+            .iter()
+            .filter_map(|rrsig| match rrsig {
+                DnsRecord::RRSIG { expiration, .. } => Some(expiration.wrapping_sub(now)),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let ttl = ttl.min(valid);
+        if ttl == 0 {
+            return;
+        }
+        let entry = Entry::new(set, Duration::from_secs(ttl as u64));
+
+        let mut zones = self.zones.lock().unwrap();
+        if zones.values().map(ZoneDenials::len).sum::<usize>() >= self.max_entries {
+            let now = Instant::now();
+            for denials in zones.values_mut() {
+                denials.chain.retain(|_, entry| entry.expires > now);
+                denials.soa = denials.soa.take().filter(|soa| soa.expires > now);
+            }
+            zones.retain(|_, denials| denials.len() > 0);
+            if zones.values().map(ZoneDenials::len).sum::<usize>() >= self.max_entries {
+                zones.clear();
+            }
+        }
+        let denials = zones.entry(zone.to_lowercase()).or_default();
+        if soa {
+            denials.soa = Some(entry);
+        } else {
+            denials
+                .chain
+                .insert(CanonicalName(set.name.to_lowercase()), entry);
+        }
+    }
+
+    /// The authority section of an NXDOMAIN answer for `name`, the zone's SOA record and the
+    /// NSEC or NSEC3 records proving it, if what's cached proves it. Names in opt-out spans of
+    /// an NSEC3 chain aren't proven not to exist, and are left to be looked up.
+    pub fn nxdomain(&self, name: &str, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        let name = name.to_lowercase();
+        let zones = self.zones.lock().unwrap();
+        // the closest zone with records cached, the NSEC records of the zones above say
+        // nothing about the names in it
+        let (zone, denials) = (0..=dnssec::labels(&name).len()).rev().find_map(|count| {
+            let zone = dnssec::suffix(&name, count);
+            zones.get_key_value(&zone)
+        })?;
+        let now = Instant::now();
+        let soa = denials.soa.as_ref()?.live(now)?;
+
+        // the records that could cover the name, or match or cover its ancestors and the
+        // wildcards below them, are all a proof could need
+        let params = denials.nsec3_params();
+        let mut entries: Vec<&Entry> = Vec::new();
+        for count in dnssec::labels(zone).len()..=dnssec::labels(&name).len() {
+            let ancestor = dnssec::suffix(&name, count);
+            for candidate in [dnssec::wildcard(&ancestor), ancestor] {
+                let owner = match &params {
+                    Some((salt, iterations)) => {
+                        dnssec::nsec3_owner(&candidate, zone, salt, *iterations)
+                    }
+                    None => candidate,
+                };
+                let Some(entry) = denials.at_or_before(&owner) else {
+                    continue;
+                };
+                if entry.expires > now && !entries.iter().any(|e| std::ptr::eq(*e, entry)) {
+                    entries.push(entry);
+                }
+            }
+        }
+        let proof: Vec<&DnsRecord> = entries.iter().flat_map(|e| &e.records).collect();
+        match dnssec::prove_denial(&name, qtype, true, zone, &proof) {
+            Ok(Denial::NxDomain) => {}
+            _ => return None,
+        }
+
+        let mut records: Vec<DnsRecord> = soa.collect();
+        for entry in entries {
+            records.extend(entry.live(now)?);
+        }
+        Some(records)
+    }
+}
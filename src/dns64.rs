@@ -0,0 +1,178 @@
+// DNS64 (rfc 6147): clients on an ipv6-only network reach ipv4 hosts through a NAT64
+// gateway, which translates addresses under a prefix of its own to ipv4 ones. for a name that
+// only has A records such a client gets AAAA records made up from them, the ipv4 address
+// embedded in the prefix as rfc 6052 says, and its connections go through the gateway. names
+// with AAAA records of their own keep them, and so do names that don't exist. a client that
+// validates itself (DO and CD set) gets nothing made up, it would fail validation.
+use crate::error::{DnsError, Result};
+use crate::net::Subnet;
+use crate::server::Handler;
+use crate::structure::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The well-known prefix of rfc 6052.
+pub const WELL_KNOWN_PREFIX: &str = "64:ff9b::/96";
+
+// AAAA records in these are treated as if they weren't there, rfc 6147 section 5.1.4:
+// ipv4-mapped addresses can't be reached over an ipv6-only network
+const EXCLUDED: [&str; 1] = ["::ffff:0:0/96"];
+
+/// A NAT64 prefix and the clients behind the gateway it belongs to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dns64Prefix {
+    pub prefix: Subnet,
+    /// The prefix is used for clients in one of these. Empty means every client.
+    pub clients: Vec<Subnet>,
+}
+
+impl Dns64Prefix {
+    /// `prefix` has to be an ipv6 prefix of one of the lengths rfc 6052 allows: 32, 40, 48,
+    /// 56, 64 or 96.
+    pub fn new(prefix: Subnet, clients: Vec<Subnet>) -> Result<Self> {
+        if !prefix.addr().is_ipv6() || ![32, 40, 48, 56, 64, 96].contains(&prefix.prefix()) {
+            return Err(DnsError::InvalidAddress(format!(
+                "{} isn't a NAT64 prefix, those are ipv6 /32, /40, /48, /56, /64 or /96",
+                prefix
+            )));
+        }
+        Ok(Self { prefix, clients })
+    }
+
+    fn applies_to(&self, client: IpAddr) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|net| net.contains(client))
+    }
+
+    /// `addr` embedded in the prefix, rfc 6052 section 2.2. Bits 64 to 71 are always zero, so
+    /// for prefixes shorter than 96 the address is split around them.
+    pub fn embed(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        let IpAddr::V6(prefix) = self.prefix.addr() else {
+            unreachable!("Dns64Prefix::new only takes ipv6 prefixes");
+        };
+        let mut bytes = prefix.octets();
+        let v4 = addr.octets();
+        let start = self.prefix.prefix() as usize / 8;
+        let mut pos = start;
+        for byte in v4 {
+            if pos == 8 {
+                pos += 1;
+            }
+            bytes[pos] = byte;
+            pos += 1;
+        }
+        Ipv6Addr::from(bytes)
+    }
+}
+
+/// Makes AAAA answers up from A records for the clients one of its prefixes applies to,
+/// looking both up through `inner`. The first prefix that applies to a client is used.
+pub struct Dns64<H> {
+    inner: H,
+    prefixes: Vec<Dns64Prefix>,
+    excluded: Vec<Subnet>,
+}
+
+impl<H: Handler> Dns64<H> {
+    pub fn new(inner: H, prefixes: Vec<Dns64Prefix>) -> Self {
+        Self {
+            inner,
+            prefixes,
+            excluded: EXCLUDED.iter().map(|net| net.parse().unwrap()).collect(),
+        }
+    }
+
+    /// AAAA records in `net` are ignored, as if the name only had A records.
+    pub fn exclude(mut self, net: Subnet) -> Self {
+        self.excluded.push(net);
+        self
+    }
+
+    pub fn prefixes(&self) -> &[Dns64Prefix] {
+        &self.prefixes
+    }
+
+    // whether `response` has AAAA records worth answering with
+    fn has_aaaa(&self, response: &DnsPacket) -> bool {
+        response.answers.iter().any(|rec| match rec {
+            DnsRecord::AAAA { ip, .. } => {
+                let addr = IpAddr::V6((*ip).into());
+                !self.excluded.iter().any(|net| net.contains(addr))
+            }
+            _ => false,
+        })
+    }
+}
+
+impl<H: Handler> Handler for Dns64<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let prefix = self
+            .prefixes
+            .iter()
+            .find(|prefix| prefix.applies_to(src.ip()));
+        let validating = request.header.flags.checking_disabled
+            && matches!(request.edns(), Some(Ok(edns)) if edns.dnssec_ok);
+        let Some(prefix) = prefix.filter(|_| !validating) else {
+            return self.inner.handle(request, src).await;
+        };
+        match request.questions.first() {
+            Some(question) if question.qtype == QueryType::AAAA && question.class == 1 => {}
+            _ => return self.inner.handle(request, src).await,
+        }
+
+        let mut lookup = request.clone();
+        let res = self.inner.handle(request, src).await?;
+        if res.header.rcode == ResultCode::NXDOMAIN || self.has_aaaa(&res) {
+            return Some(res);
+        }
+        lookup.questions[0].qtype = QueryType::A;
+        let Some(a) = self.inner.handle(lookup, src).await else {
+            return Some(res);
+        };
+        if a.header.rcode != ResultCode::NOERROR
+            || !a
+                .answers
+                .iter()
+                .any(|rec| matches!(rec, DnsRecord::A { .. }))
+        {
+            return Some(res);
+        }
+
+        // made up records last no longer than the name's lack of AAAA records, section 5.1.7
+        let negative_ttl = res.authorities.iter().find_map(|rec| match rec {
+            DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+            _ => None,
+        });
+        let mut synthesized = DnsPacket::response_to(&res);
+        synthesized.header.flags = a.header.flags;
+        // nothing made up can be authentic
+        synthesized.header.flags.authentic_data = false;
+        for rec in a.answers {
+            match rec {
+                DnsRecord::A {
+                    domain,
+                    class,
+                    ttl,
+                    ip,
+                } => {
+                    synthesized.add_answer(DnsRecord::AAAA {
+                        domain,
+                        class,
+                        ttl: negative_ttl.map_or(ttl, |max| ttl.min(max)),
+                        ip: prefix.embed(ip.into()).into(),
+                    });
+                }
+                // the signatures were over the A records, not over what they became
+                DnsRecord::RRSIG { .. } => {}
+                rec => {
+                    synthesized.add_answer(rec);
+                }
+            }
+        }
+        let edns = res.edns().and_then(|edns| edns.ok());
+        synthesized.set_edns(edns.as_ref());
+        Some(synthesized)
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.inner.transfer(request, src).await
+    }
+}
@@ -0,0 +1,429 @@
+// DNS-based service discovery (rfc 6763) over multicast DNS, how printers, speakers and the
+// like on a link are found. a service type such as _ipp._tcp.local has a PTR record for each
+// instance of it, e.g. `Office._ipp._tcp.local`, and each instance an SRV record with the host
+// and port it's at and a TXT record of key=value attributes. browsing asks for the PTR records,
+// resolving for an instance's SRV and TXT and the host's addresses, each as one-shot queries
+// (see mdns.rs). a Browser keeps browsing instead, asking again less and less often (section
+// 5.2 of rfc 6762) and whenever an instance's record is due for renewal, and says when
+// instances come and go, whether they said goodbye with a zero ttl or just stopped answering.
+// browsing _services._dns-sd._udp.local lists the service types on the link (section 9).
+// instance names can't have dots in them, since names here are plain dotted strings, and come
+// back lowercase like every name read off the wire.
+use crate::error::Result;
+use crate::mdns::{self, Responder, DEFAULT_TTL, GROUP_V4, MAX_MESSAGE, MDNS_PORT};
+use crate::structure::{BytePacketBuffer, DnsPacket, DnsRecord, QueryType};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+
+/// The name whose PTR records are the service types on the link.
+pub const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+// the interval between continuous queries starts at a second and doubles up to an hour,
+// section 5.2 of rfc 6762
+const FIRST_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_INTERVAL: Duration = Duration::from_secs(3600);
+// an instance is asked about again when this much of its ttl has gone, section 5.2
+const RENEW_PERCENT: u32 = 80;
+// known answers that don't fit in a query are left out, so their owners answer again rather
+// than the query being split (section 7.2 of rfc 6762)
+const MAX_QUERY_SIZE: usize = 1400;
+
+/// An instance of a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Service {
+    /// The instance's full name, e.g. `Office._ipp._tcp.local`.
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    /// The attributes from the TXT record, each `key=value` or a bare `key`.
+    pub txt: Vec<String>,
+    /// The host's addresses, if any came back.
+    pub addrs: Vec<IpAddr>,
+}
+
+impl Service {
+    /// An instance called `instance` of `service_type`, e.g. `_ipp._tcp`, at `port` on
+    /// `host`, for a [`Responder`] to advertise.
+    pub fn new(instance: &str, service_type: &str, host: &str, port: u16) -> Self {
+        Self {
+            name: format!("{}.{}", instance, qualified(service_type)),
+            host: host.trim_end_matches('.').to_lowercase(),
+            port,
+            txt: Vec::new(),
+            addrs: Vec::new(),
+        }
+    }
+
+    /// Adds a `key=value` attribute.
+    pub fn attribute(mut self, key: &str, value: &str) -> Self {
+        self.txt.push(format!("{}={}", key, value));
+        self
+    }
+
+    /// The instance name, the part before the service type.
+    pub fn instance(&self) -> &str {
+        self.name.split('.').next().unwrap_or_default()
+    }
+
+    /// The service type, e.g. `_ipp._tcp.local`.
+    pub fn service_type(&self) -> &str {
+        self.name
+            .split_once('.')
+            .map(|(_, rest)| rest)
+            .unwrap_or_default()
+    }
+
+    /// The value of attribute `key`, compared case insensitively, empty for a bare key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.txt.iter().find_map(|attribute| {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            name.eq_ignore_ascii_case(key).then_some(value)
+        })
+    }
+
+    /// The records that advertise it and whether each is unique to us: the PTR records for
+    /// its type and for the type's own listing are shared with other instances, the SRV and
+    /// TXT records are its own. The host's addresses are the responder's.
+    pub fn records(&self) -> Vec<(DnsRecord, bool)> {
+        // an empty TXT record is one empty string, section 6.1
+        let txt = match self.txt.is_empty() {
+            true => vec![String::new()],
+            false => self.txt.clone(),
+        };
+        vec![
+            (
+                DnsRecord::PTR {
+                    domain: SERVICE_TYPES.to_string(),
+                    class: 1,
+                    ttl: DEFAULT_TTL,
+                    host: self.service_type().to_string(),
+                },
+                false,
+            ),
+            (
+                DnsRecord::PTR {
+                    domain: self.service_type().to_string(),
+                    class: 1,
+                    ttl: DEFAULT_TTL,
+                    host: self.name.clone(),
+                },
+                false,
+            ),
+            (
+                DnsRecord::SRV {
+                    domain: self.name.clone(),
+                    class: 1,
+                    ttl: mdns::HOST_TTL,
+                    priority: 0,
+                    weight: 0,
+                    port: self.port,
+                    host: self.host.clone(),
+                },
+                true,
+            ),
+            (
+                DnsRecord::TXT {
+                    domain: self.name.clone(),
+                    class: 1,
+                    ttl: DEFAULT_TTL,
+                    data: txt,
+                },
+                true,
+            ),
+        ]
+    }
+}
+
+impl Responder {
+    /// Advertises `service` too. Its host should be one the responder answers for, see
+    /// [`Responder::host`].
+    pub fn service(self, service: &Service) -> Self {
+        service
+            .records()
+            .into_iter()
+            .fold(self, |responder, (record, unique)| {
+                responder.record(record, unique)
+            })
+    }
+}
+
+// service types are under .local unless they say otherwise
+fn qualified(service_type: &str) -> String {
+    let service_type = service_type.trim_end_matches('.').to_lowercase();
+    match service_type.ends_with(".local") {
+        true => service_type,
+        false => format!("{}.local", service_type),
+    }
+}
+
+/// The names of the instances of `service_type`, e.g. `_ipp._tcp`, that answer within
+/// `wait`.
+pub async fn browse(service_type: &str, wait: Duration) -> Result<Vec<String>> {
+    let service_type = qualified(service_type);
+    let records = mdns::query(&service_type, QueryType::PTR, wait).await?;
+    let mut names = Vec::new();
+    for record in records {
+        if let DnsRecord::PTR { domain, host, .. } = record {
+            if domain.eq_ignore_ascii_case(&service_type) && !names.contains(&host) {
+                names.push(host);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// The host, port, attributes and addresses of instance `name`, e.g.
+/// `Office._ipp._tcp.local`, None if it doesn't answer within `wait`.
+pub async fn resolve(name: &str, wait: Duration) -> Result<Option<Service>> {
+    let name = name.trim_end_matches('.');
+    let (srv, txt) = tokio::join!(
+        mdns::query(name, QueryType::SRV, wait),
+        mdns::query(name, QueryType::TXT, wait)
+    );
+    let (srv, txt) = (srv?, txt?);
+    let Some((host, port)) = srv.iter().find_map(|record| match record {
+        DnsRecord::SRV {
+            domain, host, port, ..
+        } if domain.eq_ignore_ascii_case(name) => Some((host.clone(), *port)),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+    let txt = txt
+        .into_iter()
+        .find_map(|record| match record {
+            DnsRecord::TXT { domain, data, .. } if domain.eq_ignore_ascii_case(name) => Some(data),
+            _ => None,
+        })
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|attribute| !attribute.is_empty())
+        .collect();
+
+    // responders usually send the addresses along with the SRV record, rfc 6763 section 12.2
+    let mut addrs = addresses(&srv, &host);
+    if addrs.is_empty() {
+        let (a, aaaa) = tokio::join!(
+            mdns::query(&host, QueryType::A, wait),
+            mdns::query(&host, QueryType::AAAA, wait)
+        );
+        addrs = addresses(&a?.into_iter().chain(aaaa?).collect::<Vec<_>>(), &host);
+    }
+    Ok(Some(Service {
+        name: name.to_string(),
+        host,
+        port,
+        txt,
+        addrs,
+    }))
+}
+
+fn addresses(records: &[DnsRecord], host: &str) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    for record in records {
+        let addr = match record {
+            DnsRecord::A { domain, ip, .. } if domain.eq_ignore_ascii_case(host) => {
+                IpAddr::V4((*ip).into())
+            }
+            DnsRecord::AAAA { domain, ip, .. } if domain.eq_ignore_ascii_case(host) => {
+                IpAddr::V6((*ip).into())
+            }
+            _ => continue,
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+// an instance seen while browsing
+struct Instance {
+    ttl: u32,
+    seen: Instant,
+    // whether it's been asked about again since its record was last seen
+    renewing: bool,
+}
+
+impl Instance {
+    fn expires(&self) -> Instant {
+        self.seen + Duration::from_secs(self.ttl.into())
+    }
+
+    fn renews(&self) -> Instant {
+        self.seen + Duration::from_secs(self.ttl.into()) * RENEW_PERCENT / 100
+    }
+
+    fn remaining(&self, now: Instant) -> u32 {
+        self.expires().saturating_duration_since(now).as_secs() as u32
+    }
+}
+
+type Callback = Box<dyn FnMut(&str) + Send>;
+
+/// Keeps browsing a service type and calls back with the names of instances as they come
+/// and go.
+pub struct Browser {
+    service_type: String,
+    on_added: Option<Callback>,
+    on_removed: Option<Callback>,
+    instances: HashMap<String, Instance>,
+}
+
+impl Browser {
+    /// Browses `service_type`, e.g. `_ipp._tcp`.
+    pub fn new(service_type: &str) -> Self {
+        Self {
+            service_type: qualified(service_type),
+            on_added: None,
+            on_removed: None,
+            instances: HashMap::new(),
+        }
+    }
+
+    /// Calls `f` with the name of each instance that appears, see [`resolve`].
+    pub fn on_added(mut self, f: impl FnMut(&str) + Send + 'static) -> Self {
+        self.on_added = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` with the name of each instance that goes away.
+    pub fn on_removed(mut self, f: impl FnMut(&str) + Send + 'static) -> Self {
+        self.on_removed = Some(Box::new(f));
+        self
+    }
+
+    /// The names of the instances there are now.
+    pub fn instances(&self) -> impl Iterator<Item = &str> {
+        self.instances.keys().map(String::as_str)
+    }
+
+    /// Browses until the future is dropped or the socket fails.
+    pub async fn run(&mut self) -> Result<()> {
+        let socket = mdns::multicast_socket(false)?;
+        socket.join_multicast_v4(&GROUP_V4, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_ttl_v4(255)?;
+        let socket = UdpSocket::from_std(socket)?;
+
+        let mut interval = FIRST_INTERVAL;
+        let mut next_query = Instant::now();
+        loop {
+            let now = Instant::now();
+            let renew = self
+                .instances
+                .values()
+                .any(|instance| !instance.renewing && instance.renews() <= now);
+            if next_query <= now || renew {
+                self.query(&socket, now).await?;
+                if next_query <= now {
+                    next_query = now + interval;
+                    interval = (interval * 2).min(MAX_INTERVAL);
+                }
+            }
+            self.expire(now);
+
+            let wake = self
+                .instances
+                .values()
+                .map(|instance| match instance.renewing {
+                    true => instance.expires(),
+                    false => instance.renews(),
+                })
+                .fold(next_query, Instant::min);
+            // a fresh buffer per message, as mdns.rs does
+            let mut buf = BytePacketBuffer::with_len(MAX_MESSAGE);
+            let Ok(received) = time::timeout_at(wake, socket.recv_from(&mut buf.buf[..])).await
+            else {
+                continue;
+            };
+            let (len, _) = received?;
+            let Ok(res) = DnsPacket::from_message(&mut buf, len) else {
+                continue;
+            };
+            if !res.header.flags.is_response() {
+                continue;
+            }
+            for record in res.answers.iter().chain(&res.additional) {
+                self.seen(record);
+            }
+        }
+    }
+
+    // asks for the type's PTR records, listing the ones known with more than half their ttl
+    // left so their owners don't answer again, section 7.1 of rfc 6762
+    async fn query(&mut self, socket: &UdpSocket, now: Instant) -> Result<()> {
+        let mut query = DnsPacket::query(&self.service_type, QueryType::PTR)
+            .recursion_desired(false)
+            .edns(None)
+            .build();
+        query.header.id = 0;
+        let mut size = 12 + self.service_type.len() + 6;
+        for (name, instance) in &mut self.instances {
+            let remaining = instance.remaining(now);
+            // the type's name is compressed to a pointer
+            size += 2 + 10 + name.len() + 2;
+            if remaining <= instance.ttl / 2 {
+                instance.renewing = true;
+            } else if size <= MAX_QUERY_SIZE {
+                query.add_answer(DnsRecord::PTR {
+                    domain: self.service_type.clone(),
+                    class: 1,
+                    ttl: remaining,
+                    host: name.clone(),
+                });
+            }
+        }
+        let mut buf = BytePacketBuffer::new();
+        query.write(&mut buf)?;
+        socket
+            .send_to(&buf.buf[..buf.pos], (GROUP_V4, MDNS_PORT))
+            .await?;
+        Ok(())
+    }
+
+    fn seen(&mut self, record: &DnsRecord) {
+        let DnsRecord::PTR { domain, host, .. } = record else {
+            return;
+        };
+        if !domain.eq_ignore_ascii_case(&self.service_type) {
+            return;
+        }
+        // a goodbye, section 10.1
+        if record.ttl() == 0 {
+            if self.instances.remove(host).is_some() {
+                if let Some(f) = &mut self.on_removed {
+                    f(host);
+                }
+            }
+            return;
+        }
+        let instance = Instance {
+            ttl: record.ttl(),
+            seen: Instant::now(),
+            renewing: false,
+        };
+        if self.instances.insert(host.clone(), instance).is_none() {
+            if let Some(f) = &mut self.on_added {
+                f(host);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<String> = self
+            .instances
+            .iter()
+            .filter(|(_, instance)| instance.expires() <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in expired {
+            self.instances.remove(&name);
+            if let Some(f) = &mut self.on_removed {
+                f(&name);
+            }
+        }
+    }
+}
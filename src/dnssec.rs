@@ -0,0 +1,824 @@
+// the parts of DNSSEC validation (rfc 4033, 4034, 4035, and rfc 5155 for NSEC3) that don't
+// need the network: key tags and DS digests, the canonical order of names, checking the
+// signatures over RRsets, and proving from NSEC or NSEC3 records that a name or type doesn't
+// exist. the validator strings these together with the lookups.
+use crate::digest::{Sha1, Sha256};
+use crate::edns::EdeCode;
+use crate::presentation::{base32hex, parse_base32hex};
+use crate::signature::Algorithm;
+use crate::structure::{is_subdomain, DnsPacket, DnsRecord, QueryType};
+use std::cmp::Ordering;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// DNSKEY flag of keys that sign the zone's data, rfc 4034 section 2.1.1.
+pub const ZONE_KEY: u16 = 0x0100;
+/// DNSKEY flag of keys meant to be pointed at by DS records (the key signing key).
+pub const SECURE_ENTRY_POINT: u16 = 0x0001;
+// DNSKEY protocol field, always 3
+const PROTOCOL: u8 = 3;
+const DIGEST_SHA1: u8 = 1;
+const DIGEST_SHA256: u8 = 2;
+const NSEC3_SHA1: u8 = 1;
+const NSEC3_OPT_OUT: u8 = 0x01;
+/// NSEC3 chains hashed more often than this are treated as insecure rather than spending the
+/// time to check them, rfc 9276 section 3.2.
+pub const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// Why something didn't validate, with the extended error (rfc 8914) to answer with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bogus {
+    pub code: EdeCode,
+    pub reason: String,
+}
+
+impl Bogus {
+    pub fn new(code: EdeCode, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Bogus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// What NSEC or NSEC3 records prove about a name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denial {
+    /// The name doesn't exist, and no wildcard could have made it.
+    NxDomain,
+    /// The name exists without the type. `delegation` if it's a zone cut the parent has no
+    /// DS for, i.e. an insecure delegation.
+    NoData { delegation: bool },
+    /// The proof relies on an opt-out NSEC3 span or an NSEC3 chain too costly to check, so
+    /// there may be an unsigned delegation and the answer can't be more than insecure.
+    Insecure,
+}
+
+/// The current time as RRSIG validity periods count it.
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// The key tag of a DNSKEY record, which RRSIG and DS records name keys by. rfc 4034
+/// appendix B.
+pub fn key_tag(dnskey: &DnsRecord) -> Option<u16> {
+    if !matches!(dnskey, DnsRecord::DNSKEY { .. }) {
+        return None;
+    }
+    let rdata = dnskey.rdata().ok()?;
+    let mut acc: u32 = 0;
+    for (i, &b) in rdata.iter().enumerate() {
+        acc += if i % 2 == 0 {
+            (b as u32) << 8
+        } else {
+            b as u32
+        };
+    }
+    acc += acc >> 16;
+    Some(acc as u16)
+}
+
+/// The digest a DS record pointing at `dnskey` would hold, None for digest types we don't
+/// know. rfc 4034 section 5.1.4
+pub fn ds_digest(dnskey: &DnsRecord, digest_type: u8) -> Option<Vec<u8>> {
+    let mut data = wire_name(dnskey.domain());
+    data.extend(dnskey.rdata().ok()?);
+    match digest_type {
+        DIGEST_SHA1 => Some(Sha1::digest(&data).to_vec()),
+        DIGEST_SHA256 => Some(Sha256::digest(&data).to_vec()),
+        _ => None,
+    }
+}
+
+/// Whether a DS record can be used at all: its algorithm and digest type are ones we
+/// support. A zone whose DS records are all unusable is treated as unsigned.
+pub fn supported_ds(ds: &DnsRecord) -> bool {
+    matches!(ds, DnsRecord::DS { algorithm, digest_type, .. }
+        if Algorithm::from_num(*algorithm).is_some()
+            && matches!(*digest_type, DIGEST_SHA1 | DIGEST_SHA256))
+}
+
+/// Whether `ds` points at `dnskey`.
+pub fn ds_matches(ds: &DnsRecord, dnskey: &DnsRecord) -> bool {
+    let (
+        DnsRecord::DS {
+            domain,
+            key_tag: tag,
+            algorithm,
+            digest_type,
+            digest,
+            ..
+        },
+        DnsRecord::DNSKEY {
+            domain: key_domain,
+            algorithm: key_algorithm,
+            ..
+        },
+    ) = (ds, dnskey)
+    else {
+        return false;
+    };
+    domain.eq_ignore_ascii_case(key_domain)
+        && algorithm == key_algorithm
+        && key_tag(dnskey) == Some(*tag)
+        && ds_digest(dnskey, *digest_type).is_some_and(|d| d == *digest)
+}
+
+// the labels of a name, leftmost first, none for the root
+pub(crate) fn labels(name: &str) -> Vec<&str> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        Vec::new()
+    } else {
+        name.split('.').collect()
+    }
+}
+
+/// Orders names the way NSEC chains are: by their labels from the root down, each compared
+/// as lowercase bytes.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (labels(a), labels(b));
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        let ord = x
+            .bytes()
+            .map(|c| c.to_ascii_lowercase())
+            .cmp(y.bytes().map(|c| c.to_ascii_lowercase()));
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// A name ordered by [`canonical_cmp`], for keeping NSEC owners sorted the way the chain
+/// runs.
+#[derive(Clone, Debug)]
+pub struct CanonicalName(pub String);
+
+impl PartialEq for CanonicalName {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CanonicalName {}
+
+impl PartialOrd for CanonicalName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CanonicalName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        canonical_cmp(&self.0, &other.0)
+    }
+}
+
+/// The number of labels in `name` as RRSIG records count them: without the root, and without
+/// a leading wildcard label.
+pub fn label_count(name: &str) -> usize {
+    let labels = labels(name);
+    labels.len() - (labels.first() == Some(&"*")) as usize
+}
+
+// the last `count` labels of `name`, its ancestor that many labels below the root
+pub(crate) fn suffix(name: &str, count: usize) -> String {
+    let labels = labels(name);
+    labels[labels.len() - count.min(labels.len())..].join(".")
+}
+
+// the name one label longer than `ancestor` on the way down to `name`
+fn child_toward(name: &str, ancestor: &str) -> String {
+    suffix(name, labels(ancestor).len() + 1)
+}
+
+// the closest name both are under
+fn common_ancestor(a: &str, b: &str) -> String {
+    let (la, lb) = (labels(a), labels(b));
+    let common = la
+        .iter()
+        .rev()
+        .zip(lb.iter().rev())
+        .take_while(|(x, y)| x.eq_ignore_ascii_case(y))
+        .count();
+    suffix(a, common)
+}
+
+// the wildcard directly below a name
+pub(crate) fn wildcard(name: &str) -> String {
+    if name.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{}", name)
+    }
+}
+
+// uncompressed and lowercase, the canonical wire form of a name
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len() + 2);
+    for label in labels(name) {
+        out.push(label.len() as u8);
+        out.extend(label.bytes().map(|b| b.to_ascii_lowercase()));
+    }
+    out.push(0);
+    out
+}
+
+/// The records of one RRset and the signatures over it, as grouped by [`rrsets`].
+#[derive(Clone, Debug)]
+pub struct RRset<'a> {
+    pub name: &'a str,
+    pub qtype: QueryType,
+    pub records: Vec<&'a DnsRecord>,
+    pub signatures: Vec<&'a DnsRecord>,
+}
+
+/// Groups `records` into RRsets, in the order they first appear, with the RRSIGs covering
+/// each. Signatures without records to go with them are left out.
+pub fn rrsets(records: &[DnsRecord]) -> Vec<RRset<'_>> {
+    let mut sets: Vec<RRset> = Vec::new();
+    for rec in records {
+        if matches!(rec, DnsRecord::RRSIG { .. } | DnsRecord::OPT { .. }) {
+            continue;
+        }
+        let (name, qtype) = (rec.domain(), rec.qtype());
+        match sets
+            .iter_mut()
+            .find(|set| set.qtype == qtype && set.name.eq_ignore_ascii_case(name))
+        {
+            Some(set) => set.records.push(rec),
+            None => sets.push(RRset {
+                name,
+                qtype,
+                records: vec![rec],
+                signatures: Vec::new(),
+            }),
+        }
+    }
+    for rec in records {
+        let DnsRecord::RRSIG { type_covered, .. } = rec else {
+            continue;
+        };
+        if let Some(set) = sets
+            .iter_mut()
+            .find(|set| set.qtype == *type_covered && set.name.eq_ignore_ascii_case(rec.domain()))
+        {
+            set.signatures.push(rec);
+        }
+    }
+    sets
+}
+
+/// What an RRSIG signs: its own rdata without the signature, then every record of the RRset
+/// in canonical form and order, with the TTL it was signed with. rfc 4034 section 3.1.8.1
+pub fn signed_data(rrset: &[&DnsRecord], rrsig: &DnsRecord) -> Option<Vec<u8>> {
+    let DnsRecord::RRSIG {
+        type_covered,
+        algorithm,
+        labels,
+        original_ttl,
+        expiration,
+        inception,
+        key_tag,
+        signer,
+        ..
+    } = rrsig
+    else {
+        return None;
+    };
+    let mut data = Vec::new();
+    data.extend(type_covered.to_num().to_be_bytes());
+    data.push(*algorithm);
+    data.push(*labels);
+    data.extend(original_ttl.to_be_bytes());
+    data.extend(expiration.to_be_bytes());
+    data.extend(inception.to_be_bytes());
+    data.extend(key_tag.to_be_bytes());
+    data.extend(wire_name(signer));
+
+    // records expanded from a wildcard are signed under the wildcard's name
+    let owner = rrset.first()?.domain();
+    let count = label_count(owner);
+    let owner = match (*labels as usize).cmp(&count) {
+        Ordering::Equal => owner.to_string(),
+        Ordering::Less => wildcard(&suffix(owner, *labels as usize)),
+        Ordering::Greater => return None,
+    };
+
+    let mut rdatas = rrset
+        .iter()
+        .map(|rec| rec.rdata().ok())
+        .collect::<Option<Vec<_>>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+    for rdata in rdatas {
+        data.extend(wire_name(&owner));
+        data.extend(type_covered.to_num().to_be_bytes());
+        data.extend(rrset[0].class().to_be_bytes());
+        data.extend(original_ttl.to_be_bytes());
+        data.extend((rdata.len() as u16).to_be_bytes());
+        data.extend(rdata);
+    }
+    Some(data)
+}
+
+// serial number arithmetic, the times wrap around every 136 years
+fn not_before(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) as i32 <= 0
+}
+
+/// Checks that one of `signatures` over `rrset` was made by one of `keys`, the zone's
+/// DNSKEY records, and is valid at `now` (rfc 4035 section 5.3). Returns the signature that
+/// verified.
+pub fn verify_rrset<'a>(
+    rrset: &[&DnsRecord],
+    signatures: &[&'a DnsRecord],
+    keys: &[DnsRecord],
+    now: u32,
+) -> Result<&'a DnsRecord, Bogus> {
+    let Some(first) = rrset.first() else {
+        return Err(Bogus::new(EdeCode::DnssecBogus, "empty RRset"));
+    };
+    let (name, qtype) = (first.domain(), first.qtype());
+    let mut failure = Bogus::new(
+        EdeCode::RrsigsMissing,
+        format!("no signature over {} {}", name, qtype),
+    );
+    for &rrsig in signatures {
+        let DnsRecord::RRSIG {
+            domain,
+            type_covered,
+            algorithm,
+            labels,
+            expiration,
+            inception,
+            key_tag: tag,
+            signer,
+            signature,
+            ..
+        } = rrsig
+        else {
+            continue;
+        };
+        if *type_covered != qtype
+            || !domain.eq_ignore_ascii_case(name)
+            || rrsig.class() != first.class()
+            || !is_subdomain(name, signer)
+            || *labels as usize > label_count(name)
+        {
+            continue;
+        }
+        let Some(alg) = Algorithm::from_num(*algorithm) else {
+            failure = Bogus::new(
+                EdeCode::UnsupportedDnskeyAlgorithm,
+                format!("{} {} is signed with algorithm {}", name, qtype, algorithm),
+            );
+            continue;
+        };
+        if !not_before(now, *inception) {
+            failure = Bogus::new(
+                EdeCode::SignatureNotYetValid,
+                format!("signature over {} {} isn't valid yet", name, qtype),
+            );
+            continue;
+        }
+        if !not_before(*expiration, now) {
+            failure = Bogus::new(
+                EdeCode::SignatureExpired,
+                format!("signature over {} {} has expired", name, qtype),
+            );
+            continue;
+        }
+        let Some(data) = signed_data(rrset, rrsig) else {
+            continue;
+        };
+
+        let candidates = keys.iter().filter(|key| {
+            matches!(key, DnsRecord::DNSKEY { domain, flags, protocol, algorithm: key_alg, .. }
+                if domain.eq_ignore_ascii_case(signer)
+                    && flags & ZONE_KEY != 0
+                    && *protocol == PROTOCOL
+                    && key_alg == algorithm)
+                && key_tag(key) == Some(*tag)
+        });
+        let mut any_key = false;
+        for key in candidates {
+            any_key = true;
+            let DnsRecord::DNSKEY { public_key, .. } = key else {
+                continue;
+            };
+            if alg.verify(public_key, &data, signature) {
+                return Ok(rrsig);
+            }
+        }
+        failure = if any_key {
+            Bogus::new(
+                EdeCode::DnssecBogus,
+                format!("bad signature over {} {}", name, qtype),
+            )
+        } else {
+            Bogus::new(
+                EdeCode::DnskeyMissing,
+                format!(
+                    "no key {} of {} for the signature over {} {}",
+                    tag, signer, name, qtype
+                ),
+            )
+        };
+    }
+    Err(failure)
+}
+
+/// Checks a zone's DNSKEY RRset against what's trusted to point at its keys: the DS records
+/// its parent has for it, or trust anchors, which can be DNSKEY records too. One of the keys
+/// pointed at has to have signed the set. Returns the zone keys, which sign everything else
+/// in the zone.
+pub fn verify_dnskeys(
+    zone: &str,
+    dnskeys: &[&DnsRecord],
+    signatures: &[&DnsRecord],
+    trusted: &[&DnsRecord],
+    now: u32,
+) -> Result<Vec<DnsRecord>, Bogus> {
+    let points_at = |trusted: &DnsRecord, key: &DnsRecord| match trusted {
+        DnsRecord::DS { .. } => supported_ds(trusted) && ds_matches(trusted, key),
+        DnsRecord::DNSKEY { .. } => trusted.rdata().ok() == key.rdata().ok(),
+        _ => false,
+    };
+    let entry_points: Vec<DnsRecord> = dnskeys
+        .iter()
+        .filter(|key| trusted.iter().any(|trusted| points_at(trusted, key)))
+        .map(|&key| key.clone())
+        .collect();
+    if entry_points.is_empty() {
+        return Err(Bogus::new(
+            EdeCode::DnskeyMissing,
+            format!("no DNSKEY of {} matches its DS records", zone),
+        ));
+    }
+    verify_rrset(dnskeys, signatures, &entry_points, now)?;
+    Ok(dnskeys.iter().map(|&key| key.clone()).collect())
+}
+
+/// The NSEC3 hash of `name`: SHA-1 over the name and salt, then `iterations` more times over
+/// the previous hash and the salt. rfc 5155 section 5
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut hash = Sha1::new();
+    hash.update(&wire_name(name));
+    hash.update(salt);
+    let mut digest = hash.finish();
+    for _ in 0..iterations {
+        let mut hash = Sha1::new();
+        hash.update(&digest);
+        hash.update(salt);
+        digest = hash.finish();
+    }
+    digest.to_vec()
+}
+
+/// The owner name of the NSEC3 record for `name` in `zone`: the hash in lowercase
+/// base32hex, as a label right below the apex.
+pub fn nsec3_owner(name: &str, zone: &str, salt: &[u8], iterations: u16) -> String {
+    let label = base32hex(&nsec3_hash(name, salt, iterations)).to_lowercase();
+    if zone.is_empty() {
+        label
+    } else {
+        format!("{}.{}", label, zone)
+    }
+}
+
+// a delegation's NSEC comes from the parent and says nothing about what's below the cut
+fn is_delegation(types: &[QueryType]) -> bool {
+    types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA)
+}
+
+// the types at a name say it doesn't have `qtype`, not even behind a CNAME
+fn lacks(types: &[QueryType], qtype: QueryType) -> bool {
+    !types.contains(&qtype) && !types.contains(&QueryType::CNAME)
+}
+
+/// Proves from the NSEC or NSEC3 records among `records`, all already validated as signed by
+/// `zone`, that `name` doesn't exist (for NXDOMAIN answers) or doesn't have `qtype`.
+pub fn prove_denial(
+    name: &str,
+    qtype: QueryType,
+    nxdomain: bool,
+    zone: &str,
+    records: &[&DnsRecord],
+) -> Result<Denial, Bogus> {
+    let nsecs: Vec<Nsec> = records
+        .iter()
+        .filter_map(|rec| Nsec::from_record(rec, zone))
+        .collect();
+    if !nsecs.is_empty() {
+        return prove_nsec(name, qtype, nxdomain, zone, &nsecs);
+    }
+    match Nsec3Chain::from_records(records, zone) {
+        Some(Ok(chain)) => chain.prove(name, qtype, nxdomain, zone),
+        Some(Err(denial)) => Ok(denial),
+        None => Err(missing(name, qtype)),
+    }
+}
+
+/// Proves that `name`, answered from the wildcard at `closest_encloser`, doesn't exist
+/// itself, which is what makes the wildcard apply. rfc 4035 section 5.3.4
+pub fn prove_wildcard(
+    name: &str,
+    closest_encloser: &str,
+    zone: &str,
+    records: &[&DnsRecord],
+) -> Result<Denial, Bogus> {
+    let nsecs: Vec<Nsec> = records
+        .iter()
+        .filter_map(|rec| Nsec::from_record(rec, zone))
+        .collect();
+    if nsecs.iter().any(|nsec| nsec.covers(name)) {
+        return Ok(Denial::NxDomain);
+    }
+    match Nsec3Chain::from_records(records, zone) {
+        Some(Ok(chain))
+            if chain
+                .covering(&child_toward(name, closest_encloser))
+                .is_some() =>
+        {
+            Ok(Denial::NxDomain)
+        }
+        Some(Err(denial)) => Ok(denial),
+        _ => Err(Bogus::new(
+            EdeCode::NsecMissing,
+            format!("nothing proves {} isn't there besides the wildcard", name),
+        )),
+    }
+}
+
+fn missing(name: &str, qtype: QueryType) -> Bogus {
+    Bogus::new(
+        EdeCode::NsecMissing,
+        format!("no proof that {} {} doesn't exist", name, qtype),
+    )
+}
+
+struct Nsec<'a> {
+    owner: &'a str,
+    next: &'a str,
+    types: &'a [QueryType],
+}
+
+impl<'a> Nsec<'a> {
+    fn from_record(rec: &'a DnsRecord, zone: &str) -> Option<Self> {
+        match rec {
+            DnsRecord::NSEC {
+                domain,
+                next,
+                types,
+                ..
+            } if is_subdomain(domain, zone) => Some(Nsec {
+                owner: domain,
+                next,
+                types,
+            }),
+            _ => None,
+        }
+    }
+
+    // the name falls strictly between the owner and the next name, the last NSEC in the zone
+    // wrapping around to the apex. names below a delegation or a DNAME aren't covered, the
+    // zone doesn't know about them
+    fn covers(&self, name: &str) -> bool {
+        let below_cut = is_subdomain(name, self.owner)
+            && (is_delegation(self.types) || self.types.contains(&QueryType::DNAME));
+        canonical_cmp(self.owner, name) == Ordering::Less
+            && (canonical_cmp(name, self.next) == Ordering::Less
+                || canonical_cmp(self.next, self.owner) != Ordering::Greater)
+            && !below_cut
+    }
+}
+
+// rfc 4035 section 5.4
+fn prove_nsec(
+    name: &str,
+    qtype: QueryType,
+    nxdomain: bool,
+    zone: &str,
+    nsecs: &[Nsec],
+) -> Result<Denial, Bogus> {
+    if !nxdomain {
+        if let Some(nsec) = nsecs.iter().find(|n| n.owner.eq_ignore_ascii_case(name)) {
+            let delegation = is_delegation(nsec.types);
+            // below a cut the child zone answers, except for DS which lives in the parent
+            if !lacks(nsec.types, qtype) || (delegation && qtype != QueryType::DS) {
+                return Err(missing(name, qtype));
+            }
+            return Ok(Denial::NoData { delegation });
+        }
+    }
+
+    let Some(cover) = nsecs.iter().find(|n| n.covers(name)) else {
+        return Err(missing(name, qtype));
+    };
+    // a name with something below it exists even without records of its own
+    let empty_non_terminal = is_subdomain(cover.next, name);
+    let mut closest_encloser = common_ancestor(name, cover.owner);
+    let other = common_ancestor(name, cover.next);
+    if labels(&other).len() > labels(&closest_encloser).len() {
+        closest_encloser = other;
+    }
+    if !is_subdomain(&closest_encloser, zone) {
+        closest_encloser = zone.to_string();
+    }
+    let wildcard = wildcard(&closest_encloser);
+
+    if nxdomain {
+        if !empty_non_terminal && nsecs.iter().any(|n| n.covers(&wildcard)) {
+            return Ok(Denial::NxDomain);
+        }
+        return Err(missing(name, qtype));
+    }
+    if empty_non_terminal {
+        return Ok(Denial::NoData { delegation: false });
+    }
+    // answered from a wildcard that doesn't have the type either
+    match nsecs
+        .iter()
+        .find(|n| n.owner.eq_ignore_ascii_case(&wildcard))
+    {
+        Some(nsec) if lacks(nsec.types, qtype) => Ok(Denial::NoData { delegation: false }),
+        _ => Err(missing(name, qtype)),
+    }
+}
+
+struct Nsec3<'a> {
+    hash: Vec<u8>,
+    next: &'a [u8],
+    opt_out: bool,
+    types: &'a [QueryType],
+}
+
+impl Nsec3<'_> {
+    // the hash falls strictly between the owner's and the next one. the last record in the
+    // chain wraps around, covering the hashes after its own and those before the first
+    fn covers(&self, hash: &[u8]) -> bool {
+        let owner = self.hash.as_slice();
+        match owner < self.next {
+            true => owner < hash && hash < self.next,
+            false => owner < hash || hash < self.next,
+        }
+    }
+}
+
+struct Nsec3Chain<'a> {
+    salt: &'a [u8],
+    iterations: u16,
+    records: Vec<Nsec3<'a>>,
+}
+
+impl<'a> Nsec3Chain<'a> {
+    // None without NSEC3 records, and the answer outright if they can't be checked. records
+    // with other parameters than the first aren't part of the same chain and are ignored
+    fn from_records(records: &[&'a DnsRecord], zone: &str) -> Option<Result<Self, Denial>> {
+        let mut chain: Option<Nsec3Chain> = None;
+        for rec in records {
+            let DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } = rec
+            else {
+                continue;
+            };
+            // the owner is the hash in base32hex, right below the apex
+            let Some((label, parent)) = domain.split_once('.') else {
+                continue;
+            };
+            let Ok(hash) = parse_base32hex(label) else {
+                continue;
+            };
+            if !parent.eq_ignore_ascii_case(zone) {
+                continue;
+            }
+            if *hash_algorithm != NSEC3_SHA1 || *iterations > MAX_NSEC3_ITERATIONS {
+                return Some(Err(Denial::Insecure));
+            }
+            let chain = chain.get_or_insert_with(|| Nsec3Chain {
+                salt,
+                iterations: *iterations,
+                records: Vec::new(),
+            });
+            if chain.salt != salt.as_slice() || chain.iterations != *iterations {
+                continue;
+            }
+            chain.records.push(Nsec3 {
+                hash,
+                next: next_hashed,
+                opt_out: flags & NSEC3_OPT_OUT != 0,
+                types,
+            });
+        }
+        chain.map(Ok)
+    }
+
+    fn hash(&self, name: &str) -> Vec<u8> {
+        nsec3_hash(name, self.salt, self.iterations)
+    }
+
+    fn matching(&self, name: &str) -> Option<&Nsec3<'a>> {
+        let hash = self.hash(name);
+        self.records.iter().find(|rec| rec.hash == hash)
+    }
+
+    fn covering(&self, name: &str) -> Option<&Nsec3<'a>> {
+        let hash = self.hash(name);
+        self.records.iter().find(|rec| rec.covers(&hash))
+    }
+
+    // rfc 5155 section 8, the closest encloser proof in 8.3 and the cases after it
+    fn prove(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        nxdomain: bool,
+        zone: &str,
+    ) -> Result<Denial, Bogus> {
+        if let Some(rec) = self.matching(name) {
+            let delegation = is_delegation(rec.types);
+            if nxdomain || !lacks(rec.types, qtype) || (delegation && qtype != QueryType::DS) {
+                return Err(missing(name, qtype));
+            }
+            return Ok(Denial::NoData { delegation });
+        }
+
+        // the closest encloser is the longest existing ancestor, the next closer name the one
+        // below it on the way to the name
+        let mut next_closer = name.to_string();
+        let mut closest_encloser = None;
+        for count in (labels(zone).len()..labels(name).len()).rev() {
+            let candidate = suffix(name, count);
+            if let Some(rec) = self.matching(&candidate) {
+                closest_encloser = Some((candidate, rec));
+                break;
+            }
+            next_closer = candidate;
+        }
+        let Some((closest_encloser, encloser)) = closest_encloser else {
+            return Err(missing(name, qtype));
+        };
+        if is_delegation(encloser.types) || encloser.types.contains(&QueryType::DNAME) {
+            return Err(missing(name, qtype));
+        }
+        let Some(cover) = self.covering(&next_closer) else {
+            return Err(missing(name, qtype));
+        };
+        // an opt-out span may hide unsigned delegations, so nothing in it is provably absent
+        if cover.opt_out {
+            return Ok(Denial::Insecure);
+        }
+
+        let wildcard = wildcard(&closest_encloser);
+        if nxdomain {
+            return match self.covering(&wildcard) {
+                Some(_) => Ok(Denial::NxDomain),
+                None => Err(missing(name, qtype)),
+            };
+        }
+        match self.matching(&wildcard) {
+            Some(rec) if lacks(rec.types, qtype) => Ok(Denial::NoData { delegation: false }),
+            _ => Err(missing(name, qtype)),
+        }
+    }
+}
+
+/// Whether records of this type only exist for DNSSEC and are left out of answers to
+/// clients that didn't set DO, unless asked for by type.
+pub fn is_dnssec_type(qtype: QueryType) -> bool {
+    matches!(
+        qtype,
+        QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3 | QueryType::DS | QueryType::DNSKEY
+    )
+}
+
+/// Drops DNSSEC records from `packet` for a client that didn't ask for them with DO, keeping
+/// those of the type it asked for. rfc 4035 section 3.2.1
+pub fn strip_dnssec(packet: &mut DnsPacket) {
+    let asked = packet.questions.first().map(|q| q.qtype);
+    let keep = |rec: &DnsRecord| {
+        let qtype = rec.qtype();
+        !is_dnssec_type(qtype) || Some(qtype) == asked
+    };
+    packet.answers.retain(keep);
+    packet.authorities.retain(keep);
+    packet.additional.retain(keep);
+}
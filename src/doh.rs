@@ -0,0 +1,367 @@
+// DNS over HTTPS to upstreams (rfc 8484): each query is POSTed as application/dns-message to
+// the server's url over HTTP/2, which hyper speaks for us, and the response body is the answer.
+// it gets through networks that block port 853 since it looks like any other https. the id is
+// sent as 0 so caches along the way see the same request for the same question, section 4.1. as
+// with DoQ, one connection per upstream carries every query, each on a stream of its own, and
+// when it's gone the next query makes a new one; a query that fails on a connection made
+// earlier is sent again on a fresh one. a server only known by name is looked up with bootstrap
+// resolvers, since we can't ask it for its own address. queries are padded as over TLS, see
+// dot.rs.
+use crate::client::{matches_query, read_response, Client};
+use crate::edns::{PaddingPolicy, QUERY_PADDING_BLOCK};
+use crate::error::{DnsError, Result};
+use crate::metrics::TlsCounters;
+use crate::structure::{BytePacketBuffer, DnsPacket, QueryType, ResultCode, MAX_MESSAGE_SIZE};
+use crate::tls::{self, ServerIdentity, TlsStream, TrustAnchors};
+use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper::{header, Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::convert::Infallible;
+use std::fmt;
+use std::future::poll_fn;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time;
+
+pub const HTTPS_PORT: u16 = 443;
+/// The path queries go to when the url doesn't have one, the one the rfc's examples use.
+pub const DEFAULT_PATH: &str = "/dns-query";
+const MEDIA_TYPE: &str = "application/dns-message";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// a response is a DNS message, nothing bigger is taken
+const MAX_BODY: usize = u16::MAX as usize;
+
+/// Where a DoH server takes queries: `https://<host>[:<port>][<path>]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DohUrl {
+    /// A name or an address, without the brackets around IPv6 ones.
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl DohUrl {
+    /// The host's address, if it's given as one.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+
+    // the :authority of requests, the host and the port if it isn't the default
+    fn authority(&self) -> String {
+        let host = match self.ip() {
+            Some(IpAddr::V6(_)) => format!("[{}]", self.host),
+            _ => self.host.clone(),
+        };
+        match self.port {
+            HTTPS_PORT => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+
+impl FromStr for DohUrl {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || DnsError::Syntax(format!("{} isn't an https url", s));
+        let rest = s.strip_prefix("https://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, DEFAULT_PATH),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(v6) => {
+                let (host, port) = v6.split_once(']').ok_or_else(invalid)?;
+                host.parse::<IpAddr>().map_err(|_| invalid())?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => HTTPS_PORT,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_lowercase(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for DohUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}{}", self.authority(), self.path)
+    }
+}
+
+/// Looks up the addresses of a DoH server's `host` with `resolvers`, IPv4 first, taking
+/// them from the first resolver that answers for each.
+pub async fn bootstrap(
+    client: &Client,
+    resolvers: &[SocketAddr],
+    host: &str,
+) -> Result<Vec<IpAddr>> {
+    let mut addrs = Vec::new();
+    let mut reason = "no resolvers to ask".to_string();
+    for qtype in [QueryType::A, QueryType::AAAA] {
+        for &resolver in resolvers {
+            let mut query = DnsPacket::query(host, qtype).build();
+            match client.query(resolver, &mut query).await {
+                Ok(response) => {
+                    reason = match response.header.rcode {
+                        ResultCode::NOERROR => "no addresses".to_string(),
+                        rcode => format!("{} answered {:?}", resolver, rcode),
+                    };
+                    addrs.extend(
+                        response
+                            .answers
+                            .iter()
+                            .filter_map(|record| record.address()),
+                    );
+                    break;
+                }
+                Err(e) => reason = e.to_string(),
+            }
+        }
+    }
+    if addrs.is_empty() {
+        return Err(DnsError::Bootstrap(host.to_string(), reason));
+    }
+    Ok(addrs)
+}
+
+/// An upstream spoken to over HTTPS, at one of its server's addresses.
+pub struct HttpsUpstream {
+    addr: SocketAddr,
+    url: DohUrl,
+    identity: ServerIdentity,
+    anchors: Arc<TrustAnchors>,
+    counters: Arc<TlsCounters>,
+    config: Arc<rustls::ClientConfig>,
+    timeout: Duration,
+    padding: PaddingPolicy,
+    // held while connecting, so queries arriving meanwhile wait for the same connection
+    connection: tokio::sync::Mutex<Option<SendRequest<Message>>>,
+}
+
+impl HttpsUpstream {
+    /// Queries `url` at `addr`, with the server's certificate checked against the url's
+    /// host. The port comes from the url.
+    pub fn new(url: DohUrl, addr: IpAddr, anchors: Arc<TrustAnchors>) -> Self {
+        let identity = ServerIdentity::new(&url.host);
+        let counters = Arc::default();
+        Self {
+            addr: SocketAddr::new(addr, url.port),
+            config: client_config(&identity, &anchors, &counters),
+            identity,
+            url,
+            anchors,
+            counters,
+            timeout: DEFAULT_TIMEOUT,
+            padding: PaddingPolicy::Block(QUERY_PADDING_BLOCK),
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Only accepts the server if a key in its chain has this hash, on top of the chain
+    /// leading to a trust anchor, see [`ServerIdentity::pin`].
+    pub fn pin(mut self, spki_sha256: [u8; 32]) -> Self {
+        self.identity = self.identity.pin(spki_sha256);
+        self.config = client_config(&self.identity, &self.anchors, &self.counters);
+        self
+    }
+
+    /// How long a query may take, connecting included.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How queries are padded, to blocks of [`QUERY_PADDING_BLOCK`] bytes unless told
+    /// otherwise. Only queries with an OPT record can be, see [`DnsPacket::apply_padding`].
+    pub fn padding(mut self, padding: PaddingPolicy) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Counts the handshakes that failed on its pins.
+    pub fn counters(&self) -> &TlsCounters {
+        &self.counters
+    }
+
+    pub fn url(&self) -> &DohUrl {
+        &self.url
+    }
+
+    /// Sends `query` and waits for the matching response. The query needs exactly one
+    /// question.
+    pub async fn query(&self, query: &DnsPacket) -> Result<DnsPacket> {
+        let [question] = &query.questions[..] else {
+            return Err(DnsError::QuestionCount(query.questions.len()));
+        };
+        let mut zeroed = query.clone();
+        zeroed.header.id = 0;
+        zeroed.apply_padding(self.padding, MAX_MESSAGE_SIZE)?;
+        let mut out = BytePacketBuffer::new();
+        zeroed.write(&mut out)?;
+        let msg = Bytes::copy_from_slice(out.as_slice());
+        let body = match time::timeout(self.timeout, self.exchange(msg)).await {
+            Ok(res) => res?,
+            Err(_) => {
+                // as with DoQ, a server gone quiet is only noticed by timing out, so the next
+                // query starts a new connection
+                self.connection.lock().await.take();
+                return Err(DnsError::Timeout(self.addr));
+            }
+        };
+        let mut buf = BytePacketBuffer::from_bytes(&body)?;
+        let mut response = read_response(&mut buf, body.len())?;
+        if !matches_query(&response, 0, question) {
+            return Err(DnsError::MismatchedResponse(self.addr));
+        }
+        response.header.id = query.header.id;
+        Ok(response)
+    }
+
+    async fn exchange(&self, msg: Bytes) -> Result<Bytes> {
+        let (mut sender, fresh) = self.connection(false).await?;
+        match self.post(&mut sender, msg.clone()).await {
+            Err(_) if !fresh => {
+                let (mut sender, _) = self.connection(true).await?;
+                self.post(&mut sender, msg).await
+            }
+            res => res,
+        }
+    }
+
+    // the open connection, or a new one, and whether it's new. a `stale` one is replaced
+    async fn connection(&self, stale: bool) -> Result<(SendRequest<Message>, bool)> {
+        let mut current = self.connection.lock().await;
+        if let Some(sender) = current.as_ref() {
+            if !stale && !sender.is_closed() {
+                return Ok((sender.clone(), false));
+            }
+        }
+        let stream = TlsStream::connect(self.addr, &self.identity, self.config.clone()).await?;
+        if stream.alpn() != Some(b"h2") {
+            return Err(DnsError::Http(format!("{} doesn't speak HTTP/2", self.url)));
+        }
+        let (sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(http_error)?;
+        // runs the connection until the server closes it or every sender is dropped
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        *current = Some(sender.clone());
+        Ok((sender, true))
+    }
+
+    // POSTs `msg` and returns the body of the response
+    async fn post(&self, sender: &mut SendRequest<Message>, msg: Bytes) -> Result<Bytes> {
+        let request = Request::post(self.url.to_string())
+            .header(header::ACCEPT, MEDIA_TYPE)
+            .header(header::CONTENT_TYPE, MEDIA_TYPE)
+            .body(Message::new(msg))
+            .map_err(http_error)?;
+        sender.ready().await.map_err(http_error)?;
+        let response = sender.send_request(request).await.map_err(http_error)?;
+        if response.status() != StatusCode::OK {
+            return Err(DnsError::Http(format!(
+                "{} answered {}",
+                self.url,
+                response.status().as_u16()
+            )));
+        }
+        if response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|media_type| media_type != MEDIA_TYPE)
+        {
+            return Err(DnsError::Http(format!(
+                "{} answered with something else",
+                self.url
+            )));
+        }
+        read_body(response.into_body(), MAX_BODY)
+            .await?
+            .ok_or_else(|| DnsError::Http(format!("response from {} too big", self.url)))
+    }
+}
+
+// rebuilt when a pin is added, since the configuration holds the identity
+fn client_config(
+    identity: &ServerIdentity,
+    anchors: &Arc<TrustAnchors>,
+    counters: &Arc<TlsCounters>,
+) -> Arc<rustls::ClientConfig> {
+    let config = tls::client_config(identity, anchors.clone(), counters.clone(), &[b"h2"]);
+    Arc::new(config)
+}
+
+/// A body that's one DNS message, or nothing, sent in a single frame.
+pub(crate) struct Message(Option<Bytes>);
+
+impl Message {
+    pub(crate) fn new(msg: Bytes) -> Self {
+        Self(Some(msg).filter(|msg| !msg.is_empty()))
+    }
+
+    pub(crate) fn empty() -> Self {
+        Self(None)
+    }
+}
+
+impl Body for Message {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, Infallible>>> {
+        Poll::Ready(self.0.take().map(|msg| Ok(Frame::data(msg))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.0.as_ref().map_or(0, |msg| msg.len() as u64))
+    }
+}
+
+/// Reads all of `body`, or None once it's longer than `limit`. Trailers are skipped.
+pub(crate) async fn read_body(mut body: Incoming, limit: usize) -> Result<Option<Bytes>> {
+    let mut data = Vec::new();
+    while let Some(frame) = poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+        if let Ok(chunk) = frame.map_err(http_error)?.into_data() {
+            if data.len() + chunk.len() > limit {
+                return Ok(None);
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+    Ok(Some(data.into()))
+}
+
+pub(crate) fn http_error(e: impl fmt::Display) -> DnsError {
+    DnsError::Http(e.to_string())
+}
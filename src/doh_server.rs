@@ -0,0 +1,502 @@
+// DNS over HTTPS for clients (rfc 8484), so browsers and stub resolvers can point straight at
+// the server. connections come in over TLS, see tls.rs, and hyper speaks HTTP/2 on them when
+// the client offers it, HTTP/1.1 otherwise. a query is either POSTed as application/dns-message
+// or sent in a GET's `dns` parameter as base64url without padding (section 4.1), and only to
+// the one path the listener is given; anything else gets the HTTP error that fits. queries are
+// answered by the same handler as over udp and tcp, with the same signature checks, see
+// server.rs, and a response may be cached for as long as the smallest TTL of its answers, or a
+// negative answer's SOA, says (section 5.1). each HTTP/2 stream is answered on a task of its
+// own, so a slow query doesn't hold up the ones behind it. a connection with no request open
+// for the idle timeout is shut down gracefully. responses to queries with an OPT record are
+// padded to blocks of 468 bytes, as rfc 8467 recommends.
+use crate::doh::{http_error, read_body, Message, DEFAULT_PATH};
+use crate::edns::{PaddingPolicy, RESPONSE_PADDING_BLOCK};
+use crate::error::Result;
+use crate::limits::is_fd_exhaustion;
+use crate::logging;
+use crate::metrics::AnomalyCounters;
+use crate::net::check_scope;
+use crate::server::{answer_message, Drain, Handler, DEFAULT_MAX_CONNECTIONS};
+use crate::structure::{DnsPacket, DnsRecord, MAX_MESSAGE_SIZE};
+use crate::tls::{ServerCertificate, TlsStream};
+use crate::tsig::Keyring;
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+const MEDIA_TYPE: &str = "application/dns-message";
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// browsers keep connections to a resolver open for a while, the handshake costs a signature
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// pause between accepts when out of descriptors, as in server.rs
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// streams a client may have open on a connection at once
+const MAX_STREAMS: u32 = 100;
+// a header block or HTTP/1.1 head bigger than this isn't a DNS client's
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Serves queries over HTTPS.
+pub struct HttpsServer<H> {
+    listener: TcpListener,
+    service: Arc<Service<H>>,
+    config: Arc<rustls::ServerConfig>,
+    idle_timeout: Duration,
+    connections: Arc<Semaphore>,
+}
+
+impl<H: Handler> HttpsServer<H> {
+    /// Binds the listening socket. Has to be called from within a tokio runtime.
+    pub async fn bind(
+        addr: SocketAddr,
+        certificate: Arc<ServerCertificate>,
+        handler: H,
+    ) -> Result<Self> {
+        Self::bind_shared(addr, certificate, Arc::new(handler)).await
+    }
+
+    /// Like [`HttpsServer::bind`], for a handler that is also used by other listeners.
+    pub async fn bind_shared(
+        addr: SocketAddr,
+        certificate: Arc<ServerCertificate>,
+        handler: Arc<H>,
+    ) -> Result<Self> {
+        check_scope(&addr)?;
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self::new(listener, certificate, handler))
+    }
+
+    /// Accepts on a listener that's bound already, like one systemd passed in, see
+    /// [`crate::systemd`]. Has to be called from within a tokio runtime.
+    pub fn from_std(
+        listener: std::net::TcpListener,
+        certificate: Arc<ServerCertificate>,
+        handler: Arc<H>,
+    ) -> Result<Self> {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        Ok(Self::new(listener, certificate, handler))
+    }
+
+    fn new(listener: TcpListener, certificate: Arc<ServerCertificate>, handler: Arc<H>) -> Self {
+        Self {
+            listener,
+            service: Arc::new(Service {
+                handler,
+                keys: Keyring::new(),
+                counters: AnomalyCounters::default(),
+                path: DEFAULT_PATH.to_string(),
+                timeout: DEFAULT_QUERY_TIMEOUT,
+                padding: PaddingPolicy::Block(RESPONSE_PADDING_BLOCK),
+                drain: Arc::default(),
+            }),
+            config: Arc::new(certificate.server_config(&[b"h2", b"http/1.1"])),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            connections: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+        }
+    }
+
+    /// The path queries are taken on, [`DEFAULT_PATH`](crate::doh::DEFAULT_PATH) if not given.
+    pub fn path(mut self, path: &str) -> Self {
+        self.service_mut().path = path.to_string();
+        self
+    }
+
+    /// How long a handler gets before its query is answered with SERVFAIL.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.service_mut().timeout = timeout;
+        self
+    }
+
+    /// How responses are padded, to blocks of [`RESPONSE_PADDING_BLOCK`] bytes unless told
+    /// otherwise. Only responses with an OPT record can be, see [`DnsPacket::apply_padding`].
+    pub fn padding(mut self, padding: PaddingPolicy) -> Self {
+        self.service_mut().padding = padding;
+        self
+    }
+
+    /// How long a connection may sit without a request, or take over its handshake, before
+    /// it's closed.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Caps the number of open connections, see [`crate::server::TcpServer::max_connections`].
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.connections = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// The keys signed requests are checked against, see [`crate::server::UdpServer::keys`].
+    pub fn keys(mut self, keys: Keyring) -> Self {
+        self.service_mut().keys = keys;
+        self
+    }
+
+    /// Counts the queries being answered in `drain`, see [`Drain`].
+    pub fn drain(mut self, drain: Arc<Drain>) -> Self {
+        self.service_mut().drain = drain;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn counters(&self) -> &AnomalyCounters {
+        &self.service.counters
+    }
+
+    // the builders run before any connection holds on to the service
+    fn service_mut(&mut self) -> &mut Service<H> {
+        Arc::get_mut(&mut self.service).expect("not serving yet")
+    }
+
+    /// Accepts connections until accepting fails, as [`crate::server::TcpServer::run`] does.
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let permit = self
+                .connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let (tcp, src) = match self.listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) if is_fd_exhaustion(&e) => {
+                    logging::error(&format!("out of file descriptors, pausing accepts: {}", e));
+                    time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let service = self.service.clone();
+            let config = self.config.clone();
+            let idle_timeout = self.idle_timeout;
+            tokio::spawn(async move {
+                let res = match time::timeout(idle_timeout, TlsStream::accept(tcp, config)).await {
+                    Ok(Ok(stream)) => serve(stream, service, src, idle_timeout).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Ok(()),
+                };
+                if let Err(e) = res {
+                    logging::warning(&format!("https connection from {} failed: {}", src, e));
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+// serves the requests on a connection until the client closes it or leaves it idle
+async fn serve<H: Handler>(
+    stream: TlsStream,
+    service: Arc<Service<H>>,
+    src: SocketAddr,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let activity = Arc::new(Activity::default());
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(idle_timeout)
+        .max_buf_size(MAX_HEAD);
+    builder
+        .http2()
+        .max_concurrent_streams(MAX_STREAMS)
+        .max_header_list_size(MAX_HEAD as u32);
+    let requests = activity.clone();
+    let conn = builder.serve_connection(
+        TokioIo::new(stream),
+        service_fn(move |request| {
+            let service = service.clone();
+            let activity = requests.clone();
+            async move {
+                let _open = activity.enter();
+                Ok::<_, Infallible>(service.respond(request, src).await)
+            }
+        }),
+    );
+    let mut conn = pin!(conn);
+    loop {
+        let deadline = activity.idle_until(idle_timeout);
+        tokio::select! {
+            res = conn.as_mut() => return res.map_err(http_error),
+            _ = time::sleep_until(deadline.into()) => {
+                if activity.idle_until(idle_timeout) <= Instant::now() {
+                    break;
+                }
+            }
+        }
+    }
+    // lets the requests that came in meanwhile finish, GOAWAY on HTTP/2
+    conn.as_mut().graceful_shutdown();
+    conn.await.map_err(http_error)
+}
+
+// the requests open on a connection, and when the last one finished
+struct Activity(Mutex<(usize, Instant)>);
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self(Mutex::new((0, Instant::now())))
+    }
+}
+
+impl Activity {
+    fn enter(&self) -> Open<'_> {
+        self.0.lock().unwrap().0 += 1;
+        Open(self)
+    }
+
+    // when the connection will have been idle for `timeout`, if nothing comes in meanwhile
+    fn idle_until(&self, timeout: Duration) -> Instant {
+        match *self.0.lock().unwrap() {
+            (0, since) => since + timeout,
+            _ => Instant::now() + timeout,
+        }
+    }
+}
+
+struct Open<'a>(&'a Activity);
+
+impl Drop for Open<'_> {
+    fn drop(&mut self) {
+        let mut activity = self.0 .0.lock().unwrap();
+        activity.0 -= 1;
+        activity.1 = Instant::now();
+    }
+}
+
+// a response without a body
+fn status(status: StatusCode) -> Response<Message> {
+    let mut response = Response::new(Message::empty());
+    *response.status_mut() = status;
+    response
+}
+
+// what answering requests takes, shared by a listener's connections
+struct Service<H> {
+    handler: Arc<H>,
+    keys: Keyring,
+    counters: AnomalyCounters,
+    path: String,
+    timeout: Duration,
+    padding: PaddingPolicy,
+    drain: Arc<Drain>,
+}
+
+impl<H: Handler> Service<H> {
+    async fn respond(&self, request: Request<Incoming>, src: SocketAddr) -> Response<Message> {
+        if request.uri().path() != self.path {
+            return status(StatusCode::NOT_FOUND);
+        }
+        let msg = match *request.method() {
+            Method::GET => {
+                let param = request
+                    .uri()
+                    .query()
+                    .unwrap_or("")
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("dns="));
+                match param.and_then(parse_base64url) {
+                    Some(msg) => msg,
+                    None => return status(StatusCode::BAD_REQUEST),
+                }
+            }
+            Method::POST => {
+                let content_type = request.headers().get(header::CONTENT_TYPE);
+                if !is_dns_message(content_type.and_then(|value| value.to_str().ok())) {
+                    return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                }
+                match read_body(request.into_body(), MAX_MESSAGE_SIZE).await {
+                    Ok(Some(body)) => body.to_vec(),
+                    Ok(None) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+                    Err(_) => return status(StatusCode::BAD_REQUEST),
+                }
+            }
+            _ => {
+                let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+                let allow = header::HeaderValue::from_static("GET, POST");
+                response.headers_mut().insert(header::ALLOW, allow);
+                return response;
+            }
+        };
+        if msg.len() > MAX_MESSAGE_SIZE {
+            return status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        if !self.counters.accept_query(&msg) {
+            return status(StatusCode::BAD_REQUEST);
+        }
+
+        let _query = self.drain.enter();
+        let answer = answer_message(
+            &*self.handler,
+            &self.keys,
+            self.timeout,
+            self.padding,
+            &msg,
+            src,
+        );
+        match answer.await {
+            Ok(Some((response, body))) => {
+                let mut builder = Response::builder().header(header::CONTENT_TYPE, MEDIA_TYPE);
+                if let Some(max_age) = max_age(&response) {
+                    builder = builder.header(header::CACHE_CONTROL, format!("max-age={}", max_age));
+                }
+                builder
+                    .body(Message::new(Bytes::from(body)))
+                    .expect("the headers are valid")
+            }
+            // the handler would rather not answer this client at all
+            Ok(None) => status(StatusCode::FORBIDDEN),
+            Err(e) => {
+                logging::warning(&format!("failed to answer query from {}: {}", src, e));
+                status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+// the media type without any parameters
+fn is_dns_message(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or("");
+        media_type.trim().eq_ignore_ascii_case(MEDIA_TYPE)
+    })
+}
+
+// base64url (rfc 4648 section 5) without the padding, as section 4.1 wants it
+fn parse_base64url(s: &str) -> Option<Vec<u8>> {
+    let mut base64: String = s
+        .chars()
+        .map(|c| match c {
+            '-' => Some('+'),
+            '_' => Some('/'),
+            c if c.is_ascii_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    while !base64.len().is_multiple_of(4) {
+        base64.push('=');
+    }
+    crate::presentation::parse_base64(&base64).ok()
+}
+
+// how long a response may be cached: the smallest TTL among its answers, or for a negative
+// answer the time its SOA allows (rfc 2308 section 5). None for responses without either
+fn max_age(response: &DnsPacket) -> Option<u32> {
+    if !response.answers.is_empty() {
+        return response.answers.iter().map(DnsRecord::ttl).min();
+    }
+    response.authorities.iter().find_map(|rec| match *rec {
+        DnsRecord::SOA { ttl, minimum, .. } => Some(ttl.min(minimum)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doh::{DohUrl, HttpsUpstream};
+    use crate::edns::QUERY_PADDING_BLOCK;
+    use crate::structure::QueryType;
+    use crate::tls::tests::{leaf_certificate, ROOT};
+    use crate::tls::TrustAnchors;
+
+    fn upstream_at(addr: SocketAddr, path: &str) -> HttpsUpstream {
+        let url = format!("https://dns.example:{}{}", addr.port(), path);
+        let anchors = Arc::new(TrustAnchors::from_pem(ROOT.as_bytes()));
+        HttpsUpstream::new(url.parse::<DohUrl>().unwrap(), addr.ip(), anchors)
+    }
+
+    #[tokio::test]
+    async fn answers_queries_posted_to_its_path() {
+        let handler = |request: DnsPacket, _| async move {
+            let mut response = request.clone();
+            response.header.flags.response = true;
+            response.answers.push(DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                class: 1,
+                ttl: 300,
+                ip: 0xc0000201,
+            });
+            Some(response)
+        };
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = HttpsServer::bind(addr, Arc::new(leaf_certificate()), handler)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let query = DnsPacket::query("example.com", QueryType::A).build();
+        let upstream = upstream_at(addr, DEFAULT_PATH);
+        for _ in 0..2 {
+            let response = upstream.query(&query).await.unwrap();
+            assert_eq!(response.header.id, query.header.id);
+            assert_eq!(response.answers.len(), 1);
+        }
+
+        let err = upstream_at(addr, "/elsewhere")
+            .query(&query)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn pads_queries_and_responses_to_their_blocks() {
+        // drops queries that didn't come padded, which the upstream sees as a 403
+        let handler = |mut request: DnsPacket, _| async move {
+            if !request
+                .wire_len()
+                .unwrap()
+                .is_multiple_of(QUERY_PADDING_BLOCK as usize)
+            {
+                return None;
+            }
+            let mut response = request.clone();
+            response.header.flags.response = true;
+            response.answers.push(DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                class: 1,
+                ttl: 300,
+                ip: 0xc0000201,
+            });
+            Some(response)
+        };
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = HttpsServer::bind(addr, Arc::new(leaf_certificate()), handler)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let query = DnsPacket::query("example.com", QueryType::A).build();
+        let mut response = upstream_at(addr, DEFAULT_PATH).query(&query).await.unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert!(response
+            .wire_len()
+            .unwrap()
+            .is_multiple_of(RESPONSE_PADDING_BLOCK as usize));
+
+        // nothing to put the padding in without an OPT record
+        let query = DnsPacket::query("example.com", QueryType::A)
+            .edns(None)
+            .build();
+        assert!(upstream_at(addr, DEFAULT_PATH).query(&query).await.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+// DNS over QUIC to upstreams (rfc 9250): each query goes on a QUIC stream of its own, over a
+// quinn connection, framed as over tcp with two bytes of length in front, and the server sends
+// the response back on the stream and ends it. the id is sent as 0, section 4.2.1. one
+// connection per upstream carries every query, as many at once as the server's stream limit
+// allows, and when it's gone the next query makes a new one; a query that fails on a connection
+// made earlier is sent again on a fresh one, as with DoT. the sessions rustls keeps resume the
+// next connection, and when the server allows early data the queries go out in 0-RTT without
+// waiting for the handshake, which section 4.5 allows for queries though not zone transfers,
+// and we only ever forward queries; ones the server turns down are sent again once the
+// handshake is done. the server is authenticated as over TLS, see tls::client_config. queries
+// are padded as over TLS, see dot.rs.
+use crate::client::{matches_query, read_response};
+use crate::edns::{PaddingPolicy, QUERY_PADDING_BLOCK};
+use crate::error::{DnsError, Result};
+use crate::metrics::TlsCounters;
+use crate::structure::{BytePacketBuffer, DnsPacket, MAX_MESSAGE_SIZE};
+use crate::tls::{self, ServerIdentity, TrustAnchors};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Connection, Endpoint, ReadError, ReadToEndError, VarInt, WriteError};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+pub const DOQ_PORT: u16 = 853;
+pub(crate) const ALPN: &[u8] = b"doq";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// error codes, section 4.3
+const DOQ_NO_ERROR: u32 = 0x0;
+
+/// An upstream spoken to over QUIC.
+pub struct QuicUpstream {
+    addr: SocketAddr,
+    identity: ServerIdentity,
+    counters: Arc<TlsCounters>,
+    // one for every connection, so they share the sessions rustls keeps
+    config: ClientConfig,
+    timeout: Duration,
+    padding: PaddingPolicy,
+    // held while connecting, so queries arriving meanwhile wait for the same connection
+    connection: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl QuicUpstream {
+    pub fn new(addr: SocketAddr, identity: ServerIdentity, anchors: Arc<TrustAnchors>) -> Self {
+        let counters = Arc::default();
+        let crypto = tls::client_config(&identity, anchors, Arc::clone(&counters), &[ALPN]);
+        let crypto =
+            QuicClientConfig::try_from(crypto).expect("the provider has QUIC's initial suite");
+        Self {
+            addr,
+            identity,
+            counters,
+            config: ClientConfig::new(Arc::new(crypto)),
+            timeout: DEFAULT_TIMEOUT,
+            padding: PaddingPolicy::Block(QUERY_PADDING_BLOCK),
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// How long a query may take, connecting included.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How queries are padded, to blocks of [`QUERY_PADDING_BLOCK`] bytes unless told
+    /// otherwise. Only queries with an OPT record can be, see [`DnsPacket::apply_padding`].
+    pub fn padding(mut self, padding: PaddingPolicy) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn identity(&self) -> &ServerIdentity {
+        &self.identity
+    }
+
+    /// Counts the handshakes that failed on its pins.
+    pub fn counters(&self) -> &TlsCounters {
+        &self.counters
+    }
+
+    /// Sends `query` and waits for the matching response. The query needs exactly one
+    /// question.
+    pub async fn query(&self, query: &DnsPacket) -> Result<DnsPacket> {
+        let [question] = &query.questions[..] else {
+            return Err(DnsError::QuestionCount(query.questions.len()));
+        };
+        let mut zeroed = query.clone();
+        zeroed.header.id = 0;
+        zeroed.apply_padding(self.padding, MAX_MESSAGE_SIZE)?;
+        let mut out = BytePacketBuffer::new();
+        zeroed.write(&mut out)?;
+        let msg = out.as_slice();
+        let mut framed = Vec::with_capacity(msg.len() + 2);
+        framed.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        framed.extend_from_slice(msg);
+        let framed = match time::timeout(self.timeout, self.exchange(framed)).await {
+            Ok(res) => res?,
+            Err(_) => {
+                // the server may have gone away without a word, which the connection would
+                // only notice once it times out, so the next query starts a new one
+                if let Some(connection) = self.connection.lock().await.take() {
+                    connection.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+                }
+                return Err(DnsError::Timeout(self.addr));
+            }
+        };
+
+        let len = match framed.get(..2) {
+            Some(prefix) => u16::from_be_bytes([prefix[0], prefix[1]]) as usize,
+            None => return Err(DnsError::MismatchedResponse(self.addr)),
+        };
+        if framed.len() != len + 2 {
+            return Err(DnsError::MismatchedResponse(self.addr));
+        }
+        let mut buf = BytePacketBuffer::from_bytes(&framed[2..])?;
+        let mut response = read_response(&mut buf, len)?;
+        if !matches_query(&response, 0, question) {
+            return Err(DnsError::MismatchedResponse(self.addr));
+        }
+        response.header.id = query.header.id;
+        Ok(response)
+    }
+
+    async fn exchange(&self, framed: Vec<u8>) -> Result<Vec<u8>> {
+        let (connection, fresh) = self.connection(false).await?;
+        match request(&connection, &framed).await {
+            Err(_) if !fresh => {
+                connection.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+                let (connection, _) = self.connection(true).await?;
+                request(&connection, &framed).await
+            }
+            res => res,
+        }
+    }
+
+    // the open connection, or a new one, and whether it's new. a `stale` one is replaced
+    async fn connection(&self, stale: bool) -> Result<(Connection, bool)> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref() {
+            if !stale && connection.close_reason().is_none() {
+                return Ok((connection.clone(), false));
+            }
+        }
+        let local = match self.addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let endpoint = Endpoint::client(local)?;
+        let connecting = endpoint
+            .connect_with(self.config.clone(), self.addr, self.identity.name())
+            .map_err(quic_error)?;
+        let connection = match connecting.into_0rtt() {
+            Ok((connection, _)) => connection,
+            Err(connecting) => connecting.await.map_err(quic_error)?,
+        };
+        *current = Some(connection.clone());
+        Ok((connection, true))
+    }
+}
+
+// sends `framed` on a stream of its own and reads what comes back, again on a new stream if it
+// went out in early data the server turned down
+async fn request(connection: &Connection, framed: &[u8]) -> Result<Vec<u8>> {
+    loop {
+        let (mut send, mut recv) = connection.open_bi().await.map_err(quic_error)?;
+        let sent = send.write_all(framed).await;
+        if matches!(sent, Err(WriteError::ZeroRttRejected)) {
+            continue;
+        }
+        sent.map_err(quic_error)?;
+        send.finish().map_err(quic_error)?;
+        let received = recv.read_to_end(MAX_MESSAGE_SIZE + 2).await;
+        if matches!(
+            received,
+            Err(ReadToEndError::Read(ReadError::ZeroRttRejected))
+        ) {
+            continue;
+        }
+        return received.map_err(quic_error);
+    }
+}
+
+pub(crate) fn quic_error(e: impl fmt::Display) -> DnsError {
+    DnsError::Quic(e.to_string())
+}
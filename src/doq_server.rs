@@ -0,0 +1,313 @@
+// DNS over QUIC for clients (rfc 9250), what doq.rs is to upstreams. quinn runs the
+// connections, which carry a client's queries one to a stream, each framed as over tcp with two
+// bytes of length in front, and the response goes back on the query's stream, which then ends.
+// queries are answered by the same handler as over udp, tcp and https, with the same signature
+// checks, see server.rs, each on a task of its own so a slow one doesn't hold up the others,
+// and the certificate is one the HTTPS listener can share. a query has to have an id of 0
+// (section 4.2.1) and a stream has to hold the one message, anything else closes the connection
+// with DOQ_PROTOCOL_ERROR. a query the handler would rather not answer has its stream reset
+// with DOQ_REQUEST_CANCELLED, one it fails on with DOQ_INTERNAL_ERROR (section 4.3). we take no
+// early data, so queries can't be replayed. responses are padded as over https, see
+// doh_server.rs.
+use crate::doq::{quic_error, ALPN};
+use crate::edns::{PaddingPolicy, RESPONSE_PADDING_BLOCK};
+use crate::error::Result;
+use crate::logging;
+use crate::metrics::AnomalyCounters;
+use crate::net::check_scope;
+use crate::server::{answer_message, Drain, Handler, DEFAULT_MAX_CONNECTIONS};
+use crate::structure::MAX_MESSAGE_SIZE;
+use crate::tls::ServerCertificate;
+use crate::tsig::Keyring;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{
+    Connection, Endpoint, EndpointConfig, Incoming, ReadToEndError, RecvStream, SendStream, VarInt,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// error codes, section 4.3
+const DOQ_INTERNAL_ERROR: u32 = 0x1;
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+const DOQ_REQUEST_CANCELLED: u32 = 0x3;
+
+/// Serves queries over QUIC.
+pub struct QuicServer<H> {
+    endpoint: Endpoint,
+    handler: Arc<H>,
+    timeout: Duration,
+    padding: PaddingPolicy,
+    connections: Arc<Semaphore>,
+    counters: Arc<AnomalyCounters>,
+    keys: Arc<Keyring>,
+    drain: Arc<Drain>,
+}
+
+impl<H: Handler> QuicServer<H> {
+    /// Binds the listening socket. Has to be called from within a tokio runtime.
+    pub async fn bind(
+        addr: SocketAddr,
+        certificate: Arc<ServerCertificate>,
+        handler: H,
+    ) -> Result<Self> {
+        Self::bind_shared(addr, certificate, Arc::new(handler)).await
+    }
+
+    /// Like [`QuicServer::bind`], for a handler that is also used by other listeners.
+    pub async fn bind_shared(
+        addr: SocketAddr,
+        certificate: Arc<ServerCertificate>,
+        handler: Arc<H>,
+    ) -> Result<Self> {
+        check_scope(&addr)?;
+        let endpoint = Endpoint::server(server_config(&certificate), addr)?;
+        Ok(Self::new(endpoint, handler))
+    }
+
+    /// Serves on a socket that's bound already, like one systemd passed in, see
+    /// [`crate::systemd`]. Has to be called from within a tokio runtime.
+    pub fn from_std(
+        socket: std::net::UdpSocket,
+        certificate: Arc<ServerCertificate>,
+        handler: Arc<H>,
+    ) -> Result<Self> {
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config(&certificate)),
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        Ok(Self::new(endpoint, handler))
+    }
+
+    fn new(endpoint: Endpoint, handler: Arc<H>) -> Self {
+        Self {
+            endpoint,
+            handler,
+            timeout: DEFAULT_QUERY_TIMEOUT,
+            padding: PaddingPolicy::Block(RESPONSE_PADDING_BLOCK),
+            connections: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+            counters: Arc::new(AnomalyCounters::default()),
+            keys: Arc::new(Keyring::new()),
+            drain: Arc::default(),
+        }
+    }
+
+    /// How long a handler gets before its query is answered with SERVFAIL.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How responses are padded, to blocks of [`RESPONSE_PADDING_BLOCK`] bytes unless told
+    /// otherwise. Only responses with an OPT record can be, see
+    /// [`DnsPacket::apply_padding`](crate::structure::DnsPacket::apply_padding).
+    pub fn padding(mut self, padding: PaddingPolicy) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Caps the number of open connections, new ones past it are refused.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.connections = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// The keys signed requests are checked against, see [`crate::server::UdpServer::keys`].
+    pub fn keys(mut self, keys: Keyring) -> Self {
+        self.keys = Arc::new(keys);
+        self
+    }
+
+    /// Counts the queries being answered in `drain`, see [`Drain`].
+    pub fn drain(mut self, drain: Arc<Drain>) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    pub fn counters(&self) -> &AnomalyCounters {
+        &self.counters
+    }
+
+    /// Answers queries until the endpoint is closed. Errors on a single connection only close
+    /// that connection.
+    pub async fn run(&self) -> Result<()> {
+        while let Some(incoming) = self.endpoint.accept().await {
+            let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                incoming.refuse();
+                continue;
+            };
+            let server = Connections {
+                handler: self.handler.clone(),
+                keys: self.keys.clone(),
+                counters: self.counters.clone(),
+                timeout: self.timeout,
+                padding: self.padding,
+                drain: self.drain.clone(),
+            };
+            tokio::spawn(async move {
+                let src = incoming.remote_address();
+                if let Err(e) = server.serve(incoming).await {
+                    logging::warning(&format!("quic connection from {} failed: {}", src, e));
+                }
+                drop(permit);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn server_config(certificate: &ServerCertificate) -> quinn::ServerConfig {
+    let crypto = QuicServerConfig::try_from(certificate.server_config(&[ALPN]))
+        .expect("the provider has QUIC's initial suite");
+    quinn::ServerConfig::with_crypto(Arc::new(crypto))
+}
+
+// what answering the queries on a connection takes
+struct Connections<H> {
+    handler: Arc<H>,
+    keys: Arc<Keyring>,
+    counters: Arc<AnomalyCounters>,
+    timeout: Duration,
+    padding: PaddingPolicy,
+    drain: Arc<Drain>,
+}
+
+impl<H: Handler> Connections<H> {
+    // answers the queries on the connection until the client closes it, each on a task of its own
+    async fn serve(self, incoming: Incoming) -> Result<()> {
+        let connection = incoming.await.map_err(quic_error)?;
+        let this = Arc::new(self);
+        loop {
+            let (send, recv) = match connection.accept_bi().await {
+                Ok(streams) => streams,
+                Err(quinn::ConnectionError::ApplicationClosed(_))
+                | Err(quinn::ConnectionError::LocallyClosed)
+                | Err(quinn::ConnectionError::TimedOut) => return Ok(()),
+                Err(e) => return Err(quic_error(e)),
+            };
+            let this = this.clone();
+            let connection = connection.clone();
+            let query = this.drain.enter();
+            tokio::spawn(async move {
+                this.answer(&connection, send, recv).await;
+                drop(query);
+            });
+        }
+    }
+
+    async fn answer(&self, connection: &Connection, mut send: SendStream, mut recv: RecvStream) {
+        let close = |code| connection.close(VarInt::from_u32(code), b"");
+        let data = match recv.read_to_end(MAX_MESSAGE_SIZE + 2).await {
+            Ok(data) => data,
+            Err(ReadToEndError::TooLong) => return close(DOQ_PROTOCOL_ERROR),
+            // the client gave up on it, or the connection's gone
+            Err(_) => return,
+        };
+        let msg = match data.get(..2) {
+            Some(prefix)
+                if u16::from_be_bytes([prefix[0], prefix[1]]) as usize == data.len() - 2 =>
+            {
+                &data[2..]
+            }
+            _ => return close(DOQ_PROTOCOL_ERROR),
+        };
+        if !self.counters.accept_query(msg) {
+            let _ = send.reset(VarInt::from_u32(DOQ_PROTOCOL_ERROR));
+            return;
+        }
+        if msg[..2] != [0, 0] {
+            return close(DOQ_PROTOCOL_ERROR);
+        }
+
+        let src = connection.remote_address();
+        let answer = answer_message(
+            &*self.handler,
+            &self.keys,
+            self.timeout,
+            self.padding,
+            msg,
+            src,
+        );
+        let code = match answer.await {
+            Ok(Some((_, body))) => {
+                let mut framed = Vec::with_capacity(body.len() + 2);
+                framed.extend_from_slice(&(body.len() as u16).to_be_bytes());
+                framed.extend_from_slice(&body);
+                if send.write_all(&framed).await.is_ok() {
+                    let _ = send.finish();
+                }
+                return;
+            }
+            // the handler would rather not answer this client at all
+            Ok(None) => DOQ_REQUEST_CANCELLED,
+            Err(e) => {
+                logging::warning(&format!("failed to answer query from {}: {}", src, e));
+                DOQ_INTERNAL_ERROR
+            }
+        };
+        let _ = send.reset(VarInt::from_u32(code));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doq::QuicUpstream;
+    use crate::structure::{DnsPacket, DnsRecord, QueryType};
+    use crate::tls::tests::{leaf_certificate, ROOT};
+    use crate::tls::{ServerIdentity, TrustAnchors};
+    use std::sync::atomic::Ordering;
+
+    fn anchors() -> Arc<TrustAnchors> {
+        Arc::new(TrustAnchors::from_pem(ROOT.as_bytes()))
+    }
+
+    #[tokio::test]
+    async fn answers_upstreams_that_trust_its_certificate() {
+        let certificate = leaf_certificate();
+        let handler = |request: DnsPacket, _| async move {
+            let mut response = request.clone();
+            response.header.flags.response = true;
+            response.answers.push(DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                class: 1,
+                ttl: 300,
+                ip: 0xc0000201,
+            });
+            Some(response)
+        };
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = QuicServer::bind(addr, Arc::new(certificate), handler)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let query = DnsPacket::query("example.com", QueryType::A).build();
+        let upstream = QuicUpstream::new(addr, ServerIdentity::new("dns.example"), anchors());
+        for _ in 0..2 {
+            let response = upstream.query(&query).await.unwrap();
+            assert_eq!(response.header.id, query.header.id);
+            assert_eq!(response.answers.len(), 1);
+        }
+
+        // a chain that leads to the anchor but has none of the pinned keys
+        let pinned = ServerIdentity::new("dns.example").pin([0; 32]);
+        let upstream = QuicUpstream::new(addr, pinned, anchors());
+        assert!(upstream.query(&query).await.is_err());
+        assert_eq!(
+            upstream.counters().pin_mismatches.load(Ordering::Relaxed),
+            1
+        );
+        let upstream = QuicUpstream::new(addr, ServerIdentity::new("other.example"), anchors());
+        assert!(upstream.query(&query).await.is_err());
+    }
+}
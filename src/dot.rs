@@ -0,0 +1,149 @@
+// DNS over TLS to upstreams (rfc 7858): queries go over a TLS connection to port 853 framed as
+// over tcp, two bytes of length then the message. connections are kept open and reused, up to a
+// few idle ones per upstream, since the handshake costs a round trip or two and some
+// signatures. the server may close an idle connection whenever it likes (rfc 7766 section
+// 6.2.3), which we only find out when the next query on it fails, so a query that fails on a
+// reused connection is sent again on a fresh one. the server is authenticated by its
+// certificate leading to a trust anchor and being for the name it's configured with, and if it
+// has pins by a pinned key in that chain too, see tls.rs. queries are padded to blocks of 128
+// bytes, as rfc 8467 recommends, so their length says less about what's asked.
+use crate::client::{matches_query, read_response};
+use crate::edns::{PaddingPolicy, QUERY_PADDING_BLOCK};
+use crate::error::{DnsError, Result};
+use crate::metrics::TlsCounters;
+use crate::structure::{BytePacketBuffer, DnsPacket, MAX_MESSAGE_SIZE};
+use crate::tls::{self, ServerIdentity, TlsStream, TrustAnchors};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+pub const DOT_PORT: u16 = 853;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// idle connections kept per upstream
+const MAX_IDLE: usize = 4;
+// servers close idle connections after some seconds, one older than this most likely is
+const IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// An upstream spoken to over TLS.
+pub struct TlsUpstream {
+    addr: SocketAddr,
+    identity: ServerIdentity,
+    counters: Arc<TlsCounters>,
+    config: Arc<rustls::ClientConfig>,
+    timeout: Duration,
+    padding: PaddingPolicy,
+    idle: Mutex<Vec<(TlsStream, Instant)>>,
+}
+
+impl TlsUpstream {
+    pub fn new(addr: SocketAddr, identity: ServerIdentity, anchors: Arc<TrustAnchors>) -> Self {
+        let counters = Arc::default();
+        let config = tls::client_config(&identity, anchors, Arc::clone(&counters), &[]);
+        Self {
+            addr,
+            identity,
+            counters,
+            config: Arc::new(config),
+            timeout: DEFAULT_TIMEOUT,
+            padding: PaddingPolicy::Block(QUERY_PADDING_BLOCK),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// How long a query may take, connecting included.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How queries are padded, to blocks of [`QUERY_PADDING_BLOCK`] bytes unless told
+    /// otherwise. Only queries with an OPT record can be, see [`DnsPacket::apply_padding`].
+    pub fn padding(mut self, padding: PaddingPolicy) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn identity(&self) -> &ServerIdentity {
+        &self.identity
+    }
+
+    /// Counts the handshakes that failed on its pins.
+    pub fn counters(&self) -> &TlsCounters {
+        &self.counters
+    }
+
+    /// Connections open and waiting for a query.
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Sends `query` and waits for the matching response. The query needs exactly one
+    /// question.
+    pub async fn query(&self, query: &DnsPacket) -> Result<DnsPacket> {
+        let [_] = &query.questions[..] else {
+            return Err(DnsError::QuestionCount(query.questions.len()));
+        };
+        let mut padded = query.clone();
+        padded.apply_padding(self.padding, MAX_MESSAGE_SIZE)?;
+        let mut out = BytePacketBuffer::new();
+        padded.write(&mut out)?;
+        match time::timeout(self.timeout, self.exchange(query, out.as_slice())).await {
+            Ok(res) => res,
+            Err(_) => Err(DnsError::Timeout(self.addr)),
+        }
+    }
+
+    async fn exchange(&self, query: &DnsPacket, msg: &[u8]) -> Result<DnsPacket> {
+        if let Some(mut stream) = self.take_idle() {
+            if let Ok(response) = self.send(&mut stream, query, msg).await {
+                self.put_idle(stream);
+                return Ok(response);
+            }
+        }
+        let mut stream = TlsStream::connect(self.addr, &self.identity, self.config.clone()).await?;
+        let response = self.send(&mut stream, query, msg).await?;
+        self.put_idle(stream);
+        Ok(response)
+    }
+
+    async fn send(
+        &self,
+        stream: &mut TlsStream,
+        query: &DnsPacket,
+        msg: &[u8],
+    ) -> Result<DnsPacket> {
+        let mut framed = Vec::with_capacity(msg.len() + 2);
+        framed.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        framed.extend_from_slice(msg);
+        stream.write_all(&framed).await?;
+
+        let mut prefix = [0; 2];
+        stream.read_exact(&mut prefix).await?;
+        let len = u16::from_be_bytes(prefix) as usize;
+        let mut buf = BytePacketBuffer::with_len(len);
+        stream.read_exact(&mut buf.buf[..len]).await?;
+        let response = read_response(&mut buf, len)?;
+        if !matches_query(&response, query.header.id, &query.questions[0]) {
+            return Err(DnsError::MismatchedResponse(self.addr));
+        }
+        Ok(response)
+    }
+
+    fn take_idle(&self) -> Option<TlsStream> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|(_, since)| since.elapsed() < IDLE_TIMEOUT);
+        idle.pop().map(|(stream, _)| stream)
+    }
+
+    fn put_idle(&self, stream: TlsStream) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push((stream, Instant::now()));
+        }
+    }
+}
@@ -0,0 +1,715 @@
+#![allow(clippy::upper_case_acronyms)]
+// typed access to the options carried in an OPT record (rfc 6891). the OPT record itself only
+// keeps the raw option bytes, this is where they get split into individual options.
+use crate::error::{DnsError, Result};
+use crate::structure::{DnsPacket, DnsRecord, DEFAULT_EDNS_PAYLOAD};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const OPTION_NSID: u16 = 3;
+pub const OPTION_CLIENT_SUBNET: u16 = 8;
+pub const OPTION_COOKIE: u16 = 10;
+pub const OPTION_KEEPALIVE: u16 = 11;
+pub const OPTION_PADDING: u16 = 12;
+pub const OPTION_EXTENDED_ERROR: u16 = 15;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EdnsOption {
+    /// rfc 7873, an 8 byte client cookie optionally followed by an 8-32 byte server cookie
+    Cookie {
+        client: [u8; 8],
+        server: Option<Vec<u8>>,
+    },
+    /// rfc 7871, the address is truncated to the source prefix length
+    ClientSubnet {
+        family: u16,
+        source_prefix: u8,
+        scope_prefix: u8,
+        address: Vec<u8>,
+    },
+    /// rfc 5001, empty in queries, the server's identifier in responses
+    NSID(Vec<u8>),
+    /// rfc 7830, only the length matters, the content is zeroes
+    Padding(u16),
+    /// rfc 8914
+    ExtendedError {
+        info_code: u16,
+        extra_text: String,
+    },
+    /// rfc 7828, empty in queries, the idle timeout in units of 100ms in responses
+    KeepAlive(Option<u16>),
+    Unknown {
+        code: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::Cookie { .. } => OPTION_COOKIE,
+            EdnsOption::ClientSubnet { .. } => OPTION_CLIENT_SUBNET,
+            EdnsOption::NSID(_) => OPTION_NSID,
+            EdnsOption::Padding(_) => OPTION_PADDING,
+            EdnsOption::ExtendedError { .. } => OPTION_EXTENDED_ERROR,
+            EdnsOption::KeepAlive(_) => OPTION_KEEPALIVE,
+            EdnsOption::Unknown { code, .. } => *code,
+        }
+    }
+
+    pub fn parse(code: u16, data: &[u8]) -> Result<Self> {
+        let opt = match code {
+            OPTION_COOKIE => {
+                if data.len() != 8 && !(16..=40).contains(&data.len()) {
+                    return Err(DnsError::InvalidOption(format!(
+                        "invalid cookie length {}",
+                        data.len()
+                    )));
+                }
+                let mut client = [0; 8];
+                client.copy_from_slice(&data[..8]);
+                EdnsOption::Cookie {
+                    client,
+                    server: (data.len() > 8).then(|| data[8..].to_vec()),
+                }
+            }
+            OPTION_CLIENT_SUBNET => {
+                if data.len() < 4 {
+                    return Err(DnsError::InvalidOption(
+                        "client subnet option too short".into(),
+                    ));
+                }
+                let family = u16::from_be_bytes([data[0], data[1]]);
+                let source_prefix = data[2];
+                let scope_prefix = data[3];
+                let address = &data[4..];
+
+                // rfc 7871 section 7.1.1, the address must be exactly as long as the source
+                // prefix needs and any bits past the prefix must be zero
+                let max_prefix = match family {
+                    ECS_FAMILY_IPV4 => 32,
+                    ECS_FAMILY_IPV6 => 128,
+                    _ => {
+                        return Err(DnsError::InvalidOption(format!(
+                            "unknown client subnet family {}",
+                            family
+                        )))
+                    }
+                };
+                if source_prefix > max_prefix || scope_prefix > max_prefix {
+                    return Err(DnsError::InvalidOption(
+                        "client subnet prefix longer than the address".into(),
+                    ));
+                }
+                if address.len() != prefix_bytes(source_prefix) {
+                    return Err(DnsError::InvalidOption(
+                        "client subnet address doesn't match its source prefix".into(),
+                    ));
+                }
+                if mask_address(address, source_prefix) != address {
+                    return Err(DnsError::InvalidOption(
+                        "client subnet address has bits set past its source prefix".into(),
+                    ));
+                }
+
+                EdnsOption::ClientSubnet {
+                    family,
+                    source_prefix,
+                    scope_prefix,
+                    address: address.to_vec(),
+                }
+            }
+            OPTION_NSID => EdnsOption::NSID(data.to_vec()),
+            OPTION_PADDING => EdnsOption::Padding(data.len() as u16),
+            OPTION_EXTENDED_ERROR => {
+                if data.len() < 2 {
+                    return Err(DnsError::InvalidOption(
+                        "extended error option too short".into(),
+                    ));
+                }
+                EdnsOption::ExtendedError {
+                    info_code: u16::from_be_bytes([data[0], data[1]]),
+                    extra_text: String::from_utf8_lossy(&data[2..]).into_owned(),
+                }
+            }
+            OPTION_KEEPALIVE => match data.len() {
+                0 => EdnsOption::KeepAlive(None),
+                2 => EdnsOption::KeepAlive(Some(u16::from_be_bytes([data[0], data[1]]))),
+                n => {
+                    return Err(DnsError::InvalidOption(format!(
+                        "invalid keepalive length {}",
+                        n
+                    )))
+                }
+            },
+            _ => EdnsOption::Unknown {
+                code,
+                data: data.to_vec(),
+            },
+        };
+        Ok(opt)
+    }
+
+    /// Appends the option, code and length included, to `out`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.code().to_be_bytes());
+        let len_pos = out.len();
+        out.extend_from_slice(&[0, 0]);
+
+        match self {
+            EdnsOption::Cookie { client, server } => {
+                out.extend_from_slice(client);
+                if let Some(server) = server {
+                    out.extend_from_slice(server);
+                }
+            }
+            EdnsOption::ClientSubnet {
+                family,
+                source_prefix,
+                scope_prefix,
+                address,
+            } => {
+                out.extend_from_slice(&family.to_be_bytes());
+                out.push(*source_prefix);
+                out.push(*scope_prefix);
+                out.extend_from_slice(address);
+            }
+            EdnsOption::NSID(data) | EdnsOption::Unknown { data, .. } => {
+                out.extend_from_slice(data);
+            }
+            EdnsOption::Padding(len) => {
+                out.resize(out.len() + *len as usize, 0);
+            }
+            EdnsOption::ExtendedError {
+                info_code,
+                extra_text,
+            } => {
+                out.extend_from_slice(&info_code.to_be_bytes());
+                out.extend_from_slice(extra_text.as_bytes());
+            }
+            EdnsOption::KeepAlive(timeout) => {
+                if let Some(timeout) = timeout {
+                    out.extend_from_slice(&timeout.to_be_bytes());
+                }
+            }
+        }
+
+        let len = (out.len() - len_pos - 2) as u16;
+        out[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+    }
+
+    /// A client subnet option for `addr` truncated to `source_prefix` bits, as sent in queries.
+    /// The prefix is clamped to the length of the address.
+    pub fn client_subnet(addr: IpAddr, source_prefix: u8) -> Self {
+        let (family, bytes, source_prefix) = match addr {
+            IpAddr::V4(v4) => (ECS_FAMILY_IPV4, v4.octets().to_vec(), source_prefix.min(32)),
+            IpAddr::V6(v6) => (
+                ECS_FAMILY_IPV6,
+                v6.octets().to_vec(),
+                source_prefix.min(128),
+            ),
+        };
+        EdnsOption::ClientSubnet {
+            family,
+            source_prefix,
+            scope_prefix: 0,
+            address: mask_address(&bytes, source_prefix),
+        }
+    }
+
+    /// The subnet of a client subnet option as (address, source prefix, scope prefix), with
+    /// the truncated address padded back out to a full address.
+    pub fn subnet(&self) -> Option<(IpAddr, u8, u8)> {
+        let EdnsOption::ClientSubnet {
+            family,
+            source_prefix,
+            scope_prefix,
+            address,
+        } = self
+        else {
+            return None;
+        };
+
+        let addr = match *family {
+            ECS_FAMILY_IPV4 => {
+                let mut octets = [0; 4];
+                let len = address.len().min(4);
+                octets[..len].copy_from_slice(&address[..len]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            ECS_FAMILY_IPV6 => {
+                let mut octets = [0; 16];
+                let len = address.len().min(16);
+                octets[..len].copy_from_slice(&address[..len]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some((addr, *source_prefix, *scope_prefix))
+    }
+
+    /// Size on the wire, including the 4 byte option header.
+    pub fn wire_len(&self) -> usize {
+        let mut out = Vec::new();
+        self.write(&mut out);
+        out.len()
+    }
+}
+
+pub const ECS_FAMILY_IPV4: u16 = 1;
+pub const ECS_FAMILY_IPV6: u16 = 2;
+
+// bytes needed to hold a prefix of the given length
+fn prefix_bytes(prefix: u8) -> usize {
+    (prefix as usize).div_ceil(8)
+}
+
+// truncates an address to the bytes covering `prefix` and zeroes the bits past it
+fn mask_address(bytes: &[u8], prefix: u8) -> Vec<u8> {
+    let mut out = bytes[..prefix_bytes(prefix).min(bytes.len())].to_vec();
+    let rem = prefix % 8;
+    if rem != 0 {
+        if let Some(last) = out.last_mut() {
+            *last &= 0xFFu8 << (8 - rem);
+        }
+    }
+    out
+}
+
+/// What to do with client subnet information when forwarding a query upstream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EcsPolicy {
+    /// Never send client subnet information upstream. The privacy preserving default.
+    #[default]
+    Strip,
+    /// Pass along an option the client sent, shortened to at most these prefix lengths, but
+    /// don't add one on the client's behalf.
+    Forward { max_v4: u8, max_v6: u8 },
+    /// Like `Forward`, and derive an option from the client's source address when it didn't
+    /// send one itself.
+    Add { v4: u8, v6: u8 },
+}
+
+impl EcsPolicy {
+    /// Rewrites the client subnet option in an outgoing query's `edns` according to the policy.
+    /// `client` is the source address the query came in from.
+    pub fn apply(&self, edns: &mut Edns, client: IpAddr) {
+        let (max_v4, max_v6, add) = match *self {
+            EcsPolicy::Strip => {
+                edns.remove_option(OPTION_CLIENT_SUBNET);
+                return;
+            }
+            EcsPolicy::Forward { max_v4, max_v6 } => (max_v4, max_v6, false),
+            EcsPolicy::Add { v4, v6 } => (v4, v6, true),
+        };
+        let max_for = |addr: &IpAddr| match addr {
+            IpAddr::V4(_) => max_v4,
+            IpAddr::V6(_) => max_v6,
+        };
+
+        let existing = edns
+            .option(OPTION_CLIENT_SUBNET)
+            .and_then(EdnsOption::subnet);
+        match existing {
+            // a source prefix of 0 is the client asking for its address to not be used
+            Some((_, 0, _)) => {}
+            Some((addr, source, _)) => {
+                let prefix = source.min(max_for(&addr));
+                edns.set_option(EdnsOption::client_subnet(addr, prefix));
+            }
+            None if add => {
+                edns.set_option(EdnsOption::client_subnet(client, max_for(&client)));
+            }
+            None => {}
+        }
+    }
+}
+
+// the source prefixes rfc 7871 section 11.1 recommends sending at most
+const DEFAULT_ECS_V4: u8 = 24;
+const DEFAULT_ECS_V6: u8 = 56;
+
+/// `strip`, or `forward` or `add` optionally followed by the longest v4 and v6 prefixes to
+/// send, /24 and /56 if not given.
+impl std::str::FromStr for EcsPolicy {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            DnsError::Syntax(format!(
+                "expected strip, or forward or add and optionally two prefixes, got {:?}",
+                s
+            ))
+        };
+        let prefix = |s: &str, max: u8| match s.parse::<u8>() {
+            Ok(prefix) if prefix <= max => Ok(prefix),
+            _ => Err(DnsError::Syntax(format!(
+                "invalid client subnet prefix {:?}, expected 0 to {}",
+                s, max
+            ))),
+        };
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (kind, prefixes) = match words[..] {
+            ["strip"] => return Ok(EcsPolicy::Strip),
+            [kind, ref prefixes @ ..] => (kind, prefixes),
+            [] => return Err(invalid()),
+        };
+        let (v4, v6) = match prefixes {
+            [] => (DEFAULT_ECS_V4, DEFAULT_ECS_V6),
+            [v4, v6] => (prefix(v4, 32)?, prefix(v6, 128)?),
+            _ => return Err(invalid()),
+        };
+        match kind {
+            "forward" => Ok(EcsPolicy::Forward {
+                max_v4: v4,
+                max_v6: v6,
+            }),
+            "add" => Ok(EcsPolicy::Add { v4, v6 }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+pub fn parse_options(mut data: &[u8]) -> Result<Vec<EdnsOption>> {
+    let mut options = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(DnsError::InvalidOption(
+                "truncated edns option header".into(),
+            ));
+        }
+        let code = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let Some(body) = data.get(4..4 + len) else {
+            return Err(DnsError::InvalidOption(format!(
+                "edns option {} overruns the OPT record",
+                code
+            )));
+        };
+        options.push(EdnsOption::parse(code, body)?);
+        data = &data[4 + len..];
+    }
+    Ok(options)
+}
+
+pub fn write_options(options: &[EdnsOption]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for opt in options {
+        opt.write(&mut out);
+    }
+    out
+}
+
+/// The decoded contents of an OPT record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Edns {
+    pub payload_size: u16,
+    /// the upper 8 bits of the 12 bit extended rcode
+    pub ext_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(payload_size: u16) -> Self {
+        Self {
+            payload_size,
+            ext_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+
+    /// Returns None for anything that isn't an OPT record.
+    pub fn from_record(rec: &DnsRecord) -> Option<Result<Self>> {
+        let DnsRecord::OPT {
+            packet_len,
+            flags,
+            data,
+        } = rec
+        else {
+            return None;
+        };
+
+        // the ttl field is split into ext rcode (8), version (8), DO (1) and z (15)
+        Some(parse_options(data).map(|options| Self {
+            payload_size: *packet_len,
+            ext_rcode: (flags >> 24) as u8,
+            version: (flags >> 16) as u8,
+            dnssec_ok: (flags & 0x8000) != 0,
+            options,
+        }))
+    }
+
+    pub fn to_record(&self) -> DnsRecord {
+        DnsRecord::OPT {
+            packet_len: self.payload_size,
+            flags: ((self.ext_rcode as u32) << 24)
+                | ((self.version as u32) << 16)
+                | if self.dnssec_ok { 0x8000 } else { 0 },
+            data: write_options(&self.options),
+        }
+    }
+
+    pub fn option(&self, code: u16) -> Option<&EdnsOption> {
+        self.options.iter().find(|o| o.code() == code)
+    }
+
+    /// Replaces any existing option with the same code.
+    pub fn set_option(&mut self, opt: EdnsOption) {
+        self.remove_option(opt.code());
+        self.options.push(opt);
+    }
+
+    pub fn remove_option(&mut self, code: u16) {
+        self.options.retain(|o| o.code() != code);
+    }
+}
+
+/// INFO-CODEs for extended dns errors, rfc 8914 section 4.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EdeCode {
+    Other,
+    UnsupportedDnskeyAlgorithm,
+    UnsupportedDsDigestType,
+    StaleAnswer,
+    ForgedAnswer,
+    DnssecIndeterminate,
+    DnssecBogus,
+    SignatureExpired,
+    SignatureNotYetValid,
+    DnskeyMissing,
+    RrsigsMissing,
+    NoZoneKeyBitSet,
+    NsecMissing,
+    CachedError,
+    NotReady,
+    Blocked,
+    Censored,
+    Filtered,
+    Prohibited,
+    StaleNxdomainAnswer,
+    NotAuthoritative,
+    NotSupported,
+    NoReachableAuthority,
+    NetworkError,
+    InvalidData,
+    Unknown(u16),
+}
+
+impl EdeCode {
+    pub fn from_num(n: u16) -> Self {
+        match n {
+            0 => EdeCode::Other,
+            1 => EdeCode::UnsupportedDnskeyAlgorithm,
+            2 => EdeCode::UnsupportedDsDigestType,
+            3 => EdeCode::StaleAnswer,
+            4 => EdeCode::ForgedAnswer,
+            5 => EdeCode::DnssecIndeterminate,
+            6 => EdeCode::DnssecBogus,
+            7 => EdeCode::SignatureExpired,
+            8 => EdeCode::SignatureNotYetValid,
+            9 => EdeCode::DnskeyMissing,
+            10 => EdeCode::RrsigsMissing,
+            11 => EdeCode::NoZoneKeyBitSet,
+            12 => EdeCode::NsecMissing,
+            13 => EdeCode::CachedError,
+            14 => EdeCode::NotReady,
+            15 => EdeCode::Blocked,
+            16 => EdeCode::Censored,
+            17 => EdeCode::Filtered,
+            18 => EdeCode::Prohibited,
+            19 => EdeCode::StaleNxdomainAnswer,
+            20 => EdeCode::NotAuthoritative,
+            21 => EdeCode::NotSupported,
+            22 => EdeCode::NoReachableAuthority,
+            23 => EdeCode::NetworkError,
+            24 => EdeCode::InvalidData,
+            n => EdeCode::Unknown(n),
+        }
+    }
+
+    pub fn to_num(self) -> u16 {
+        match self {
+            EdeCode::Other => 0,
+            EdeCode::UnsupportedDnskeyAlgorithm => 1,
+            EdeCode::UnsupportedDsDigestType => 2,
+            EdeCode::StaleAnswer => 3,
+            EdeCode::ForgedAnswer => 4,
+            EdeCode::DnssecIndeterminate => 5,
+            EdeCode::DnssecBogus => 6,
+            EdeCode::SignatureExpired => 7,
+            EdeCode::SignatureNotYetValid => 8,
+            EdeCode::DnskeyMissing => 9,
+            EdeCode::RrsigsMissing => 10,
+            EdeCode::NoZoneKeyBitSet => 11,
+            EdeCode::NsecMissing => 12,
+            EdeCode::CachedError => 13,
+            EdeCode::NotReady => 14,
+            EdeCode::Blocked => 15,
+            EdeCode::Censored => 16,
+            EdeCode::Filtered => 17,
+            EdeCode::Prohibited => 18,
+            EdeCode::StaleNxdomainAnswer => 19,
+            EdeCode::NotAuthoritative => 20,
+            EdeCode::NotSupported => 21,
+            EdeCode::NoReachableAuthority => 22,
+            EdeCode::NetworkError => 23,
+            EdeCode::InvalidData => 24,
+            EdeCode::Unknown(n) => n,
+        }
+    }
+}
+
+// the purpose strings from the iana registry
+impl fmt::Display for EdeCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            EdeCode::Other => "Other Error",
+            EdeCode::UnsupportedDnskeyAlgorithm => "Unsupported DNSKEY Algorithm",
+            EdeCode::UnsupportedDsDigestType => "Unsupported DS Digest Type",
+            EdeCode::StaleAnswer => "Stale Answer",
+            EdeCode::ForgedAnswer => "Forged Answer",
+            EdeCode::DnssecIndeterminate => "DNSSEC Indeterminate",
+            EdeCode::DnssecBogus => "DNSSEC Bogus",
+            EdeCode::SignatureExpired => "Signature Expired",
+            EdeCode::SignatureNotYetValid => "Signature Not Yet Valid",
+            EdeCode::DnskeyMissing => "DNSKEY Missing",
+            EdeCode::RrsigsMissing => "RRSIGs Missing",
+            EdeCode::NoZoneKeyBitSet => "No Zone Key Bit Set",
+            EdeCode::NsecMissing => "NSEC Missing",
+            EdeCode::CachedError => "Cached Error",
+            EdeCode::NotReady => "Not Ready",
+            EdeCode::Blocked => "Blocked",
+            EdeCode::Censored => "Censored",
+            EdeCode::Filtered => "Filtered",
+            EdeCode::Prohibited => "Prohibited",
+            EdeCode::StaleNxdomainAnswer => "Stale NXDOMAIN Answer",
+            EdeCode::NotAuthoritative => "Not Authoritative",
+            EdeCode::NotSupported => "Not Supported",
+            EdeCode::NoReachableAuthority => "No Reachable Authority",
+            EdeCode::NetworkError => "Network Error",
+            EdeCode::InvalidData => "Invalid Data",
+            EdeCode::Unknown(n) => return write!(f, "Unknown Error {}", n),
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl EdnsOption {
+    pub fn extended_error(code: EdeCode, extra_text: &str) -> Self {
+        EdnsOption::ExtendedError {
+            info_code: code.to_num(),
+            extra_text: extra_text.to_string(),
+        }
+    }
+}
+
+/// Block sizes recommended by rfc 8467 section 4.1.
+pub const QUERY_PADDING_BLOCK: u16 = 128;
+pub const RESPONSE_PADDING_BLOCK: u16 = 468;
+
+/// How to pad messages sent over encrypted transports, so their length says less about what
+/// is being asked or answered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    #[default]
+    Off,
+    /// Pad the whole message to a multiple of this many bytes.
+    Block(u16),
+}
+
+impl DnsPacket {
+    /// Adds a padding option according to `policy`, sized from the rest of the serialized
+    /// message, without growing the message past `max_len`. Any existing padding is replaced.
+    /// Packets without an OPT record are left alone since padding can only travel inside one.
+    pub fn apply_padding(&mut self, policy: PaddingPolicy, max_len: usize) -> Result<()> {
+        let Some(edns) = self.edns() else {
+            return Ok(());
+        };
+        let mut edns = edns?;
+        edns.remove_option(OPTION_PADDING);
+        self.set_edns(Some(&edns));
+
+        let PaddingPolicy::Block(block) = policy else {
+            return Ok(());
+        };
+        if block == 0 {
+            return Ok(());
+        }
+
+        // the option header itself takes 4 bytes, on top of whatever padding goes inside
+        let unpadded = self.wire_len()? + 4;
+        let target = unpadded.next_multiple_of(block as usize).min(max_len);
+        if target < unpadded {
+            return Ok(());
+        }
+
+        edns.set_option(EdnsOption::Padding((target - unpadded) as u16));
+        self.set_edns(Some(&edns));
+        Ok(())
+    }
+
+    /// Attaches an extended error to the packet's OPT record, adding one if needed. A packet
+    /// can carry several extended errors. Only use this on responses to queries that had an
+    /// OPT record themselves.
+    pub fn add_extended_error(&mut self, code: EdeCode, extra_text: &str) -> Result<()> {
+        let mut edns = match self.edns() {
+            Some(edns) => edns?,
+            None => Edns::new(DEFAULT_EDNS_PAYLOAD),
+        };
+        // unlike other options, repeating this one is allowed
+        edns.options
+            .push(EdnsOption::extended_error(code, extra_text));
+        self.set_edns(Some(&edns));
+        Ok(())
+    }
+
+    /// All extended errors in the packet, in order.
+    pub fn extended_errors(&self) -> Vec<(EdeCode, String)> {
+        let Some(Ok(edns)) = self.edns() else {
+            return vec![];
+        };
+        edns.options
+            .into_iter()
+            .filter_map(|opt| match opt {
+                EdnsOption::ExtendedError {
+                    info_code,
+                    extra_text,
+                } => Some((EdeCode::from_num(info_code), extra_text)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The packet's OPT record, decoded. None if there isn't one.
+    pub fn edns(&self) -> Option<Result<Edns>> {
+        self.additional.iter().find_map(Edns::from_record)
+    }
+
+    /// The OPT record to put on a response to `request` from somewhere else (an upstream or a
+    /// cache): EDNS is hop by hop, so the client only gets one if it sent one, advertising our
+    /// own payload size and echoing its DO bit.
+    pub fn response_edns(request: &DnsPacket) -> Option<Edns> {
+        let Some(Ok(client)) = request.edns() else {
+            return None;
+        };
+        let mut edns = Edns::new(DEFAULT_EDNS_PAYLOAD);
+        edns.dnssec_ok = client.dnssec_ok;
+        Some(edns)
+    }
+
+    /// Replaces the packet's OPT record, or removes it when `edns` is None.
+    pub fn set_edns(&mut self, edns: Option<&Edns>) {
+        self.additional
+            .retain(|rec| !matches!(rec, DnsRecord::OPT { .. }));
+        if let Some(edns) = edns {
+            self.additional.push(edns.to_record());
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// locally served empty zones for reverse lookups of private and reserved address space
+// (rfc 6303 and rfc 7793). nobody on the internet can answer PTR queries for 10.x.x.x, so
+// forwarding them only leaks which private addresses are in use. instead we answer from an
+// empty zone: NXDOMAIN below the apex, NODATA at it, with the zone's SOA in the authority.
+use crate::structure::{is_subdomain, DnsPacket, DnsRecord, QueryType, ResultCode};
+
+// rfc 6303 section 3 recommends these values
+const TTL: u32 = 10800;
+
+pub fn default_zones() -> Vec<String> {
+    let mut zones: Vec<String> = vec![
+        // rfc 1918
+        "10.in-addr.arpa".into(),
+        "168.192.in-addr.arpa".into(),
+        // rfc 5735 / rfc 6303 section 4.2 - 4.6
+        "0.in-addr.arpa".into(),
+        "127.in-addr.arpa".into(),
+        "254.169.in-addr.arpa".into(),
+        "2.0.192.in-addr.arpa".into(),
+        "100.51.198.in-addr.arpa".into(),
+        "113.0.203.in-addr.arpa".into(),
+        "255.255.255.255.in-addr.arpa".into(),
+        // ipv6 unspecified and loopback
+        format!("{}ip6.arpa", "0.".repeat(32)),
+        format!("1.{}ip6.arpa", "0.".repeat(31)),
+        // ula, link-local and the documentation prefix
+        "d.f.ip6.arpa".into(),
+        "8.e.f.ip6.arpa".into(),
+        "9.e.f.ip6.arpa".into(),
+        "a.e.f.ip6.arpa".into(),
+        "b.e.f.ip6.arpa".into(),
+        "8.b.d.0.1.0.0.2.ip6.arpa".into(),
+    ];
+    // 172.16.0.0/12
+    zones.extend((16..=31).map(|n| format!("{}.172.in-addr.arpa", n)));
+    // rfc 6598 shared address space, 100.64.0.0/10
+    zones.extend((64..=127).map(|n| format!("{}.100.in-addr.arpa", n)));
+    zones
+}
+
+pub struct EmptyZones {
+    zones: Vec<String>,
+    // names not to answer for even if they're in one of the zones
+    passed: Vec<String>,
+}
+
+impl Default for EmptyZones {
+    fn default() -> Self {
+        Self {
+            zones: default_zones(),
+            passed: Vec::new(),
+        }
+    }
+}
+
+impl EmptyZones {
+    pub fn new(zones: Vec<String>) -> Self {
+        Self {
+            zones: zones
+                .into_iter()
+                .map(|z| z.trim_end_matches('.').to_lowercase())
+                .collect(),
+            passed: Vec::new(),
+        }
+    }
+
+    /// Stops serving `zone` (and any empty zone below it) locally, for operators that have
+    /// real data for it.
+    pub fn disable(&mut self, zone: &str) {
+        let zone = zone.trim_end_matches('.');
+        self.zones.retain(|z| !is_subdomain(z, zone));
+    }
+
+    /// Leaves `domain` and the names below it to be looked up even where they're in an empty
+    /// zone, for a part of the private address space that's forwarded to a server that knows
+    /// it. The rest of the zone is still served empty.
+    pub fn pass_through(&mut self, domain: &str) {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        self.disable(&domain);
+        self.passed.push(domain);
+    }
+
+    pub fn zones(&self) -> &[String] {
+        &self.zones
+    }
+
+    fn find(&self, name: &str) -> Option<&str> {
+        if self.passed.iter().any(|domain| is_subdomain(name, domain)) {
+            return None;
+        }
+        self.zones
+            .iter()
+            .find(|z| is_subdomain(name, z))
+            .map(|z| z.as_str())
+    }
+
+    /// The response for `request` if its question falls into one of the empty zones.
+    pub fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if question.class != 1 {
+            return None;
+        }
+        let zone = self.find(&question.name)?;
+
+        let soa = DnsRecord::SOA {
+            domain: zone.to_string(),
+            class: 1,
+            ttl: TTL,
+            mname: zone.to_string(),
+            rname: "nobody.invalid".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 1200,
+            expire: 604800,
+            minimum: TTL,
+        };
+
+        let mut res = DnsPacket::response_to(request);
+        res.set_authoritative(true);
+
+        // the apex holds the SOA and NS records, everything else doesn't exist
+        if question.name.eq_ignore_ascii_case(zone) {
+            match question.qtype {
+                QueryType::SOA => {
+                    res.add_answer(soa);
+                }
+                QueryType::NS => {
+                    res.add_answer(DnsRecord::NS {
+                        domain: zone.to_string(),
+                        class: 1,
+                        ttl: TTL,
+                        host: zone.to_string(),
+                    });
+                }
+                _ => {
+                    res.add_authority(soa);
+                }
+            }
+        } else {
+            res.set_rcode(ResultCode::NXDOMAIN).add_authority(soa);
+        }
+
+        Some(res)
+    }
+}
@@ -0,0 +1,99 @@
+// the error type for everything that parses or builds packets. callers can match on what went
+// wrong instead of comparing strings.
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("end of buffer")]
+    BufferOverrun,
+    #[error("label exceeds 63 bytes")]
+    LabelTooLong,
+    #[error("name exceeds 255 bytes: {0}")]
+    NameTooLong(String),
+    #[error("empty label in {0:?}")]
+    EmptyLabel(String),
+    #[error("max jumps exceeded while following name compression")]
+    CompressionLoop,
+    #[error("unsupported record type {0}")]
+    UnsupportedType(String),
+    #[error("{0} trailing bytes")]
+    TrailingBytes(usize),
+    #[error("invalid rdata: {0}")]
+    InvalidRdata(String),
+    #[error("opcode {0} doesn't fit in four bits")]
+    InvalidOpcode(u8),
+    #[error("invalid edns option: {0}")]
+    InvalidOption(String),
+    #[error("message doesn't fit: {0}")]
+    MessageTooLarge(String),
+    #[error("syntax error: {0}")]
+    Syntax(String),
+    #[error("{file}:{line}: {error}")]
+    ZoneFile {
+        file: String,
+        line: usize,
+        error: Box<DnsError>,
+    },
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
+    #[error("queries need exactly one question, not {0}")]
+    QuestionCount(usize),
+    #[error("invalid cache snapshot: {0}")]
+    InvalidSnapshot(String),
+    #[error("no upstream resolvers configured")]
+    NoUpstreams,
+    #[error("timed out waiting for {0}")]
+    Timeout(std::net::SocketAddr),
+    #[error("response from {0} doesn't match the query")]
+    MismatchedResponse(std::net::SocketAddr),
+    #[error("{0} answered {1:?}")]
+    ServerFailure(std::net::SocketAddr, crate::structure::ResultCode),
+    #[error("no reachable nameserver for {0}")]
+    NoNameservers(String),
+    #[error("zone transfer from {0} failed: {1}")]
+    TransferFailed(std::net::SocketAddr, String),
+    #[error("transaction signature check failed: {0}")]
+    Tsig(String),
+    #[error("invalid DNSSEC key: {0}")]
+    InvalidKey(String),
+    #[error("zone difference doesn't apply: {0}")]
+    InvalidDiff(String),
+    #[error("referral loop at {0}")]
+    ReferralLoop(String),
+    #[error("resolving {0} exceeded the depth limit")]
+    DepthExceeded(String),
+    #[error("downloading {0} failed: {1}")]
+    Download(String, String),
+    #[error("invalid geoip database: {0}")]
+    GeoDatabase(String),
+    #[error("invalid certificate: {0}")]
+    Certificate(String),
+    #[error("tls: {0}")]
+    Tls(String),
+    #[error("http: {0}")]
+    Http(String),
+    #[error("quic: {0}")]
+    Quic(String),
+    #[error("control: {0}")]
+    Control(String),
+    #[error("looking up {0} with the bootstrap resolvers failed: {1}")]
+    Bootstrap(String, String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, DnsError>;
+
+// numbers and addresses in zone data and config are parsed with std's FromStr, whose errors
+// are syntax errors as far as we're concerned
+impl From<std::num::ParseIntError> for DnsError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        DnsError::Syntax(e.to_string())
+    }
+}
+
+impl From<std::net::AddrParseError> for DnsError {
+    fn from(e: std::net::AddrParseError) -> Self {
+        DnsError::Syntax(e.to_string())
+    }
+}
@@ -0,0 +1,449 @@
+// forwarding to upstream resolvers. the client's question is sent upstream under a fresh id and
+// the answer is readdressed to the client. upstreams are tried until one answers with something
+// other than SERVFAIL, the fastest first and ones that stopped answering last, see
+// upstreams.rs. if they all fail the last SERVFAIL is passed on. racing sends the query to
+// several upstreams at once, or to the next one whenever the last hasn't answered within a
+// short delay, and takes whichever answer comes first, dropping the queries still out.
+// upstreams can be spoken to over TLS, HTTPS or QUIC instead, see dot.rs, doh.rs and doq.rs.
+// conditional forwarding sends the names under some domains to upstreams of their own, for
+// split DNS behind a VPN or an internal zone only the company's servers know, with the longest
+// matching domain winning. a prefetch for the cache skips the upstreams with as many of them
+// out as the cache allows, see refresh.rs. what upstreams learn of the client's address through
+// EDNS client subnet is up to the forwarder's policy, by default nothing, see edns.rs.
+use crate::client::Client;
+use crate::dnssec;
+use crate::doh::HttpsUpstream;
+use crate::doq::QuicUpstream;
+use crate::dot::TlsUpstream;
+use crate::edns::{EcsPolicy, OPTION_CLIENT_SUBNET};
+use crate::error::{DnsError, Result};
+use crate::logging;
+use crate::metrics::CaseCounters;
+use crate::refresh;
+use crate::server::Handler;
+use crate::structure::{
+    is_subdomain, DnsPacket, DnsQuestion, QueryType, ResultCode, DEFAULT_EDNS_PAYLOAD,
+};
+use crate::upstreams::{Outcome, UpstreamStats, Upstreams};
+use std::future::{self, Future};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// a query out to one upstream: which one, how long it took and what came back
+type Exchange<'a> =
+    Pin<Box<dyn Future<Output = (SocketAddr, Duration, Result<DnsPacket>)> + Send + 'a>>;
+
+pub struct Forwarder {
+    upstreams: Upstreams,
+    client: Client,
+    tls: Vec<TlsUpstream>,
+    https: Vec<HttpsUpstream>,
+    quic: Vec<QuicUpstream>,
+    edns_payload: u16,
+    race: usize,
+    stagger: Duration,
+    client_subnet: EcsPolicy,
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<SocketAddr>) -> Self {
+        Self {
+            upstreams: Upstreams::new(upstreams),
+            client: Client::new(),
+            tls: Vec::new(),
+            https: Vec::new(),
+            quic: Vec::new(),
+            edns_payload: DEFAULT_EDNS_PAYLOAD,
+            race: 1,
+            stagger: Duration::ZERO,
+            client_subnet: EcsPolicy::default(),
+        }
+    }
+
+    /// How long to wait for one upstream before moving on to the next.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client = self.client.timeout(timeout);
+        self
+    }
+
+    /// Caps the number of sockets open towards upstreams at once.
+    pub fn max_sockets(mut self, max: usize) -> Self {
+        self.client = self.client.max_sockets(max);
+        self
+    }
+
+    /// The udp payload size advertised to upstreams, the largest response they should send
+    /// over udp. Bigger ones come truncated and are fetched again over tcp.
+    pub fn edns_payload(mut self, size: u16) -> Self {
+        self.edns_payload = size;
+        self
+    }
+
+    /// Randomizes the case of names sent upstream, see [`Client::randomize_case`].
+    pub fn randomize_case(mut self, on: bool) -> Self {
+        self.client = self.client.randomize_case(on);
+        self
+    }
+
+    /// Sends names in lower case to upstreams that don't keep the case of more than this share
+    /// of them, see [`Upstreams::max_case_mismatches`].
+    pub fn max_case_mismatches(mut self, rate: f64) -> Self {
+        self.upstreams = self.upstreams.max_case_mismatches(rate);
+        self
+    }
+
+    /// Has up to `upstreams` queries out at once, taking the first answer. One, the default,
+    /// tries the upstreams one after the other.
+    pub fn race(mut self, upstreams: usize) -> Self {
+        self.race = upstreams.max(1);
+        self
+    }
+
+    /// When racing, waits this long for an answer before sending the query to the next
+    /// upstream, rather than sending it to all of them at once. Upstreams that fail are
+    /// replaced right away.
+    pub fn stagger(mut self, delay: Duration) -> Self {
+        self.stagger = delay;
+        self
+    }
+
+    /// Adds an upstream spoken to over TLS, tried along with the others.
+    pub fn tls_upstream(mut self, upstream: TlsUpstream) -> Self {
+        self.add_upstream(upstream.addr());
+        self.tls.push(upstream);
+        self
+    }
+
+    /// Adds an upstream spoken to over HTTPS, tried along with the others.
+    pub fn https_upstream(mut self, upstream: HttpsUpstream) -> Self {
+        self.add_upstream(upstream.addr());
+        self.https.push(upstream);
+        self
+    }
+
+    /// Adds an upstream spoken to over QUIC, tried along with the others.
+    pub fn quic_upstream(mut self, upstream: QuicUpstream) -> Self {
+        self.add_upstream(upstream.addr());
+        self.quic.push(upstream);
+        self
+    }
+
+    fn add_upstream(&mut self, addr: SocketAddr) {
+        self.upstreams.add(addr);
+    }
+
+    pub fn upstreams(&self) -> &[SocketAddr] {
+        self.upstreams.addrs()
+    }
+
+    /// How each upstream has been doing.
+    pub fn upstream_stats(&self) -> Vec<UpstreamStats> {
+        self.upstreams.stats()
+    }
+
+    /// How each upstream has been doing with the case of the names sent to it.
+    pub fn case_counters(&self) -> impl Iterator<Item = (SocketAddr, &CaseCounters)> {
+        (self.upstreams.addrs().iter())
+            .filter_map(|&addr| Some((addr, self.upstreams.case_counters(addr)?)))
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// What upstreams are told of the client's address, nothing unless this says otherwise.
+    pub fn client_subnet(mut self, policy: EcsPolicy) -> Self {
+        self.client_subnet = policy;
+        self
+    }
+
+    /// Sends the question in `request`, from `client`, upstream and returns the answer,
+    /// readdressed to the client. Errors if no upstream answered.
+    pub async fn forward(&self, request: &DnsPacket, client: IpAddr) -> Result<DnsPacket> {
+        let Some(question) = request.questions.first() else {
+            let mut res = DnsPacket::response_to(request);
+            res.set_rcode(ResultCode::FORMERR);
+            return Ok(res);
+        };
+
+        let client_edns = DnsPacket::response_edns(request);
+        let query = self.query(request, question, client);
+
+        let mut last_err = DnsError::NoUpstreams;
+        let mut servfail = None;
+        let mut waiting = self.upstreams.order().into_iter();
+        let mut out: Vec<Exchange> = Vec::new();
+        let mut next_send = time::Instant::now();
+        loop {
+            while out.len() < self.race && next_send <= time::Instant::now() {
+                let Some(upstream) = waiting.next() else {
+                    break;
+                };
+                // a refresh leaves out the upstreams that have enough of them already
+                let permit = match refresh::limiter() {
+                    Some(limiter) => match limiter.try_acquire(upstream) {
+                        Some(permit) => Some(permit),
+                        None => continue,
+                    },
+                    None => None,
+                };
+                let exchange = self.exchange(upstream, query.clone());
+                out.push(Box::pin(async move {
+                    let done = exchange.await;
+                    drop(permit);
+                    done
+                }));
+                next_send = time::Instant::now() + self.stagger;
+            }
+            if out.is_empty() {
+                return servfail.ok_or(last_err);
+            }
+            // with another upstream to send to, only until it's time to send
+            let more = out.len() < self.race && waiting.len() > 0;
+            let (upstream, rtt, res) = if more {
+                match time::timeout_at(next_send, first(&mut out)).await {
+                    Ok(done) => done,
+                    Err(_) => continue,
+                }
+            } else {
+                first(&mut out).await
+            };
+            match res {
+                Ok(mut response) => {
+                    response.header.id = request.header.id;
+                    response.set_edns(client_edns.as_ref());
+                    if response.header.rcode == ResultCode::SERVFAIL {
+                        self.upstreams.record(upstream, Outcome::ServerFailure(rtt));
+                        servfail = Some(response);
+                        next_send = time::Instant::now();
+                        continue;
+                    }
+                    self.upstreams.record(upstream, Outcome::Answered(rtt));
+                    return Ok(response);
+                }
+                Err(e) => {
+                    self.upstreams.record(upstream, Outcome::Failed(rtt));
+                    last_err = e;
+                    next_send = time::Instant::now();
+                }
+            }
+        }
+    }
+
+    // the query for `question` sent upstream: a fresh one, with only the flags and client
+    // subnet the client's has a say in
+    fn query(&self, request: &DnsPacket, question: &DnsQuestion, client: IpAddr) -> DnsPacket {
+        let client_edns = match request.edns() {
+            Some(Ok(edns)) => Some(edns),
+            _ => None,
+        };
+        let mut query = DnsPacket::query(&question.name, question.qtype)
+            .class(question.class)
+            .recursion_desired(request.header.flags.recursion_desired)
+            .edns(Some(self.edns_payload))
+            .dnssec_ok(client_edns.as_ref().is_some_and(|edns| edns.dnssec_ok))
+            .build();
+        query.header.flags.checking_disabled = request.header.flags.checking_disabled;
+        if self.client_subnet != EcsPolicy::Strip {
+            if let Some(Ok(mut edns)) = query.edns() {
+                let subnet = client_edns
+                    .as_ref()
+                    .and_then(|edns| edns.option(OPTION_CLIENT_SUBNET));
+                if let Some(subnet) = subnet {
+                    edns.set_option(subnet.clone());
+                }
+                self.client_subnet.apply(&mut edns, client);
+                query.set_edns(Some(&edns));
+            }
+        }
+        query
+    }
+
+    fn exchange(&self, upstream: SocketAddr, mut query: DnsPacket) -> Exchange<'_> {
+        Box::pin(async move {
+            let sent = Instant::now();
+            let tls = self.tls.iter().find(|tls| tls.addr() == upstream);
+            let https = self.https.iter().find(|https| https.addr() == upstream);
+            let quic = self.quic.iter().find(|quic| quic.addr() == upstream);
+            let res = match (tls, https, quic) {
+                (Some(tls), _, _) => tls.query(&query).await,
+                (_, Some(https), _) => https.query(&query).await,
+                (_, _, Some(quic)) => quic.query(&query).await,
+                _ => {
+                    let randomize = self.upstreams.randomizes_case(upstream);
+                    let res = self
+                        .client
+                        .query_cased(upstream, &mut query, randomize)
+                        .await;
+                    res.map(|(response, kept_case)| {
+                        if let Some(kept) = kept_case {
+                            self.upstreams.record_case(upstream, kept);
+                        }
+                        response
+                    })
+                }
+            };
+            (upstream, sent.elapsed(), res)
+        })
+    }
+}
+
+// whichever of `exchanges` finishes first, taken out of them
+async fn first<T>(exchanges: &mut Vec<Pin<Box<dyn Future<Output = T> + Send + '_>>>) -> T {
+    future::poll_fn(|cx| {
+        for i in 0..exchanges.len() {
+            if let Poll::Ready(out) = exchanges[i].as_mut().poll(cx) {
+                drop(exchanges.swap_remove(i));
+                return Poll::Ready(out);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+impl Handler for Forwarder {
+    /// Answers with SERVFAIL when no upstream could be reached.
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        match self.forward(&request, src.ip()).await {
+            Ok(response) => Some(response),
+            Err(e) => {
+                logging::warning(&format!("forwarding {:?} failed: {}", request.questions, e));
+                let mut res = DnsPacket::response_to(&request);
+                res.set_rcode(ResultCode::SERVFAIL);
+                Some(res)
+            }
+        }
+    }
+}
+
+/// Forwards the names under the domains it has routes for to the upstreams for the closest
+/// one, and everything else to `default`.
+pub struct ConditionalForwarder<H> {
+    // longest domain first
+    routes: Vec<(String, Forwarder)>,
+    default: H,
+}
+
+impl<H: Handler> ConditionalForwarder<H> {
+    pub fn new(default: H) -> Self {
+        Self {
+            routes: Vec::new(),
+            default,
+        }
+    }
+
+    /// Forwards `domain` and the names below it with `forwarder`. A leading `*.` is
+    /// ignored, `*.corp.example` is the same as `corp.example`.
+    pub fn route(mut self, domain: &str, forwarder: Forwarder) -> Self {
+        let domain = domain.trim_start_matches("*.").trim_end_matches('.');
+        self.routes.push((domain.to_lowercase(), forwarder));
+        self.routes
+            .sort_by_key(|(domain, _)| std::cmp::Reverse(dnssec::label_count(domain)));
+        self
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &Forwarder)> {
+        self.routes
+            .iter()
+            .map(|(domain, forwarder)| (domain.as_str(), forwarder))
+    }
+
+    /// The forwarder for a question, None for the default. DS records are in the zone above
+    /// the one they're for, so they're looked up where the parent's names are.
+    pub fn forwarder_for(&self, name: &str, qtype: QueryType) -> Option<&Forwarder> {
+        let name = name.trim_end_matches('.');
+        let name = match qtype {
+            QueryType::DS => name.split_once('.').map_or("", |(_, parent)| parent),
+            _ => name,
+        };
+        self.routes
+            .iter()
+            .find(|(domain, _)| is_subdomain(name, domain))
+            .map(|(_, forwarder)| forwarder)
+    }
+}
+
+impl<H: Handler> Handler for ConditionalForwarder<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let forwarder = request
+            .questions
+            .first()
+            .and_then(|question| self.forwarder_for(&question.name, question.qtype));
+        match forwarder {
+            Some(forwarder) => forwarder.handle(request, src).await,
+            None => self.default.handle(request, src).await,
+        }
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.default.transfer(request, src).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edns::{Edns, EdnsOption};
+    use crate::refresh::{RefreshLimiter, RefreshPolicy};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    // the client subnet of the query sent upstream for `request` from 192.0.2.77
+    fn subnet_sent(policy: &str, request: &DnsPacket) -> Option<(IpAddr, u8, u8)> {
+        let forwarder = Forwarder::new(Vec::new()).client_subnet(policy.parse().unwrap());
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 77));
+        let query = forwarder.query(request, &request.questions[0], client);
+        let edns = query.edns().unwrap().unwrap();
+        edns.option(OPTION_CLIENT_SUBNET)
+            .and_then(EdnsOption::subnet)
+    }
+
+    #[test]
+    fn tells_upstreams_what_its_policy_says_of_the_client() {
+        let plain = DnsPacket::query("example.com", QueryType::A).build();
+        let mut with_subnet = plain.clone();
+        let mut edns = Edns::new(1232);
+        let sent = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0));
+        edns.set_option(EdnsOption::client_subnet(sent, 24));
+        with_subnet.set_edns(Some(&edns));
+
+        assert_eq!(subnet_sent("strip", &with_subnet), None);
+        assert_eq!(subnet_sent("forward", &plain), None);
+        assert_eq!(subnet_sent("forward", &with_subnet), Some((sent, 24, 0)));
+        assert_eq!(
+            subnet_sent("forward 16 48", &with_subnet),
+            Some((IpAddr::V4(Ipv4Addr::new(198, 51, 0, 0)), 16, 0))
+        );
+        assert_eq!(
+            subnet_sent("add", &plain),
+            Some((IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)), 24, 0))
+        );
+        assert_eq!(subnet_sent("add", &with_subnet), Some((sent, 24, 0)));
+
+        for policy in ["", "strip 24 56", "add 24", "forward 33 56", "send"] {
+            assert!(policy.parse::<EcsPolicy>().is_err(), "{}", policy);
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_skip_upstreams_with_enough_of_them() {
+        let upstream: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let forwarder = Forwarder::new(vec![upstream]);
+        let limiter = Arc::new(RefreshLimiter::new(&RefreshPolicy {
+            jitter: 0.0,
+            max_concurrent_per_upstream: 1,
+        }));
+        let permit = limiter.try_acquire(upstream).unwrap();
+
+        let query = DnsPacket::query("example.com", QueryType::A).build();
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let refresh = refresh::limited(limiter.clone(), forwarder.forward(&query, client));
+        assert!(matches!(refresh.await, Err(DnsError::NoUpstreams)));
+        drop(permit);
+        assert_eq!(limiter.in_flight(upstream), 0);
+    }
+}
@@ -0,0 +1,480 @@
+// answers that depend on where the client is: a MaxMind database (GeoLite2 or GeoIP2
+// Country/City, or anything else in the mmdb format with the same fields) says which country
+// and continent an address is in, and names can have records of their own for some countries
+// or continents, e.g. sending european clients to a european datacenter. the address looked
+// up is the client subnet of the query (rfc 7871) if there is one, a resolver asking on behalf
+// of its clients says where they are with it, and the source address otherwise. a country's
+// records win over its continent's, and clients with neither get what the rest of the
+// handlers answer.
+//
+// the database is a binary search tree over address bits, whose leaves point into a data
+// section of self-describing values (maps, strings, numbers...), and a metadata map at the
+// end saying how the tree is laid out:
+// https://maxmind.github.io/MaxMind-DB/
+use crate::edns::EdnsOption;
+use crate::error::{DnsError, Result};
+use crate::server::Handler;
+use crate::structure::{DnsPacket, DnsRecord, Opcode, QueryType};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// where the metadata starts, searched for from the end of the file
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+// between the tree and the data section
+const DATA_SEPARATOR: usize = 16;
+// maps and arrays nest, but not this deep in a real database
+const MAX_DEPTH: usize = 32;
+
+/// A value from the data section.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    Bool(bool),
+    Float(f32),
+}
+
+impl Value {
+    /// The value at `key` of a map.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// The value at a path of keys into nested maps.
+    pub fn path(&self, keys: &[&str]) -> Option<&Value> {
+        keys.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+// reads values out of a section of the file, pointers being offsets into the same section
+struct Decoder<'a> {
+    section: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn fail(&self, what: &str) -> DnsError {
+        DnsError::GeoDatabase(what.to_string())
+    }
+
+    fn bytes(&self, pos: usize, len: usize) -> Result<&[u8]> {
+        self.section
+            .get(pos..pos + len)
+            .ok_or_else(|| self.fail("value runs past the end of the file"))
+    }
+
+    // a big endian number of `len` bytes, up to 16
+    fn number(&self, pos: usize, len: usize) -> Result<u128> {
+        if len > 16 {
+            return Err(self.fail("number too long"));
+        }
+        Ok(self
+            .bytes(pos, len)?
+            .iter()
+            .fold(0, |n, byte| (n << 8) | *byte as u128))
+    }
+
+    // the value at `pos` and where the next one starts
+    fn decode(&self, pos: usize, depth: usize) -> Result<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return Err(self.fail("values nested too deep"));
+        }
+        let ctrl = *self
+            .section
+            .get(pos)
+            .ok_or_else(|| self.fail("value runs past the end of the file"))?;
+        let mut pos = pos + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            // a pointer, whose size bits are part of the offset
+            let size = ((ctrl >> 3) & 0x3) as usize;
+            let low = (ctrl & 0x7) as u128;
+            let offset = match size {
+                0 => (low << 8) | self.number(pos, 1)?,
+                1 => ((low << 16) | self.number(pos, 2)?) + 2048,
+                2 => ((low << 24) | self.number(pos, 3)?) + 526_336,
+                _ => self.number(pos, 4)?,
+            };
+            let (value, _) = self.decode(offset as usize, depth + 1)?;
+            return Ok((value, pos + size + 1));
+        }
+        if kind == 0 {
+            kind = 7 + *self
+                .section
+                .get(pos)
+                .ok_or_else(|| self.fail("value runs past the end of the file"))?;
+            pos += 1;
+        }
+        let mut size = (ctrl & 0x1f) as usize;
+        match size {
+            29 => {
+                size = 29 + self.number(pos, 1)? as usize;
+                pos += 1;
+            }
+            30 => {
+                size = 285 + self.number(pos, 2)? as usize;
+                pos += 2;
+            }
+            31 => {
+                size = 65_821 + self.number(pos, 3)? as usize;
+                pos += 3;
+            }
+            _ => {}
+        }
+
+        let value = match kind {
+            2 => Value::String(String::from_utf8_lossy(self.bytes(pos, size)?).into_owned()),
+            3 => {
+                let bytes = self.bytes(pos, 8)?.try_into().unwrap();
+                return Ok((Value::Double(f64::from_be_bytes(bytes)), pos + 8));
+            }
+            4 => Value::Bytes(self.bytes(pos, size)?.to_vec()),
+            5 | 6 | 9 | 10 => Value::Uint(self.number(pos, size)?),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err(self.fail("map key isn't a string"));
+                    };
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    entries.push((key, value));
+                    pos = next;
+                }
+                return Ok((Value::Map(entries), pos));
+            }
+            8 => {
+                // two's complement, left padded with zeros when shorter than 4 bytes
+                Value::Int(self.number(pos, size.min(4))? as u32 as i32)
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (item, next) = self.decode(pos, depth + 1)?;
+                    items.push(item);
+                    pos = next;
+                }
+                return Ok((Value::Array(items), pos));
+            }
+            14 => return Ok((Value::Bool(size != 0), pos)),
+            15 => {
+                let bytes = self.bytes(pos, 4)?.try_into().unwrap();
+                return Ok((Value::Float(f32::from_be_bytes(bytes)), pos + 4));
+            }
+            _ => return Err(self.fail(&format!("unknown data type {}", kind))),
+        };
+        Ok((value, pos + size))
+    }
+}
+
+/// Where an address is, as far as the database knows.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// The ISO 3166-1 code of the country, like `DE`.
+    pub country: Option<String>,
+    /// The continent code, like `EU`.
+    pub continent: Option<String>,
+}
+
+/// A database in the MaxMind DB format, read into memory.
+pub struct GeoDb {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u128,
+    // where the data section starts
+    data_start: usize,
+    // the node ipv4 addresses start from in an ipv6 tree, at ::/96
+    ipv4_start: usize,
+    /// The database's type from its metadata, like `GeoLite2-Country`.
+    pub database_type: String,
+}
+
+impl fmt::Debug for GeoDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoDb")
+            .field("database_type", &self.database_type)
+            .field("node_count", &self.node_count)
+            .finish()
+    }
+}
+
+impl GeoDb {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        let fail = |what: &str| DnsError::GeoDatabase(what.to_string());
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| fail("no metadata"))?;
+        let metadata = Decoder {
+            section: &data[marker + METADATA_MARKER.len()..],
+        }
+        .decode(0, 0)?
+        .0;
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| fail(&format!("no {} in the metadata", name)))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(fail(&format!("unsupported record size {}", record_size)));
+        }
+        let database_type = metadata
+            .get("database_type")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let tree_size = record_size * 2 / 8 * node_count;
+        if tree_size + DATA_SEPARATOR > marker {
+            return Err(fail("search tree runs past the data"));
+        }
+
+        let mut db = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start: tree_size + DATA_SEPARATOR,
+            ipv4_start: 0,
+            database_type,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0);
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    // the left (bit 0) or right (bit 1) record of a node
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let bytes = self.record_size * 2 / 8;
+        let n = &self.data[node * bytes..(node + 1) * bytes];
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, byte| (n << 8) | *byte as usize);
+        match (self.record_size, bit) {
+            (24, 0) => be(&n[0..3]),
+            (24, _) => be(&n[3..6]),
+            (28, 0) => ((n[3] as usize & 0xf0) << 20) | be(&n[0..3]),
+            (28, _) => ((n[3] as usize & 0x0f) << 24) | be(&n[4..7]),
+            (_, 0) => be(&n[0..4]),
+            _ => be(&n[4..8]),
+        }
+    }
+
+    /// Everything the database has on `addr`, None if it has nothing.
+    pub fn lookup(&self, addr: IpAddr) -> Result<Option<Value>> {
+        let (bits, start): (Vec<u8>, usize) = match addr {
+            IpAddr::V4(v4) => (v4.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(v6) => (v6.octets().to_vec(), 0),
+        };
+        let mut node = start;
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit);
+        }
+        if node == self.node_count {
+            return Ok(None);
+        }
+        if node < self.node_count {
+            return Err(DnsError::GeoDatabase("search tree too deep".into()));
+        }
+        let offset = (node - self.node_count)
+            .checked_sub(DATA_SEPARATOR)
+            .ok_or_else(|| DnsError::GeoDatabase("record points into the separator".into()))?;
+        let decoder = Decoder {
+            section: &self.data[self.data_start..],
+        };
+        Ok(Some(decoder.decode(offset, 0)?.0))
+    }
+
+    /// The country and continent of `addr`. The registered country stands in when the
+    /// database doesn't know where it's used.
+    pub fn locate(&self, addr: IpAddr) -> Location {
+        let Ok(Some(value)) = self.lookup(addr) else {
+            return Location::default();
+        };
+        let code = |keys: &[&str]| value.path(keys).and_then(Value::as_str).map(str::to_string);
+        Location {
+            country: code(&["country", "iso_code"])
+                .or_else(|| code(&["registered_country", "iso_code"])),
+            continent: code(&["continent", "code"]),
+        }
+    }
+}
+
+/// The clients records are for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Region {
+    Country(String),
+    Continent(String),
+}
+
+impl FromStr for Region {
+    type Err = DnsError;
+
+    /// `country:<code>` or `continent:<code>`. Some codes are both (`AS` is Asia and American
+    /// Samoa), so which one is meant has to be said.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("country", code)) if !code.is_empty() => Ok(Region::Country(code.to_uppercase())),
+            Some(("continent", code)) if !code.is_empty() => {
+                Ok(Region::Continent(code.to_uppercase()))
+            }
+            _ => Err(DnsError::Syntax(format!(
+                "invalid region {:?}, expected country:<code> or continent:<code>",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Region::Country(code) => write!(f, "country:{}", code),
+            Region::Continent(code) => write!(f, "continent:{}", code),
+        }
+    }
+}
+
+/// Answers names that have records for the client's region from those, and asks `inner`
+/// about everything else.
+pub struct Geo<H> {
+    inner: H,
+    db: Option<Arc<GeoDb>>,
+    records: HashMap<(String, QueryType), HashMap<Region, Vec<DnsRecord>>>,
+}
+
+impl<H: Handler> Geo<H> {
+    /// Without a database nobody has a location, and everything goes to `inner`.
+    pub fn new(inner: H, db: Option<Arc<GeoDb>>) -> Self {
+        Self {
+            inner,
+            db,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Answers with `record`, and any others added for the same region, name and type, for
+    /// clients in `region`.
+    pub fn record(mut self, region: Region, record: DnsRecord) -> Self {
+        let key = (
+            record.domain().trim_end_matches('.').to_lowercase(),
+            record.qtype(),
+        );
+        self.records
+            .entry(key)
+            .or_default()
+            .entry(region)
+            .or_default()
+            .push(record);
+        self
+    }
+
+    // the address the client of `request` is at: its client subnet if it has one, with the
+    // subnet's source prefix, `src` otherwise
+    fn client(request: &DnsPacket, src: SocketAddr) -> (IpAddr, Option<u8>) {
+        let subnet = match request.edns() {
+            Some(Ok(edns)) => edns.options.iter().find_map(EdnsOption::subnet),
+            _ => None,
+        };
+        match subnet {
+            Some((addr, source, _)) => (addr, Some(source)),
+            None => (src.ip(), None),
+        }
+    }
+}
+
+impl<H: Handler> Handler for Geo<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let regions = request.questions.first().and_then(|question| {
+            let key = (
+                question.name.trim_end_matches('.').to_lowercase(),
+                question.qtype,
+            );
+            self.records.get(&key).filter(|_| question.class == 1)
+        });
+        let (Some(regions), Some(db)) = (regions, &self.db) else {
+            return self.inner.handle(request, src).await;
+        };
+        if request.header.flags.opcode != Opcode::QUERY {
+            return self.inner.handle(request, src).await;
+        }
+        let (client, source_prefix) = Self::client(&request, src);
+        let location = db.locate(client);
+        let country = location.country.map(Region::Country);
+        let continent = location.continent.map(Region::Continent);
+        let Some(records) = [country, continent]
+            .into_iter()
+            .flatten()
+            .find_map(|region| regions.get(&region))
+        else {
+            return self.inner.handle(request, src).await;
+        };
+
+        let mut res = DnsPacket::response_to(&request);
+        res.set_authoritative(true);
+        for rec in records {
+            res.add_answer(rec.clone());
+        }
+        let mut edns = DnsPacket::response_edns(&request);
+        if let (Some(edns), Some(source_prefix)) = (&mut edns, source_prefix) {
+            // the answer is for the whole subnet the resolver gave, rfc 7871 section 7.2.1
+            let mut subnet = EdnsOption::client_subnet(client, source_prefix);
+            if let EdnsOption::ClientSubnet { scope_prefix, .. } = &mut subnet {
+                *scope_prefix = source_prefix;
+            }
+            edns.options.push(subnet);
+        }
+        res.set_edns(edns.as_ref());
+        Some(res)
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.inner.transfer(request, src).await
+    }
+}
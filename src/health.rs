@@ -0,0 +1,235 @@
+// health-checked records: the addresses of a name in our zones are checked every so often,
+// by connecting to a tcp port or by fetching a page over http, and the ones failing their
+// check are left out of answers, so clients only get sent to backends that are up. a name
+// whose addresses all fail keeps all of them, an answer that might work beats one that
+// can't. addresses are healthy until their first check says otherwise, and the addresses
+// checked are whatever the zone has at the time, so zone reloads are picked up. signed RRsets
+// are left as they are, taking records out would break their signatures.
+use crate::authority::Authority;
+use crate::error::{DnsError, Result};
+use crate::logging;
+use crate::structure::{DnsPacket, DnsRecord, QueryType};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time;
+
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How an address is checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// Something accepts connections on the port.
+    Tcp(u16),
+    /// A GET for the path on the port gets a 2xx or 3xx status.
+    Http { port: u16, path: String },
+}
+
+impl FromStr for Check {
+    type Err = DnsError;
+
+    /// `tcp <port>` or `http <port> [<path>]`, the path being `/` if not given.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || DnsError::Syntax(format!("invalid health check {:?}", s));
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let port = |port: &str| port.parse().map_err(|_| invalid());
+        match words[..] {
+            ["tcp", p] => Ok(Check::Tcp(port(p)?)),
+            ["http", p] => Ok(Check::Http {
+                port: port(p)?,
+                path: "/".to_string(),
+            }),
+            ["http", p, path] if path.starts_with('/') => Ok(Check::Http {
+                port: port(p)?,
+                path: path.to_string(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Check {
+    /// Whether `addr` passes, giving up after `timeout`.
+    pub async fn run(&self, name: &str, addr: IpAddr, timeout: Duration) -> bool {
+        time::timeout(timeout, self.check(name, addr))
+            .await
+            .is_ok_and(|passed| passed.unwrap_or(false))
+    }
+
+    async fn check(&self, name: &str, addr: IpAddr) -> Result<bool> {
+        match self {
+            Check::Tcp(port) => {
+                TcpStream::connect(SocketAddr::new(addr, *port)).await?;
+                Ok(true)
+            }
+            Check::Http { port, path } => {
+                let mut stream = TcpStream::connect(SocketAddr::new(addr, *port)).await?;
+                let request = format!(
+                    "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: dns-server\r\nConnection: close\r\n\r\n",
+                    path,
+                    name.trim_end_matches('.')
+                );
+                stream.write_all(request.as_bytes()).await?;
+                // the status is all that matters, and it's in the first line
+                let mut head = [0; 64];
+                let mut len = 0;
+                while len < head.len() && !head[..len].contains(&b'\n') {
+                    match stream.read(&mut head[len..]).await? {
+                        0 => break,
+                        n => len += n,
+                    }
+                }
+                let status = String::from_utf8_lossy(&head[..len])
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|status| status.parse::<u16>().ok());
+                Ok(status.is_some_and(|status| (200..400).contains(&status)))
+            }
+        }
+    }
+}
+
+/// The names whose addresses are checked, and how the checks went.
+#[derive(Debug)]
+pub struct HealthChecks {
+    checks: Vec<(String, Check)>,
+    interval: Duration,
+    timeout: Duration,
+    failing: RwLock<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl Default for HealthChecks {
+    fn default() -> Self {
+        HealthChecks::new()
+    }
+}
+
+impl HealthChecks {
+    pub fn new() -> Self {
+        Self {
+            checks: Vec::new(),
+            interval: DEFAULT_CHECK_INTERVAL,
+            timeout: DEFAULT_CHECK_TIMEOUT,
+            failing: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the A and AAAA records at `name` with `check`.
+    pub fn monitor(mut self, name: &str, check: Check) -> Self {
+        self.checks
+            .push((name.trim_end_matches('.').to_lowercase(), check));
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checks.is_empty()
+    }
+
+    pub fn is_healthy(&self, name: &str, addr: IpAddr) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let failing = self.failing.read().unwrap();
+        !failing
+            .get(&name)
+            .is_some_and(|addrs| addrs.contains(&addr))
+    }
+
+    /// Takes the addresses that failed their checks out of the answer section of `response`,
+    /// unless that would leave an RRset empty or it's signed.
+    pub fn filter(&self, response: &mut DnsPacket) {
+        let failing = self.failing.read().unwrap();
+        if failing.is_empty() {
+            return;
+        }
+        for (name, addrs) in failing.iter() {
+            for qtype in [QueryType::A, QueryType::AAAA] {
+                let in_rrset = |rec: &DnsRecord| {
+                    let owner = rec.domain().trim_end_matches('.');
+                    rec.qtype() == qtype && owner.eq_ignore_ascii_case(name)
+                };
+                let failed = |rec: &DnsRecord| {
+                    in_rrset(rec) && rec.address().is_some_and(|addr| addrs.contains(&addr))
+                };
+                let signed = response.answers.iter().any(|rec| {
+                    matches!(rec, DnsRecord::RRSIG { type_covered, domain, .. }
+                        if *type_covered == qtype
+                            && domain.trim_end_matches('.').eq_ignore_ascii_case(name))
+                });
+                let healthy = response
+                    .answers
+                    .iter()
+                    .filter(|rec| in_rrset(rec) && !failed(rec))
+                    .count();
+                if !signed && healthy > 0 {
+                    response.answers.retain(|rec| !failed(rec));
+                }
+            }
+        }
+    }
+
+    /// Checks every address now, one round.
+    pub async fn check(&self, authority: &Authority) {
+        for (name, check) in &self.checks {
+            let Some(zone) = authority.find(name) else {
+                continue;
+            };
+            let addrs: Vec<IpAddr> = zone
+                .rrset(name, QueryType::A)
+                .chain(zone.rrset(name, QueryType::AAAA))
+                .filter_map(DnsRecord::address)
+                .collect();
+            // all at once, a round takes as long as the slowest check
+            let mut running = JoinSet::new();
+            for addr in addrs {
+                let (name, check, timeout) = (name.clone(), check.clone(), self.timeout);
+                running.spawn(async move { (addr, check.run(&name, addr, timeout).await) });
+            }
+            let mut failed = Vec::new();
+            while let Some(result) = running.join_next().await {
+                if let Ok((addr, false)) = result {
+                    failed.push(addr);
+                }
+            }
+
+            let mut failing = self.failing.write().unwrap();
+            let before = failing.remove(name).unwrap_or_default();
+            for addr in &failed {
+                if !before.contains(addr) {
+                    logging::warning(&format!("{} at {} failed its health check", name, addr));
+                }
+            }
+            for addr in &before {
+                if !failed.contains(addr) {
+                    logging::info(&format!("{} at {} is healthy again", name, addr));
+                }
+            }
+            if !failed.is_empty() {
+                failing.insert(name.clone(), failed);
+            }
+        }
+    }
+
+    /// Checks the addresses of `authority`'s zones every interval. Runs until the future is
+    /// dropped.
+    pub async fn run(&self, authority: &Authority) {
+        loop {
+            self.check(authority).await;
+            time::sleep(self.interval).await;
+        }
+    }
+}
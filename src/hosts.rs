@@ -0,0 +1,187 @@
+// names from hosts(5) files, /etc/hosts and any others, answered like dnsmasq does before
+// anything is looked up: A and AAAA queries for a name in them get its addresses, or an empty
+// answer if it only has the other kind, and PTR queries for one of their addresses get the
+// first name it's listed with. other types are looked up as usual. each line is an address
+// followed by a canonical name and its aliases, and `#` starts a comment; lines that don't
+// parse are skipped as libc does. the files are read again whenever they change (see watch.rs),
+// and a file that can't be read keeps the names it had.
+use crate::error::Result;
+use crate::logging;
+use crate::net::reverse_name;
+use crate::structure::{DnsPacket, DnsRecord, Opcode, QueryType};
+use crate::watch::Watcher;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Where the system keeps its hosts.
+pub const PATH: &str = "/etc/hosts";
+
+// as dnsmasq does, so an edited file takes effect at once
+const TTL: u32 = 0;
+
+#[derive(Default)]
+struct Table {
+    addrs: HashMap<String, Vec<IpAddr>>,
+    // reverse names to the first name their address is listed with
+    names: HashMap<String, String>,
+}
+
+/// Names and addresses from hosts files.
+pub struct Hosts {
+    paths: Vec<PathBuf>,
+    // what each file had when it was last read
+    files: Mutex<Vec<Option<String>>>,
+    table: RwLock<Arc<Table>>,
+}
+
+impl Hosts {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let files = Mutex::new(vec![None; paths.len()]);
+        Self {
+            paths,
+            files,
+            table: RwLock::new(Arc::new(Table::default())),
+        }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// How many names there are.
+    pub fn len(&self) -> usize {
+        self.table.read().unwrap().addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the files again, failing on the first that can't be read. The ones before it
+    /// are taken in anyway. Returns how many names there are.
+    pub fn reload(&self) -> Result<usize> {
+        let mut files = self.files.lock().unwrap();
+        let mut failed = None;
+        for (path, text) in self.paths.iter().zip(files.iter_mut()) {
+            match fs::read_to_string(path) {
+                Ok(new) => *text = Some(new),
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            }
+        }
+
+        let mut table = Table::default();
+        for text in files.iter().flatten() {
+            parse(text, &mut table);
+        }
+        let len = table.addrs.len();
+        *self.table.write().unwrap() = Arc::new(table);
+        match failed {
+            Some(e) => Err(e.into()),
+            None => Ok(len),
+        }
+    }
+
+    /// Reads the files again whenever they change. Runs until the future is dropped or the
+    /// files can't be watched.
+    pub async fn run(&self) -> Result<()> {
+        let watcher = Watcher::new(&self.paths)?;
+        loop {
+            watcher.changed().await?;
+            match self.reload() {
+                Ok(len) => logging::info(&format!("reloaded {} names from hosts files", len)),
+                Err(e) => logging::error(&format!("reading hosts files failed: {}", e)),
+            }
+        }
+    }
+
+    /// The addresses `name` is listed with, None if it isn't.
+    pub fn lookup(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let table = self.table.read().unwrap();
+        table
+            .addrs
+            .get(&name.trim_end_matches('.').to_lowercase())
+            .cloned()
+    }
+
+    /// The response for `request` if it's an A or AAAA query for a name in the files or a
+    /// PTR query for one of their addresses.
+    pub fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if question.class != 1 || request.header.flags.opcode != Opcode::QUERY {
+            return None;
+        }
+        let name = question.name.trim_end_matches('.').to_lowercase();
+        let table = self.table.read().unwrap().clone();
+
+        let mut res = DnsPacket::response_to(request);
+        res.set_authoritative(true).set_recursion_available(true);
+        match question.qtype {
+            QueryType::A | QueryType::AAAA => {
+                for addr in table.addrs.get(&name)? {
+                    let record = match addr {
+                        IpAddr::V4(ip) if question.qtype == QueryType::A => DnsRecord::A {
+                            domain: name.clone(),
+                            class: 1,
+                            ttl: TTL,
+                            ip: (*ip).into(),
+                        },
+                        IpAddr::V6(ip) if question.qtype == QueryType::AAAA => DnsRecord::AAAA {
+                            domain: name.clone(),
+                            class: 1,
+                            ttl: TTL,
+                            ip: (*ip).into(),
+                        },
+                        _ => continue,
+                    };
+                    res.add_answer(record);
+                }
+            }
+            QueryType::PTR => {
+                res.add_answer(DnsRecord::PTR {
+                    domain: name.clone(),
+                    class: 1,
+                    ttl: TTL,
+                    host: table.names.get(&name)?.clone(),
+                });
+            }
+            _ => return None,
+        }
+        res.set_edns(DnsPacket::response_edns(request).as_ref());
+        Some(res)
+    }
+}
+
+fn parse(text: &str, table: &mut Table) {
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(addr) = fields.next() else {
+            continue;
+        };
+        // link-local addresses may come with a zone, which has no place in an answer
+        let Ok(addr) = addr.split('%').next().unwrap_or_default().parse::<IpAddr>() else {
+            continue;
+        };
+        let mut first = true;
+        for name in fields {
+            let name = name.trim_end_matches('.').to_lowercase();
+            if first {
+                table
+                    .names
+                    .entry(reverse_name(addr))
+                    .or_insert_with(|| name.clone());
+                first = false;
+            }
+            let addrs = table.addrs.entry(name).or_default();
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// the history of a zone's changes, for incremental transfers (IXFR, rfc 1995). whenever a zone
+// is replaced by a newer version the difference between the two is kept, so a secondary with
+// an older serial can be sent just what changed since. the journal only goes back so far,
+// anyone further behind gets the whole zone instead.
+use crate::error::{DnsError, Result};
+use crate::structure::{DnsRecord, QueryType};
+use crate::zone::Zone;
+use std::collections::{BTreeSet, VecDeque};
+
+pub const DEFAULT_JOURNAL_SIZE: usize = 100;
+
+/// The difference between two versions of a zone, in the shape an incremental transfer sends
+/// it in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ZoneDiff {
+    /// The SOA of the version the difference applies to.
+    pub from: DnsRecord,
+    /// The SOA of the version it leads to.
+    pub to: DnsRecord,
+    /// The records to take out, apart from the SOA.
+    pub removed: Vec<DnsRecord>,
+    /// The records to put in, apart from the SOA.
+    pub added: Vec<DnsRecord>,
+}
+
+impl ZoneDiff {
+    /// What changed from `old` to `new`. None if either of them has no SOA.
+    pub fn between(old: &Zone, new: &Zone) -> Option<Self> {
+        let names: BTreeSet<&str> = old.names().chain(new.names()).collect();
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for name in names {
+            let before = old.get(name).unwrap_or_default();
+            let after = new.get(name).unwrap_or_default();
+            let changed = |a: &[DnsRecord], b: &[DnsRecord]| -> Vec<DnsRecord> {
+                a.iter()
+                    .filter(|rec| rec.qtype() != QueryType::SOA && !b.contains(rec))
+                    .cloned()
+                    .collect()
+            };
+            removed.extend(changed(before, after));
+            added.extend(changed(after, before));
+        }
+        Some(Self {
+            from: old.soa()?.clone(),
+            to: new.soa()?.clone(),
+            removed,
+            added,
+        })
+    }
+
+    pub fn from_serial(&self) -> Option<u32> {
+        soa_serial(&self.from)
+    }
+
+    pub fn to_serial(&self) -> Option<u32> {
+        soa_serial(&self.to)
+    }
+
+    /// Applies the difference to `zone`, which has to be the version it starts from.
+    pub fn apply(&self, zone: &Zone) -> Result<Zone> {
+        if zone.serial() != self.from_serial() {
+            return Err(DnsError::InvalidDiff(format!(
+                "it starts from serial {:?}, the zone is at {:?}",
+                self.from_serial(),
+                zone.serial()
+            )));
+        }
+        let mut zone = zone.clone();
+        for rec in &self.removed {
+            if !zone.remove(rec) {
+                return Err(DnsError::InvalidDiff(format!("{} isn't in the zone", rec)));
+            }
+        }
+        if let Some(soa) = zone.soa().cloned() {
+            zone.remove(&soa);
+        }
+        for rec in self.added.iter().chain([&self.to]) {
+            zone.insert(rec.clone())?;
+        }
+        zone.validate()?;
+        Ok(zone)
+    }
+}
+
+/// The most recent changes to one zone, oldest first, each starting where the one before
+/// ended.
+#[derive(Clone, Debug)]
+pub struct Journal {
+    diffs: VecDeque<ZoneDiff>,
+    max_diffs: usize,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Journal::new(DEFAULT_JOURNAL_SIZE)
+    }
+}
+
+impl Journal {
+    /// A journal keeping at most `max_diffs` changes.
+    pub fn new(max_diffs: usize) -> Self {
+        Self {
+            diffs: VecDeque::new(),
+            max_diffs,
+        }
+    }
+
+    /// Records the next change, forgetting the oldest once the journal is full. A change that
+    /// doesn't continue from the last one breaks the history, so everything before it goes.
+    pub fn push(&mut self, diff: ZoneDiff) {
+        if self
+            .diffs
+            .back()
+            .is_some_and(|last| last.to_serial() != diff.from_serial())
+        {
+            self.diffs.clear();
+        }
+        self.diffs.push_back(diff);
+        while self.diffs.len() > self.max_diffs {
+            self.diffs.pop_front();
+        }
+    }
+
+    /// The changes that lead from `serial` to the latest version, None if the journal doesn't
+    /// go back that far.
+    pub fn since(&self, serial: u32) -> Option<impl Iterator<Item = &ZoneDiff>> {
+        let start = self
+            .diffs
+            .iter()
+            .position(|diff| diff.from_serial() == Some(serial))?;
+        Some(self.diffs.range(start..))
+    }
+
+    pub fn clear(&mut self) {
+        self.diffs.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.diffs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diffs.is_empty()
+    }
+}
+
+pub(crate) fn soa_serial(rec: &DnsRecord) -> Option<u32> {
+    match rec {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+// serial number arithmetic, rfc 1982. serials wrap around, so a is newer than b if it's ahead
+// by less than half the number space.
+pub(crate) fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
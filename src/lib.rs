@@ -0,0 +1,87 @@
+//! A small DNS library: parsing and writing of the wire format, zone file text, EDNS options,
+//! and the pieces a resolver is built from.
+//!
+//! [`DnsPacket`] owns its data and is what most callers want. [`borrowed`] has zero-copy views
+//! for hot paths that only inspect a packet, and [`presentation`] parses records written in
+//! master file syntax.
+//!
+//! ```
+//! use dns_server::{DnsPacket, QueryType};
+//!
+//! let mut buf = DnsPacket::query("example.com", QueryType::A).into_buffer()?;
+//! buf.seek(0)?;
+//! let packet = DnsPacket::from_buf(&mut buf)?;
+//! println!("{}", packet.questions[0]);
+//! # Ok::<(), dns_server::DnsError>(())
+//! ```
+pub mod acl;
+pub mod authority;
+pub mod blocklist;
+pub mod borrowed;
+pub mod cache;
+pub mod chaos;
+pub mod client;
+pub mod config;
+pub mod control;
+pub mod ddr;
+pub mod denial_cache;
+pub mod digest;
+pub mod dns64;
+pub mod dns_sd;
+pub mod dnssec;
+pub mod doh;
+pub mod doh_server;
+pub mod doq;
+pub mod doq_server;
+pub mod dot;
+pub mod edns;
+pub mod empty_zones;
+pub mod error;
+pub mod forward;
+pub mod geoip;
+pub mod health;
+pub mod hosts;
+pub mod journal;
+pub mod json;
+pub mod limits;
+pub mod logging;
+pub mod mdns;
+pub mod metrics;
+pub mod net;
+pub mod overrides;
+pub mod presentation;
+pub mod privileges;
+pub mod querylog;
+pub mod ratelimit;
+pub mod recursive;
+pub mod refresh;
+pub mod reload;
+pub mod resolv_conf;
+pub mod rewrite;
+pub mod rotation;
+pub mod rrl;
+pub mod sampling;
+pub mod secondary;
+pub mod server;
+pub mod signals;
+pub mod signature;
+pub mod signer;
+pub mod snapshot;
+pub mod structure;
+pub mod svcb;
+pub mod systemd;
+pub mod tls;
+pub mod totals;
+pub mod tsig;
+pub mod upstreams;
+pub mod validator;
+pub mod versions;
+pub mod views;
+pub mod watch;
+pub mod zone;
+
+pub use error::{DnsError, Result};
+pub use structure::{
+    BytePacketBuffer, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, HeaderFlags, Opcode, QueryType,
+    ResultCode, MAX_MESSAGE_SIZE,
+};
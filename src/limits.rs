@@ -0,0 +1,104 @@
+// file descriptor limits. every tcp connection and every socket towards an upstream costs a
+// descriptor, and the default soft limit is often only 1024. the soft limit is raised as far as
+// the hard limit allows at startup, so running short shows up as a warning then rather than as
+// failing accepts under load.
+use crate::error::Result;
+use crate::logging;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// stdio, listening sockets, config and log files and whatever else the process keeps open
+const RESERVED_FDS: usize = 64;
+
+pub const DEFAULT_MAX_UPSTREAM_SOCKETS: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FdLimit {
+    pub soft: usize,
+    pub hard: usize,
+}
+
+pub fn fd_limit() -> Result<FdLimit> {
+    let mut lim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: lim is a valid rlimit for getrlimit to fill in
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(FdLimit {
+        soft: usize::try_from(lim.rlim_cur).unwrap_or(usize::MAX),
+        hard: usize::try_from(lim.rlim_max).unwrap_or(usize::MAX),
+    })
+}
+
+/// Raises the soft descriptor limit so `sockets` sockets can be open at once, as far as the
+/// hard limit allows. Returns how many sockets fit under the resulting limit, which is less
+/// than asked for if the hard limit is in the way.
+pub fn reserve_fds(sockets: usize) -> Result<usize> {
+    let limit = fd_limit()?;
+    let wanted = sockets.saturating_add(RESERVED_FDS);
+    if limit.soft < wanted {
+        let soft = wanted.min(limit.hard);
+        let lim = libc::rlimit {
+            rlim_cur: soft as libc::rlim_t,
+            rlim_max: limit.hard as libc::rlim_t,
+        };
+        // SAFETY: lim is a valid rlimit and the soft limit doesn't exceed the hard one
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lim) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    let available = fd_limit()?.soft.saturating_sub(RESERVED_FDS);
+    if available < sockets {
+        logging::warning(&format!(
+            "file descriptor limit allows {} sockets but {} are configured",
+            available, sockets
+        ));
+    }
+    Ok(available)
+}
+
+/// Whether an error means the process or the system is out of descriptors. Those go away once
+/// something is closed, so callers should back off and retry rather than give up.
+pub fn is_fd_exhaustion(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+/// Caps the number of sockets open towards upstreams at once. Queries that need another socket
+/// wait for one to be released instead of failing once descriptors run out.
+#[derive(Clone, Debug)]
+pub struct UpstreamSockets {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for UpstreamSockets {
+    fn default() -> Self {
+        UpstreamSockets::new(DEFAULT_MAX_UPSTREAM_SOCKETS)
+    }
+}
+
+impl UpstreamSockets {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// Waits for room for one more socket. The socket should be closed before the permit is
+    /// dropped.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+}
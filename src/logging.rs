@@ -0,0 +1,585 @@
+// log sinks. everything goes to stderr unless a stream is pointed somewhere else: syslog as
+// rfc 5424 messages over udp or a unix socket, journald over its native protocol, which
+// keeps the fields of a record as separate, searchable journal fields, or a file of our own,
+// rotated once it's big or old enough. the server's own messages, from wherever in the library
+// they come, go through the logger installed for the process (see install) on the server
+// stream, leaving out the ones less severe than its level.
+use crate::error::{DnsError, Result};
+use crate::net::parse_socket_addr;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+/// Where syslog daemons listen on udp, rfc 5426.
+pub const SYSLOG_PORT: u16 = 514;
+
+// the enterprise number rfc 5612 sets aside for documentation, used as the SD-ID suffix of our
+// structured data
+const SD_ID: &str = "dns@32473";
+// rfc 5424 section 6.2.1
+const FACILITY_DAEMON: u8 = 3;
+/// How many rotated files are kept next to a log file if not said otherwise.
+pub const DEFAULT_KEEP: usize = 5;
+
+// the logger info, warning and error go through, see install
+static INSTALLED: RwLock<Option<Logger>> = RwLock::new(None);
+
+/// Syslog severities, which journald uses for its PRIORITY field too.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    #[default]
+    Info = 6,
+    Debug = 7,
+}
+
+impl FromStr for Severity {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(Severity::Error),
+            "warning" => Ok(Severity::Warning),
+            "info" => Ok(Severity::Info),
+            "debug" => Ok(Severity::Debug),
+            _ => Err(DnsError::Syntax(format!(
+                "unknown log level {:?}, expected error, warning, info or debug",
+                s
+            ))),
+        }
+    }
+}
+
+/// The separate kinds of log output, each of which can go to its own sink.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stream {
+    /// One record per answered query.
+    Query,
+    /// Everything else: startup, failures, warnings.
+    Server,
+}
+
+impl FromStr for Stream {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "queries" => Ok(Stream::Query),
+            "server" => Ok(Stream::Server),
+            _ => Err(DnsError::Syntax(format!("unknown log stream {:?}", s))),
+        }
+    }
+}
+
+/// Where a stream goes, as config files name it: `stderr`, `journald`, `syslog` followed by
+/// the daemon's socket or udp address, [`SYSLOG_SOCKET`] if neither is given, or `file`
+/// followed by its path and when it's rotated, see [`Rollover`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Stderr,
+    Journald,
+    SyslogUnix(PathBuf),
+    SyslogUdp(SocketAddr),
+    File(PathBuf, Rollover),
+}
+
+/// When a log file is moved aside for a new one: `size <bytes>`, `every <interval>` (with
+/// units like ttls) and how many of the old ones are kept, `keep <count>`. The newest is the
+/// path with `.1` added, the one before it `.2`, and so on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rollover {
+    pub max_bytes: Option<u64>,
+    pub every: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Default for Rollover {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            every: None,
+            keep: DEFAULT_KEEP,
+        }
+    }
+}
+
+impl LogTarget {
+    /// Opens the sink, under `app_name` for syslog and journald.
+    pub fn open(&self, app_name: &str) -> Result<Arc<dyn LogSink>> {
+        Ok(match self {
+            LogTarget::Stderr => Arc::new(StderrSink),
+            LogTarget::Journald => Arc::new(JournaldSink::new(app_name)?),
+            LogTarget::SyslogUnix(path) => Arc::new(SyslogSink::unix(path, app_name)?),
+            LogTarget::SyslogUdp(addr) => Arc::new(SyslogSink::udp(*addr, app_name)?),
+            LogTarget::File(path, rollover) => Arc::new(FileSink::open(path, *rollover)?),
+        })
+    }
+}
+
+impl FromStr for LogTarget {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut args = s.split_whitespace();
+        let target = match (args.next(), args.next(), args.next()) {
+            (Some("stderr"), None, None) => LogTarget::Stderr,
+            (Some("journald"), None, None) => LogTarget::Journald,
+            (Some("syslog"), None, None) => LogTarget::SyslogUnix(PathBuf::from(SYSLOG_SOCKET)),
+            // a path starts with a slash, anything else is where the daemon listens on udp
+            (Some("syslog"), Some(path), None) if path.starts_with('/') => {
+                LogTarget::SyslogUnix(PathBuf::from(path))
+            }
+            (Some("syslog"), Some(addr), None) => {
+                LogTarget::SyslogUdp(parse_socket_addr(addr, SYSLOG_PORT)?)
+            }
+            (Some("file"), Some(path), setting) => {
+                let mut rollover = Rollover::default();
+                let mut setting = setting;
+                while let Some(name) = setting {
+                    let Some(value) = args.next() else {
+                        return Err(DnsError::Syntax(format!("log file {} needs a value", name)));
+                    };
+                    let invalid =
+                        || DnsError::Syntax(format!("invalid log file {} {:?}", name, value));
+                    match name {
+                        "size" => rollover.max_bytes = Some(value.parse().map_err(|_| invalid())?),
+                        "every" => {
+                            let secs =
+                                crate::presentation::parse_ttl(value).map_err(|_| invalid())?;
+                            rollover.every = Some(Duration::from_secs(secs.max(1) as u64));
+                        }
+                        "keep" => rollover.keep = value.parse().map_err(|_| invalid())?,
+                        _ => {
+                            return Err(DnsError::Syntax(format!(
+                                "unknown log file setting {:?}, expected size, every or keep",
+                                name
+                            )))
+                        }
+                    }
+                    setting = args.next();
+                }
+                return Ok(LogTarget::File(PathBuf::from(path), rollover));
+            }
+            _ => return Err(DnsError::Syntax(format!("unknown log target {:?}", s))),
+        };
+        Ok(target)
+    }
+}
+
+pub struct LogRecord<'a> {
+    pub severity: Severity,
+    pub message: &'a str,
+    /// Extra key/value pairs. Keys should be lowercase ascii, journald gets them uppercased and
+    /// prefixed with `DNS_`.
+    pub fields: &'a [(&'a str, String)],
+}
+
+pub trait LogSink: Send + Sync {
+    fn log(&self, record: &LogRecord) -> Result<()>;
+}
+
+/// Writes `message key=value ...` lines to stderr.
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn log(&self, record: &LogRecord) -> Result<()> {
+        let mut line = record.message.to_string();
+        for (key, value) in record.fields {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        eprintln!("{}", line);
+        Ok(())
+    }
+}
+
+/// Appends `timestamp message key=value ...` lines to a file, moving it aside for a new one
+/// when its [`Rollover`] says. Rotating renames files in the file's directory and creates a new
+/// one there, so the directory has to stay writable for whoever the server runs as.
+pub struct FileSink {
+    path: PathBuf,
+    rollover: Rollover,
+    file: Mutex<Open>,
+}
+
+// the file being written and what's needed to tell when it's due
+struct Open {
+    file: File,
+    len: u64,
+    since: SystemTime,
+}
+
+impl FileSink {
+    pub fn open(path: impl AsRef<Path>, rollover: Rollover) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let open = Self::append(&path)?;
+        Ok(Self {
+            path,
+            rollover,
+            file: Mutex::new(open),
+        })
+    }
+
+    // a file that's there is carried on with, counting what it has towards its size
+    fn append(path: &Path) -> io::Result<Open> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Open {
+            len: file.metadata()?.len(),
+            file,
+            since: SystemTime::now(),
+        })
+    }
+
+    // an empty file stays however big the line is, rotating it only leaves an empty one behind
+    fn is_due(&self, open: &Open, len: usize) -> bool {
+        let full = (self.rollover.max_bytes).is_some_and(|max| open.len + len as u64 > max);
+        let old = (self.rollover.every)
+            .is_some_and(|every| open.since.elapsed().unwrap_or_default() >= every);
+        open.len > 0 && (full || old)
+    }
+
+    // path.1 becomes path.2 and so on, the oldest falling off the end
+    fn rotate(&self) -> io::Result<Open> {
+        let numbered = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.rollover.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rollover.keep).rev() {
+                match fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        Self::append(&self.path)
+    }
+}
+
+impl LogSink for FileSink {
+    fn log(&self, record: &LogRecord) -> Result<()> {
+        let mut line = format!("{} {}", timestamp(SystemTime::now()), record.message);
+        for (key, value) in record.fields {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line.push('\n');
+        let mut open = self.file.lock().unwrap();
+        if self.is_due(&open, line.len()) {
+            *open = self.rotate()?;
+        }
+        // a file's age counts from its first line, an idle one isn't old yet
+        if open.len == 0 {
+            open.since = SystemTime::now();
+        }
+        // one write per line, so lines from threads and other writers don't mix
+        open.file.write_all(line.as_bytes())?;
+        open.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+enum SyslogSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+/// Sends rfc 5424 messages to a syslog daemon, with the record's fields as structured data.
+pub struct SyslogSink {
+    socket: SyslogSocket,
+    hostname: String,
+    app_name: String,
+    facility: u8,
+}
+
+impl SyslogSink {
+    pub fn udp(server: SocketAddr, app_name: &str) -> Result<Self> {
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        Ok(Self::with_socket(SyslogSocket::Udp(socket), app_name))
+    }
+
+    /// Usually [`SYSLOG_SOCKET`].
+    pub fn unix(path: impl AsRef<Path>, app_name: &str) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self::with_socket(SyslogSocket::Unix(socket), app_name))
+    }
+
+    fn with_socket(socket: SyslogSocket, app_name: &str) -> Self {
+        Self {
+            socket,
+            hostname: hostname().unwrap_or_else(|| "-".into()),
+            app_name: app_name.to_string(),
+            facility: FACILITY_DAEMON,
+        }
+    }
+
+    /// The facility code, daemon (3) unless set.
+    pub fn facility(mut self, facility: u8) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    // <PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+    fn format(&self, record: &LogRecord) -> String {
+        let pri = self.facility as u16 * 8 + record.severity as u16;
+        let data = if record.fields.is_empty() {
+            "-".to_string()
+        } else {
+            let params: String = record
+                .fields
+                .iter()
+                .map(|(key, value)| format!(" {}=\"{}\"", key, escape_param(value)))
+                .collect();
+            format!("[{}{}]", SD_ID, params)
+        };
+        format!(
+            "<{}>1 {} {} {} {} - {} {}",
+            pri,
+            timestamp(SystemTime::now()),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            data,
+            record.message
+        )
+    }
+}
+
+impl LogSink for SyslogSink {
+    fn log(&self, record: &LogRecord) -> Result<()> {
+        let msg = self.format(record);
+        match &self.socket {
+            SyslogSocket::Udp(socket) => socket.send(msg.as_bytes())?,
+            SyslogSocket::Unix(socket) => socket.send(msg.as_bytes())?,
+        };
+        Ok(())
+    }
+}
+
+/// Sends records to systemd-journald over its native protocol.
+pub struct JournaldSink {
+    socket: UnixDatagram,
+    identifier: String,
+}
+
+impl JournaldSink {
+    /// Connects to the journal at [`JOURNALD_SOCKET`].
+    pub fn new(identifier: &str) -> Result<Self> {
+        Self::with_path(JOURNALD_SOCKET, identifier)
+    }
+
+    pub fn with_path(path: impl AsRef<Path>, identifier: &str) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket,
+            identifier: identifier.to_string(),
+        })
+    }
+}
+
+impl LogSink for JournaldSink {
+    fn log(&self, record: &LogRecord) -> Result<()> {
+        let mut msg = Vec::new();
+        journal_field(&mut msg, "MESSAGE", record.message);
+        journal_field(&mut msg, "PRIORITY", &(record.severity as u8).to_string());
+        journal_field(&mut msg, "SYSLOG_IDENTIFIER", &self.identifier);
+        for (key, value) in record.fields {
+            journal_field(&mut msg, &journal_key(key), value);
+        }
+        // records too big for one datagram would have to go through a memfd, query log
+        // records never get close
+        self.socket.send(&msg)?;
+        Ok(())
+    }
+}
+
+/// Routes each [`Stream`] to its sink, stderr for the ones without.
+#[derive(Clone, Default)]
+pub struct Logger {
+    sinks: HashMap<Stream, Arc<dyn LogSink>>,
+    level: Severity,
+}
+
+impl Logger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sink(mut self, stream: Stream, sink: Arc<dyn LogSink>) -> Self {
+        self.sinks.insert(stream, sink);
+        self
+    }
+
+    /// Leaves out the records on the server stream less severe than `level`, [`Severity::Info`]
+    /// if not given. The query stream has its own sampling, see querylog.rs.
+    pub fn level(mut self, level: Severity) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Whether the stream was given a sink, stderr or not.
+    pub fn has_sink(&self, stream: Stream) -> bool {
+        self.sinks.contains_key(&stream)
+    }
+
+    /// Logs `record` to the stream's sink. A sink that fails doesn't lose the record, it's
+    /// written to stderr along with the reason.
+    pub fn log(&self, stream: Stream, record: &LogRecord) {
+        if stream == Stream::Server && record.severity > self.level {
+            return;
+        }
+        let Some(sink) = self.sinks.get(&stream) else {
+            let _ = StderrSink.log(record);
+            return;
+        };
+        if let Err(e) = sink.log(record) {
+            eprintln!("log sink failed: {}", e);
+            let _ = StderrSink.log(record);
+        }
+    }
+}
+
+/// Has [`info`], [`warning`] and [`error`] go through `logger` from now on, rather than to
+/// stderr.
+pub fn install(logger: Logger) {
+    *INSTALLED.write().unwrap() = Some(logger);
+}
+
+/// Logs `message` on the server stream of the installed logger, see [`install`].
+pub fn server(severity: Severity, message: &str) {
+    let record = LogRecord {
+        severity,
+        message,
+        fields: &[],
+    };
+    match &*INSTALLED.read().unwrap() {
+        Some(logger) => logger.log(Stream::Server, &record),
+        None => Logger::default().log(Stream::Server, &record),
+    }
+}
+
+/// Logs what the server is up to, see [`server`].
+pub fn info(message: &str) {
+    server(Severity::Info, message);
+}
+
+/// Logs something that went wrong but that the server gets by without, see [`server`].
+pub fn warning(message: &str) {
+    server(Severity::Warning, message);
+}
+
+/// Logs a failure, see [`server`].
+pub fn error(message: &str) {
+    server(Severity::Error, message);
+}
+
+// fields are KEY=value lines, values that contain a newline use the binary form: the key, a
+// newline, the value's length as a little endian u64, the value and a newline
+fn journal_field(msg: &mut Vec<u8>, key: &str, value: &str) {
+    msg.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        msg.push(b'\n');
+        msg.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        msg.push(b'=');
+    }
+    msg.extend_from_slice(value.as_bytes());
+    msg.push(b'\n');
+}
+
+// journal field names are uppercase letters, digits and underscores. the prefix keeps them from
+// starting with an underscore, those are trusted fields only journald sets.
+fn journal_key(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    format!("DNS_{}", key)
+}
+
+// rfc 5424 section 6.3.3, '"', '\' and ']' have to be escaped in param values
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// rfc 3339 in utc with milliseconds, e.g. 2024-05-01T12:00:00.000Z
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// days since 1970-01-01 to a gregorian date, from howard hinnant's chrono-compatible
+// algorithms
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+// the inverse of civil_from_days
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub(crate) fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let name = CStr::from_bytes_until_nul(&buf).ok()?;
+    Some(name.to_string_lossy().into_owned())
+}
@@ -1,30 +1,1576 @@
 use anyhow::Result;
-mod structure;
-use std::fs::File;
-use std::io::Read;
-use structure::{BytePacketBuffer, DnsPacket};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use dns_server::acl::{self, Acl, Capability, Restricted};
+use dns_server::authority::Authority;
+use dns_server::blocklist::{self, BlockAction, Blocklist, Group, Policy, Rule, Source};
+use dns_server::cache::{Cache, Cached};
+use dns_server::chaos::Chaos;
+use dns_server::client::Client;
+use dns_server::config::{Config, ConfigDiff, ViewConfig};
+use dns_server::control::{self, Commands, Control};
+use dns_server::ddr::Designated;
+use dns_server::dns64::Dns64;
+use dns_server::doh::{self, DohUrl, HttpsUpstream};
+use dns_server::doh_server::HttpsServer;
+use dns_server::doq::QuicUpstream;
+use dns_server::doq_server::QuicServer;
+use dns_server::dot::TlsUpstream;
+use dns_server::edns::EcsPolicy;
+use dns_server::empty_zones::EmptyZones;
+use dns_server::forward::{ConditionalForwarder, Forwarder};
+use dns_server::geoip::{Geo, GeoDb};
+use dns_server::health::HealthChecks;
+use dns_server::hosts::{self, Hosts};
+use dns_server::json::Value as Json;
+use dns_server::limits::{reserve_fds, DEFAULT_MAX_UPSTREAM_SOCKETS};
+use dns_server::logging::{self, Logger, Stream};
+use dns_server::mdns::{self, MdnsProxy, Responder};
+use dns_server::metrics::QueryCounters;
+use dns_server::net::{parse_socket_addr, Subnet};
+use dns_server::overrides::{LocalRecords, Overrides};
+use dns_server::presentation::parse_ttl;
+use dns_server::privileges::{self, User};
+use dns_server::querylog::QueryLog;
+use dns_server::ratelimit::{Limited, QueryLimiter};
+use dns_server::recursive::Resolver;
+use dns_server::reload::Reloadable;
+use dns_server::resolv_conf::{self, ResolvConf};
+use dns_server::rewrite::{AnswerRewriter, Rewrite};
+use dns_server::rotation::Rotator;
+use dns_server::sampling::QuerySampler;
+use dns_server::server::{BlockingServer, Handler, DEFAULT_MAX_CONNECTIONS};
+use dns_server::signals::{self, Signals, SIGHUP, SIGINT, SIGTERM};
+use dns_server::snapshot;
+use dns_server::structure::{is_subdomain, DEFAULT_EDNS_PAYLOAD, MAX_MESSAGE_SIZE};
+use dns_server::systemd::{self, Activated};
+use dns_server::tls::{ServerCertificate, TrustAnchors};
+use dns_server::totals::{Totals, DEFAULT_SAVE_INTERVAL};
+use dns_server::upstreams::DEFAULT_MAX_CASE_MISMATCHES;
+use dns_server::validator::Validator;
+use dns_server::versions::{ZoneVersions, DEFAULT_ZONE_VERSIONS};
+use dns_server::views::{View, Views};
+use dns_server::watch::Watcher;
+use dns_server::zone::Zone;
+use dns_server::{BytePacketBuffer, DnsError, DnsPacket, QueryType};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime;
+use tokio::task::JoinHandle;
+use tokio::time;
 
+/// A DNS server, and a few tools to go with it.
+#[derive(Parser)]
+#[command(name = "dns-server", version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    // serving is what's done without a subcommand, as before there were others
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Answer on the listen address, 0.0.0.0:53 if not given (the default)
+    Serve(ServeArgs),
+    /// Print a DNS message read from a file as it came off the wire
+    Parse {
+        #[arg(value_name = "PACKET FILE")]
+        packet: PathBuf,
+    },
+    /// Ask a server for a name's records and print the answer, like a small dig
+    Query(QueryArgs),
+    /// Send a command to a running server's control socket and print the answer
+    ///
+    /// help lists the commands. zone <zone> prints a zone's records, how many there are of
+    /// each type, its serial, size and how it's signed, zone-versions <zone> lists the serials
+    /// of the versions kept of a zone, and rollback <zone> [<serial>] serves an earlier one, the
+    /// one before if no serial is given. a zone in a view other than the default is given as
+    /// <view>/<zone>. stats prints the queries, blocked queries and cache counters since the
+    /// server started, or since the counters file was first saved.
+    Control {
+        socket: PathBuf,
+        #[arg(required = true, num_args = 1.., value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+}
+
+// the flags of serve, as given. the lists and rules belong to the last --group before them,
+// which takes clap's indices to tell, see ServeArgs::resolve
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// The listen address, then upstreams to forward to
+    #[arg(value_name = "ADDRESS", value_parser = port_53)]
+    addrs: Vec<SocketAddr>,
+    /// Instead of the first address without a flag
+    #[arg(long, value_name = "ADDRESS", value_parser = port_53)]
+    listen: Option<SocketAddr>,
+    /// Forward to it, can be given more than once
+    #[arg(long = "upstream", value_name = "ADDRESS", value_parser = port_53)]
+    upstreams: Vec<SocketAddr>,
+    /// Read the config file, TOML if it ends in .toml
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Don't validate under the domain for a while
+    #[arg(long = "negative-anchor", value_name = "DOMAIN[=LIFETIME]", value_parser = negative_anchor)]
+    negative_anchors: Vec<(String, Duration)>,
+    /// How blocked names are answered
+    #[arg(long = "block-with", value_name = "nxdomain|null|ADDRESS")]
+    block_action: Option<BlockAction>,
+    /// The TTL of those answers, 10 seconds if not given
+    #[arg(long = "block-ttl", value_name = "TTL", value_parser = parse_ttl)]
+    block_ttl: Option<u32>,
+    /// Block the names on the list
+    #[arg(long = "blocklist", value_name = "FILE OR HTTP URL", value_parser = source)]
+    blocklists: Vec<Source>,
+    /// Don't block the names on the list
+    #[arg(long = "allowlist", value_name = "FILE OR HTTP URL", value_parser = source)]
+    allowlists: Vec<Source>,
+    /// Don't block the name
+    #[arg(long = "allow", value_name = "RULE", value_parser = Rule::parse)]
+    allowed: Vec<Rule>,
+    /// The lists and rules after it are for these clients
+    #[arg(long = "group", value_name = "NAME=SUBNET[,SUBNET...]", value_parser = group)]
+    groups: Vec<GroupArgs>,
+}
+
+#[derive(clap::Args)]
+struct QueryArgs {
+    name: String,
+    /// A if not given
+    #[arg(value_name = "TYPE")]
+    qtype: Option<QueryType>,
+    /// The first nameserver in /etc/resolv.conf if not given
+    #[arg(long, value_name = "ADDRESS", value_parser = port_53)]
+    server: Option<SocketAddr>,
+    #[arg(long)]
+    tcp: bool,
+    #[arg(long)]
+    dnssec: bool,
+}
+
+// how long the queries being answered get to finish once SIGTERM or SIGINT comes, as long as a
+// handler gets for one anyway
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+// how long a negative trust anchor lasts if no lifetime is given, as in bind
+const DEFAULT_NEGATIVE_ANCHOR_LIFETIME: Duration = Duration::from_secs(3600);
+
+// what the command line says
+#[derive(Default)]
+struct Args {
+    config: Option<PathBuf>,
+    listen: Option<SocketAddr>,
+    upstreams: Vec<SocketAddr>,
+    negative_anchors: Vec<(String, Duration)>,
+    // the lists and rules of the clients not in a group first, then each group's
+    groups: Vec<GroupArgs>,
+    block_action: Option<BlockAction>,
+    block_ttl: Option<u32>,
+}
+
+// the filtering flags given for a group of clients
+#[derive(Clone, Debug, Default, PartialEq)]
+struct GroupArgs {
+    name: String,
+    clients: Vec<Subnet>,
+    blocklists: Vec<Source>,
+    allowlists: Vec<Source>,
+    allowed: Vec<Rule>,
+}
+
+// what every view's handler is built with
+struct Shared {
+    upstream_sockets: usize,
+    edns_payload: u16,
+    client_subnet: EcsPolicy,
+    randomize_case: bool,
+    max_case_mismatches: f64,
+    // how many upstreams a query goes to at once, and how far apart
+    race: (usize, Duration),
+    negative_anchors: Vec<(String, Duration)>,
+    policy: Arc<Policy>,
+    acl: Arc<Acl>,
+    geoip: Option<Arc<GeoDb>>,
+    hosts: Arc<Hosts>,
+    counters: Arc<QueryCounters>,
+}
+
+// what's set up once at the start and stays that way through reloads
+struct Fixed {
+    listen: SocketAddr,
+    upstream_sockets: usize,
+    // the listeners the config file asked for, to tell when it asks for others
+    listeners: Listeners,
+    // the log streams' sinks, the query log's among them if it has one
+    logger: Logger,
+    control: Option<PathBuf>,
+    counters: Option<(PathBuf, Option<Duration>)>,
+    // what every handler counts into, so nothing a reload replaces takes its counts along
+    totals: Arc<Totals>,
+}
+
+type Listeners = (
+    Option<SocketAddr>,
+    Option<(SocketAddr, PathBuf, PathBuf, String)>,
+    Option<(SocketAddr, Option<(PathBuf, PathBuf)>)>,
+);
+
+// what a reload keeps from the handler before if it would be built the same: each view's
+// cache, by what its answers come from, and the blocking policy, by its groups, so that
+// answers aren't forgotten and lists aren't fetched again for nothing
+#[derive(Default)]
+struct Kept {
+    caches: HashMap<String, (String, Arc<Cache>)>,
+    policy: Option<(Lists, Arc<Policy>)>,
+    // where each view's cache is saved when the server stops, the ones of the latest config
+    snapshots: Vec<(PathBuf, Arc<Cache>)>,
+    // the versions of each view's zones, so they can still be rolled back after a reload
+    versions: HashMap<String, Arc<ZoneVersions>>,
+}
+
+// each view's authority by the view's name, the default view's first
+type Authorities = Vec<(String, Arc<Authority>)>;
+
+// every group's lists and rules, and what blocked names get
+type Lists = (Vec<GroupArgs>, BlockAction, u32);
+
+// a handler and what it was built from
+struct Built<H> {
+    handler: H,
+    // the config file, zone files and local lists, a change to any of which reloads
+    files: Vec<PathBuf>,
+    // for the control socket
+    authorities: Authorities,
+    background: Background,
+}
+
+// what runs alongside a handler
+struct Background {
+    health_checks: Vec<(Arc<HealthChecks>, Arc<Authority>)>,
+    hosts: Arc<Hosts>,
+    policy: Arc<Policy>,
+    // whether the policy is a new one rather than the one before, whose lists are kept up to
+    // date already
+    new_policy: bool,
+}
+
+// how the last reload went, for the control socket
+struct LastReload {
+    // unix seconds
+    at: u64,
+    trigger: &'static str,
+    // what it changed, or why it didn't
+    outcome: std::result::Result<ConfigDiff, String>,
+}
+
+impl LastReload {
+    fn to_json(last: &Option<LastReload>) -> Json {
+        let Some(last) = last else {
+            return Json::object([("status", "none".into())]);
+        };
+        let (status, detail) = match &last.outcome {
+            Ok(diff) => ("ok", ("diff", diff.to_json())),
+            Err(e) => ("failed", ("error", e.as_str().into())),
+        };
+        Json::object([
+            ("status", status.into()),
+            ("at", last.at.into()),
+            ("trigger", last.trigger.into()),
+            detail,
+        ])
+    }
+}
+
+// the tasks running alongside the current handler, stopped when it's replaced
+#[derive(Default)]
+struct Tasks {
+    handler: Vec<JoinHandle<()>>,
+    blocklists: Vec<JoinHandle<()>>,
+}
+
+impl Tasks {
+    // has to be called from within the runtime. `refreshed` is whether a new policy's lists
+    // have been fetched already
+    fn start(&mut self, background: Background, refreshed: bool) {
+        for task in self.handler.drain(..) {
+            task.abort();
+        }
+        for (health, authority) in background.health_checks {
+            let task = tokio::spawn(async move { health.run(&authority).await });
+            self.handler.push(task);
+        }
+        let hosts = background.hosts;
+        self.handler.push(tokio::spawn(async move {
+            if let Err(e) = hosts.run().await {
+                logging::warning(&format!("not watching hosts files: {}", e));
+            }
+        }));
+        if !background.new_policy {
+            return;
+        }
+        for task in self.blocklists.drain(..) {
+            task.abort();
+        }
+        for blocklist in background.policy.blocklists() {
+            self.blocklists.push(tokio::spawn(async move {
+                if refreshed {
+                    time::sleep(blocklist::DEFAULT_REFRESH).await;
+                }
+                blocklist.run(None).await
+            }));
+        }
+    }
+}
+
+// the command line is in Cli. the config file is read as TOML if its name ends in .toml, see
+// config.rs. the listen address and upstreams given on the command line win over the config
+// file's, and without either the nameservers in /etc/resolv.conf are, unless the config file
+// says otherwise. names under a negative anchor aren't validated until its lifetime is up.
+// names on the blocklists but not on the allowlists are answered with the unspecified address
+// unless --block-with or the config file says otherwise, with a TTL of 10 seconds unless
+// --block-ttl or the config file says otherwise, and the lists are fetched again every day. the
+// lists and rules given before any --group are for clients not in a group, the ones after it
+// for the clients in its subnets. the config file's access lists say who may do what, by
+// default anybody may query, local clients get recursion, and nobody gets transfers or updates.
+// responses over udp and each client's queries are only rate limited if the config file says
+// so. geo records need the config file to name a geoip database. on SIGHUP, or when the config
+// file, a zone file or a local list changes, everything is read again and new queries go to
+// what it says, while queries already in flight finish as they were. what a reload changed is
+// logged, and the config file's control socket tells how the last one went and rolls zones back
+// to the versions before. a config file that doesn't load changes nothing, and the listeners,
+// rate limits, multicast DNS and logs stay as they were until a restart. on SIGTERM or SIGINT
+// nothing new is taken, the queries being answered get a few seconds to finish, and the caches
+// and counters are saved if the config file says where. started by systemd, the listeners take
+// the sockets it passed in for their addresses, DNS the ones left if it has no address of its
+// own, and systemd is told when the server is ready, reloading and stopping, and that it's
+// still alive if the unit has a watchdog, see systemd.rs
 fn main() -> Result<()> {
-    let mut f = File::open("response_packet.txt")?;
-    let mut buffer = BytePacketBuffer::new();
-    #[allow(clippy::unused_io_amount)]
-    f.read(&mut buffer.buf)?;
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+    match cli.command {
+        Some(Command::Serve(args)) => {
+            let matches = matches.subcommand_matches("serve").unwrap();
+            serve(args.resolve(matches)?)
+        }
+        Some(Command::Parse { packet }) => parse(&packet),
+        Some(Command::Query(args)) => query(args),
+        Some(Command::Control { socket, command }) => control_request(&socket, &command),
+        None => serve(cli.serve.resolve(&matches)?),
+    }
+}
 
-    let packet = DnsPacket::from_buf(&mut buffer)?;
-    println!("{:#?}", packet.header);
+fn serve(args: Args) -> Result<()> {
+    // the reloader and shutdown take their signals through a signalfd, which only works if no
+    // thread takes them the usual way, so they're blocked before there are any others
+    signals::block(&[SIGHUP, SIGTERM, SIGINT])?;
+    let mut activated = Activated::from_env()?;
+    let config = load_config(&args)?;
+    // the password database is looked up before anything's bound, a user that isn't there
+    // shouldn't leave the server running as root
+    let user = match (&config.user, &config.chroot) {
+        (Some(name), _) => match User::lookup(name) {
+            Ok(user) => Some(user),
+            Err(e) => anyhow::bail!("can't run as {}: {}", name, e),
+        },
+        (None, Some(_)) => anyhow::bail!("chroot needs a user to run as"),
+        (None, None) => None,
+    };
+    let mut logger = Logger::new().level(config.log_level.unwrap_or_default());
+    for (stream, target) in &config.logs {
+        let sink = match target.open("dns-server") {
+            Ok(sink) => sink,
+            Err(e) => anyhow::bail!("can't log to {:?}: {}", target, e),
+        };
+        logger = logger.sink(*stream, sink);
+    }
+    // from here on the server's messages go where the config file says
+    logging::install(logger.clone());
+    // sockets systemd bound go to the listeners configured for their address, and the DNS
+    // listener takes the ones left if it has no address of its own
+    let https_socket =
+        (config.listen_https.as_ref()).and_then(|https| activated.tcp(Some(https.0)));
+    let quic_socket = (config.listen_quic.as_ref()).and_then(|quic| activated.udp(Some(quic.0)));
+    let listen = args.listen.or(config.listen);
+    let sockets = match (activated.udp(listen), activated.tcp(listen)) {
+        (Some(udp), Some(tcp)) => Some((udp, tcp)),
+        (None, None) => None,
+        _ => anyhow::bail!("systemd passed in a udp or a tcp socket for DNS, but not both"),
+    };
+    for addr in activated.left() {
+        match addr {
+            Ok(addr) => logging::warning(&format!(
+                "not using the socket systemd passed in for {}",
+                addr
+            )),
+            Err(e) => logging::warning(&format!("not using a socket systemd passed in: {}", e)),
+        }
+    }
+    let addr = match &sockets {
+        Some((udp, _)) => udp.local_addr()?,
+        None => listen.unwrap_or(SocketAddr::from(([0, 0, 0, 0], 53))),
+    };
+
+    // tcp and https connections and upstream sockets split whatever the descriptor limit
+    // allows
+    let listeners = 1 + config.listen_https.is_some() as usize;
+    let fds = reserve_fds(listeners * DEFAULT_MAX_CONNECTIONS + DEFAULT_MAX_UPSTREAM_SOCKETS)?;
+    let upstream_sockets = DEFAULT_MAX_UPSTREAM_SOCKETS.min(fds / 2).max(1);
+    let edns_payload = config.edns_payload.unwrap_or(DEFAULT_EDNS_PAYLOAD);
+    let connections = DEFAULT_MAX_CONNECTIONS
+        .min(fds.saturating_sub(upstream_sockets))
+        .max(1);
+    let fixed = Fixed {
+        listen: addr,
+        upstream_sockets,
+        listeners: (
+            config.listen,
+            config.listen_https.clone(),
+            config.listen_quic.clone(),
+        ),
+        logger: logger.clone(),
+        control: config.control.clone(),
+        counters: config.counters.clone(),
+        totals: Arc::default(),
+    };
+
+    let kept = Arc::new(Mutex::new(Kept::default()));
+    let built = build(&args, config.clone(), &fixed, &mut kept.lock().unwrap())?;
+    // what a reload's config is compared with
+    let mut current = config.clone();
+    for (path, cache) in &kept.lock().unwrap().snapshots {
+        match snapshot::load(cache, path) {
+            Ok(len) => logging::info(&format!(
+                "loaded {} cached answers from {}",
+                len,
+                path.display()
+            )),
+            Err(DnsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => logging::warning(&format!("not loading {}: {}", path.display(), e)),
+        }
+    }
+    let totals = fixed.totals.clone();
+    if let Some((path, _)) = &config.counters {
+        match totals.load(path) {
+            Ok(()) => logging::info(&format!("counting on from {}", path.display())),
+            Err(DnsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => logging::warning(&format!("not loading {}: {}", path.display(), e)),
+        }
+    }
+    let handler = Arc::new(Reloadable::new(built.handler));
+    let server = match sockets {
+        Some((udp, tcp)) => BlockingServer::from_std(udp, tcp, handler.clone())?,
+        None => BlockingServer::bind_shared(addr, handler.clone())?,
+    };
+    let mut server = server
+        .max_connections(connections)
+        .max_payload(edns_payload);
+    if let Some(rrl) = config.rrl {
+        server = server.rrl(rrl);
+    }
+    let mut https_certificate = None;
+    if let Some((https_addr, chain, key, path)) = config.listen_https {
+        let certificate = Arc::new(ServerCertificate::from_pem_files(&chain, &key)?);
+        let https = match https_socket {
+            Some(listener) => {
+                let _runtime = server.runtime().enter();
+                HttpsServer::from_std(listener, certificate.clone(), handler.clone())?
+            }
+            None => server.runtime().block_on(HttpsServer::bind_shared(
+                https_addr,
+                certificate.clone(),
+                handler.clone(),
+            ))?,
+        };
+        let https = https.path(&path).max_connections(connections);
+        logging::info(&format!(
+            "serving DNS over HTTPS on {}{}",
+            https.local_addr()?,
+            path
+        ));
+        server = server.https(https);
+        https_certificate = Some(certificate);
+    }
+    if let Some((quic_addr, own_certificate)) = config.listen_quic {
+        let certificate = match (own_certificate, https_certificate) {
+            (Some((chain, key)), _) => Arc::new(ServerCertificate::from_pem_files(&chain, &key)?),
+            (None, Some(certificate)) => certificate,
+            (None, None) => anyhow::bail!("listen-quic needs a certificate without listen-https"),
+        };
+        let quic = match quic_socket {
+            Some(socket) => {
+                let _runtime = server.runtime().enter();
+                QuicServer::from_std(socket, certificate, handler.clone())?
+            }
+            None => server.runtime().block_on(QuicServer::bind_shared(
+                quic_addr,
+                certificate,
+                handler.clone(),
+            ))?,
+        };
+        let quic = quic.max_connections(connections);
+        logging::info(&format!("serving DNS over QUIC on {}", quic.local_addr()?));
+        server = server.quic(quic);
+    }
+    if config.mdns.unwrap_or(false) {
+        let Some(name) = mdns::local_name() else {
+            anyhow::bail!("mdns needs the host to have a name");
+        };
+        let addrs = mdns::local_addresses()?;
+        let responder = {
+            let _runtime = server.runtime().enter();
+            Responder::bind()?.host(&name, &addrs)
+        };
+        logging::info(&format!("answering multicast DNS for {}", name));
+        server.runtime().spawn(async move {
+            if let Err(e) = responder.run().await {
+                logging::error(&format!("multicast DNS responder stopped: {}", e));
+            }
+        });
+    }
 
-    for q in packet.questions {
-        println!("{:#?}", q);
+    let last_reload = Arc::new(Mutex::new(None));
+    let authorities = Arc::new(RwLock::new(built.authorities));
+    if let Some(path) = &config.control {
+        let commands = {
+            let last_reload = last_reload.clone();
+            let (exported, listed, rolled_back) = (
+                authorities.clone(),
+                authorities.clone(),
+                authorities.clone(),
+            );
+            let totals = totals.clone();
+            Commands::new()
+                .command("reload-status", move |_| {
+                    Ok(LastReload::to_json(&last_reload.lock().unwrap()))
+                })
+                .command("stats", move |_| Ok(totals.to_json()))
+                .command("zone", move |args| {
+                    let [zone] = args else {
+                        return Err(DnsError::Control("zone needs a zone".into()));
+                    };
+                    let (authority, origin) = zone_in(&exported.read().unwrap(), zone)?;
+                    let origin = origin.trim_end_matches('.').to_lowercase();
+                    match authority
+                        .zones()
+                        .iter()
+                        .find(|zone| zone.origin() == origin)
+                    {
+                        Some(zone) => Ok(zone.to_json()),
+                        None => Err(DnsError::Control(format!("no zone {}", origin))),
+                    }
+                })
+                .command("zone-versions", move |args| {
+                    let [zone] = args else {
+                        return Err(DnsError::Control("zone-versions needs a zone".into()));
+                    };
+                    let (authority, origin) = zone_in(&listed.read().unwrap(), zone)?;
+                    let serials = authority.versions(origin);
+                    let Some((serving, earlier)) = serials.split_first() else {
+                        return Err(DnsError::Control(format!("no zone {}", origin)));
+                    };
+                    Ok(Json::object([
+                        ("serving", (*serving).into()),
+                        ("earlier", earlier.to_vec().into()),
+                    ]))
+                })
+                .command("rollback", move |args| {
+                    let (zone, serial) = match args {
+                        [zone] => (zone, None),
+                        [zone, serial] => match serial.parse() {
+                            Ok(serial) => (zone, Some(serial)),
+                            Err(_) => {
+                                return Err(DnsError::Control(format!(
+                                    "invalid serial {:?}",
+                                    serial
+                                )))
+                            }
+                        },
+                        _ => {
+                            return Err(DnsError::Control(
+                                "rollback needs a zone and optionally a serial".into(),
+                            ))
+                        }
+                    };
+                    let (authority, origin) = zone_in(&rolled_back.read().unwrap(), zone)?;
+                    let zone = authority.rollback(origin, serial)?;
+                    Ok(Json::object([("serving", zone.serial().into())]))
+                })
+        };
+        let control = {
+            let _runtime = server.runtime().enter();
+            Control::bind(path, commands)?
+        };
+        logging::info(&format!("answering commands on {}", path.display()));
+        server.runtime().spawn(async move {
+            if let Err(e) = control.run().await {
+                logging::error(&format!("control socket stopped: {}", e));
+            }
+        });
     }
-    for rec in packet.answers {
-        println!("{:#?}", rec);
+    if let Some((path, every)) = config.counters.clone() {
+        let totals = totals.clone();
+        server.runtime().spawn(async move {
+            let every = every.unwrap_or(DEFAULT_SAVE_INTERVAL);
+            totals.run(&path, every).await
+        });
     }
-    for rec in packet.authorities {
-        println!("{:#?}", rec);
+
+    // every socket there is to bind is bound, so there's no need for root from here on. the
+    // user and chroot are only taken from the config at startup, a reload can't change them
+    if let Some(user) = &user {
+        let root = config.chroot.as_deref();
+        if let Err(e) = privileges::drop_to(user, root) {
+            anyhow::bail!("can't run as {}: {}", user.name, e);
+        }
+        match root {
+            Some(root) => logging::info(&format!("running as {} in {}", user.name, root.display())),
+            None => logging::info(&format!("running as {}", user.name)),
+        }
+    }
+
+    let mut tasks = Tasks::default();
+    {
+        let _runtime = server.runtime().enter();
+        tasks.start(built.background, false);
     }
-    for rec in packet.additional {
-        println!("{:#?}", rec);
+    let rebuilt = kept.clone();
+    let rebuild = move || {
+        let config = load_config(&args)?;
+        let diff = current.diff(&config);
+        let built = build(&args, config.clone(), &fixed, &mut rebuilt.lock().unwrap())?;
+        current = config;
+        Ok((built, diff))
+    };
+    server.runtime().spawn(reload(
+        handler,
+        rebuild,
+        tasks,
+        built.files,
+        last_reload,
+        authorities,
+    ));
+    let stop = {
+        let _runtime = server.runtime().enter();
+        Signals::new(&[SIGTERM, SIGINT])?
+    };
+
+    logging::info(&format!("listening on {}", server.local_addr()?));
+    notify("READY=1");
+    if let Some(interval) = systemd::watchdog_interval() {
+        server.runtime().spawn(async move {
+            loop {
+                notify("WATCHDOG=1");
+                time::sleep(interval / 2).await;
+            }
+        });
+    }
+    let stopping = async {
+        let signal = match stop.recv().await {
+            Ok(signal) => signal,
+            Err(e) => {
+                logging::warning(&format!("not stopping on SIGTERM or SIGINT: {}", e));
+                return future::pending().await;
+            }
+        };
+        logging::info(match signal {
+            SIGTERM => "stopping on SIGTERM",
+            _ => "stopping on SIGINT",
+        });
+        notify("STOPPING=1");
+    };
+    if let Err(e) = server.run_until(stopping, SHUTDOWN_DEADLINE) {
+        logging::error(&format!("server failed: {}", e));
+        return Err(e.into());
+    }
+    for (path, cache) in &kept.lock().unwrap().snapshots {
+        match snapshot::save(cache, path) {
+            Ok(len) => logging::info(&format!(
+                "saved {} cached answers to {}",
+                len,
+                path.display()
+            )),
+            Err(e) => logging::error(&format!("saving {} failed: {}", path.display(), e)),
+        }
+    }
+    if let Some((path, _)) = &config.counters {
+        match totals.save(path) {
+            Ok(()) => logging::info(&format!("saved the counters to {}", path.display())),
+            Err(e) => logging::error(&format!("saving {} failed: {}", path.display(), e)),
+        }
+    }
+    Ok(())
+}
+
+impl ServeArgs {
+    // what serve is to do, with the lists and rules sorted into their groups
+    fn resolve(self, matches: &ArgMatches) -> Result<Args> {
+        // the first address without a flag is the listen address, the rest are upstreams
+        let mut addrs = self.addrs.into_iter();
+        let listen = match (self.listen, addrs.next()) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("the listen address is given twice, with and without --listen")
+            }
+            (listen, addr) => listen.or(addr),
+        };
+        let mut upstreams = self.upstreams;
+        upstreams.extend(addrs);
+
+        let mut groups = vec![GroupArgs {
+            name: "default".to_string(),
+            ..GroupArgs::default()
+        }];
+        let starts = indices(matches, "groups");
+        groups.extend(self.groups);
+        // the group a flag at `index` is for, the last one started before it
+        let group = |index| starts.iter().take_while(|&&start| start < index).count();
+        for (source, index) in self
+            .blocklists
+            .into_iter()
+            .zip(indices(matches, "blocklists"))
+        {
+            groups[group(index)].blocklists.push(source);
+        }
+        for (source, index) in self
+            .allowlists
+            .into_iter()
+            .zip(indices(matches, "allowlists"))
+        {
+            groups[group(index)].allowlists.push(source);
+        }
+        for (rule, index) in self.allowed.into_iter().zip(indices(matches, "allowed")) {
+            groups[group(index)].allowed.push(rule);
+        }
+
+        Ok(Args {
+            config: self.config,
+            listen,
+            upstreams,
+            negative_anchors: self.negative_anchors,
+            groups,
+            block_action: self.block_action,
+            block_ttl: self.block_ttl,
+        })
+    }
+}
+
+// where an argument's values are on the command line
+fn indices(matches: &ArgMatches, id: &str) -> Vec<usize> {
+    matches
+        .indices_of(id)
+        .map(Iterator::collect)
+        .unwrap_or_default()
+}
+
+fn port_53(s: &str) -> dns_server::Result<SocketAddr> {
+    parse_socket_addr(s, 53)
+}
+
+fn negative_anchor(s: &str) -> dns_server::Result<(String, Duration)> {
+    Ok(match s.split_once('=') {
+        Some((domain, lifetime)) => (
+            domain.to_string(),
+            Duration::from_secs(parse_ttl(lifetime)? as u64),
+        ),
+        None => (s.to_string(), DEFAULT_NEGATIVE_ANCHOR_LIFETIME),
+    })
+}
+
+fn source(s: &str) -> std::result::Result<Source, Infallible> {
+    Ok(Source::parse(s))
+}
+
+fn group(s: &str) -> Result<GroupArgs> {
+    let Some((name, clients)) = s.split_once('=') else {
+        anyhow::bail!("a group is <name>=<subnet>[,<subnet>...]");
+    };
+    Ok(GroupArgs {
+        name: name.to_string(),
+        clients: clients
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()?,
+        ..GroupArgs::default()
+    })
+}
+
+fn load_config(args: &Args) -> Result<Config> {
+    Ok(match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    })
+}
+
+// prints a packet read from a file as it came off the wire
+fn parse(path: &Path) -> Result<()> {
+    let bytes = fs::read(path)?;
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        anyhow::bail!("{} is too big for a DNS message", path.display());
     }
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes)?;
+    print_packet(&DnsPacket::from_message(&mut buffer, bytes.len())?);
+    Ok(())
+}
 
+// asks a server a question and prints the answer, like a small dig
+fn query(args: QueryArgs) -> Result<()> {
+    let QueryArgs {
+        name,
+        qtype,
+        server,
+        tcp,
+        dnssec,
+    } = args;
+    let qtype = qtype.unwrap_or(QueryType::A);
+    // the system's nameserver if none is given, or the one on this host
+    let server = match server {
+        Some(server) => server,
+        None => ResolvConf::load(resolv_conf::PATH)
+            .ok()
+            .and_then(|conf| conf.nameservers.first().copied())
+            .unwrap_or(SocketAddr::from(([127, 0, 0, 1], 53))),
+    };
+    let mut request = DnsPacket::query(&name, qtype).dnssec_ok(dnssec).build();
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let client = Client::new();
+    let start = Instant::now();
+    let response = runtime.block_on(async {
+        match tcp {
+            true => client.query_tcp(server, &mut request).await,
+            false => client.query(server, &mut request).await,
+        }
+    })?;
+    print_packet(&response);
+    println!(";; from {} in {:?}", server, start.elapsed());
     Ok(())
 }
+
+// sends a command to a running server's control socket and prints the answer
+fn control_request(socket: &Path, command: &[String]) -> Result<()> {
+    println!("{}", control::request(socket, &command.join(" "))?);
+    Ok(())
+}
+
+fn print_packet(packet: &DnsPacket) {
+    let header = &packet.header;
+    let flags = [
+        ("qr", header.flags.response),
+        ("aa", header.flags.authoritative),
+        ("tc", header.flags.truncated),
+        ("rd", header.flags.recursion_desired),
+        ("ra", header.flags.recursion_available),
+        ("ad", header.flags.authentic_data),
+        ("cd", header.flags.checking_disabled),
+    ];
+    let flags: Vec<&str> = flags
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| *flag)
+        .collect();
+    println!(
+        ";; id {} {:?} {:?} flags {}",
+        header.id,
+        header.flags.opcode,
+        header.rcode,
+        flags.join(" ")
+    );
+    let sections = [
+        ("answer", &packet.answers),
+        ("authority", &packet.authorities),
+        ("additional", &packet.additional),
+    ];
+    println!(";; question");
+    for question in &packet.questions {
+        println!("{}", question);
+    }
+    for (section, records) in sections {
+        if !records.is_empty() {
+            println!(";; {}", section);
+        }
+        for record in records {
+            println!("{}", record);
+        }
+    }
+}
+
+// the handler for a config, and what has to run alongside it
+fn build(
+    args: &Args,
+    mut config: Config,
+    fixed: &Fixed,
+    kept: &mut Kept,
+) -> Result<Built<impl Handler>> {
+    let listeners = (
+        config.listen,
+        config.listen_https.clone(),
+        config.listen_quic.clone(),
+    );
+    if listeners != fixed.listeners {
+        logging::warning("the listeners stay as they are until a restart");
+    }
+    if config.control != fixed.control {
+        logging::warning("the control socket stays where it is until a restart");
+    }
+    if config.counters != fixed.counters {
+        logging::warning("the counters are saved where they were until a restart");
+    }
+    let mut upstreams = args.upstreams.clone();
+    let mut tls_upstreams = Vec::new();
+    let mut quic_upstreams = Vec::new();
+    let mut https_upstreams = Vec::new();
+    if upstreams.is_empty() {
+        upstreams = config.upstreams;
+        tls_upstreams = config.tls_upstreams;
+        quic_upstreams = config.quic_upstreams;
+        https_upstreams = config.https_upstreams;
+    }
+    let no_upstreams = upstreams.is_empty()
+        && tls_upstreams.is_empty()
+        && quic_upstreams.is_empty()
+        && https_upstreams.is_empty();
+    // without upstreams of our own the system's will do, unless the system's is us
+    let resolv_conf = config
+        .resolv_conf
+        .clone()
+        .unwrap_or_else(|| Some(PathBuf::from(resolv_conf::PATH)));
+    if let (true, Some(path)) = (no_upstreams, resolv_conf) {
+        match ResolvConf::load(&path) {
+            Ok(conf) => {
+                upstreams = conf
+                    .nameservers
+                    .into_iter()
+                    .filter(|&nameserver| !is_us(nameserver, fixed.listen))
+                    .collect();
+                if !upstreams.is_empty() {
+                    logging::info(&format!(
+                        "forwarding to the nameservers in {}",
+                        path.display()
+                    ));
+                }
+            }
+            // a missing resolv.conf is no reason not to resolve from the root
+            Err(e) if config.resolv_conf.is_some() => return Err(e.into()),
+            Err(e) => logging::warning(&format!("not reading {}: {}", path.display(), e)),
+        }
+    }
+    let upstream_sockets = fixed.upstream_sockets;
+    let edns_payload = config.edns_payload.unwrap_or(DEFAULT_EDNS_PAYLOAD);
+    let client_subnet = config.client_subnet.unwrap_or_default();
+    let randomize_case = config.randomize_case.unwrap_or(true);
+    let max_case_mismatches = config
+        .max_case_mismatches
+        .unwrap_or(DEFAULT_MAX_CASE_MISMATCHES);
+    let race = config.race.unwrap_or((1, Duration::ZERO));
+    let qname_minimisation = config.qname_minimisation.unwrap_or(true);
+    // everything the answers in the caches come from but each view's own forwarding
+    let sources = format!(
+        "{:?}",
+        (
+            &upstreams,
+            &tls_upstreams,
+            &quic_upstreams,
+            &https_upstreams,
+            edns_payload,
+            client_subnet,
+            randomize_case,
+            race,
+            qname_minimisation,
+            &config.cache,
+        )
+    );
+
+    // without upstreams names are resolved from the root
+    let secure_upstreams =
+        !tls_upstreams.is_empty() || !quic_upstreams.is_empty() || !https_upstreams.is_empty();
+    let forwarder = if upstreams.is_empty() && !secure_upstreams {
+        None
+    } else {
+        let bootstrap = match config.bootstrap.is_empty() {
+            true => upstreams.clone(),
+            false => config.bootstrap.clone(),
+        };
+        let mut forwarder = Forwarder::new(upstreams)
+            .max_sockets(upstream_sockets)
+            .edns_payload(edns_payload)
+            .client_subnet(client_subnet)
+            .randomize_case(randomize_case)
+            .max_case_mismatches(max_case_mismatches)
+            .race(race.0)
+            .stagger(race.1);
+        if secure_upstreams {
+            let anchors = match &config.tls_ca {
+                Some(path) => TrustAnchors::from_pem_file(path)?,
+                None => TrustAnchors::system()?,
+            };
+            logging::info(&format!("loaded {} trust anchors", anchors.len()));
+            let anchors = Arc::new(anchors);
+            for (addr, identity) in tls_upstreams {
+                let upstream = TlsUpstream::new(addr, identity, anchors.clone());
+                forwarder = forwarder.tls_upstream(upstream);
+            }
+            for (addr, identity) in quic_upstreams {
+                let upstream = QuicUpstream::new(addr, identity, anchors.clone());
+                forwarder = forwarder.quic_upstream(upstream);
+            }
+            for (url, addrs, pins) in https_upstreams {
+                for addr in https_addrs(&url, addrs, &bootstrap)? {
+                    let upstream = pins.iter().fold(
+                        HttpsUpstream::new(url.clone(), addr, anchors.clone()),
+                        |upstream, &pin| upstream.pin(pin),
+                    );
+                    forwarder = forwarder.https_upstream(upstream);
+                }
+            }
+        }
+        Some(Arc::new(forwarder))
+    };
+    let resolver = Resolver::default()
+        .max_sockets(upstream_sockets)
+        .edns_payload(edns_payload)
+        .qname_minimisation(qname_minimisation)
+        .randomize_case(randomize_case);
+    let resolver = Arc::new(resolver);
+    let upstream = move |request: DnsPacket, src: SocketAddr| {
+        let forwarder = forwarder.clone();
+        let resolver = resolver.clone();
+        async move {
+            match forwarder {
+                Some(forwarder) => forwarder.handle(request, src).await,
+                None => resolver.handle(request, src).await,
+            }
+        }
+    };
+
+    // the config file's lists are for the clients not in a group, like the flags before any
+    // --group, and come before them
+    let block_action = args
+        .block_action
+        .or(config.block_with)
+        .unwrap_or(BlockAction::Null);
+    let block_ttl = args
+        .block_ttl
+        .or(config.block_ttl)
+        .unwrap_or(blocklist::DEFAULT_TTL);
+    let mut groups = args.groups.clone();
+    let default_group = &mut groups[0];
+    default_group
+        .blocklists
+        .splice(0..0, config.blocklists.drain(..));
+    default_group
+        .allowlists
+        .splice(0..0, config.allowlists.drain(..));
+    default_group.allowed.splice(0..0, config.allowed.drain(..));
+    // the config file and zone files, and the local lists, a change to which reloads
+    let mut files: Vec<PathBuf> = args.config.iter().cloned().collect();
+    for view in std::iter::once(&config.default_view).chain(&config.views) {
+        files.extend(view.zones.iter().map(|(_, path)| path.clone()));
+    }
+    for group in &groups {
+        let sources = group.blocklists.iter().chain(&group.allowlists);
+        files.extend(sources.filter_map(|source| match source {
+            Source::File(path) => Some(path.clone()),
+            Source::Url(_) => None,
+        }));
+    }
+    let lists = (groups, block_action, block_ttl);
+    let (policy, new_policy) = match kept.policy.take() {
+        Some((kept_lists, policy)) if kept_lists == lists => (policy, false),
+        _ => (
+            Arc::new(blocking_policy(lists.0.clone(), block_action, block_ttl)),
+            true,
+        ),
+    };
+    kept.policy = Some((lists, policy.clone()));
+
+    let geoip = match &config.geoip {
+        Some(path) => {
+            let db = GeoDb::load(path)?;
+            logging::info(&format!(
+                "loaded {} from {}",
+                db.database_type,
+                path.display()
+            ));
+            Some(Arc::new(db))
+        }
+        None => None,
+    };
+    let mut hosts_files = config.hosts_files.clone();
+    if config.etc_hosts.unwrap_or(true) {
+        hosts_files.insert(0, PathBuf::from(hosts::PATH));
+    }
+    let hosts = Arc::new(Hosts::new(hosts_files));
+    logging::info(&format!(
+        "loaded {} names from hosts files",
+        hosts.reload()?
+    ));
+    let shared = Shared {
+        upstream_sockets,
+        edns_payload,
+        client_subnet,
+        randomize_case,
+        max_case_mismatches,
+        race,
+        negative_anchors: args.negative_anchors.clone(),
+        policy: policy.clone(),
+        acl: Arc::new(config.acl),
+        geoip,
+        hosts: hosts.clone(),
+        counters: fixed.totals.queries.clone(),
+    };
+
+    // each view's cache is kept unless where its answers come from has changed
+    let mut caches = HashMap::new();
+    let mut snapshots = Vec::new();
+    let mut cache_for = |view: &ViewConfig| {
+        let sources = format!("{}{:?}", sources, view.forwards);
+        let cache = match kept.caches.remove(&view.name) {
+            Some((kept_sources, cache)) if kept_sources == sources => cache,
+            _ => Arc::new(
+                config
+                    .cache
+                    .cache()
+                    .shared_counters(fixed.totals.cache.clone()),
+            ),
+        };
+        caches.insert(view.name.clone(), (sources, cache.clone()));
+        // the default view's, which comes first, goes under the file's own name
+        if let Some(path) = &config.cache_snapshot {
+            let mut path = path.clone().into_os_string();
+            if !snapshots.is_empty() {
+                path.push(format!(".{}", view.name));
+            }
+            snapshots.push((PathBuf::from(path), cache.clone()));
+        }
+        cache
+    };
+    // and the versions of its zones unless how many there are to keep has changed
+    let max_versions = config.zone_versions.unwrap_or(DEFAULT_ZONE_VERSIONS);
+    let mut versions = HashMap::new();
+    let mut versions_for = |view: &ViewConfig| {
+        let zone_versions = match kept.versions.remove(&view.name) {
+            Some(zone_versions) if zone_versions.max() == max_versions => zone_versions,
+            _ => Arc::new(ZoneVersions::new(max_versions)),
+        };
+        versions.insert(view.name.clone(), zone_versions.clone());
+        zone_versions
+    };
+    // the health checks of each view's zones, run once the handler is
+    let mut health_checks = Vec::new();
+    let mut authorities = Vec::new();
+    let default_cache = cache_for(&config.default_view);
+    let default_versions = versions_for(&config.default_view);
+    let mut handler = Views::new(view_handler(
+        config.default_view,
+        upstream.clone(),
+        &shared,
+        default_cache,
+        default_versions,
+        &mut health_checks,
+        &mut authorities,
+    )?);
+    for view in config.views {
+        logging::info(&format!("view {}", view.name));
+        let (name, clients) = (view.name.clone(), view.clients.clone());
+        let (cache, versions) = (cache_for(&view), versions_for(&view));
+        let view = view_handler(
+            view,
+            upstream.clone(),
+            &shared,
+            cache,
+            versions,
+            &mut health_checks,
+            &mut authorities,
+        )?;
+        handler = handler.view(View::new(&name, clients, view));
+    }
+    kept.caches = caches;
+    kept.snapshots = snapshots;
+    kept.versions = versions;
+    // .local names and link-local addresses are only known on the link
+    let handler = MdnsProxy::new(handler).enabled(config.mdns_proxy.unwrap_or(false));
+    // resolver.arpa is nobody's to forward to, and where we serve encrypted DNS is in it
+    let mut handler = Designated::new(handler);
+    if let Some(name) = &config.designated_resolver {
+        if let Some((https_addr, _, _, path)) = &config.listen_https {
+            handler = handler.https(name, *https_addr, path);
+        }
+        if let Some((quic_addr, _)) = &config.listen_quic {
+            handler = handler.quic(name, *quic_addr);
+        }
+        if handler.records().is_empty() {
+            anyhow::bail!("designated-resolver needs listen-https or listen-quic");
+        }
+    }
+    // questions in class CH are about us, not anything upstream
+    let mut handler = Chaos::new(handler);
+    for (probe, reply) in config.chaos {
+        handler = handler.reply(probe, reply);
+    }
+
+    // clients are checked before any view sees what they ask, and counted before that
+    let handler = Limited::new(
+        Restricted::new(handler, (*shared.acl).clone()),
+        config
+            .query_limit
+            .unwrap_or_else(|| QueryLimiter::new().rate(0)),
+    );
+    // and logged before anything, limited and refused queries being the ones to look into
+    let mut handler = QueryLog::new(handler, fixed.logger.clone())
+        .enabled(fixed.logger.has_sink(Stream::Query))
+        .counters(fixed.totals.queries.clone());
+    if let Some((rate, always)) = &config.log_sample {
+        let always: Vec<&str> = always.iter().map(String::as_str).collect();
+        handler = handler.sampler(QuerySampler::new(*rate, &always));
+    }
+    Ok(Built {
+        handler,
+        files,
+        authorities,
+        background: Background {
+            health_checks,
+            hosts,
+            policy,
+            new_policy,
+        },
+    })
+}
+
+// the blocking policy for the groups' lists and rules
+fn blocking_policy(groups: Vec<GroupArgs>, block_action: BlockAction, block_ttl: u32) -> Policy {
+    let mut groups = groups.into_iter().map(|args| {
+        let mut group = Group::new(&args.name, args.clients);
+        if !args.blocklists.is_empty() {
+            let blocklist = Blocklist::new(args.blocklists)
+                .allowlists(args.allowlists)
+                .action(block_action)
+                .ttl(block_ttl);
+            group = group.blocklist(Arc::new(blocklist));
+        }
+        for rule in &args.allowed {
+            group = group.allow(rule);
+        }
+        group
+    });
+    let mut policy = Policy::new(groups.next().unwrap());
+    for group in groups {
+        policy = policy.group(group);
+    }
+    policy
+}
+
+// reloads on SIGHUP or when one of the files the handler was built from changes. a config
+// that doesn't load leaves everything as it was. new blocklists are loaded before the handler
+// is replaced, so nothing blocked gets through in between. what changed, or why nothing did,
+// is logged and kept in `last` for the control socket
+async fn reload<H: Handler>(
+    handler: Arc<Reloadable<H>>,
+    rebuild: impl FnMut() -> Result<(Built<H>, ConfigDiff)> + Send + 'static,
+    mut tasks: Tasks,
+    mut files: Vec<PathBuf>,
+    last: Arc<Mutex<Option<LastReload>>>,
+    authorities: Arc<RwLock<Authorities>>,
+) {
+    let signals = match Signals::new(&[SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            logging::warning(&format!("not reloading on SIGHUP: {}", e));
+            return;
+        }
+    };
+    let rebuild = Arc::new(Mutex::new(rebuild));
+    let mut watching = true;
+    loop {
+        let watcher = match watching.then(|| Watcher::new(&files)) {
+            Some(Ok(watcher)) => Some(watcher),
+            Some(Err(e)) => {
+                logging::warning(&format!("not watching the config and zone files: {}", e));
+                None
+            }
+            None => None,
+        };
+        let changed = async {
+            match &watcher {
+                Some(watcher) => watcher.changed().await,
+                None => future::pending().await,
+            }
+        };
+        let trigger = tokio::select! {
+            signal = signals.recv() => {
+                if let Err(e) = signal {
+                    logging::warning(&format!("not reloading on SIGHUP: {}", e));
+                    return;
+                }
+                logging::info("reloading on SIGHUP");
+                watching = true;
+                "SIGHUP"
+            }
+            changed = changed => {
+                if let Err(e) = changed {
+                    logging::warning(&format!("not watching the config and zone files: {}", e));
+                    watching = false;
+                    continue;
+                }
+                logging::info("reloading, files changed");
+                "files changed"
+            }
+        };
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        notify(&systemd::reloading());
+        // loading zones and looking up upstreams blocks
+        let rebuild = rebuild.clone();
+        let built = tokio::task::spawn_blocking(move || (rebuild.lock().unwrap())()).await;
+        let (built, diff) = match built.map_err(anyhow::Error::from).and_then(|built| built) {
+            Ok(built) => built,
+            Err(e) => {
+                logging::error(&format!(
+                    "reload failed, still serving the old config: {}",
+                    e
+                ));
+                *last.lock().unwrap() = Some(LastReload {
+                    at,
+                    trigger,
+                    outcome: Err(e.to_string()),
+                });
+                notify("READY=1");
+                continue;
+            }
+        };
+        for blocklist in built.background.policy.blocklists() {
+            match built.background.new_policy {
+                true => {
+                    blocklist.refresh().await;
+                }
+                // a local list may be what changed
+                false => {
+                    tokio::spawn(async move { blocklist.refresh().await });
+                }
+            }
+        }
+        files = built.files;
+        handler.replace(built.handler);
+        *authorities.write().unwrap() = built.authorities;
+        tasks.start(built.background, true);
+        logging::info(&format!("reloaded: {}", diff));
+        *last.lock().unwrap() = Some(LastReload {
+            at,
+            trigger,
+            outcome: Ok(diff),
+        });
+        notify("READY=1");
+    }
+}
+
+// tells systemd how the server is doing, if it's listening
+fn notify(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        logging::warning(&format!("can't tell systemd {:?}: {}", state, e));
+    }
+}
+
+// whether a nameserver is this server, e.g. 127.0.0.1 in resolv.conf while listening on
+// 0.0.0.0:53. forwarding to it would send every query in a circle
+fn is_us(nameserver: SocketAddr, listen: SocketAddr) -> bool {
+    nameserver.port() == listen.port()
+        && (nameserver.ip() == listen.ip()
+            || (listen.ip().is_unspecified() && nameserver.ip().is_loopback()))
+}
+
+// the addresses of a DoH server: the ones given, its host if that's an address, or else what the
+// bootstrap resolvers say. the server's runtime isn't up yet, or on a reload is busy with
+// this, so this runs one of its own on a thread of its own
+fn https_addrs(url: &DohUrl, addrs: Vec<IpAddr>, bootstrap: &[SocketAddr]) -> Result<Vec<IpAddr>> {
+    if !addrs.is_empty() {
+        return Ok(addrs);
+    }
+    if let Some(ip) = url.ip() {
+        return Ok(vec![ip]);
+    }
+    let lookup = || {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(doh::bootstrap(&Client::new(), bootstrap, &url.host))
+    };
+    let addrs = thread::scope(|scope| scope.spawn(lookup).join().unwrap())?;
+    logging::info(&format!("{} is at {:?}", url.host, addrs));
+    Ok(addrs)
+}
+
+// answers a view's clients: from its local records, zones and the hosts files first, then, for
+// clients allowed recursion, with names on the blocklists blocked and the rest looked up
+// through a cache and validator of its own, forwarded where the view says
+// the authority of the view a zone given as [<view>/]<zone> is in, and the zone
+fn zone_in<'a>(
+    authorities: &Authorities,
+    zone: &'a str,
+) -> dns_server::Result<(Arc<Authority>, &'a str)> {
+    let (authority, origin) = match zone.split_once('/') {
+        // the default view is only ever the one without a name in front
+        Some((view, origin)) => (
+            authorities.iter().skip(1).find(|(name, _)| name == view),
+            origin,
+        ),
+        None => (authorities.first(), zone),
+    };
+    match authority {
+        Some((_, authority)) => Ok((authority.clone(), origin)),
+        None => Err(DnsError::Control(format!("no view for {}", zone))),
+    }
+}
+
+fn view_handler(
+    config: ViewConfig,
+    upstream: impl Handler + Clone,
+    shared: &Shared,
+    cache: Arc<Cache>,
+    versions: Arc<ZoneVersions>,
+    health_checks: &mut Vec<(Arc<HealthChecks>, Arc<Authority>)>,
+    authorities: &mut Authorities,
+) -> Result<impl Handler> {
+    if shared.geoip.is_none() && !config.geo.is_empty() {
+        anyhow::bail!(
+            "geo records in view {:?} but no geoip database",
+            config.name
+        );
+    }
+    let mut upstream = ConditionalForwarder::new(upstream);
+    for (domain, upstreams) in &config.forwards {
+        let forwarder = Forwarder::new(upstreams.clone())
+            .max_sockets(shared.upstream_sockets)
+            .edns_payload(shared.edns_payload)
+            .client_subnet(shared.client_subnet)
+            .randomize_case(shared.randomize_case)
+            .max_case_mismatches(shared.max_case_mismatches)
+            .race(shared.race.0)
+            .stagger(shared.race.1);
+        upstream = upstream.route(domain, forwarder);
+    }
+    // answers are cached as they come, signatures and all, and checked on the way out
+    let validator =
+        Validator::new(Cached::shared(upstream, cache)).validate_except(config.validate_except);
+    for (domain, lifetime) in &shared.negative_anchors {
+        validator.add_negative_anchor(domain, *lifetime);
+    }
+    let validator = Arc::new(validator);
+
+    // reverse zones forwarded somewhere are that server's to answer
+    let mut empty_zones = EmptyZones::default();
+    for (domain, _) in &config.forwards {
+        empty_zones.pass_through(domain.trim_start_matches("*."));
+    }
+    let empty_zones = Arc::new(empty_zones);
+
+    let mut zones = Vec::new();
+    for (origin, path) in &config.zones {
+        let zone = Zone::load(path, origin)?;
+        logging::info(&format!(
+            "loaded {} records for {}",
+            zone.len(),
+            zone.origin()
+        ));
+        zones.push(zone);
+    }
+    let mut rotator = Rotator::default();
+    for (name, qtype, rotation) in config.rotations {
+        rotator = rotator.rrset(&name, qtype, rotation);
+    }
+    let mut authority = Authority::new(zones)
+        .rotation(rotator)
+        .zone_versions(versions);
+    let mut health = HealthChecks::new();
+    for (name, check) in config.health_checks {
+        health = health.monitor(&name, check);
+    }
+    let health = Arc::new(health);
+    if !health.is_empty() {
+        authority = authority.health_checks(health.clone());
+    }
+    let authority = Arc::new(authority);
+    if !health.is_empty() {
+        health_checks.push((health, authority.clone()));
+    }
+    authorities.push((config.name.clone(), authority.clone()));
+    // a domain forwarded from inside one of the zones is the forwarder's, like a delegation
+    let forwarded: Arc<Vec<String>> = Arc::new(
+        config
+            .forwards
+            .iter()
+            .map(|(domain, _)| domain.trim_start_matches("*.").to_lowercase())
+            .collect(),
+    );
+
+    let (policy, acl, hosts, counters) = (
+        shared.policy.clone(),
+        shared.acl.clone(),
+        shared.hosts.clone(),
+        shared.counters.clone(),
+    );
+    let handler = move |request: DnsPacket, src: SocketAddr| {
+        let validator = validator.clone();
+        let hosts = hosts.clone();
+        let empty_zones = empty_zones.clone();
+        let policy = policy.clone();
+        let authority = authority.clone();
+        let forwarded = forwarded.clone();
+        let acl = acl.clone();
+        let counters = counters.clone();
+        async move {
+            let forwarded_inside = request.questions.first().is_some_and(|question| {
+                authority.find(&question.name).is_some_and(|zone| {
+                    forwarded.iter().any(|domain| {
+                        is_subdomain(&question.name, domain) && is_subdomain(domain, zone.origin())
+                    })
+                })
+            });
+            if !forwarded_inside {
+                if let Some(res) = authority.answer(&request) {
+                    return Some(res);
+                }
+            }
+            if let Some(res) = hosts.answer(&request) {
+                return Some(res);
+            }
+            // anything past our own data has to be looked up for the client
+            if !acl.allows(Capability::Recursion, src.ip()) {
+                return Some(acl::refused(&request, Capability::Recursion));
+            }
+            if let Some(res) = policy.answer(&request, src.ip()) {
+                counters.blocked.fetch_add(1, Ordering::Relaxed);
+                return Some(res);
+            }
+            if let Some(res) = empty_zones.answer(&request) {
+                return Some(res);
+            }
+            validator.handle(request, src).await
+        }
+    };
+
+    // local records come before everything else, and names they point at outside of them are
+    // looked up like any other
+    let handler = Overrides::new(handler, LocalRecords::new(config.records));
+    // records for the client's region win over the ones for everybody
+    let mut handler = Geo::new(handler, shared.geoip.clone());
+    for (region, record) in config.geo {
+        handler = handler.record(region, record);
+    }
+    // addresses are swapped once they've been validated, which they'd fail after, and before
+    // AAAA records are made up from them
+    let handler = Rewrite::new(handler, AnswerRewriter::new(config.rewrites));
+    // AAAA records are made up from whatever A records the rest finds, local ones included
+    Ok(Dns64::new(handler, config.dns64))
+}
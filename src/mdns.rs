@@ -0,0 +1,515 @@
+// multicast DNS (rfc 6762), the .local names hosts on a link give themselves without any
+// server. the responder joins 224.0.0.251 and ff02::fb on port 5353, sharing the port with
+// avahi or whatever else is there, and answers questions about the records it has: the host's
+// own name under .local with its addresses, the reverse names of those, and whatever else it's
+// given, like services (see dns_sd.rs). answers go to the group unless the question asks for
+// a unicast response, and queries from a port other than 5353 come from simple resolvers that
+// get a plain unicast response with their id, their question and short ttls (section 6.7).
+// records the querier says it knows already are left out (section 7.1). the host's records are
+// announced when the responder starts, but not probed for first (section 8.1), so a name
+// another host has already isn't noticed, and groups are joined on the default interface only.
+//
+// for clients that only speak unicast DNS, the proxy sends their questions about .local names,
+// and the link-local reverse zones, as one-shot multicast queries (section 5.1) and answers with
+// what comes back within a moment, or NXDOMAIN if nothing does.
+use crate::error::Result;
+use crate::logging;
+use crate::net::reverse_name;
+use crate::server::Handler;
+use crate::structure::{
+    is_subdomain, BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode,
+};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+
+pub const MDNS_PORT: u16 = 5353;
+pub const GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// The ttl of records about host names, section 10.
+pub const HOST_TTL: u32 = 120;
+/// The ttl of all other records, section 10.
+pub const DEFAULT_TTL: u32 = 4500;
+// the most a legacy querier is given, section 6.7
+const LEGACY_TTL: u32 = 10;
+// the top bit of the class in a question asks for a unicast response, in a record it means the
+// record replaces any others of its name and type (section 10.2)
+const UNICAST_RESPONSE: u16 = 0x8000;
+const CACHE_FLUSH: u16 = 0x8000;
+// how long the proxy waits for answers to come in
+const DEFAULT_WAIT: Duration = Duration::from_millis(500);
+const ANY: QueryType = QueryType::UNKNOWN(255);
+/// The largest message multicast DNS sends or takes (section 17).
+pub const MAX_MESSAGE: usize = 9000;
+
+/// The zones multicast DNS answers for: .local and the reverse zones of link-local addresses
+/// (section 4).
+pub const ZONES: [&str; 5] = [
+    "local",
+    "254.169.in-addr.arpa",
+    "8.e.f.ip6.arpa",
+    "9.e.f.ip6.arpa",
+    "a.e.f.ip6.arpa",
+];
+
+/// Answers multicast questions about the records it has.
+pub struct Responder {
+    socket: UdpSocket,
+    socket_v6: Option<UdpSocket>,
+    // and whether each is unique to us, which makes it flush other hosts' from caches
+    records: Vec<(DnsRecord, bool)>,
+}
+
+impl Responder {
+    /// Joins the groups. Has to be called from within a tokio runtime. IPv6 is left out if
+    /// it can't be had.
+    pub fn bind() -> Result<Self> {
+        let socket = multicast_socket(false)?;
+        socket.join_multicast_v4(&GROUP_V4, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_ttl_v4(255)?;
+        let socket_v6 = match multicast_socket(true)
+            .and_then(|socket| socket.join_multicast_v6(&GROUP_V6, 0).map(|_| socket))
+        {
+            Ok(socket) => Some(UdpSocket::from_std(socket)?),
+            Err(e) => {
+                logging::warning(&format!("not answering multicast DNS over IPv6: {}", e));
+                None
+            }
+        };
+        Ok(Self {
+            socket: UdpSocket::from_std(socket)?,
+            socket_v6,
+            records: Vec::new(),
+        })
+    }
+
+    /// Answers for `name`, e.g. `nas.local`, with `addrs`, and for their reverse names with
+    /// `name`.
+    pub fn host(mut self, name: &str, addrs: &[IpAddr]) -> Self {
+        let name = name.trim_end_matches('.').to_lowercase();
+        for addr in addrs {
+            let record = match addr {
+                IpAddr::V4(ip) => DnsRecord::A {
+                    domain: name.clone(),
+                    class: 1,
+                    ttl: HOST_TTL,
+                    ip: (*ip).into(),
+                },
+                IpAddr::V6(ip) => DnsRecord::AAAA {
+                    domain: name.clone(),
+                    class: 1,
+                    ttl: HOST_TTL,
+                    ip: (*ip).into(),
+                },
+            };
+            self.records.push((record, true));
+            self.records.push((
+                DnsRecord::PTR {
+                    domain: reverse_name(*addr),
+                    class: 1,
+                    ttl: HOST_TTL,
+                    host: name.clone(),
+                },
+                true,
+            ));
+        }
+        self
+    }
+
+    /// Answers with `record` too. Unique records are ours alone, shared ones, like the PTR
+    /// records that list a service's instances, may have others' next to them.
+    pub fn record(mut self, record: DnsRecord, unique: bool) -> Self {
+        self.records.push((record, unique));
+        self
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records.iter().map(|(record, _)| record)
+    }
+
+    /// Announces the records and answers questions about them until reading from the socket
+    /// fails.
+    pub async fn run(&self) -> Result<()> {
+        self.announce().await;
+        let v6 = async {
+            match &self.socket_v6 {
+                Some(socket) => {
+                    self.serve(socket, SocketAddr::new(GROUP_V6.into(), MDNS_PORT))
+                        .await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::try_join!(
+            self.serve(&self.socket, SocketAddr::new(GROUP_V4.into(), MDNS_PORT)),
+            v6
+        )?;
+        Ok(())
+    }
+
+    // unsolicited responses with everything, twice a second apart (section 8.3)
+    async fn announce(&self) {
+        let mut res = response(0);
+        for (record, unique) in &self.records {
+            res.add_answer(multicast_record(record, *unique));
+        }
+        if res.answers.is_empty() {
+            return;
+        }
+        for i in 0..2 {
+            if i > 0 {
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            let _ = send(&self.socket, &mut res, (GROUP_V4, MDNS_PORT).into()).await;
+            if let Some(socket) = &self.socket_v6 {
+                let _ = send(socket, &mut res, (GROUP_V6, MDNS_PORT).into()).await;
+            }
+        }
+    }
+
+    async fn serve(&self, socket: &UdpSocket, group: SocketAddr) -> Result<()> {
+        loop {
+            // a buffer per message, so nothing the last one left is read as part of this one
+            let mut buf = BytePacketBuffer::with_len(MAX_MESSAGE);
+            let (len, src) = socket.recv_from(&mut buf.buf[..]).await?;
+            let Ok(query) = DnsPacket::from_message(&mut buf, len) else {
+                continue;
+            };
+            if query.header.flags.is_response() {
+                continue;
+            }
+            if let Some((mut res, dest)) = self.answer(&query, src, group) {
+                if let Err(e) = send(socket, &mut res, dest).await {
+                    logging::warning(&format!(
+                        "failed to answer multicast DNS query from {}: {}",
+                        src, e
+                    ));
+                }
+            }
+        }
+    }
+
+    // the response to `query` and where it goes
+    fn answer(
+        &self,
+        query: &DnsPacket,
+        src: SocketAddr,
+        group: SocketAddr,
+    ) -> Option<(DnsPacket, SocketAddr)> {
+        let legacy = src.port() != MDNS_PORT;
+        let mut unicast = legacy;
+        let mut answers = Vec::new();
+        for question in &query.questions {
+            unicast |= question.class & UNICAST_RESPONSE != 0;
+            for (record, unique) in &self.records {
+                if matches(question, record) && !known(query, record) {
+                    answers.push((record, *unique));
+                }
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+
+        let mut res = response(if legacy { query.header.id } else { 0 });
+        if legacy {
+            res.questions = query.questions.clone();
+        }
+        let add = |record: &DnsRecord, unique: bool| {
+            let mut record = match legacy {
+                // legacy resolvers don't know about cache flushing, section 6.7
+                true => record.clone(),
+                false => multicast_record(record, unique),
+            };
+            if legacy {
+                record.set_ttl(record.ttl().min(LEGACY_TTL));
+            }
+            record
+        };
+        for (record, unique) in &answers {
+            res.add_answer(add(record, *unique));
+        }
+        // what the answers point at saves the querier asking again, rfc 6763 section 12
+        for (record, _) in &answers {
+            let target = match record {
+                DnsRecord::PTR { host, .. } => host,
+                DnsRecord::SRV { host, .. } => host,
+                _ => continue,
+            };
+            for (extra, unique) in &self.records {
+                let already = answers.iter().any(|(answer, _)| *answer == extra);
+                if extra.domain() == target && !already {
+                    res.add_additional(add(extra, *unique));
+                }
+            }
+        }
+        Some((res, if unicast { src } else { group }))
+    }
+}
+
+fn response(id: u16) -> DnsPacket {
+    let mut res = DnsPacket::new();
+    res.header.id = id;
+    res.header.flags.response = true;
+    res.header.flags.authoritative = true;
+    res
+}
+
+fn multicast_record(record: &DnsRecord, unique: bool) -> DnsRecord {
+    let mut record = record.clone();
+    if unique {
+        record.set_class(record.class() | CACHE_FLUSH);
+    }
+    record
+}
+
+fn matches(question: &DnsQuestion, record: &DnsRecord) -> bool {
+    question
+        .name
+        .trim_end_matches('.')
+        .eq_ignore_ascii_case(record.domain())
+        && (question.qtype == ANY || question.qtype == record.qtype())
+}
+
+// whether the query's known answers have `record` with at least half its ttl left
+fn known(query: &DnsPacket, record: &DnsRecord) -> bool {
+    query.answers.iter().any(|known| {
+        known.domain().eq_ignore_ascii_case(record.domain())
+            && known.rdata().ok() == record.rdata().ok()
+            && known.qtype() == record.qtype()
+            && known.ttl() >= record.ttl() / 2
+    })
+}
+
+async fn send(socket: &UdpSocket, res: &mut DnsPacket, dest: SocketAddr) -> Result<()> {
+    let mut buf = BytePacketBuffer::new();
+    res.write(&mut buf)?;
+    socket.send_to(&buf.buf[..buf.pos], dest).await?;
+    Ok(())
+}
+
+// a socket on the mDNS port that others on this host can have too
+pub(crate) fn multicast_socket(v6: bool) -> io::Result<StdUdpSocket> {
+    let family = if v6 { libc::AF_INET6 } else { libc::AF_INET };
+    let fd = unsafe {
+        libc::socket(
+            family,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let raw = std::os::fd::AsRawFd::as_raw_fd(&fd);
+    let on: libc::c_int = 1;
+    let set = |level, option| {
+        let ret = unsafe {
+            libc::setsockopt(
+                raw,
+                level,
+                option,
+                (&on as *const libc::c_int).cast(),
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+    set(libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    set(libc::SOL_SOCKET, libc::SO_REUSEPORT)?;
+    let ret = if v6 {
+        // the ipv4 socket has the port for ipv4
+        set(libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?;
+        let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_port = MDNS_PORT.to_be();
+        unsafe {
+            libc::bind(
+                raw,
+                (&addr as *const libc::sockaddr_in6).cast(),
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        }
+    } else {
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = MDNS_PORT.to_be();
+        unsafe {
+            libc::bind(
+                raw,
+                (&addr as *const libc::sockaddr_in).cast(),
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        }
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(StdUdpSocket::from(fd))
+}
+
+/// The addresses of this host's interfaces, loopback left out.
+pub fn local_addresses() -> Result<Vec<IpAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut addrs = Vec::new();
+    let mut next = ifaddrs;
+    while let Some(ifaddr) = unsafe { next.as_ref() } {
+        next = ifaddr.ifa_next;
+        let Some(sockaddr) = (unsafe { ifaddr.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        let addr = match sockaddr.sa_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        if !addr.is_loopback() && !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// This host's name under .local, from the first label of its hostname.
+pub fn local_name() -> Option<String> {
+    let hostname = crate::logging::hostname()?;
+    let label = hostname.split('.').next()?;
+    (!label.is_empty()).then(|| format!("{}.local", label.to_lowercase()))
+}
+
+/// Asks the link about `name` with a one-shot query and returns every record that comes back
+/// within `wait`, answers and additional records alike, with the cache flush bit cleared.
+pub async fn query(name: &str, qtype: QueryType, wait: Duration) -> Result<Vec<DnsRecord>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let mut query = DnsPacket::query(name, qtype)
+        .recursion_desired(false)
+        .edns(None)
+        .build();
+    send(&socket, &mut query, (GROUP_V4, MDNS_PORT).into()).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut records: Vec<DnsRecord> = Vec::new();
+    loop {
+        // a fresh buffer each time, as in `serve`
+        let mut buf = BytePacketBuffer::with_len(MAX_MESSAGE);
+        let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf.buf[..])).await
+        else {
+            break;
+        };
+        let (len, _) = received?;
+        let Ok(res) = DnsPacket::from_message(&mut buf, len) else {
+            continue;
+        };
+        // responders to one-shot queries echo the id, section 6.7
+        if !res.header.flags.is_response() || res.header.id != query.header.id {
+            continue;
+        }
+        for mut record in res.answers.into_iter().chain(res.additional) {
+            if let DnsRecord::OPT { .. } = record {
+                continue;
+            }
+            record.set_class(record.class() & !CACHE_FLUSH);
+            if !records.contains(&record) {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Answers unicast questions about names in the multicast DNS [`ZONES`] by asking the link,
+/// and passes everything else to `inner`.
+pub struct MdnsProxy<H> {
+    inner: H,
+    wait: Duration,
+    enabled: bool,
+}
+
+impl<H: Handler> MdnsProxy<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            wait: DEFAULT_WAIT,
+            enabled: true,
+        }
+    }
+
+    /// Whether to ask the link at all, so the proxy can be in a chain of handlers either way.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// How long to wait for the link to answer.
+    pub fn wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    async fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if !self.enabled
+            || question.class != 1
+            || !ZONES.iter().any(|zone| is_subdomain(&question.name, zone))
+        {
+            return None;
+        }
+        let mut res = DnsPacket::response_to(request);
+        res.set_recursion_available(true);
+        match query(&question.name, question.qtype, self.wait).await {
+            Ok(records) => {
+                let name = question.name.trim_end_matches('.');
+                for record in records {
+                    let owner = record.domain().eq_ignore_ascii_case(name);
+                    let wanted = question.qtype == ANY || record.qtype() == question.qtype;
+                    if owner && wanted {
+                        res.add_answer(record);
+                    } else {
+                        res.add_additional(record);
+                    }
+                }
+                if res.answers.is_empty() {
+                    res.additional.clear();
+                    res.set_rcode(ResultCode::NXDOMAIN);
+                }
+            }
+            Err(e) => {
+                logging::warning(&format!(
+                    "multicast DNS query for {} failed: {}",
+                    question.name, e
+                ));
+                res.set_rcode(ResultCode::SERVFAIL);
+            }
+        }
+        res.set_edns(DnsPacket::response_edns(request).as_ref());
+        Some(res)
+    }
+}
+
+impl<H: Handler> Handler for MdnsProxy<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        match self.answer(&request).await {
+            Some(res) => Some(res),
+            None => self.inner.handle(request, src).await,
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// the QR flag is the most significant bit of the third byte in the header
+fn qr_bit(packet: &[u8]) -> Option<bool> {
+    if packet.len() < 12 {
+        return None;
+    }
+    Some((packet[2] & 0x80) != 0)
+}
+
+/// Counters for packets that were dropped because they broke basic protocol expectations.
+#[derive(Debug, Default)]
+pub struct AnomalyCounters {
+    /// Responses (QR=1) that arrived on a listening socket.
+    pub responses_on_listener: AtomicU64,
+    /// Queries (QR=0) that arrived from an upstream we sent a query to.
+    pub queries_from_upstream: AtomicU64,
+    /// Packets too short to even hold a header.
+    pub runts: AtomicU64,
+    /// Responses that came back to a query's socket but didn't parse or didn't have its id and
+    /// question, spoofing attempts or late answers to earlier queries.
+    pub unmatched_responses: AtomicU64,
+    /// Responses that didn't echo the case of the name we asked about, see
+    /// [`crate::client::Client::randomize_case`].
+    pub case_mismatches: AtomicU64,
+}
+
+impl AnomalyCounters {
+    /// Checks a raw packet received on a listening socket. Only queries should be handled
+    /// there, anything with QR set is a response (stray, spoofed or reflected) and is dropped
+    /// without parsing it or answering it.
+    pub fn accept_query(&self, packet: &[u8]) -> bool {
+        match qr_bit(packet) {
+            Some(false) => true,
+            Some(true) => {
+                self.responses_on_listener.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            None => {
+                self.runts.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Checks a raw packet received from an upstream. Only responses are acceptable.
+    pub fn accept_response(&self, packet: &[u8]) -> bool {
+        match qr_bit(packet) {
+            Some(true) => true,
+            Some(false) => {
+                self.queries_from_upstream.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            None => {
+                self.runts.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
+
+/// Counters for the case of names sent to an upstream, see
+/// [`crate::client::Client::randomize_case`].
+#[derive(Debug, Default)]
+pub struct CaseCounters {
+    /// Responses checked for the case of the name asked about.
+    pub checked: AtomicU64,
+    /// Responses that didn't keep it.
+    pub mismatches: AtomicU64,
+    /// Set once too many responses didn't, after which names go to the upstream in lower
+    /// case, see [`crate::upstreams::Upstreams::max_case_mismatches`].
+    pub off: AtomicBool,
+}
+
+/// Counters for TLS connections to an upstream.
+#[derive(Debug, Default)]
+pub struct TlsCounters {
+    /// Handshakes given up on because no key in the server's chain matched its pins, see
+    /// [`crate::tls::ServerIdentity::pin`].
+    pub pin_mismatches: AtomicU64,
+}
+
+/// Counters for the queries the server answers.
+#[derive(Debug, Default)]
+pub struct QueryCounters {
+    /// Every query, refused, limited and dropped ones included.
+    pub queries: AtomicU64,
+    /// Queries answered from a blocklist.
+    pub blocked: AtomicU64,
+}
+
+/// Counters for the answer cache.
+#[derive(Debug, Default)]
+pub struct CacheCounters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    /// Entries dropped because they outlived their TTL.
+    pub expired: AtomicU64,
+    /// Entries dropped to make room under the entry or byte limit.
+    pub evictions: AtomicU64,
+    /// Popular entries refreshed ahead of their expiry.
+    pub prefetches: AtomicU64,
+    /// Misses answered by a resolution another query for the same key had already started.
+    pub coalesced: AtomicU64,
+}
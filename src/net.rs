@@ -0,0 +1,222 @@
+// address parsing shared by listeners and upstreams. std's SocketAddr parser only accepts numeric
+// scope ids, but link-local addresses are normally written with the interface name, e.g.
+// fe80::1%eth0, so we resolve that ourselves.
+use crate::error::{DnsError, Result};
+use std::ffi::CString;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::str::FromStr;
+
+/// Parses `addr`, `addr:port`, `[v6addr]:port` or a bare v6 address, each optionally carrying a
+/// `%scope` suffix on v6 addresses that is either an interface name or a numeric index.
+pub fn parse_socket_addr(s: &str, default_port: u16) -> Result<SocketAddr> {
+    let s = s.trim();
+
+    // [v6%scope]:port or [v6%scope]
+    if let Some(rest) = s.strip_prefix('[') {
+        let Some((host, tail)) = rest.split_once(']') else {
+            return Err(DnsError::InvalidAddress(format!(
+                "missing closing bracket in {:?}",
+                s
+            )));
+        };
+        let port = match tail {
+            "" => default_port,
+            _ => match tail.strip_prefix(':') {
+                Some(port) => port.parse()?,
+                None => {
+                    return Err(DnsError::InvalidAddress(format!(
+                        "unexpected characters after address in {:?}",
+                        s
+                    )))
+                }
+            },
+        };
+        return Ok(SocketAddr::V6(parse_v6(host, port)?));
+    }
+
+    // bare v6, possibly with a scope. a v6 address always has more than one colon.
+    if s.matches(':').count() > 1 {
+        return Ok(SocketAddr::V6(parse_v6(s, default_port)?));
+    }
+
+    // v4 with or without a port
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip: IpAddr = s
+        .parse()
+        .map_err(|_| DnsError::InvalidAddress(format!("invalid address {:?}", s)))?;
+    Ok(SocketAddr::new(ip, default_port))
+}
+
+fn parse_v6(host: &str, port: u16) -> Result<SocketAddrV6> {
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+    let ip: Ipv6Addr = ip
+        .parse()
+        .map_err(|_| DnsError::InvalidAddress(format!("invalid ipv6 address {:?}", host)))?;
+
+    let scope_id = match scope {
+        None => 0,
+        Some(scope) => scope_id(scope)?,
+    };
+    if scope_id != 0 && !is_link_local(&ip) && !ip.is_multicast() {
+        return Err(DnsError::InvalidAddress(format!(
+            "scope id is only meaningful on link-local addresses: {:?}",
+            host
+        )));
+    }
+
+    Ok(SocketAddrV6::new(ip, port, 0, scope_id))
+}
+
+/// Resolves a scope given either as an interface index or an interface name.
+pub fn scope_id(scope: &str) -> Result<u32> {
+    if let Ok(index) = scope.parse::<u32>() {
+        return Ok(index);
+    }
+
+    let name = CString::new(scope)
+        .map_err(|_| DnsError::InvalidAddress(format!("invalid interface name {:?}", scope)))?;
+    // SAFETY: name is a valid nul terminated string that outlives the call
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(DnsError::InvalidAddress(format!(
+            "unknown interface {:?}",
+            scope
+        )));
+    }
+    Ok(index)
+}
+
+// fe80::/10
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// The name PTR queries for `addr` ask about, e.g. `4.3.2.1.in-addr.arpa` (rfc 1035 section
+/// 3.5, rfc 3596 section 2.5).
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(72);
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Link-local addresses without a scope id can't be used, the kernel wouldn't know which
+/// interface to send on. Catch that when the address is configured rather than on first use.
+pub fn check_scope(addr: &SocketAddr) -> Result<()> {
+    if let SocketAddr::V6(v6) = addr {
+        if is_link_local(v6.ip()) && v6.scope_id() == 0 {
+            return Err(DnsError::InvalidAddress(format!(
+                "link-local address {} needs a scope id, e.g. %eth0",
+                v6.ip()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Binds a udp socket, rejecting unscoped link-local addresses up front.
+pub fn bind_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    check_scope(&addr)?;
+    Ok(UdpSocket::bind(addr)?)
+}
+
+/// An address prefix like `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Bits of `addr` past the prefix are cleared.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let addr = match addr {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & v4_mask(prefix)))
+            }
+            IpAddr::V6(ip) if prefix <= 128 => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & v6_mask(prefix)))
+            }
+            _ => {
+                return Err(DnsError::InvalidAddress(format!(
+                    "prefix /{} is too long for {}",
+                    prefix, addr
+                )))
+            }
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.prefix) == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & v6_mask(self.prefix) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+// a bare address is a subnet holding just that address
+impl FromStr for Subnet {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| DnsError::InvalidAddress(format!("invalid subnet {:?}", s)))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| DnsError::InvalidAddress(format!("invalid subnet {:?}", s)))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Subnet::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
@@ -0,0 +1,144 @@
+// local records declared in the config file, answered authoritatively before anything is
+// looked up: the home-lab case of nas.home -> 192.168.1.10 without writing a zone file. a name
+// with local records has only those, queries for other types get an empty answer rather than
+// being forwarded where nobody knows the name. CNAMEs are followed through the local records,
+// and a chain that leads out of them is completed by looking the target up.
+use crate::server::Handler;
+use crate::structure::{DnsPacket, DnsRecord, Opcode, QueryType};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+// longer chains are almost certainly a loop
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Records to answer from by name.
+#[derive(Clone, Debug, Default)]
+pub struct LocalRecords {
+    names: HashMap<String, Vec<DnsRecord>>,
+}
+
+impl LocalRecords {
+    pub fn new(records: impl IntoIterator<Item = DnsRecord>) -> Self {
+        let mut local = Self::default();
+        for record in records {
+            local.insert(record);
+        }
+        local
+    }
+
+    pub fn insert(&mut self, record: DnsRecord) {
+        self.names
+            .entry(record.domain().trim_end_matches('.').to_lowercase())
+            .or_default()
+            .push(record);
+    }
+
+    /// How many names have records.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The response for `request` if it asks for a name with local records. When a CNAME
+    /// leads to a name without any, the answer ends with that CNAME.
+    pub fn answer(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        let question = request.questions.first()?;
+        if question.class != 1 || request.header.flags.opcode != Opcode::QUERY {
+            return None;
+        }
+        let mut name = question.name.trim_end_matches('.').to_lowercase();
+        let mut records = self.names.get(&name)?;
+
+        let mut res = DnsPacket::response_to(request);
+        res.set_authoritative(true).set_recursion_available(true);
+        let mut seen = vec![name.clone()];
+        for _ in 0..MAX_CNAME_CHAIN {
+            let matching: Vec<&DnsRecord> = records
+                .iter()
+                .filter(|rec| rec.qtype() == question.qtype)
+                .collect();
+            if !matching.is_empty() {
+                for rec in matching {
+                    res.add_answer(rec.clone());
+                }
+                break;
+            }
+            let Some(cname) = records.iter().find(|rec| rec.qtype() == QueryType::CNAME) else {
+                break;
+            };
+            res.add_answer(cname.clone());
+            let DnsRecord::CNAME { host, .. } = cname else {
+                unreachable!("qtype() said it's a CNAME");
+            };
+            name = host.trim_end_matches('.').to_lowercase();
+            if seen.contains(&name) {
+                break;
+            }
+            seen.push(name.clone());
+            match self.names.get(&name) {
+                Some(next) => records = next,
+                None => break,
+            }
+        }
+        res.set_edns(DnsPacket::response_edns(request).as_ref());
+        Some(res)
+    }
+}
+
+/// Answers from [`LocalRecords`] before asking `inner`, which looks up where CNAMEs lead when
+/// it's out of the local records.
+pub struct Overrides<H> {
+    inner: H,
+    local: LocalRecords,
+}
+
+impl<H: Handler> Overrides<H> {
+    pub fn new(inner: H, local: LocalRecords) -> Self {
+        Self { inner, local }
+    }
+}
+
+impl<H: Handler> Handler for Overrides<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let Some(mut res) = self.local.answer(&request) else {
+            return self.inner.handle(request, src).await;
+        };
+        let question = &request.questions[0];
+        let target = match res.answers.last() {
+            Some(DnsRecord::CNAME { host, .. }) if question.qtype != QueryType::CNAME => {
+                host.clone()
+            }
+            _ => return Some(res),
+        };
+        if self
+            .local
+            .names
+            .contains_key(&target.trim_end_matches('.').to_lowercase())
+        {
+            // a loop, or a chain too long to follow
+            return Some(res);
+        }
+
+        // the rest of the chain comes from outside, and isn't ours to vouch for
+        let mut lookup = request.clone();
+        lookup.questions[0].name = target;
+        let Some(rest) = self.inner.handle(lookup, src).await else {
+            return Some(res);
+        };
+        res.set_authoritative(false).set_rcode(rest.header.rcode);
+        for rec in rest.answers {
+            res.add_answer(rec);
+        }
+        for rec in rest.authorities {
+            res.add_authority(rec);
+        }
+        Some(res)
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.inner.transfer(request, src).await
+    }
+}
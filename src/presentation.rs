@@ -0,0 +1,695 @@
+// parsing records from master file (zone file) text, the inverse of the Display impls in
+// structure.rs. this handles a single record, directives and multi-line records are left to
+// whatever reads whole files.
+use crate::error::{DnsError, Result};
+use crate::logging::days_from_civil;
+use crate::structure::{BytePacketBuffer, DnsRecord, QueryType};
+use crate::svcb;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Token {
+    pub text: String,
+    // quoted tokens have their escapes resolved already, unquoted ones are kept as written
+    pub quoted: bool,
+}
+
+/// Splits a line into tokens. Quoted strings are kept together, `;` starts a comment and
+/// parentheses are treated as whitespace.
+pub(crate) fn tokenize(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            '(' | ')' => {
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut bytes = Vec::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(DnsError::Syntax("unterminated quoted string".into())),
+                        Some('"') => break,
+                        Some('\\') => bytes.push(unescape_one(&mut chars)?),
+                        Some(c) => {
+                            let mut tmp = [0; 4];
+                            bytes.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    text: String::from_utf8_lossy(&bytes).into_owned(),
+                    quoted: true,
+                });
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ';' || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    chars.next();
+                    text.push(c);
+                    // keep an escaped character attached to the token even if it is a space
+                    if c == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            text.push(escaped);
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// resolves the part after a backslash, either \X or \DDD
+fn unescape_one(chars: &mut impl Iterator<Item = char>) -> Result<u8> {
+    let Some(c) = chars.next() else {
+        return Err(DnsError::Syntax("dangling escape".into()));
+    };
+    if let Some(d) = c.to_digit(10) {
+        let mut value = d;
+        for _ in 0..2 {
+            match chars.next().and_then(|c| c.to_digit(10)) {
+                Some(d) => value = value * 10 + d,
+                None => return Err(DnsError::Syntax("\\DDD escapes need three digits".into())),
+            }
+        }
+        return u8::try_from(value)
+            .map_err(|_| DnsError::Syntax(format!("escape \\{} out of range", value)));
+    }
+    if !c.is_ascii() {
+        return Err(DnsError::Syntax("can only escape ascii characters".into()));
+    }
+    Ok(c as u8)
+}
+
+fn unescape(s: &str) -> Result<String> {
+    Ok(String::from_utf8_lossy(&unescape_bytes(s)?).into_owned())
+}
+
+// for values that needn't be text, like SVCB parameters
+pub(crate) fn unescape_bytes(s: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            bytes.push(unescape_one(&mut chars)?);
+        } else {
+            let mut tmp = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+        }
+    }
+    Ok(bytes)
+}
+
+/// Parses a ttl, either plain seconds or with bind style units like `1h30m` or `2d`.
+pub fn parse_ttl(s: &str) -> Result<u32> {
+    if let Ok(secs) = s.parse::<u32>() {
+        return Ok(secs);
+    }
+
+    let mut total: u32 = 0;
+    let mut num: Option<u32> = None;
+    for c in s.chars() {
+        if let Some(d) = c.to_digit(10) {
+            num = Some(
+                num.unwrap_or(0)
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add(d))
+                    .ok_or_else(|| DnsError::Syntax(format!("ttl {:?} is too large", s)))?,
+            );
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(DnsError::Syntax(format!("invalid ttl {:?}", s))),
+        };
+        let Some(n) = num.take() else {
+            return Err(DnsError::Syntax(format!("invalid ttl {:?}", s)));
+        };
+        total = n
+            .checked_mul(unit)
+            .and_then(|n| total.checked_add(n))
+            .ok_or_else(|| DnsError::Syntax(format!("ttl {:?} is too large", s)))?;
+    }
+    if num.is_some() {
+        return Err(DnsError::Syntax(format!("invalid ttl {:?}", s)));
+    }
+    Ok(total)
+}
+
+pub fn parse_class(s: &str) -> Option<u16> {
+    let upper = s.to_ascii_uppercase();
+    match upper.as_str() {
+        "IN" => Some(1),
+        "CH" => Some(3),
+        "HS" => Some(4),
+        _ => upper.strip_prefix("CLASS")?.parse().ok(),
+    }
+}
+
+/// Turns a name as written in a zone file into the form the rest of the crate uses, lowercase
+/// and without the trailing dot. `@` is the origin and names without a trailing dot are
+/// relative to it.
+pub fn absolute_name(name: &str, origin: &str) -> String {
+    let origin = origin.trim_end_matches('.');
+    let name = if name == "@" {
+        origin.to_string()
+    } else if let Some(absolute) = name.strip_suffix('.') {
+        absolute.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    };
+    name.to_lowercase()
+}
+
+/// Parses a single record in master file syntax, e.g. `www 3600 IN CNAME example.com.`.
+/// Relative names are completed with `origin` and `default_ttl` is used when the record
+/// doesn't carry its own.
+pub fn parse_record(line: &str, origin: &str, default_ttl: u32) -> Result<DnsRecord> {
+    let tokens = tokenize(line)?;
+    let owner_omitted = line.starts_with(char::is_whitespace);
+    let owner = if owner_omitted { Some(origin) } else { None };
+    parse_tokens(&tokens, origin, Some(default_ttl), owner)
+}
+
+// the core of the parser, shared with multi-line readers. `owner` is used when the record's
+// own owner field is left blank, and a missing ttl is an error when `default_ttl` is None.
+pub(crate) fn parse_tokens(
+    tokens: &[Token],
+    origin: &str,
+    default_ttl: Option<u32>,
+    owner: Option<&str>,
+) -> Result<DnsRecord> {
+    let mut tokens = tokens.iter();
+
+    let domain = match owner {
+        Some(owner) => owner.trim_end_matches('.').to_lowercase(),
+        None => {
+            let Some(tok) = tokens.next() else {
+                return Err(DnsError::Syntax("empty record".into()));
+            };
+            absolute_name(&tok.text, origin)
+        }
+    };
+
+    // ttl and class are both optional and may come in either order
+    let mut ttl = None;
+    let mut class = None;
+    let qtype = loop {
+        let Some(tok) = tokens.next() else {
+            return Err(DnsError::Syntax("missing record type".into()));
+        };
+        if ttl.is_none() && tok.text.starts_with(|c: char| c.is_ascii_digit()) {
+            ttl = Some(parse_ttl(&tok.text)?);
+        } else if let Some(c) = class.is_none().then(|| parse_class(&tok.text)).flatten() {
+            class = Some(c);
+        } else {
+            break QueryType::from_str(&tok.text)?;
+        }
+    };
+
+    let ttl = match ttl.or(default_ttl) {
+        Some(ttl) => ttl,
+        None => {
+            return Err(DnsError::Syntax(
+                "record has no ttl and there is no default".into(),
+            ))
+        }
+    };
+    let class = class.unwrap_or(1);
+    let rdata: Vec<&Token> = tokens.collect();
+
+    // rfc 3597 generic rdata, e.g. "TYPE65534 \# 3 abcdef". it can be used for known types
+    // too, in which case the bytes are decoded like they would be off the wire.
+    if rdata.first().is_some_and(|t| !t.quoted && t.text == "\\#") {
+        let raw = DnsRecord::raw(&domain, qtype, class, ttl, parse_generic(&rdata[1..])?);
+        return match qtype {
+            QueryType::UNKNOWN(_) => Ok(raw),
+            QueryType::OPT | QueryType::TSIG => Err(DnsError::Syntax(format!(
+                "{} is a pseudo-record and can't appear in zone data",
+                qtype
+            ))),
+            QueryType::IXFR | QueryType::AXFR => Err(DnsError::Syntax(format!(
+                "{} is only a query type and can't appear in zone data",
+                qtype
+            ))),
+            _ => decode_generic(&raw),
+        };
+    }
+
+    let field = |i: usize| -> Result<&str> {
+        rdata
+            .get(i)
+            .map(|t| t.text.as_str())
+            .ok_or_else(|| DnsError::Syntax(format!("{} record is missing rdata", qtype)))
+    };
+    let name = |i: usize| -> Result<String> { Ok(absolute_name(field(i)?, origin)) };
+    // keys, signatures and digests may be broken up by whitespace, they run to the end
+    let rest = |i: usize| -> Result<String> {
+        field(i)?;
+        Ok(rdata[i..].iter().map(|t| t.text.as_str()).collect())
+    };
+    let types = |i: usize| -> Result<Vec<QueryType>> {
+        rdata[i.min(rdata.len())..]
+            .iter()
+            .map(|t| t.text.parse())
+            .collect()
+    };
+
+    let expected = match qtype {
+        QueryType::A
+        | QueryType::AAAA
+        | QueryType::NS
+        | QueryType::CNAME
+        | QueryType::PTR
+        | QueryType::DNAME => 1,
+        QueryType::MX => 2,
+        QueryType::SRV => 4,
+        QueryType::SOA => 7,
+        QueryType::NSEC3PARAM => 4,
+        _ => rdata.len(),
+    };
+    if rdata.len() > expected {
+        return Err(DnsError::Syntax(format!(
+            "too many fields for {} record",
+            qtype
+        )));
+    }
+
+    let record = match qtype {
+        QueryType::A => DnsRecord::A {
+            domain,
+            class,
+            ttl,
+            ip: field(0)?.parse::<Ipv4Addr>()?.into(),
+        },
+        QueryType::AAAA => DnsRecord::AAAA {
+            domain,
+            class,
+            ttl,
+            ip: field(0)?.parse::<Ipv6Addr>()?.into(),
+        },
+        QueryType::NS => DnsRecord::NS {
+            domain,
+            class,
+            ttl,
+            host: name(0)?,
+        },
+        QueryType::CNAME => DnsRecord::CNAME {
+            domain,
+            class,
+            ttl,
+            host: name(0)?,
+        },
+        QueryType::PTR => DnsRecord::PTR {
+            domain,
+            class,
+            ttl,
+            host: name(0)?,
+        },
+        QueryType::DNAME => DnsRecord::DNAME {
+            domain,
+            class,
+            ttl,
+            host: name(0)?,
+        },
+        QueryType::MX => DnsRecord::MX {
+            domain,
+            class,
+            ttl,
+            priority: field(0)?.parse()?,
+            host: name(1)?,
+        },
+        QueryType::SRV => DnsRecord::SRV {
+            domain,
+            class,
+            ttl,
+            priority: field(0)?.parse()?,
+            weight: field(1)?.parse()?,
+            port: field(2)?.parse()?,
+            host: name(3)?,
+        },
+        QueryType::SVCB => {
+            // a quoted value comes as a token of its own after the key's `=`, its escapes
+            // already resolved, so they're put back for the parameter parser
+            let mut fields: Vec<String> = Vec::new();
+            for tok in rdata.iter().skip(2) {
+                match fields.last_mut() {
+                    Some(last) if tok.quoted && last.ends_with('=') => {
+                        last.push_str(&tok.text.replace('\\', "\\\\"))
+                    }
+                    _ => fields.push(tok.text.clone()),
+                }
+            }
+            let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+            DnsRecord::SVCB {
+                domain,
+                class,
+                ttl,
+                priority: field(0)?.parse()?,
+                target: name(1)?,
+                params: svcb::parse(&fields)?,
+            }
+        }
+        QueryType::SOA => DnsRecord::SOA {
+            domain,
+            class,
+            ttl,
+            mname: name(0)?,
+            rname: name(1)?,
+            serial: field(2)?.parse()?,
+            refresh: parse_ttl(field(3)?)?,
+            retry: parse_ttl(field(4)?)?,
+            expire: parse_ttl(field(5)?)?,
+            minimum: parse_ttl(field(6)?)?,
+        },
+        QueryType::TXT => {
+            if rdata.is_empty() {
+                return Err(DnsError::Syntax("TXT record is missing rdata".into()));
+            }
+            let mut data = Vec::with_capacity(rdata.len());
+            for tok in &rdata {
+                let s = if tok.quoted {
+                    tok.text.clone()
+                } else {
+                    unescape(&tok.text)?
+                };
+                if s.len() > 255 {
+                    return Err(DnsError::Syntax(
+                        "TXT strings can be at most 255 bytes".into(),
+                    ));
+                }
+                data.push(s);
+            }
+            DnsRecord::TXT {
+                domain,
+                class,
+                ttl,
+                data,
+            }
+        }
+        QueryType::DS => DnsRecord::DS {
+            domain,
+            class,
+            ttl,
+            key_tag: field(0)?.parse()?,
+            algorithm: field(1)?.parse()?,
+            digest_type: field(2)?.parse()?,
+            digest: parse_hex(&rest(3)?)?,
+        },
+        QueryType::RRSIG => DnsRecord::RRSIG {
+            domain,
+            class,
+            ttl,
+            type_covered: field(0)?.parse()?,
+            algorithm: field(1)?.parse()?,
+            labels: field(2)?.parse()?,
+            original_ttl: parse_ttl(field(3)?)?,
+            expiration: parse_signature_time(field(4)?)?,
+            inception: parse_signature_time(field(5)?)?,
+            key_tag: field(6)?.parse()?,
+            signer: name(7)?,
+            signature: parse_base64(&rest(8)?)?,
+        },
+        QueryType::NSEC => DnsRecord::NSEC {
+            domain,
+            class,
+            ttl,
+            next: name(0)?,
+            types: types(1)?,
+        },
+        QueryType::DNSKEY => DnsRecord::DNSKEY {
+            domain,
+            class,
+            ttl,
+            flags: field(0)?.parse()?,
+            protocol: field(1)?.parse()?,
+            algorithm: field(2)?.parse()?,
+            public_key: parse_base64(&rest(3)?)?,
+        },
+        QueryType::NSEC3 => DnsRecord::NSEC3 {
+            domain,
+            class,
+            ttl,
+            hash_algorithm: field(0)?.parse()?,
+            flags: field(1)?.parse()?,
+            iterations: field(2)?.parse()?,
+            salt: parse_salt(field(3)?)?,
+            next_hashed: parse_base32hex(field(4)?)?,
+            types: types(5)?,
+        },
+        QueryType::NSEC3PARAM => DnsRecord::NSEC3PARAM {
+            domain,
+            class,
+            ttl,
+            hash_algorithm: field(0)?.parse()?,
+            flags: field(1)?.parse()?,
+            iterations: field(2)?.parse()?,
+            salt: parse_salt(field(3)?)?,
+        },
+        QueryType::NULL => {
+            return Err(DnsError::Syntax(
+                "NULL records can only be written with generic \\# rdata".into(),
+            ))
+        }
+        QueryType::OPT | QueryType::TSIG => {
+            return Err(DnsError::Syntax(format!(
+                "{} is a pseudo-record and can't appear in zone data",
+                qtype
+            )))
+        }
+        QueryType::IXFR | QueryType::AXFR => {
+            return Err(DnsError::Syntax(format!(
+                "{} is only a query type and can't appear in zone data",
+                qtype
+            )))
+        }
+        QueryType::UNKNOWN(_) => {
+            return Err(DnsError::Syntax(format!(
+                "unknown type {} needs generic \\# rdata",
+                qtype
+            )))
+        }
+    };
+
+    Ok(record)
+}
+
+// runs a record holding raw rdata through the wire format to get the typed record
+fn decode_generic(raw: &DnsRecord) -> Result<DnsRecord> {
+    let mut buf = BytePacketBuffer::new();
+    let len = raw.write(&mut buf)?;
+    buf.seek(0)?;
+    let record = DnsRecord::from(&mut buf)?;
+    if buf.pos != len {
+        return Err(DnsError::InvalidRdata(format!(
+            "generic rdata doesn't match the layout of a {} record",
+            raw.qtype()
+        )));
+    }
+    Ok(record)
+}
+
+fn parse_generic(tokens: &[&Token]) -> Result<Vec<u8>> {
+    let Some(len) = tokens.first() else {
+        return Err(DnsError::Syntax(
+            "generic rdata is missing its length".into(),
+        ));
+    };
+    let len: usize = len.text.parse()?;
+
+    let hex: String = tokens[1..].iter().map(|t| t.text.as_str()).collect();
+    let data = parse_hex(&hex)?;
+
+    if data.len() != len {
+        return Err(DnsError::Syntax(format!(
+            "generic rdata length is {} but {} bytes follow",
+            len,
+            data.len()
+        )));
+    }
+    Ok(data)
+}
+
+/// Decodes hex as DS digests and NSEC3 salts are written, in either case.
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(DnsError::Syntax(format!("invalid hex {:?}", hex)));
+    }
+    if !hex.len().is_multiple_of(2) {
+        return Err(DnsError::Syntax(format!(
+            "odd number of hex digits in {:?}",
+            hex
+        )));
+    }
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()?;
+    Ok(data)
+}
+
+// a dash stands for no salt
+fn parse_salt(s: &str) -> Result<Vec<u8>> {
+    if s == "-" {
+        Ok(Vec::new())
+    } else {
+        parse_hex(s)
+    }
+}
+
+/// Parses an RRSIG expiration or inception time, either YYYYMMDDHHmmSS in UTC or seconds
+/// since the epoch (rfc 4034 section 3.2).
+pub fn parse_signature_time(s: &str) -> Result<u32> {
+    let invalid = || DnsError::Syntax(format!("invalid signature time {:?}", s));
+    if s.len() != 14 {
+        return s.parse().map_err(|_| invalid());
+    }
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let num = |range: std::ops::Range<usize>| s[range].parse::<u32>().unwrap_or(0);
+    let (month, day, hour, minute, second) =
+        (num(4..6), num(6..8), num(8..10), num(10..12), num(12..14));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return Err(invalid());
+    }
+    let days = days_from_civil(num(0..4) as i64, month, day);
+    let secs = days * 86400 + (hour * 3600 + minute * 60 + second.min(60)) as i64;
+    // times wrap around every 136 years, compared with serial number arithmetic
+    Ok(secs as u32)
+}
+
+const BASE32HEX: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Decodes base32hex without padding (rfc 4648 section 7), as NSEC3 hashes are written in
+/// records and owner names. Either case is accepted.
+pub fn parse_base32hex(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut bits = 0u64;
+    let mut count = 0;
+    for c in s.bytes() {
+        let Some(val) = BASE32HEX.iter().position(|&b| b == c.to_ascii_uppercase()) else {
+            return Err(DnsError::Syntax(format!(
+                "invalid character {:?} in base32hex",
+                c as char
+            )));
+        };
+        bits = bits << 5 | val as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    // whatever is left over has to be zero padding bits of the last character
+    if count >= 5 || bits & ((1 << count) - 1) != 0 {
+        return Err(DnsError::Syntax(format!(
+            "base32hex {:?} has trailing bits",
+            s
+        )));
+    }
+    Ok(out)
+}
+
+/// The inverse of [`parse_base32hex`], in upper case.
+pub fn base32hex(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut bits = 0u64;
+    let mut count = 0;
+    for &b in data {
+        bits = bits << 8 | b as u64;
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            out.push(BASE32HEX[(bits >> count & 0x1f) as usize] as char);
+        }
+    }
+    if count > 0 {
+        out.push(BASE32HEX[(bits << (5 - count) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Decodes base64 (rfc 4648 section 4) as keys and signatures are written, whitespace is
+/// ignored and the padding is required.
+pub fn parse_base64(s: &str) -> Result<Vec<u8>> {
+    let chars: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !chars.len().is_multiple_of(4) {
+        return Err(DnsError::Syntax(format!("base64 {:?} isn't padded", s)));
+    }
+    let mut out = Vec::with_capacity(chars.len() / 4 * 3);
+    for (i, quad) in chars.chunks_exact(4).enumerate() {
+        let last = i == chars.len() / 4 - 1;
+        let padding = quad.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(DnsError::Syntax(format!(
+                "misplaced padding in base64 {:?}",
+                s
+            )));
+        }
+        let mut bits = 0u32;
+        for &c in &quad[..4 - padding] {
+            let Some(val) = BASE64.iter().position(|&b| b == c) else {
+                return Err(DnsError::Syntax(format!(
+                    "invalid character {:?} in base64",
+                    c as char
+                )));
+            };
+            bits = bits << 6 | val as u32;
+        }
+        bits <<= 6 * padding;
+        out.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+/// The inverse of [`parse_base64`].
+pub fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut bytes = [0u8; 3];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// fully qualified text only, with an explicit ttl
+impl FromStr for DnsRecord {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_tokens(&tokenize(s)?, "", None, None)
+    }
+}
@@ -0,0 +1,128 @@
+// giving up root once the sockets are bound. binding port 53 is the only thing the server needs
+// privileges for, so after that it becomes an unprivileged user, with that user's group and no
+// others, optionally locked into a directory with chroot first. dropping is checked rather than
+// trusted: afterwards the process mustn't be able to become root again or have any
+// capabilities left, whether kept across setuid or had without being root, e.g. ambient ones a
+// systemd unit gave it, which are cleared along the way.
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// capget(2) and capset(2) with 64 bit capability sets, two halves of 32 bits each
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// A user to run as, from the password database.
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    /// The user's primary group.
+    pub gid: libc::gid_t,
+}
+
+impl User {
+    /// Looks `name` up in the password database. Has to happen before chroot, which usually
+    /// leaves the database behind.
+    pub fn lookup(name: &str) -> io::Result<Self> {
+        let c_name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "user name with a NUL"))?;
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16 * 1024];
+        let mut found = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        if found.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no user {:?}", name),
+            ));
+        }
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+        Ok(Self {
+            name: name.to_string_lossy().into_owned(),
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+        })
+    }
+}
+
+/// Becomes `user`, after locking the process into `root` if given, and checks there's no way
+/// back. Every thread changes along, the C library sees to that. Without root this only works
+/// for becoming the user the process already is, which still clears its capabilities.
+pub fn drop_to(user: &User, root: Option<&Path>) -> io::Result<()> {
+    if let Some(root) = root {
+        let dir = CString::new(root.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "chroot path with a NUL"))?;
+        check(unsafe { libc::chroot(dir.as_ptr()) })?;
+        check(unsafe { libc::chdir(c"/".as_ptr()) })?;
+    }
+    let same = unsafe { libc::getuid() == user.uid && libc::getgid() == user.gid };
+    if !same {
+        // the supplementary groups root had go first, setgid doesn't touch them
+        check(unsafe { libc::setgroups(1, &user.gid) })?;
+        check(unsafe { libc::setgid(user.gid) })?;
+        check(unsafe { libc::setuid(user.uid) })?;
+    }
+    set_capabilities(&[CapData::default(); 2])?;
+
+    if user.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other("could become root again"));
+    }
+    let left = capabilities()?;
+    if left.iter().any(|data| *data != CapData::default()) {
+        return Err(io::Error::other("capabilities are left"));
+    }
+    Ok(())
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn capabilities() -> io::Result<[CapData; 2]> {
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let ret = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    check(ret as libc::c_int)?;
+    Ok(data)
+}
+
+fn set_capabilities(data: &[CapData; 2]) -> io::Result<()> {
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    check(ret as libc::c_int)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
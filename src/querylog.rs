@@ -0,0 +1,150 @@
+// the query log: one record per query on the log's queries stream, with who asked, what, the
+// rcode, how long answering took, where the answer came from and the addresses in it. it's for
+// finding out what a client was told and who's abusing the server, so it sits in front of
+// everything, refused and dropped queries included. logging every query costs more than
+// answering it, so a sampler picks the ones that are (see sampling.rs), the rest are only
+// counted, as every query is whether it's logged or not. where the answer came from is worked
+// out on the way: the cache says when it answered or asked upstream, through a value that lives
+// as long as the query's task (see Origin), and an answer nobody said anything about was made
+// up locally.
+use crate::logging::{LogRecord, Logger, Severity, Stream};
+use crate::metrics::QueryCounters;
+use crate::sampling::QuerySampler;
+use crate::server::Handler;
+use crate::structure::DnsPacket;
+use std::cell::Cell;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+tokio::task_local! {
+    static ORIGIN: Cell<Origin>;
+}
+
+/// Where an answer came from, in the order they win out when a query has more than one, e.g.
+/// a validated answer that was cached but its keys weren't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Origin {
+    /// Zones, hosts files, local records, blocklists and the like.
+    Local,
+    Cache,
+    Upstream,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Origin::Local => "local",
+            Origin::Cache => "cache",
+            Origin::Upstream => "upstream",
+        })
+    }
+}
+
+/// Says the query being answered got (part of) its answer from `origin`. Does nothing unless
+/// the query is logged.
+pub fn answered_from(origin: Origin) {
+    let _ = ORIGIN.try_with(|answered| answered.set(answered.get().max(origin)));
+}
+
+/// Logs the queries `inner` answers on the queries stream of `logger`.
+pub struct QueryLog<H> {
+    inner: H,
+    logger: Logger,
+    sampler: QuerySampler,
+    enabled: bool,
+    counters: Arc<QueryCounters>,
+}
+
+impl<H: Handler> QueryLog<H> {
+    /// Logs every query.
+    pub fn new(inner: H, logger: Logger) -> Self {
+        Self {
+            inner,
+            logger,
+            sampler: QuerySampler::default(),
+            enabled: true,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Whether to log at all, so the log can be in a chain of handlers either way.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Logs the queries `sampler` picks instead of all of them.
+    pub fn sampler(mut self, sampler: QuerySampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Counts the queries in `counters`, logged or not, so they can outlive the log.
+    pub fn counters(mut self, counters: Arc<QueryCounters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    fn log(&self, request: &DnsPacket, src: SocketAddr, response: Option<&DnsPacket>, took: f64) {
+        let question = &request.questions[0];
+        let (rcode, answers) = match response {
+            Some(res) => {
+                let addrs: Vec<String> = (res.answers.iter())
+                    .filter_map(|rec| Some(rec.address()?.to_string()))
+                    .collect();
+                (format!("{:?}", res.header.rcode), addrs.join(","))
+            }
+            None => ("dropped".to_string(), String::new()),
+        };
+        let origin = ORIGIN.try_with(Cell::get).unwrap_or(Origin::Local);
+        let mut fields = vec![
+            ("client", src.ip().to_string()),
+            ("name", question.name.clone()),
+            ("type", question.qtype.to_string()),
+            ("rcode", rcode),
+            ("ms", format!("{:.3}", took)),
+            ("from", origin.to_string()),
+        ];
+        if !answers.is_empty() {
+            fields.push(("answers", answers));
+        }
+        self.logger.log(
+            Stream::Query,
+            &LogRecord {
+                severity: Severity::Info,
+                message: "query",
+                fields: &fields,
+            },
+        );
+    }
+}
+
+impl<H: Handler> Handler for QueryLog<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        self.counters.queries.fetch_add(1, Ordering::Relaxed);
+        let logged = match request.questions.first() {
+            Some(question) => self.enabled && self.sampler.should_log(question),
+            None => false,
+        };
+        if !logged {
+            return self.inner.handle(request, src).await;
+        }
+        let start = Instant::now();
+        let query = request.clone();
+        ORIGIN
+            .scope(Cell::new(Origin::Local), async move {
+                let response = self.inner.handle(request, src).await;
+                let took = start.elapsed().as_secs_f64() * 1000.0;
+                self.log(&query, src, response.as_ref(), took);
+                response
+            })
+            .await
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.inner.transfer(request, src).await
+    }
+}
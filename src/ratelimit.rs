@@ -0,0 +1,210 @@
+// per-client query rate limiting: a token bucket for each client address, so one device
+// stuck in a retry loop or infected with something chatty can't take the server from
+// everybody else on the network. unlike rrl.rs this counts queries rather than responses,
+// over udp and tcp alike, and a client over its rate is either refused or not answered at
+// all. the bucket holds up to `burst` tokens, refills at `rate` a second, and every query
+// takes one.
+use crate::edns::EdeCode;
+use crate::error::{DnsError, Result};
+use crate::net::Subnet;
+use crate::server::Handler;
+use crate::structure::{DnsPacket, ResultCode};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_QUERIES_PER_SECOND: u32 = 100;
+pub const DEFAULT_BURST: u32 = 200;
+pub const DEFAULT_MAX_CLIENTS: usize = 100_000;
+
+/// What a client over its rate gets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LimitAction {
+    /// REFUSED, with an extended error saying why when the client sent EDNS.
+    #[default]
+    Refuse,
+    /// Nothing.
+    Drop,
+}
+
+impl FromStr for LimitAction {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "refuse" => Ok(LimitAction::Refuse),
+            "drop" => Ok(LimitAction::Drop),
+            _ => Err(DnsError::Syntax(format!(
+                "{:?} is neither refuse nor drop",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token buckets by client address.
+#[derive(Debug)]
+pub struct QueryLimiter {
+    rate: u32,
+    burst: u32,
+    action: LimitAction,
+    exempt: Vec<Subnet>,
+    max_clients: usize,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    /// Queries over a client's rate.
+    pub limited: AtomicU64,
+}
+
+impl Default for QueryLimiter {
+    fn default() -> Self {
+        QueryLimiter::new()
+    }
+}
+
+// a copy has the same settings and starts counting afresh
+impl Clone for QueryLimiter {
+    fn clone(&self) -> Self {
+        Self {
+            exempt: self.exempt.clone(),
+            buckets: Mutex::new(HashMap::new()),
+            limited: AtomicU64::new(0),
+            ..*self
+        }
+    }
+}
+
+impl QueryLimiter {
+    pub fn new() -> Self {
+        Self {
+            rate: DEFAULT_QUERIES_PER_SECOND,
+            burst: DEFAULT_BURST,
+            action: LimitAction::default(),
+            exempt: Vec::new(),
+            max_clients: DEFAULT_MAX_CLIENTS,
+            buckets: Mutex::new(HashMap::new()),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Queries a second a client gets on average. Zero turns limiting off.
+    pub fn rate(mut self, rate: u32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Queries a client can send at once after being quiet.
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    pub fn action(mut self, action: LimitAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Clients in `net` are never limited.
+    pub fn exempt(mut self, net: Subnet) -> Self {
+        self.exempt.push(net);
+        self
+    }
+
+    /// Caps how many clients are tracked. When it's reached clients whose buckets have
+    /// filled up again are forgotten, and if there are none new clients aren't limited until
+    /// there are.
+    pub fn max_clients(mut self, max: usize) -> Self {
+        self.max_clients = max;
+        self
+    }
+
+    /// Takes a token from `client`'s bucket. False if there wasn't one.
+    pub fn allow(&self, client: IpAddr) -> bool {
+        self.allow_at(client, Instant::now())
+    }
+
+    fn allow_at(&self, client: IpAddr, now: Instant) -> bool {
+        if self.rate == 0 || self.exempt.iter().any(|net| net.contains(client)) {
+            return true;
+        }
+        let (rate, burst) = (self.rate as f64, self.burst as f64);
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&client) && buckets.len() >= self.max_clients {
+            let full = Duration::from_secs_f64(burst / rate);
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.last) < full);
+            if buckets.len() >= self.max_clients {
+                return true;
+            }
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        let earned = now.saturating_duration_since(bucket.last).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + earned).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    // what a limited client gets for `request`
+    fn refusal(&self, request: &DnsPacket) -> Option<DnsPacket> {
+        if self.action == LimitAction::Drop {
+            return None;
+        }
+        let mut res = DnsPacket::response_to(request);
+        res.set_rcode(ResultCode::REFUSED);
+        let edns = DnsPacket::response_edns(request);
+        res.set_edns(edns.as_ref());
+        if edns.is_some() {
+            let _ = res.add_extended_error(EdeCode::Prohibited, "query rate exceeded");
+        }
+        Some(res)
+    }
+}
+
+/// Passes the queries of clients under their rate on to `inner`.
+pub struct Limited<H> {
+    inner: H,
+    limiter: QueryLimiter,
+}
+
+impl<H: Handler> Limited<H> {
+    pub fn new(inner: H, limiter: QueryLimiter) -> Self {
+        Self { inner, limiter }
+    }
+
+    pub fn limiter(&self) -> &QueryLimiter {
+        &self.limiter
+    }
+}
+
+impl<H: Handler> Handler for Limited<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        if !self.limiter.allow(src.ip()) {
+            return self.limiter.refusal(&request);
+        }
+        self.inner.handle(request, src).await
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        if !self.limiter.allow(src.ip()) {
+            // dropping a transfer means answering nothing at all rather than passing it on
+            return Some(self.limiter.refusal(request).into_iter().collect());
+        }
+        self.inner.transfer(request, src).await
+    }
+}
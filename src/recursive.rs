@@ -0,0 +1,400 @@
+// iterative resolution from the root down. each step asks one of the servers for the current
+// zone, and either gets the answer or a referral to the servers of a zone closer to the name.
+// nameserver addresses come from glue when there is some and are resolved separately when not,
+// and CNAMEs are followed until the chain reaches the asked for type. every nested lookup counts
+// against a depth limit, and referrals have to lead strictly downwards, so misconfigured or
+// hostile zones can't keep a query going forever. with qname minimisation (rfc 9156) each
+// zone's servers are only asked about the name one label further down than their zone rather
+// than the whole name, as an A query, until the name is reached. servers that don't handle
+// that (an error or NXDOMAIN for a name that has names below it) are asked the whole name.
+use crate::client::Client;
+use crate::dnssec::strip_dnssec;
+use crate::error::{DnsError, Result};
+use crate::logging;
+use crate::server::Handler;
+use crate::structure::{
+    is_subdomain, DnsPacket, DnsRecord, QueryType, ResultCode, DEFAULT_EDNS_PAYLOAD,
+};
+use rand::seq::SliceRandom;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+
+// nested lookups, i.e. CNAME targets and nameservers without glue
+const DEFAULT_MAX_DEPTH: usize = 8;
+// real delegation chains are a handful of levels deep
+const MAX_REFERRALS: usize = 16;
+// longer chains are almost certainly a loop between zones
+const MAX_CNAME_CHAIN: usize = 8;
+// rfc 9156 section 2.3: at most this many minimised queries for a name, the first few of them
+// one label at a time and the rest in bigger steps, so long names can't make us send a lot
+const MAX_MINIMISE_COUNT: usize = 10;
+const MINIMISE_ONE_LAB: usize = 4;
+
+// https://www.iana.org/domains/root/servers
+const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// The ipv4 addresses of the root servers.
+pub fn root_hints() -> Vec<SocketAddr> {
+    ROOT_SERVERS
+        .iter()
+        .map(|ip| SocketAddr::new(IpAddr::V4(*ip), 53))
+        .collect()
+}
+
+type Lookup<'a> = Pin<Box<dyn Future<Output = Result<DnsPacket>> + Send + 'a>>;
+
+pub struct Resolver {
+    roots: Vec<SocketAddr>,
+    client: Client,
+    max_depth: usize,
+    edns_payload: u16,
+    qname_minimisation: bool,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(root_hints())
+    }
+}
+
+impl Resolver {
+    pub fn new(roots: Vec<SocketAddr>) -> Self {
+        Self {
+            roots,
+            client: Client::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            edns_payload: DEFAULT_EDNS_PAYLOAD,
+            qname_minimisation: true,
+        }
+    }
+
+    /// How long to wait for each server before trying the next one.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client = self.client.timeout(timeout);
+        self
+    }
+
+    /// Caps the number of sockets open towards authoritative servers at once.
+    pub fn max_sockets(mut self, max: usize) -> Self {
+        self.client = self.client.max_sockets(max);
+        self
+    }
+
+    /// How deep lookups for CNAME targets and nameserver addresses may nest.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// The udp payload size advertised to authoritative servers.
+    pub fn edns_payload(mut self, size: u16) -> Self {
+        self.edns_payload = size;
+        self
+    }
+
+    /// Whether servers are only asked about as much of the name as they need to refer us on,
+    /// on by default.
+    pub fn qname_minimisation(mut self, on: bool) -> Self {
+        self.qname_minimisation = on;
+        self
+    }
+
+    /// Randomizes the case of names sent to authoritative servers, see
+    /// [`Client::randomize_case`].
+    pub fn randomize_case(mut self, on: bool) -> Self {
+        self.client = self.client.randomize_case(on);
+        self
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Resolves `name` starting from the root. The result holds the answer, including any
+    /// CNAMEs leading to it, or the authority section of a negative answer.
+    pub async fn resolve(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+        self.lookup(name.trim_end_matches('.').to_lowercase(), qtype, 0)
+            .await
+    }
+
+    fn lookup(&self, name: String, qtype: QueryType, depth: usize) -> Lookup<'_> {
+        Box::pin(async move {
+            if depth > self.max_depth {
+                return Err(DnsError::DepthExceeded(name));
+            }
+
+            let mut servers = self.roots.clone();
+            let mut zone = String::new();
+            let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+            // how many labels of the name the last minimised query had, and how many of those there were
+            let (mut asked, mut minimised) = (0, 0);
+            let mut minimise = self.qname_minimisation;
+            for _ in 0..MAX_REFERRALS + MAX_MINIMISE_COUNT {
+                let next = if minimised < MINIMISE_ONE_LAB {
+                    asked + 1
+                } else {
+                    let left = labels.len().saturating_sub(asked);
+                    asked + left.div_ceil(MAX_MINIMISE_COUNT.saturating_sub(minimised).max(1))
+                };
+                if minimise && next < labels.len() {
+                    let partial = labels[labels.len() - next..].join(".");
+                    let response = match self.query_any(&mut servers, &partial, QueryType::A).await
+                    {
+                        Ok(response) => response,
+                        Err(_) => {
+                            minimise = false;
+                            continue;
+                        }
+                    };
+                    (asked, minimised) = (next, minimised + 1);
+                    match referral(&response, &partial) {
+                        Some(referral) => {
+                            (servers, zone) = self.follow(referral, &response, zone, depth).await?;
+                            asked = label_count(&zone);
+                        }
+                        // an empty non-terminal or a name with records of its own, either way
+                        // the zone goes on below it
+                        None if response.header.rcode == ResultCode::NOERROR => {}
+                        None => minimise = false,
+                    }
+                    continue;
+                }
+
+                let response = self.query_any(&mut servers, &name, qtype).await?;
+                let referral = if response.answers.is_empty() {
+                    referral(&response, &name)
+                } else {
+                    None
+                };
+                let Some(referral) = referral else {
+                    return self.follow_cnames(name, qtype, response, depth).await;
+                };
+                (servers, zone) = self.follow(referral, &response, zone, depth).await?;
+                asked = label_count(&zone);
+            }
+            Err(DnsError::DepthExceeded(name))
+        })
+    }
+
+    // the servers and zone a referral from `zone`'s servers leads to
+    async fn follow(
+        &self,
+        (cut, hosts): (String, Vec<String>),
+        response: &DnsPacket,
+        zone: String,
+        depth: usize,
+    ) -> Result<(Vec<SocketAddr>, String)> {
+        // each referral has to get closer to the name, anything else is a loop
+        if cut.len() <= zone.len() || !is_subdomain(&cut, &zone) {
+            return Err(DnsError::ReferralLoop(cut));
+        }
+        let mut servers = glue(response, &hosts, &zone);
+        if servers.is_empty() {
+            servers = self.resolve_nameservers(&cut, &hosts, depth).await;
+        }
+        if servers.is_empty() {
+            return Err(DnsError::NoNameservers(cut));
+        }
+        Ok((servers, cut))
+    }
+
+    // asks the servers one after another until one gives a usable answer
+    async fn query_any(
+        &self,
+        servers: &mut [SocketAddr],
+        name: &str,
+        qtype: QueryType,
+    ) -> Result<DnsPacket> {
+        servers.shuffle(&mut rand::thread_rng());
+        let mut query = DnsPacket::query(name, qtype)
+            .recursion_desired(false)
+            .edns(Some(self.edns_payload))
+            .dnssec_ok(true)
+            .build();
+
+        let mut last_err = DnsError::NoNameservers(name.to_string());
+        for &server in servers.iter() {
+            match self.client.query(server, &mut query).await {
+                Ok(response)
+                    if matches!(
+                        response.header.rcode,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP
+                    ) =>
+                {
+                    last_err = DnsError::ServerFailure(server, response.header.rcode);
+                }
+                Ok(response) => return Ok(response),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    // looks up addresses for nameservers that came without glue. names inside the zone being
+    // delegated can't be looked up without glue, the lookup would be referred back here.
+    async fn resolve_nameservers(
+        &self,
+        zone: &str,
+        hosts: &[String],
+        depth: usize,
+    ) -> Vec<SocketAddr> {
+        for host in hosts.iter().filter(|host| !is_subdomain(host, zone)) {
+            let Ok(response) = self.lookup(host.clone(), QueryType::A, depth + 1).await else {
+                continue;
+            };
+            // the answer may sit behind CNAMEs, so any address in it will do
+            let addrs = addresses(&response.answers, |_| true);
+            if !addrs.is_empty() {
+                return addrs;
+            }
+        }
+        Vec::new()
+    }
+
+    // if the answer is an alias without records of the asked for type behind it, the rest of
+    // the chain is resolved separately and appended
+    async fn follow_cnames(
+        &self,
+        name: String,
+        qtype: QueryType,
+        mut response: DnsPacket,
+        depth: usize,
+    ) -> Result<DnsPacket> {
+        if qtype == QueryType::CNAME || response.header.rcode != ResultCode::NOERROR {
+            return Ok(response);
+        }
+
+        let mut target = name;
+        let mut chain = 0;
+        loop {
+            let next = response.answers.iter().find_map(|rec| match rec {
+                DnsRecord::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(&target) => {
+                    Some(host.to_lowercase())
+                }
+                _ => None,
+            });
+            let Some(next) = next else {
+                break;
+            };
+            chain += 1;
+            if chain > MAX_CNAME_CHAIN {
+                return Err(DnsError::DepthExceeded(target));
+            }
+            target = next;
+        }
+
+        let resolved = response
+            .answers
+            .iter()
+            .any(|rec| rec.qtype() == qtype && rec.domain().eq_ignore_ascii_case(&target));
+        if chain == 0 || resolved {
+            return Ok(response);
+        }
+
+        let rest = self.lookup(target, qtype, depth + 1).await?;
+        response.header.rcode = rest.header.rcode;
+        response.answers.extend(rest.answers);
+        response.authorities = rest.authorities;
+        Ok(response)
+    }
+}
+
+impl Handler for Resolver {
+    /// Answers with SERVFAIL when resolution fails.
+    async fn handle(&self, request: DnsPacket, _src: SocketAddr) -> Option<DnsPacket> {
+        let mut res = DnsPacket::response_to(&request);
+        res.set_recursion_available(true);
+        let edns = DnsPacket::response_edns(&request);
+        res.set_edns(edns.as_ref());
+
+        let Some(question) = request.questions.first() else {
+            res.set_rcode(ResultCode::FORMERR);
+            return Some(res);
+        };
+        match self.resolve(&question.name, question.qtype).await {
+            Ok(resolved) => {
+                res.set_rcode(resolved.header.rcode);
+                res.answers = resolved.answers;
+                res.authorities = resolved.authorities;
+            }
+            Err(e) => {
+                logging::warning(&format!("resolving {:?} failed: {}", request.questions, e));
+                res.set_rcode(ResultCode::SERVFAIL);
+            }
+        }
+        // signatures are always asked for, but only passed on to clients that want them
+        if !edns.is_some_and(|edns| edns.dnssec_ok) {
+            strip_dnssec(&mut res);
+        }
+        Some(res)
+    }
+}
+
+// the zone and nameserver names of a referral, i.e. NS records in the authority section for a
+// zone the name is in. authoritative answers are final even if they list the zone's NS records.
+fn referral(response: &DnsPacket, name: &str) -> Option<(String, Vec<String>)> {
+    if response.header.rcode != ResultCode::NOERROR || response.header.flags.authoritative {
+        return None;
+    }
+    let mut cut: Option<String> = None;
+    let mut hosts = Vec::new();
+    for rec in &response.authorities {
+        let DnsRecord::NS { domain, host, .. } = rec else {
+            continue;
+        };
+        if !is_subdomain(name, domain) {
+            continue;
+        }
+        match &cut {
+            Some(cut) if !cut.eq_ignore_ascii_case(domain) => continue,
+            Some(_) => {}
+            None => cut = Some(domain.to_lowercase()),
+        }
+        hosts.push(host.to_lowercase());
+    }
+    cut.map(|cut| (cut, hosts))
+}
+
+fn label_count(name: &str) -> usize {
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+// addresses for the nameservers from the additional section. only names inside the zone of the
+// server that sent them are trusted, anything else would let it plant addresses for names it
+// has no authority over.
+fn glue(response: &DnsPacket, hosts: &[String], zone: &str) -> Vec<SocketAddr> {
+    let in_zone = |domain: &str| {
+        is_subdomain(domain, zone) && hosts.iter().any(|host| host.eq_ignore_ascii_case(domain))
+    };
+    addresses(&response.additional, in_zone)
+}
+
+// ipv4 only, without knowing whether there is ipv6 connectivity every v6 server could cost a
+// timeout
+fn addresses(records: &[DnsRecord], wanted: impl Fn(&str) -> bool) -> Vec<SocketAddr> {
+    records
+        .iter()
+        .filter_map(|rec| match rec {
+            DnsRecord::A { domain, ip, .. } if wanted(domain) => {
+                Some(SocketAddr::new(IpAddr::V4((*ip).into()), 53))
+            }
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,194 @@
+// helpers for spreading out cache refreshes. when a lot of entries are inserted at the same time
+// (e.g. right after startup) they also expire at the same time, and refreshing them all at once
+// sends a synchronized burst of queries upstream. jitter spreads the refresh times out and the
+// limiter caps how many refreshes can be in flight towards a single upstream. a refresh runs
+// with its limiter set for the task (see `limited`), and whatever sends its queries upstream
+// asks the limiter for a permit before each, see forward.rs. the stale policy decides per domain
+// whether an expired entry may still be answered while it is refreshed.
+use crate::structure::is_subdomain;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+tokio::task_local! {
+    static LIMITER: Arc<RefreshLimiter>;
+}
+
+/// Runs `refresh` with `limiter` as the task's, see [`limiter`].
+pub async fn limited<F: Future>(limiter: Arc<RefreshLimiter>, refresh: F) -> F::Output {
+    LIMITER.scope(limiter, refresh).await
+}
+
+/// The limiter of the refresh the task is running, None if it isn't running one. Queries sent
+/// upstream for it need one of its permits, an upstream there's none for is skipped.
+pub fn limiter() -> Option<Arc<RefreshLimiter>> {
+    LIMITER.try_with(Arc::clone).ok()
+}
+
+#[derive(Clone, Debug)]
+pub struct RefreshPolicy {
+    /// Fraction of the TTL (0.0 - 1.0) that a refresh may be pulled forward by.
+    pub jitter: f64,
+    /// Maximum number of concurrent refreshes towards one upstream, 0 means unlimited.
+    pub max_concurrent_per_upstream: usize,
+}
+
+impl Default for RefreshPolicy {
+    fn default() -> Self {
+        Self {
+            jitter: 0.1,
+            max_concurrent_per_upstream: 8,
+        }
+    }
+}
+
+impl RefreshPolicy {
+    /// Returns when an entry with the given TTL should be refreshed. The refresh is scheduled
+    /// somewhere in the last `jitter` fraction of the TTL so entries that were stored together
+    /// don't all come due in the same instant.
+    pub fn refresh_after(&self, ttl: Duration) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 || ttl.is_zero() {
+            return ttl;
+        }
+        let pull_forward = rand::thread_rng().gen_range(0.0..=jitter);
+        ttl.mul_f64(1.0 - pull_forward)
+    }
+}
+
+/// Caps the number of in-flight refreshes per upstream. Callers that can't get a permit should
+/// keep serving what they have and try again later rather than queueing up.
+pub struct RefreshLimiter {
+    max: usize,
+    inflight: Mutex<HashMap<SocketAddr, usize>>,
+}
+
+impl RefreshLimiter {
+    pub fn new(policy: &RefreshPolicy) -> Self {
+        Self {
+            max: policy.max_concurrent_per_upstream,
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, upstream: SocketAddr) -> Option<RefreshPermit> {
+        let mut inflight = self.inflight.lock().unwrap();
+        let count = inflight.entry(upstream).or_insert(0);
+        if self.max != 0 && *count >= self.max {
+            return None;
+        }
+        *count += 1;
+
+        Some(RefreshPermit {
+            limiter: self.clone(),
+            upstream,
+        })
+    }
+
+    pub fn in_flight(&self, upstream: SocketAddr) -> usize {
+        let inflight = self.inflight.lock().unwrap();
+        inflight.get(&upstream).copied().unwrap_or(0)
+    }
+}
+
+/// Released when dropped.
+pub struct RefreshPermit {
+    limiter: Arc<RefreshLimiter>,
+    upstream: SocketAddr,
+}
+
+impl Drop for RefreshPermit {
+    fn drop(&mut self) {
+        let mut inflight = self.limiter.inflight.lock().unwrap();
+        if let Some(count) = inflight.get_mut(&self.upstream) {
+            *count -= 1;
+            if *count == 0 {
+                inflight.remove(&self.upstream);
+            }
+        }
+    }
+}
+
+/// What to do with a query whose cached answer has expired.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StaleStrategy {
+    /// Wait for a fresh answer from upstream.
+    #[default]
+    Strict,
+    /// Answer with the expired data right away and refresh it in the background.
+    StaleWhileRevalidate,
+}
+
+/// Picks a [`StaleStrategy`] per domain. The most specific zone that covers a name wins, names
+/// outside all configured zones get the default.
+#[derive(Clone, Debug, Default)]
+pub struct StalePolicy {
+    default: StaleStrategy,
+    zones: Vec<(String, StaleStrategy)>,
+}
+
+impl StalePolicy {
+    pub fn new(default: StaleStrategy) -> Self {
+        Self {
+            default,
+            zones: Vec::new(),
+        }
+    }
+
+    /// Uses `strategy` for the names outside every zone given a strategy of its own.
+    pub fn set_default(&mut self, strategy: StaleStrategy) -> &mut Self {
+        self.default = strategy;
+        self
+    }
+
+    /// Uses `strategy` for `zone` and everything below it. Setting a zone again replaces it.
+    pub fn set(&mut self, zone: &str, strategy: StaleStrategy) -> &mut Self {
+        let zone = zone.trim_end_matches('.').to_lowercase();
+        match self.zones.iter_mut().find(|(z, _)| *z == zone) {
+            Some(entry) => entry.1 = strategy,
+            None => self.zones.push((zone, strategy)),
+        }
+        self
+    }
+
+    pub fn strategy_for(&self, name: &str) -> StaleStrategy {
+        self.zones
+            .iter()
+            .filter(|(zone, _)| is_subdomain(name, zone))
+            .max_by_key(|(zone, _)| zone.len())
+            .map_or(self.default, |(_, strategy)| *strategy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_the_refreshes_to_each_upstream() {
+        let limiter = Arc::new(RefreshLimiter::new(&RefreshPolicy {
+            jitter: 0.0,
+            max_concurrent_per_upstream: 2,
+        }));
+        let (a, b): (SocketAddr, SocketAddr) = (
+            "192.0.2.1:53".parse().unwrap(),
+            "192.0.2.2:53".parse().unwrap(),
+        );
+        let first = limiter.try_acquire(a).unwrap();
+        let second = limiter.try_acquire(a).unwrap();
+        assert!(limiter.try_acquire(a).is_none());
+        assert!(limiter.try_acquire(b).is_some());
+        drop(first);
+        assert_eq!(limiter.in_flight(a), 1);
+        assert!(limiter.try_acquire(a).is_some());
+        drop(second);
+        assert_eq!(limiter.in_flight(a), 0);
+
+        assert!(super::limiter().is_none());
+        let inside = limited(limiter.clone(), async { super::limiter() }).await;
+        assert!(inside.is_some_and(|inside| Arc::ptr_eq(&inside, &limiter)));
+    }
+}
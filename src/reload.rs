@@ -0,0 +1,40 @@
+// a handler that can be swapped for another while the server runs, for reloading the config
+// and zones without a restart. each query is answered by the handler that was current when it
+// came in, which it holds on to until it's done, so queries in flight during a reload finish
+// with the old one and nothing is dropped. the old handler goes away with the last of them.
+use crate::server::Handler;
+use crate::structure::DnsPacket;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+pub struct Reloadable<H> {
+    current: RwLock<Arc<H>>,
+}
+
+impl<H: Handler> Reloadable<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(handler)),
+        }
+    }
+
+    /// The handler new queries go to.
+    pub fn current(&self) -> Arc<H> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Sends new queries to `handler` and returns the one they went to before.
+    pub fn replace(&self, handler: H) -> Arc<H> {
+        std::mem::replace(&mut *self.current.write().unwrap(), Arc::new(handler))
+    }
+}
+
+impl<H: Handler> Handler for Reloadable<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        self.current().handle(request, src).await
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.current().transfer(request, src).await
+    }
+}
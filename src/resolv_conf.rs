@@ -0,0 +1,97 @@
+// the system's resolver settings from /etc/resolv.conf (resolv.conf(5)), for when nobody told
+// us which servers to ask. only what a stub needs is read: the nameservers, the search list
+// (from search, or domain, whichever comes last) and options ndots. anything else, and lines
+// that don't parse, are skipped like the libc resolver does, since the file is often written
+// by other tools. a name with at least ndots dots is tried as it is before the search list,
+// one with fewer after it, and one ending in a dot never gets a suffix, see Client::lookup.
+use crate::error::Result;
+use crate::net::parse_socket_addr;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Where the system keeps it.
+pub const PATH: &str = "/etc/resolv.conf";
+
+// resolv.conf(5) caps ndots at 15
+const MAX_NDOTS: usize = 15;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvConf {
+    /// The servers to ask, in order, always on port 53.
+    pub nameservers: Vec<SocketAddr>,
+    /// Domains appended to names with fewer than `ndots` dots, lowercase and without the
+    /// trailing dot.
+    pub search: Vec<String>,
+    pub ndots: usize,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            ndots: 1,
+        }
+    }
+}
+
+impl ResolvConf {
+    pub fn parse(text: &str) -> Self {
+        let mut conf = Self::default();
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            match keyword {
+                "nameserver" => {
+                    if let Some(Ok(addr)) = words.next().map(|word| parse_socket_addr(word, 53)) {
+                        conf.nameservers.push(addr);
+                    }
+                }
+                "search" | "domain" => {
+                    conf.search = words
+                        .take_while(|word| !word.starts_with(['#', ';']))
+                        .map(|domain| domain.trim_end_matches('.').to_lowercase())
+                        .filter(|domain| !domain.is_empty())
+                        .collect();
+                }
+                "options" => {
+                    for option in words {
+                        if let Some(Ok(ndots)) = option.strip_prefix("ndots:").map(str::parse) {
+                            conf.ndots = usize::min(ndots, MAX_NDOTS);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        conf
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// The system's, from [`PATH`].
+    pub fn system() -> Result<Self> {
+        Self::load(PATH)
+    }
+
+    /// The names to try for `name`, in order.
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(absolute) = name.strip_suffix('.') {
+            return vec![absolute.to_string()];
+        }
+        let searched = self
+            .search
+            .iter()
+            .map(|domain| format!("{}.{}", name, domain));
+        if name.matches('.').count() >= self.ndots {
+            std::iter::once(name.to_string()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(name.to_string())).collect()
+        }
+    }
+}
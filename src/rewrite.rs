@@ -0,0 +1,112 @@
+// rewriting addresses in answers after they've been resolved, for split-horizon setups behind
+// NAT. a client on the inside asking for a name that resolves to the router's public address
+// can't reach it (no NAT loopback), so for clients in the given subnets the public address is
+// swapped for the internal one.
+use crate::error::{DnsError, Result};
+use crate::net::Subnet;
+use crate::server::Handler;
+use crate::structure::{DnsPacket, DnsRecord};
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RewriteRule {
+    pub from: IpAddr,
+    pub to: IpAddr,
+    /// The rule only applies to clients in one of these. Empty means every client.
+    pub clients: Vec<Subnet>,
+}
+
+impl RewriteRule {
+    pub fn new(from: IpAddr, to: IpAddr, clients: Vec<Subnet>) -> Result<Self> {
+        if from.is_ipv4() != to.is_ipv4() {
+            return Err(DnsError::InvalidAddress(format!(
+                "can't rewrite {} to {}, they're different address families",
+                from, to
+            )));
+        }
+        Ok(Self { from, to, clients })
+    }
+
+    fn applies_to(&self, client: IpAddr) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|net| net.contains(client))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AnswerRewriter {
+    rules: Vec<RewriteRule>,
+}
+
+impl AnswerRewriter {
+    pub fn new(rules: Vec<RewriteRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn add_rule(&mut self, rule: RewriteRule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rules(&self) -> &[RewriteRule] {
+        &self.rules
+    }
+
+    /// Rewrites the A and AAAA records in the answer section of `response` for `client`. The
+    /// first matching rule wins for each record. Returns how many records were changed.
+    pub fn apply(&self, response: &mut DnsPacket, client: IpAddr) -> usize {
+        let rules: Vec<&RewriteRule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.applies_to(client))
+            .collect();
+        if rules.is_empty() {
+            return 0;
+        }
+
+        let mut rewritten = 0;
+        for rec in &mut response.answers {
+            let addr = match rec {
+                DnsRecord::A { ip, .. } => IpAddr::V4((*ip).into()),
+                DnsRecord::AAAA { ip, .. } => IpAddr::V6((*ip).into()),
+                _ => continue,
+            };
+            let Some(rule) = rules.iter().find(|rule| rule.from == addr) else {
+                continue;
+            };
+            match (rec, rule.to) {
+                (DnsRecord::A { ip, .. }, IpAddr::V4(to)) => *ip = to.into(),
+                (DnsRecord::AAAA { ip, .. }, IpAddr::V6(to)) => *ip = to.into(),
+                // RewriteRule::new keeps both sides in the same family
+                _ => continue,
+            }
+            rewritten += 1;
+        }
+        rewritten
+    }
+}
+
+/// Runs every response from `inner` through an [`AnswerRewriter`], keyed on the client's
+/// address.
+pub struct Rewrite<H> {
+    inner: H,
+    rewriter: AnswerRewriter,
+}
+
+impl<H: Handler> Rewrite<H> {
+    pub fn new(inner: H, rewriter: AnswerRewriter) -> Self {
+        Self { inner, rewriter }
+    }
+}
+
+impl<H: Handler> Handler for Rewrite<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let mut response = self.inner.handle(request, src).await?;
+        self.rewriter.apply(&mut response, src.ip());
+        Some(response)
+    }
+
+    // a transfer is the zone as it is, rewriting it would hand secondaries a different zone
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.inner.transfer(request, src).await
+    }
+}
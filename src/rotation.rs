@@ -0,0 +1,160 @@
+// the order the records of an RRset go out in. most clients use the first address they get, so
+// a name with several backends only spreads its load if the order changes between responses:
+// turning it one place further each time (round-robin), shuffling it, or shuffling it with
+// some addresses more likely to come first than others (weighted). the order of an RRset
+// doesn't matter to its signatures, validators sort it before checking them.
+use crate::error::{DnsError, Result};
+use crate::structure::{DnsPacket, DnsRecord, QueryType};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How an RRset's records are ordered in each response.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Rotation {
+    /// As in the zone.
+    #[default]
+    Fixed,
+    Random,
+    /// Starting one record further on each time.
+    RoundRobin,
+    /// Shuffled, an address being first in proportion to its weight. Addresses without one
+    /// weigh 1, ones weighing 0 always go last.
+    Weighted(Vec<(IpAddr, u32)>),
+}
+
+impl FromStr for Rotation {
+    type Err = DnsError;
+
+    /// `fixed`, `random`, `round-robin`, or `weighted` followed by `<address>=<weight>`
+    /// pairs separated by whitespace.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let rotation = match words.next().unwrap_or_default() {
+            "fixed" => Rotation::Fixed,
+            "random" => Rotation::Random,
+            "round-robin" => Rotation::RoundRobin,
+            "weighted" => {
+                let mut weights = Vec::new();
+                for weight in words.by_ref() {
+                    let parsed = weight.split_once('=').and_then(|(addr, weight)| {
+                        Some((addr.parse().ok()?, weight.parse().ok()?))
+                    });
+                    match parsed {
+                        Some(weight) => weights.push(weight),
+                        None => {
+                            return Err(DnsError::Syntax(format!(
+                                "invalid weight {:?}, expected <address>=<weight>",
+                                weight
+                            )))
+                        }
+                    }
+                }
+                Rotation::Weighted(weights)
+            }
+            other => {
+                return Err(DnsError::Syntax(format!(
+                    "unknown rotation {:?}, expected fixed, random, round-robin or weighted",
+                    other
+                )))
+            }
+        };
+        if let Some(extra) = words.next() {
+            return Err(DnsError::Syntax(format!("unexpected {:?}", extra)));
+        }
+        Ok(rotation)
+    }
+}
+
+/// The rotation of each RRset, by owner name and type.
+#[derive(Debug, Default)]
+pub struct Rotator {
+    default: Rotation,
+    rrsets: HashMap<(String, QueryType), Rotation>,
+    // how many times each round-robin RRset has been turned
+    turns: Mutex<HashMap<(String, QueryType), usize>>,
+}
+
+impl Rotator {
+    /// Leaves RRsets without a rotation of their own in the order of `default`.
+    pub fn new(default: Rotation) -> Self {
+        Self {
+            default,
+            ..Self::default()
+        }
+    }
+
+    /// Orders the `qtype` RRset at `name` by `rotation`.
+    pub fn rrset(mut self, name: &str, qtype: QueryType, rotation: Rotation) -> Self {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.rrsets.insert((name, qtype), rotation);
+        self
+    }
+
+    pub fn is_fixed(&self) -> bool {
+        self.default == Rotation::Fixed
+            && self
+                .rrsets
+                .values()
+                .all(|rotation| *rotation == Rotation::Fixed)
+    }
+
+    /// Reorders each RRset in the answer section of `response`. Records only move within
+    /// the run of records of their RRset, so CNAME chains stay in order.
+    pub fn apply(&self, response: &mut DnsPacket) {
+        if self.is_fixed() {
+            return;
+        }
+        let mut start = 0;
+        while start < response.answers.len() {
+            let first = &response.answers[start];
+            let (name, qtype) = (first.domain().to_lowercase(), first.qtype());
+            let same =
+                |rec: &&DnsRecord| rec.qtype() == qtype && rec.domain().eq_ignore_ascii_case(&name);
+            let len = response.answers[start..].iter().take_while(same).count();
+            let rrset = &mut response.answers[start..start + len];
+            start += len;
+            if len < 2 {
+                continue;
+            }
+            let key = (name.trim_end_matches('.').to_string(), qtype);
+            match self.rrsets.get(&key).unwrap_or(&self.default) {
+                Rotation::Fixed => {}
+                Rotation::Random => rrset.shuffle(&mut rand::thread_rng()),
+                Rotation::RoundRobin => {
+                    let mut turns = self.turns.lock().unwrap();
+                    let turn = turns.entry(key).or_default();
+                    rrset.rotate_left(*turn % len);
+                    *turn = turn.wrapping_add(1);
+                }
+                Rotation::Weighted(weights) => {
+                    let weight = |rec: &DnsRecord| {
+                        rec.address()
+                            .and_then(|addr| weights.iter().find(|(a, _)| *a == addr))
+                            .map_or(1, |(_, weight)| *weight)
+                    };
+                    // a random key u^(1/w) for each record, highest first, puts records
+                    // first in proportion to their weight (Efraimidis and Spirakis)
+                    let mut rng = rand::thread_rng();
+                    let mut keyed: Vec<(f64, DnsRecord)> = rrset
+                        .iter()
+                        .map(|rec| {
+                            let key = match weight(rec) {
+                                0 => -1.0,
+                                w => rng.gen::<f64>().powf(1.0 / w as f64),
+                            };
+                            (key, rec.clone())
+                        })
+                        .collect();
+                    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                    for (slot, (_, rec)) in rrset.iter_mut().zip(keyed) {
+                        *slot = rec;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+// response rate limiting, as in bind: a server answering over udp can be made to send large
+// answers to a spoofed address, so identical responses going to the same network are counted
+// and the ones over the rate aren't sent. responses are bucketed by the client's /24 (or /56
+// for ipv6), the name and what kind of answer it is. NXDOMAINs are bucketed by the zone they
+// come from rather than the name, so random names under one zone all count together. a
+// bucket earns its rate in credit every second, up to a second's worth, and each response
+// spends one. a bucket in debt has its responses dropped, except that every `slip`th one is
+// sent back empty with TC set: a real client behind a spoofed flood then still gets its
+// answer over tcp, which can't be spoofed and isn't limited.
+use crate::net::Subnet;
+use crate::structure::{DnsPacket, DnsRecord, ResultCode};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RESPONSES_PER_SECOND: u32 = 10;
+pub const DEFAULT_SLIP: u32 = 2;
+/// How far into debt a bucket can go, in seconds of its rate. A flood has to stop for this
+/// long before its responses go out again.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(15);
+pub const DEFAULT_IPV4_PREFIX: u8 = 24;
+pub const DEFAULT_IPV6_PREFIX: u8 = 56;
+pub const DEFAULT_MAX_BUCKETS: usize = 100_000;
+
+/// What to do with a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    Drop,
+    /// Send an empty truncated response instead, see [`slipped`].
+    Slip,
+}
+
+// the responses counted together, besides going to the same network for the same name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    Answer,
+    NxDomain,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    network: IpAddr,
+    name: String,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // responses that can still be sent, negative in debt
+    credit: f64,
+    last: Instant,
+    // responses limited in a row, for slipping every so often
+    limited: u32,
+}
+
+/// Counts of the responses that weren't sent as they were.
+#[derive(Debug, Default)]
+pub struct RrlCounters {
+    pub dropped: AtomicU64,
+    pub slipped: AtomicU64,
+}
+
+/// Limits identical responses to the same network, see the top of rrl.rs.
+#[derive(Debug)]
+pub struct Rrl {
+    responses_per_second: u32,
+    nxdomains_per_second: u32,
+    errors_per_second: u32,
+    slip: u32,
+    window: Duration,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    exempt: Vec<Subnet>,
+    max_buckets: usize,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    counters: RrlCounters,
+}
+
+impl Default for Rrl {
+    fn default() -> Self {
+        Rrl::new()
+    }
+}
+
+// a copy has the same settings and starts counting afresh
+impl Clone for Rrl {
+    fn clone(&self) -> Self {
+        Self {
+            exempt: self.exempt.clone(),
+            buckets: Mutex::new(HashMap::new()),
+            counters: RrlCounters::default(),
+            ..*self
+        }
+    }
+}
+
+impl Rrl {
+    pub fn new() -> Self {
+        Self {
+            responses_per_second: DEFAULT_RESPONSES_PER_SECOND,
+            nxdomains_per_second: DEFAULT_RESPONSES_PER_SECOND,
+            errors_per_second: DEFAULT_RESPONSES_PER_SECOND,
+            slip: DEFAULT_SLIP,
+            window: DEFAULT_WINDOW,
+            ipv4_prefix: DEFAULT_IPV4_PREFIX,
+            ipv6_prefix: DEFAULT_IPV6_PREFIX,
+            exempt: Vec::new(),
+            max_buckets: DEFAULT_MAX_BUCKETS,
+            buckets: Mutex::new(HashMap::new()),
+            counters: RrlCounters::default(),
+        }
+    }
+
+    /// The rate for every kind of response. Zero turns limiting off.
+    pub fn responses_per_second(mut self, rate: u32) -> Self {
+        self.responses_per_second = rate;
+        self.nxdomains_per_second = rate;
+        self.errors_per_second = rate;
+        self
+    }
+
+    /// The rate for NXDOMAINs, after [`Rrl::responses_per_second`].
+    pub fn nxdomains_per_second(mut self, rate: u32) -> Self {
+        self.nxdomains_per_second = rate;
+        self
+    }
+
+    /// The rate for errors (SERVFAIL, REFUSED, FORMERR...), after
+    /// [`Rrl::responses_per_second`].
+    pub fn errors_per_second(mut self, rate: u32) -> Self {
+        self.errors_per_second = rate;
+        self
+    }
+
+    /// Every how many limited responses one is slipped. Zero drops them all, one slips
+    /// them all.
+    pub fn slip(mut self, slip: u32) -> Self {
+        self.slip = slip;
+        self
+    }
+
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// The prefix lengths clients are grouped by.
+    pub fn prefixes(mut self, ipv4: u8, ipv6: u8) -> Self {
+        self.ipv4_prefix = ipv4.min(32);
+        self.ipv6_prefix = ipv6.min(128);
+        self
+    }
+
+    pub fn prefix_lengths(&self) -> (u8, u8) {
+        (self.ipv4_prefix, self.ipv6_prefix)
+    }
+
+    /// Clients in `net` are never limited.
+    pub fn exempt(mut self, net: Subnet) -> Self {
+        self.exempt.push(net);
+        self
+    }
+
+    /// Caps how many buckets are kept. When it's reached idle buckets are forgotten, and if
+    /// none are idle new ones aren't kept until some are.
+    pub fn max_buckets(mut self, max: usize) -> Self {
+        self.max_buckets = max;
+        self
+    }
+
+    pub fn counters(&self) -> &RrlCounters {
+        &self.counters
+    }
+
+    /// Counts `response` going to `client` and says whether it can be sent.
+    pub fn check(&self, client: IpAddr, response: &DnsPacket) -> Verdict {
+        self.check_at(client, response, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, response: &DnsPacket, now: Instant) -> Verdict {
+        if self.exempt.iter().any(|net| net.contains(client)) {
+            return Verdict::Send;
+        }
+        let Some(question) = response.questions.first() else {
+            return Verdict::Send;
+        };
+        let kind = match response.header.rcode {
+            ResultCode::NOERROR => Kind::Answer,
+            ResultCode::NXDOMAIN => Kind::NxDomain,
+            _ => Kind::Error,
+        };
+        let rate = match kind {
+            Kind::Answer => self.responses_per_second,
+            Kind::NxDomain => self.nxdomains_per_second,
+            Kind::Error => self.errors_per_second,
+        };
+        if rate == 0 {
+            return Verdict::Send;
+        }
+        let name = match kind {
+            Kind::NxDomain => response
+                .authorities
+                .iter()
+                .find_map(|rec| match rec {
+                    DnsRecord::SOA { domain, .. } => Some(domain.as_str()),
+                    _ => None,
+                })
+                .unwrap_or(&question.name),
+            _ => &question.name,
+        };
+        let prefix = match client {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        let key = Key {
+            network: Subnet::new(client, prefix)
+                .expect("prefixes are capped to the address length")
+                .addr(),
+            name: name.trim_end_matches('.').to_lowercase(),
+            kind,
+        };
+
+        let rate = rate as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&key) && buckets.len() >= self.max_buckets {
+            // a bucket that has earned back a full second of credit has nothing to remember
+            let idle = self.window + Duration::from_secs(1);
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.last) < idle);
+            if buckets.len() >= self.max_buckets {
+                return Verdict::Send;
+            }
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            credit: rate,
+            last: now,
+            limited: 0,
+        });
+        let earned = now.saturating_duration_since(bucket.last).as_secs_f64() * rate;
+        bucket.credit = (bucket.credit + earned).min(rate) - 1.0;
+        bucket.credit = bucket.credit.max(-self.window.as_secs_f64() * rate);
+        bucket.last = now;
+        if bucket.credit >= 0.0 {
+            bucket.limited = 0;
+            return Verdict::Send;
+        }
+        bucket.limited = bucket.limited.wrapping_add(1);
+        if self.slip != 0 && bucket.limited.is_multiple_of(self.slip) {
+            self.counters.slipped.fetch_add(1, Ordering::Relaxed);
+            Verdict::Slip
+        } else {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            Verdict::Drop
+        }
+    }
+}
+
+/// `response` with its records taken out and TC set, so the client asks again over TCP.
+pub fn slipped(response: &DnsPacket) -> DnsPacket {
+    let mut res = DnsPacket::response_to(response);
+    res.header.flags = response.header.flags;
+    res.header.flags.truncated = true;
+    res.set_rcode(response.header.rcode);
+    if let Some(Ok(edns)) = response.edns() {
+        res.set_edns(Some(&edns));
+    }
+    res
+}
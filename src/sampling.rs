@@ -0,0 +1,78 @@
+// decides which queries get logged in full. under heavy traffic logging every query costs more
+// than answering it, so only a fraction is logged in detail and the rest only bump counters.
+// names matching an override are always logged.
+use crate::structure::{is_subdomain, DnsQuestion};
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pattern {
+    Exact(String),
+    // *.example.com, matches anything below example.com but not example.com itself
+    Wildcard(String),
+}
+
+impl Pattern {
+    fn parse(s: &str) -> Self {
+        let s = s.trim_end_matches('.').to_lowercase();
+        match s.strip_prefix("*.") {
+            Some(suffix) => Pattern::Wildcard(suffix.to_string()),
+            None => Pattern::Exact(s),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Exact(exact) => name.eq_ignore_ascii_case(exact),
+            Pattern::Wildcard(suffix) => name.len() > suffix.len() && is_subdomain(name, suffix),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SamplingCounters {
+    pub total: AtomicU64,
+    pub sampled: AtomicU64,
+    pub forced: AtomicU64,
+}
+
+pub struct QuerySampler {
+    rate: f64,
+    always: Vec<Pattern>,
+    pub counters: SamplingCounters,
+}
+
+impl QuerySampler {
+    /// `rate` is the fraction of queries to log in detail, 0.0 to 1.0. `always` are names
+    /// (or `*.suffix` wildcards) that are logged regardless of the rate.
+    pub fn new(rate: f64, always: &[&str]) -> Self {
+        Self {
+            rate: rate.clamp(0.0, 1.0),
+            always: always.iter().map(|s| Pattern::parse(s)).collect(),
+            counters: SamplingCounters::default(),
+        }
+    }
+
+    /// Whether this query should be logged in full. Every call is counted.
+    pub fn should_log(&self, question: &DnsQuestion) -> bool {
+        self.counters.total.fetch_add(1, Ordering::Relaxed);
+
+        if self.always.iter().any(|p| p.matches(&question.name)) {
+            self.counters.forced.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        let sampled =
+            self.rate >= 1.0 || (self.rate > 0.0 && rand::thread_rng().gen_bool(self.rate));
+        if sampled {
+            self.counters.sampled.fetch_add(1, Ordering::Relaxed);
+        }
+        sampled
+    }
+}
+
+impl Default for QuerySampler {
+    fn default() -> Self {
+        QuerySampler::new(1.0, &[])
+    }
+}
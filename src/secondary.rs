@@ -0,0 +1,304 @@
+// secondary zones, copies of zones another server is the primary for. the zone is fetched with
+// AXFR and the primary's SOA serial is checked every refresh interval, fetching the changes
+// with IXFR when it went up (rfc 1034 section 4.3.5). while the primary can't be reached checks
+// are retried, backing off from a few seconds up to the retry interval, and a copy that hasn't
+// been confirmed for longer than expire is dropped instead of being served stale. until
+// there's a copy the zone's names get SERVFAIL rather than being passed on as if the zone
+// weren't ours. each transferred copy can be kept on disk as a master file so a restart
+// serves it straight away instead of waiting for a transfer.
+use crate::authority::Authority;
+use crate::client::{Client, Transfer};
+use crate::error::{DnsError, Result};
+use crate::journal::serial_newer;
+use crate::logging;
+use crate::structure::{DnsPacket, DnsRecord, QueryType};
+use crate::zone::Zone;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::time;
+
+// how long to wait at most before trying again when there's no SOA to take the retry
+// interval from
+const DEFAULT_RETRY: Duration = Duration::from_secs(60);
+// a SOA with timers of a few seconds would have us hammering the primary. it's also the first
+// wait after a failure, doubling from there up to the retry interval
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Keeps a copy of a zone from its primaries in an [`Authority`].
+pub struct Secondary {
+    origin: String,
+    primaries: Vec<SocketAddr>,
+    path: Option<PathBuf>,
+    client: Client,
+}
+
+impl Secondary {
+    /// A secondary for the zone at `origin`, transferred from the first of `primaries` that
+    /// answers.
+    pub fn new(origin: &str, primaries: Vec<SocketAddr>) -> Self {
+        Self {
+            origin: origin.trim_end_matches('.').to_lowercase(),
+            primaries,
+            path: None,
+            client: Client::new(),
+        }
+    }
+
+    /// Keeps the transferred zone in a master file at `path`, and starts out with the copy
+    /// found there if it hasn't expired yet.
+    pub fn persist(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// The client used to reach the primaries, for its timeout or to sign with a TSIG key.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Keeps the zone in `authority` up to date. Runs until the future is dropped, failures
+    /// are reported and retried. The zone is pending in `authority` until there's a copy of
+    /// it, which is reported when there is.
+    pub async fn run(&self, authority: &Authority) {
+        authority.add_pending(&self.origin);
+        let mut serial = None;
+        // when the copy being served stops being good
+        let mut expires_at = None;
+        if let Some((zone, saved_at)) = self.load() {
+            let timers = Timers::of(&zone);
+            if saved_at + timers.expire > SystemTime::now() {
+                serial = timers.serial;
+                expires_at = Some(saved_at + timers.expire);
+                logging::info(&format!(
+                    "secondary zone {} loaded from disk at serial {}",
+                    self.origin,
+                    serial.unwrap_or_default()
+                ));
+                authority.add_zone(zone);
+            }
+        }
+
+        let mut backoff = MIN_INTERVAL;
+        loop {
+            let wait = match self.refresh(authority, serial).await {
+                Ok(timers) => {
+                    if serial.is_none() {
+                        logging::info(&format!(
+                            "secondary zone {} transferred at serial {}",
+                            self.origin,
+                            timers.serial.unwrap_or_default()
+                        ));
+                    }
+                    serial = timers.serial;
+                    expires_at = Some(SystemTime::now() + timers.expire);
+                    backoff = MIN_INTERVAL;
+                    timers.refresh
+                }
+                Err(e) => {
+                    logging::warning(&format!(
+                        "refreshing secondary zone {} failed: {}",
+                        self.origin, e
+                    ));
+                    if expires_at.is_some_and(|at| at <= SystemTime::now()) {
+                        logging::error(&format!("secondary zone {} expired", self.origin));
+                        authority.remove_zone(&self.origin);
+                        authority.add_pending(&self.origin);
+                        serial = None;
+                        expires_at = None;
+                    }
+                    let retry = authority
+                        .find(&self.origin)
+                        .filter(|zone| zone.origin() == self.origin)
+                        .map_or(DEFAULT_RETRY, |zone| Timers::of(&zone).retry);
+                    let wait = backoff.min(retry);
+                    backoff = backoff.saturating_mul(2);
+                    wait
+                }
+            };
+            time::sleep(wait.max(MIN_INTERVAL)).await;
+        }
+    }
+
+    // checks the primaries for a newer serial than `serial` and transfers the zone into
+    // `authority` if there is one, or if there's no copy yet. returns the timers of the zone
+    // being served afterwards.
+    async fn refresh(&self, authority: &Authority, serial: Option<u32>) -> Result<Timers> {
+        let mut last_error = DnsError::NoUpstreams;
+        for &primary in &self.primaries {
+            match self.refresh_from(primary, authority, serial).await {
+                Ok(timers) => return Ok(timers),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn refresh_from(
+        &self,
+        primary: SocketAddr,
+        authority: &Authority,
+        serial: Option<u32>,
+    ) -> Result<Timers> {
+        let mut query = DnsPacket::query(&self.origin, QueryType::SOA)
+            .recursion_desired(false)
+            .build();
+        let response = self.client.query(primary, &mut query).await?;
+        let soa = response
+            .answers
+            .iter()
+            .find(|rec| rec.qtype() == QueryType::SOA && rec.domain() == self.origin)
+            .ok_or_else(|| {
+                DnsError::TransferFailed(primary, "no SOA in the answer to the serial check".into())
+            })?;
+        let timers = Timers::of_soa(soa);
+        if let (Some(ours), Some(theirs)) = (serial, timers.serial) {
+            if !serial_newer(theirs, ours) {
+                // the copy on disk was just confirmed too, its age is what counts on restart
+                if let Some(path) = &self.path {
+                    let _ = File::options()
+                        .write(true)
+                        .open(path)
+                        .and_then(|f| f.set_modified(SystemTime::now()));
+                }
+                return Ok(timers);
+            }
+        }
+
+        let current = authority
+            .find(&self.origin)
+            .filter(|zone| zone.origin() == self.origin);
+        let Some(zone) = self.fetch(primary, current.as_deref()).await? else {
+            return Ok(timers);
+        };
+        let timers = Timers::of(&zone);
+        if let Some(path) = &self.path {
+            // the copy in memory is still good, it's just not kept for the next start
+            if let Err(e) = save(&zone, path) {
+                logging::error(&format!(
+                    "failed to save secondary zone {} to {}: {}",
+                    self.origin,
+                    path.display(),
+                    e
+                ));
+            }
+        }
+        authority.add_zone(zone);
+        Ok(timers)
+    }
+
+    // the zone as the primary has it now, None if that's the version we have. with a copy
+    // to start from only the changes are asked for, which the primary may answer with the
+    // whole zone anyway.
+    async fn fetch(&self, primary: SocketAddr, current: Option<&Zone>) -> Result<Option<Zone>> {
+        let Some((current, soa)) = current.and_then(|zone| Some((zone, zone.soa()?))) else {
+            return self.fetch_full(primary).await.map(Some);
+        };
+        let diffs = match self
+            .client
+            .transfer_incremental(primary, &self.origin, soa)
+            .await?
+        {
+            Transfer::UpToDate => return Ok(None),
+            Transfer::Full(records) => return Zone::from_records(&self.origin, records).map(Some),
+            Transfer::Incremental(diffs) => diffs,
+        };
+
+        let mut zone = current.clone();
+        for diff in &diffs {
+            match diff.apply(&zone) {
+                Ok(next) => zone = next,
+                Err(e) => {
+                    logging::warning(&format!(
+                        "incremental transfer of {} from {} doesn't apply, transferring all of it: {}",
+                        self.origin, primary, e
+                    ));
+                    return self.fetch_full(primary).await.map(Some);
+                }
+            }
+        }
+        Ok(Some(zone))
+    }
+
+    async fn fetch_full(&self, primary: SocketAddr) -> Result<Zone> {
+        let records = self.client.transfer(primary, &self.origin).await?;
+        Zone::from_records(&self.origin, records)
+    }
+
+    // the copy on disk and when it was written
+    fn load(&self) -> Option<(Zone, SystemTime)> {
+        let path = self.path.as_ref()?;
+        let saved_at = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+        match Zone::load(path, &self.origin) {
+            Ok(zone) => Some((zone, saved_at)),
+            Err(e) => {
+                logging::warning(&format!("ignoring saved copy of {}: {}", self.origin, e));
+                None
+            }
+        }
+    }
+}
+
+/// Writes `zone` to `path` as a master file, through a temporary file so a crash halfway
+/// through leaves the previous copy intact.
+pub fn save(zone: &Zone, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    // SOA first, as the format asks for
+    let soa = zone.soa().into_iter();
+    for rec in soa.chain(zone.records().filter(|rec| rec.qtype() != QueryType::SOA)) {
+        writeln!(file, "{}", rec)?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+struct Timers {
+    serial: Option<u32>,
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+}
+
+impl Timers {
+    // for when there's no SOA to take them from
+    const UNKNOWN: Timers = Timers {
+        serial: None,
+        refresh: DEFAULT_RETRY,
+        retry: DEFAULT_RETRY,
+        expire: Duration::ZERO,
+    };
+
+    fn of(zone: &Zone) -> Self {
+        zone.soa().map_or(Self::UNKNOWN, Self::of_soa)
+    }
+
+    fn of_soa(soa: &DnsRecord) -> Self {
+        let DnsRecord::SOA {
+            serial,
+            refresh,
+            retry,
+            expire,
+            ..
+        } = soa
+        else {
+            return Self::UNKNOWN;
+        };
+        let secs = |s: u32| Duration::from_secs(s as u64);
+        Self {
+            serial: Some(*serial),
+            refresh: secs(*refresh),
+            retry: secs(*retry),
+            expire: secs(*expire),
+        }
+    }
+}
@@ -0,0 +1,880 @@
+// the udp and tcp listeners, and what the others (doh_server.rs, doq_server.rs) answer through.
+// every query is parsed into a DnsPacket and handed to a Handler on its own task, and whatever
+// it returns is written back to the sender. a handler that takes longer than the query timeout
+// is cancelled and the client gets SERVFAIL instead. zone transfers only work over tcp, where
+// the handler can answer with a series of messages. responses over udp can be rate limited
+// (rrl.rs), tcp can't be spoofed and isn't. signed requests (TSIG) are checked against the
+// server's keys before the handler sees them, and everything sent back for them is signed with
+// the same key. a server that's stopped stops accepting and waits a while for the queries it's
+// answering, see Drain.
+use crate::borrowed::LazyPacket;
+use crate::doh_server::HttpsServer;
+use crate::doq_server::QuicServer;
+use crate::edns::PaddingPolicy;
+use crate::error::{DnsError, Result};
+use crate::limits::is_fd_exhaustion;
+use crate::logging;
+use crate::metrics::AnomalyCounters;
+use crate::net::{bind_udp, check_scope};
+use crate::rrl::{slipped, Rrl, RrlCounters, Verdict};
+use crate::structure::{
+    BytePacketBuffer, DnsHeader, DnsPacket, QueryType, ResultCode, DEFAULT_EDNS_PAYLOAD,
+    MAX_MESSAGE_SIZE, MIN_UDP_PAYLOAD,
+};
+use crate::tsig::{Keyring, Session};
+use std::future::{self, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{Notify, Semaphore};
+use tokio::time;
+
+// the largest query read over udp, anything longer is cut short and fails to parse. queries
+// are small, this leaves room for an OPT record with options and a TSIG record.
+const MAX_UDP_QUERY: usize = 4096;
+
+// clients usually give up and retry after a few seconds, answering later than that is useless
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_IN_FLIGHT: usize = 4096;
+
+// rfc 7766 section 6.2.3 suggests a few seconds for idle connections
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+// how long to stop accepting when out of descriptors, giving open connections time to close
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Answers queries. Resolving to `None` drops the query without a response. A request that
+/// still has a TSIG record when it gets here was signed with one of the server's keys, see
+/// [`signer`](crate::tsig::signer).
+pub trait Handler: Send + Sync + 'static {
+    fn handle(
+        &self,
+        request: DnsPacket,
+        src: SocketAddr,
+    ) -> impl Future<Output = Option<DnsPacket>> + Send;
+
+    /// Answers a zone transfer (AXFR or IXFR) received over TCP with the messages to send back, in
+    /// order. `None`, the default, passes the request on to [`Handler::handle`] like any other.
+    fn transfer(
+        &self,
+        request: &DnsPacket,
+        src: SocketAddr,
+    ) -> impl Future<Output = Option<Vec<DnsPacket>>> + Send {
+        let _ = (request, src);
+        future::ready(None)
+    }
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(DnsPacket, SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<DnsPacket>> + Send,
+{
+    fn handle(
+        &self,
+        request: DnsPacket,
+        src: SocketAddr,
+    ) -> impl Future<Output = Option<DnsPacket>> + Send {
+        self(request, src)
+    }
+}
+
+/// Turns a plain function into a [`Handler`], for answers that never wait on anything.
+pub struct FnHandler<F>(pub F);
+
+impl<F> Handler for FnHandler<F>
+where
+    F: Fn(&DnsPacket, SocketAddr) -> Option<DnsPacket> + Send + Sync + 'static,
+{
+    fn handle(
+        &self,
+        request: DnsPacket,
+        src: SocketAddr,
+    ) -> impl Future<Output = Option<DnsPacket>> + Send {
+        future::ready((self.0)(&request, src))
+    }
+}
+
+/// Counts the queries being answered, from when they're read until their response is on its
+/// way, so a server that's stopping can wait for them. Listeners given the same one with their
+/// `drain` builders are waited for together, each has one of its own otherwise.
+#[derive(Default)]
+pub struct Drain {
+    queries: AtomicUsize,
+    idle: Notify,
+}
+
+impl Drain {
+    pub fn len(&self) -> usize {
+        self.queries.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until no query is being answered.
+    pub async fn wait(&self) {
+        loop {
+            // registered before looking, so the last query finishing in between isn't missed
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    // counts a query until what's returned is dropped
+    pub(crate) fn enter(self: &Arc<Self>) -> InFlight {
+        self.queries.fetch_add(1, Ordering::AcqRel);
+        InFlight(self.clone())
+    }
+}
+
+pub(crate) struct InFlight(Arc<Drain>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.queries.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+pub struct UdpServer<H> {
+    socket: Arc<UdpSocket>,
+    handler: Arc<H>,
+    timeout: Duration,
+    in_flight: Arc<Semaphore>,
+    max_payload: u16,
+    counters: AnomalyCounters,
+    keys: Arc<Keyring>,
+    rrl: Option<Arc<Rrl>>,
+    drain: Arc<Drain>,
+}
+
+impl<H: Handler> UdpServer<H> {
+    /// Binds the listening socket. Has to be called from within a tokio runtime.
+    pub async fn bind(addr: SocketAddr, handler: H) -> Result<Self> {
+        Self::bind_shared(addr, Arc::new(handler)).await
+    }
+
+    /// Like [`UdpServer::bind`], for a handler that is also used by other listeners.
+    pub async fn bind_shared(addr: SocketAddr, handler: Arc<H>) -> Result<Self> {
+        Self::from_std(bind_udp(addr)?, handler)
+    }
+
+    /// Serves on a socket that's bound already, like one systemd passed in, see
+    /// [`crate::systemd`]. Has to be called from within a tokio runtime.
+    pub fn from_std(socket: std::net::UdpSocket, handler: Arc<H>) -> Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Arc::new(UdpSocket::from_std(socket)?),
+            handler,
+            timeout: DEFAULT_QUERY_TIMEOUT,
+            in_flight: Arc::new(Semaphore::new(DEFAULT_MAX_IN_FLIGHT)),
+            max_payload: DEFAULT_EDNS_PAYLOAD,
+            counters: AnomalyCounters::default(),
+            keys: Arc::new(Keyring::new()),
+            rrl: None,
+            drain: Arc::default(),
+        })
+    }
+
+    /// How long a handler gets before its query is answered with SERVFAIL.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Caps the number of queries being handled at once. Once it's reached no more datagrams
+    /// are read until one finishes, the kernel's socket buffer absorbs the rest.
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// The largest UDP response we send, whatever the client advertises. Anything bigger is
+    /// truncated so the client retries over TCP instead of relying on ip fragments.
+    pub fn max_payload(mut self, size: u16) -> Self {
+        self.max_payload = size.max(MIN_UDP_PAYLOAD);
+        self
+    }
+
+    /// The keys signed requests are checked against. Without any, signed requests are
+    /// answered with BADKEY.
+    pub fn keys(mut self, keys: Keyring) -> Self {
+        self.keys = Arc::new(keys);
+        self
+    }
+
+    /// Limits the responses sent, see [`Rrl`]. Without it every response is sent.
+    pub fn rrl(mut self, rrl: Rrl) -> Self {
+        self.rrl = Some(Arc::new(rrl));
+        self
+    }
+
+    /// Counts the queries being answered in `drain`, see [`Drain`].
+    pub fn drain(mut self, drain: Arc<Drain>) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn counters(&self) -> &AnomalyCounters {
+        &self.counters
+    }
+
+    pub fn rrl_counters(&self) -> Option<&RrlCounters> {
+        self.rrl.as_deref().map(Rrl::counters)
+    }
+
+    /// Serves queries until receiving from the socket fails. A query that can't be answered,
+    /// e.g. because the response couldn't be sent, is reported and doesn't stop the loop.
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let permit = self
+                .in_flight
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+
+            let mut req = BytePacketBuffer::with_len(MAX_UDP_QUERY);
+            let (len, src) = self.socket.recv_from(&mut req.buf[..]).await?;
+            if !self.counters.accept_query(&req.buf[..len]) {
+                continue;
+            }
+
+            let socket = self.socket.clone();
+            let handler = self.handler.clone();
+            let timeout = self.timeout;
+            let max_payload = self.max_payload;
+            let keys = self.keys.clone();
+            let rrl = self.rrl.clone();
+            let query = self.drain.enter();
+            tokio::spawn(async move {
+                let server = Server {
+                    handler: &*handler,
+                    keys: &keys,
+                    timeout,
+                };
+                let rrl = rrl.as_deref();
+                let res = serve_datagram(&socket, server, max_payload, rrl, &mut req, len, src);
+                if let Err(e) = res.await {
+                    logging::warning(&format!("failed to answer query from {}: {}", src, e));
+                }
+                drop(query);
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn serve_datagram<H: Handler>(
+    socket: &UdpSocket,
+    server: Server<'_, H>,
+    max_payload: u16,
+    rrl: Option<&Rrl>,
+    req: &mut BytePacketBuffer,
+    len: usize,
+    src: SocketAddr,
+) -> Result<()> {
+    let Some(mut answer) = server.answer(req, len, src).await? else {
+        return Ok(());
+    };
+    if let Some(rrl) = rrl {
+        match rrl.check(src.ip(), &answer.response) {
+            Verdict::Send => {}
+            Verdict::Drop => return Ok(()),
+            Verdict::Slip => answer.response = slipped(&answer.response),
+        }
+    }
+
+    let payload = match answer.edns_payload {
+        Some(size) => size.clamp(MIN_UDP_PAYLOAD, max_payload),
+        None => MIN_UDP_PAYLOAD,
+    };
+    // whatever the handler put in the OPT record, what we take is up to the listener
+    if let Some(Ok(mut edns)) = answer.response.edns() {
+        edns.payload_size = max_payload;
+        answer.response.set_edns(Some(&edns));
+    }
+    // the signature goes on after truncating, so it needs room set aside
+    let signature_len = answer.session.as_ref().map_or(0, Session::record_len);
+    let mut res = BytePacketBuffer::new();
+    answer
+        .response
+        .write_truncated(&mut res, payload as usize - signature_len)?;
+    if let Some(session) = &mut answer.session {
+        session.sign(&mut res, 0)?;
+    }
+    socket.send_to(res.as_slice(), src).await?;
+    Ok(())
+}
+
+struct Answer {
+    response: DnsPacket,
+    // the udp payload size the client advertised, if it sent an OPT record
+    edns_payload: Option<u16>,
+    // for a signed request, what signs the response
+    session: Option<Session>,
+}
+
+// how a request came through the signature check
+enum Verified {
+    // on to the handler, with the session that signs the response if it was signed
+    Accepted(Option<Session>),
+    // answered right away with this, signed by the session if there is one
+    Rejected(DnsPacket, Option<Session>),
+}
+
+// what both transports need to answer a query
+struct Server<'a, H> {
+    handler: &'a H,
+    keys: &'a Keyring,
+    timeout: Duration,
+}
+
+impl<H> Clone for Server<'_, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H> Copy for Server<'_, H> {}
+
+impl<H: Handler> Server<'_, H> {
+    // parse the `len` bytes in `req`, check the signature if there is one, and ask the handler
+    async fn answer(
+        self,
+        req: &mut BytePacketBuffer,
+        len: usize,
+        src: SocketAddr,
+    ) -> Result<Option<Answer>> {
+        let request = DnsPacket::from_message(req, len);
+
+        match request {
+            Ok(request) => {
+                let edns_payload = match request.edns() {
+                    Some(Ok(edns)) => Some(edns.payload_size),
+                    _ => None,
+                };
+                let session = match self.verify(&request, &req.buf[..len]) {
+                    Verified::Accepted(session) => session,
+                    Verified::Rejected(response, session) => {
+                        return Ok(Some(Answer {
+                            response,
+                            edns_payload,
+                            session,
+                        }))
+                    }
+                };
+                let mut servfail = DnsPacket::response_to(&request);
+                servfail.set_rcode(ResultCode::SERVFAIL);
+                let response =
+                    match time::timeout(self.timeout, self.handler.handle(request, src)).await {
+                        Ok(Some(response)) => response,
+                        Ok(None) => return Ok(None),
+                        Err(_) => servfail,
+                    };
+                Ok(Some(Answer {
+                    response,
+                    edns_payload,
+                    session,
+                }))
+            }
+            // the header made it through accept_query, so it can always be read back
+            Err(_) => {
+                req.seek(0)?;
+                let mut header = DnsHeader::new();
+                header.read(req)?;
+                Ok(Some(Answer {
+                    response: DnsPacket::format_error(&header),
+                    edns_payload: None,
+                    session: None,
+                }))
+            }
+        }
+    }
+
+    // checks the TSIG record of a request, if it has one. a request that fails the check is
+    // answered right away instead of going to the handler: NOTAUTH, with the session putting
+    // the TSIG error in on the way out, or FORMERR for a malformed record.
+    fn verify(&self, request: &DnsPacket, msg: &[u8]) -> Verified {
+        let mut response = DnsPacket::response_to(request);
+        match Session::verify_request(self.keys, request, msg) {
+            Ok(Some(session)) if session.error().is_some() => {
+                response.set_rcode(ResultCode::NOTAUTH);
+                Verified::Rejected(response, Some(session))
+            }
+            Ok(session) => Verified::Accepted(session),
+            Err(_) => {
+                response.set_rcode(ResultCode::FORMERR);
+                Verified::Rejected(response, None)
+            }
+        }
+    }
+}
+
+/// Answers a query that came in over some other transport as one over TCP would be: checked,
+/// answered by `handler` or with SERVFAIL once `timeout` is up, padded by `padding`, and signed
+/// if it was. Returns the response and its encoding, or None if the handler dropped the query.
+/// `msg` mustn't be longer than a DNS message can be.
+pub(crate) async fn answer_message<H: Handler>(
+    handler: &H,
+    keys: &Keyring,
+    timeout: Duration,
+    padding: PaddingPolicy,
+    msg: &[u8],
+    src: SocketAddr,
+) -> Result<Option<(DnsPacket, Vec<u8>)>> {
+    let mut req = BytePacketBuffer::from_bytes(msg)?;
+    let server = Server {
+        handler,
+        keys,
+        timeout,
+    };
+    let Some(mut answer) = server.answer(&mut req, msg.len(), src).await? else {
+        return Ok(None);
+    };
+    let signature_len = answer.session.as_ref().map_or(0, Session::record_len);
+    answer
+        .response
+        .apply_padding(padding, MAX_MESSAGE_SIZE - signature_len)?;
+    let mut res = BytePacketBuffer::new();
+    answer
+        .response
+        .write_truncated(&mut res, MAX_MESSAGE_SIZE - signature_len)?;
+    if let Some(session) = &mut answer.session {
+        session.sign(&mut res, 0)?;
+    }
+    Ok(Some((answer.response, res.as_slice().to_vec())))
+}
+
+/// Serves queries over TCP, each prefixed with its length (rfc 1035 section 4.2.2). A
+/// connection can carry any number of queries, they are answered in the order they arrive.
+pub struct TcpServer<H> {
+    listener: TcpListener,
+    handler: Arc<H>,
+    timeout: Duration,
+    idle_timeout: Duration,
+    connections: Arc<Semaphore>,
+    counters: Arc<AnomalyCounters>,
+    keys: Arc<Keyring>,
+    drain: Arc<Drain>,
+}
+
+impl<H: Handler> TcpServer<H> {
+    /// Binds the listening socket. Has to be called from within a tokio runtime.
+    pub async fn bind(addr: SocketAddr, handler: H) -> Result<Self> {
+        Self::bind_shared(addr, Arc::new(handler)).await
+    }
+
+    /// Like [`TcpServer::bind`], for a handler that is also used by other listeners.
+    pub async fn bind_shared(addr: SocketAddr, handler: Arc<H>) -> Result<Self> {
+        check_scope(&addr)?;
+        Ok(Self::new(TcpListener::bind(addr).await?, handler))
+    }
+
+    /// Accepts on a listener that's bound already, like one systemd passed in, see
+    /// [`crate::systemd`]. Has to be called from within a tokio runtime.
+    pub fn from_std(listener: std::net::TcpListener, handler: Arc<H>) -> Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self::new(TcpListener::from_std(listener)?, handler))
+    }
+
+    fn new(listener: TcpListener, handler: Arc<H>) -> Self {
+        Self {
+            listener,
+            handler,
+            timeout: DEFAULT_QUERY_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            connections: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+            counters: Arc::new(AnomalyCounters::default()),
+            keys: Arc::new(Keyring::new()),
+            drain: Arc::default(),
+        }
+    }
+
+    /// How long a handler gets before its query is answered with SERVFAIL.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long a connection may sit without a complete query before it's closed.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Caps the number of open connections. Once it's reached no more connections are
+    /// accepted until one closes.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.connections = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// The keys signed requests are checked against, see [`UdpServer::keys`].
+    pub fn keys(mut self, keys: Keyring) -> Self {
+        self.keys = Arc::new(keys);
+        self
+    }
+
+    /// Counts the queries being answered in `drain`, see [`Drain`].
+    pub fn drain(mut self, drain: Arc<Drain>) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn counters(&self) -> &AnomalyCounters {
+        &self.counters
+    }
+
+    /// Accepts connections until accepting fails. Running out of descriptors only pauses
+    /// accepting, and errors on a single connection only close that connection.
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let permit = self
+                .connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let (stream, src) = match self.listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) if is_fd_exhaustion(&e) => {
+                    logging::error(&format!("out of file descriptors, pausing accepts: {}", e));
+                    time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let connection = Connection {
+                handler: self.handler.clone(),
+                counters: self.counters.clone(),
+                keys: self.keys.clone(),
+                drain: self.drain.clone(),
+                timeout: self.timeout,
+                idle_timeout: self.idle_timeout,
+            };
+            tokio::spawn(async move {
+                if let Err(e) = connection.serve(stream, src).await {
+                    logging::warning(&format!("tcp connection from {} failed: {}", src, e));
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+struct Connection<H> {
+    handler: Arc<H>,
+    counters: Arc<AnomalyCounters>,
+    keys: Arc<Keyring>,
+    drain: Arc<Drain>,
+    timeout: Duration,
+    idle_timeout: Duration,
+}
+
+impl<H: Handler> Connection<H> {
+    async fn serve(&self, mut stream: TcpStream, src: SocketAddr) -> Result<()> {
+        loop {
+            let mut req = BytePacketBuffer::new();
+            let Some(len) = self.read_message(&mut stream, &mut req).await? else {
+                return Ok(());
+            };
+            let _query = self.drain.enter();
+            // a client sending responses isn't a dns client, don't bother with the rest
+            if !self.counters.accept_query(&req.buf[..len]) {
+                return Ok(());
+            }
+
+            if is_transfer(&req.buf[..len]) {
+                if let Some((messages, mut session)) = self.transfer(&mut req, len, src).await? {
+                    for mut message in messages {
+                        write_message(&mut stream, &mut message, session.as_mut()).await?;
+                    }
+                    continue;
+                }
+                req.seek(0)?;
+            }
+
+            let Some(mut answer) = self.server().answer(&mut req, len, src).await? else {
+                continue;
+            };
+            write_message(&mut stream, &mut answer.response, answer.session.as_mut()).await?;
+        }
+    }
+
+    fn server(&self) -> Server<'_, H> {
+        Server {
+            handler: &*self.handler,
+            keys: &self.keys,
+            timeout: self.timeout,
+        }
+    }
+
+    // asks the handler for the messages of a zone transfer, and the session that signs them if
+    // the request was signed. running out of time is answered with SERVFAIL like any other
+    // query.
+    async fn transfer(
+        &self,
+        req: &mut BytePacketBuffer,
+        len: usize,
+        src: SocketAddr,
+    ) -> Result<Option<(Vec<DnsPacket>, Option<Session>)>> {
+        // anything malformed is left to the normal path, which answers it with FORMERR
+        let Ok(request) = DnsPacket::from_message(req, len) else {
+            return Ok(None);
+        };
+        let session = match self.server().verify(&request, &req.buf[..len]) {
+            Verified::Accepted(session) => session,
+            Verified::Rejected(response, session) => return Ok(Some((vec![response], session))),
+        };
+        match time::timeout(self.timeout, self.handler.transfer(&request, src)).await {
+            Ok(messages) => Ok(messages.map(|messages| (messages, session))),
+            Err(_) => {
+                let mut servfail = DnsPacket::response_to(&request);
+                servfail.set_rcode(ResultCode::SERVFAIL);
+                Ok(Some((vec![servfail], session)))
+            }
+        }
+    }
+
+    // reads one length prefixed message into `req`. None means the connection should be
+    // closed, because the client closed it or went quiet.
+    async fn read_message(
+        &self,
+        stream: &mut TcpStream,
+        req: &mut BytePacketBuffer,
+    ) -> Result<Option<usize>> {
+        let mut prefix = [0u8; 2];
+        match time::timeout(self.idle_timeout, stream.read_exact(&mut prefix)).await {
+            Err(_) => return Ok(None),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Ok(res) => res?,
+        };
+
+        let len = u16::from_be_bytes(prefix) as usize;
+        req.buf.resize(len, 0);
+        // a client stalling halfway through a message is treated like an idle one
+        match time::timeout(self.idle_timeout, stream.read_exact(&mut req.buf[..len])).await {
+            Err(_) => Ok(None),
+            Ok(res) => {
+                res?;
+                Ok(Some(len))
+            }
+        }
+    }
+}
+
+// whether the message asks for a zone transfer, without parsing all of it
+fn is_transfer(msg: &[u8]) -> bool {
+    let Ok(packet) = LazyPacket::new(msg) else {
+        return false;
+    };
+    packet.header.qdcount == 1
+        && packet
+            .iter_questions()
+            .next()
+            .is_some_and(|q| q.is_ok_and(|q| matches!(q.qtype, QueryType::AXFR | QueryType::IXFR)))
+}
+
+async fn write_message(
+    stream: &mut TcpStream,
+    message: &mut DnsPacket,
+    session: Option<&mut Session>,
+) -> Result<()> {
+    // leave room for the length prefix and fill it in once the size is known
+    let mut res = BytePacketBuffer::new();
+    res.seek(2)?;
+    let signature_len = session.as_ref().map_or(0, |session| session.record_len());
+    message.write_truncated(&mut res, MAX_MESSAGE_SIZE - 2 - signature_len)?;
+    if let Some(session) = session {
+        session.sign(&mut res, 2)?;
+    }
+    let msg_len = (res.pos - 2) as u16;
+    res.buf[..2].copy_from_slice(&msg_len.to_be_bytes());
+    stream.write_all(res.as_slice()).await?;
+    Ok(())
+}
+
+/// Serves the same handler over UDP and TCP on one address, and optionally over HTTPS and
+/// QUIC, with its own runtime, for callers that don't use async themselves.
+pub struct BlockingServer<H> {
+    runtime: Runtime,
+    udp: UdpServer<H>,
+    tcp: TcpServer<H>,
+    https: Option<HttpsServer<H>>,
+    quic: Option<QuicServer<H>>,
+    // the queries being answered by any of the listeners
+    drain: Arc<Drain>,
+}
+
+impl<H: Handler> BlockingServer<H> {
+    pub fn bind(addr: SocketAddr, handler: H) -> Result<Self> {
+        Self::bind_shared(addr, Arc::new(handler))
+    }
+
+    /// Like [`BlockingServer::bind`], but keeps a handle on the handler for the caller.
+    pub fn bind_shared(addr: SocketAddr, handler: Arc<H>) -> Result<Self> {
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        let (udp, tcp) = runtime.block_on(async {
+            let udp = UdpServer::bind_shared(addr, handler.clone()).await?;
+            // with port 0 the tcp listener has to end up on the port udp was given
+            let tcp = TcpServer::bind_shared(udp.local_addr()?, handler).await?;
+            Ok::<_, DnsError>((udp, tcp))
+        })?;
+        Ok(Self::new(runtime, udp, tcp))
+    }
+
+    /// Serves on sockets that are bound already, like ones systemd passed in, see
+    /// [`crate::systemd`].
+    pub fn from_std(
+        udp: std::net::UdpSocket,
+        tcp: std::net::TcpListener,
+        handler: Arc<H>,
+    ) -> Result<Self> {
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        let (udp, tcp) = {
+            let _runtime = runtime.enter();
+            let udp = UdpServer::from_std(udp, handler.clone())?;
+            (udp, TcpServer::from_std(tcp, handler)?)
+        };
+        Ok(Self::new(runtime, udp, tcp))
+    }
+
+    fn new(runtime: Runtime, udp: UdpServer<H>, tcp: TcpServer<H>) -> Self {
+        let drain = Arc::<Drain>::default();
+        Self {
+            runtime,
+            udp: udp.drain(drain.clone()),
+            tcp: tcp.drain(drain.clone()),
+            https: None,
+            quic: None,
+            drain,
+        }
+    }
+
+    /// Serves over HTTPS as well, with a listener bound on [`BlockingServer::runtime`]. Its
+    /// settings are its own, the ones set here don't reach it, but its queries are waited
+    /// for with the others' by [`BlockingServer::run_until`].
+    pub fn https(mut self, https: HttpsServer<H>) -> Self {
+        self.https = Some(https.drain(self.drain.clone()));
+        self
+    }
+
+    /// Serves over QUIC as well, as [`BlockingServer::https`] does over HTTPS.
+    pub fn quic(mut self, quic: QuicServer<H>) -> Self {
+        self.quic = Some(quic.drain(self.drain.clone()));
+        self
+    }
+
+    /// Caps the number of open TCP connections, see [`TcpServer::max_connections`].
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.tcp = self.tcp.max_connections(max);
+        self
+    }
+
+    /// The keys signed requests are checked against, see [`UdpServer::keys`].
+    pub fn keys(mut self, keys: Keyring) -> Self {
+        self.udp = self.udp.keys(keys.clone());
+        self.tcp = self.tcp.keys(keys);
+        self
+    }
+
+    /// The largest UDP response sent, see [`UdpServer::max_payload`].
+    pub fn max_payload(mut self, size: u16) -> Self {
+        self.udp = self.udp.max_payload(size);
+        self
+    }
+
+    /// Limits the responses sent over UDP, see [`UdpServer::rrl`].
+    pub fn rrl(mut self, rrl: Rrl) -> Self {
+        self.udp = self.udp.rrl(rrl);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    pub fn udp(&self) -> &UdpServer<H> {
+        &self.udp
+    }
+
+    /// The runtime the listeners run on, for work that has to happen before [`run`] or
+    /// alongside it, like warming up a cache.
+    ///
+    /// [`run`]: BlockingServer::run
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    pub fn tcp(&self) -> &TcpServer<H> {
+        &self.tcp
+    }
+
+    /// Serves until any of the listeners fails.
+    pub fn run(&self) -> Result<()> {
+        self.run_until(future::pending(), Duration::ZERO)
+    }
+
+    /// Serves until any of the listeners fails or `shutdown` resolves. After that nothing new
+    /// is accepted and the queries being answered get up to `deadline` to finish, while
+    /// connections already open stay open until then. QUIC goes on reading its socket in the
+    /// meantime, since that's where its connections' answers go out from too.
+    pub fn run_until(&self, shutdown: impl Future<Output = ()>, deadline: Duration) -> Result<()> {
+        self.runtime.block_on(async {
+            let https = async {
+                match &self.https {
+                    Some(https) => https.run().await,
+                    None => future::pending().await,
+                }
+            };
+            let quic = async {
+                match &self.quic {
+                    Some(quic) => quic.run().await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::pin!(quic);
+            tokio::select! {
+                res = async { tokio::try_join!(self.udp.run(), self.tcp.run(), https) } => {
+                    res?;
+                }
+                res = &mut quic => res?,
+                () = shutdown => {}
+            }
+
+            tokio::select! {
+                () = self.drain.wait() => {}
+                () = time::sleep(deadline) => logging::warning(&format!(
+                    "stopping with {} queries unanswered after {:?}",
+                    self.drain.len(),
+                    deadline
+                )),
+                res = &mut quic => res?,
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,80 @@
+// unix signals as something to await, through a signalfd. a signal only goes to the signalfd if
+// no thread takes it the usual way, so it has to be blocked in every thread first, which is
+// what block does for the thread it's called on and, since new threads start with the mask of
+// the one that made them, for every thread made after. it has to be called before the runtime
+// starts its workers.
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM};
+
+/// Blocks `signals` on this thread and the threads it makes from now on, so they're only
+/// seen through [`Signals`].
+pub fn block(signals: &[libc::c_int]) -> io::Result<()> {
+    let set = sigset(signals)?;
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    match ret {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Signals that have come in, for signals that were blocked with [`block`].
+pub struct Signals {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Signals {
+    /// Has to be called from within a tokio runtime.
+    pub fn new(signals: &[libc::c_int]) -> io::Result<Self> {
+        let set = sigset(signals)?;
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Waits for one of the signals and returns which it was.
+    pub async fn recv(&self) -> io::Result<libc::c_int> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| read(fd.get_ref())) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+fn read(fd: &OwnedFd) -> io::Result<libc::c_int> {
+    let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+    let len = mem::size_of::<libc::signalfd_siginfo>();
+    let read = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            (&mut info as *mut libc::signalfd_siginfo).cast(),
+            len,
+        )
+    };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info.ssi_signo as libc::c_int)
+}
+
+fn sigset(signals: &[libc::c_int]) -> io::Result<libc::sigset_t> {
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe { libc::sigemptyset(&mut set) };
+    for &signal in signals {
+        if unsafe { libc::sigaddset(&mut set, signal) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(set)
+}
@@ -0,0 +1,473 @@
+// the public key algorithms DNSSEC signs with, verifying signatures against keys as DNSKEY
+// records hold them: RSA/SHA-256 (rfc 5702), ECDSA P-256 with SHA-256 (rfc 6605) and Ed25519
+// (rfc 8080, rfc 8032), and signing with and generating the private halves. aws-lc-rs does the
+// arithmetic, this only translates between its keys and signatures and the formats DNSKEY and
+// RRSIG records and BIND's private key files have them in. RSA signatures are verified from
+// 1024 bit keys, which zones still sign with, but our own RSA keys have to have at least the
+// 2048 bits rfc 8624 section 3.1 asks for.
+use aws_lc_rs::encoding::{AsBigEndian, AsDer};
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::rsa::{KeyPairComponents, KeySize, PublicKeyComponents};
+use aws_lc_rs::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
+    ECDSA_P256_SHA256_FIXED_SIGNING, ED25519, RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+    RSA_PKCS1_SHA256,
+};
+use std::fmt;
+use std::sync::Arc;
+
+// the DER of an RFC 5915 ECPrivateKey on P-256 up to the private key's 32 bytes, and the curve
+// after them, which is all aws-lc-rs needs to load one
+const EC_PRIVATE_KEY_PREFIX: [u8; 7] = [0x30, 0x31, 0x02, 0x01, 0x01, 0x04, 0x20];
+const EC_PRIVATE_KEY_SUFFIX: [u8; 12] = [
+    0xa0, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07,
+];
+
+/// A DNSSEC signing algorithm (rfc 8624 lists which ones are worth supporting).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    RsaSha256,
+    EcdsaP256Sha256,
+    Ed25519,
+}
+
+impl Algorithm {
+    /// The algorithm with this number in DNSKEY, RRSIG and DS records, None for the ones we
+    /// can't verify.
+    pub fn from_num(num: u8) -> Option<Self> {
+        match num {
+            8 => Some(Algorithm::RsaSha256),
+            13 => Some(Algorithm::EcdsaP256Sha256),
+            15 => Some(Algorithm::Ed25519),
+            _ => None,
+        }
+    }
+
+    pub fn to_num(self) -> u8 {
+        match self {
+            Algorithm::RsaSha256 => 8,
+            Algorithm::EcdsaP256Sha256 => 13,
+            Algorithm::Ed25519 => 15,
+        }
+    }
+
+    /// The algorithm's mnemonic, as in key files and the output of [`fmt::Display`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Algorithm::RsaSha256,
+            Algorithm::EcdsaP256Sha256,
+            Algorithm::Ed25519,
+        ]
+        .into_iter()
+        .find(|alg| alg.to_string().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether `signature` over `data` was made with the private half of `public_key`, in
+    /// the wire formats of the DNSKEY and RRSIG records. Keys that can't be parsed don't
+    /// verify anything.
+    pub fn verify(self, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        match self {
+            Algorithm::RsaSha256 => verify_rsa_sha256(public_key, data, signature),
+            Algorithm::EcdsaP256Sha256 => verify_ecdsa_p256(public_key, data, signature),
+            Algorithm::Ed25519 => verify_ed25519(public_key, data, signature),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::RsaSha256 => write!(f, "RSASHA256"),
+            Algorithm::EcdsaP256Sha256 => write!(f, "ECDSAP256SHA256"),
+            Algorithm::Ed25519 => write!(f, "ED25519"),
+        }
+    }
+}
+
+// the key is the exponent's length in one byte (or a zero and two bytes), the exponent and
+// the modulus, rfc 3110 section 2
+fn parse_rsa_key(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&first, rest) = key.split_first()?;
+    let (len, rest) = match first {
+        0 => (
+            u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize,
+            &rest[2..],
+        ),
+        len => (len as usize, rest),
+    };
+    if len == 0 || rest.len() <= len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+fn verify_rsa_sha256(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let Some((exponent, modulus)) = parse_rsa_key(key) else {
+        return false;
+    };
+    PublicKeyComponents {
+        n: modulus,
+        e: exponent,
+    }
+    .verify(
+        &RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+        data,
+        signature,
+    )
+    .is_ok()
+}
+
+// the key is the point's x and y, without the 04 in front that says it's uncompressed,
+// rfc 6605 section 4
+fn verify_ecdsa_p256(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let mut point = vec![0x04];
+    point.extend_from_slice(key);
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+        .verify(data, signature)
+        .is_ok()
+}
+
+fn verify_ed25519(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(data, signature)
+        .is_ok()
+}
+
+/// An RSA private key's numbers, big-endian, with the names BIND's private key files give them
+/// (n, e, d, p, q, dP, dQ and qInv in rfc 8017 section 3.2).
+#[derive(Clone, Copy)]
+pub struct RsaPrivateKey<'a> {
+    pub modulus: &'a [u8],
+    pub public_exponent: &'a [u8],
+    pub private_exponent: &'a [u8],
+    pub prime1: &'a [u8],
+    pub prime2: &'a [u8],
+    pub exponent1: &'a [u8],
+    pub exponent2: &'a [u8],
+    pub coefficient: &'a [u8],
+}
+
+/// A private key, to sign with.
+#[derive(Clone)]
+pub struct SigningKey {
+    algorithm: Algorithm,
+    pair: Arc<Pair>,
+    // the parts of the key as BIND's private key files name them, see private_fields
+    fields: Vec<(&'static str, Vec<u8>)>,
+}
+
+enum Pair {
+    Rsa(RsaKeyPair),
+    EcdsaP256(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+// keeps the secret out of logs
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey({})", self.algorithm)
+    }
+}
+
+impl SigningKey {
+    /// An RSA/SHA-256 key from all of its numbers. None if they don't make up a key, or one of
+    /// fewer than 2048 bits.
+    pub fn rsa(key: &RsaPrivateKey) -> Option<Self> {
+        let (modulus, public_exponent) = (unsigned(key.modulus), unsigned(key.public_exponent));
+        let pair = RsaKeyPair::from_components(&KeyPairComponents {
+            public_key: PublicKeyComponents {
+                n: modulus,
+                e: public_exponent,
+            },
+            d: key.private_exponent,
+            p: key.prime1,
+            q: key.prime2,
+            dP: key.exponent1,
+            dQ: key.exponent2,
+            qInv: key.coefficient,
+        })
+        .ok()?;
+        let fields = [
+            ("Modulus", modulus),
+            ("PublicExponent", public_exponent),
+            ("PrivateExponent", key.private_exponent),
+            ("Prime1", key.prime1),
+            ("Prime2", key.prime2),
+            ("Exponent1", key.exponent1),
+            ("Exponent2", key.exponent2),
+            ("Coefficient", key.coefficient),
+        ];
+        Some(Self {
+            algorithm: Algorithm::RsaSha256,
+            pair: Arc::new(Pair::Rsa(pair)),
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name, unsigned(value).to_vec()))
+                .collect(),
+        })
+    }
+
+    /// A new random key. RSA keys are 2048 bits with the public exponent 65537.
+    pub fn generate(algorithm: Algorithm) -> Self {
+        let failed = "generating a key";
+        match algorithm {
+            Algorithm::RsaSha256 => {
+                let pkcs8 = RsaKeyPair::generate(KeySize::Rsa2048)
+                    .and_then(|pair| pair.as_der())
+                    .expect(failed);
+                let [modulus, public_exponent, private_exponent, prime1, prime2, exponent1, exponent2, coefficient] =
+                    rsa_integers(pkcs8.as_ref()).expect("aws-lc-rs writes PKCS #8");
+                Self::rsa(&RsaPrivateKey {
+                    modulus,
+                    public_exponent,
+                    private_exponent,
+                    prime1,
+                    prime2,
+                    exponent1,
+                    exponent2,
+                    coefficient,
+                })
+                .expect(failed)
+            }
+            Algorithm::EcdsaP256Sha256 => {
+                let pair = EcdsaKeyPair::generate(&ECDSA_P256_SHA256_FIXED_SIGNING).expect(failed);
+                let private_key = pair.private_key().as_be_bytes().expect(failed);
+                Self::ecdsa_p256(private_key.as_ref()).expect(failed)
+            }
+            Algorithm::Ed25519 => {
+                let pair = Ed25519KeyPair::generate().expect(failed);
+                let seed = pair
+                    .seed()
+                    .and_then(|seed| seed.as_be_bytes())
+                    .expect(failed);
+                Self::ed25519(seed.as_ref()).expect(failed)
+            }
+        }
+    }
+
+    /// An ECDSA P-256 key from its private scalar, 32 bytes big-endian.
+    pub fn ecdsa_p256(private_key: &[u8]) -> Option<Self> {
+        if private_key.len() != 32 {
+            return None;
+        }
+        let mut der = EC_PRIVATE_KEY_PREFIX.to_vec();
+        der.extend_from_slice(private_key);
+        der.extend(EC_PRIVATE_KEY_SUFFIX);
+        let pair =
+            EcdsaKeyPair::from_private_key_der(&ECDSA_P256_SHA256_FIXED_SIGNING, &der).ok()?;
+        Some(Self {
+            algorithm: Algorithm::EcdsaP256Sha256,
+            pair: Arc::new(Pair::EcdsaP256(pair)),
+            fields: vec![("PrivateKey", private_key.to_vec())],
+        })
+    }
+
+    /// An Ed25519 key from its 32 byte seed, what rfc 8032 calls the private key.
+    pub fn ed25519(seed: &[u8]) -> Option<Self> {
+        let pair = Ed25519KeyPair::from_seed_unchecked(seed).ok()?;
+        Some(Self {
+            algorithm: Algorithm::Ed25519,
+            pair: Arc::new(Pair::Ed25519(pair)),
+            fields: vec![("PrivateKey", seed.to_vec())],
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The public half, as DNSKEY records hold it.
+    pub fn public_key(&self) -> Vec<u8> {
+        match &*self.pair {
+            Pair::Rsa(_) => {
+                let (modulus, exponent) = (&self.fields[0].1, &self.fields[1].1);
+                let mut key = match exponent.len() {
+                    len @ 1..=255 => vec![len as u8],
+                    len => vec![0, (len >> 8) as u8, len as u8],
+                };
+                key.extend(exponent);
+                key.extend(modulus);
+                key
+            }
+            Pair::EcdsaP256(pair) => pair.public_key().as_ref()[1..].to_vec(),
+            Pair::Ed25519(pair) => pair.public_key().as_ref().to_vec(),
+        }
+    }
+
+    /// The parts of the private key as BIND's private key files name them, big-endian.
+    pub(crate) fn private_fields(&self) -> Vec<(&'static str, Vec<u8>)> {
+        self.fields.clone()
+    }
+
+    /// Signs `data`, with the signature in the format RRSIG records hold it in.
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let failed = "signing with a key that loaded";
+        match &*self.pair {
+            Pair::Rsa(pair) => {
+                let mut signature = vec![0; pair.public_modulus_len()];
+                pair.sign(
+                    &RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    data,
+                    &mut signature,
+                )
+                .expect(failed);
+                signature
+            }
+            Pair::EcdsaP256(pair) => pair
+                .sign(&SystemRandom::new(), data)
+                .expect(failed)
+                .as_ref()
+                .to_vec(),
+            Pair::Ed25519(pair) => pair.sign(data).as_ref().to_vec(),
+        }
+    }
+}
+
+// an integer's bytes without the leading zeros DER and key files may have
+fn unsigned(integer: &[u8]) -> &[u8] {
+    let zeros = integer.iter().take_while(|&&b| b == 0).count();
+    &integer[zeros..]
+}
+
+// a DER value with `tag` at the start of `data`: its contents and what follows it
+fn der_value(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&found, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (first as usize, rest),
+        0x81..=0x82 => {
+            let n = (first & 0x7f) as usize;
+            let len = rest
+                .get(..n)?
+                .iter()
+                .fold(0, |len, &b| len << 8 | b as usize);
+            (len, &rest[n..])
+        }
+        _ => return None,
+    };
+    if found != tag || rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+// the numbers of the RSAPrivateKey (rfc 8017 appendix A.1.2) in a PKCS #8 PrivateKeyInfo
+// (rfc 5208 section 5), after its version
+fn rsa_integers(pkcs8: &[u8]) -> Option<[&[u8]; 8]> {
+    const INTEGER: u8 = 0x02;
+    const OCTET_STRING: u8 = 0x04;
+    const SEQUENCE: u8 = 0x30;
+    let (info, _) = der_value(pkcs8, SEQUENCE)?;
+    let (_, rest) = der_value(info, INTEGER)?;
+    let (_, rest) = der_value(rest, SEQUENCE)?;
+    let (key, _) = der_value(rest, OCTET_STRING)?;
+    let (key, _) = der_value(key, SEQUENCE)?;
+    let (_, mut rest) = der_value(key, INTEGER)?;
+    let mut integers = [&[][..]; 8];
+    for integer in &mut integers {
+        (*integer, rest) = der_value(rest, INTEGER)?;
+    }
+    Some(integers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presentation::parse_hex;
+
+    fn hex(s: &str) -> Vec<u8> {
+        parse_hex(s).unwrap()
+    }
+
+    // rfc 8032 section 7.1, tests 1 and 2
+    #[test]
+    fn signs_the_rfc_8032_examples() {
+        let examples = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc\
+                 61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e4\
+                 58f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (seed, public_key, message, signature) in examples {
+            let key = SigningKey::ed25519(&hex(seed)).unwrap();
+            assert_eq!(key.public_key(), hex(public_key));
+            assert_eq!(key.sign(&hex(message)), hex(signature));
+            assert!(Algorithm::Ed25519.verify(&hex(public_key), &hex(message), &hex(signature)));
+            assert!(!Algorithm::Ed25519.verify(&hex(public_key), b"other", &hex(signature)));
+        }
+    }
+
+    // rfc 6979 appendix a.2.5, the key and the SHA-256 signature of "sample"
+    #[test]
+    fn verifies_the_rfc_6979_p256_example() {
+        let key = SigningKey::ecdsa_p256(&hex(
+            "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721",
+        ))
+        .unwrap();
+        let public_key = hex(
+            "60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6\
+             7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299",
+        );
+        assert_eq!(key.public_key(), public_key);
+        let signature = hex(
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716\
+             f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8",
+        );
+        let verify = |data: &[u8], signature: &[u8]| {
+            Algorithm::EcdsaP256Sha256.verify(&public_key, data, signature)
+        };
+        assert!(verify(b"sample", &signature));
+        assert!(!verify(b"test", &signature));
+        // our own signatures use random nonces, so they only have to verify
+        assert!(verify(b"test", &key.sign(b"test")));
+    }
+
+    #[test]
+    fn generated_keys_sign_what_their_public_keys_verify() {
+        for algorithm in [
+            Algorithm::RsaSha256,
+            Algorithm::EcdsaP256Sha256,
+            Algorithm::Ed25519,
+        ] {
+            let key = SigningKey::generate(algorithm);
+            let signature = key.sign(b"data");
+            assert!(algorithm.verify(&key.public_key(), b"data", &signature));
+            assert!(!algorithm.verify(&key.public_key(), b"other", &signature));
+        }
+
+        // an RSA key comes back from the fields it's written out with
+        let key = SigningKey::generate(Algorithm::RsaSha256);
+        let fields = key.private_fields();
+        let field = |i: usize| fields[i].1.as_slice();
+        let parts = RsaPrivateKey {
+            modulus: field(0),
+            public_exponent: field(1),
+            private_exponent: field(2),
+            prime1: field(3),
+            prime2: field(4),
+            exponent1: field(5),
+            exponent2: field(6),
+            coefficient: field(7),
+        };
+        let again = SigningKey::rsa(&parts).unwrap();
+        assert_eq!(again.public_key(), key.public_key());
+        assert_eq!(again.public_key()[..4], [3, 1, 0, 1]);
+        let swapped = RsaPrivateKey {
+            exponent1: parts.exponent2,
+            exponent2: parts.exponent1,
+            ..parts
+        };
+        assert!(SigningKey::rsa(&swapped).is_none());
+    }
+}
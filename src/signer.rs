@@ -0,0 +1,1010 @@
+// signing zones for DNSSEC (rfc 4035 section 2). every RRset the zone is authoritative for gets
+// an RRSIG from the zone's keys, and NSEC (rfc 4034 section 4) or NSEC3 (rfc 5155) records
+// chain its names together so the authority can prove what isn't there. when there are both
+// key signing and zone signing keys the former sign only the DNSKEY RRset, a single kind of key
+// signs everything. zones are signed whole when loaded and again well before the signatures
+// expire, so answering a query never signs anything. keys are read from and written to the
+// pair of files BIND's dnssec-keygen writes, which most DNSSEC tools read and write too,
+// along with the times they're published, start and stop signing and are removed. the zone
+// is signed again whenever one of those comes around, which is what rolls keys over (rfc
+// 6781 section 4.1).
+use crate::authority::Authority;
+use crate::dnssec::{self, CanonicalName, SECURE_ENTRY_POINT, ZONE_KEY};
+use crate::error::{DnsError, Result};
+use crate::logging;
+use crate::presentation::{base64, parse_base64, parse_record, parse_signature_time};
+use crate::signature::{Algorithm, RsaPrivateKey, SigningKey};
+use crate::structure::{DnsRecord, QueryType, SignatureTime};
+use crate::zone::Zone;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// How long signatures stay valid unless configured otherwise.
+pub const DEFAULT_VALIDITY: Duration = Duration::from_secs(14 * 24 * 3600);
+// signatures count as made this long ago, for validators whose clocks are behind
+const INCEPTION_OFFSET: u32 = 3600;
+// the DNSKEY protocol field, always 3
+const PROTOCOL: u8 = 3;
+// the ttl of DNSKEY records in key files that don't give one
+const DEFAULT_DNSKEY_TTL: u32 = 3600;
+// how long to wait before trying again when signing fails
+const RETRY: Duration = Duration::from_secs(60);
+// the version of BIND's private key format with the key's times in it
+const PRIVATE_KEY_FORMAT: &str = "v1.3";
+
+/// How a signed zone proves that names and types don't exist.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Chain {
+    /// NSEC records linking the names in order, rfc 4034.
+    Nsec,
+    /// NSEC3 records linking hashes of the names, so the names can't be walked (rfc 5155).
+    /// rfc 9276 recommends no extra iterations and no salt. With `opt_out` unsigned
+    /// delegations are left out of the chain.
+    Nsec3 {
+        iterations: u16,
+        salt: Vec<u8>,
+        opt_out: bool,
+    },
+}
+
+/// When a key goes through the stages of its life, in seconds since the epoch. A key is in
+/// the zone's DNSKEY RRset from `publish` until `delete` and signs from `activate` until
+/// `inactive`. A stage without a time has always started, or never ends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyTiming {
+    pub created: Option<u32>,
+    pub publish: Option<u32>,
+    pub activate: Option<u32>,
+    pub inactive: Option<u32>,
+    pub delete: Option<u32>,
+}
+
+impl KeyTiming {
+    /// Timing for a key made at `now` that's published and signs right away.
+    pub fn immediate(now: u32) -> Self {
+        Self {
+            created: Some(now),
+            publish: Some(now),
+            activate: Some(now),
+            inactive: None,
+            delete: None,
+        }
+    }
+
+    /// Whether the key's DNSKEY record is in the zone at `now`.
+    pub fn is_published(&self, now: u32) -> bool {
+        self.publish.is_none_or(|t| t <= now) && self.delete.is_none_or(|t| t > now)
+    }
+
+    /// Whether the key signs at `now`. Only published keys do, a signature the key isn't
+    /// there for wouldn't validate.
+    pub fn is_active(&self, now: u32) -> bool {
+        self.is_published(now)
+            && self.activate.is_none_or(|t| t <= now)
+            && self.inactive.is_none_or(|t| t > now)
+    }
+
+    /// The first time after `now` the key changes stage.
+    pub fn next_change(&self, now: u32) -> Option<u32> {
+        [self.publish, self.activate, self.inactive, self.delete]
+            .into_iter()
+            .flatten()
+            .filter(|&t| t > now)
+            .min()
+    }
+
+    // the times as the "Field: value" lines of the private key file
+    fn fields(&self) -> [(&'static str, Option<u32>); 5] {
+        [
+            ("Created", self.created),
+            ("Publish", self.publish),
+            ("Activate", self.activate),
+            ("Inactive", self.inactive),
+            ("Delete", self.delete),
+        ]
+    }
+}
+
+/// How a new key takes over from an old one of the same kind, rfc 6781 section 4.1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rollover {
+    /// The new key is published an interval before it takes over from the old one, so it's
+    /// in caches by the time its signatures are, and the old key stays published for another
+    /// interval while its signatures are still cached. Only one key signs at a time, which
+    /// keeps responses small. For zone signing keys.
+    PrePublish,
+    /// The new key is published and signs next to the old one right away, and the old one is
+    /// removed after an interval, by when the parent has to have the new key's DS record.
+    /// For key signing keys, where the DS change can't be timed exactly.
+    DoubleSignature,
+}
+
+impl Rollover {
+    /// Sets the times of `old` and `new` for `new` to take over starting at `now`, with
+    /// `interval` between the steps.
+    pub fn schedule(self, old: &mut ZoneKey, new: &mut ZoneKey, now: u32, interval: Duration) {
+        let interval = interval.as_secs() as u32;
+        let takeover = now.saturating_add(interval);
+        new.timing.publish = Some(now);
+        match self {
+            Rollover::PrePublish => {
+                new.timing.activate = Some(takeover);
+                old.timing.inactive = Some(takeover);
+                old.timing.delete = Some(takeover.saturating_add(interval));
+            }
+            Rollover::DoubleSignature => {
+                new.timing.activate = Some(now);
+                old.timing.inactive = Some(takeover);
+                old.timing.delete = Some(takeover);
+            }
+        }
+    }
+}
+
+/// One of a zone's keys: its DNSKEY record, the private key that goes with it and when it's
+/// used.
+#[derive(Clone, Debug)]
+pub struct ZoneKey {
+    dnskey: DnsRecord,
+    key: SigningKey,
+    timing: KeyTiming,
+}
+
+impl ZoneKey {
+    /// A key for `zone`, with the DNSKEY flags 257 for a key signing key or 256 for a zone
+    /// signing key.
+    pub fn new(zone: &str, flags: u16, ttl: u32, key: SigningKey) -> Self {
+        let dnskey = DnsRecord::DNSKEY {
+            domain: zone.trim_end_matches('.').to_lowercase(),
+            class: 1,
+            ttl,
+            flags,
+            protocol: PROTOCOL,
+            algorithm: key.algorithm().to_num(),
+            public_key: key.public_key(),
+        };
+        Self {
+            dnskey,
+            key,
+            timing: KeyTiming::default(),
+        }
+    }
+
+    /// Reads a key from its `.key` file, holding the DNSKEY record, and its `.private` file.
+    /// `path` can be either file or the name they share without the extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        // the names have dots in them, so the extension is added rather than replaced
+        let path = path.as_ref().to_string_lossy();
+        let base = path
+            .strip_suffix(".key")
+            .or_else(|| path.strip_suffix(".private"))
+            .unwrap_or(&path);
+        let key_path = PathBuf::from(format!("{}.key", base));
+        let private_path = PathBuf::from(format!("{}.private", base));
+
+        let text = fs::read_to_string(&key_path)?;
+        let dnskey = text
+            .lines()
+            .map(|line| line.split(';').next().unwrap_or_default())
+            .find(|line| !line.trim().is_empty())
+            .ok_or_else(|| {
+                DnsError::InvalidKey(format!("no DNSKEY record in {}", key_path.display()))
+            })
+            .and_then(|line| parse_record(line, "", DEFAULT_DNSKEY_TTL))?;
+        let (key, timing) = parse_private_key(&fs::read_to_string(&private_path)?)?;
+        Self::from_parts(dnskey, key, timing)
+            .map_err(|e| DnsError::InvalidKey(format!("{}: {}", base, e)))
+    }
+
+    /// Writes the key's `.key` and `.private` files into `dir`, replacing any there, and
+    /// returns the name they share without the extension. The private key file is only
+    /// readable by its owner.
+    pub fn save(&self, dir: impl AsRef<Path>) -> Result<PathBuf> {
+        let base = dir.as_ref().join(self.file_name());
+        let kind = if self.is_key_signing() {
+            "key-signing"
+        } else {
+            "zone-signing"
+        };
+        let mut public = format!(
+            "; This is a {} key, keyid {}, for {}\n",
+            kind,
+            self.key_tag(),
+            fqdn(self.zone())
+        );
+        let mut private = format!(
+            "Private-key-format: {}\nAlgorithm: {} ({})\n",
+            PRIVATE_KEY_FORMAT,
+            self.key.algorithm().to_num(),
+            self.key.algorithm()
+        );
+        for (field, value) in self.key.private_fields() {
+            let _ = writeln!(private, "{}: {}", field, base64(&value));
+        }
+        for (field, time) in self.timing.fields() {
+            if let Some(time) = time {
+                let _ = writeln!(public, "; {}: {}", field, SignatureTime(time));
+                let _ = writeln!(private, "{}: {}", field, SignatureTime(time));
+            }
+        }
+        let _ = writeln!(public, "{}", self.dnskey);
+
+        write_file(
+            &PathBuf::from(format!("{}.key", base.display())),
+            &public,
+            0o644,
+        )?;
+        write_file(
+            &PathBuf::from(format!("{}.private", base.display())),
+            &private,
+            0o600,
+        )?;
+        Ok(base)
+    }
+
+    /// The name BIND gives the key's files, K<zone>.+<algorithm>+<key tag>.
+    pub fn file_name(&self) -> String {
+        format!(
+            "K{}+{:03}+{:05}",
+            fqdn(self.zone()),
+            self.key.algorithm().to_num(),
+            self.key_tag()
+        )
+    }
+
+    // checks that the private key is the one the DNSKEY record has the public half of
+    fn from_parts(
+        dnskey: DnsRecord,
+        key: SigningKey,
+        timing: KeyTiming,
+    ) -> std::result::Result<Self, String> {
+        let DnsRecord::DNSKEY {
+            flags,
+            protocol,
+            algorithm,
+            public_key,
+            ..
+        } = &dnskey
+        else {
+            return Err(format!("{} isn't a DNSKEY record", dnskey.qtype()));
+        };
+        if flags & ZONE_KEY == 0 || *protocol != PROTOCOL {
+            return Err("not a zone key".into());
+        }
+        if *algorithm != key.algorithm().to_num() || *public_key != key.public_key() {
+            return Err("the private key doesn't match the DNSKEY record".into());
+        }
+        Ok(Self {
+            dnskey,
+            key,
+            timing,
+        })
+    }
+
+    pub fn dnskey(&self) -> &DnsRecord {
+        &self.dnskey
+    }
+
+    pub fn zone(&self) -> &str {
+        self.dnskey.domain()
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        dnssec::key_tag(&self.dnskey).unwrap_or_default()
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.key.algorithm()
+    }
+
+    /// The DNSKEY flags, 257 for a key signing key and 256 for a zone signing key.
+    pub fn flags(&self) -> u16 {
+        match self.dnskey {
+            DnsRecord::DNSKEY { flags, .. } => flags,
+            _ => 0,
+        }
+    }
+
+    pub fn timing(&self) -> &KeyTiming {
+        &self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: KeyTiming) {
+        self.timing = timing;
+    }
+
+    /// The DS record for the parent zone to point at this key with, using the digest type
+    /// `digest_type` (1 for SHA-1, 2 for SHA-256). None for digest types we don't support.
+    pub fn ds(&self, digest_type: u8) -> Option<DnsRecord> {
+        Some(DnsRecord::DS {
+            domain: self.zone().to_string(),
+            class: 1,
+            ttl: self.dnskey.ttl(),
+            key_tag: self.key_tag(),
+            algorithm: self.key.algorithm().to_num(),
+            digest_type,
+            digest: dnssec::ds_digest(&self.dnskey, digest_type)?,
+        })
+    }
+
+    /// Whether this is a key signing key, the one the parent's DS record points at.
+    pub fn is_key_signing(&self) -> bool {
+        self.flags() & SECURE_ENTRY_POINT != 0
+    }
+
+    /// An RRSIG over `rrset`, valid from `inception` to `expiration`.
+    pub fn sign(&self, rrset: &[&DnsRecord], inception: u32, expiration: u32) -> Option<DnsRecord> {
+        let first = rrset.first()?;
+        let mut rrsig = DnsRecord::RRSIG {
+            domain: first.domain().to_string(),
+            class: first.class(),
+            ttl: first.ttl(),
+            type_covered: first.qtype(),
+            algorithm: self.key.algorithm().to_num(),
+            labels: dnssec::label_count(first.domain()) as u8,
+            original_ttl: first.ttl(),
+            expiration,
+            inception,
+            key_tag: self.key_tag(),
+            signer: self.zone().to_string(),
+            signature: Vec::new(),
+        };
+        let data = dnssec::signed_data(rrset, &rrsig)?;
+        if let DnsRecord::RRSIG { signature, .. } = &mut rrsig {
+            *signature = self.key.sign(&data);
+        }
+        Some(rrsig)
+    }
+}
+
+// the .private file, "Field: value" lines with the algorithm number, the parts of the key in
+// base64 and the key's times
+fn parse_private_key(text: &str) -> Result<(SigningKey, KeyTiming)> {
+    let fields: HashMap<&str, &str> = text
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(field, value)| (field.trim(), value.trim()))
+        .collect();
+    let field = |name: &str| -> Result<Vec<u8>> {
+        let value = fields
+            .get(name)
+            .ok_or_else(|| DnsError::InvalidKey(format!("no {} in the private key", name)))?;
+        parse_base64(value)
+    };
+    let number = fields
+        .get("Algorithm")
+        .and_then(|alg| alg.split_whitespace().next())
+        .ok_or_else(|| DnsError::InvalidKey("no algorithm in the private key".into()))?;
+    let algorithm = number
+        .parse()
+        .ok()
+        .and_then(Algorithm::from_num)
+        .ok_or_else(|| DnsError::InvalidKey(format!("unsupported algorithm {}", number)))?;
+    let key = match algorithm {
+        Algorithm::RsaSha256 => SigningKey::rsa(&RsaPrivateKey {
+            modulus: &field("Modulus")?,
+            public_exponent: &field("PublicExponent")?,
+            private_exponent: &field("PrivateExponent")?,
+            prime1: &field("Prime1")?,
+            prime2: &field("Prime2")?,
+            exponent1: &field("Exponent1")?,
+            exponent2: &field("Exponent2")?,
+            coefficient: &field("Coefficient")?,
+        }),
+        Algorithm::EcdsaP256Sha256 => SigningKey::ecdsa_p256(&field("PrivateKey")?),
+        Algorithm::Ed25519 => SigningKey::ed25519(&field("PrivateKey")?),
+    };
+    let key =
+        key.ok_or_else(|| DnsError::InvalidKey(format!("invalid {} private key", algorithm)))?;
+
+    let time = |name: &str| -> Result<Option<u32>> {
+        fields
+            .get(name)
+            .map(|value| parse_signature_time(value))
+            .transpose()
+    };
+    let timing = KeyTiming {
+        created: time("Created")?,
+        publish: time("Publish")?,
+        activate: time("Activate")?,
+        inactive: time("Inactive")?,
+        delete: time("Delete")?,
+    };
+    Ok((key, timing))
+}
+
+// replaces the file at `path` through a temporary one, so it's never seen half written
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+// the name with the trailing dot, as key files write it
+fn fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
+
+/// Signs a zone, and keeps the signatures of the copy an [`Authority`] serves from
+/// running out.
+pub struct Signer {
+    zone: Zone,
+    keys: Vec<ZoneKey>,
+    chain: Chain,
+    validity: Duration,
+    // the serial of the last signed version, and when it was signed
+    serial: Option<u32>,
+    signed_at: Option<Instant>,
+}
+
+impl Signer {
+    /// A signer for `zone`, unsigned or with signatures that get replaced, with `keys`.
+    pub fn new(zone: Zone, keys: Vec<ZoneKey>) -> Self {
+        Self {
+            zone,
+            keys,
+            chain: Chain::Nsec,
+            validity: DEFAULT_VALIDITY,
+            serial: None,
+            signed_at: None,
+        }
+    }
+
+    /// How names and types are proven not to exist, NSEC unless set otherwise.
+    pub fn chain(mut self, chain: Chain) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// How long signatures stay valid. The zone is signed again when a quarter of that is
+    /// left.
+    pub fn validity(mut self, validity: Duration) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    pub fn origin(&self) -> &str {
+        self.zone.origin()
+    }
+
+    /// Signs the zone. The first version keeps the zone's serial, every one after that gets
+    /// the next serial, so secondaries pick up the new signatures.
+    pub fn sign(&mut self) -> Result<Zone> {
+        let serial = match self.serial {
+            Some(serial) => serial.wrapping_add(1),
+            None => self.zone.serial().unwrap_or_default(),
+        };
+        let zone = sign_zone(
+            &self.zone,
+            &self.keys,
+            &self.chain,
+            serial,
+            dnssec::now(),
+            self.validity,
+        )?;
+        self.serial = Some(serial);
+        self.signed_at = Some(Instant::now());
+        Ok(zone)
+    }
+
+    /// Serves the signed zone from `authority`, signing it again before the signatures
+    /// expire and whenever one of the keys changes stage. Signs it first if
+    /// [`Signer::sign`] hasn't been called yet. Runs until the future is dropped, failures
+    /// are reported and retried.
+    pub async fn run(mut self, authority: &Authority) {
+        loop {
+            if let Some(due) = self.due() {
+                time::sleep_until(due.into()).await;
+            }
+            match self.sign() {
+                Ok(zone) => authority.add_zone(zone),
+                Err(e) => {
+                    logging::error(&format!("signing zone {} failed: {}", self.origin(), e));
+                    time::sleep(RETRY).await;
+                }
+            }
+        }
+    }
+
+    // when the zone next has to be signed, None if it hasn't been yet
+    fn due(&self) -> Option<Instant> {
+        let refresh = self.signed_at? + self.validity - self.validity / 4;
+        let now = dnssec::now();
+        let change = self
+            .keys
+            .iter()
+            .filter_map(|key| key.timing.next_change(now))
+            .min();
+        // a second late, the clock is read in whole seconds
+        let change = change.map(|t| Instant::now() + Duration::from_secs((t - now) as u64 + 1));
+        Some(change.map_or(refresh, |change| change.min(refresh)))
+    }
+}
+
+/// Signs `zone` with `keys` at `now`, giving it `serial`: adds the DNSKEY records of the keys
+/// published at `now`, the NSEC or NSEC3 chain and an RRSIG over every RRset the zone is
+/// authoritative for from the keys active at `now`. Signatures, NSEC and NSEC3 records
+/// already in the zone are replaced, DNSKEY records of other keys are kept.
+pub fn sign_zone(
+    zone: &Zone,
+    keys: &[ZoneKey],
+    chain: &Chain,
+    serial: u32,
+    now: u32,
+    validity: Duration,
+) -> Result<Zone> {
+    let origin = zone.origin();
+    if !keys.iter().any(|key| key.timing.is_active(now)) {
+        return Err(DnsError::InvalidKey(format!(
+            "no active keys to sign {} with",
+            origin
+        )));
+    }
+    if let Some(key) = keys.iter().find(|key| key.zone() != origin) {
+        return Err(DnsError::InvalidKey(format!(
+            "key {} is for {}, not {}",
+            key.key_tag(),
+            key.zone(),
+            origin
+        )));
+    }
+    let Some(DnsRecord::SOA { ttl, minimum, .. }) = zone.soa() else {
+        return Err(DnsError::Syntax(format!(
+            "zone {} has no SOA record",
+            origin
+        )));
+    };
+    // rfc 9077, denial records live as long as the negative answers they're part of
+    let negative_ttl = (*ttl).min(*minimum);
+    let soa_ttl = *ttl;
+
+    let mut signed = Zone::new(origin);
+    for rec in zone.records() {
+        if matches!(
+            rec.qtype(),
+            QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3 | QueryType::NSEC3PARAM
+        ) {
+            continue;
+        }
+        let mut rec = rec.clone();
+        if let DnsRecord::SOA { serial: s, .. } = &mut rec {
+            *s = serial;
+        }
+        signed.insert(rec)?;
+    }
+    let dnskey_ttl = signed
+        .rrset(origin, QueryType::DNSKEY)
+        .next()
+        .map_or(soa_ttl, DnsRecord::ttl);
+    for key in keys.iter().filter(|key| key.timing.is_published(now)) {
+        let mut dnskey = key.dnskey.clone();
+        dnskey.set_ttl(dnskey_ttl);
+        signed.insert(dnskey)?;
+    }
+
+    match chain {
+        Chain::Nsec => add_nsec(&mut signed, negative_ttl)?,
+        Chain::Nsec3 {
+            iterations,
+            salt,
+            opt_out,
+        } => add_nsec3(&mut signed, *iterations, salt, *opt_out, negative_ttl)?,
+    }
+
+    // key signing keys only sign the DNSKEY RRset, unless they're all there is
+    let (ksks, zsks): (Vec<&ZoneKey>, Vec<&ZoneKey>) = keys
+        .iter()
+        .filter(|key| key.timing.is_active(now))
+        .partition(|key| key.is_key_signing());
+    let key_signing = if ksks.is_empty() { &zsks } else { &ksks };
+    let zone_signing = if zsks.is_empty() { &ksks } else { &zsks };
+
+    let inception = now.wrapping_sub(INCEPTION_OFFSET);
+    let expiration = now.wrapping_add(validity.as_secs() as u32);
+    let mut signatures = Vec::new();
+    for (name, qtype, rrset) in rrsets(&signed) {
+        if !is_signed(&signed, name, qtype) {
+            continue;
+        }
+        let signers = if qtype == QueryType::DNSKEY {
+            key_signing
+        } else {
+            zone_signing
+        };
+        for key in signers.iter() {
+            let rrsig = key
+                .sign(&rrset, inception, expiration)
+                .ok_or_else(|| DnsError::InvalidRdata(format!("can't sign {} {}", name, qtype)))?;
+            signatures.push(rrsig);
+        }
+    }
+    for rrsig in signatures {
+        signed.insert(rrsig)?;
+    }
+    Ok(signed)
+}
+
+// the zone's RRsets, as owner, type and records
+fn rrsets(zone: &Zone) -> Vec<(&str, QueryType, Vec<&DnsRecord>)> {
+    let mut sets = Vec::new();
+    for name in zone.names() {
+        let mut by_type: BTreeMap<u16, Vec<&DnsRecord>> = BTreeMap::new();
+        for rec in zone.get(name).unwrap_or_default() {
+            by_type.entry(rec.qtype().to_num()).or_default().push(rec);
+        }
+        for (qtype, rrset) in by_type {
+            sets.push((name, QueryType::from_num(qtype), rrset));
+        }
+    }
+    sets
+}
+
+// whether the zone signs this RRset: not glue below a delegation, and at a delegation only
+// the DS and NSEC records, the NS records there belong to the child. rfc 4035 section 2.2.
+// nothing below a DNAME is ever served, so it isn't signed either
+fn is_signed(zone: &Zone, name: &str, qtype: QueryType) -> bool {
+    if qtype == QueryType::RRSIG || zone.dname(name).is_some() {
+        return false;
+    }
+    match zone.delegation(name) {
+        None => true,
+        Some(cut) if cut == name => matches!(qtype, QueryType::DS | QueryType::NSEC),
+        Some(_) => false,
+    }
+}
+
+// the names the zone is authoritative for, the delegation points included but not the glue
+// below them, nor the names below a DNAME
+fn authoritative_names(zone: &Zone) -> Vec<String> {
+    zone.names()
+        .filter(|name| zone.delegation(name).is_none_or(|cut| cut == *name))
+        .filter(|name| zone.dname(name).is_none())
+        .map(str::to_string)
+        .collect()
+}
+
+// the types at a name as its NSEC or NSEC3 record lists them, with `extra` on top. at a
+// delegation only the NS and DS records count, anything else there is glue
+fn types_at(zone: &Zone, name: &str, extra: &[QueryType]) -> Vec<QueryType> {
+    let delegation = zone.delegation(name) == Some(name);
+    let mut types: BTreeSet<u16> = zone
+        .get(name)
+        .unwrap_or_default()
+        .iter()
+        .map(DnsRecord::qtype)
+        .filter(|qtype| !delegation || matches!(qtype, QueryType::NS | QueryType::DS))
+        .map(QueryType::to_num)
+        .collect();
+    types.extend(extra.iter().map(|qtype| qtype.to_num()));
+    types.into_iter().map(QueryType::from_num).collect()
+}
+
+fn add_nsec(zone: &mut Zone, ttl: u32) -> Result<()> {
+    let mut names = authoritative_names(zone);
+    names.sort_by(|a, b| dnssec::canonical_cmp(a, b));
+    let mut records = Vec::with_capacity(names.len());
+    for (i, name) in names.iter().enumerate() {
+        // the last one wraps around to the apex
+        let next = &names[(i + 1) % names.len()];
+        records.push(DnsRecord::NSEC {
+            domain: name.clone(),
+            class: 1,
+            ttl,
+            next: next.clone(),
+            types: types_at(zone, name, &[QueryType::RRSIG, QueryType::NSEC]),
+        });
+    }
+    for rec in records {
+        zone.insert(rec)?;
+    }
+    Ok(())
+}
+
+// rfc 5155 section 7.1. empty non-terminals get NSEC3 records too, as they exist and nothing
+// could otherwise prove it
+fn add_nsec3(zone: &mut Zone, iterations: u16, salt: &[u8], opt_out: bool, ttl: u32) -> Result<()> {
+    let origin = zone.origin().to_string();
+    zone.insert(DnsRecord::NSEC3PARAM {
+        domain: origin.clone(),
+        class: 1,
+        ttl: 0,
+        hash_algorithm: 1,
+        flags: 0,
+        iterations,
+        salt: salt.to_vec(),
+    })?;
+
+    let mut names: BTreeSet<CanonicalName> = BTreeSet::new();
+    for name in authoritative_names(zone) {
+        let unsigned_delegation = zone.delegation(&name) == Some(&name)
+            && zone.rrset(&name, QueryType::DS).next().is_none();
+        if opt_out && unsigned_delegation {
+            continue;
+        }
+        let mut ancestor = name.as_str();
+        while ancestor != origin {
+            names.insert(CanonicalName(ancestor.to_string()));
+            ancestor = ancestor.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        names.insert(CanonicalName(origin.clone()));
+    }
+
+    let mut hashed: Vec<(Vec<u8>, &str, Vec<QueryType>)> = names
+        .iter()
+        .map(|name| {
+            let name = name.0.as_str();
+            let signed = zone
+                .get(name)
+                .unwrap_or_default()
+                .iter()
+                .any(|rec| is_signed(zone, name, rec.qtype()));
+            let extra: &[QueryType] = if signed { &[QueryType::RRSIG] } else { &[] };
+            (
+                dnssec::nsec3_hash(name, salt, iterations),
+                name,
+                types_at(zone, name, extra),
+            )
+        })
+        .collect();
+    hashed.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut records = Vec::with_capacity(hashed.len());
+    for (i, (_, name, types)) in hashed.iter().enumerate() {
+        // the last one wraps around to the first
+        let next = &hashed[(i + 1) % hashed.len()].0;
+        records.push(DnsRecord::NSEC3 {
+            domain: dnssec::nsec3_owner(name, &origin, salt, iterations),
+            class: 1,
+            ttl,
+            hash_algorithm: 1,
+            flags: opt_out as u8,
+            iterations,
+            salt: salt.to_vec(),
+            next_hashed: next.clone(),
+            types: types.clone(),
+        });
+    }
+    for rec in records {
+        zone.insert(rec)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the names of rfc 4034 section 6.1 that a zone file can hold, a delegation with glue
+    // below it, and one with a DS record
+    const ZONE: &str = "\
+$TTL 3600
+@ SOA a hostmaster 1 7200 900 1209600 300
+@ NS a
+a A 192.0.2.1
+yljkjljk.a A 192.0.2.2
+Z.a TXT \"upper case\"
+zABC.a TXT \"mixed case\"
+z A 192.0.2.3
+*.z A 192.0.2.4
+sub NS ns.sub
+ns.sub A 192.0.2.5
+secure NS ns.sub
+secure DS 12345 15 2 e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d
+";
+
+    fn keys() -> Vec<ZoneKey> {
+        vec![
+            ZoneKey::new(
+                "example",
+                257,
+                3600,
+                SigningKey::generate(Algorithm::Ed25519),
+            ),
+            ZoneKey::new(
+                "example",
+                256,
+                3600,
+                SigningKey::generate(Algorithm::EcdsaP256Sha256),
+            ),
+        ]
+    }
+
+    fn signed(keys: &[ZoneKey], chain: &Chain) -> Zone {
+        let zone = Zone::parse(ZONE, "example").unwrap();
+        sign_zone(&zone, keys, chain, 2, dnssec::now(), DEFAULT_VALIDITY).unwrap()
+    }
+
+    #[test]
+    fn signs_what_a_validator_then_accepts() {
+        let keys = keys();
+        let zone = signed(&keys, &Chain::Nsec);
+        assert_eq!(zone.serial(), Some(2));
+        let records: Vec<DnsRecord> = zone.records().cloned().collect();
+        let sets = dnssec::rrsets(&records);
+        let now = dnssec::now();
+
+        // the DNSKEY RRset checks out against the DS the parent would have, signed by the key
+        // signing key alone
+        let dnskeys = sets
+            .iter()
+            .find(|set| set.qtype == QueryType::DNSKEY)
+            .unwrap();
+        assert_eq!(dnskeys.records.len(), 2);
+        let ds = keys[0].ds(2).unwrap();
+        let zone_keys = dnssec::verify_dnskeys(
+            "example",
+            &dnskeys.records,
+            &dnskeys.signatures,
+            &[&ds],
+            now,
+        )
+        .unwrap();
+        let tags = |set: &dnssec::RRset| -> Vec<u16> {
+            set.signatures
+                .iter()
+                .map(|rrsig| match rrsig {
+                    DnsRecord::RRSIG { key_tag, .. } => *key_tag,
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        assert_eq!(tags(dnskeys), [keys[0].key_tag()]);
+
+        // everything else by the zone signing key, but for what belongs to the child
+        for set in &sets {
+            let delegated = matches!(
+                set.name,
+                "sub.example" | "secure.example" | "ns.sub.example"
+            ) && !matches!(set.qtype, QueryType::DS | QueryType::NSEC);
+            match set.qtype {
+                QueryType::DNSKEY => continue,
+                _ if delegated => assert!(set.signatures.is_empty(), "{} {}", set.name, set.qtype),
+                _ => {
+                    assert_eq!(tags(set), [keys[1].key_tag()], "{} {}", set.name, set.qtype);
+                    dnssec::verify_rrset(&set.records, &set.signatures, &zone_keys, now).unwrap();
+                }
+            }
+        }
+
+        // the wildcard's signature says how many labels it was made for
+        let wildcard = sets.iter().find(|set| set.name == "*.z.example").unwrap();
+        let Some(DnsRecord::RRSIG { labels, .. }) = wildcard.signatures.first() else {
+            panic!("the wildcard isn't signed");
+        };
+        assert_eq!(*labels, 2);
+
+        // a key not yet active signs nothing, and without any the zone can't be signed
+        let mut waiting = keys.clone();
+        waiting[1].set_timing(KeyTiming {
+            activate: Some(now + 3600),
+            ..KeyTiming::default()
+        });
+        let zone = signed(&waiting, &Chain::Nsec);
+        assert!(zone.records().all(|rec| !matches!(
+            rec,
+            DnsRecord::RRSIG { key_tag, .. } if *key_tag == waiting[1].key_tag()
+        )));
+        let zone = Zone::parse(ZONE, "example").unwrap();
+        assert!(sign_zone(&zone, &waiting[1..], &Chain::Nsec, 2, now, DEFAULT_VALIDITY).is_err());
+    }
+
+    #[test]
+    fn chains_the_names_in_canonical_order() {
+        let zone = signed(&keys(), &Chain::Nsec);
+        let nsec: Vec<(&str, &str, &[QueryType])> = zone
+            .records()
+            .filter_map(|rec| match rec {
+                DnsRecord::NSEC {
+                    domain,
+                    next,
+                    types,
+                    ..
+                } => Some((domain.as_str(), next.as_str(), types.as_slice())),
+                _ => None,
+            })
+            .collect();
+
+        // from the apex, each name to the next, and round to the apex again. the glue below
+        // the delegations has no place in it
+        let mut owner = "example";
+        let mut order = Vec::new();
+        for _ in 0..nsec.len() {
+            order.push(owner);
+            let (_, next, _) = nsec.iter().find(|(name, _, _)| *name == owner).unwrap();
+            owner = next;
+        }
+        assert_eq!(owner, "example");
+        assert_eq!(
+            order,
+            [
+                "example",
+                "a.example",
+                "yljkjljk.a.example",
+                "z.a.example",
+                "zabc.a.example",
+                "secure.example",
+                "sub.example",
+                "z.example",
+                "*.z.example",
+            ]
+        );
+        for pair in order.windows(2) {
+            assert_eq!(
+                dnssec::canonical_cmp(pair[0], pair[1]),
+                std::cmp::Ordering::Less
+            );
+        }
+
+        let types = |name: &str| nsec.iter().find(|(owner, _, _)| *owner == name).unwrap().2;
+        use QueryType::*;
+        assert_eq!(types("example"), [NS, SOA, RRSIG, NSEC, DNSKEY]);
+        assert_eq!(types("sub.example"), [NS, RRSIG, NSEC]);
+        assert_eq!(types("secure.example"), [NS, DS, RRSIG, NSEC]);
+        assert_eq!(types("z.a.example"), [TXT, RRSIG, NSEC]);
+    }
+
+    #[test]
+    fn chains_the_hashes_in_order() {
+        let (salt, iterations) = (vec![0xab, 0xcd], 1);
+        let chain = Chain::Nsec3 {
+            iterations,
+            salt: salt.clone(),
+            opt_out: true,
+        };
+        let zone = signed(&keys(), &chain);
+        let mut nsec3: Vec<(Vec<u8>, Vec<u8>, u8)> = zone
+            .records()
+            .filter_map(|rec| match rec {
+                DnsRecord::NSEC3 {
+                    domain,
+                    next_hashed,
+                    flags,
+                    ..
+                } => {
+                    let label = domain.split_once('.').unwrap().0;
+                    let hash = crate::presentation::parse_base32hex(label).unwrap();
+                    Some((hash, next_hashed.clone(), *flags))
+                }
+                _ => None,
+            })
+            .collect();
+        nsec3.sort();
+
+        // every name but the unsigned delegation, which is opted out, and the glue below it
+        let hashed = |name: &str| dnssec::nsec3_hash(name, &salt, iterations);
+        let mut expected: Vec<Vec<u8>> = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "z.a.example",
+            "zabc.a.example",
+            "secure.example",
+            "z.example",
+            "*.z.example",
+        ]
+        .iter()
+        .map(|name| hashed(name))
+        .collect();
+        expected.sort();
+        let owners: Vec<Vec<u8>> = nsec3.iter().map(|(hash, _, _)| hash.clone()).collect();
+        assert_eq!(owners, expected);
+        for (i, (_, next, flags)) in nsec3.iter().enumerate() {
+            assert_eq!(*next, nsec3[(i + 1) % nsec3.len()].0);
+            assert_eq!(*flags, 1);
+        }
+    }
+}
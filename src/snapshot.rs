@@ -0,0 +1,172 @@
+// saving the cache to disk and loading it back, so a restart doesn't start cold. the file is a
+// short header followed by one record per entry:
+//
+//   "DNSC" | version (u8) | saved at, unix seconds (u64)
+//   ttl left when saved, seconds (u32) | message length (u16) | message
+//
+// each message is the cached answer written as a dns response to the entry's question, which
+// gets name compression for free and can be read back with the normal parser. on load the time
+// the file spent on disk is taken off every TTL and whatever ran out in the meantime is skipped.
+use crate::cache::{Cache, CacheKey, CachedAnswer};
+use crate::error::{DnsError, Result};
+use crate::structure::{BytePacketBuffer, DnsPacket};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 4] = b"DNSC";
+const VERSION: u8 = 1;
+
+pub struct SnapshotEntry {
+    pub key: CacheKey,
+    pub answer: CachedAnswer,
+    /// How long the entry had left when the snapshot was taken.
+    pub ttl: Duration,
+}
+
+pub struct Snapshot {
+    pub saved_at: SystemTime,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+impl Snapshot {
+    /// Takes the live entries of `cache`.
+    pub fn of(cache: &Cache) -> Self {
+        let entries = cache
+            .entries()
+            .into_iter()
+            .map(|(key, answer, ttl)| SnapshotEntry { key, answer, ttl })
+            .collect();
+        Self {
+            saved_at: SystemTime::now(),
+            entries,
+        }
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(DnsError::InvalidSnapshot("not a cache snapshot".into()));
+        }
+        let version = read_array::<1>(&mut file)?[0];
+        if version != VERSION {
+            return Err(DnsError::InvalidSnapshot(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+        let saved_at = u64::from_be_bytes(read_array(&mut file)?);
+        let saved_at = UNIX_EPOCH + Duration::from_secs(saved_at);
+
+        let mut entries = Vec::new();
+        loop {
+            let ttl = match read_array(&mut file) {
+                Ok(ttl) => u32::from_be_bytes(ttl),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            let len = u16::from_be_bytes(read_array(&mut file)?) as usize;
+            let mut buf = BytePacketBuffer::with_len(len);
+            file.read_exact(&mut buf.buf[..len])?;
+            entries.push(read_entry(&mut buf, len, ttl)?);
+        }
+        Ok(Self { saved_at, entries })
+    }
+
+    /// Writes the snapshot to a temporary file next to `path` and moves it into place, so a
+    /// crash halfway through leaves the previous snapshot intact.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+
+        let saved_at = self.saved_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION])?;
+        file.write_all(&saved_at.as_secs().to_be_bytes())?;
+        for entry in &self.entries {
+            let mut buf = BytePacketBuffer::new();
+            entry_message(entry).write(&mut buf)?;
+            let ttl = entry.ttl.as_secs().min(u32::MAX as u64) as u32;
+            file.write_all(&ttl.to_be_bytes())?;
+            file.write_all(&(buf.as_slice().len() as u16).to_be_bytes())?;
+            file.write_all(buf.as_slice())?;
+        }
+
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Puts the entries into `cache`, minus however long ago the snapshot was taken. Returns
+    /// how many were still fresh.
+    pub fn restore(self, cache: &Cache) -> usize {
+        let age = self.saved_at.elapsed().unwrap_or_default();
+        let elapsed = age.as_secs() as u32;
+        let mut restored = 0;
+        for mut entry in self.entries {
+            let Some(ttl) = entry.ttl.checked_sub(age).filter(|ttl| !ttl.is_zero()) else {
+                continue;
+            };
+            let answer = &mut entry.answer;
+            for rec in answer.answers.iter_mut().chain(&mut answer.authorities) {
+                rec.set_ttl(rec.ttl().saturating_sub(elapsed));
+            }
+            cache.restore(entry.key, entry.answer, ttl);
+            restored += 1;
+        }
+        restored
+    }
+}
+
+/// Saves the live entries of `cache` to `path`.
+pub fn save(cache: &Cache, path: impl AsRef<Path>) -> Result<usize> {
+    let snapshot = Snapshot::of(cache);
+    snapshot.write(path)?;
+    Ok(snapshot.entries.len())
+}
+
+/// Loads the snapshot at `path` into `cache`, returning how many entries were still fresh.
+pub fn load(cache: &Cache, path: impl AsRef<Path>) -> Result<usize> {
+    Ok(Snapshot::read(path)?.restore(cache))
+}
+
+fn entry_message(entry: &SnapshotEntry) -> DnsPacket {
+    let key = &entry.key;
+    let mut packet = DnsPacket::query(&key.name, key.qtype)
+        .class(key.class)
+        .edns(None)
+        .build();
+    packet.header.flags.response = true;
+    packet.header.flags.recursion_available = entry.answer.recursion_available;
+    packet.header.rcode = entry.answer.rcode;
+    packet.answers = entry.answer.answers.clone();
+    packet.authorities = entry.answer.authorities.clone();
+    packet
+}
+
+fn read_entry(buf: &mut BytePacketBuffer, len: usize, ttl: u32) -> Result<SnapshotEntry> {
+    let packet = DnsPacket::from_message(buf, len)?;
+    let [question] = &packet.questions[..] else {
+        return Err(DnsError::QuestionCount(packet.questions.len()));
+    };
+    Ok(SnapshotEntry {
+        key: CacheKey::from(question),
+        answer: CachedAnswer {
+            rcode: packet.header.rcode,
+            recursion_available: packet.header.flags.recursion_available,
+            answers: packet.answers,
+            authorities: packet.authorities,
+        },
+        ttl: Duration::from_secs(ttl as u64),
+    })
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
@@ -1,33 +1,76 @@
 #![allow(clippy::upper_case_acronyms)]
-use crate::structure::QueryType::{A, UNKNOWN};
-use anyhow::{bail, Result};
+use crate::error::{DnsError, Result};
+use crate::logging::civil_from_days;
+use crate::presentation;
+use crate::structure::QueryType::{
+    A, AAAA, AXFR, CNAME, DNAME, DNSKEY, DS, IXFR, MX, NS, NSEC, NSEC3, NSEC3PARAM, NULL, OPT, PTR,
+    RRSIG, SOA, SRV, SVCB, TSIG, TXT, UNKNOWN,
+};
+use crate::svcb;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
+/// The largest message the wire format can describe, TCP messages carry a 16 bit length.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
 
-// this will represent our entire query
+// this will represent our entire query. it lives on the heap, buffers are held across awaits.
+// one being written grows with what's written, up to the largest TCP message, udp limits are
+// enforced when writing with DnsPacket::write_truncated. one being read into is sized to what
+// the transport can deliver, see BytePacketBuffer::with_len, and reads don't go past its end.
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512], // 512 bytes because that's the udp packet limit
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        BytePacketBuffer::new()
+    }
+}
+
 impl BytePacketBuffer {
     pub fn new() -> Self {
         Self {
-            buf: [0; 512],
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// A zero filled buffer of `len` bytes to receive a message into, e.g. the largest
+    /// datagram expected.
+    pub fn with_len(len: usize) -> Self {
+        Self {
+            buf: vec![0; len.min(MAX_MESSAGE_SIZE)],
             pos: 0,
         }
     }
 
+    /// A buffer holding a copy of `message`, to be read.
+    pub fn from_bytes(message: &[u8]) -> Result<Self> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(DnsError::MessageTooLarge(format!(
+                "message of {} bytes",
+                message.len()
+            )));
+        }
+        Ok(Self {
+            buf: message.to_vec(),
+            pos: 0,
+        })
+    }
+
     fn pos(&self) -> usize {
         self.pos
     }
 
-    fn seek(&mut self, pos: usize) -> Result<()> {
+    pub fn seek(&mut self, pos: usize) -> Result<()> {
         self.pos = pos;
         Ok(())
     }
 
     fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
-            bail!("End of buffer")
+        if self.pos >= self.buf.len() {
+            return Err(DnsError::BufferOverrun);
         }
         let byte = self.buf[self.pos];
         self.pos += 1;
@@ -40,9 +83,14 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
+    fn read_u32(&mut self) -> Result<u32> {
+        let res = ((self.read_u16()? as u32) << 16) | (self.read_u16()? as u32);
+        Ok(res)
+    }
+
     fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
-            bail!("End of buffer");
+        if pos >= self.buf.len() {
+            return Err(DnsError::BufferOverrun);
         }
 
         Ok(self.buf[pos])
@@ -50,12 +98,21 @@ impl BytePacketBuffer {
 
     // read a range of bytes as mentioned by the length preceding a part of the qname
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
-            bail!("End of buffer");
+        if start + len > self.buf.len() {
+            return Err(DnsError::BufferOverrun);
         }
         Ok(&self.buf[start..(start + len)])
     }
 
+    // skip over bytes we don't decode, e.g. the rdata of unknown records
+    fn step(&mut self, steps: usize) -> Result<()> {
+        if self.pos + steps > self.buf.len() {
+            return Err(DnsError::BufferOverrun);
+        }
+        self.pos += steps;
+        Ok(())
+    }
+
     fn read_qname(&mut self) -> Result<String> {
         // locally track pos because we might encounter jumps
         let mut pos = self.pos();
@@ -70,7 +127,7 @@ impl BytePacketBuffer {
         loop {
             // to prevent a infinite jump loop
             if jumps_performed > max_jumps {
-                bail!("max jumps exceeded");
+                return Err(DnsError::CompressionLoop);
             }
 
             let len = self.get(pos)?;
@@ -128,12 +185,155 @@ impl BytePacketBuffer {
         }
         Ok(out)
     }
+
+    // the rest of the rdata ending at `end`, for fields that run to its end
+    fn read_to(&mut self, end: usize) -> Result<Vec<u8>> {
+        let len = end
+            .checked_sub(self.pos)
+            .ok_or_else(|| DnsError::InvalidRdata("rdata is too short".to_string()))?;
+        let bytes = self.get_range(self.pos, len)?.to_vec();
+        self.step(len)?;
+        Ok(bytes)
+    }
+
+    // a field prefixed by its length in one byte, like the NSEC3 salt
+    fn read_counted(&mut self) -> Result<Vec<u8>> {
+        let len = self.read()? as usize;
+        let bytes = self.get_range(self.pos, len)?.to_vec();
+        self.step(len)?;
+        Ok(bytes)
+    }
+
+    // the type bitmaps of NSEC and NSEC3, rfc 4034 section 4.1.2. the types are split into
+    // windows of 256, each written as its number, the length of its bitmap, and the bitmap
+    // with the most significant bit of the first byte standing for the lowest type
+    fn read_type_bitmap(&mut self, end: usize) -> Result<Vec<QueryType>> {
+        let mut types = Vec::new();
+        while self.pos < end {
+            let window = self.read()? as u16;
+            let len = self.read()? as usize;
+            if len == 0 || len > 32 {
+                return Err(DnsError::InvalidRdata(format!(
+                    "type bitmap window of {} bytes",
+                    len
+                )));
+            }
+            let bitmap = self.get_range(self.pos, len)?.to_vec();
+            self.step(len)?;
+            for (i, byte) in bitmap.into_iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        types.push(QueryType::from_num((window << 8) | (i * 8 + bit) as u16));
+                    }
+                }
+            }
+        }
+        Ok(types)
+    }
+
+    /// The bytes written so far, i.e. what goes on the wire.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        self.write_bytes(&[val])
+    }
+
+    fn write_u16(&mut self, val: u16) -> Result<()> {
+        self.write((val >> 8) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+        Ok(())
+    }
+
+    fn write_u32(&mut self, val: u32) -> Result<()> {
+        self.write_u16((val >> 16) as u16)?;
+        self.write_u16((val & 0xFFFF) as u16)?;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let end = self.pos + bytes.len();
+        if end > MAX_MESSAGE_SIZE {
+            return Err(DnsError::BufferOverrun);
+        }
+        if end > self.buf.len() {
+            self.buf.resize(end, 0);
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    // the inverse of read_qname, every label gets its length byte in front and the name is
+    // terminated by the empty root label. we don't compress names when writing.
+    fn write_qname(&mut self, qname: &str) -> Result<()> {
+        let qname = qname.trim_end_matches('.');
+        if qname.len() > 253 {
+            return Err(DnsError::NameTooLong(qname.to_string()));
+        }
+        if !qname.is_empty() {
+            for label in qname.split('.') {
+                let len = label.len();
+                if len == 0 {
+                    return Err(DnsError::EmptyLabel(qname.to_string()));
+                }
+                if len > 0x3f {
+                    return Err(DnsError::LabelTooLong);
+                }
+                self.write(len as u8)?;
+                self.write_bytes(label.as_bytes())?;
+            }
+        }
+        self.write(0)?;
+        Ok(())
+    }
+
+    fn write_counted(&mut self, bytes: &[u8]) -> Result<()> {
+        if bytes.len() > 255 {
+            return Err(DnsError::InvalidRdata(format!(
+                "a field of {} bytes doesn't fit its length byte",
+                bytes.len()
+            )));
+        }
+        self.write(bytes.len() as u8)?;
+        self.write_bytes(bytes)
+    }
+
+    fn write_type_bitmap(&mut self, types: &[QueryType]) -> Result<()> {
+        let mut nums: Vec<u16> = types.iter().map(|qtype| qtype.to_num()).collect();
+        nums.sort_unstable();
+        nums.dedup();
+        for window in nums.chunk_by(|a, b| a >> 8 == b >> 8) {
+            let mut bitmap = [0u8; 32];
+            let mut len = 0;
+            for num in window {
+                let low = (num & 0xFF) as usize;
+                bitmap[low / 8] |= 0x80 >> (low % 8);
+                len = low / 8 + 1;
+            }
+            self.write((window[0] >> 8) as u8)?;
+            self.write(len as u8)?;
+            self.write_bytes(&bitmap[..len])?;
+        }
+        Ok(())
+    }
+
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        if pos + 2 > self.buf.len() {
+            return Err(DnsError::BufferOverrun);
+        }
+        self.buf[pos] = (val >> 8) as u8;
+        self.buf[pos + 1] = (val & 0xFF) as u8;
+        Ok(())
+    }
 }
 
 /// only implementing a few common result codes, the entire list is here
-/// https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-6
+/// <https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-6>
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
@@ -143,8 +343,9 @@ pub enum ResultCode {
     REFUSED = 5,
     YXDOMAIN = 6,
     XRRSET = 7,
-    NOTAUTH = 8,
-    NOTZONE = 9,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10,
 }
 
 impl ResultCode {
@@ -157,28 +358,130 @@ impl ResultCode {
             5 => ResultCode::REFUSED,
             6 => ResultCode::YXDOMAIN,
             7 => ResultCode::XRRSET,
-            8 => ResultCode::NOTAUTH,
-            9 => ResultCode::NOTZONE,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
             _ => ResultCode::NOERROR,
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Opcode {
+    #[default]
+    QUERY,
+    IQUERY,
+    STATUS,
+    NOTIFY,
+    UPDATE,
+    DSO,
+    UNKNOWN(u8),
+}
+
+impl Opcode {
+    pub fn from_num(n: u8) -> Self {
+        match n {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            6 => Opcode::DSO,
+            _ => Opcode::UNKNOWN(n),
+        }
+    }
+
+    pub fn to_num(self) -> u8 {
+        match self {
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::DSO => 6,
+            Opcode::UNKNOWN(n) => n,
+        }
+    }
+}
+
+/// The flag bits of the header, everything in the second 16 bits except the rcode. The Z bit
+/// has no field: it's dropped when reading and always written as zero, as rfc 1035 requires.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeaderFlags {
+    pub response: bool,
+    pub opcode: Opcode,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    pub authentic_data: bool,
+    pub checking_disabled: bool,
+}
+
+impl HeaderFlags {
+    pub fn is_query(&self) -> bool {
+        !self.response
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.response
+    }
+
+    // 0 0 0 0 0 0 0 1  0 0 1 0 0 0 0 0
+    // - -+-+-+- - - -  - -+-+- -+-+-+-
+    // Q    O    A T R  R Z A C    R
+    // R    P    A C D  A   D D    C
+    //      C                      O
+    //      O                      D
+    //      D                      E
+    //      E
+    // shared with the borrowed parser, which reads the two flag bytes off a plain slice. the
+    // rcode in the low nibble of the second byte is left to the caller.
+    pub(crate) fn unpack(a: u8, b: u8) -> Self {
+        Self {
+            response: a & 0x80 != 0,
+            opcode: Opcode::from_num((a & 0x78) >> 3),
+            authoritative: a & 0x4 != 0,
+            truncated: a & 0x2 != 0,
+            recursion_desired: a & 0x1 != 0,
+            recursion_available: b & 0x80 != 0,
+            authentic_data: b & 0x20 != 0,
+            checking_disabled: b & 0x10 != 0,
+        }
+    }
+
+    /// The two flag bytes with `rcode` in the low nibble. Fails if the opcode doesn't fit in
+    /// its four bits, rather than letting it spill into the neighbouring flags.
+    pub fn pack(&self, rcode: ResultCode) -> Result<[u8; 2]> {
+        let opcode = self.opcode.to_num();
+        if opcode > 0xF {
+            return Err(DnsError::InvalidOpcode(opcode));
+        }
+        let a = ((self.response as u8) << 7)
+            | (opcode << 3)
+            | ((self.authoritative as u8) << 2)
+            | ((self.truncated as u8) << 1)
+            | (self.recursion_desired as u8);
+        let b = ((self.recursion_available as u8) << 7)
+            | ((self.authentic_data as u8) << 5)
+            | ((self.checking_disabled as u8) << 4)
+            | (rcode as u8 & 0xF);
+        Ok([a, b])
+    }
+}
+
 // header structure
 // 86 2a 01 20 00 01 00 00 00 00 00 00
 // in this example, 86 2a are the 16-bit ids
-// 01 20 represent the flags from query_res to rcode
+// 01 20 represent the flags and the rcode
 // 00 01, 00 00, 00 00, 00 00 represent the u16 counts
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsHeader {
     pub id: u16, // 16 bit uid
-    pub query_res: bool,
-    pub opcode: u8, // 4 bits but we can use the low nibble
-    pub auth_ans: bool,
-    pub trunc_msg: bool,
-    pub rec_des: bool,
-    pub rec_ava: bool,
-    pub z: u8, // 3 bits fsr
+    pub flags: HeaderFlags,
     pub rcode: ResultCode,
     pub qdcount: u16,
     pub anscount: u16,
@@ -186,17 +489,17 @@ pub struct DnsHeader {
     pub arcount: u16,
 }
 
+impl Default for DnsHeader {
+    fn default() -> Self {
+        DnsHeader::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> Self {
         Self {
             id: 0,
-            query_res: false,
-            opcode: 0,
-            auth_ans: false,
-            trunc_msg: false,
-            rec_des: false,
-            rec_ava: false,
-            z: 0,
+            flags: HeaderFlags::default(),
             rcode: ResultCode::NOERROR,
             qdcount: 0,
             anscount: 0,
@@ -204,30 +507,21 @@ impl DnsHeader {
             arcount: 0,
         }
     }
+
+    pub fn is_query(&self) -> bool {
+        self.flags.is_query()
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags.is_response()
+    }
+
     pub fn read(&mut self, buf: &mut BytePacketBuffer) -> Result<()> {
         self.id = buf.read_u16()?;
 
-        // 0 0 0 0 0 0 0 1  0 0 1 0 0 0 0 0
-        // - -+-+-+- - - -  - -+-+- -+-+-+-
-        // Q    O    A T R  R   Z      R
-        // R    P    A C D  A          C
-        //      C                      O
-        //      O                      D
-        //      D                      E
-        //      E
         let a = buf.read()?;
         let b = buf.read()?;
-
-        // im using a mask to get only the required bits ,and then I shift it to right most side.
-        self.query_res = ((a & 0x80) >> 7) > 0;
-        self.opcode = (a & 0x78) >> 3;
-        self.auth_ans = ((a & 0x4) >> 2) > 0;
-        self.trunc_msg = ((a & 0x2) >> 1) > 0;
-        self.rec_des = (a & 0x1) > 0;
-
-        self.rec_ava = ((b & 0x80) >> 7) > 0;
-        self.z = (b & 0x70) >> 4;
-        self.rcode = ResultCode::from_num(b & 0xF);
+        self.unpack_flags(a, b);
 
         self.qdcount = buf.read_u16()?;
         self.anscount = buf.read_u16()?;
@@ -236,30 +530,249 @@ impl DnsHeader {
 
         Ok(())
     }
+
+    pub fn write(&self, buf: &mut BytePacketBuffer) -> Result<()> {
+        buf.write_u16(self.id)?;
+
+        let [a, b] = self.flags.pack(self.rcode)?;
+        buf.write(a)?;
+        buf.write(b)?;
+
+        buf.write_u16(self.qdcount)?;
+        buf.write_u16(self.anscount)?;
+        buf.write_u16(self.nscount)?;
+        buf.write_u16(self.arcount)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn unpack_flags(&mut self, a: u8, b: u8) {
+        self.flags = HeaderFlags::unpack(a, b);
+        self.rcode = ResultCode::from_num(b & 0xF);
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
+    NS,
+    CNAME,
+    SOA,
+    NULL,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+    DNAME,
+    DS,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    NSEC3PARAM,
+    SVCB,
+    OPT,  // edns pseudo-record, only ever found in the additional section
+    TSIG, // transaction signature pseudo-record, last in the additional section
+    IXFR, // incremental zone transfer, only ever asked for
+    AXFR, // zone transfer, only ever asked for
 }
 
 impl QueryType {
-    fn from_num(num: u16) -> QueryType {
+    pub fn from_num(num: u16) -> QueryType {
         match num {
             1 => A,
+            2 => NS,
+            5 => CNAME,
+            6 => SOA,
+            10 => NULL,
+            12 => PTR,
+            15 => MX,
+            16 => TXT,
+            28 => AAAA,
+            33 => SRV,
+            39 => DNAME,
+            43 => DS,
+            46 => RRSIG,
+            47 => NSEC,
+            48 => DNSKEY,
+            50 => NSEC3,
+            51 => NSEC3PARAM,
+            64 => SVCB,
+            41 => OPT,
+            250 => TSIG,
+            251 => IXFR,
+            252 => AXFR,
             _ => UNKNOWN(num),
         }
     }
+
+    pub fn to_num(self) -> u16 {
+        match self {
+            UNKNOWN(num) => num,
+            A => 1,
+            NS => 2,
+            CNAME => 5,
+            SOA => 6,
+            NULL => 10,
+            PTR => 12,
+            MX => 15,
+            TXT => 16,
+            AAAA => 28,
+            SRV => 33,
+            DNAME => 39,
+            DS => 43,
+            RRSIG => 46,
+            NSEC => 47,
+            DNSKEY => 48,
+            NSEC3 => 50,
+            NSEC3PARAM => 51,
+            SVCB => 64,
+            OPT => 41,
+            TSIG => 250,
+            IXFR => 251,
+            AXFR => 252,
+        }
+    }
+}
+
+// mnemonics as used in zone files, unknown types use the generic TYPEnnn form from rfc 3597
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UNKNOWN(num) => write!(f, "TYPE{}", num),
+            A => write!(f, "A"),
+            NS => write!(f, "NS"),
+            CNAME => write!(f, "CNAME"),
+            SOA => write!(f, "SOA"),
+            NULL => write!(f, "NULL"),
+            PTR => write!(f, "PTR"),
+            MX => write!(f, "MX"),
+            TXT => write!(f, "TXT"),
+            AAAA => write!(f, "AAAA"),
+            SRV => write!(f, "SRV"),
+            DNAME => write!(f, "DNAME"),
+            DS => write!(f, "DS"),
+            RRSIG => write!(f, "RRSIG"),
+            NSEC => write!(f, "NSEC"),
+            DNSKEY => write!(f, "DNSKEY"),
+            NSEC3 => write!(f, "NSEC3"),
+            NSEC3PARAM => write!(f, "NSEC3PARAM"),
+            SVCB => write!(f, "SVCB"),
+            OPT => write!(f, "OPT"),
+            TSIG => write!(f, "TSIG"),
+            IXFR => write!(f, "IXFR"),
+            AXFR => write!(f, "AXFR"),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let upper = s.to_ascii_uppercase();
+        let qtype = match upper.as_str() {
+            "A" => A,
+            "NS" => NS,
+            "CNAME" => CNAME,
+            "SOA" => SOA,
+            "NULL" => NULL,
+            "PTR" => PTR,
+            "MX" => MX,
+            "TXT" => TXT,
+            "AAAA" => AAAA,
+            "SRV" => SRV,
+            "DNAME" => DNAME,
+            "DS" => DS,
+            "RRSIG" => RRSIG,
+            "NSEC" => NSEC,
+            "DNSKEY" => DNSKEY,
+            "NSEC3" => NSEC3,
+            "NSEC3PARAM" => NSEC3PARAM,
+            "SVCB" => SVCB,
+            "OPT" => OPT,
+            "TSIG" => TSIG,
+            "IXFR" => IXFR,
+            "AXFR" => AXFR,
+            _ => match upper.strip_prefix("TYPE").map(str::parse::<u16>) {
+                Some(Ok(num)) => QueryType::from_num(num),
+                _ => return Err(DnsError::UnsupportedType(s.to_string())),
+            },
+        };
+        Ok(qtype)
+    }
+}
+
+// same idea for classes, only IN, CH and HS have mnemonics
+pub(crate) struct ClassName(pub u16);
+
+impl fmt::Display for ClassName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            1 => write!(f, "IN"),
+            3 => write!(f, "CH"),
+            4 => write!(f, "HS"),
+            n => write!(f, "CLASS{}", n),
+        }
+    }
+}
+
+/// Whether `name` is `parent` or below it, comparing whole labels and ignoring case.
+pub fn is_subdomain(name: &str, parent: &str) -> bool {
+    labels_below(name, parent).is_some()
+}
+
+// the labels of `name` in front of `parent`, without the dot between them, if `name` is
+// `parent` or below it. compared as bytes from the end, names read off the wire can have
+// multi-byte characters where `parent` has a label boundary
+fn labels_below<'a>(name: &'a str, parent: &str) -> Option<&'a str> {
+    let name = name.trim_end_matches('.');
+    let parent = parent.trim_end_matches('.');
+    if parent.is_empty() {
+        return Some(name);
+    }
+    let split = name.len().checked_sub(parent.len())?;
+    let (head, tail) = name.as_bytes().split_at(split);
+    if !tail.eq_ignore_ascii_case(parent.as_bytes()) {
+        return None;
+    }
+    match head {
+        [] => Some(""),
+        [.., b'.'] => name.get(..split - 1),
+        _ => None,
+    }
+}
+
+// names are stored without the trailing dot, zone files want them fully qualified
+pub(crate) struct Fqdn<'a>(pub &'a str);
+
+impl fmt::Display for Fqdn<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, ".")
+        } else {
+            write!(f, "{}.", self.0)
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
     pub class: u16,
 }
 
+impl Default for DnsQuestion {
+    fn default() -> Self {
+        DnsQuestion::new()
+    }
+}
+
 impl DnsQuestion {
     pub fn new() -> Self {
         Self {
@@ -276,24 +789,205 @@ impl DnsQuestion {
 
         Ok(())
     }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_qname(&self.name)?;
+        buffer.write_u16(self.qtype.to_num())?;
+        buffer.write_u16(self.class)?;
+
+        Ok(())
+    }
+}
+
+// the question section as dig prints it, commented out since it isn't a record
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            ";{} {} {}",
+            Fqdn(&self.name),
+            ClassName(self.class),
+            self.qtype
+        )
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: QueryType,
         class: u16,
         ttl: u32,
-        len: u16,
+        data: Vec<u8>,
     },
     A {
         domain: String,
         class: u16,
         ttl: u32,
-        len: u16,
         ip: u32,
     },
+    NS {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        host: String,
+    },
+    CNAME {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        host: String,
+    },
+    SOA {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        mname: String, // primary name server
+        rname: String, // mailbox of the person responsible, with the @ replaced by a dot
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32, // ttl for negative answers
+    },
+    // anything goes in the rdata, up to 65535 bytes. obsolete, but still seen in tests and
+    // in tunneling tools
+    NULL {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        data: Vec<u8>,
+    },
+    PTR {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        host: String,
+    },
+    MX {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        priority: u16,
+        host: String,
+    },
+    TXT {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        data: Vec<String>, // each entry is one length-prefixed character-string
+    },
+    AAAA {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        ip: u128,
+    },
+    SRV {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+    },
+    // redirects everything below the owner (but not the owner itself) to the same names
+    // under host, rfc 6672
+    DNAME {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        host: String,
+    },
+    // a digest of one of a child zone's keys, held by the parent to link the two. rfc 4034
+    // section 5
+    DS {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    // a signature over all the records of one type at one name, rfc 4034 section 3
+    RRSIG {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8, // labels of the owner as signed, fewer than it has if it's from a wildcard
+        original_ttl: u32,
+        expiration: u32, // seconds since the epoch, modulo 2^32
+        inception: u32,
+        key_tag: u16,
+        signer: String,
+        signature: Vec<u8>,
+    },
+    // no names exist between the owner and next in the zone's canonical order, and the owner
+    // only has the listed types. rfc 4034 section 4
+    NSEC {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        next: String,
+        types: Vec<QueryType>,
+    },
+    // a public key of the zone, rfc 4034 section 2
+    DNSKEY {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        flags: u16, // 256 for a zone key, 257 if it's also a key signing key
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    // NSEC between hashed owner names, so the zone can't be listed by walking the chain.
+    // rfc 5155
+    NSEC3 {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        hash_algorithm: u8,
+        flags: u8, // the opt-out bit
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+    },
+    // the hash parameters of the zone's NSEC3 chain, at its apex
+    NSEC3PARAM {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+    },
+    // where and how to reach a service, rfc 9460. a priority of 0 makes it an alias for
+    // target, and a target of the root means the owner itself. params are kept in wire
+    // form, see svcb.rs
+    SVCB {
+        domain: String,
+        class: u16,
+        ttl: u32,
+        priority: u16,
+        target: String,
+        params: Vec<(u16, Vec<u8>)>,
+    },
+    // the owner of an OPT record is always the root, and the class and ttl fields are reused
+    // for the requestor's udp payload size and the extended rcode/version/flags
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        data: Vec<u8>,
+    },
 }
 
 impl DnsRecord {
@@ -302,68 +996,1473 @@ impl DnsRecord {
 
         let qtype = QueryType::from_num(buf.read_u16()?);
         let class = buf.read_u16()?;
-        let ttl = (buf.read_u16()? << 8) as u32 | buf.read_u16()? as u32;
+        let ttl = buf.read_u32()?;
         let len = buf.read_u16()?;
 
-        match qtype {
-            QueryType::A => Ok(DnsRecord::A {
+        // the rdata length isn't kept on the record since it depends on how names were
+        // compressed, but it tells us exactly where the next record starts
+        let end = buf.pos() + len as usize;
+
+        let record = match qtype {
+            QueryType::A => DnsRecord::A {
                 domain,
                 class,
                 ttl,
-                len,
-                ip: (buf.read_u16()? as u32) << 16 | buf.read_u16()? as u32,
-            }),
-            _ => Ok(DnsRecord::UNKNOWN {
+                ip: buf.read_u32()?,
+            },
+            QueryType::NS => DnsRecord::NS {
                 domain,
-                qtype,
                 class,
                 ttl,
-                len,
-            }),
-        }
-    }
-}
-
-#[derive(Clone, Debug)]
-pub struct DnsPacket {
-    pub header: DnsHeader,
-    pub questions: Vec<DnsQuestion>,
-    pub answers: Vec<DnsRecord>,
-    pub authorities: Vec<DnsRecord>,
-    pub additional: Vec<DnsRecord>,
-}
-
-impl DnsPacket {
-    fn new() -> Self {
-        Self {
-            header: DnsHeader::new(),
-            questions: vec![],
-            answers: vec![],
-            authorities: vec![],
-            additional: vec![],
-        }
-    }
-
-    pub fn from_buf(buf: &mut BytePacketBuffer) -> Result<Self> {
-        let mut res = DnsPacket::new();
-        res.header.read(buf)?;
-
-        for _ in 0..res.header.qdcount {
-            let mut qn = DnsQuestion::new();
-            qn.read(buf)?;
-            res.questions.push(qn)
-        }
-
-        for _ in 0..res.header.anscount {
-            res.answers.push(DnsRecord::from(buf)?)
-        }
-        for _ in 0..res.header.nscount {
+                host: buf.read_qname()?,
+            },
+            QueryType::CNAME => DnsRecord::CNAME {
+                domain,
+                class,
+                ttl,
+                host: buf.read_qname()?,
+            },
+            QueryType::SOA => DnsRecord::SOA {
+                domain,
+                class,
+                ttl,
+                mname: buf.read_qname()?,
+                rname: buf.read_qname()?,
+                serial: buf.read_u32()?,
+                refresh: buf.read_u32()?,
+                retry: buf.read_u32()?,
+                expire: buf.read_u32()?,
+                minimum: buf.read_u32()?,
+            },
+            QueryType::NULL => DnsRecord::NULL {
+                domain,
+                class,
+                ttl,
+                data: buf.get_range(buf.pos(), len as usize)?.to_vec(),
+            },
+            QueryType::PTR => DnsRecord::PTR {
+                domain,
+                class,
+                ttl,
+                host: buf.read_qname()?,
+            },
+            QueryType::MX => DnsRecord::MX {
+                domain,
+                class,
+                ttl,
+                priority: buf.read_u16()?,
+                host: buf.read_qname()?,
+            },
+            QueryType::TXT => {
+                let mut data = Vec::new();
+                while buf.pos() < end {
+                    let len = buf.read()? as usize;
+                    let bytes = buf.get_range(buf.pos(), len)?;
+                    data.push(String::from_utf8_lossy(bytes).into_owned());
+                    buf.step(len)?;
+                }
+                DnsRecord::TXT {
+                    domain,
+                    class,
+                    ttl,
+                    data,
+                }
+            }
+            QueryType::AAAA => DnsRecord::AAAA {
+                domain,
+                class,
+                ttl,
+                ip: ((buf.read_u32()? as u128) << 96)
+                    | ((buf.read_u32()? as u128) << 64)
+                    | ((buf.read_u32()? as u128) << 32)
+                    | (buf.read_u32()? as u128),
+            },
+            QueryType::SRV => DnsRecord::SRV {
+                domain,
+                class,
+                ttl,
+                priority: buf.read_u16()?,
+                weight: buf.read_u16()?,
+                port: buf.read_u16()?,
+                host: buf.read_qname()?,
+            },
+            QueryType::DNAME => DnsRecord::DNAME {
+                domain,
+                class,
+                ttl,
+                host: buf.read_qname()?,
+            },
+            QueryType::DS => DnsRecord::DS {
+                domain,
+                class,
+                ttl,
+                key_tag: buf.read_u16()?,
+                algorithm: buf.read()?,
+                digest_type: buf.read()?,
+                digest: buf.read_to(end)?,
+            },
+            QueryType::RRSIG => DnsRecord::RRSIG {
+                domain,
+                class,
+                ttl,
+                type_covered: QueryType::from_num(buf.read_u16()?),
+                algorithm: buf.read()?,
+                labels: buf.read()?,
+                original_ttl: buf.read_u32()?,
+                expiration: buf.read_u32()?,
+                inception: buf.read_u32()?,
+                key_tag: buf.read_u16()?,
+                signer: buf.read_qname()?,
+                signature: buf.read_to(end)?,
+            },
+            QueryType::NSEC => DnsRecord::NSEC {
+                domain,
+                class,
+                ttl,
+                next: buf.read_qname()?,
+                types: buf.read_type_bitmap(end)?,
+            },
+            QueryType::DNSKEY => DnsRecord::DNSKEY {
+                domain,
+                class,
+                ttl,
+                flags: buf.read_u16()?,
+                protocol: buf.read()?,
+                algorithm: buf.read()?,
+                public_key: buf.read_to(end)?,
+            },
+            QueryType::NSEC3 => DnsRecord::NSEC3 {
+                domain,
+                class,
+                ttl,
+                hash_algorithm: buf.read()?,
+                flags: buf.read()?,
+                iterations: buf.read_u16()?,
+                salt: buf.read_counted()?,
+                next_hashed: buf.read_counted()?,
+                types: buf.read_type_bitmap(end)?,
+            },
+            QueryType::NSEC3PARAM => DnsRecord::NSEC3PARAM {
+                domain,
+                class,
+                ttl,
+                hash_algorithm: buf.read()?,
+                flags: buf.read()?,
+                iterations: buf.read_u16()?,
+                salt: buf.read_counted()?,
+            },
+            QueryType::SVCB => DnsRecord::SVCB {
+                domain,
+                class,
+                ttl,
+                priority: buf.read_u16()?,
+                target: buf.read_qname()?,
+                params: svcb::decode(&buf.read_to(end)?)?,
+            },
+            QueryType::OPT => DnsRecord::OPT {
+                packet_len: class,
+                flags: ttl,
+                data: buf.get_range(buf.pos(), len as usize)?.to_vec(),
+            },
+            QueryType::UNKNOWN(_) | QueryType::TSIG | QueryType::IXFR | QueryType::AXFR => {
+                DnsRecord::UNKNOWN {
+                    domain,
+                    qtype,
+                    class,
+                    ttl,
+                    data: buf.get_range(buf.pos(), len as usize)?.to_vec(),
+                }
+            }
+        };
+
+        if buf.pos() > end {
+            return Err(DnsError::InvalidRdata(format!(
+                "{} rdata overruns its length",
+                qtype
+            )));
+        }
+        buf.seek(end)?;
+
+        Ok(record)
+    }
+
+    /// For a DNAME, the CNAME that redirects `qname` (a name strictly below the DNAME's
+    /// owner) into the DNAME target, as in rfc 6672 section 3.3. None if this isn't a DNAME or
+    /// `qname` isn't below it. Errors if the rewritten name would be too long, which should be
+    /// answered with YXDOMAIN.
+    pub fn synthesize_cname(&self, qname: &str) -> Result<Option<DnsRecord>> {
+        let DnsRecord::DNAME {
+            domain,
+            class,
+            ttl,
+            host,
+        } = self
+        else {
+            return Ok(None);
+        };
+        let Some(prefix) = labels_below(qname, domain).filter(|prefix| !prefix.is_empty()) else {
+            return Ok(None);
+        };
+        let target = if host.is_empty() {
+            prefix.to_string()
+        } else {
+            format!("{}.{}", prefix, host)
+        };
+        if target.len() > 253 {
+            return Err(DnsError::NameTooLong(target));
+        }
+
+        Ok(Some(DnsRecord::CNAME {
+            domain: qname.to_string(),
+            class: *class,
+            ttl: *ttl,
+            host: target,
+        }))
+    }
+
+    /// A record of any type with the given rdata, written out as is. Nothing checks that the
+    /// data makes sense for the type, which is the point when crafting test packets.
+    pub fn raw(domain: &str, qtype: QueryType, class: u16, ttl: u32, data: Vec<u8>) -> Self {
+        DnsRecord::UNKNOWN {
+            domain: domain.trim_end_matches('.').to_lowercase(),
+            qtype,
+            class,
+            ttl,
+            data,
+        }
+    }
+
+    /// The record's rdata as it goes on the wire (without name compression).
+    pub fn rdata(&self) -> Result<Vec<u8>> {
+        let mut buf = BytePacketBuffer::new();
+        self.write(&mut buf)?;
+
+        // walk back over what was just written: owner, type, class, ttl and rdata length
+        buf.seek(0)?;
+        buf.read_qname()?;
+        buf.step(8)?;
+        let len = buf.read_u16()? as usize;
+        Ok(buf.get_range(buf.pos(), len)?.to_vec())
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::NULL { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::DNAME { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::NSEC3PARAM { domain, .. }
+            | DnsRecord::SVCB { domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    /// Changes the owner name, e.g. when synthesizing records from a wildcard. Does nothing
+    /// for OPT records, their owner is always the root.
+    pub fn set_domain(&mut self, name: &str) {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::NULL { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::DNAME { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::NSEC3PARAM { domain, .. }
+            | DnsRecord::SVCB { domain, .. } => *domain = name.trim_end_matches('.').to_lowercase(),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => *qtype,
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::NULL { .. } => QueryType::NULL,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::DNAME { .. } => QueryType::DNAME,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    /// The record's class. OPT records reuse the field for the udp payload size and return
+    /// that.
+    pub fn class(&self) -> u16 {
+        self.class_and_ttl().0
+    }
+
+    /// The address of an A or AAAA record, None for other types.
+    pub fn address(&self) -> Option<IpAddr> {
+        match self {
+            DnsRecord::A { ip, .. } => Some(IpAddr::V4((*ip).into())),
+            DnsRecord::AAAA { ip, .. } => Some(IpAddr::V6((*ip).into())),
+            _ => None,
+        }
+    }
+
+    /// The record's TTL. OPT records have none and return 0.
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::OPT { .. } => 0,
+            _ => self.class_and_ttl().1,
+        }
+    }
+
+    /// Sets the TTL, e.g. to count down the time a record spent in a cache. Does nothing for
+    /// OPT records.
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::NULL { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// Sets the class, e.g. to mark a multicast DNS record for cache flushing. Does nothing
+    /// for OPT records.
+    pub fn set_class(&mut self, new_class: u16) {
+        match self {
+            DnsRecord::UNKNOWN { class, .. }
+            | DnsRecord::A { class, .. }
+            | DnsRecord::NS { class, .. }
+            | DnsRecord::CNAME { class, .. }
+            | DnsRecord::SOA { class, .. }
+            | DnsRecord::NULL { class, .. }
+            | DnsRecord::PTR { class, .. }
+            | DnsRecord::MX { class, .. }
+            | DnsRecord::TXT { class, .. }
+            | DnsRecord::AAAA { class, .. }
+            | DnsRecord::SRV { class, .. }
+            | DnsRecord::DNAME { class, .. }
+            | DnsRecord::DS { class, .. }
+            | DnsRecord::RRSIG { class, .. }
+            | DnsRecord::NSEC { class, .. }
+            | DnsRecord::DNSKEY { class, .. }
+            | DnsRecord::NSEC3 { class, .. }
+            | DnsRecord::NSEC3PARAM { class, .. }
+            | DnsRecord::SVCB { class, .. } => *class = new_class,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    // for OPT these are the payload size and the extended flags, like on the wire
+    fn class_and_ttl(&self) -> (u16, u32) {
+        match *self {
+            DnsRecord::UNKNOWN { class, ttl, .. }
+            | DnsRecord::A { class, ttl, .. }
+            | DnsRecord::NS { class, ttl, .. }
+            | DnsRecord::CNAME { class, ttl, .. }
+            | DnsRecord::SOA { class, ttl, .. }
+            | DnsRecord::NULL { class, ttl, .. }
+            | DnsRecord::PTR { class, ttl, .. }
+            | DnsRecord::MX { class, ttl, .. }
+            | DnsRecord::TXT { class, ttl, .. }
+            | DnsRecord::AAAA { class, ttl, .. }
+            | DnsRecord::SRV { class, ttl, .. }
+            | DnsRecord::DNAME { class, ttl, .. }
+            | DnsRecord::DS { class, ttl, .. }
+            | DnsRecord::RRSIG { class, ttl, .. }
+            | DnsRecord::NSEC { class, ttl, .. }
+            | DnsRecord::DNSKEY { class, ttl, .. }
+            | DnsRecord::NSEC3 { class, ttl, .. }
+            | DnsRecord::NSEC3PARAM { class, ttl, .. }
+            | DnsRecord::SVCB { class, ttl, .. } => (class, ttl),
+            DnsRecord::OPT {
+                packet_len, flags, ..
+            } => (packet_len, flags),
+        }
+    }
+
+    pub fn write(&self, buf: &mut BytePacketBuffer) -> Result<usize> {
+        let start_pos = buf.pos();
+        let (class, ttl) = self.class_and_ttl();
+
+        buf.write_qname(self.domain())?;
+        buf.write_u16(self.qtype().to_num())?;
+        buf.write_u16(class)?;
+        buf.write_u32(ttl)?;
+
+        // the rdata length isn't known until the rdata is written, so reserve it and fill it
+        // in afterwards
+        let len_pos = buf.pos();
+        buf.write_u16(0)?;
+
+        match self {
+            DnsRecord::UNKNOWN { data, .. }
+            | DnsRecord::NULL { data, .. }
+            | DnsRecord::OPT { data, .. } => {
+                buf.write_bytes(data)?;
+            }
+            DnsRecord::A { ip, .. } => {
+                buf.write_u32(*ip)?;
+            }
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. }
+            | DnsRecord::DNAME { host, .. } => {
+                buf.write_qname(host)?;
+            }
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => {
+                buf.write_qname(mname)?;
+                buf.write_qname(rname)?;
+                buf.write_u32(*serial)?;
+                buf.write_u32(*refresh)?;
+                buf.write_u32(*retry)?;
+                buf.write_u32(*expire)?;
+                buf.write_u32(*minimum)?;
+            }
+            DnsRecord::MX { priority, host, .. } => {
+                buf.write_u16(*priority)?;
+                buf.write_qname(host)?;
+            }
+            DnsRecord::TXT { data, .. } => {
+                for s in data {
+                    if s.len() > 255 {
+                        return Err(DnsError::InvalidRdata(
+                            "TXT strings can be at most 255 bytes".to_string(),
+                        ));
+                    }
+                    buf.write(s.len() as u8)?;
+                    buf.write_bytes(s.as_bytes())?;
+                }
+            }
+            DnsRecord::AAAA { ip, .. } => {
+                buf.write_bytes(&ip.to_be_bytes())?;
+            }
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                host,
+                ..
+            } => {
+                buf.write_u16(*priority)?;
+                buf.write_u16(*weight)?;
+                buf.write_u16(*port)?;
+                buf.write_qname(host)?;
+            }
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => {
+                buf.write_u16(*key_tag)?;
+                buf.write(*algorithm)?;
+                buf.write(*digest_type)?;
+                buf.write_bytes(digest)?;
+            }
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } => {
+                buf.write_u16(type_covered.to_num())?;
+                buf.write(*algorithm)?;
+                buf.write(*labels)?;
+                buf.write_u32(*original_ttl)?;
+                buf.write_u32(*expiration)?;
+                buf.write_u32(*inception)?;
+                buf.write_u16(*key_tag)?;
+                buf.write_qname(signer)?;
+                buf.write_bytes(signature)?;
+            }
+            DnsRecord::NSEC { next, types, .. } => {
+                buf.write_qname(next)?;
+                buf.write_type_bitmap(types)?;
+            }
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => {
+                buf.write_u16(*flags)?;
+                buf.write(*protocol)?;
+                buf.write(*algorithm)?;
+                buf.write_bytes(public_key)?;
+            }
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } => {
+                buf.write(*hash_algorithm)?;
+                buf.write(*flags)?;
+                buf.write_u16(*iterations)?;
+                buf.write_counted(salt)?;
+                buf.write_counted(next_hashed)?;
+                buf.write_type_bitmap(types)?;
+            }
+            DnsRecord::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                ..
+            } => {
+                buf.write(*hash_algorithm)?;
+                buf.write(*flags)?;
+                buf.write_u16(*iterations)?;
+                buf.write_counted(salt)?;
+            }
+            DnsRecord::SVCB {
+                priority,
+                target,
+                params,
+                ..
+            } => {
+                buf.write_u16(*priority)?;
+                buf.write_qname(target)?;
+                buf.write_bytes(&svcb::encode(params)?)?;
+            }
+        }
+
+        let len = buf.pos() - (len_pos + 2);
+        buf.set_u16(len_pos, len as u16)?;
+
+        Ok(buf.pos() - start_pos)
+    }
+}
+
+// rdata we don't understand is printed in the generic \# form from rfc 3597
+pub(crate) struct GenericRdata<'a>(pub &'a [u8]);
+
+impl fmt::Display for GenericRdata<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\\# {}", self.0.len())?;
+        if !self.0.is_empty() {
+            write!(f, " ")?;
+            for b in self.0 {
+                write!(f, "{:02X}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// digests in DS records, and NSEC3 salts
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+// an empty salt is written as a dash, rfc 5155 section 3.3
+struct Salt<'a>(&'a [u8]);
+
+impl fmt::Display for Salt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "-")
+        } else {
+            write!(f, "{}", Hex(self.0))
+        }
+    }
+}
+
+// RRSIG validity times as YYYYMMDDHHmmSS in UTC, rfc 4034 section 3.2
+pub(crate) struct SignatureTime(pub(crate) u32);
+
+impl fmt::Display for SignatureTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0 as i64;
+        let (year, month, day) = civil_from_days(secs / 86400);
+        let rem = secs % 86400;
+        write!(
+            f,
+            "{:04}{:02}{:02}{:02}{:02}{:02}",
+            year,
+            month,
+            day,
+            rem / 3600,
+            rem / 60 % 60,
+            rem % 60
+        )
+    }
+}
+
+// txt data is quoted, with quotes, backslashes and anything unprintable escaped
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for b in self.0.bytes() {
+            match b {
+                b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+                0x20..=0x7e => write!(f, "{}", b as char)?,
+                _ => write!(f, "\\{:03}", b)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+// master file syntax, e.g. "example.com. 300 IN A 1.2.3.4"
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsRecord::UNKNOWN {
+                domain,
+                qtype,
+                class,
+                ttl,
+                data,
+            } => write!(
+                f,
+                "{} {} {} {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                qtype,
+                GenericRdata(data)
+            ),
+            DnsRecord::A {
+                domain,
+                class,
+                ttl,
+                ip,
+            } => write!(
+                f,
+                "{} {} {} A {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                Ipv4Addr::from(*ip)
+            ),
+            DnsRecord::NS {
+                domain,
+                class,
+                ttl,
+                host,
+            } => write!(
+                f,
+                "{} {} {} NS {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                Fqdn(host)
+            ),
+            DnsRecord::CNAME {
+                domain,
+                class,
+                ttl,
+                host,
+            } => write!(
+                f,
+                "{} {} {} CNAME {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                Fqdn(host)
+            ),
+            DnsRecord::SOA {
+                domain,
+                class,
+                ttl,
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} SOA {} {} {} {} {} {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                Fqdn(mname),
+                Fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            // NULL has no presentation format of its own, rfc 3597 says to use the generic one
+            DnsRecord::NULL {
+                domain,
+                class,
+                ttl,
+                data,
+            } => write!(
+                f,
+                "{} {} {} NULL {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                GenericRdata(data)
+            ),
+            DnsRecord::PTR {
+                domain,
+                class,
+                ttl,
+                host,
+            } => write!(
+                f,
+                "{} {} {} PTR {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                Fqdn(host)
+            ),
+            DnsRecord::MX {
+                domain,
+                class,
+                ttl,
+                priority,
+                host,
+            } => write!(
+                f,
+                "{} {} {} MX {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                priority,
+                Fqdn(host)
+            ),
+            DnsRecord::TXT {
+                domain,
+                class,
+                ttl,
+                data,
+            } => {
+                write!(f, "{} {} {} TXT", Fqdn(domain), ttl, ClassName(*class))?;
+                for s in data {
+                    write!(f, " {}", Quoted(s))?;
+                }
+                Ok(())
+            }
+            DnsRecord::AAAA {
+                domain,
+                class,
+                ttl,
+                ip,
+            } => write!(
+                f,
+                "{} {} {} AAAA {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                Ipv6Addr::from(*ip)
+            ),
+            DnsRecord::SRV {
+                domain,
+                class,
+                ttl,
+                priority,
+                weight,
+                port,
+                host,
+            } => write!(
+                f,
+                "{} {} {} SRV {} {} {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                priority,
+                weight,
+                port,
+                Fqdn(host)
+            ),
+            DnsRecord::DNAME {
+                domain,
+                class,
+                ttl,
+                host,
+            } => write!(
+                f,
+                "{} {} {} DNAME {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                Fqdn(host)
+            ),
+            DnsRecord::DS {
+                domain,
+                class,
+                ttl,
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => write!(
+                f,
+                "{} {} {} DS {} {} {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                key_tag,
+                algorithm,
+                digest_type,
+                Hex(digest)
+            ),
+            DnsRecord::RRSIG {
+                domain,
+                class,
+                ttl,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => write!(
+                f,
+                "{} {} {} RRSIG {} {} {} {} {} {} {} {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                SignatureTime(*expiration),
+                SignatureTime(*inception),
+                key_tag,
+                Fqdn(signer),
+                presentation::base64(signature)
+            ),
+            DnsRecord::NSEC {
+                domain,
+                class,
+                ttl,
+                next,
+                types,
+            } => {
+                write!(
+                    f,
+                    "{} {} {} NSEC {}",
+                    Fqdn(domain),
+                    ttl,
+                    ClassName(*class),
+                    Fqdn(next)
+                )?;
+                for qtype in types {
+                    write!(f, " {}", qtype)?;
+                }
+                Ok(())
+            }
+            DnsRecord::DNSKEY {
+                domain,
+                class,
+                ttl,
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => write!(
+                f,
+                "{} {} {} DNSKEY {} {} {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                flags,
+                protocol,
+                algorithm,
+                presentation::base64(public_key)
+            ),
+            DnsRecord::NSEC3 {
+                domain,
+                class,
+                ttl,
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+            } => {
+                write!(
+                    f,
+                    "{} {} {} NSEC3 {} {} {} {} {}",
+                    Fqdn(domain),
+                    ttl,
+                    ClassName(*class),
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    Salt(salt),
+                    presentation::base32hex(next_hashed)
+                )?;
+                for qtype in types {
+                    write!(f, " {}", qtype)?;
+                }
+                Ok(())
+            }
+            DnsRecord::NSEC3PARAM {
+                domain,
+                class,
+                ttl,
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+            } => write!(
+                f,
+                "{} {} {} NSEC3PARAM {} {} {} {}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                hash_algorithm,
+                flags,
+                iterations,
+                Salt(salt)
+            ),
+            DnsRecord::SVCB {
+                domain,
+                class,
+                ttl,
+                priority,
+                target,
+                params,
+            } => write!(
+                f,
+                "{} {} {} SVCB {} {}{}",
+                Fqdn(domain),
+                ttl,
+                ClassName(*class),
+                priority,
+                Fqdn(target),
+                svcb::Params(params)
+            ),
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                data,
+            } => write!(
+                f,
+                ". {} CLASS{} OPT {}",
+                flags,
+                packet_len,
+                GenericRdata(data)
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DnsPacket {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub additional: Vec<DnsRecord>,
+}
+
+impl DnsPacket {
+    pub(crate) fn new() -> Self {
+        Self {
+            header: DnsHeader::new(),
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additional: vec![],
+        }
+    }
+
+    pub fn from_buf(buf: &mut BytePacketBuffer) -> Result<Self> {
+        let mut res = DnsPacket::new();
+        res.header.read(buf)?;
+
+        for _ in 0..res.header.qdcount {
+            let mut qn = DnsQuestion::new();
+            qn.read(buf)?;
+            res.questions.push(qn)
+        }
+
+        for _ in 0..res.header.anscount {
             res.answers.push(DnsRecord::from(buf)?)
         }
+        for _ in 0..res.header.nscount {
+            res.authorities.push(DnsRecord::from(buf)?)
+        }
         for _ in 0..res.header.arcount {
-            res.answers.push(DnsRecord::from(buf)?)
+            res.additional.push(DnsRecord::from(buf)?)
         }
 
         Ok(res)
     }
+
+    /// Parses the `len` byte message at the start of `buf`. Unlike [`DnsPacket::from_buf`],
+    /// which can't tell where the message ends, a message whose sections run past `len` or
+    /// end before it is an error.
+    pub fn from_message(buf: &mut BytePacketBuffer, len: usize) -> Result<Self> {
+        // the buffer may be longer than the message, reading past it means it was cut short
+        buf.buf.truncate(len);
+        let packet = Self::from_buf(buf)?;
+        match buf.pos {
+            pos if pos > len => Err(DnsError::BufferOverrun),
+            pos if pos < len => Err(DnsError::TrailingBytes(len - pos)),
+            _ => Ok(packet),
+        }
+    }
+
+    /// Writes the packet without exceeding `max_size` bytes, e.g. the payload size the client
+    /// negotiated for udp. Records that don't fit are dropped whole from the tail. Losing
+    /// answers or authorities sets TC so the client retries over tcp, losing additional
+    /// records doesn't since they're optional. The OPT record is always kept. Returns whether
+    /// the message was truncated.
+    pub fn write_truncated(&mut self, buf: &mut BytePacketBuffer, max_size: usize) -> Result<bool> {
+        let start = buf.pos();
+        let max_size = max_size.min(MAX_MESSAGE_SIZE.saturating_sub(start));
+
+        // room for the OPT record is set aside before anything else is written
+        let opt = self
+            .additional
+            .iter()
+            .find(|rec| matches!(rec, DnsRecord::OPT { .. }));
+        let opt_len = match opt {
+            // owner, then type, class, ttl and rdata length, then the options
+            Some(opt @ DnsRecord::OPT { data, .. }) => qname_len(opt.domain()) + 10 + data.len(),
+            _ => 0,
+        };
+        let Some(limit) = max_size.checked_sub(opt_len) else {
+            return Err(DnsError::MessageTooLarge(format!(
+                "OPT record doesn't fit in {} bytes",
+                max_size
+            )));
+        };
+
+        let mut header = self.header.clone();
+        header.write(buf)?;
+
+        for question in &self.questions {
+            question.write(buf)?;
+        }
+        if buf.pos() - start > limit {
+            return Err(DnsError::MessageTooLarge(format!(
+                "question section doesn't fit in {} bytes",
+                max_size
+            )));
+        }
+
+        let mut counts = [0u16; 3];
+        let mut truncated = false;
+        let sections = [&self.answers, &self.authorities, &self.additional];
+        'sections: for (i, section) in sections.into_iter().enumerate() {
+            for rec in section {
+                if let DnsRecord::OPT { .. } = rec {
+                    continue;
+                }
+                let before = buf.pos();
+                if rec.write(buf).is_err() || buf.pos() - start > limit {
+                    buf.seek(before)?;
+                    truncated = i < 2;
+                    break 'sections;
+                }
+                counts[i] += 1;
+            }
+        }
+
+        if let Some(opt) = opt {
+            opt.write(buf)?;
+            counts[2] += 1;
+        }
+
+        header.flags.truncated = header.flags.truncated || truncated;
+        header.qdcount = self.questions.len() as u16;
+        header.anscount = counts[0];
+        header.nscount = counts[1];
+        header.arcount = counts[2];
+
+        let end = buf.pos();
+        buf.seek(start)?;
+        header.write(buf)?;
+        buf.seek(end)?;
+
+        self.header.flags.truncated = header.flags.truncated;
+        Ok(truncated)
+    }
+
+    /// Starts building a query, see [`QueryBuilder`].
+    pub fn query(name: &str, qtype: QueryType) -> QueryBuilder {
+        QueryBuilder::new(name, qtype)
+    }
+
+    /// An empty response to `request`: same id, opcode and question, QR set, and RD and CD
+    /// echoed back. RA is left unset, servers that recurse turn it on with
+    /// [`DnsPacket::set_recursion_available`].
+    pub fn response_to(request: &DnsPacket) -> Self {
+        let mut res = DnsPacket::new();
+        res.header.id = request.header.id;
+        res.header.flags.response = true;
+        res.header.flags.opcode = request.header.flags.opcode;
+        res.header.flags.recursion_desired = request.header.flags.recursion_desired;
+        // AD is ours to set
+        res.header.flags.checking_disabled = request.header.flags.checking_disabled;
+        res.questions = request.questions.clone();
+        res
+    }
+
+    /// A FORMERR response for a request that couldn't be parsed past its header. Only the id,
+    /// opcode and RD bit are echoed, there is no question to copy.
+    pub fn format_error(request: &DnsHeader) -> Self {
+        let mut res = DnsPacket::new();
+        res.header.id = request.id;
+        res.header.flags.response = true;
+        res.header.flags.opcode = request.flags.opcode;
+        res.header.flags.recursion_desired = request.flags.recursion_desired;
+        res.header.rcode = ResultCode::FORMERR;
+        res
+    }
+
+    pub fn set_recursion_available(&mut self, ra: bool) -> &mut Self {
+        self.header.flags.recursion_available = ra;
+        self
+    }
+
+    pub fn set_authoritative(&mut self, aa: bool) -> &mut Self {
+        self.header.flags.authoritative = aa;
+        self
+    }
+
+    pub fn set_rcode(&mut self, rcode: ResultCode) -> &mut Self {
+        self.header.rcode = rcode;
+        self
+    }
+
+    pub fn add_answer(&mut self, rec: DnsRecord) -> &mut Self {
+        self.answers.push(rec);
+        self
+    }
+
+    pub fn add_authority(&mut self, rec: DnsRecord) -> &mut Self {
+        self.authorities.push(rec);
+        self
+    }
+
+    pub fn add_additional(&mut self, rec: DnsRecord) -> &mut Self {
+        self.additional.push(rec);
+        self
+    }
+
+    /// Size of the packet once serialized.
+    pub fn wire_len(&mut self) -> Result<usize> {
+        let mut buf = BytePacketBuffer::new();
+        self.write(&mut buf)?;
+        Ok(buf.pos())
+    }
+
+    // the counts in the header are taken from the sections rather than trusted as they are
+    pub fn write(&mut self, buf: &mut BytePacketBuffer) -> Result<()> {
+        self.header.qdcount = self.questions.len() as u16;
+        self.header.anscount = self.answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+        self.header.arcount = self.additional.len() as u16;
+
+        self.header.write(buf)?;
+
+        for question in &self.questions {
+            question.write(buf)?;
+        }
+        for rec in &self.answers {
+            rec.write(buf)?;
+        }
+        for rec in &self.authorities {
+            rec.write(buf)?;
+        }
+        for rec in &self.additional {
+            rec.write(buf)?;
+        }
+
+        Ok(())
+    }
+}
+
+// the length of a name written by write_qname: a length byte per label and the root label
+fn qname_len(qname: &str) -> usize {
+    match qname.trim_end_matches('.') {
+        "" => 1,
+        qname => qname.len() + 2,
+    }
+}
+
+/// The largest UDP message without EDNS (rfc 1035 section 4.2.1), rfc 6891 also has smaller
+/// advertised sizes treated as this.
+pub const MIN_UDP_PAYLOAD: u16 = 512;
+
+/// The UDP payload size we advertise and accept by default. 1232 bytes fits in the minimum ipv6
+/// MTU with room for headers, so responses of that size don't get fragmented (DNS Flag Day
+/// 2020).
+pub const DEFAULT_EDNS_PAYLOAD: u16 = 1232;
+
+/// Builds a query with sensible defaults: a random id, recursion desired, and an OPT record
+/// advertising our payload size.
+///
+/// ```ignore
+/// let buf = DnsPacket::query("example.com", QueryType::A).into_buffer()?;
+/// socket.send_to(buf.as_slice(), upstream)?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct QueryBuilder {
+    id: u16,
+    question: DnsQuestion,
+    recursion_desired: bool,
+    edns_payload: Option<u16>,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+}
+
+impl QueryBuilder {
+    pub fn new(name: &str, qtype: QueryType) -> Self {
+        Self {
+            id: rand::random(),
+            question: DnsQuestion {
+                name: name.trim_end_matches('.').to_lowercase(),
+                qtype,
+                class: 1,
+            },
+            recursion_desired: true,
+            edns_payload: Some(DEFAULT_EDNS_PAYLOAD),
+            dnssec_ok: false,
+            checking_disabled: false,
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn class(mut self, class: u16) -> Self {
+        self.question.class = class;
+        self
+    }
+
+    pub fn recursion_desired(mut self, rd: bool) -> Self {
+        self.recursion_desired = rd;
+        self
+    }
+
+    /// Advertise a udp payload size in an OPT record, or pass None to send a plain query.
+    pub fn edns(mut self, payload: Option<u16>) -> Self {
+        self.edns_payload = payload;
+        self
+    }
+
+    /// Sets the DO bit, asking for DNSSEC records. Implies edns.
+    pub fn dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    /// Sets the CD bit, asking a validating resolver to hand over data it couldn't validate.
+    pub fn checking_disabled(mut self, cd: bool) -> Self {
+        self.checking_disabled = cd;
+        self
+    }
+
+    pub fn build(self) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = self.id;
+        packet.header.flags.recursion_desired = self.recursion_desired;
+        packet.header.flags.checking_disabled = self.checking_disabled;
+        packet.questions.push(self.question);
+
+        let payload = match (self.edns_payload, self.dnssec_ok) {
+            (Some(payload), _) => Some(payload),
+            (None, true) => Some(DEFAULT_EDNS_PAYLOAD),
+            (None, false) => None,
+        };
+        if let Some(payload) = payload {
+            packet.additional.push(DnsRecord::OPT {
+                packet_len: payload,
+                flags: if self.dnssec_ok { 0x8000 } else { 0 },
+                data: vec![],
+            });
+        }
+
+        packet
+    }
+
+    /// Builds the query and serializes it, ready to be sent.
+    pub fn into_buffer(self) -> Result<BytePacketBuffer> {
+        let mut buf = BytePacketBuffer::new();
+        self.build().write(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_end_where_their_sections_do() {
+        let buf = DnsPacket::query("example.com", QueryType::A)
+            .into_buffer()
+            .unwrap();
+        let mut bytes = buf.as_slice().to_vec();
+        let len = bytes.len();
+        bytes.extend_from_slice(&[0; 3]);
+        // read from a buffer longer than the message, as when receiving a datagram
+        let read = |len| DnsPacket::from_message(&mut BytePacketBuffer::from_bytes(&bytes)?, len);
+
+        assert!(read(len).is_ok());
+        assert!(matches!(read(len + 3), Err(DnsError::TrailingBytes(3))));
+        assert!(matches!(read(len - 1), Err(DnsError::BufferOverrun)));
+
+        assert!(crate::borrowed::DnsPacketRef::from_bytes(&bytes[..len]).is_ok());
+        assert!(matches!(
+            crate::borrowed::DnsPacketRef::from_bytes(&bytes[..len + 1]),
+            Err(DnsError::TrailingBytes(1))
+        ));
+    }
+
+    // a label that isn't utf-8 is read as U+FFFD, three bytes where the wire had one
+    #[test]
+    fn compares_names_with_labels_that_arent_utf8() {
+        let message = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+            \x01\xff\x07in-addr\x04arpa\x00\x00\x0c\x00\x01";
+        let mut buf = BytePacketBuffer::from_bytes(message).unwrap();
+        let packet = DnsPacket::from_message(&mut buf, message.len()).unwrap();
+        let name = &packet.questions[0].name;
+        assert_eq!(name, "\u{fffd}.in-addr.arpa");
+
+        assert!(is_subdomain(name, "in-addr.arpa"));
+        assert!(!is_subdomain(name, "10.in-addr.arpa"));
+        assert!(!is_subdomain(name, "\u{fffd}\u{fffd}.in-addr.arpa"));
+        assert!(crate::empty_zones::EmptyZones::default()
+            .answer(&packet)
+            .is_none());
+
+        let dname = |domain: &str| DnsRecord::DNAME {
+            domain: domain.to_string(),
+            class: 1,
+            ttl: 300,
+            host: "example.net".to_string(),
+        };
+        assert!(dname("10.in-addr.arpa")
+            .synthesize_cname(name)
+            .unwrap()
+            .is_none());
+        let cname = dname("in-addr.arpa").synthesize_cname(name).unwrap();
+        assert!(matches!(
+            cname,
+            Some(DnsRecord::CNAME { host, .. }) if host == "\u{fffd}.example.net"
+        ));
+    }
+    #[test]
+    fn truncating_sets_aside_exactly_the_opt_record() {
+        let mut packet = DnsPacket::query("example.com", QueryType::A).build();
+        packet.additional[0] = DnsRecord::OPT {
+            packet_len: 1232,
+            flags: 0,
+            data: vec![0, 12, 0, 7, 0, 0, 0, 0, 0, 0, 0],
+        };
+        for i in 0..4 {
+            packet.answers.push(DnsRecord::A {
+                domain: "example.com".to_string(),
+                class: 1,
+                ttl: 300,
+                ip: u32::from(Ipv4Addr::new(192, 0, 2, i)),
+            });
+        }
+        let full = packet.wire_len().unwrap();
+
+        // room for everything, to the byte
+        let mut buf = BytePacketBuffer::new();
+        assert!(!packet.write_truncated(&mut buf, full).unwrap());
+        assert_eq!(buf.pos, full);
+        // a byte short loses the last answer, but never the OPT record
+        let mut buf = BytePacketBuffer::new();
+        assert!(packet.write_truncated(&mut buf, full - 1).unwrap());
+        let len = buf.pos;
+        let written = DnsPacket::from_message(
+            &mut BytePacketBuffer::from_bytes(buf.as_slice()).unwrap(),
+            len,
+        )
+        .unwrap();
+        assert_eq!(written.answers.len(), 3);
+        assert_eq!(written.additional, packet.additional);
+    }
 }
@@ -0,0 +1,287 @@
+// service binding parameters for SVCB records (rfc 9460). on the wire they follow the
+// priority and target as key, length and value, with keys in increasing order and none
+// twice. the records keep them in that form and this converts to and from the master file
+// syntax, e.g. `alpn=h2,http/1.1 port=443 dohpath=/dns-query{?dns}`, with keys we have no
+// name for written as keyNNNNN. values are unescaped when parsed and escaped with \DDD when
+// printed, so a printed record reads back the same.
+use crate::error::{DnsError, Result};
+use crate::presentation::unescape_bytes;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Keys that have to be understood for the record to be used, section 8.
+pub const MANDATORY: u16 = 0;
+/// Application protocols in ALPN form, section 7.1.
+pub const ALPN: u16 = 1;
+/// The protocol's default ALPN value is not supported, section 7.1.
+pub const NO_DEFAULT_ALPN: u16 = 2;
+/// The port to connect to, section 7.2.
+pub const PORT: u16 = 3;
+/// Addresses of the target, section 7.3.
+pub const IPV4HINT: u16 = 4;
+/// Encrypted client hello configuration, rfc 9460 section 14.3.1.
+pub const ECH: u16 = 5;
+/// Addresses of the target, section 7.3.
+pub const IPV6HINT: u16 = 6;
+/// The URI template of a DNS over HTTPS endpoint, rfc 9461 section 5.
+pub const DOHPATH: u16 = 7;
+
+/// A parameter for protocols given by their ALPN ids, e.g. `h2` or `doq`.
+pub fn alpn(protocols: &[&str]) -> (u16, Vec<u8>) {
+    let mut value = Vec::new();
+    for protocol in protocols {
+        value.push(protocol.len() as u8);
+        value.extend_from_slice(protocol.as_bytes());
+    }
+    (ALPN, value)
+}
+
+pub fn port(port: u16) -> (u16, Vec<u8>) {
+    (PORT, port.to_be_bytes().to_vec())
+}
+
+pub fn ipv4hint(addrs: &[Ipv4Addr]) -> (u16, Vec<u8>) {
+    (IPV4HINT, addrs.iter().flat_map(|ip| ip.octets()).collect())
+}
+
+pub fn ipv6hint(addrs: &[Ipv6Addr]) -> (u16, Vec<u8>) {
+    (IPV6HINT, addrs.iter().flat_map(|ip| ip.octets()).collect())
+}
+
+/// A parameter for a DNS over HTTPS path template, e.g. `/dns-query{?dns}`.
+pub fn dohpath(template: &str) -> (u16, Vec<u8>) {
+    (DOHPATH, template.as_bytes().to_vec())
+}
+
+fn key_name(key: u16) -> Option<&'static str> {
+    Some(match key {
+        MANDATORY => "mandatory",
+        ALPN => "alpn",
+        NO_DEFAULT_ALPN => "no-default-alpn",
+        PORT => "port",
+        IPV4HINT => "ipv4hint",
+        ECH => "ech",
+        IPV6HINT => "ipv6hint",
+        DOHPATH => "dohpath",
+        _ => return None,
+    })
+}
+
+fn parse_key(name: &str) -> Result<u16> {
+    let lower = name.to_ascii_lowercase();
+    match (0..=DOHPATH).find(|&key| key_name(key) == Some(lower.as_str())) {
+        Some(key) => Ok(key),
+        None => match lower.strip_prefix("key").map(str::parse::<u16>) {
+            Some(Ok(key)) => Ok(key),
+            _ => Err(DnsError::Syntax(format!(
+                "unknown SVCB parameter {:?}",
+                name
+            ))),
+        },
+    }
+}
+
+/// Splits the parameters off the end of SVCB rdata.
+pub(crate) fn decode(mut data: &[u8]) -> Result<Vec<(u16, Vec<u8>)>> {
+    let mut params: Vec<(u16, Vec<u8>)> = Vec::new();
+    while !data.is_empty() {
+        let Some((head, rest)) = data.split_first_chunk::<4>() else {
+            return Err(DnsError::InvalidRdata("truncated SVCB parameter".into()));
+        };
+        let key = u16::from_be_bytes([head[0], head[1]]);
+        let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+        if rest.len() < len {
+            return Err(DnsError::InvalidRdata("truncated SVCB parameter".into()));
+        }
+        if params.last().is_some_and(|&(last, _)| last >= key) {
+            return Err(DnsError::InvalidRdata(
+                "SVCB parameters out of order".into(),
+            ));
+        }
+        params.push((key, rest[..len].to_vec()));
+        data = &rest[len..];
+    }
+    Ok(params)
+}
+
+/// The parameters as they go on the wire after the target.
+pub(crate) fn encode(params: &[(u16, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut last = None;
+    for (key, value) in params {
+        if last.is_some_and(|last| last >= *key) {
+            return Err(DnsError::InvalidRdata(
+                "SVCB parameters have to be in increasing order".into(),
+            ));
+        }
+        let len = u16::try_from(value.len())
+            .map_err(|_| DnsError::InvalidRdata("SVCB parameter is too long".into()))?;
+        out.extend_from_slice(&key.to_be_bytes());
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(value);
+        last = Some(*key);
+    }
+    Ok(out)
+}
+
+/// Parses parameters in master file syntax, one `key=value` or bare `key` per field, and
+/// sorts them into wire order.
+pub(crate) fn parse(fields: &[&str]) -> Result<Vec<(u16, Vec<u8>)>> {
+    let mut params = Vec::with_capacity(fields.len());
+    for field in fields {
+        let (name, text) = match field.split_once('=') {
+            Some((name, text)) => (name, Some(text)),
+            None => (*field, None),
+        };
+        let key = parse_key(name)?;
+        let value = match (key, text) {
+            (NO_DEFAULT_ALPN, None) => Vec::new(),
+            (NO_DEFAULT_ALPN, Some(_)) => {
+                return Err(DnsError::Syntax("no-default-alpn takes no value".into()))
+            }
+            (_, None) => return Err(DnsError::Syntax(format!("{} needs a value", name))),
+            (MANDATORY, Some(text)) => {
+                let mut value = Vec::new();
+                for name in text.split(',') {
+                    value.extend_from_slice(&parse_key(name)?.to_be_bytes());
+                }
+                value
+            }
+            (ALPN, Some(text)) => {
+                let mut value = Vec::new();
+                for id in split_list(text) {
+                    let id = unescape_bytes(id)?;
+                    if id.is_empty() || id.len() > 255 {
+                        return Err(DnsError::Syntax(format!("invalid alpn {:?}", text)));
+                    }
+                    value.push(id.len() as u8);
+                    value.extend_from_slice(&id);
+                }
+                value
+            }
+            (PORT, Some(text)) => text.parse::<u16>()?.to_be_bytes().to_vec(),
+            (IPV4HINT, Some(text)) => {
+                let addrs = text
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<std::result::Result<Vec<Ipv4Addr>, _>>()?;
+                ipv4hint(&addrs).1
+            }
+            (IPV6HINT, Some(text)) => {
+                let addrs = text
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<std::result::Result<Vec<Ipv6Addr>, _>>()?;
+                ipv6hint(&addrs).1
+            }
+            (_, Some(text)) => unescape_bytes(text)?,
+        };
+        params.push((key, value));
+    }
+    params.sort_by_key(|&(key, _)| key);
+    if params.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return Err(DnsError::Syntax("SVCB parameter given twice".into()));
+    }
+    Ok(params)
+}
+
+// commas separate list items unless escaped, rfc 9460 appendix a.1
+fn split_list(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut start, mut escaped) = (0, false);
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => {
+                items.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&text[start..]);
+    items
+}
+
+/// Prints parameters in master file syntax, each preceded by a space.
+pub(crate) struct Params<'a>(pub &'a [(u16, Vec<u8>)]);
+
+impl fmt::Display for Params<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in self.0 {
+            match key_name(*key) {
+                Some(name) => write!(f, " {}", name)?,
+                None => write!(f, " key{}", key)?,
+            }
+            if *key == NO_DEFAULT_ALPN && value.is_empty() {
+                continue;
+            }
+            write!(f, "=")?;
+            match *key {
+                MANDATORY if value.len() % 2 == 0 => {
+                    for (i, pair) in value.chunks(2).enumerate() {
+                        let key = u16::from_be_bytes([pair[0], pair[1]]);
+                        let sep = if i == 0 { "" } else { "," };
+                        match key_name(key) {
+                            Some(name) => write!(f, "{}{}", sep, name)?,
+                            None => write!(f, "{}key{}", sep, key)?,
+                        }
+                    }
+                }
+                ALPN => {
+                    let mut rest = value.as_slice();
+                    let mut first = true;
+                    while let Some((&len, tail)) = rest.split_first() {
+                        let id = &tail[..(len as usize).min(tail.len())];
+                        if !first {
+                            write!(f, ",")?;
+                        }
+                        // a comma inside an id is escaped so it doesn't split the list
+                        for &b in id {
+                            match b {
+                                b',' => write!(f, "\\,")?,
+                                _ => write!(f, "{}", Escaped(&[b]))?,
+                            }
+                        }
+                        rest = &tail[id.len()..];
+                        first = false;
+                    }
+                }
+                PORT if value.len() == 2 => {
+                    write!(f, "{}", u16::from_be_bytes([value[0], value[1]]))?
+                }
+                IPV4HINT if value.len() % 4 == 0 => {
+                    for (i, ip) in value.chunks(4).enumerate() {
+                        let ip = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+                        write!(f, "{}{}", if i == 0 { "" } else { "," }, ip)?;
+                    }
+                }
+                IPV6HINT if value.len() % 16 == 0 => {
+                    for (i, ip) in value.chunks(16).enumerate() {
+                        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap());
+                        write!(f, "{}{}", if i == 0 { "" } else { "," }, ip)?;
+                    }
+                }
+                _ => write!(f, "{}", Escaped(value))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// a value without quotes, so anything that would end the field is escaped
+struct Escaped<'a>(&'a [u8]);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &b in self.0 {
+            match b {
+                b'"' | b'\\' | b';' | b'(' | b')' => write!(f, "\\{}", b as char)?,
+                0x21..=0x7e => write!(f, "{}", b as char)?,
+                _ => write!(f, "\\{:03}", b)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+// running under systemd: taking the sockets it bound for us (socket activation, see
+// sd_listen_fds(3)) and telling it how we're doing (sd_notify(3)). both go through the
+// environment variables systemd sets, the way libsystemd does it, so nothing here needs
+// libsystemd and all of it is a no-op when systemd didn't start us. with socket activation a
+// unit can have port 53 bound for it and run the server with no privileges at all, and a
+// Type=notify-reload unit knows when the server is ready, reloading, stopping and still alive.
+use std::env;
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{self, UnixDatagram};
+use std::time::Duration;
+
+// where the passed descriptors start, after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets systemd passed in, for the listeners to take by their address.
+#[derive(Default)]
+pub struct Activated {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+}
+
+impl Activated {
+    /// Takes the sockets passed to this process, none if it wasn't socket activated. The
+    /// variables that say which they are are unset, so nothing started from here takes them
+    /// for its own. Has to be called before there are other threads, which could be reading
+    /// the environment meanwhile.
+    pub fn from_env() -> io::Result<Self> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(var);
+        }
+        let mut activated = Self::default();
+        let (Some(pid), Some(fds)) = (pid, fds) else {
+            return Ok(activated);
+        };
+        // the variables were meant for whoever systemd started, which isn't us
+        if pid.parse() != Ok(std::process::id()) {
+            return Ok(activated);
+        }
+        let Ok(fds) = fds.parse::<RawFd>() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("LISTEN_FDS={} isn't a number", fds),
+            ));
+        };
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let kind = socket_type(fd)?;
+            let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+            match kind {
+                libc::SOCK_DGRAM => activated.udp.push(UdpSocket::from(socket)),
+                libc::SOCK_STREAM => activated.tcp.push(TcpListener::from(socket)),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("descriptor {} isn't a datagram or stream socket", fd),
+                    ))
+                }
+            }
+        }
+        Ok(activated)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.udp.is_empty() && self.tcp.is_empty()
+    }
+
+    /// The UDP socket bound to `addr`, or without one the first that's left.
+    pub fn udp(&mut self, addr: Option<SocketAddr>) -> Option<UdpSocket> {
+        let i = self
+            .udp
+            .iter()
+            .position(|socket| addr.is_none() || socket.local_addr().ok() == addr)?;
+        Some(self.udp.remove(i))
+    }
+
+    /// The TCP listener bound to `addr`, or without one the first that's left.
+    pub fn tcp(&mut self, addr: Option<SocketAddr>) -> Option<TcpListener> {
+        let i = self
+            .tcp
+            .iter()
+            .position(|listener| addr.is_none() || listener.local_addr().ok() == addr)?;
+        Some(self.tcp.remove(i))
+    }
+
+    /// The addresses of the sockets nobody took, for telling about them.
+    pub fn left(&self) -> Vec<io::Result<SocketAddr>> {
+        let udp = self.udp.iter().map(UdpSocket::local_addr);
+        udp.chain(self.tcp.iter().map(TcpListener::local_addr))
+            .collect()
+    }
+}
+
+fn socket_type(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            (&mut kind as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(kind),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Tells systemd `state`, newline separated assignments like `READY=1` or `STOPPING=1`.
+/// Returns false without doing anything if systemd isn't listening, i.e. the unit isn't
+/// Type=notify or the server wasn't started by systemd.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes() {
+        [b'@', name @ ..] => {
+            let addr = net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?
+        }
+        [b'/', ..] => socket.send_to(state.as_bytes(), &path)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't send to NOTIFY_SOCKET={:?}", path),
+            ))
+        }
+    };
+    Ok(true)
+}
+
+/// The state that says a reload has started. Type=notify-reload units need the time along
+/// with it, and READY=1 once the reload is done, whether it worked or not.
+pub fn reloading() -> String {
+    let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1000;
+    format!("RELOADING=1\nMONOTONIC_USEC={}", usec)
+}
+
+/// How often systemd wants to hear WATCHDOG=1 before it thinks the server hung, None if the
+/// unit has no WatchdogSec. Sending it every half of this is what sd_watchdog_enabled(3)
+/// suggests.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match env::var("WATCHDOG_PID") {
+        Ok(pid) if pid.parse() != Ok(std::process::id()) => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
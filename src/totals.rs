@@ -0,0 +1,156 @@
+// the counters that go on counting across restarts: queries, blocked queries and what the
+// caches did, summed over every view and every cache a reload built. they're saved to a file
+// every so often and when the server stops, and what the file has is added back when it
+// starts, so graphs of them don't drop to zero with every restart. the file is a line per
+// counter, its name and value separated by a space, so it's easy to read and edit, and names
+// it doesn't know are left out when it's read. a crash loses what was counted since the last
+// save, nothing more.
+use crate::error::{DnsError, Result};
+use crate::json::Value;
+use crate::logging;
+use crate::metrics::{CacheCounters, QueryCounters};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+pub const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The counters kept across restarts, shared with what counts into them.
+#[derive(Debug, Default)]
+pub struct Totals {
+    pub queries: Arc<QueryCounters>,
+    pub cache: Arc<CacheCounters>,
+}
+
+impl Totals {
+    // every counter by the name it's saved under
+    fn counters(&self) -> [(&'static str, &AtomicU64); 8] {
+        [
+            ("queries", &self.queries.queries),
+            ("blocked", &self.queries.blocked),
+            ("cache-hits", &self.cache.hits),
+            ("cache-misses", &self.cache.misses),
+            ("cache-expired", &self.cache.expired),
+            ("cache-evictions", &self.cache.evictions),
+            ("cache-prefetches", &self.cache.prefetches),
+            ("cache-coalesced", &self.cache.coalesced),
+        ]
+    }
+
+    /// Every counter by its name, for the control socket.
+    pub fn to_json(&self) -> Value {
+        Value::object(
+            self.counters()
+                .map(|(name, counter)| (name, counter.load(Ordering::Relaxed).into())),
+        )
+    }
+
+    /// Writes the counters to a temporary file next to `path`, `path` with ".tmp" added, and
+    /// moves it into place, so a crash halfway through leaves the last save intact.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        for (name, counter) in self.counters() {
+            let _ = writeln!(text, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        // added rather than put in place of an extension, "counters.1" and "counters.2"
+        // mustn't share one
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        // the rename is only durable once the directory it's in is
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Adds the counters saved at `path` to these.
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<()> {
+        let text = fs::read_to_string(path)?;
+        let mut saved = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let value = line
+                .split_once(' ')
+                .and_then(|(name, value)| Some((name, value.trim().parse::<u64>().ok()?)));
+            match value {
+                Some(value) => saved.push(value),
+                None => {
+                    return Err(DnsError::Syntax(format!(
+                        "line {}: expected a counter and its value, got {:?}",
+                        i + 1,
+                        line
+                    )))
+                }
+            }
+        }
+        // nothing's added unless the whole file is good
+        for (name, value) in saved {
+            if let Some((_, counter)) = self.counters().into_iter().find(|(n, _)| *n == name) {
+                counter.fetch_add(value, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the counters to `path` every `interval`. Runs until the future is dropped,
+    /// failures are reported and retried the next time.
+    pub async fn run(&self, path: &Path, interval: Duration) {
+        let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = self.save(path) {
+                logging::warning(&format!("saving {} failed: {}", path.display(), e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_the_saved_counters() {
+        let path = std::env::temp_dir().join(format!("totals-{}.txt", std::process::id()));
+        let totals = Totals::default();
+        totals.queries.queries.store(10, Ordering::Relaxed);
+        totals.cache.hits.store(4, Ordering::Relaxed);
+        // a file that only differs in its extension is left alone
+        let neighbour = path.with_extension("tmp");
+        fs::write(&neighbour, "keep").unwrap();
+        totals.save(&path).unwrap();
+        assert_eq!(fs::read_to_string(&neighbour).unwrap(), "keep");
+        fs::remove_file(&neighbour).unwrap();
+
+        let restarted = Totals::default();
+        restarted.queries.queries.store(1, Ordering::Relaxed);
+        restarted.load(&path).unwrap();
+        let json = restarted.to_json();
+        assert_eq!(json.get("queries"), Some(&Value::Number(11)));
+        assert_eq!(json.get("cache-hits"), Some(&Value::Number(4)));
+        assert_eq!(json.get("blocked"), Some(&Value::Number(0)));
+
+        fs::write(&path, "queries 5\nsomething-new 3\n\n").unwrap();
+        restarted.load(&path).unwrap();
+        assert_eq!(restarted.queries.queries.load(Ordering::Relaxed), 16);
+        fs::write(&path, "queries 5\nblocked many\n").unwrap();
+        assert!(restarted.load(&path).is_err());
+        assert_eq!(restarted.queries.queries.load(Ordering::Relaxed), 16);
+        fs::remove_file(&path).unwrap();
+    }
+}
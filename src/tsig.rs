@@ -0,0 +1,1087 @@
+// transaction signatures, rfc 8945. a message is signed with a secret shared between the two
+// ends by appending a TSIG record holding an HMAC over the message. a response's MAC also
+// covers the request's, and in a series of messages like a zone transfer each MAC covers the
+// one before, so nothing can be left out, reordered or replayed. the signing time is part of
+// the MAC and checked against the clock, give or take the fudge the signer allows.
+use crate::digest::{constant_time_eq, HmacSha256, SHA256_LEN};
+use crate::error::{DnsError, Result};
+use crate::presentation::parse_base64;
+use crate::structure::{BytePacketBuffer, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far off the signing time may be from our clock, in seconds. rfc 8945 recommends 300.
+pub const DEFAULT_FUDGE: u16 = 300;
+
+// TSIG records are in class ANY with a ttl of 0
+const CLASS_ANY: u16 = 255;
+
+// a receiver may accept up to 99 unsigned messages in a row between signed ones, rfc 8945
+// section 5.3.1
+const MAX_UNSIGNED: usize = 99;
+
+/// The MAC algorithms we can sign and verify with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    HmacSha256,
+}
+
+impl Algorithm {
+    /// The algorithm's name as it goes in the TSIG record.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256",
+        }
+    }
+
+    fn mac_len(self) -> usize {
+        match self {
+            Algorithm::HmacSha256 => SHA256_LEN,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Ok(Algorithm::HmacSha256),
+            _ => Err(DnsError::Syntax(format!(
+                "unsupported TSIG algorithm {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// TSIG error codes, rfc 8945 section 3. They go in the TSIG record of a NOTAUTH response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsigError {
+    /// The MAC didn't verify.
+    BadSig,
+    /// The key isn't known, or not with that algorithm.
+    BadKey,
+    /// The signing time is too far from the receiver's clock.
+    BadTime,
+    /// The MAC was truncated shorter than the receiver accepts.
+    BadTrunc,
+    Other(u16),
+}
+
+impl TsigError {
+    pub fn from_num(n: u16) -> Self {
+        match n {
+            16 => TsigError::BadSig,
+            17 => TsigError::BadKey,
+            18 => TsigError::BadTime,
+            22 => TsigError::BadTrunc,
+            _ => TsigError::Other(n),
+        }
+    }
+
+    pub fn to_num(self) -> u16 {
+        match self {
+            TsigError::BadSig => 16,
+            TsigError::BadKey => 17,
+            TsigError::BadTime => 18,
+            TsigError::BadTrunc => 22,
+            TsigError::Other(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsigError::BadSig => write!(f, "BADSIG"),
+            TsigError::BadKey => write!(f, "BADKEY"),
+            TsigError::BadTime => write!(f, "BADTIME"),
+            TsigError::BadTrunc => write!(f, "BADTRUNC"),
+            TsigError::Other(n) => write!(f, "TSIG error {}", n),
+        }
+    }
+}
+
+/// A shared secret, known to both ends by its name.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TsigKey {
+    name: String,
+    algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+// the secret stays out of logs
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: Algorithm, secret: Vec<u8>) -> Result<Self> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        encode_name(&name)?;
+        Ok(Self {
+            name,
+            algorithm,
+            secret,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// How many bytes signing a message with this key adds to it, so the message can be cut
+    /// short enough to leave room.
+    pub fn record_len(&self) -> usize {
+        // owner, type, class, ttl and rdlength, then time signed, fudge, mac size, original
+        // id, error and other len around the algorithm and MAC. the 6 bytes at the end are for
+        // the time a BADTIME error carries.
+        let name_len = self.name.len() + 2;
+        let algorithm_len = self.algorithm.name().len() + 2;
+        name_len + 10 + algorithm_len + 16 + self.algorithm.mac_len() + 6
+    }
+
+    fn hmac(&self) -> HmacSha256 {
+        match self.algorithm {
+            Algorithm::HmacSha256 => HmacSha256::new(&self.secret),
+        }
+    }
+}
+
+// keys are written like dig's -y option, [algorithm:]name:secret with the secret in base64
+impl FromStr for TsigKey {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.trim().split(':').collect();
+        let (algorithm, name, secret) = match parts[..] {
+            [name, secret] => (Algorithm::HmacSha256, name, secret),
+            [algorithm, name, secret] => (algorithm.parse()?, name, secret),
+            _ => {
+                return Err(DnsError::Syntax(format!(
+                    "expected [algorithm:]name:secret, got {:?}",
+                    s
+                )))
+            }
+        };
+        TsigKey::new(name, algorithm, parse_base64(secret)?)
+    }
+}
+
+/// The keys a server accepts signed messages with, by name.
+#[derive(Clone, Debug, Default)]
+pub struct Keyring {
+    keys: HashMap<String, TsigKey>,
+}
+
+impl FromIterator<TsigKey> for Keyring {
+    fn from_iter<I: IntoIterator<Item = TsigKey>>(keys: I) -> Self {
+        let mut keyring = Keyring::new();
+        for key in keys {
+            keyring.add(key);
+        }
+        keyring
+    }
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads keys from a file with one key per line as [`TsigKey`] parses them. Empty lines
+    /// and lines starting with `#` are skipped.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let mut keyring = Keyring::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = line.parse().map_err(|e| DnsError::ZoneFile {
+                file: path.display().to_string(),
+                line: i + 1,
+                error: Box::new(e),
+            })?;
+            keyring.add(key);
+        }
+        Ok(keyring)
+    }
+
+    /// Adds `key`, replacing any key of the same name.
+    pub fn add(&mut self, key: TsigKey) {
+        self.keys.insert(key.name.clone(), key);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TsigKey> {
+        self.keys
+            .get(name.trim_end_matches('.').to_lowercase().as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// The rdata of a TSIG record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tsig {
+    /// The name of the key, the owner of the record.
+    pub key_name: String,
+    pub algorithm: String,
+    /// Seconds since the epoch, 48 bits on the wire.
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    /// The message id at signing time, in case something in between changed it.
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+impl Tsig {
+    /// Reads a TSIG record, None if `rec` is some other type.
+    pub fn from_record(rec: &DnsRecord) -> Result<Option<Self>> {
+        let DnsRecord::UNKNOWN {
+            domain,
+            qtype: QueryType::TSIG,
+            data,
+            ..
+        } = rec
+        else {
+            return Ok(None);
+        };
+
+        let mut rdata = Rdata(data);
+        let algorithm = rdata.name()?;
+        let time = rdata.take(6)?;
+        let time_signed = time.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        let fudge = rdata.u16()?;
+        let mac_len = rdata.u16()? as usize;
+        let mac = rdata.take(mac_len)?.to_vec();
+        let original_id = rdata.u16()?;
+        let error = rdata.u16()?;
+        let other_len = rdata.u16()? as usize;
+        let other = rdata.take(other_len)?.to_vec();
+        if !rdata.0.is_empty() {
+            return Err(DnsError::InvalidRdata(format!(
+                "{} bytes after the TSIG rdata",
+                rdata.0.len()
+            )));
+        }
+        Ok(Some(Self {
+            key_name: domain.clone(),
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        }))
+    }
+
+    pub fn to_record(&self) -> Result<DnsRecord> {
+        let mut data = encode_name(&self.algorithm)?;
+        data.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        data.extend_from_slice(&self.fudge.to_be_bytes());
+        data.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.mac);
+        data.extend_from_slice(&self.original_id.to_be_bytes());
+        data.extend_from_slice(&self.error.to_be_bytes());
+        data.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.other);
+        Ok(DnsRecord::raw(
+            &self.key_name,
+            QueryType::TSIG,
+            CLASS_ANY,
+            0,
+            data,
+        ))
+    }
+
+    // the TSIG variables the first MAC of an exchange covers besides the message, rfc 8945
+    // section 4.3.3
+    fn variables(&self) -> Result<Vec<u8>> {
+        let mut vars = encode_name(&self.key_name)?;
+        vars.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        vars.extend_from_slice(&0u32.to_be_bytes());
+        vars.extend(encode_name(&self.algorithm.to_lowercase())?);
+        vars.extend(self.timers());
+        vars.extend_from_slice(&self.error.to_be_bytes());
+        vars.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        vars.extend_from_slice(&self.other);
+        Ok(vars)
+    }
+
+    // all that later messages of an exchange cover, section 5.3.1
+    fn timers(&self) -> Vec<u8> {
+        let mut timers = self.time_signed.to_be_bytes()[2..].to_vec();
+        timers.extend_from_slice(&self.fudge.to_be_bytes());
+        timers
+    }
+}
+
+/// The name of the key a message was signed with. A request carrying a TSIG record has had
+/// it verified by the server before a handler sees it.
+pub fn signer(packet: &DnsPacket) -> Option<&str> {
+    match packet.additional.last()? {
+        DnsRecord::UNKNOWN {
+            domain,
+            qtype: QueryType::TSIG,
+            ..
+        } => Some(domain),
+        _ => None,
+    }
+}
+
+/// One signed exchange, a request and its response or responses. Each side keeps one to sign
+/// its messages and verify the other's, since every MAC is chained to the one before it.
+pub struct Session {
+    key: Option<TsigKey>,
+    // what the request was signed with, echoed in responses even when we don't have the key
+    key_name: String,
+    algorithm: String,
+    // the MAC the next message's is chained to
+    prior_mac: Vec<u8>,
+    // messages after the request, signed or verified
+    responses: usize,
+    // when verifying, the MAC over the unsigned messages since the last signed one
+    running: Option<(HmacSha256, usize)>,
+    error: Option<TsigError>,
+}
+
+impl Session {
+    /// Signs the request in `buf`, which has to start at `start` and end at `buf.pos`, and
+    /// starts a session for its responses.
+    pub fn sign_request(key: &TsigKey, buf: &mut BytePacketBuffer, start: usize) -> Result<Self> {
+        let mut session = Session::new(key.clone());
+        session.sign_message(buf, start, true)?;
+        // the responses are the ones chained to the request
+        session.responses = 0;
+        Ok(session)
+    }
+
+    /// Checks the TSIG record of a request parsed from `msg` against `keys`. None if the
+    /// request isn't signed. A session with an [`error`] means the request has to be answered
+    /// with NOTAUTH, signed by the session, instead of being answered at all. Errors if the
+    /// TSIG record is malformed or misplaced, which is a FORMERR.
+    ///
+    /// [`error`]: Session::error
+    pub fn verify_request(keys: &Keyring, request: &DnsPacket, msg: &[u8]) -> Result<Option<Self>> {
+        let Some((offset, tsig)) = locate(request, msg)? else {
+            return Ok(None);
+        };
+
+        let key = keys.get(&tsig.key_name).filter(|key| {
+            key.algorithm
+                .name()
+                .eq_ignore_ascii_case(tsig.algorithm.trim_end_matches('.'))
+        });
+        let mut session = Session {
+            key: key.cloned(),
+            key_name: tsig.key_name.clone(),
+            algorithm: tsig.algorithm.clone(),
+            prior_mac: Vec::new(),
+            responses: 0,
+            running: None,
+            error: None,
+        };
+        let Some(key) = key else {
+            session.error = Some(TsigError::BadKey);
+            return Ok(Some(session));
+        };
+
+        match check_mac(key, &tsig, msg, offset, None)? {
+            Ok(()) => {}
+            Err(error) => {
+                session.error = Some(error);
+                return Ok(Some(session));
+            }
+        }
+        session.prior_mac = tsig.mac.clone();
+        if !within_fudge(&tsig) {
+            session.error = Some(TsigError::BadTime);
+        }
+        Ok(Some(session))
+    }
+
+    fn new(key: TsigKey) -> Self {
+        Self {
+            key_name: key.name.clone(),
+            algorithm: key.algorithm.name().to_string(),
+            key: Some(key),
+            prior_mac: Vec::new(),
+            responses: 0,
+            running: None,
+            error: None,
+        }
+    }
+
+    /// Why the request was turned down, if it was.
+    pub fn error(&self) -> Option<TsigError> {
+        self.error
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    /// How many bytes signing a response adds, see [`TsigKey::record_len`].
+    pub fn record_len(&self) -> usize {
+        match &self.key {
+            Some(key) => key.record_len(),
+            None => self.key_name.len() + 2 + 10 + self.algorithm.len() + 2 + 16,
+        }
+    }
+
+    /// Signs the next response, the message in `buf` from `start` to `buf.pos`. The first
+    /// response covers the request's MAC and every one after covers the response before it.
+    /// A request that failed verification gets a TSIG record with the error and, unless the
+    /// problem was the time, no MAC.
+    pub fn sign(&mut self, buf: &mut BytePacketBuffer, start: usize) -> Result<()> {
+        let first = self.responses == 0;
+        self.sign_message(buf, start, first)
+    }
+
+    fn sign_message(&mut self, buf: &mut BytePacketBuffer, start: usize, full: bool) -> Result<()> {
+        let msg = &buf.buf[start..buf.pos];
+        let mut tsig = Tsig {
+            key_name: self.key_name.clone(),
+            algorithm: self.algorithm.clone(),
+            time_signed: now(),
+            fudge: DEFAULT_FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([msg[0], msg[1]]),
+            error: self.error.map_or(0, TsigError::to_num),
+            other: Vec::new(),
+        };
+        if self.error == Some(TsigError::BadTime) {
+            // our clock, so the client can tell how far off it is
+            tsig.other = tsig.time_signed.to_be_bytes()[2..].to_vec();
+        }
+
+        let key = match (&self.key, self.error) {
+            (Some(key), None | Some(TsigError::BadTime)) => Some(key),
+            _ => None,
+        };
+        if let Some(key) = key {
+            let mut hmac = key.hmac();
+            if !self.prior_mac.is_empty() {
+                hmac.update(&(self.prior_mac.len() as u16).to_be_bytes());
+                hmac.update(&self.prior_mac);
+            }
+            hmac.update(msg);
+            match full {
+                true => hmac.update(&tsig.variables()?),
+                false => hmac.update(&tsig.timers()),
+            }
+            tsig.mac = hmac.finish().to_vec();
+            self.prior_mac = tsig.mac.clone();
+        }
+
+        let before = buf.pos;
+        let rec = tsig.to_record()?;
+        if rec.write(buf).is_err() {
+            buf.pos = before;
+            return Err(DnsError::MessageTooLarge(
+                "no room for the TSIG record".into(),
+            ));
+        }
+        let arcount = u16::from_be_bytes([buf.buf[start + 10], buf.buf[start + 11]]) + 1;
+        buf.buf[start + 10..start + 12].copy_from_slice(&arcount.to_be_bytes());
+        self.responses += 1;
+        Ok(())
+    }
+
+    /// Verifies the next response, parsed from `msg` into `response`, and takes its TSIG
+    /// record out. Returns whether it was signed: after the first response, up to 99 in a row
+    /// may be left unsigned, with the next signed one covering them. Whoever reads the last
+    /// response has to make sure it was signed.
+    pub fn verify(&mut self, response: &mut DnsPacket, msg: &[u8]) -> Result<bool> {
+        let Some(key) = self.key.clone() else {
+            return Err(DnsError::Tsig("no key to verify with".into()));
+        };
+        let first = self.responses == 0;
+        self.responses += 1;
+
+        let Some((offset, tsig)) = locate(response, msg)? else {
+            if first {
+                return Err(DnsError::Tsig("the response isn't signed".into()));
+            }
+            let (hmac, unsigned) = self.running.get_or_insert_with(|| {
+                let mut hmac = key.hmac();
+                hmac.update(&(self.prior_mac.len() as u16).to_be_bytes());
+                hmac.update(&self.prior_mac);
+                (hmac, 0)
+            });
+            if *unsigned == MAX_UNSIGNED {
+                return Err(DnsError::Tsig(format!(
+                    "more than {} unsigned messages in a row",
+                    MAX_UNSIGNED
+                )));
+            }
+            hmac.update(msg);
+            *unsigned += 1;
+            return Ok(false);
+        };
+
+        if tsig.key_name != self.key_name
+            || !tsig
+                .algorithm
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(&self.algorithm)
+        {
+            return Err(DnsError::Tsig(format!(
+                "signed with {} instead of {}",
+                tsig.key_name, self.key_name
+            )));
+        }
+        if tsig.error != 0 {
+            return Err(DnsError::Tsig(format!(
+                "the server answered {}",
+                TsigError::from_num(tsig.error)
+            )));
+        }
+        let running = self.running.take().map(|(hmac, _)| hmac);
+        if let Err(error) = check_mac(
+            &key,
+            &tsig,
+            msg,
+            offset,
+            Some((&self.prior_mac, first, running)),
+        )? {
+            return Err(DnsError::Tsig(format!(
+                "the response failed with {}",
+                error
+            )));
+        }
+        if !within_fudge(&tsig) {
+            return Err(DnsError::Tsig(format!(
+                "the response was signed at {}, too far from our clock",
+                tsig.time_signed
+            )));
+        }
+        self.prior_mac = tsig.mac;
+        response.additional.pop();
+        Ok(true)
+    }
+}
+
+// the TSIG record of a message and where it starts. it has to be the last record, and the
+// only one.
+fn locate(packet: &DnsPacket, msg: &[u8]) -> Result<Option<(usize, Tsig)>> {
+    let tsigs = packet
+        .additional
+        .iter()
+        .filter(|rec| rec.qtype() == QueryType::TSIG)
+        .count();
+    let Some(last) = packet.additional.last() else {
+        return Ok(None);
+    };
+    if tsigs == 0 {
+        return Ok(None);
+    }
+    let tsig = match Tsig::from_record(last)? {
+        Some(tsig) if tsigs == 1 => tsig,
+        _ => {
+            return Err(DnsError::InvalidRdata(
+                "TSIG has to be the last record of the message".into(),
+            ))
+        }
+    };
+
+    // walk the message up to the last record to find where it starts
+    let mut buf = BytePacketBuffer::from_bytes(msg)?;
+    let mut header = DnsHeader::new();
+    header.read(&mut buf)?;
+    for _ in 0..header.qdcount {
+        DnsQuestion::new().read(&mut buf)?;
+    }
+    let records = header.anscount as usize + header.nscount as usize + header.arcount as usize;
+    for _ in 0..records - 1 {
+        DnsRecord::from(&mut buf)?;
+    }
+    Ok(Some((buf.pos, tsig)))
+}
+
+// checks the MAC of a message whose TSIG record starts at `offset`. for a response, `chain`
+// has the MAC it's chained to, whether it's the first response, and the MAC over any unsigned
+// messages before it. the outer error is for a malformed record, the inner one the TSIG error
+// to answer with.
+fn check_mac(
+    key: &TsigKey,
+    tsig: &Tsig,
+    msg: &[u8],
+    offset: usize,
+    chain: Option<(&[u8], bool, Option<HmacSha256>)>,
+) -> Result<std::result::Result<(), TsigError>> {
+    let full_len = key.algorithm.mac_len();
+    if tsig.mac.len() > full_len {
+        return Err(DnsError::InvalidRdata(format!(
+            "{} byte MAC for {}",
+            tsig.mac.len(),
+            key.algorithm
+        )));
+    }
+    // truncated MACs (section 5.2.2.1) aren't accepted, only full ones
+    if tsig.mac.len() < full_len {
+        return Ok(Err(TsigError::BadTrunc));
+    }
+
+    let (mut hmac, full) = match chain {
+        None => (key.hmac(), true),
+        Some((_, first, Some(running))) => (running, first),
+        Some((prior, first, None)) => {
+            let mut hmac = key.hmac();
+            hmac.update(&(prior.len() as u16).to_be_bytes());
+            hmac.update(prior);
+            (hmac, first)
+        }
+    };
+    // the message as it was before the TSIG record was added, with the original id
+    let mut header = [0u8; 12];
+    header.copy_from_slice(&msg[..12]);
+    header[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    let arcount = u16::from_be_bytes([header[10], header[11]]) - 1;
+    header[10..12].copy_from_slice(&arcount.to_be_bytes());
+    hmac.update(&header);
+    hmac.update(&msg[12..offset]);
+    match full {
+        true => hmac.update(&tsig.variables()?),
+        false => hmac.update(&tsig.timers()),
+    }
+
+    match constant_time_eq(&hmac.finish(), &tsig.mac) {
+        true => Ok(Ok(())),
+        false => Ok(Err(TsigError::BadSig)),
+    }
+}
+
+fn within_fudge(tsig: &Tsig) -> bool {
+    now().abs_diff(tsig.time_signed) <= tsig.fudge as u64
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// names in TSIG rdata and variables are never compressed and always lowercase
+fn encode_name(name: &str) -> Result<Vec<u8>> {
+    let name = name.trim_end_matches('.');
+    if name.len() > 253 {
+        return Err(DnsError::NameTooLong(name.to_string()));
+    }
+    let mut out = Vec::with_capacity(name.len() + 2);
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() {
+                return Err(DnsError::EmptyLabel(name.to_string()));
+            }
+            if label.len() > 0x3f {
+                return Err(DnsError::LabelTooLong);
+            }
+            out.push(label.len() as u8);
+            out.extend(label.bytes().map(|b| b.to_ascii_lowercase()));
+        }
+    }
+    out.push(0);
+    Ok(out)
+}
+
+// reads rdata front to back
+struct Rdata<'a>(&'a [u8]);
+
+impl<'a> Rdata<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(DnsError::InvalidRdata("TSIG rdata is cut short".into()));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let mut labels = Vec::new();
+        loop {
+            let len = self.take(1)?[0] as usize;
+            if len == 0 {
+                return Ok(labels.join("."));
+            }
+            if len > 0x3f {
+                return Err(DnsError::InvalidRdata(
+                    "compressed name in TSIG rdata".into(),
+                ));
+            }
+            labels.push(String::from_utf8_lossy(self.take(len)?).to_lowercase());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presentation::parse_hex;
+
+    fn key() -> TsigKey {
+        TsigKey::new(
+            "test.key",
+            Algorithm::HmacSha256,
+            b"a shared secret".to_vec(),
+        )
+        .unwrap()
+    }
+
+    fn keyring() -> Keyring {
+        [key()].into_iter().collect()
+    }
+
+    fn parse(msg: &[u8]) -> DnsPacket {
+        DnsPacket::from_buf(&mut BytePacketBuffer::from_bytes(msg).unwrap()).unwrap()
+    }
+
+    fn query() -> DnsPacket {
+        DnsPacket::query("example.com", QueryType::A)
+            .edns(None)
+            .build()
+    }
+
+    fn unsigned() -> Vec<u8> {
+        let mut buf = BytePacketBuffer::new();
+        query().write(&mut buf).unwrap();
+        buf.as_slice().to_vec()
+    }
+
+    // a response to `request` with an answer of its own, so the messages of a transfer differ
+    fn response(request: &[u8], ip: u32) -> BytePacketBuffer {
+        let mut response = DnsPacket::response_to(&parse(request));
+        response.answers.push(DnsRecord::A {
+            domain: "example.com".into(),
+            class: 1,
+            ttl: 300,
+            ip,
+        });
+        let mut buf = BytePacketBuffer::new();
+        response.write(&mut buf).unwrap();
+        buf
+    }
+
+    // a signed request, with the sessions both ends keep for the responses
+    fn exchange() -> (Vec<u8>, Session, Session) {
+        let mut buf = BytePacketBuffer::new();
+        query().write(&mut buf).unwrap();
+        let client = Session::sign_request(&key(), &mut buf, 0).unwrap();
+        let request = buf.as_slice().to_vec();
+        let server = Session::verify_request(&keyring(), &parse(&request), &request)
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.error(), None);
+        (request, client, server)
+    }
+
+    fn sign(session: &mut Session, mut buf: BytePacketBuffer) -> Vec<u8> {
+        session.sign(&mut buf, 0).unwrap();
+        buf.as_slice().to_vec()
+    }
+
+    // the TSIG record `key` would sign `msg` with at `time_signed`, its MAC covering `before`
+    // and then the message, with all the variables or, later in an exchange, just the timers
+    fn tsig_at(key: &TsigKey, before: &[&[u8]], msg: &[u8], time_signed: u64, full: bool) -> Tsig {
+        let mut tsig = Tsig {
+            key_name: key.name().to_string(),
+            algorithm: key.algorithm().name().to_string(),
+            time_signed,
+            fudge: DEFAULT_FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([msg[0], msg[1]]),
+            error: 0,
+            other: Vec::new(),
+        };
+        let mut hmac = key.hmac();
+        for bytes in before {
+            hmac.update(bytes);
+        }
+        hmac.update(msg);
+        match full {
+            true => hmac.update(&tsig.variables().unwrap()),
+            false => hmac.update(&tsig.timers()),
+        }
+        tsig.mac = hmac.finish().to_vec();
+        tsig
+    }
+
+    // `msg` with `tsig` added as its last record
+    fn append(msg: &[u8], tsig: &Tsig) -> Vec<u8> {
+        let mut buf = BytePacketBuffer::from_bytes(msg).unwrap();
+        buf.pos = msg.len();
+        tsig.to_record().unwrap().write(&mut buf).unwrap();
+        let arcount = u16::from_be_bytes([msg[10], msg[11]]) + 1;
+        buf.buf[10..12].copy_from_slice(&arcount.to_be_bytes());
+        buf.as_slice().to_vec()
+    }
+
+    fn verify(keys: &Keyring, request: &[u8]) -> Result<Option<TsigError>> {
+        let session = Session::verify_request(keys, &parse(request), request)?;
+        Ok(session.expect("the request is signed").error())
+    }
+
+    // a query for example.com A signed with hmac-sha256 at 1600000000, laid out as rfc 8945
+    // section 4 has it, with the MAC over the message and the variables of section 4.3.3
+    // worked out apart from this code
+    #[test]
+    fn verifies_a_mac_worked_out_by_hand() {
+        let signed = parse_hex(concat!(
+            "123401000001000000000001076578616d706c6503636f6d0000010001",
+            "0474657374036b65790000fa00ff00000000003d0b686d61632d736861323536",
+            "0000005f5e1000012c00208893aa027c421b806bc64a9cb80f8d3b539ddd19d3",
+            "7e72d54044fc465b07bb37123400000000",
+        ))
+        .unwrap();
+        let (offset, tsig) = locate(&parse(&signed), &signed).unwrap().unwrap();
+        assert_eq!(offset, 29);
+        assert_eq!(tsig.key_name, "test.key");
+        assert_eq!(tsig.algorithm, "hmac-sha256");
+        assert_eq!(tsig.time_signed, 1_600_000_000);
+        assert_eq!(tsig.fudge, 300);
+        assert_eq!(tsig.original_id, 0x1234);
+        assert_eq!(
+            check_mac(&key(), &tsig, &signed, offset, None).unwrap(),
+            Ok(())
+        );
+
+        // the record goes back on the wire as it came
+        let mut unsigned = signed[..offset].to_vec();
+        unsigned[11] = 0;
+        assert_eq!(append(&unsigned, &tsig), signed);
+        assert_eq!(tsig_at(&key(), &[], &unsigned, 1_600_000_000, true), tsig);
+
+        // the MAC is right, the time is long past
+        assert_eq!(
+            verify(&keyring(), &signed).unwrap(),
+            Some(TsigError::BadTime)
+        );
+
+        // a forwarder in between gave the message an id of its own
+        let mut forwarded = signed.clone();
+        forwarded[..2].copy_from_slice(&[0xab, 0xcd]);
+        assert_eq!(
+            check_mac(&key(), &tsig, &forwarded, offset, None).unwrap(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn turns_down_requests_whose_mac_is_wrong() {
+        let (request, _, _) = exchange();
+        assert_eq!(verify(&keyring(), &request).unwrap(), None);
+
+        // one letter of the question changed on the way
+        let mut tampered = request.clone();
+        tampered[13] = b'f';
+        assert_eq!(
+            verify(&keyring(), &tampered).unwrap(),
+            Some(TsigError::BadSig)
+        );
+
+        // the same name with some other secret
+        let other = TsigKey::new("test.key", Algorithm::HmacSha256, b"another".to_vec()).unwrap();
+        let keys = [other].into_iter().collect();
+        assert_eq!(verify(&keys, &request).unwrap(), Some(TsigError::BadSig));
+        assert_eq!(
+            verify(&Keyring::new(), &request).unwrap(),
+            Some(TsigError::BadKey)
+        );
+
+        // the NOTAUTH answer says why and, having nothing to chain to, carries no MAC
+        let mut session = Session::verify_request(&keyring(), &parse(&tampered), &tampered)
+            .unwrap()
+            .unwrap();
+        let answer = sign(&mut session, response(&tampered, 0));
+        let tsig = Tsig::from_record(parse(&answer).additional.last().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(TsigError::from_num(tsig.error), TsigError::BadSig);
+        assert!(tsig.mac.is_empty());
+
+        // and a response with a wrong MAC is an error for the client
+        let (request, mut client, mut server) = exchange();
+        let mut answer = sign(&mut server, response(&request, 0xc0000201));
+        let last = answer.len() - 8;
+        answer[last] ^= 1;
+        assert!(client.verify(&mut parse(&answer), &answer).is_err());
+    }
+
+    #[test]
+    fn turns_down_signatures_from_too_far_off() {
+        let unsigned = unsigned();
+        let at = |time_signed| {
+            append(
+                &unsigned,
+                &tsig_at(&key(), &[], &unsigned, time_signed, true),
+            )
+        };
+        let fudge = DEFAULT_FUDGE as u64;
+        assert_eq!(verify(&keyring(), &at(now() - fudge + 5)).unwrap(), None);
+        assert_eq!(verify(&keyring(), &at(now() + fudge - 5)).unwrap(), None);
+        assert_eq!(
+            verify(&keyring(), &at(now() - fudge - 5)).unwrap(),
+            Some(TsigError::BadTime)
+        );
+        assert_eq!(
+            verify(&keyring(), &at(now() + fudge + 5)).unwrap(),
+            Some(TsigError::BadTime)
+        );
+
+        // the answer is signed, the MAC being right, and tells the client our time
+        let late = at(now() - fudge - 5);
+        let mut session = Session::verify_request(&keyring(), &parse(&late), &late)
+            .unwrap()
+            .unwrap();
+        let answer = sign(&mut session, response(&late, 0));
+        let tsig = Tsig::from_record(parse(&answer).additional.last().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(TsigError::from_num(tsig.error), TsigError::BadTime);
+        assert_eq!(tsig.mac.len(), SHA256_LEN);
+        let ours = tsig.other.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        assert_eq!(ours, tsig.time_signed);
+
+        // a response signed too long ago is an error for the client
+        let (request, mut client, _) = exchange();
+        let request_mac = Tsig::from_record(parse(&request).additional.last().unwrap())
+            .unwrap()
+            .unwrap()
+            .mac;
+        let answer = response(&request, 0xc0000201);
+        let answer = answer.as_slice();
+        let prior = (request_mac.len() as u16).to_be_bytes();
+        let tsig = tsig_at(
+            &key(),
+            &[&prior, &request_mac],
+            answer,
+            now() - fudge - 5,
+            true,
+        );
+        let late = append(answer, &tsig);
+        assert!(client.verify(&mut parse(&late), &late).is_err());
+    }
+
+    #[test]
+    fn turns_down_truncated_macs() {
+        let unsigned = unsigned();
+        let mut tsig = tsig_at(&key(), &[], &unsigned, now(), true);
+        let full = tsig.mac.clone();
+
+        // half a MAC is allowed by section 5.2.2.1, but not by us
+        tsig.mac.truncate(SHA256_LEN / 2);
+        let truncated = append(&unsigned, &tsig);
+        assert_eq!(
+            verify(&keyring(), &truncated).unwrap(),
+            Some(TsigError::BadTrunc)
+        );
+
+        // more than the algorithm makes is malformed
+        tsig.mac = [full.as_slice(), &[0]].concat();
+        assert!(verify(&keyring(), &append(&unsigned, &tsig)).is_err());
+
+        let (request, mut client, mut server) = exchange();
+        let answer = sign(&mut server, response(&request, 0xc0000201));
+        let mut packet = parse(&answer);
+        let mut tsig = Tsig::from_record(packet.additional.last().unwrap())
+            .unwrap()
+            .unwrap();
+        tsig.mac.truncate(SHA256_LEN / 2);
+        packet.additional.pop();
+        let mut buf = BytePacketBuffer::new();
+        packet.write(&mut buf).unwrap();
+        let truncated = append(buf.as_slice(), &tsig);
+        assert!(client.verify(&mut parse(&truncated), &truncated).is_err());
+    }
+
+    // a zone transfer over tcp, where every message's MAC covers the one before
+    #[test]
+    fn chains_the_messages_of_a_transfer() {
+        let (request, mut client, mut server) = exchange();
+        let messages: Vec<_> = (1..=3)
+            .map(|ip| sign(&mut server, response(&request, ip)))
+            .collect();
+        for msg in &messages {
+            let mut packet = parse(msg);
+            assert!(client.verify(&mut packet, msg).unwrap());
+            // the TSIG record is taken out
+            assert_eq!(packet.additional.len(), 0);
+        }
+
+        // left out or out of order, the chain breaks
+        let (request, mut client, mut server) = exchange();
+        let messages: Vec<_> = (1..=3)
+            .map(|ip| sign(&mut server, response(&request, ip)))
+            .collect();
+        assert!(client
+            .verify(&mut parse(&messages[0]), &messages[0])
+            .unwrap());
+        assert!(client
+            .verify(&mut parse(&messages[2]), &messages[2])
+            .is_err());
+        let (_, mut client, _) = exchange();
+        assert!(client
+            .verify(&mut parse(&messages[0]), &messages[0])
+            .is_err());
+
+        // messages may go unsigned in between, the next signed one covering them
+        let (request, mut client, mut server) = exchange();
+        let first = sign(&mut server, response(&request, 1));
+        let first_mac = Tsig::from_record(parse(&first).additional.last().unwrap())
+            .unwrap()
+            .unwrap()
+            .mac;
+        let unsigned = response(&request, 2).as_slice().to_vec();
+        let last = response(&request, 3);
+        let last = last.as_slice();
+        let prior = (first_mac.len() as u16).to_be_bytes();
+        let tsig = tsig_at(&key(), &[&prior, &first_mac, &unsigned], last, now(), false);
+        let last = append(last, &tsig);
+        assert!(client.verify(&mut parse(&first), &first).unwrap());
+        assert!(!client.verify(&mut parse(&unsigned), &unsigned).unwrap());
+        assert!(client.verify(&mut parse(&last), &last).unwrap());
+
+        // but not the first
+        let (request, mut client, _) = exchange();
+        let unsigned = response(&request, 1).as_slice().to_vec();
+        assert!(client.verify(&mut parse(&unsigned), &unsigned).is_err());
+    }
+}
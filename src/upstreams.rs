@@ -0,0 +1,259 @@
+// how each upstream has been doing, so queries go to the ones answering fastest first and stop
+// waiting on ones that are down. every query updates a smoothed round trip time for the upstream
+// it went to (a timeout counts as the time waited) and how often it answers with SERVFAIL. after
+// a few failures in a row an upstream is dead and only tried when every other one has failed,
+// until its backoff is up; then a single query probes it, first in line, and each failed probe
+// doubles the backoff. one answer brings it back. names are sent with their case randomized
+// (see client.rs) until too many of an upstream's answers don't keep it, from then on that
+// upstream gets them in lower case.
+use crate::logging;
+use crate::metrics::CaseCounters;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// failures in a row before an upstream is dead
+const DEAD_AFTER: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(120);
+// weight of the newest sample in the smoothed values, as in tcp's srtt
+const SMOOTHING: f64 = 0.125;
+/// The share of answers an upstream may get the case of the name wrong in before names go to
+/// it in lower case. A server that doesn't keep the case gets it wrong every time, to get past
+/// this spoofed answers would have to outnumber the real ones.
+pub const DEFAULT_MAX_CASE_MISMATCHES: f64 = 0.5;
+// answers checked before the share of them is taken to say anything
+const CASE_SAMPLE: u64 = 20;
+
+/// What came of sending a query to an upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Answered(Duration),
+    /// Answered with SERVFAIL. The upstream is up, but may be failing to resolve.
+    ServerFailure(Duration),
+    /// No answer, a timeout or a network error, after waiting this long.
+    Failed(Duration),
+}
+
+/// How an upstream has been doing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamStats {
+    pub addr: SocketAddr,
+    /// Smoothed round trip time, zero until the first query.
+    pub srtt: Duration,
+    pub queries: u64,
+    pub servfails: u64,
+    pub failures: u64,
+    /// Failures since the last answer.
+    pub failing: u32,
+    /// Dead until this time, when it gets probed.
+    pub dead_until: Option<Instant>,
+    // smoothed share of answers that are SERVFAIL
+    servfail_rate: f64,
+}
+
+impl UpstreamStats {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            srtt: Duration::ZERO,
+            queries: 0,
+            servfails: 0,
+            failures: 0,
+            failing: 0,
+            dead_until: None,
+            servfail_rate: 0.0,
+        }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.failing >= DEAD_AFTER
+    }
+
+    // lower is better: the round trip time, made worse by answering with SERVFAIL
+    fn score(&self) -> Duration {
+        self.srtt.mul_f64(1.0 + 4.0 * self.servfail_rate)
+    }
+
+    fn smooth(&mut self, rtt: Duration, servfail: bool) {
+        if self.queries == 1 {
+            self.srtt = rtt;
+        } else {
+            self.srtt = self.srtt.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING);
+        }
+        let sample = if servfail { 1.0 } else { 0.0 };
+        self.servfail_rate += (sample - self.servfail_rate) * SMOOTHING;
+    }
+}
+
+/// The upstreams of a forwarder and how they've been doing.
+#[derive(Debug)]
+pub struct Upstreams {
+    addrs: Vec<SocketAddr>,
+    stats: Mutex<Vec<UpstreamStats>>,
+    // one for each of addrs
+    case: Vec<CaseCounters>,
+    max_case_mismatches: f64,
+}
+
+impl Upstreams {
+    pub fn new(addrs: Vec<SocketAddr>) -> Self {
+        let stats = addrs.iter().copied().map(UpstreamStats::new).collect();
+        let case = addrs.iter().map(|_| CaseCounters::default()).collect();
+        Self {
+            addrs,
+            stats: Mutex::new(stats),
+            case,
+            max_case_mismatches: DEFAULT_MAX_CASE_MISMATCHES,
+        }
+    }
+
+    /// Stops randomizing the case of names for an upstream once more than this share of its
+    /// answers haven't kept it, [`DEFAULT_MAX_CASE_MISMATCHES`] if not given.
+    pub fn max_case_mismatches(mut self, rate: f64) -> Self {
+        self.max_case_mismatches = rate;
+        self
+    }
+
+    /// Adds an upstream, tried along with the others.
+    pub fn add(&mut self, addr: SocketAddr) {
+        self.addrs.push(addr);
+        self.stats.get_mut().unwrap().push(UpstreamStats::new(addr));
+        self.case.push(CaseCounters::default());
+    }
+
+    /// The upstreams in the order they were given.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// The order to try the upstreams in for a query: a dead one that's due a probe first,
+    /// then the live ones, best first, then the other dead ones.
+    pub fn order(&self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let mut stats = self.stats.lock().unwrap();
+        let probe = stats.iter_mut().find(|upstream| {
+            upstream.is_dead() && upstream.dead_until.is_some_and(|until| until <= now)
+        });
+        // one query at a time probes, the next one gets to if this one never comes back
+        let probe = probe.map(|upstream| {
+            upstream.dead_until = Some(now + INITIAL_BACKOFF);
+            upstream.addr
+        });
+
+        let mut live: Vec<&UpstreamStats> = stats.iter().filter(|up| !up.is_dead()).collect();
+        // stable, so upstreams doing equally well stay in the order they were given
+        live.sort_by_key(|upstream| upstream.score());
+        let mut dead: Vec<&UpstreamStats> = stats
+            .iter()
+            .filter(|upstream| upstream.is_dead() && Some(upstream.addr) != probe)
+            .collect();
+        dead.sort_by_key(|upstream| upstream.dead_until);
+        probe
+            .into_iter()
+            .chain(live.into_iter().chain(dead).map(|upstream| upstream.addr))
+            .collect()
+    }
+
+    /// Records how a query to `addr` went.
+    pub fn record(&self, addr: SocketAddr, outcome: Outcome) {
+        let mut stats = self.stats.lock().unwrap();
+        let Some(upstream) = stats.iter_mut().find(|upstream| upstream.addr == addr) else {
+            return;
+        };
+        upstream.queries += 1;
+        match outcome {
+            Outcome::Answered(rtt) | Outcome::ServerFailure(rtt) => {
+                let servfail = matches!(outcome, Outcome::ServerFailure(_));
+                if servfail {
+                    upstream.servfails += 1;
+                }
+                upstream.smooth(rtt, servfail);
+                if upstream.is_dead() {
+                    logging::info(&format!("upstream {} is answering again", addr));
+                }
+                upstream.failing = 0;
+                upstream.dead_until = None;
+            }
+            Outcome::Failed(waited) => {
+                upstream.failures += 1;
+                upstream.failing += 1;
+                upstream.smooth(waited, false);
+                if upstream.is_dead() {
+                    let doublings = (upstream.failing - DEAD_AFTER).min(16);
+                    let backoff = INITIAL_BACKOFF
+                        .saturating_mul(1 << doublings)
+                        .min(MAX_BACKOFF);
+                    if upstream.failing == DEAD_AFTER {
+                        logging::warning(&format!(
+                            "upstream {} isn't answering, trying it again in {:?}",
+                            addr, backoff
+                        ));
+                    }
+                    upstream.dead_until = Some(Instant::now() + backoff);
+                }
+            }
+        }
+    }
+
+    /// How each upstream has been doing, in the order they were given.
+    pub fn stats(&self) -> Vec<UpstreamStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Whether the case of names sent to `addr` is still randomized.
+    pub fn randomizes_case(&self, addr: SocketAddr) -> bool {
+        self.case_counters(addr)
+            .is_none_or(|case| !case.off.load(Ordering::Relaxed))
+    }
+
+    /// Records whether an answer from `addr` kept the case of the name it was asked about.
+    pub fn record_case(&self, addr: SocketAddr, kept: bool) {
+        let Some(case) = self.case_counters(addr) else {
+            return;
+        };
+        let checked = case.checked.fetch_add(1, Ordering::Relaxed) + 1;
+        let mismatches = if kept {
+            case.mismatches.load(Ordering::Relaxed)
+        } else {
+            case.mismatches.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        let too_many = mismatches as f64 > checked as f64 * self.max_case_mismatches;
+        if checked >= CASE_SAMPLE && too_many && !case.off.swap(true, Ordering::Relaxed) {
+            logging::warning(&format!(
+                "upstream {} didn't keep the case of {} of {} names, sending it lower case ones",
+                addr, mismatches, checked
+            ));
+        }
+    }
+
+    /// The counters for the case of names sent to `addr`.
+    pub fn case_counters(&self, addr: SocketAddr) -> Option<&CaseCounters> {
+        let i = self.addrs.iter().position(|&a| a == addr)?;
+        Some(&self.case[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_randomizing_case_for_upstreams_that_dont_keep_it() {
+        let (good, bad): (SocketAddr, SocketAddr) = (
+            "192.0.2.1:53".parse().unwrap(),
+            "192.0.2.2:53".parse().unwrap(),
+        );
+        let upstreams = Upstreams::new(vec![good, bad]).max_case_mismatches(0.25);
+        for i in 0..CASE_SAMPLE {
+            // spoofed answers now and then don't count against it
+            upstreams.record_case(good, i % 5 != 0);
+            upstreams.record_case(bad, false);
+            assert!(upstreams.randomizes_case(good));
+            assert_eq!(upstreams.randomizes_case(bad), i + 1 < CASE_SAMPLE);
+        }
+        let counters = upstreams.case_counters(bad).unwrap();
+        assert_eq!(counters.mismatches.load(Ordering::Relaxed), CASE_SAMPLE);
+    }
+}
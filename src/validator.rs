@@ -0,0 +1,978 @@
+// DNSSEC validation in front of a resolving handler (rfc 4035 section 5). every answer is
+// asked for with DO and CD set, so the handler behind passes on the signatures and doesn't
+// filter anything itself, and is then checked against a chain of trust from the trust anchors
+// (the root's key signing keys by default) down to the zone that signed it. the DS and DNSKEY
+// records the chain is built from are looked up through the same handler, and which zones
+// are signed, unsigned or broken is remembered for as long as their records live. answers
+// that check out get the AD bit if the client can tell, answers that don't are replaced by
+// SERVFAIL with an extended error saying why. requests with CD set get the unchecked answer.
+// the NSEC and NSEC3 records of validated answers are kept to answer for other names they
+// prove don't exist (rfc 8198), see denial_cache.rs. names under a negative trust anchor
+// (rfc 7646) are left unvalidated until it runs out, for operators to keep a domain whose
+// signatures are broken resolving while it gets fixed, and names under the exceptions are
+// never validated.
+use crate::denial_cache::DenialCache;
+use crate::dnssec::{self, Bogus, Denial};
+use crate::edns::EdeCode;
+use crate::logging;
+use crate::server::Handler;
+use crate::structure::{is_subdomain, DnsPacket, DnsRecord, Opcode, QueryType, ResultCode};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how long what was learnt about a zone is kept at most, even if its records live longer
+const MAX_ZONE_TTL: Duration = Duration::from_secs(3600);
+// broken zones are retried after this, rfc 4035 section 4.7 asks for a limit
+const BOGUS_TTL: Duration = Duration::from_secs(60);
+// more zones than this and expired ones are dropped, or everything if none have
+const MAX_ZONES: usize = 10_000;
+/// How long a negative trust anchor is kept at most, rfc 7646 section 2 asks for a limit.
+pub const MAX_NEGATIVE_ANCHOR_LIFETIME: Duration = Duration::from_secs(7 * 24 * 3600);
+
+// the root zone's key signing keys, from https://data.iana.org/root-anchors/root-anchors.xml
+const ROOT_ANCHORS: [(u16, &str); 2] = [
+    (
+        20326,
+        "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ),
+    (
+        38696,
+        "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+    ),
+];
+
+/// The DS records of the root zone's key signing keys, the default trust anchors.
+pub fn root_anchors() -> Vec<DnsRecord> {
+    ROOT_ANCHORS
+        .iter()
+        .map(|(key_tag, digest)| DnsRecord::DS {
+            domain: String::new(),
+            class: 1,
+            ttl: 0,
+            key_tag: *key_tag,
+            algorithm: 8,
+            digest_type: 2,
+            digest: crate::presentation::parse_hex(digest).unwrap_or_default(),
+        })
+        .collect()
+}
+
+// what's known about a name on the way down a chain of trust
+#[derive(Clone)]
+enum ZoneState {
+    // a signed zone, with its validated keys
+    Secure(Arc<Vec<DnsRecord>>),
+    // not a zone cut, what's below is signed by the zone above
+    NotZone,
+    // an unsigned zone, or one signed with algorithms we can't check
+    Insecure,
+    Bogus(Bogus),
+}
+
+// the zone whose keys sign a name, if there's a chain of trust to it
+enum Trust {
+    Secure {
+        zone: String,
+        keys: Arc<Vec<DnsRecord>>,
+    },
+    Insecure,
+    Bogus(Bogus),
+}
+
+// what checking a response or an RRset came to
+enum Security {
+    Secure,
+    Insecure,
+    Bogus(Bogus),
+}
+
+// a validated RRset, and if it was expanded from a wildcard the name the wildcard is at
+struct Checked {
+    zone: String,
+    closest_encloser: Option<String>,
+}
+
+/// Validates the answers of a resolving [`Handler`] with DNSSEC.
+pub struct Validator<H> {
+    inner: H,
+    // trust anchors by the zone they're for
+    anchors: HashMap<String, Vec<DnsRecord>>,
+    zones: Mutex<HashMap<String, (ZoneState, Instant)>>,
+    denials: Option<DenialCache>,
+    // domains not to validate, with when that stops
+    negative_anchors: Mutex<HashMap<String, Instant>>,
+    // domains never to validate
+    exceptions: Vec<String>,
+}
+
+impl<H: Handler> Validator<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            anchors: HashMap::new(),
+            zones: Mutex::new(HashMap::new()),
+            denials: Some(DenialCache::default()),
+            negative_anchors: Mutex::new(HashMap::new()),
+            exceptions: Vec::new(),
+        }
+        .trust_anchors(root_anchors())
+    }
+
+    /// Where validated NSEC and NSEC3 records are kept to answer for names they prove don't
+    /// exist without asking the handler behind. None turns that off.
+    pub fn denial_cache(mut self, cache: Option<DenialCache>) -> Self {
+        self.denials = cache;
+        self
+    }
+
+    /// Replaces the trust anchors, DS or DNSKEY records for the zones whose keys are trusted
+    /// without a parent vouching for them. Names not below any anchor are left unvalidated.
+    pub fn trust_anchors(mut self, anchors: Vec<DnsRecord>) -> Self {
+        self.anchors.clear();
+        for anchor in anchors {
+            if matches!(anchor, DnsRecord::DS { .. } | DnsRecord::DNSKEY { .. }) {
+                self.anchors
+                    .entry(anchor.domain().to_lowercase())
+                    .or_default()
+                    .push(anchor);
+            }
+        }
+        self
+    }
+
+    /// Domains not to validate at all, with the names below them, like a negative trust
+    /// anchor that doesn't run out. For internal zones that only exist on internal servers,
+    /// which a signed public zone above them says don't exist.
+    pub fn validate_except(mut self, domains: Vec<String>) -> Self {
+        self.exceptions = domains
+            .iter()
+            .map(|domain| domain.trim_end_matches('.').to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Stops validating `domain` and the names below it for `lifetime`, at most
+    /// [`MAX_NEGATIVE_ANCHOR_LIFETIME`], and answers for them as if they weren't signed.
+    /// Replaces any negative trust anchor already there.
+    pub fn add_negative_anchor(&self, domain: &str, lifetime: Duration) {
+        let expires = Instant::now() + lifetime.min(MAX_NEGATIVE_ANCHOR_LIFETIME);
+        self.negative_anchors
+            .lock()
+            .unwrap()
+            .insert(domain.trim_end_matches('.').to_lowercase(), expires);
+    }
+
+    /// Validates `domain` again, returns whether it had a negative trust anchor.
+    pub fn remove_negative_anchor(&self, domain: &str) -> bool {
+        self.negative_anchors
+            .lock()
+            .unwrap()
+            .remove(&domain.trim_end_matches('.').to_lowercase())
+            .is_some()
+    }
+
+    /// The domains with negative trust anchors, and how long they're left unvalidated for.
+    pub fn negative_anchors(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        let mut anchors: Vec<(String, Duration)> = self
+            .negative_anchors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(domain, expires)| {
+                let left = expires.checked_duration_since(now)?;
+                Some((domain.clone(), left))
+            })
+            .collect();
+        anchors.sort();
+        anchors
+    }
+
+    // whether `name` is at or below an exception or a negative trust anchor that hasn't run
+    // out. the anchors that have are dropped on the way
+    fn exempt(&self, name: &str) -> bool {
+        if self
+            .exceptions
+            .iter()
+            .any(|domain| is_subdomain(name, domain))
+        {
+            return true;
+        }
+        let mut anchors = self.negative_anchors.lock().unwrap();
+        if anchors.is_empty() {
+            return false;
+        }
+        let now = Instant::now();
+        anchors.retain(|domain, expires| {
+            let live = *expires > now;
+            if !live {
+                logging::info(&format!(
+                    "negative trust anchor for {} expired",
+                    zone_name(domain)
+                ));
+            }
+            live
+        });
+        anchors.keys().any(|domain| is_subdomain(name, domain))
+    }
+
+    // asks the handler behind, for signatures and without any checking of its own
+    async fn fetch(&self, name: &str, qtype: QueryType, src: SocketAddr) -> Option<DnsPacket> {
+        let query = DnsPacket::query(name, qtype)
+            .dnssec_ok(true)
+            .checking_disabled(true)
+            .build();
+        self.inner.handle(query, src).await
+    }
+
+    // follows the chain of trust from the closest anchor down to the zone `name` is in
+    async fn trust(&self, name: &str, src: SocketAddr) -> Trust {
+        if self.exempt(name) {
+            return Trust::Insecure;
+        }
+        let Some((anchor, _)) = self
+            .anchors
+            .iter()
+            .filter(|(zone, _)| is_subdomain(name, zone))
+            .max_by_key(|(zone, _)| zone.len())
+        else {
+            return Trust::Insecure;
+        };
+
+        let mut zone = anchor.clone();
+        let mut keys = match self.zone_state(anchor, None, src).await {
+            ZoneState::Secure(keys) => keys,
+            ZoneState::Bogus(bogus) => return Trust::Bogus(bogus),
+            ZoneState::Insecure | ZoneState::NotZone => return Trust::Insecure,
+        };
+        for count in dnssec::labels(anchor).len() + 1..=dnssec::labels(name).len() {
+            let child = dnssec::suffix(name, count);
+            match self.zone_state(&child, Some((&zone, &keys)), src).await {
+                ZoneState::Secure(child_keys) => {
+                    zone = child;
+                    keys = child_keys;
+                }
+                ZoneState::NotZone => {}
+                ZoneState::Insecure => return Trust::Insecure,
+                ZoneState::Bogus(bogus) => return Trust::Bogus(bogus),
+            }
+        }
+        Trust::Secure { zone, keys }
+    }
+
+    // what's known about `name`, looked up if it isn't known yet. `parent` is the signed zone
+    // above it with its keys, None for a trust anchor
+    async fn zone_state(
+        &self,
+        name: &str,
+        parent: Option<(&str, &[DnsRecord])>,
+        src: SocketAddr,
+    ) -> ZoneState {
+        if let Some((state, expires)) = self.zones.lock().unwrap().get(name) {
+            if *expires > Instant::now() {
+                return state.clone();
+            }
+        }
+
+        let (state, ttl) = match parent {
+            None => self.anchor_state(name, src).await,
+            Some((zone, keys)) => self.delegation_state(name, zone, keys, src).await,
+        };
+        let Some(ttl) = ttl else {
+            return state;
+        };
+        let ttl = match state {
+            ZoneState::Bogus(_) => BOGUS_TTL,
+            _ => ttl.min(MAX_ZONE_TTL),
+        };
+
+        let mut zones = self.zones.lock().unwrap();
+        if zones.len() >= MAX_ZONES {
+            let now = Instant::now();
+            zones.retain(|_, (_, expires)| *expires > now);
+            if zones.len() >= MAX_ZONES {
+                zones.clear();
+            }
+        }
+        zones.insert(name.to_string(), (state.clone(), Instant::now() + ttl));
+        state
+    }
+
+    // the keys of a zone with a trust anchor. the ttl is None when the state shouldn't be
+    // remembered, because the lookup failed rather than the zone being broken
+    async fn anchor_state(&self, zone: &str, src: SocketAddr) -> (ZoneState, Option<Duration>) {
+        let anchors: Vec<&DnsRecord> = self.anchors.get(zone).into_iter().flatten().collect();
+        let supported = anchors.iter().any(|anchor| match anchor {
+            DnsRecord::DS { .. } => dnssec::supported_ds(anchor),
+            DnsRecord::DNSKEY { algorithm, .. } => {
+                crate::signature::Algorithm::from_num(*algorithm).is_some()
+            }
+            _ => false,
+        });
+        if !supported {
+            return (ZoneState::Insecure, Some(MAX_ZONE_TTL));
+        }
+        self.keys(zone, &anchors, src).await
+    }
+
+    // the zone's DNSKEY RRset, checked against the DS records or anchors pointing at it
+    async fn keys(
+        &self,
+        zone: &str,
+        trusted: &[&DnsRecord],
+        src: SocketAddr,
+    ) -> (ZoneState, Option<Duration>) {
+        let Some(response) = self.fetch(zone, QueryType::DNSKEY, src).await else {
+            return (lookup_failed(zone, QueryType::DNSKEY), None);
+        };
+        let sets = dnssec::rrsets(&response.answers);
+        let Some(dnskeys) = sets
+            .iter()
+            .find(|set| set.qtype == QueryType::DNSKEY && set.name.eq_ignore_ascii_case(zone))
+        else {
+            let bogus = Bogus::new(
+                EdeCode::DnskeyMissing,
+                format!("no DNSKEY records for {}", zone_name(zone)),
+            );
+            return (ZoneState::Bogus(bogus), Some(BOGUS_TTL));
+        };
+        match dnssec::verify_dnskeys(
+            zone,
+            &dnskeys.records,
+            &dnskeys.signatures,
+            trusted,
+            dnssec::now(),
+        ) {
+            Ok(keys) => {
+                let ttl = min_ttl(keys.iter().chain(trusted.iter().copied()));
+                (ZoneState::Secure(Arc::new(keys)), Some(ttl))
+            }
+            Err(bogus) => (ZoneState::Bogus(bogus), Some(BOGUS_TTL)),
+        }
+    }
+
+    // whether `child` is a signed zone below `zone`, going by the DS records `zone` has for it
+    // or its proof that there are none
+    async fn delegation_state(
+        &self,
+        child: &str,
+        zone: &str,
+        keys: &[DnsRecord],
+        src: SocketAddr,
+    ) -> (ZoneState, Option<Duration>) {
+        let response = match self.fetch(child, QueryType::DS, src).await {
+            Some(response)
+                if matches!(
+                    response.header.rcode,
+                    ResultCode::NOERROR | ResultCode::NXDOMAIN
+                ) =>
+            {
+                response
+            }
+            _ => return (lookup_failed(child, QueryType::DS), None),
+        };
+        let now = dnssec::now();
+        let bogus = |bogus| (ZoneState::Bogus(bogus), Some(BOGUS_TTL));
+
+        let answers = dnssec::rrsets(&response.answers);
+        if let Some(ds) = answers
+            .iter()
+            .find(|set| set.qtype == QueryType::DS && set.name.eq_ignore_ascii_case(child))
+        {
+            if let Err(e) = dnssec::verify_rrset(&ds.records, &ds.signatures, keys, now) {
+                return bogus(e);
+            }
+            // signed, but not in a way we can check
+            if !ds.records.iter().any(|ds| dnssec::supported_ds(ds)) {
+                return (
+                    ZoneState::Insecure,
+                    Some(min_ttl(ds.records.iter().copied())),
+                );
+            }
+            return self.keys(child, &ds.records, src).await;
+        }
+        // an alias can't be a zone cut
+        if let Some(cname) = answers
+            .iter()
+            .find(|set| set.qtype == QueryType::CNAME && set.name.eq_ignore_ascii_case(child))
+        {
+            return match dnssec::verify_rrset(&cname.records, &cname.signatures, keys, now) {
+                Ok(_) => (
+                    ZoneState::NotZone,
+                    Some(min_ttl(cname.records.iter().copied())),
+                ),
+                Err(e) => bogus(e),
+            };
+        }
+
+        // no DS records, which the zone above has to prove
+        let mut proof = Vec::new();
+        for set in dnssec::rrsets(&response.authorities) {
+            if !matches!(set.qtype, QueryType::NSEC | QueryType::NSEC3) {
+                continue;
+            }
+            if let Err(e) = dnssec::verify_rrset(&set.records, &set.signatures, keys, now) {
+                return bogus(e);
+            }
+            proof.extend(set.records);
+        }
+        let ttl = Some(min_ttl(proof.iter().copied()));
+        let nxdomain = response.header.rcode == ResultCode::NXDOMAIN;
+        match dnssec::prove_denial(child, QueryType::DS, nxdomain, zone, &proof) {
+            Ok(Denial::NoData { delegation: true }) | Ok(Denial::Insecure) => {
+                (ZoneState::Insecure, ttl)
+            }
+            Ok(Denial::NoData { delegation: false }) | Ok(Denial::NxDomain) => {
+                (ZoneState::NotZone, ttl)
+            }
+            Err(e) => bogus(e),
+        }
+    }
+
+    // checks the signatures over one RRset, against the keys of the zone that signed it
+    async fn check(&self, set: &dnssec::RRset<'_>, src: SocketAddr) -> Result<Checked, Security> {
+        // signed by a zone above the exception or not, it isn't checked
+        if self.exempt(set.name) {
+            return Err(Security::Insecure);
+        }
+        let Some(DnsRecord::RRSIG { signer, .. }) = set.signatures.first() else {
+            return Err(match self.trust(set.name, src).await {
+                Trust::Insecure => Security::Insecure,
+                Trust::Bogus(bogus) => Security::Bogus(bogus),
+                Trust::Secure { .. } => Security::Bogus(Bogus::new(
+                    EdeCode::RrsigsMissing,
+                    format!("{} {} isn't signed", zone_name(set.name), set.qtype),
+                )),
+            });
+        };
+        if !is_subdomain(set.name, signer) {
+            return Err(Security::Bogus(Bogus::new(
+                EdeCode::DnssecBogus,
+                format!(
+                    "{} {} is signed by {}, which it isn't in",
+                    zone_name(set.name),
+                    set.qtype,
+                    zone_name(signer)
+                ),
+            )));
+        }
+        let (zone, keys) = match self.trust(signer, src).await {
+            Trust::Secure { zone, keys } => (zone, keys),
+            Trust::Insecure => return Err(Security::Insecure),
+            Trust::Bogus(bogus) => return Err(Security::Bogus(bogus)),
+        };
+        if !zone.eq_ignore_ascii_case(signer) {
+            return Err(Security::Bogus(Bogus::new(
+                EdeCode::DnssecBogus,
+                format!("{} isn't a signed zone", zone_name(signer)),
+            )));
+        }
+        let rrsig = dnssec::verify_rrset(&set.records, &set.signatures, &keys, dnssec::now())
+            .map_err(Security::Bogus)?;
+        let DnsRecord::RRSIG { labels, .. } = rrsig else {
+            unreachable!("verify_rrset only returns RRSIG records");
+        };
+        let closest_encloser = (*labels as usize) < dnssec::label_count(set.name);
+        Ok(Checked {
+            closest_encloser: closest_encloser.then(|| dnssec::suffix(set.name, *labels as usize)),
+            zone,
+        })
+    }
+
+    // rfc 4035 section 5.3 for the answers, and 5.4 for what the authority section has to
+    // prove when there's no answer
+    async fn validate(&self, response: &DnsPacket, src: SocketAddr) -> Security {
+        let Some(question) = response.questions.first() else {
+            return Security::Insecure;
+        };
+        let mut insecure = false;
+        let mut wildcards = Vec::new();
+        let mut dnames: Vec<&DnsRecord> = Vec::new();
+
+        let answers = dnssec::rrsets(&response.answers);
+        for set in &answers {
+            // CNAMEs made up from a validated DNAME carry no signature of their own
+            if set.qtype == QueryType::CNAME && set.signatures.is_empty() {
+                let synthesized = |cname: &&DnsRecord| {
+                    dnames.iter().any(|dname| {
+                        dname.synthesize_cname(set.name).ok().flatten().as_ref() == Some(*cname)
+                    })
+                };
+                if set.records.iter().all(synthesized) {
+                    continue;
+                }
+            }
+            match self.check(set, src).await {
+                Ok(checked) => {
+                    if let Some(closest_encloser) = checked.closest_encloser {
+                        wildcards.push((set.name, closest_encloser, checked.zone));
+                    }
+                    if set.qtype == QueryType::DNAME {
+                        dnames.extend(&set.records);
+                    }
+                }
+                Err(Security::Insecure) => insecure = true,
+                Err(bogus) => return bogus,
+            }
+        }
+        if insecure {
+            return Security::Insecure;
+        }
+
+        // where the aliases in the answer lead
+        let mut target = question.name.to_lowercase();
+        for _ in 0..answers.len() {
+            let next = response.answers.iter().find_map(|rec| match rec {
+                DnsRecord::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(&target) => {
+                    Some(host.to_lowercase())
+                }
+                _ => None,
+            });
+            match next {
+                Some(next) => target = next,
+                None => break,
+            }
+        }
+        let answered = question.qtype == QueryType::CNAME
+            || answers
+                .iter()
+                .any(|set| set.qtype == question.qtype && set.name.eq_ignore_ascii_case(&target));
+        if answered && wildcards.is_empty() {
+            return Security::Secure;
+        }
+
+        // whatever has to be proven absent is, by the zone the proof came from
+        let mut proof = Vec::new();
+        let mut zone = None;
+        for set in dnssec::rrsets(&response.authorities) {
+            if !matches!(
+                set.qtype,
+                QueryType::NSEC | QueryType::NSEC3 | QueryType::SOA
+            ) {
+                continue;
+            }
+            match self.check(&set, src).await {
+                Ok(checked) => {
+                    if let Some(denials) = &self.denials {
+                        denials.insert(&checked.zone, &set);
+                    }
+                    zone = Some(checked.zone);
+                }
+                Err(Security::Insecure) => return Security::Insecure,
+                Err(bogus) => return bogus,
+            }
+            if set.qtype != QueryType::SOA {
+                proof.extend(set.records);
+            }
+        }
+
+        for (name, closest_encloser, zone) in &wildcards {
+            match dnssec::prove_wildcard(name, closest_encloser, zone, &proof) {
+                Ok(Denial::Insecure) => insecure = true,
+                Ok(_) => {}
+                Err(bogus) => return Security::Bogus(bogus),
+            }
+        }
+        if !answered {
+            let zone = match zone {
+                Some(zone) => zone,
+                None => match self.trust(&target, src).await {
+                    Trust::Insecure => return Security::Insecure,
+                    Trust::Bogus(bogus) => return Security::Bogus(bogus),
+                    Trust::Secure { zone, .. } => zone,
+                },
+            };
+            let nxdomain = response.header.rcode == ResultCode::NXDOMAIN;
+            match dnssec::prove_denial(&target, question.qtype, nxdomain, &zone, &proof) {
+                Ok(Denial::Insecure) => insecure = true,
+                Ok(_) => {}
+                Err(bogus) => return Security::Bogus(bogus),
+            }
+        }
+        if insecure {
+            Security::Insecure
+        } else {
+            Security::Secure
+        }
+    }
+}
+
+impl<H: Handler> Handler for Validator<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let question = match request.questions.first() {
+            Some(question) if request.header.flags.opcode == Opcode::QUERY => question,
+            _ => return self.inner.handle(request, src).await,
+        };
+        let client_edns = DnsPacket::response_edns(&request);
+        let dnssec_ok = client_edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        let checking_disabled = request.header.flags.checking_disabled;
+
+        let cached = self
+            .denials
+            .as_ref()
+            .filter(|_| !checking_disabled && question.class == 1)
+            .filter(|_| !self.exempt(&question.name))
+            .and_then(|denials| denials.nxdomain(&question.name, question.qtype));
+        if let Some(authorities) = cached {
+            let mut res = DnsPacket::response_to(&request);
+            res.set_rcode(ResultCode::NXDOMAIN)
+                .set_recursion_available(true);
+            res.header.flags.authentic_data = dnssec_ok || request.header.flags.authentic_data;
+            for rec in authorities {
+                res.add_authority(rec);
+            }
+            if !dnssec_ok {
+                dnssec::strip_dnssec(&mut res);
+            }
+            res.set_edns(client_edns.as_ref());
+            return Some(res);
+        }
+
+        let query = DnsPacket::query(&question.name, question.qtype)
+            .class(question.class)
+            .recursion_desired(request.header.flags.recursion_desired)
+            .dnssec_ok(true)
+            .checking_disabled(true)
+            .build();
+        let mut response = self.inner.handle(query, src).await?;
+        response.header.id = request.header.id;
+        response.questions = request.questions.clone();
+        response.header.flags.checking_disabled = checking_disabled;
+        response.header.flags.authentic_data = false;
+
+        let validate = !checking_disabled
+            && matches!(
+                response.header.rcode,
+                ResultCode::NOERROR | ResultCode::NXDOMAIN
+            );
+        if validate {
+            match self.validate(&response, src).await {
+                // only for clients that asked for it one way or the other, rfc 6840 section 5.8
+                Security::Secure => {
+                    response.header.flags.authentic_data =
+                        dnssec_ok || request.header.flags.authentic_data
+                }
+                Security::Insecure => {}
+                Security::Bogus(bogus) => {
+                    logging::warning(&format!(
+                        "validating {:?} failed: {}",
+                        request.questions, bogus
+                    ));
+                    let mut res = DnsPacket::response_to(&request);
+                    res.set_rcode(ResultCode::SERVFAIL)
+                        .set_recursion_available(response.header.flags.recursion_available);
+                    res.set_edns(client_edns.as_ref());
+                    if client_edns.is_some() {
+                        let _ = res.add_extended_error(bogus.code, &bogus.reason);
+                    }
+                    return Some(res);
+                }
+            }
+        }
+
+        if !dnssec_ok {
+            dnssec::strip_dnssec(&mut response);
+        }
+        response.set_edns(client_edns.as_ref());
+        Some(response)
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        self.inner.transfer(request, src).await
+    }
+}
+
+fn lookup_failed(name: &str, qtype: QueryType) -> ZoneState {
+    ZoneState::Bogus(Bogus::new(
+        EdeCode::DnssecIndeterminate,
+        format!("no answer to {} {}", zone_name(name), qtype),
+    ))
+}
+
+// the root is easier to read as a dot
+fn zone_name(name: &str) -> &str {
+    if name.is_empty() {
+        "."
+    } else {
+        name
+    }
+}
+
+fn min_ttl<'a>(records: impl Iterator<Item = &'a DnsRecord>) -> Duration {
+    let ttl = records.map(DnsRecord::ttl).min().unwrap_or(0);
+    Duration::from_secs(ttl as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::Authority;
+    use crate::signature::{Algorithm, SigningKey};
+    use crate::signer::{sign_zone, Chain, ZoneKey, DEFAULT_VALIDITY};
+    use crate::zone::Zone;
+
+    // test is the trust anchor, with example.test signed below it and insecure.test not
+    const PARENT: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+ns A 192.0.2.1
+example NS ns.example
+ns.example A 192.0.2.2
+insecure NS ns.insecure
+ns.insecure A 192.0.2.3
+";
+    const CHILD: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+ns A 192.0.2.2
+www A 192.0.2.10
+";
+    const INSECURE: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+ns A 192.0.2.3
+www A 192.0.2.20
+";
+
+    fn key(zone: &str) -> ZoneKey {
+        ZoneKey::new(zone, 257, 3600, SigningKey::generate(Algorithm::Ed25519))
+    }
+
+    fn sign(zone: &Zone, key: &ZoneKey, chain: &Chain) -> Zone {
+        sign_zone(
+            zone,
+            std::slice::from_ref(key),
+            chain,
+            1,
+            dnssec::now(),
+            DEFAULT_VALIDITY,
+        )
+        .unwrap()
+    }
+
+    // the zones, signed, with a chance to tamper with example.test after. the DNSKEY of
+    // test is the only trust anchor
+    fn zones(chain: Chain, tamper: impl FnOnce(&mut Zone, &ZoneKey)) -> (Authority, DnsRecord) {
+        let parent_key = key("test");
+        let child_key = key("example.test");
+        let mut parent = Zone::parse(PARENT, "test").unwrap();
+        parent.insert(child_key.ds(2).unwrap()).unwrap();
+        let mut child = sign(
+            &Zone::parse(CHILD, "example.test").unwrap(),
+            &child_key,
+            &chain,
+        );
+        tamper(&mut child, &child_key);
+        let authority = Authority::new(vec![
+            sign(&parent, &parent_key, &Chain::Nsec),
+            child,
+            Zone::parse(INSECURE, "insecure.test").unwrap(),
+        ]);
+        (authority, parent_key.dnskey().clone())
+    }
+
+    fn signed(chain: Chain, tamper: impl FnOnce(&mut Zone, &ZoneKey)) -> Validator<Authority> {
+        let (authority, anchor) = zones(chain, tamper);
+        Validator::new(authority).trust_anchors(vec![anchor])
+    }
+
+    // replaces the signature over www.example.test A with one `key` makes over `rrset`
+    fn resign(zone: &mut Zone, key: &ZoneKey, rrset: &DnsRecord, inception: u32, expiration: u32) {
+        let rrsigs: Vec<DnsRecord> = zone
+            .rrset("www.example.test", QueryType::RRSIG)
+            .filter(|rec| {
+                matches!(
+                    rec,
+                    DnsRecord::RRSIG {
+                        type_covered: QueryType::A,
+                        ..
+                    }
+                )
+            })
+            .cloned()
+            .collect();
+        for rrsig in &rrsigs {
+            assert!(zone.remove(rrsig));
+        }
+        zone.insert(key.sign(&[rrset], inception, expiration).unwrap())
+            .unwrap();
+    }
+
+    fn www(ip: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: "www.example.test".into(),
+            class: 1,
+            ttl: 3600,
+            ip,
+        }
+    }
+
+    async fn ask<H: Handler>(validator: &Validator<H>, name: &str, qtype: QueryType) -> DnsPacket {
+        let query = DnsPacket::query(name, qtype).dnssec_ok(true).build();
+        let src = "127.0.0.1:53".parse().unwrap();
+        validator.handle(query, src).await.unwrap()
+    }
+
+    fn ede(res: &DnsPacket) -> Vec<EdeCode> {
+        res.extended_errors()
+            .into_iter()
+            .map(|(code, _)| code)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn marks_answers_down_a_chain_of_trust_as_authentic() {
+        let validator = signed(Chain::Nsec, |_, _| {});
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::NOERROR);
+        assert!(res.header.flags.authentic_data);
+        assert_eq!(res.answers[0], www(0xc000020a));
+
+        // the parent proves there's no DS for insecure.test, so its answers are let through
+        // as they are
+        let res = ask(&validator, "www.insecure.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::NOERROR);
+        assert!(!res.header.flags.authentic_data);
+        assert_eq!(res.answers.len(), 1);
+
+        // names under no anchor aren't validated either
+        let validator =
+            Validator::new(zones(Chain::Nsec, |_, _| {}).0)
+                .trust_anchors(vec![key("elsewhere").dnskey().clone()]);
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::NOERROR);
+        assert!(!res.header.flags.authentic_data);
+    }
+
+    #[tokio::test]
+    async fn fails_answers_whose_signature_is_bogus() {
+        // a signature by the zone's key, but over some other address
+        let validator = signed(Chain::Nsec, |zone, key| {
+            let now = dnssec::now();
+            resign(zone, key, &www(0xc0000263), now - 3600, now + 3600);
+        });
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::SERVFAIL);
+        assert!(res.answers.is_empty());
+        assert_eq!(ede(&res), [EdeCode::DnssecBogus]);
+
+        // and by a key the zone doesn't have
+        let validator = signed(Chain::Nsec, |zone, _| {
+            let now = dnssec::now();
+            resign(
+                zone,
+                &key("example.test"),
+                &www(0xc000020a),
+                now - 3600,
+                now + 3600,
+            );
+        });
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::SERVFAIL);
+        assert_eq!(ede(&res), [EdeCode::DnskeyMissing]);
+
+        // the rest of the zone is fine, and checking disabled gets the answer regardless
+        let res = ask(&validator, "ns.example.test", QueryType::A).await;
+        assert!(res.header.flags.authentic_data);
+        let query = DnsPacket::query("www.example.test", QueryType::A)
+            .dnssec_ok(true)
+            .checking_disabled(true)
+            .build();
+        let res = validator
+            .handle(query, "127.0.0.1:53".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.header.rcode, ResultCode::NOERROR);
+        assert!(!res.header.flags.authentic_data);
+    }
+
+    #[tokio::test]
+    async fn fails_answers_whose_signature_has_expired() {
+        let validator = signed(Chain::Nsec, |zone, key| {
+            let now = dnssec::now();
+            resign(zone, key, &www(0xc000020a), now - 7200, now - 3600);
+        });
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::SERVFAIL);
+        assert_eq!(ede(&res), [EdeCode::SignatureExpired]);
+
+        let validator = signed(Chain::Nsec, |zone, key| {
+            let now = dnssec::now();
+            resign(zone, key, &www(0xc000020a), now + 3600, now + 7200);
+        });
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(ede(&res), [EdeCode::SignatureNotYetValid]);
+    }
+
+    #[tokio::test]
+    async fn checks_what_proves_a_name_or_type_missing() {
+        for chain in [
+            Chain::Nsec,
+            Chain::Nsec3 {
+                iterations: 0,
+                salt: Vec::new(),
+                opt_out: false,
+            },
+        ] {
+            let validator = signed(chain.clone(), |_, _| {}).denial_cache(None);
+            let res = ask(&validator, "missing.example.test", QueryType::A).await;
+            assert_eq!(res.header.rcode, ResultCode::NXDOMAIN, "{:?}", chain);
+            assert!(res.header.flags.authentic_data, "{:?}", chain);
+            let res = ask(&validator, "www.example.test", QueryType::TXT).await;
+            assert_eq!(res.header.rcode, ResultCode::NOERROR, "{:?}", chain);
+            assert!(res.answers.is_empty());
+            assert!(res.header.flags.authentic_data, "{:?}", chain);
+
+            // without the records that prove it, a denial isn't believed
+            let (authority, anchor) = zones(chain.clone(), |_, _| {});
+            let stripped = move |request: DnsPacket, _| {
+                let mut res = authority.answer(&request);
+                if let Some(res) = &mut res {
+                    res.authorities
+                        .retain(|rec| !matches!(rec.qtype(), QueryType::NSEC | QueryType::NSEC3));
+                }
+                async move { res }
+            };
+            let validator = Validator::new(stripped).trust_anchors(vec![anchor]);
+            let res = ask(&validator, "missing.example.test", QueryType::A).await;
+            assert_eq!(res.header.rcode, ResultCode::SERVFAIL, "{:?}", chain);
+            assert_eq!(ede(&res), [EdeCode::NsecMissing], "{:?}", chain);
+        }
+    }
+
+    #[tokio::test]
+    async fn leaves_names_under_negative_anchors_unvalidated() {
+        let validator = signed(Chain::Nsec, |zone, key| {
+            let now = dnssec::now();
+            resign(zone, key, &www(0xc0000263), now - 3600, now + 3600);
+        });
+        validator.add_negative_anchor("Example.Test.", Duration::from_secs(3600));
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::NOERROR);
+        assert!(!res.header.flags.authentic_data);
+        assert_eq!(res.answers[0], www(0xc000020a));
+        let anchors = validator.negative_anchors();
+        assert_eq!(anchors.len(), 1);
+        assert_eq!(anchors[0].0, "example.test");
+
+        // validated again once it's taken away, or runs out
+        assert!(validator.remove_negative_anchor("example.test"));
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::SERVFAIL);
+        validator.add_negative_anchor("example.test", Duration::ZERO);
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::SERVFAIL);
+        assert!(validator.negative_anchors().is_empty());
+
+        // an exception never runs out
+        let validator = validator.validate_except(vec!["example.test".into()]);
+        let res = ask(&validator, "www.example.test", QueryType::A).await;
+        assert_eq!(res.header.rcode, ResultCode::NOERROR);
+        assert!(!res.header.flags.authentic_data);
+    }
+}
@@ -0,0 +1,138 @@
+// the versions of each zone that have been served, kept so a bad one can be rolled back from
+// without waiting for a fixed zone file or primary. every version an authority is given, from
+// a zone file, a transfer or the signer, is kept, newest first, up to a number of them, and a
+// rollback serves one of the older ones again. a version only differing from the one before
+// in its serial and what signing adds doesn't count as a version of its own. a rollback holds
+// until the zone is given a version that isn't one of those rolled back from: the same bad
+// zone file being read again on a reload, or the same bad version being transferred again,
+// keeps serving the one rolled back to. the version rolled back to keeps its serial, so
+// secondaries that already have the newer one keep it until a fixed version goes above that,
+// and a signed zone rolled back isn't signed again, so it has to be fixed before its
+// signatures expire.
+use crate::error::{DnsError, Result};
+use crate::logging;
+use crate::structure::{DnsRecord, QueryType};
+use crate::zone::Zone;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_ZONE_VERSIONS: usize = 3;
+
+/// The versions of each zone an authority has served, see the module docs. Shared by the
+/// authorities one replaces another with, so the versions outlive any one of them.
+pub struct ZoneVersions {
+    max: usize,
+    zones: Mutex<HashMap<String, Versions>>,
+}
+
+#[derive(Default)]
+struct Versions {
+    // newest first, the one being served first
+    kept: VecDeque<Arc<Zone>>,
+    // the versions rolled back from since the last new one
+    rolled_back_from: Vec<Arc<Zone>>,
+}
+
+impl ZoneVersions {
+    /// Keeps `max` versions of each zone, the one being served among them, at least one.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// The version of its zone to serve now that `zone` has been loaded: `zone`, kept as the
+    /// newest version, or the one rolled back to if `zone` is one that was rolled back from.
+    pub fn admit(&self, zone: Arc<Zone>) -> Arc<Zone> {
+        let mut zones = self.zones.lock().unwrap();
+        let versions = zones.entry(zone.origin().to_string()).or_default();
+        if let Some(current) = versions.kept.front() {
+            if versions
+                .rolled_back_from
+                .iter()
+                .any(|z| same_data(z, &zone))
+            {
+                logging::warning(&format!(
+                    "zone {} is still the version rolled back from, serving serial {}",
+                    zone.origin(),
+                    current.serial().unwrap_or_default()
+                ));
+                return current.clone();
+            }
+            // the same version signed again, or with only its serial bumped
+            if same_data(current, &zone) {
+                versions.kept.pop_front();
+            }
+        }
+        versions.rolled_back_from.clear();
+        versions.kept.push_front(zone.clone());
+        versions.kept.truncate(self.max);
+        zone
+    }
+
+    /// Makes an older version of the zone at `origin` the one to serve and returns it: the one
+    /// with `serial`, or without one the version before the one being served.
+    pub fn rollback(&self, origin: &str, serial: Option<u32>) -> Result<Arc<Zone>> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let mut zones = self.zones.lock().unwrap();
+        let Some(versions) = zones.get_mut(&origin) else {
+            return Err(DnsError::Control(format!("no versions of zone {}", origin)));
+        };
+        let i = match serial {
+            Some(serial) => (versions.kept.iter().skip(1))
+                .position(|zone| zone.serial() == Some(serial))
+                .map(|i| i + 1)
+                .ok_or_else(|| {
+                    DnsError::Control(format!(
+                        "no earlier version of zone {} with serial {}",
+                        origin, serial
+                    ))
+                })?,
+            None if versions.kept.len() > 1 => 1,
+            None => {
+                return Err(DnsError::Control(format!(
+                    "no earlier version of zone {}",
+                    origin
+                )))
+            }
+        };
+        let zone = versions.kept.remove(i).unwrap();
+        let current = versions.kept.front().unwrap().clone();
+        versions.rolled_back_from.push(current);
+        versions.kept.push_front(zone.clone());
+        Ok(zone)
+    }
+
+    /// The serials of the versions kept of the zone at `origin`, the one being served first.
+    pub fn serials(&self, origin: &str) -> Vec<Option<u32>> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let zones = self.zones.lock().unwrap();
+        zones.get(&origin).map_or(Vec::new(), |versions| {
+            versions.kept.iter().map(|zone| zone.serial()).collect()
+        })
+    }
+}
+
+impl Default for ZoneVersions {
+    fn default() -> Self {
+        Self::new(DEFAULT_ZONE_VERSIONS)
+    }
+}
+
+// whether two versions have the same records, leaving out the SOA and what signing adds
+fn same_data(a: &Zone, b: &Zone) -> bool {
+    fn data(zone: &Zone) -> impl Iterator<Item = &DnsRecord> {
+        zone.records().filter(|rec| {
+            !matches!(
+                rec.qtype(),
+                QueryType::SOA | QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3
+            )
+        })
+    }
+    data(a).eq(data(b))
+}
@@ -0,0 +1,84 @@
+// split-horizon views: different answers for the same names depending on who's asking, e.g.
+// the internal addresses of a company's servers for clients on its network and the public
+// ones for everyone else. each view matches clients by source address and has a handler of
+// its own, with its own zones, local records, forwarding and cache, so nothing one view
+// answers can leak into another. views are tried in the order they were added and the first
+// that matches the client answers, as in bind, clients no view matches get the default.
+use crate::net::Subnet;
+use crate::server::Handler;
+use crate::structure::DnsPacket;
+use std::net::{IpAddr, SocketAddr};
+
+/// A set of clients and the handler answering them.
+pub struct View<H> {
+    name: String,
+    clients: Vec<Subnet>,
+    handler: H,
+}
+
+impl<H: Handler> View<H> {
+    pub fn new(name: &str, clients: Vec<Subnet>, handler: H) -> Self {
+        Self {
+            name: name.to_string(),
+            clients,
+            handler,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn clients(&self) -> &[Subnet] {
+        &self.clients
+    }
+
+    pub fn matches(&self, client: IpAddr) -> bool {
+        self.clients.iter().any(|net| net.contains(client))
+    }
+}
+
+/// Answers each client from the first view it matches.
+pub struct Views<H> {
+    views: Vec<View<H>>,
+    default: H,
+}
+
+impl<H: Handler> Views<H> {
+    /// Views that are all `default` until others are added.
+    pub fn new(default: H) -> Self {
+        Self {
+            views: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn view(mut self, view: View<H>) -> Self {
+        self.views.push(view);
+        self
+    }
+
+    pub fn views(&self) -> &[View<H>] {
+        &self.views
+    }
+
+    /// The handler for `client`, and the name of its view if it's not the default.
+    pub fn handler_for(&self, client: IpAddr) -> (Option<&str>, &H) {
+        match self.views.iter().find(|view| view.matches(client)) {
+            Some(view) => (Some(&view.name), &view.handler),
+            None => (None, &self.default),
+        }
+    }
+}
+
+impl<H: Handler> Handler for Views<H> {
+    async fn handle(&self, request: DnsPacket, src: SocketAddr) -> Option<DnsPacket> {
+        let (_, handler) = self.handler_for(src.ip());
+        handler.handle(request, src).await
+    }
+
+    async fn transfer(&self, request: &DnsPacket, src: SocketAddr) -> Option<Vec<DnsPacket>> {
+        let (_, handler) = self.handler_for(src.ip());
+        handler.transfer(request, src).await
+    }
+}
@@ -0,0 +1,116 @@
+// watching files for changes with inotify, for whatever has to be read again when they do.
+// the directories the files are in are watched rather than the files, since editors and tools
+// like to replace a file rather than write it, so a watch on the file itself would be left on
+// the old one. a file that doesn't exist yet is noticed when it's made, but its directory has
+// to exist.
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::time;
+
+// changes usually come in bursts, a rename after a write or several writes, so they're only
+// reported once things have settled
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// Watches files for anything that changes them.
+pub struct Watcher {
+    fd: AsyncFd<OwnedFd>,
+    // watch descriptors and the names of the files in their directory
+    watches: HashMap<i32, Vec<Vec<u8>>>,
+}
+
+impl Watcher {
+    pub fn new(paths: &[PathBuf]) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_MOVED_TO
+            | libc::IN_MOVED_FROM
+            | libc::IN_CREATE
+            | libc::IN_DELETE;
+        let mut watches: HashMap<i32, Vec<Vec<u8>>> = HashMap::new();
+        for path in paths {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let Some(file) = path.file_name() else {
+                continue;
+            };
+            let dir = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let wd = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            watches
+                .entry(wd)
+                .or_default()
+                .push(file.as_bytes().to_vec());
+        }
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            watches,
+        })
+    }
+
+    /// Waits for one of the files to change, and for things to settle after that.
+    pub async fn changed(&self) -> io::Result<()> {
+        self.event().await?;
+        time::sleep(SETTLE).await;
+        self.drain();
+        Ok(())
+    }
+
+    // waits for an event about one of the files
+    async fn event(&self) -> io::Result<()> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| self.read(fd.get_ref())) {
+                Ok(Ok(true)) => return Ok(()),
+                Ok(Ok(false)) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => {}
+            }
+        }
+    }
+
+    // throws away the events that came in while settling, they're covered by the reload
+    fn drain(&self) {
+        while self.read(self.fd.get_ref()).is_ok() {}
+    }
+
+    // reads what's queued, whether any of it is about the files. lost events could be about
+    // anything
+    fn read(&self, fd: &OwnedFd) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        let len = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut relevant = false;
+        let mut pos = 0;
+        while pos + header <= len as usize {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buf[pos..].as_ptr().cast()) };
+            let name = &buf[pos + header..pos + header + event.len as usize];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            relevant |= event.mask & libc::IN_Q_OVERFLOW != 0
+                || self
+                    .watches
+                    .get(&event.wd)
+                    .is_some_and(|files| files.iter().any(|file| file == name));
+            pos += header + event.len as usize;
+        }
+        Ok(relevant)
+    }
+}
@@ -0,0 +1,671 @@
+// zones read from master files (rfc 1035 section 5). on top of the single records presentation.rs
+// parses this handles the rest of the format: $ORIGIN, $TTL and $INCLUDE, blank owners that
+// repeat the previous one, and records spread over several lines with parentheses.
+use crate::dnssec::CanonicalName;
+use crate::error::{DnsError, Result};
+use crate::json::Value;
+use crate::presentation::{absolute_name, parse_tokens, parse_ttl, tokenize, Token};
+use crate::structure::{is_subdomain, BytePacketBuffer, DnsRecord, QueryType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+// nesting of $INCLUDE, deeper than this is most likely a file including itself
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// The records of one zone, grouped by owner name.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    origin: String,
+    // in canonical order, which puts the names below an owner right after it
+    records: BTreeMap<CanonicalName, Vec<DnsRecord>>,
+    // owners of NSEC and NSEC3 records in the order of their chains, to find the record
+    // covering a name without going through all of them
+    nsec: BTreeSet<CanonicalName>,
+    nsec3: BTreeSet<CanonicalName>,
+}
+
+impl Zone {
+    /// An empty zone, records are added with [`Zone::insert`].
+    pub fn new(origin: &str) -> Self {
+        Self {
+            origin: absolute_name(origin, ""),
+            records: BTreeMap::new(),
+            nsec: BTreeSet::new(),
+            nsec3: BTreeSet::new(),
+        }
+    }
+
+    /// Parses master file text for the zone at `origin`. `$INCLUDE` is resolved relative to
+    /// the working directory.
+    pub fn parse(text: &str, origin: &str) -> Result<Self> {
+        let mut zone = Zone::new(origin);
+        let mut reader = Reader::new(&zone.origin);
+        reader.read(&mut zone, text, "<zone>", None, 0)?;
+        zone.validate()?;
+        Ok(zone)
+    }
+
+    /// Reads the master file at `path`. `$INCLUDE` is resolved relative to its directory.
+    pub fn load(path: impl AsRef<Path>, origin: &str) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let mut zone = Zone::new(origin);
+        let mut reader = Reader::new(&zone.origin);
+        reader.read(
+            &mut zone,
+            &text,
+            &path.display().to_string(),
+            path.parent(),
+            0,
+        )?;
+        zone.validate()?;
+        Ok(zone)
+    }
+
+    /// Builds the zone at `origin` out of `records`, e.g. the ones received in a zone transfer.
+    pub fn from_records(
+        origin: &str,
+        records: impl IntoIterator<Item = DnsRecord>,
+    ) -> Result<Self> {
+        let mut zone = Zone::new(origin);
+        for record in records {
+            zone.insert(record)?;
+        }
+        zone.validate()?;
+        Ok(zone)
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// The SOA record at the apex.
+    pub fn soa(&self) -> Option<&DnsRecord> {
+        self.rrset(&self.origin, QueryType::SOA).next()
+    }
+
+    /// The serial number of the zone's SOA.
+    pub fn serial(&self) -> Option<u32> {
+        match self.soa()? {
+            DnsRecord::SOA { serial, .. } => Some(*serial),
+            _ => None,
+        }
+    }
+
+    /// Adds a record. Fails if its owner isn't in the zone.
+    pub fn insert(&mut self, mut record: DnsRecord) -> Result<()> {
+        // owners are kept lowercase, so lookups don't depend on how a name was written
+        let owner = record.domain().to_string();
+        record.set_domain(&owner);
+        if !is_subdomain(record.domain(), &self.origin) {
+            return Err(DnsError::Syntax(format!(
+                "{} is outside of zone {}",
+                record.domain(),
+                self.origin
+            )));
+        }
+        if let Some(chain) = self.chain_mut(record.qtype()) {
+            chain.insert(CanonicalName(record.domain().to_string()));
+        }
+        let records = (self.records)
+            .entry(CanonicalName(record.domain().to_string()))
+            .or_default();
+        if !records.contains(&record) {
+            records.push(record);
+        }
+        Ok(())
+    }
+
+    /// Takes a record out of the zone, returning whether it was there.
+    pub fn remove(&mut self, record: &DnsRecord) -> bool {
+        // stored with the owner lowercased, see insert
+        let mut record = record.clone();
+        let owner = record.domain().to_string();
+        record.set_domain(&owner);
+        let owner = CanonicalName(record.domain().to_string());
+        let Some(records) = self.records.get_mut(&owner) else {
+            return false;
+        };
+        let Some(i) = records.iter().position(|rec| *rec == record) else {
+            return false;
+        };
+        records.remove(i);
+        let last_of_type = !records.iter().any(|rec| rec.qtype() == record.qtype());
+        if records.is_empty() {
+            self.records.remove(&owner);
+        }
+        if last_of_type {
+            if let Some(chain) = self.chain_mut(record.qtype()) {
+                chain.remove(&CanonicalName(record.domain().to_string()));
+            }
+        }
+        true
+    }
+
+    fn chain_mut(&mut self, qtype: QueryType) -> Option<&mut BTreeSet<CanonicalName>> {
+        match qtype {
+            QueryType::NSEC => Some(&mut self.nsec),
+            QueryType::NSEC3 => Some(&mut self.nsec3),
+            _ => None,
+        }
+    }
+
+    /// All records owned by `name`, None if the zone has nothing at that name.
+    pub fn get(&self, name: &str) -> Option<&[DnsRecord]> {
+        let name = CanonicalName(name.trim_end_matches('.').to_lowercase());
+        self.records.get(&name).map(Vec::as_slice)
+    }
+
+    /// The records of type `qtype` owned by `name`.
+    pub fn rrset<'a>(
+        &'a self,
+        name: &str,
+        qtype: QueryType,
+    ) -> impl Iterator<Item = &'a DnsRecord> + 'a {
+        self.get(name)
+            .unwrap_or_default()
+            .iter()
+            .filter(move |rec| rec.qtype() == qtype)
+    }
+
+    /// Whether the zone has names below `name`. A name without records of its own but with
+    /// descendants (an empty non-terminal) still exists.
+    pub fn has_descendants(&self, name: &str) -> bool {
+        let name = CanonicalName(name.trim_end_matches('.').to_lowercase());
+        // if anything is below the name, the first owner after it is
+        let next = (self.records)
+            .range((Bound::Excluded(&name), Bound::Unbounded))
+            .next();
+        next.is_some_and(|(owner, _)| is_subdomain(&owner.0, &name.0))
+    }
+
+    /// Whether `name` exists, either with records of its own or as an empty non-terminal.
+    pub fn contains_name(&self, name: &str) -> bool {
+        self.get(name).is_some() || self.has_descendants(name)
+    }
+
+    /// The closest encloser of `name` (rfc 4592 section 3.3.1): the longest of its ancestors
+    /// that exists in the zone. The apex always does, so this is None only for names outside
+    /// the zone.
+    pub fn closest_encloser(&self, name: &str) -> Option<String> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if !is_subdomain(&name, &self.origin) {
+            return None;
+        }
+        let mut ancestor = name.as_str();
+        while ancestor != self.origin {
+            ancestor = ancestor.split_once('.').map_or("", |(_, parent)| parent);
+            if self.contains_name(ancestor) {
+                return Some(ancestor.to_string());
+            }
+        }
+        Some(self.origin.clone())
+    }
+
+    /// The records of the wildcard that covers `name`, which has to be a name that doesn't
+    /// exist. Only the wildcard directly below the closest encloser counts: `*.example.com`
+    /// covers `a.b.example.com`, but not if `b.example.com` exists (rfc 4592 section 3.3.1).
+    pub fn wildcard(&self, name: &str) -> Option<&[DnsRecord]> {
+        let encloser = self.closest_encloser(name)?;
+        self.get(&format!("*.{}", encloser))
+    }
+
+    /// The delegation point at or above `name`: the name closest to the apex, but below it,
+    /// that has NS records. Everything under it belongs to the child zone, the records the
+    /// zone has there are only glue.
+    pub fn delegation(&self, name: &str) -> Option<&str> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if !is_subdomain(&name, &self.origin) {
+            return None;
+        }
+        let mut cut = None;
+        let mut ancestor = name.as_str();
+        while ancestor != self.origin {
+            let key = CanonicalName(ancestor.to_string());
+            if let Some((owner, _)) = self.records.get_key_value(&key) {
+                if self.rrset(&owner.0, QueryType::NS).next().is_some() {
+                    cut = Some(owner.0.as_str());
+                }
+            }
+            ancestor = ancestor.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        cut
+    }
+
+    /// The DNAME record that redirects `name` (rfc 6672): the one owned by the ancestor
+    /// closest to the apex, the apex included, that has one. A DNAME's own name isn't
+    /// redirected, only the names below it.
+    pub fn dname(&self, name: &str) -> Option<&DnsRecord> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if !is_subdomain(&name, &self.origin) {
+            return None;
+        }
+        let mut dname = None;
+        let mut ancestor = name.as_str();
+        while ancestor != self.origin {
+            ancestor = ancestor.split_once('.').map_or("", |(_, parent)| parent);
+            if let Some(rec) = self.rrset(ancestor, QueryType::DNAME).next() {
+                dname = Some(rec);
+            }
+        }
+        dname
+    }
+
+    /// The NSEC record owned by `name`, or else the one before it in the chain, which is the
+    /// one covering it. The last record wraps around and covers names before the first.
+    /// With `qtype` NSEC3, `name` is a hashed owner name and the record an NSEC3 record.
+    pub fn chain_record(&self, name: &str, qtype: QueryType) -> Option<&DnsRecord> {
+        let chain = match qtype {
+            QueryType::NSEC => &self.nsec,
+            QueryType::NSEC3 => &self.nsec3,
+            _ => return None,
+        };
+        let name = CanonicalName(name.trim_end_matches('.').to_lowercase());
+        let owner = chain.range(..=name).next_back().or_else(|| chain.last())?;
+        self.rrset(&owner.0, qtype).next()
+    }
+
+    /// The owner names in the zone, in canonical order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.records.keys().map(|owner| owner.0.as_str())
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records.values().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The records the zone has but never answers with: anything below a DNAME (rfc 6672
+    /// section 2.3), and at or below a delegation anything but the delegation's NS, DS and NSEC
+    /// records, their signatures and glue addresses.
+    pub fn occluded(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records().filter(|rec| {
+            let name = rec.domain();
+            if self.dname(name).is_some() {
+                return true;
+            }
+            let qtype = rec.qtype();
+            match self.delegation(name) {
+                None => false,
+                Some(cut) if cut == name => !matches!(
+                    qtype,
+                    QueryType::NS
+                        | QueryType::DS
+                        | QueryType::NSEC
+                        | QueryType::RRSIG
+                        | QueryType::A
+                        | QueryType::AAAA
+                ),
+                Some(_) => !matches!(qtype, QueryType::A | QueryType::AAAA),
+            }
+        })
+    }
+
+    /// What the zone has, for the control socket: its serial, how many names and records of
+    /// each type, the bytes the records take on the wire without compression, how it's
+    /// signed, and every record in master file format.
+    pub fn to_json(&self) -> Value {
+        let mut types: BTreeMap<String, usize> = BTreeMap::new();
+        let mut bytes = 0;
+        let mut buf = BytePacketBuffer::new();
+        // the signatures that run out first, which the zone has to be signed again before
+        let mut expires: Option<u32> = None;
+        for rec in self.records() {
+            *types.entry(rec.qtype().to_string()).or_default() += 1;
+            buf.pos = 0;
+            bytes += rec.write(&mut buf).unwrap_or_default();
+            if let DnsRecord::RRSIG { expiration, .. } = rec {
+                expires = Some(expires.map_or(*expiration, |e| e.min(*expiration)));
+            }
+        }
+        let count = |qtype: QueryType| types.get(&qtype.to_string()).copied().unwrap_or(0);
+        let denial = match (count(QueryType::NSEC3), count(QueryType::NSEC)) {
+            (0, 0) => None,
+            (0, _) => Some("nsec"),
+            _ => Some("nsec3"),
+        };
+        let keys = self.rrset(&self.origin, QueryType::DNSKEY).count();
+        let signing = Value::object([
+            ("signed", (keys > 0 && count(QueryType::RRSIG) > 0).into()),
+            ("keys", keys.into()),
+            ("denial", denial.into()),
+            ("signatures-expire", expires.into()),
+        ]);
+        let records: Vec<String> = self.records().map(|rec| rec.to_string()).collect();
+        Value::object([
+            ("origin", self.origin.as_str().into()),
+            ("serial", self.serial().into()),
+            ("names", self.records.len().into()),
+            ("records", self.len().into()),
+            (
+                "types",
+                Value::Object(types.into_iter().map(|(t, n)| (t, n.into())).collect()),
+            ),
+            ("bytes", bytes.into()),
+            ("signing", signing),
+            ("contents", records.into()),
+        ])
+    }
+
+    // an alias has no other data than its own signatures and NSEC record (rfc 2181 section
+    // 10.1, rfc 4035 section 2.5), and rfc 1035 section 5.2, exactly one SOA and it's at the
+    // top of the zone
+    pub(crate) fn validate(&self) -> Result<()> {
+        for (owner, records) in &self.records {
+            let alias = records.iter().any(|rec| rec.qtype() == QueryType::CNAME);
+            let other = records.iter().find(|rec| {
+                !matches!(
+                    rec.qtype(),
+                    QueryType::CNAME | QueryType::RRSIG | QueryType::NSEC
+                )
+            });
+            if let (true, Some(other)) = (alias, other) {
+                return Err(DnsError::Syntax(format!(
+                    "{} has a CNAME and {} records",
+                    owner.0,
+                    other.qtype()
+                )));
+            }
+        }
+        let soas = self
+            .records()
+            .filter(|rec| rec.qtype() == QueryType::SOA)
+            .collect::<Vec<_>>();
+        match soas[..] {
+            [soa] if soa.domain() == self.origin => Ok(()),
+            [soa] => Err(DnsError::Syntax(format!(
+                "the SOA record is at {}, not the zone apex {}",
+                soa.domain(),
+                self.origin
+            ))),
+            [] => Err(DnsError::Syntax(format!(
+                "zone {} has no SOA record",
+                self.origin
+            ))),
+            _ => Err(DnsError::Syntax(format!(
+                "zone {} has {} SOA records",
+                self.origin,
+                soas.len()
+            ))),
+        }
+    }
+}
+
+// the state that carries over from one entry to the next
+struct Reader {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_ttl: Option<u32>,
+}
+
+impl Reader {
+    fn new(origin: &str) -> Self {
+        Self {
+            origin: origin.to_string(),
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+        }
+    }
+
+    fn read(
+        &mut self,
+        zone: &mut Zone,
+        text: &str,
+        source: &str,
+        dir: Option<&Path>,
+        depth: usize,
+    ) -> Result<()> {
+        let mut lines = text.lines().enumerate();
+        while let Some((index, line)) = lines.next() {
+            let at = |e: DnsError| DnsError::ZoneFile {
+                file: source.to_string(),
+                line: index + 1,
+                error: Box::new(e),
+            };
+
+            // an entry continues over the following lines until its parentheses are closed
+            let mut tokens = tokenize(line).map_err(at)?;
+            let mut depth_open = paren_depth(line, 0).map_err(at)?;
+            while depth_open > 0 {
+                let Some((_, next)) = lines.next() else {
+                    return Err(at(DnsError::Syntax("unclosed parenthesis".into())));
+                };
+                tokens.extend(tokenize(next).map_err(at)?);
+                depth_open = paren_depth(next, depth_open).map_err(at)?;
+            }
+            if tokens.is_empty() {
+                continue;
+            }
+
+            let blank_owner = line.starts_with(char::is_whitespace);
+            match tokens[0].text.as_str() {
+                "$ORIGIN" if !blank_owner => {
+                    let [_, origin] = &tokens[..] else {
+                        return Err(at(DnsError::Syntax("$ORIGIN takes one name".into())));
+                    };
+                    self.origin = absolute_name(&origin.text, &self.origin);
+                }
+                "$TTL" if !blank_owner => {
+                    let [_, ttl] = &tokens[..] else {
+                        return Err(at(DnsError::Syntax("$TTL takes one value".into())));
+                    };
+                    self.default_ttl = Some(parse_ttl(&ttl.text).map_err(at)?);
+                }
+                "$INCLUDE" if !blank_owner => {
+                    self.include(zone, &tokens[1..], dir, depth).map_err(at)?;
+                }
+                _ => self.record(zone, &tokens, blank_owner).map_err(at)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, zone: &mut Zone, tokens: &[Token], blank_owner: bool) -> Result<()> {
+        let owner = match (blank_owner, &self.last_owner) {
+            (false, _) => None,
+            (true, Some(owner)) => Some(owner.as_str()),
+            (true, None) => {
+                return Err(DnsError::Syntax(
+                    "the first record needs an owner name".into(),
+                ))
+            }
+        };
+        // without $TTL a record without its own ttl takes the previous one's, rfc 1035
+        // section 5.1
+        let default_ttl = self.default_ttl.or(self.last_ttl);
+        let record = parse_tokens(tokens, &self.origin, default_ttl, owner)?;
+
+        self.last_owner = Some(record.domain().to_string());
+        self.last_ttl = Some(record.ttl());
+        zone.insert(record)
+    }
+
+    // $INCLUDE <file> [origin]. the origin only applies to the included file, afterwards the
+    // one from before is back, rfc 1035 section 5.1
+    fn include(
+        &mut self,
+        zone: &mut Zone,
+        args: &[Token],
+        dir: Option<&Path>,
+        depth: usize,
+    ) -> Result<()> {
+        let (file, origin) = match args {
+            [file] => (file, None),
+            [file, origin] => (file, Some(absolute_name(&origin.text, &self.origin))),
+            _ => {
+                return Err(DnsError::Syntax(
+                    "$INCLUDE takes a file name and an optional origin".into(),
+                ))
+            }
+        };
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(DnsError::Syntax(format!(
+                "$INCLUDE nested more than {} deep",
+                MAX_INCLUDE_DEPTH
+            )));
+        }
+
+        let path = match dir {
+            Some(dir) => dir.join(&file.text),
+            None => PathBuf::from(&file.text),
+        };
+        let text = fs::read_to_string(&path)?;
+
+        let saved_origin = self.origin.clone();
+        if let Some(origin) = origin {
+            self.origin = origin;
+        }
+        let result = self.read(
+            zone,
+            &text,
+            &path.display().to_string(),
+            path.parent(),
+            depth + 1,
+        );
+        self.origin = saved_origin;
+        result
+    }
+}
+
+// the parenthesis depth after `line`, starting from `depth`. parentheses inside quotes,
+// escaped ones and anything after a comment don't count.
+fn paren_depth(line: &str, mut depth: usize) -> Result<usize> {
+    let mut chars = line.chars();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ';' if !quoted => break,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| DnsError::Syntax("unbalanced ')'".into()))?;
+            }
+            _ => {}
+        }
+    }
+    Ok(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+ns A 192.0.2.1
+a.b.c A 192.0.2.2
+ca A 192.0.2.3
+";
+
+    #[test]
+    fn finds_names_with_something_below_them() {
+        let zone = Zone::parse(ZONE, "example.com").unwrap();
+        assert!(zone.has_descendants("example.com"));
+        assert!(zone.has_descendants("C.example.com."));
+        assert!(zone.has_descendants("b.c.example.com"));
+        // the next owner, ca.example.com, isn't below it
+        assert!(!zone.has_descendants("a.b.c.example.com"));
+        assert!(!zone.has_descendants("ca.example.com"));
+        assert!(!zone.has_descendants("d.example.com"));
+        assert_eq!(
+            zone.closest_encloser("x.a.b.c.example.com").as_deref(),
+            Some("a.b.c.example.com")
+        );
+        assert_eq!(
+            zone.closest_encloser("x.b.c.example.com").as_deref(),
+            Some("b.c.example.com")
+        );
+        assert_eq!(
+            zone.closest_encloser("x.y.example.com").as_deref(),
+            Some("example.com")
+        );
+    }
+
+    #[test]
+    fn finds_what_delegations_and_dnames_hide() {
+        let text = format!(
+            "{}child NS ns.child\nns.child A 192.0.2.4\nchild TXT hidden\nx.child MX 10 mail\nold DNAME new\nx.old A 192.0.2.5\n",
+            ZONE
+        );
+        let zone = Zone::parse(&text, "example.com").unwrap();
+        let occluded: Vec<_> = zone
+            .occluded()
+            .map(|rec| (rec.domain(), rec.qtype()))
+            .collect();
+        assert_eq!(
+            occluded,
+            [
+                ("child.example.com", QueryType::TXT),
+                ("x.child.example.com", QueryType::MX),
+                ("x.old.example.com", QueryType::A),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_aliases_with_other_data() {
+        let text = format!("{}www CNAME ns\nwww TXT alias\n", ZONE);
+        assert!(Zone::parse(&text, "example.com").is_err());
+        let text = format!("{}www CNAME ns\n", ZONE);
+        assert!(Zone::parse(&text, "example.com").is_ok());
+    }
+
+    #[test]
+    fn describes_itself_as_json() {
+        let zone = Zone::parse(ZONE, "example.com").unwrap();
+        let json = zone.to_json();
+        assert_eq!(json.get("serial"), Some(&Value::Number(1)));
+        assert_eq!(json.get("names"), Some(&Value::Number(4)));
+        assert_eq!(json.get("records"), Some(&Value::Number(5)));
+        assert_eq!(
+            json.get("types").unwrap().to_string(),
+            r#"{"A":3,"NS":1,"SOA":1}"#
+        );
+        assert_eq!(
+            json.get("signing").unwrap().to_string(),
+            r#"{"signed":false,"keys":0,"denial":null,"signatures-expire":null}"#
+        );
+        let Some(Value::Array(contents)) = json.get("contents") else {
+            panic!("no contents");
+        };
+        assert_eq!(
+            contents[1],
+            Value::String("example.com. 3600 IN NS ns.example.com.".into())
+        );
+        // every record uncompressed, the SOA's 83 bytes, the NS's 39, the A's 33, 30 and 30
+        assert_eq!(json.get("bytes"), Some(&Value::Number(215)));
+
+        let signed = format!(
+            "{}\
+@ DNSKEY 257 3 15 l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=
+@ RRSIG SOA 15 2 3600 20300101000000 20240101000000 12345 example.com. AAAA
+@ RRSIG NS 15 2 3600 20290101000000 20240101000000 12345 example.com. AAAA
+@ NSEC ns SOA NS RRSIG NSEC DNSKEY
+",
+            ZONE
+        );
+        let zone = Zone::parse(&signed, "example.com").unwrap();
+        assert_eq!(
+            zone.to_json().get("signing").unwrap().to_string(),
+            r#"{"signed":true,"keys":1,"denial":"nsec","signatures-expire":1861920000}"#
+        );
+    }
+}